@@ -1,10 +1,270 @@
+use crate::principal::Principal;
 use crate::profilestorepb::agents_service_server::AgentsService;
-use crate::profilestorepb::{AgentsRequest, AgentsResponse};
+use crate::profilestorepb::{
+    Agent, AgentError, AgentsRequest, AgentsResponse, ReportErrorsRequest, ReportErrorsResponse,
+};
+use chrono::{DateTime, Utc};
+use moka::sync::Cache;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use prost_types::Timestamp;
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
 use std::result::Result;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
 use tonic::{Request, Response, Status};
 
+/// Metadata key agents can set to identify themselves. When it is absent the
+/// peer address of the connection is used as the agent identity. Since
+/// agents can choose it, it's only used to tell agents apart in the agent
+/// records and logs, not for quotas or metrics.
+pub const AGENT_ID_METADATA_KEY: &str = "x-agent-id";
+
+/// Maximum number of distinct error kinds kept per agent.
+const MAX_ERROR_KINDS: usize = 16;
+/// Maximum length of a reported error message.
+const MAX_ERROR_MESSAGE_LEN: usize = 1024;
+/// Maximum number of agents, and of quota windows, kept. The least recently
+/// seen ones are forgotten first.
+const MAX_AGENTS: u64 = 10_000;
+/// How long agents are kept without being seen.
+const AGENT_IDLE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+/// Maximum number of distinct values of the agent and node labels of the
+/// upload metrics.
+const MAX_LABEL_VALUES: usize = 1_000;
+
+static AGENT_UPLOADED_BYTES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_agent_uploaded_bytes_total",
+        "Total number of bytes uploaded by an agent, by receiving service.",
+        &["agent", "service"]
+    )
+    .unwrap()
+});
+
+static NODE_UPLOADED_BYTES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_node_uploaded_bytes_total",
+        "Total number of profile bytes uploaded for a node.",
+        &["node"]
+    )
+    .unwrap()
+});
+
+static AGENT_LABELS: LazyLock<LabelValues> = LazyLock::new(LabelValues::default);
+static NODE_LABELS: LazyLock<LabelValues> = LazyLock::new(LabelValues::default);
+
+/// LabelValues caps the values of a metric label taken from requests, so
+/// that clients can't create series without bounds. Values beyond
+/// `MAX_LABEL_VALUES` are reported as "other".
+#[derive(Debug, Default)]
+struct LabelValues {
+    values: Mutex<HashSet<String>>,
+}
+
+impl LabelValues {
+    fn get<'a>(&self, value: &'a str) -> &'a str {
+        let mut values = self.values.lock().unwrap();
+        if values.contains(value) {
+            return value;
+        }
+        if values.len() >= MAX_LABEL_VALUES {
+            return "other";
+        }
+        values.insert(value.to_string());
+        value
+    }
+}
+
+/// The service an agent uploaded bytes to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UploadService {
+    Debuginfo,
+    ProfileStore,
+}
+
+impl UploadService {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Debuginfo => "debuginfo",
+            Self::ProfileStore => "profilestore",
+        }
+    }
+}
+
+/// AgentQuota limits how many bytes a single agent may upload within a window.
+#[derive(Debug, Clone)]
+pub struct AgentQuota {
+    pub max_bytes: u64,
+    pub window: Duration,
+}
+
 #[derive(Debug, Default)]
-pub struct AgentStore {}
+struct AgentRecord {
+    last_error: String,
+    last_push: Option<DateTime<Utc>>,
+    last_push_duration: Option<Duration>,
+    uploaded_bytes: u64,
+    /// Most recent reported error by kind.
+    reported_errors: BTreeMap<String, AgentError>,
+}
+
+/// The bytes uploaded by a peer in the current quota window.
+#[derive(Debug)]
+struct QuotaWindow {
+    started_at: Instant,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct AgentStore {
+    agents: Cache<String, Arc<Mutex<AgentRecord>>>,
+    quota: Option<AgentQuota>,
+    /// Quota windows by principal peer.
+    windows: Cache<String, Arc<Mutex<QuotaWindow>>>,
+}
+
+impl Default for AgentStore {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+/// Returns the identity of the agent that sent the request, either from the
+/// `x-agent-id` metadata or the address of the client.
+pub fn agent_id<T>(request: &Request<T>, client: Option<IpAddr>) -> String {
+    if let Some(id) = request
+        .metadata()
+        .get(AGENT_ID_METADATA_KEY)
+        .and_then(|v| v.to_str().ok())
+    {
+        if !id.is_empty() {
+            return id.to_string();
+        }
+    }
+
+    match client {
+        Some(ip) => ip.to_string(),
+        None => "unknown".into(),
+    }
+}
+
+impl AgentStore {
+    pub fn new(quota: Option<AgentQuota>) -> Self {
+        // Windows idle for longer than the quota window would be reset anyway.
+        let window = quota.as_ref().map_or(AGENT_IDLE_TIMEOUT, |q| q.window);
+        Self {
+            agents: Cache::builder()
+                .max_capacity(MAX_AGENTS)
+                .time_to_idle(AGENT_IDLE_TIMEOUT)
+                .build(),
+            quota,
+            windows: Cache::builder()
+                .max_capacity(MAX_AGENTS)
+                .time_to_idle(window.max(Duration::from_secs(1)))
+                .build(),
+        }
+    }
+
+    fn record(&self, agent: &str) -> Arc<Mutex<AgentRecord>> {
+        self.agents.get_with(agent.to_string(), Arc::default)
+    }
+
+    /// Accounts `bytes` uploaded by `principal` to `service`. Returns
+    /// ResourceExhausted without accounting anything if the upload would
+    /// exceed the quota of the principal's peer.
+    pub fn record_upload(
+        &self,
+        principal: &Principal,
+        service: UploadService,
+        bytes: u64,
+    ) -> Result<(), Status> {
+        if let Some(quota) = &self.quota {
+            let window = self.windows.get_with(principal.peer().to_string(), || {
+                Arc::new(Mutex::new(QuotaWindow {
+                    started_at: Instant::now(),
+                    bytes: 0,
+                }))
+            });
+            let mut window = window.lock().unwrap();
+            if window.started_at.elapsed() >= quota.window {
+                window.started_at = Instant::now();
+                window.bytes = 0;
+            }
+
+            if window.bytes + bytes > quota.max_bytes {
+                return Err(Status::resource_exhausted(format!(
+                    "{} exceeded its upload quota of {} bytes per {}",
+                    principal.peer(),
+                    quota.max_bytes,
+                    humantime::format_duration(quota.window),
+                )));
+            }
+            window.bytes += bytes;
+        }
+
+        self.record(principal.id()).lock().unwrap().uploaded_bytes += bytes;
+        AGENT_UPLOADED_BYTES
+            .with_label_values(&[AGENT_LABELS.get(principal.peer()), service.as_str()])
+            .inc_by(bytes);
+        Ok(())
+    }
+
+    /// Accounts profile bytes uploaded on behalf of `node`.
+    pub fn record_node_upload(&self, node: &str, bytes: u64) {
+        NODE_UPLOADED_BYTES
+            .with_label_values(&[NODE_LABELS.get(node)])
+            .inc_by(bytes);
+    }
+
+    /// Records the outcome of a profile push performed by `agent`.
+    pub fn record_push(&self, agent: &str, duration: Duration, error: Option<String>) {
+        let record = self.record(agent);
+        let mut record = record.lock().unwrap();
+        record.last_push = Some(Utc::now());
+        record.last_push_duration = Some(duration);
+        record.last_error = error.unwrap_or_default();
+    }
+
+    /// Records errors reported by `agent`, keeping the most recent error of
+    /// each kind. Errors of new kinds are dropped once the agent reported
+    /// `MAX_ERROR_KINDS` kinds.
+    pub fn record_errors(&self, agent: &str, errors: Vec<AgentError>) -> Result<(), Status> {
+        if let Some(e) = errors.iter().find(|e| e.kind.is_empty()) {
+            return Err(Status::invalid_argument(format!(
+                "error kind is required, got error {:?}",
+                e.message
+            )));
+        }
+
+        let now = Utc::now();
+        let record = self.record(agent);
+        let mut record = record.lock().unwrap();
+
+        for mut error in errors {
+            let count = match record.reported_errors.get(&error.kind) {
+                Some(previous) => previous.count + 1,
+                None if record.reported_errors.len() >= MAX_ERROR_KINDS => continue,
+                None => 1,
+            };
+
+            if error.message.len() > MAX_ERROR_MESSAGE_LEN {
+                let mut end = MAX_ERROR_MESSAGE_LEN;
+                while !error.message.is_char_boundary(end) {
+                    end -= 1;
+                }
+                error.message.truncate(end);
+            }
+            error.time = error.time.or(Some(Timestamp {
+                seconds: now.timestamp(),
+                nanos: now.timestamp_subsec_nanos() as i32,
+            }));
+            error.count = count;
+            record.reported_errors.insert(error.kind.clone(), error);
+        }
+        Ok(())
+    }
+}
 
 #[tonic::async_trait]
 impl AgentsService for AgentStore {
@@ -16,6 +276,142 @@ impl AgentsService for AgentStore {
             "Received AgentsService::agents request \n body: {:?}",
             request
         );
-        return Ok(Response::new(AgentsResponse { agents: vec![] }));
+
+        let mut res: Vec<Agent> = self
+            .agents
+            .iter()
+            .map(|(id, record)| {
+                let record = record.lock().unwrap();
+                Agent {
+                    id: id.to_string(),
+                    last_error: record.last_error.clone(),
+                    last_push: record.last_push.map(|t| Timestamp {
+                        seconds: t.timestamp(),
+                        nanos: t.timestamp_subsec_nanos() as i32,
+                    }),
+                    last_push_duration: record
+                        .last_push_duration
+                        .and_then(|d| prost_types::Duration::try_from(d).ok()),
+                    uploaded_bytes: record.uploaded_bytes,
+                    reported_errors: record.reported_errors.values().cloned().collect(),
+                }
+            })
+            .collect();
+        res.sort_by(|a, b| a.id.cmp(&b.id));
+
+        return Ok(Response::new(AgentsResponse { agents: res }));
+    }
+
+    async fn report_errors(
+        &self,
+        request: Request<ReportErrorsRequest>,
+    ) -> Result<Response<ReportErrorsResponse>, Status> {
+        let agent = Principal::of(&request).to_string();
+        self.record_errors(&agent, request.into_inner().errors)?;
+        Ok(Response::new(ReportErrorsResponse {}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::principal::Authenticator;
+    use tonic::service::Interceptor;
+    use tonic::transport::server::TcpConnectInfo;
+
+    #[test]
+    fn test_agent_quota() {
+        let store = AgentStore::new(Some(AgentQuota {
+            max_bytes: 100,
+            window: Duration::from_secs(3600),
+        }));
+        let (a, b) = (Principal::new("agent-a"), Principal::new("agent-b"));
+
+        assert!(store
+            .record_upload(&a, UploadService::Debuginfo, 60)
+            .is_ok());
+        assert!(store
+            .record_upload(&b, UploadService::Debuginfo, 60)
+            .is_ok());
+
+        let err = store
+            .record_upload(&a, UploadService::ProfileStore, 60)
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+
+        assert!(store
+            .record_upload(&a, UploadService::ProfileStore, 40)
+            .is_ok());
+
+        // Agents can't get a new quota by changing their x-agent-id, the
+        // quota is of the peer.
+        let principal = |id: &str| {
+            let mut request = Request::new(());
+            request.extensions_mut().insert(TcpConnectInfo {
+                local_addr: None,
+                remote_addr: Some("192.168.0.1:1234".parse().unwrap()),
+            });
+            request
+                .metadata_mut()
+                .insert(AGENT_ID_METADATA_KEY, id.parse().unwrap());
+            let request = Authenticator::default().call(request).unwrap();
+            Principal::of(&request)
+        };
+        assert!(store
+            .record_upload(&principal("agent-c"), UploadService::Debuginfo, 60)
+            .is_ok());
+        let err = store
+            .record_upload(&principal("agent-d"), UploadService::Debuginfo, 60)
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+    }
+
+    #[test]
+    fn test_label_values() {
+        let labels = LabelValues::default();
+        for i in 0..MAX_LABEL_VALUES {
+            assert_eq!(labels.get(&i.to_string()), i.to_string());
+        }
+        assert_eq!(labels.get("0"), "0");
+        assert_eq!(labels.get("new"), "other");
+    }
+
+    #[tokio::test]
+    async fn test_report_errors() {
+        let store = AgentStore::default();
+        let error = |kind: &str, message: &str| AgentError {
+            kind: kind.into(),
+            message: message.into(),
+            ..Default::default()
+        };
+
+        store
+            .record_errors(
+                "agent-a",
+                vec![
+                    error("write", "token expired"),
+                    error("debuginfo_upload", "timeout"),
+                ],
+            )
+            .unwrap();
+        store
+            .record_errors("agent-a", vec![error("write", "token still expired")])
+            .unwrap();
+        assert!(store
+            .record_errors("agent-a", vec![error("", "x")])
+            .is_err());
+
+        let agents = store
+            .agents(Request::new(AgentsRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .agents;
+        let errors = &agents[0].reported_errors;
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].kind, "debuginfo_upload");
+        assert_eq!(errors[1].message, "token still expired");
+        assert_eq!(errors[1].count, 2);
+        assert!(errors[1].time.is_some());
     }
 }
@@ -0,0 +1,531 @@
+use crate::leader::{self, LeaderElection};
+use crate::query::{QueryExecutor, SampleReader, Selector, TimeRange};
+use crate::scrape::duration;
+use anyhow::{ensure, Context};
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use prometheus::{register_gauge_vec, register_int_counter_vec, GaugeVec, IntCounterVec};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use url::Url;
+
+static FUNCTION_SHARE: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "evprofiler_alert_function_share_ratio",
+        "Share of the selected samples with the function of the alerting rule on their stack, as of the last evaluation.",
+        &["alert"]
+    )
+    .unwrap()
+});
+
+static NOTIFICATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_alert_notifications_total",
+        "Total number of alert notifications sent to Alertmanagers and webhooks, by result.",
+        &["result"]
+    )
+    .unwrap()
+});
+
+fn default_evaluation_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_window() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+/// AlertingConfig configures the alerting rules evaluated over the stored
+/// samples, and where their alerts are sent:
+///
+/// ```yaml
+/// evaluation_interval: 1m
+/// alertmanagers: [http://alertmanager:9093]
+/// webhooks: [https://ci.example.com/hooks/alerts]
+/// rules:
+///   - alert: JSONEncodingHot
+///     selector: 'process_cpu:samples:count:cpu:nanoseconds:delta{job="api"}'
+///     function: encoding/json\..*
+///     threshold: 10
+///     window: 5m
+///     for: 10m
+///     labels: {severity: warning}
+///     annotations: {summary: "JSON encoding takes {{ $value }}% of the CPU"}
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertingConfig {
+    /// Time between evaluations of the rules.
+    #[serde(default = "default_evaluation_interval", deserialize_with = "duration")]
+    pub evaluation_interval: Duration,
+    /// Alertmanagers sent the firing and resolved alerts on every evaluation,
+    /// through their v2 API.
+    #[serde(default)]
+    pub alertmanagers: Vec<String>,
+    /// Endpoints POSTed the alerts whenever they fire or resolve.
+    #[serde(default)]
+    pub webhooks: Vec<String>,
+    #[serde(default)]
+    pub rules: Vec<AlertRule>,
+}
+
+impl Default for AlertingConfig {
+    fn default() -> Self {
+        Self {
+            evaluation_interval: default_evaluation_interval(),
+            alertmanagers: vec![],
+            webhooks: vec![],
+            rules: vec![],
+        }
+    }
+}
+
+/// AlertRule fires when the cumulative share of a function, which is the
+/// share of the selected samples' value with the function anywhere on their
+/// stack, exceeds the threshold for some time.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    /// Name of the alert, its `alertname` label.
+    pub alert: String,
+    /// Selector of the series the share is computed over.
+    pub selector: String,
+    /// Regular expression matched against the whole function name.
+    pub function: String,
+    /// Share in percent above which the rule is active.
+    pub threshold: f64,
+    /// Time range of the samples each evaluation looks at.
+    #[serde(default = "default_window", deserialize_with = "duration")]
+    pub window: Duration,
+    /// Time the rule must be active before the alert fires.
+    #[serde(default, rename = "for", deserialize_with = "duration")]
+    pub for_: Duration,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Annotations of the alert, where `{{ $value }}` is replaced by the
+    /// share in percent.
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl AlertingConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            !self.evaluation_interval.is_zero(),
+            "evaluation_interval must be positive"
+        );
+        for url in self.alertmanagers.iter().chain(self.webhooks.iter()) {
+            let parsed = Url::parse(url).with_context(|| format!("invalid url {:?}", url))?;
+            ensure!(
+                matches!(parsed.scheme(), "http" | "https"),
+                "url {:?} must be http or https",
+                url
+            );
+        }
+        ensure!(
+            self.rules.is_empty() || !self.alertmanagers.is_empty() || !self.webhooks.is_empty(),
+            "rules need an alertmanager or a webhook to send alerts to"
+        );
+        let mut alerts = HashSet::new();
+        for rule in self.rules.iter() {
+            rule.validate()
+                .with_context(|| format!("rule {:?}", rule.alert))?;
+            ensure!(
+                alerts.insert(&rule.alert),
+                "duplicate rule {:?}",
+                rule.alert
+            );
+        }
+        Ok(())
+    }
+}
+
+impl AlertRule {
+    fn validate(&self) -> anyhow::Result<()> {
+        self.compile().map(|_| ())
+    }
+
+    fn compile(&self) -> anyhow::Result<(Selector, Regex)> {
+        ensure!(!self.alert.is_empty(), "alert is required");
+        ensure!(
+            (0.0..=100.0).contains(&self.threshold),
+            "threshold must be between 0 and 100"
+        );
+        ensure!(!self.window.is_zero(), "window must be positive");
+        let selector = Selector::parse(&self.selector)?;
+        let function = Regex::new(&format!("^(?:{})$", self.function))
+            .with_context(|| format!("invalid function {:?}", self.function))?;
+        Ok((selector, function))
+    }
+}
+
+/// State of a rule between evaluations.
+#[derive(Debug, Default)]
+struct RuleState {
+    /// Time since which the share is above the threshold.
+    active_since: Option<DateTime<Utc>>,
+    firing: bool,
+}
+
+#[derive(Debug)]
+struct Rule {
+    config: AlertRule,
+    selector: Selector,
+    function: Regex,
+    state: Mutex<RuleState>,
+}
+
+impl Rule {
+    /// Returns the alert of the rule, with the share in its annotations.
+    fn alert(&self, share: f64, starts_at: DateTime<Utc>, ends_at: DateTime<Utc>) -> Alert {
+        let mut labels = self.config.labels.clone();
+        labels.insert("alertname".to_string(), self.config.alert.clone());
+        let value = format!("{:.2}", share);
+        let annotations = self
+            .config
+            .annotations
+            .iter()
+            .map(|(k, v)| (k.clone(), v.replace("{{ $value }}", &value)))
+            .collect();
+        Alert {
+            labels,
+            annotations,
+            starts_at: starts_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            ends_at: ends_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            status: None,
+        }
+    }
+}
+
+/// Alert is an alert as posted to Alertmanager.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Alert {
+    labels: BTreeMap<String, String>,
+    annotations: BTreeMap<String, String>,
+    starts_at: String,
+    /// End of a resolved alert, or the time until which a firing alert is
+    /// considered firing without being resent.
+    ends_at: String,
+    /// `firing` or `resolved` if the alert changed state in the evaluation.
+    #[serde(skip)]
+    status: Option<&'static str>,
+}
+
+/// RuleEvaluator periodically evaluates the alerting rules over the stored
+/// samples, as a first step toward alerting on profile regressions. Firing
+/// alerts are resent to the Alertmanagers on every evaluation, while the
+/// webhooks are only notified when an alert fires or resolves.
+#[derive(Debug)]
+pub struct RuleEvaluator {
+    reader: SampleReader,
+    executor: Arc<QueryExecutor>,
+    rules: Vec<Rule>,
+    interval: Duration,
+    alertmanagers: Vec<String>,
+    webhooks: Vec<String>,
+    client: ureq::Agent,
+    /// Rules are only evaluated on the leader, if set, so that instances
+    /// sharing the buckets don't send every alert.
+    leader: Option<Arc<LeaderElection>>,
+}
+
+impl RuleEvaluator {
+    pub fn new(config: &AlertingConfig, reader: SampleReader) -> anyhow::Result<Self> {
+        config.validate()?;
+        let mut rules = vec![];
+        for rule in config.rules.iter() {
+            let (selector, function) = rule.compile()?;
+            rules.push(Rule {
+                config: rule.clone(),
+                selector,
+                function,
+                state: Mutex::default(),
+            });
+        }
+        Ok(Self {
+            reader,
+            executor: Arc::default(),
+            rules,
+            interval: config.evaluation_interval,
+            alertmanagers: config
+                .alertmanagers
+                .iter()
+                .map(|url| format!("{}/api/v2/alerts", url.trim_end_matches('/')))
+                .collect(),
+            webhooks: config.webhooks.clone(),
+            client: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build(),
+            leader: None,
+        })
+    }
+
+    pub fn with_leader_election(mut self, leader: Arc<LeaderElection>) -> Self {
+        self.leader = Some(leader);
+        self
+    }
+
+    /// Reads the samples with the executor, which bounds the concurrency and
+    /// memory of the evaluations along with the other queries.
+    pub fn with_query_executor(mut self, executor: Arc<QueryExecutor>) -> Self {
+        self.executor = executor;
+        self
+    }
+
+    /// Evaluates the rules every evaluation interval, sending their alerts.
+    pub async fn run(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(self.interval);
+        loop {
+            ticker.tick().await;
+            if !leader::should_run(&self.leader) {
+                continue;
+            }
+            let alerts = self.evaluate(Utc::now()).await;
+            self.send(alerts).await;
+        }
+    }
+
+    /// Evaluates the rules as of `now`, returning the alerts that are firing
+    /// or were just resolved.
+    async fn evaluate(&self, now: DateTime<Utc>) -> Vec<Alert> {
+        let mut alerts = vec![];
+        for rule in self.rules.iter() {
+            let share = match self.share(rule, now).await {
+                Ok(share) => share,
+                Err(e) => {
+                    log::warn!("Failed to evaluate rule {}: {:#}", rule.config.alert, e);
+                    continue;
+                }
+            };
+            FUNCTION_SHARE
+                .with_label_values(&[&rule.config.alert])
+                .set(share.unwrap_or_default() / 100.0);
+
+            let mut state = rule.state.lock().unwrap();
+            let active = share.is_some_and(|share| share > rule.config.threshold);
+            match (active, state.active_since) {
+                (true, None) => state.active_since = Some(now),
+                (false, Some(since)) => {
+                    state.active_since = None;
+                    if std::mem::take(&mut state.firing) {
+                        let mut alert = rule.alert(share.unwrap_or_default(), since, now);
+                        alert.status = Some("resolved");
+                        alerts.push(alert);
+                    }
+                }
+                _ => {}
+            }
+            let Some(since) = state.active_since else {
+                continue;
+            };
+            if now - since < TimeDelta::from_std(rule.config.for_).unwrap_or(TimeDelta::MAX) {
+                continue;
+            }
+            // Firing alerts expire unless resent within a few evaluations.
+            let expiry = TimeDelta::from_std(self.interval * 4).unwrap_or(TimeDelta::MAX);
+            let mut alert = rule.alert(
+                share.unwrap_or_default(),
+                since,
+                now.checked_add_signed(expiry).unwrap_or(now),
+            );
+            if !state.firing {
+                state.firing = true;
+                alert.status = Some("firing");
+            }
+            alerts.push(alert);
+        }
+        alerts
+    }
+
+    /// Returns the cumulative share of the rule's function in percent over
+    /// the window before `now`, or None without samples.
+    async fn share(&self, rule: &Rule, now: DateTime<Utc>) -> anyhow::Result<Option<f64>> {
+        let window = TimeDelta::from_std(rule.config.window)?;
+        let range = TimeRange {
+            start: (now - window).timestamp_millis(),
+            end: now.timestamp_millis(),
+        };
+        let selectors = std::slice::from_ref(&rule.selector);
+        let (mut total, mut matching) = (0i64, 0i64);
+        let mut query = self.executor.start().await;
+        for segment in self.reader.segments(range).await? {
+            let samples = self
+                .reader
+                .read_segment(&segment, selectors, range)
+                .await
+                .with_context(|| format!("reading {}", segment))?;
+            let size = samples.iter().map(|s| s.memory_usage()).sum();
+            query.reserve(size)?;
+            for sample in samples {
+                total += sample.value;
+                if sample
+                    .stacktrace
+                    .iter()
+                    .any(|frame| rule.function.is_match(&frame.name()))
+                {
+                    matching += sample.value;
+                }
+            }
+            query.release(size);
+        }
+        Ok((total != 0).then(|| matching as f64 * 100.0 / total as f64))
+    }
+
+    /// Sends the alerts to the Alertmanagers, and those that changed state to
+    /// the webhooks.
+    async fn send(&self, alerts: Vec<Alert>) {
+        let mut requests = vec![];
+        if !alerts.is_empty() {
+            for url in self.alertmanagers.iter() {
+                requests.push((url.clone(), serde_json::json!(alerts)));
+            }
+        }
+        for status in ["firing", "resolved"] {
+            let changed: Vec<&Alert> = alerts.iter().filter(|a| a.status == Some(status)).collect();
+            if changed.is_empty() {
+                continue;
+            }
+            for url in self.webhooks.iter() {
+                let body = serde_json::json!({"status": status, "alerts": changed});
+                requests.push((url.clone(), body));
+            }
+        }
+
+        for (url, body) in requests {
+            let request = self
+                .client
+                .post(&url)
+                .set("Content-Type", "application/json");
+            let res = tokio::task::spawn_blocking(move || {
+                request
+                    .send_bytes(body.to_string().as_bytes())
+                    .map(|_| ())
+                    .with_context(|| format!("sending alerts to {}", url))
+            })
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|res| res);
+            let result = match res {
+                Ok(()) => "sent",
+                Err(e) => {
+                    log::warn!("Failed to notify of alerts: {:#}", e);
+                    "failed"
+                }
+            };
+            NOTIFICATIONS.with_label_values(&[result]).inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalizer::Metastore;
+    use crate::query::samples::tests::segment;
+    use axum::{extract::Path as UrlPath, routing::post, Json, Router};
+    use object_store::{memory::InMemory, path::Path, ObjectStore};
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_validate() {
+        let config: AlertingConfig = serde_yaml::from_str(
+            "alertmanagers: [http://alertmanager:9093]\n\
+             rules:\n  - alert: Hot\n    selector: '{job=\"api\"}'\n    function: main\n    threshold: 10\n    for: 10m\n",
+        )
+        .unwrap();
+        assert_eq!(config.evaluation_interval, Duration::from_secs(60));
+        assert_eq!(config.rules[0].window, Duration::from_secs(300));
+        assert_eq!(config.rules[0].for_, Duration::from_secs(600));
+        config.validate().unwrap();
+
+        let mut invalid = config.clone();
+        invalid.alertmanagers.clear();
+        assert!(invalid.validate().is_err());
+        let mut invalid = config.clone();
+        invalid.rules[0].threshold = 150.0;
+        assert!(invalid.validate().is_err());
+        let mut invalid = config.clone();
+        invalid.rules[0].function = "(".into();
+        assert!(invalid.validate().is_err());
+        let mut invalid = config.clone();
+        invalid.rules.push(config.rules[0].clone());
+        assert!(invalid.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_evaluate() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let router = Router::new().route(
+            "/*path",
+            post(
+                move |UrlPath(path): UrlPath<String>, Json(body): Json<serde_json::Value>| {
+                    let tx = tx.clone();
+                    async move { tx.send((path, body)).unwrap() }
+                },
+            ),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(crate::http::serve(listener, router, std::future::pending()));
+
+        let storage: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let metastore = Arc::new(Metastore::default());
+        let data = segment(
+            &metastore,
+            "api",
+            1_000,
+            &[(&["leaf", "main"], 3), (&["other", "main"], 1)],
+        )
+        .await;
+        let path = Path::from("date=1970-01-01/0.parquet");
+        storage.put(&path, data.into()).await.unwrap();
+
+        let rule = |alert: &str, function: &str, for_| AlertRule {
+            alert: alert.into(),
+            selector: "{node=\"api\"}".into(),
+            function: function.into(),
+            threshold: 50.0,
+            window: Duration::from_secs(60),
+            for_,
+            labels: BTreeMap::new(),
+            annotations: [("summary".to_string(), "at {{ $value }}%".to_string())].into(),
+        };
+        let config = AlertingConfig {
+            alertmanagers: vec![format!("http://{}", addr)],
+            webhooks: vec![format!("http://{}/hook", addr)],
+            rules: vec![
+                rule("Leaf", "le.f", Duration::ZERO),
+                rule("Other", "other", Duration::ZERO),
+                rule("Slow", "leaf", Duration::from_secs(600)),
+            ],
+            ..Default::default()
+        };
+        let evaluator = RuleEvaluator::new(&config, SampleReader::new(storage, metastore)).unwrap();
+
+        let now = DateTime::from_timestamp_millis(30_000).unwrap();
+        let alerts = evaluator.evaluate(now).await;
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].labels["alertname"], "Leaf");
+        assert_eq!(alerts[0].annotations["summary"], "at 75.00%");
+        assert_eq!(alerts[0].status, Some("firing"));
+        evaluator.send(alerts).await;
+        let mut requests = [rx.recv().await.unwrap(), rx.recv().await.unwrap()];
+        requests.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(requests[0].0, "api/v2/alerts");
+        assert_eq!(requests[0].1[0]["labels"]["alertname"], "Leaf");
+        assert_eq!(requests[1].0, "hook");
+        assert_eq!(requests[1].1["status"], "firing");
+
+        // The samples are out of the window, so the alert resolves, while the
+        // slow rule never fired.
+        let later = DateTime::from_timestamp_millis(120_000).unwrap();
+        let alerts = evaluator.evaluate(later).await;
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].status, Some("resolved"));
+        assert_eq!(alerts[0].ends_at, "1970-01-01T00:02:00.000Z");
+    }
+}
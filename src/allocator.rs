@@ -0,0 +1,137 @@
+//! Selects the global allocator. Ingestion allocates and frees many small
+//! objects per profile, so long-running servers can fragment the system
+//! allocator; the `jemalloc` and `mimalloc` features switch to an allocator
+//! that is more resilient to that and export its statistics.
+
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("the jemalloc and mimalloc features are mutually exclusive");
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+static ALLOCATOR_BYTES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "evprofiler_allocator_bytes",
+        "Statistics of the global allocator in bytes, by statistic.",
+        &["allocator", "stat"]
+    )
+    .unwrap()
+});
+
+/// Name of the global allocator.
+pub const NAME: &str = if cfg!(feature = "jemalloc") {
+    "jemalloc"
+} else if cfg!(feature = "mimalloc") {
+    "mimalloc"
+} else {
+    "system"
+};
+
+/// Returns the statistics of the global allocator in bytes.
+#[cfg(feature = "jemalloc")]
+pub fn stats() -> Vec<(&'static str, u64)> {
+    jemalloc_stats().unwrap_or_else(|e| {
+        log::warn!("Failed to read the jemalloc statistics: {}", e);
+        vec![]
+    })
+}
+
+/// Returns the statistics of the global allocator in bytes.
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+pub fn stats() -> Vec<(&'static str, u64)> {
+    mimalloc_stats()
+}
+
+/// Returns no statistics, the system allocator doesn't have any.
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub fn stats() -> Vec<(&'static str, u64)> {
+    vec![]
+}
+
+/// Exports the statistics of the global allocator every `interval`.
+pub async fn run(interval: Duration) {
+    if stats().is_empty() {
+        return;
+    }
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        update();
+    }
+}
+
+fn update() {
+    for (stat, bytes) in stats() {
+        ALLOCATOR_BYTES
+            .with_label_values(&[NAME, stat])
+            .set(bytes as i64);
+    }
+}
+
+/// Reads the jemalloc statistics, which are only refreshed when the epoch is
+/// advanced. Fragmentation shows as `resident` growing away from `allocated`.
+#[cfg(feature = "jemalloc")]
+fn jemalloc_stats() -> Result<Vec<(&'static str, u64)>, tikv_jemalloc_ctl::Error> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+    epoch::advance()?;
+    Ok(vec![
+        ("allocated", stats::allocated::read()? as u64),
+        ("active", stats::active::read()? as u64),
+        ("metadata", stats::metadata::read()? as u64),
+        ("resident", stats::resident::read()? as u64),
+        ("mapped", stats::mapped::read()? as u64),
+        ("retained", stats::retained::read()? as u64),
+    ])
+}
+
+/// Reads the process statistics of mimalloc. Fragmentation shows as
+/// `committed` growing while the heap doesn't.
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+fn mimalloc_stats() -> Vec<(&'static str, u64)> {
+    let (mut elapsed, mut user, mut system) = (0, 0, 0);
+    let (mut rss, mut peak_rss, mut committed, mut peak_committed, mut faults) = (0, 0, 0, 0, 0);
+    // SAFETY: mi_process_info only writes to the passed pointers.
+    unsafe {
+        libmimalloc_sys::mi_process_info(
+            &mut elapsed,
+            &mut user,
+            &mut system,
+            &mut rss,
+            &mut peak_rss,
+            &mut committed,
+            &mut peak_committed,
+            &mut faults,
+        );
+    }
+    vec![
+        ("resident", rss as u64),
+        ("peak_resident", peak_rss as u64),
+        ("committed", committed as u64),
+        ("peak_committed", peak_committed as u64),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let stats = stats();
+        if NAME == "system" {
+            assert!(stats.is_empty());
+        } else {
+            assert!(stats.iter().any(|(_, bytes)| *bytes > 0), "{:?}", stats);
+        }
+        update();
+    }
+}
@@ -0,0 +1,302 @@
+use crate::ingester::Ingester;
+use crate::normalizer::WritePipeline;
+use crate::pipeline::StageTimes;
+use crate::pprofpb::Profile;
+use crate::profilestorepb::{Label, LabelSet, RawProfileSeries, RawSample, WriteRawRequest};
+use anyhow::{bail, Context};
+use chrono::DateTime;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use prost::Message;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Number of profiles written into a single segment.
+const PROFILES_PER_SEGMENT: usize = 10;
+
+/// Overrides of a single file from the manifest.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct ManifestEntry {
+    /// Timestamp in nanoseconds, replacing the one recorded in the profile.
+    time_nanos: Option<i64>,
+    labels: BTreeMap<String, String>,
+}
+
+/// Backfill ingests pprof files from a directory into storage, for migrating
+/// historical data or load-testing.
+///
+/// The optional manifest has one line per file, with the path relative to
+/// the directory followed by whitespace separated `key=value` labels. The
+/// special `timestamp` key sets the profile's timestamp in RFC 3339 format:
+///
+/// ```text
+/// # comment
+/// api/cpu.pb.gz timestamp=2024-01-01T00:00:00Z job=api
+/// ```
+#[derive(Debug)]
+pub struct Backfill {
+    name: String,
+    labels: BTreeMap<String, String>,
+    manifest: HashMap<PathBuf, ManifestEntry>,
+}
+
+impl Backfill {
+    pub fn new(name: &str, labels: &[String], manifest: Option<&Path>) -> anyhow::Result<Self> {
+        let manifest = match manifest {
+            Some(path) => parse_manifest(&std::fs::read_to_string(path)?)
+                .with_context(|| format!("reading manifest {}", path.display()))?,
+            None => HashMap::new(),
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            labels: parse_labels(labels.iter().map(String::as_str))?,
+            manifest,
+        })
+    }
+
+    /// Ingests the pprof files below `dir` and returns the number of
+    /// profiles that were written. Files that can't be read as profiles are
+    /// logged and skipped, so one bad file doesn't abort a long backfill.
+    pub async fn run(
+        &self,
+        dir: &Path,
+        pipeline: &WritePipeline,
+        ingester: &Ingester,
+    ) -> anyhow::Result<usize> {
+        let mut files = vec![];
+        walk(dir, &mut files)?;
+        files.sort();
+
+        let (mut ingested, mut skipped) = (0, 0);
+        for path in files {
+            let relative = path.strip_prefix(dir)?;
+            let request = std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| self.request(relative, &data));
+            let res = match request {
+                Ok(request) => pipeline.run(&request, &mut StageTimes::default()).await,
+                Err(e) => Err(e),
+            };
+            let chunk = match res {
+                Ok((_, chunk)) => chunk,
+                Err(e) => {
+                    log::warn!("Skipping {}: {:#}", path.display(), e);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            if chunk.is_empty() {
+                log::warn!("Skipping {}: no samples", path.display());
+                continue;
+            }
+
+            ingester.ingest(chunk).await?;
+            ingested += 1;
+            if ingested % PROFILES_PER_SEGMENT == 0 {
+                ingester.flush().await?;
+            }
+        }
+
+        ingester.flush().await?;
+        if skipped > 0 {
+            log::warn!("Skipped {} files that aren't valid profiles", skipped);
+        }
+        Ok(ingested)
+    }
+
+    fn request(&self, relative: &Path, data: &[u8]) -> anyhow::Result<WriteRawRequest> {
+        let entry = self.manifest.get(relative).cloned().unwrap_or_default();
+
+        let mut labels = self.labels.clone();
+        labels.extend(entry.labels);
+        labels.insert("__name__".into(), self.name.clone());
+
+        let raw_profile = match entry.time_nanos {
+            Some(time_nanos) => {
+                let mut profile = Profile::decode(decompress(data)?.as_slice())?;
+                profile.time_nanos = time_nanos;
+                compress(&profile.encode_to_vec())?
+            }
+            // The normalizer only accepts gzipped profiles.
+            None if is_gzip(data) => data.to_vec(),
+            None => compress(data)?,
+        };
+
+        Ok(WriteRawRequest {
+            series: vec![RawProfileSeries {
+                labels: Some(LabelSet {
+                    labels: labels
+                        .into_iter()
+                        .map(|(name, value)| Label { name, value })
+                        .collect(),
+                }),
+                samples: vec![RawSample {
+                    raw_profile,
+                    executable_info: vec![],
+                }],
+            }],
+            ..Default::default()
+        })
+    }
+}
+
+/// Extensions of pprof files, optionally followed by `.gz`.
+const PPROF_EXTENSIONS: [&str; 3] = ["pb", "pprof", "prof"];
+
+/// Returns whether the file is named like a pprof file, like `cpu.pb.gz`.
+fn is_pprof_file(path: &Path) -> bool {
+    let path = match path.extension() {
+        Some(ext) if ext == "gz" => path.with_extension(""),
+        _ => path.to_path_buf(),
+    };
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| PPROF_EXTENSIONS.contains(&ext))
+}
+
+/// Collects the pprof files below `dir`.
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            walk(&path, files)?;
+        } else if is_pprof_file(&path) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn parse_labels<'a>(
+    labels: impl Iterator<Item = &'a str>,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut parsed = BTreeMap::new();
+    for label in labels {
+        match label.split_once('=') {
+            Some((name, value)) if !name.is_empty() => {
+                parsed.insert(name.to_string(), value.to_string());
+            }
+            _ => bail!("invalid label {}: expected name=value", label),
+        }
+    }
+    Ok(parsed)
+}
+
+fn parse_manifest(manifest: &str) -> anyhow::Result<HashMap<PathBuf, ManifestEntry>> {
+    let mut entries = HashMap::new();
+    for (i, line) in manifest.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let path = fields.next().unwrap_or_default();
+        let mut labels = parse_labels(fields).with_context(|| format!("line {}", i + 1))?;
+        let time_nanos = match labels.remove("timestamp") {
+            Some(ts) => Some(
+                DateTime::parse_from_rfc3339(&ts)
+                    .with_context(|| format!("line {}: invalid timestamp {}", i + 1, ts))?
+                    .timestamp_nanos_opt()
+                    .context("timestamp out of range")?,
+            ),
+            None => None,
+        };
+
+        entries.insert(PathBuf::from(path), ManifestEntry { time_nanos, labels });
+    }
+    Ok(entries)
+}
+
+pub(crate) fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&[0x1f, 0x8b])
+}
+
+pub(crate) fn decompress(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if !is_gzip(data) {
+        return Ok(data.to_vec());
+    }
+    let mut decompressed = vec![];
+    GzDecoder::new(data).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+pub(crate) fn compress(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let manifest = parse_manifest(
+            "# comment\n\napi/cpu.pb.gz timestamp=2024-01-01T00:00:01Z job=api\nother.pb\n",
+        )
+        .unwrap();
+        assert_eq!(
+            manifest[Path::new("api/cpu.pb.gz")],
+            ManifestEntry {
+                time_nanos: Some(1_704_067_201_000_000_000),
+                labels: BTreeMap::from([("job".to_string(), "api".to_string())]),
+            }
+        );
+        assert_eq!(manifest[Path::new("other.pb")], ManifestEntry::default());
+        assert!(parse_manifest("a.pb timestamp=yesterday").is_err());
+        assert!(parse_manifest("a.pb job").is_err());
+
+        let backfill = Backfill {
+            name: "process_cpu".into(),
+            labels: parse_labels(["job=default", "env=prod"].into_iter()).unwrap(),
+            manifest,
+        };
+        let profile = Profile {
+            time_nanos: 1,
+            ..Default::default()
+        };
+        let request = backfill
+            .request(Path::new("api/cpu.pb.gz"), &profile.encode_to_vec())
+            .unwrap();
+        let series = &request.series[0];
+        let labels: Vec<_> = series.labels.as_ref().unwrap().labels.iter().collect();
+        assert_eq!(
+            labels
+                .iter()
+                .map(|l| format!("{}={}", l.name, l.value))
+                .collect::<Vec<_>>(),
+            vec!["__name__=process_cpu", "env=prod", "job=api"]
+        );
+        let raw = &series.samples[0].raw_profile;
+        assert!(is_gzip(raw));
+        assert_eq!(
+            Profile::decode(decompress(raw).unwrap().as_slice())
+                .unwrap()
+                .time_nanos,
+            1_704_067_201_000_000_000
+        );
+    }
+
+    #[test]
+    fn test_walk() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("api")).unwrap();
+        for name in ["api/cpu.pb.gz", "heap.pprof", "manifest.txt", "notes.gz"] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let mut files = vec![];
+        walk(dir.path(), &mut files).unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                dir.path().join("api/cpu.pb.gz"),
+                dir.path().join("heap.pprof")
+            ]
+        );
+    }
+}
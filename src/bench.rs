@@ -0,0 +1,357 @@
+use crate::debuginfo_store::{MetadataStore, NewUpload};
+use crate::debuginfopb::DebuginfoType;
+use crate::ingester::Ingester;
+use crate::normalizer::{self, DecompressionLimits, Metastore, NormalizedWriteRawRequest};
+use crate::pprofpb::{Function, Line, Location, Profile, Sample, ValueType};
+use crate::profilestorepb::profile_store_service_client::ProfileStoreServiceClient;
+use crate::profilestorepb::{Label, LabelSet, RawProfileSeries, RawSample, WriteRawRequest};
+use crate::storage;
+use flate2::{write::GzEncoder, Compression};
+use prost::Message;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Shape of the synthesized profiles.
+#[derive(Debug, Clone, Copy)]
+pub struct ProfileShape {
+    /// Number of frames per stack.
+    pub stack_depth: usize,
+    /// Number of samples per profile.
+    pub samples: usize,
+    /// Number of distinct functions stacks are drawn from.
+    pub functions: usize,
+    /// Number of distinct series requests are spread over.
+    pub cardinality: usize,
+}
+
+/// Target the requests are sent to.
+#[derive(Debug)]
+pub enum Target {
+    /// Normalize and ingest in process, into an in-memory bucket.
+    Local,
+    /// Send WriteRaw requests to the gRPC server at the address.
+    Remote(String),
+}
+
+/// Report summarizes a benchmark run.
+#[derive(Debug)]
+pub struct Report {
+    pub requests: usize,
+    pub errors: usize,
+    pub elapsed: Duration,
+    pub samples: usize,
+    /// Sorted request latencies.
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (p / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        write!(
+            f,
+            "{} requests ({} errors) in {:.2?}: {:.1} req/s, {:.0} samples/s, latency p50={:.2?} p90={:.2?} p99={:.2?} max={:.2?}",
+            self.requests,
+            self.errors,
+            self.elapsed,
+            self.requests as f64 / secs,
+            self.samples as f64 / secs,
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.percentile(100.0),
+        )
+    }
+}
+
+/// Sends `requests` synthesized WriteRaw requests to the target from
+/// `concurrency` workers. Payloads are generated up front, so generation
+/// doesn't count towards the latencies.
+pub async fn run(
+    target: Target,
+    shape: ProfileShape,
+    requests: usize,
+    concurrency: usize,
+) -> anyhow::Result<Report> {
+    let payloads: Arc<Vec<WriteRawRequest>> = Arc::new(
+        (0..shape.cardinality.max(1))
+            .map(|series| write_raw_request(&shape, series as u64))
+            .collect::<anyhow::Result<_>>()?,
+    );
+
+    let client = match &target {
+        Target::Local => None,
+        Target::Remote(addr) => Some(ProfileStoreServiceClient::connect(addr.clone()).await?),
+    };
+    let metastore = Arc::new(Metastore::default());
+    let ingester = Arc::new(Ingester::new(10, Arc::new(storage::new_memory_bucket())));
+
+    let next = Arc::new(AtomicUsize::new(0));
+    let latencies = Arc::new(Mutex::new(Vec::with_capacity(requests)));
+    let errors = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+
+    let mut workers = vec![];
+    for _ in 0..concurrency.max(1) {
+        let (payloads, next, latencies, errors) = (
+            Arc::clone(&payloads),
+            Arc::clone(&next),
+            Arc::clone(&latencies),
+            Arc::clone(&errors),
+        );
+        let (mut client, metastore, ingester) = (
+            client.clone(),
+            Arc::clone(&metastore),
+            Arc::clone(&ingester),
+        );
+
+        workers.push(tokio::spawn(async move {
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= requests {
+                    break;
+                }
+                let request = &payloads[i % payloads.len()];
+
+                let start = Instant::now();
+                let res = match client.as_mut() {
+                    Some(client) => client
+                        .write_raw(request.clone())
+                        .await
+                        .map(|_| ())
+                        .map_err(anyhow::Error::from),
+                    None => write_local(request, &metastore, &ingester).await,
+                };
+                latencies.lock().unwrap().push(start.elapsed());
+
+                if let Err(e) = res {
+                    log::debug!("Request {} failed: {}", i, e);
+                    errors.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }));
+    }
+    for worker in workers {
+        worker.await?;
+    }
+
+    let mut latencies = std::mem::take(&mut *latencies.lock().unwrap());
+    latencies.sort();
+    Ok(Report {
+        requests,
+        errors: errors.load(Ordering::Relaxed),
+        elapsed: started.elapsed(),
+        samples: requests * shape.samples,
+        latencies,
+    })
+}
+
+/// MetadataReport summarizes a benchmark run of the debuginfo metadata store.
+#[derive(Debug)]
+pub struct MetadataReport {
+    pub ops: usize,
+    pub threads: usize,
+    pub elapsed: Duration,
+}
+
+impl std::fmt::Display for MetadataReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} debuginfo metadata operations from {} threads in {:.2?}: {:.0} ops/s",
+            self.ops,
+            self.threads,
+            self.elapsed,
+            self.ops as f64 / self.elapsed.as_secs_f64(),
+        )
+    }
+}
+
+/// Runs `ops` operations against an in-memory debuginfo metadata store from
+/// `threads` threads, to measure how lookups and upload marking scale with
+/// concurrent agents. Like ShouldInitiateUpload, most operations are
+/// lookups, of known and unknown build IDs, and one in ten marks an upload.
+pub fn debuginfo_metadata(ops: usize, threads: usize) -> MetadataReport {
+    let threads = threads.max(1);
+    let store = MetadataStore::new();
+    let t = DebuginfoType::DebuginfoUnspecified;
+    let now = chrono::Utc::now();
+    let started = Instant::now();
+    std::thread::scope(|s| {
+        for thread in 0..threads {
+            let store = &store;
+            s.spawn(move || {
+                for i in 0..ops / threads {
+                    let build_id = format!("{}-{}", thread, i % 1_000);
+                    if i % 10 == 0 {
+                        let generation = store.generation(&build_id, &t);
+                        let upload = NewUpload {
+                            id: "upload",
+                            hash: "hash",
+                            size: 3,
+                            started_at: now,
+                        };
+                        let _ = store.mark_as_uploading(&build_id, upload, &t, generation);
+                    } else {
+                        let _ = store.fetch(&build_id, &t);
+                        let _ = store.fetch(&format!("unknown-{}", i), &t);
+                    }
+                }
+            });
+        }
+    });
+    MetadataReport {
+        ops: ops / threads * threads,
+        threads,
+        elapsed: started.elapsed(),
+    }
+}
+
+async fn write_local(
+    request: &WriteRawRequest,
+    metastore: &Metastore,
+    ingester: &Ingester,
+) -> anyhow::Result<()> {
+    let normalized =
+        NormalizedWriteRawRequest::try_new(request, metastore, &DecompressionLimits::default())?;
+    let chunk = normalizer::normalized_request_to_arrow_chunk(&normalized).await?;
+    ingester.ingest(chunk).await
+}
+
+fn write_raw_request(shape: &ProfileShape, series: u64) -> anyhow::Result<WriteRawRequest> {
+    let profile = synthesize(shape, series);
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(&profile.encode_to_vec())?;
+
+    let labels = [
+        ("__name__", "process_cpu".to_string()),
+        ("instance", format!("bench-{}", series)),
+    ];
+    Ok(WriteRawRequest {
+        series: vec![RawProfileSeries {
+            labels: Some(LabelSet {
+                labels: labels
+                    .into_iter()
+                    .map(|(name, value)| Label {
+                        name: name.to_string(),
+                        value,
+                    })
+                    .collect(),
+            }),
+            samples: vec![RawSample {
+                raw_profile: encoder.finish()?,
+                executable_info: vec![],
+            }],
+        }],
+        ..Default::default()
+    })
+}
+
+/// Synthesizes a CPU profile of the given shape. Stacks are drawn
+/// deterministically from `seed`.
+fn synthesize(shape: &ProfileShape, seed: u64) -> Profile {
+    let mut string_table: Vec<String> = ["", "samples", "count", "cpu", "nanoseconds", "bench.rs"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    let functions = shape.functions.max(1);
+
+    let mut profile = Profile {
+        sample_type: vec![ValueType { r#type: 1, unit: 2 }],
+        period_type: Some(ValueType { r#type: 3, unit: 4 }),
+        period: 10_000_000,
+        time_nanos: chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+        duration_nanos: 10_000_000_000,
+        ..Default::default()
+    };
+
+    for i in 0..functions {
+        let id = i as u64 + 1;
+        string_table.push(format!("bench::function_{}", i));
+        profile.function.push(Function {
+            id,
+            name: string_table.len() as i64 - 1,
+            system_name: string_table.len() as i64 - 1,
+            filename: 5,
+            start_line: 1,
+        });
+        profile.location.push(Location {
+            id,
+            address: 0x1000 + id * 0x10,
+            line: vec![Line {
+                function_id: id,
+                line: 10,
+            }],
+            ..Default::default()
+        });
+    }
+
+    // xorshift64, so runs are reproducible without pulling in a RNG.
+    let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    for _ in 0..shape.samples {
+        profile.sample.push(Sample {
+            location_id: (0..shape.stack_depth)
+                .map(|_| next() % functions as u64 + 1)
+                .collect(),
+            value: vec![(next() % 100) as i64 + 1],
+            label: vec![],
+        });
+    }
+
+    profile.string_table = string_table;
+    profile
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_local_run() {
+        let shape = ProfileShape {
+            stack_depth: 8,
+            samples: 20,
+            functions: 50,
+            cardinality: 3,
+        };
+        let request = write_raw_request(&shape, 1).unwrap();
+        let normalized = NormalizedWriteRawRequest::try_new(
+            &request,
+            &Metastore::default(),
+            &DecompressionLimits::default(),
+        )
+        .unwrap();
+        let samples = &normalized.series[0].samples[0][0].samples;
+        assert_eq!(samples.len(), 20);
+        assert!(samples.iter().all(|s| s.locations.len() == 8));
+
+        let report = run(Target::Local, shape, 12, 3).await.unwrap();
+        assert_eq!(report.requests, 12);
+        assert_eq!(report.errors, 0);
+        assert_eq!(report.latencies.len(), 12);
+        assert!(report.percentile(50.0) <= report.percentile(99.0));
+    }
+
+    #[test]
+    fn test_debuginfo_metadata() {
+        let report = debuginfo_metadata(1_000, 3);
+        assert_eq!((report.ops, report.threads), (999, 3));
+    }
+}
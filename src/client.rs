@@ -0,0 +1,406 @@
+use crate::debuginfopb::debuginfo_service_client::DebuginfoServiceClient;
+use crate::debuginfopb::upload_instructions::UploadStrategy;
+use crate::debuginfopb::{
+    upload_request, BuildIdType, DebuginfoType, InitiateUploadRequest, MarkUploadFinishedRequest,
+    MarkUploadUnavailableRequest, ShouldInitiateUploadRequest, ShouldInitiateUploadResponse,
+    UploadInfo, UploadInstructions, UploadRequest,
+};
+use anyhow::{bail, Context};
+use ring::digest::{digest, SHA256};
+use std::fmt::Write;
+use tonic::metadata::{AsciiMetadataKey, AsciiMetadataValue, MetadataMap};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Request};
+
+/// Size of the chunks objects are streamed to the server in by default.
+pub const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+/// Object to upload: the debuginfo or executable of a build ID.
+#[derive(Debug, Clone)]
+pub struct Object {
+    pub build_id: String,
+    pub build_id_type: BuildIdType,
+    pub r#type: DebuginfoType,
+    pub data: Vec<u8>,
+}
+
+/// Outcome of uploading an object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UploadOutcome {
+    /// The object was uploaded and stored by the server.
+    Uploaded { upload_id: String, size: u64 },
+    /// The server doesn't need the object, for `reason`.
+    Skipped { reason: String },
+}
+
+/// DebuginfoClient uploads debuginfo to the debuginfo service of an
+/// evprofiler or Parca server.
+#[derive(Debug, Clone)]
+pub struct DebuginfoClient {
+    client: DebuginfoServiceClient<Channel>,
+    chunk_size: usize,
+    metadata: MetadataMap,
+}
+
+impl DebuginfoClient {
+    /// Connects to the server at `addr`, like `http://localhost:3333`.
+    pub async fn connect(addr: impl Into<String>) -> anyhow::Result<Self> {
+        let addr = addr.into();
+        let channel = Endpoint::from_shared(addr.clone())
+            .with_context(|| format!("invalid server address {}", addr))?
+            .connect()
+            .await
+            .with_context(|| format!("connecting to {}", addr))?;
+        Ok(Self::new(channel))
+    }
+
+    /// Creates a client on an existing channel, which may carry credentials.
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            client: DebuginfoServiceClient::new(channel),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            metadata: MetadataMap::new(),
+        }
+    }
+
+    /// Streams objects to the server in chunks of `chunk_size` bytes.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Sends `key: value` metadata with every request, like an agent ID or
+    /// credentials.
+    pub fn with_metadata(mut self, key: &str, value: &str) -> anyhow::Result<Self> {
+        let key: AsciiMetadataKey = key.parse()?;
+        let value: AsciiMetadataValue = value.parse()?;
+        self.metadata.insert(key, value);
+        Ok(self)
+    }
+
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        *request.metadata_mut() = self.metadata.clone();
+        request
+    }
+
+    /// Asks the server whether it needs the object with `hash`.
+    pub async fn should_initiate_upload(
+        &mut self,
+        object: &Object,
+        hash: &str,
+        force: bool,
+    ) -> anyhow::Result<ShouldInitiateUploadResponse> {
+        let response = self
+            .client
+            .should_initiate_upload(self.request(ShouldInitiateUploadRequest {
+                build_id: object.build_id.clone(),
+                hash: hash.to_string(),
+                force,
+                r#type: object.r#type.into(),
+                build_id_type: object.build_id_type.into(),
+            }))
+            .await
+            .context("ShouldInitiateUpload")?;
+        Ok(response.into_inner())
+    }
+
+    /// Starts an upload of the object and returns how to upload it.
+    pub async fn initiate_upload(
+        &mut self,
+        object: &Object,
+        hash: &str,
+        force: bool,
+    ) -> anyhow::Result<UploadInstructions> {
+        let response = self
+            .client
+            .initiate_upload(self.request(InitiateUploadRequest {
+                build_id: object.build_id.clone(),
+                size: object.data.len() as i64,
+                hash: hash.to_string(),
+                force,
+                r#type: object.r#type.into(),
+                build_id_type: object.build_id_type.into(),
+            }))
+            .await
+            .context("InitiateUpload")?;
+        response
+            .into_inner()
+            .upload_instructions
+            .context("InitiateUpload returned no upload instructions")
+    }
+
+    /// Uploads the data of an initiated upload with the strategy the server
+    /// chose and returns the number of bytes uploaded.
+    pub async fn upload_data(
+        &mut self,
+        instructions: &UploadInstructions,
+        data: &[u8],
+    ) -> anyhow::Result<u64> {
+        match instructions.upload_strategy() {
+            UploadStrategy::Grpc => {
+                let mut messages = vec![UploadRequest {
+                    data: Some(upload_request::Data::Info(UploadInfo {
+                        build_id: instructions.build_id.clone(),
+                        upload_id: instructions.upload_id.clone(),
+                        r#type: instructions.r#type,
+                    })),
+                }];
+                messages.extend(data.chunks(self.chunk_size).map(|chunk| UploadRequest {
+                    data: Some(upload_request::Data::ChunkData(chunk.to_vec())),
+                }));
+                let response = self
+                    .client
+                    .upload(self.request(tokio_stream::iter(messages)))
+                    .await
+                    .context("Upload")?;
+                Ok(response.into_inner().size)
+            }
+            UploadStrategy::SignedUrl => {
+                let url = instructions.signed_url.clone();
+                let data = data.to_vec();
+                let size = data.len() as u64;
+                tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+                    ureq::put(&url).send_bytes(&data)?;
+                    Ok(())
+                })
+                .await?
+                .context("uploading to the signed URL")?;
+                Ok(size)
+            }
+            UploadStrategy::Unspecified => bail!("the server returned no upload strategy"),
+        }
+    }
+
+    /// Marks an upload as finished, which makes the server keep the object.
+    pub async fn mark_upload_finished(
+        &mut self,
+        instructions: &UploadInstructions,
+    ) -> anyhow::Result<()> {
+        self.client
+            .mark_upload_finished(self.request(MarkUploadFinishedRequest {
+                build_id: instructions.build_id.clone(),
+                upload_id: instructions.upload_id.clone(),
+                r#type: instructions.r#type,
+            }))
+            .await
+            .context("MarkUploadFinished")?;
+        Ok(())
+    }
+
+    /// Tells the server that the debuginfo of the build ID can't be
+    /// extracted, so that it stops asking for it for a while.
+    pub async fn mark_upload_unavailable(
+        &mut self,
+        build_id: &str,
+        r#type: DebuginfoType,
+        reason: &str,
+    ) -> anyhow::Result<()> {
+        self.client
+            .mark_upload_unavailable(self.request(MarkUploadUnavailableRequest {
+                build_id: build_id.to_string(),
+                r#type: r#type.into(),
+                reason: reason.to_string(),
+            }))
+            .await
+            .context("MarkUploadUnavailable")?;
+        Ok(())
+    }
+
+    /// Uploads the object if the server needs it, going through every step
+    /// of the protocol. With `force`, the object is uploaded even if the
+    /// server already has one for the build ID.
+    pub async fn upload(&mut self, object: &Object, force: bool) -> anyhow::Result<UploadOutcome> {
+        let hash = hash(&object.data);
+        let should = self.should_initiate_upload(object, &hash, force).await?;
+        if !should.should_initiate_upload {
+            return Ok(UploadOutcome::Skipped {
+                reason: should.reason,
+            });
+        }
+        let instructions = match self.initiate_upload(object, &hash, force).await {
+            Ok(instructions) => instructions,
+            Err(e) => match e.downcast_ref::<tonic::Status>() {
+                // Another agent uploaded the same object in the meantime.
+                Some(status) if status.code() == Code::AlreadyExists => {
+                    return Ok(UploadOutcome::Skipped {
+                        reason: status.message().to_string(),
+                    })
+                }
+                _ => return Err(e),
+            },
+        };
+        let size = self.upload_data(&instructions, &object.data).await?;
+        self.mark_upload_finished(&instructions).await?;
+        Ok(UploadOutcome::Uploaded {
+            upload_id: instructions.upload_id,
+            size,
+        })
+    }
+}
+
+/// Returns the hash the server compares objects by, the hex encoded SHA-256
+/// of the data.
+pub fn hash(data: &[u8]) -> String {
+    digest(&SHA256, data)
+        .as_ref()
+        .iter()
+        .fold(String::new(), |mut hash, byte| {
+            let _ = write!(hash, "{:02x}", byte);
+            hash
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debuginfopb::debuginfo_service_server::{DebuginfoService, DebuginfoServiceServer};
+    use crate::debuginfopb::{
+        InitiateUploadResponse, MarkUploadFinishedResponse, MarkUploadUnavailableResponse,
+        UploadResponse,
+    };
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tonic::{Request, Response, Status, Streaming};
+
+    /// Keeps objects by build ID, and uploads in progress by upload ID.
+    #[derive(Debug, Default)]
+    struct FakeServer {
+        objects: Mutex<HashMap<String, (String, Vec<u8>)>>,
+        uploads: Mutex<HashMap<String, (String, Vec<u8>)>>,
+        hashes: Mutex<HashMap<String, String>>,
+    }
+
+    #[tonic::async_trait]
+    impl DebuginfoService for Arc<FakeServer> {
+        async fn upload(
+            &self,
+            request: Request<Streaming<UploadRequest>>,
+        ) -> Result<Response<UploadResponse>, Status> {
+            let mut stream = request.into_inner();
+            let Some(upload_request::Data::Info(info)) = stream.message().await?.unwrap().data
+            else {
+                return Err(Status::invalid_argument("missing upload info"));
+            };
+            let mut data = vec![];
+            while let Some(message) = stream.message().await? {
+                let Some(upload_request::Data::ChunkData(chunk)) = message.data else {
+                    return Err(Status::invalid_argument("missing chunk"));
+                };
+                data.extend(chunk);
+            }
+            let size = data.len() as u64;
+            self.uploads
+                .lock()
+                .unwrap()
+                .insert(info.upload_id, (info.build_id.clone(), data));
+            Ok(Response::new(UploadResponse {
+                build_id: info.build_id,
+                size,
+            }))
+        }
+
+        async fn should_initiate_upload(
+            &self,
+            request: Request<ShouldInitiateUploadRequest>,
+        ) -> Result<Response<ShouldInitiateUploadResponse>, Status> {
+            let request = request.into_inner();
+            let objects = self.objects.lock().unwrap();
+            let equal = objects
+                .get(&request.build_id)
+                .is_some_and(|(hash, _)| *hash == request.hash);
+            Ok(Response::new(ShouldInitiateUploadResponse {
+                should_initiate_upload: !equal || request.force,
+                reason: if equal { "equal" } else { "new" }.into(),
+            }))
+        }
+
+        async fn initiate_upload(
+            &self,
+            request: Request<InitiateUploadRequest>,
+        ) -> Result<Response<InitiateUploadResponse>, Status> {
+            let request = request.into_inner();
+            let upload_id = format!("upload-{}", request.build_id);
+            self.hashes
+                .lock()
+                .unwrap()
+                .insert(upload_id.clone(), request.hash);
+            Ok(Response::new(InitiateUploadResponse {
+                upload_instructions: Some(UploadInstructions {
+                    build_id: request.build_id,
+                    upload_id,
+                    upload_strategy: UploadStrategy::Grpc.into(),
+                    signed_url: String::new(),
+                    r#type: request.r#type,
+                }),
+            }))
+        }
+
+        async fn mark_upload_finished(
+            &self,
+            request: Request<MarkUploadFinishedRequest>,
+        ) -> Result<Response<MarkUploadFinishedResponse>, Status> {
+            let request = request.into_inner();
+            let (build_id, data) = self
+                .uploads
+                .lock()
+                .unwrap()
+                .remove(&request.upload_id)
+                .ok_or_else(|| Status::failed_precondition("not uploaded"))?;
+            let hash = self.hashes.lock().unwrap()[&request.upload_id].clone();
+            self.objects.lock().unwrap().insert(build_id, (hash, data));
+            Ok(Response::new(MarkUploadFinishedResponse {}))
+        }
+
+        async fn mark_upload_unavailable(
+            &self,
+            _: Request<MarkUploadUnavailableRequest>,
+        ) -> Result<Response<MarkUploadUnavailableResponse>, Status> {
+            Ok(Response::new(MarkUploadUnavailableResponse::default()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let server = Arc::new(FakeServer::default());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(DebuginfoServiceServer::new(Arc::clone(&server)))
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+
+        let mut client = DebuginfoClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap()
+            .with_chunk_size(3);
+        let object = Object {
+            build_id: "abcd".into(),
+            build_id_type: BuildIdType::Gnu,
+            r#type: DebuginfoType::DebuginfoUnspecified,
+            data: b"debuginfo".to_vec(),
+        };
+        assert_eq!(
+            client.upload(&object, false).await.unwrap(),
+            UploadOutcome::Uploaded {
+                upload_id: "upload-abcd".into(),
+                size: 9
+            }
+        );
+        assert_eq!(
+            server.objects.lock().unwrap()["abcd"],
+            (hash(b"debuginfo"), b"debuginfo".to_vec())
+        );
+        assert_eq!(
+            client.upload(&object, false).await.unwrap(),
+            UploadOutcome::Skipped {
+                reason: "equal".into()
+            }
+        );
+        assert!(matches!(
+            client.upload(&object, true).await.unwrap(),
+            UploadOutcome::Uploaded { .. }
+        ));
+    }
+}
@@ -0,0 +1,229 @@
+use crate::alerting::AlertingConfig;
+use crate::debuginfo_store::{BucketRouteConfig, DebugInfod, ObjectLayout};
+use crate::flags::Flags;
+use crate::normalizer::LabelScrubbing;
+use crate::principal::TrustedProxies;
+use crate::scrape::ScrapeConfig;
+use crate::storage::Keyring;
+use crate::webhooks::WebhookConfig;
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Config is the configuration file of the server, for settings that don't
+/// fit command line flags.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Jobs whose targets are scraped periodically.
+    #[serde(default)]
+    pub scrape_configs: Vec<ScrapeConfig>,
+    /// Endpoints notified of debuginfo lifecycle events.
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    /// Alerting rules evaluated over the stored samples.
+    #[serde(default)]
+    pub alerting: AlertingConfig,
+    /// Buckets the debuginfo of some build IDs is stored in instead of the
+    /// default one, the first matching route winning.
+    #[serde(default)]
+    pub debuginfo_buckets: Vec<BucketRouteConfig>,
+}
+
+impl Config {
+    /// Reads and validates the YAML configuration file at `path`.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let config = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config file {}", path.display()))?;
+        Self::parse(&config).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    pub fn parse(config: &str) -> anyhow::Result<Self> {
+        let config: Config = serde_yaml::from_str(config)?;
+        match config.problems().into_iter().next() {
+            Some(problem) => Err(problem),
+            None => Ok(config),
+        }
+    }
+
+    /// Returns every problem of the configuration, rather than the first.
+    fn problems(&self) -> Vec<anyhow::Error> {
+        let mut problems = vec![];
+        let mut jobs = HashSet::new();
+        for scrape_config in self.scrape_configs.iter() {
+            if let Err(e) = scrape_config.validate() {
+                problems.push(e.context(format!("scrape config {:?}", scrape_config.job_name)));
+            }
+            if !jobs.insert(&scrape_config.job_name) {
+                problems.push(anyhow!(
+                    "duplicate scrape config {:?}",
+                    scrape_config.job_name
+                ));
+            }
+        }
+        for webhook in self.webhooks.iter() {
+            if let Err(e) = webhook.validate() {
+                problems.push(e.context(format!("webhook {:?}", webhook.url)));
+            }
+        }
+        if let Err(e) = self.alerting.validate() {
+            problems.push(e.context("alerting"));
+        }
+        let mut buckets = HashSet::new();
+        for route in self.debuginfo_buckets.iter() {
+            if let Err(e) = route.validate() {
+                problems.push(e.context(format!("debuginfo bucket {:?}", route.name)));
+            }
+            if !buckets.insert(&route.name) {
+                problems.push(anyhow!("duplicate debuginfo bucket {:?}", route.name));
+            }
+        }
+        problems
+    }
+}
+
+/// Checks the configuration file at `path` and the flags the server would
+/// start with, returning every problem found, so that configurations can be
+/// validated before they are deployed.
+pub fn check(flags: &Flags, path: &Path) -> Vec<anyhow::Error> {
+    let mut problems = vec![];
+    match std::fs::read_to_string(path) {
+        Ok(config) => match serde_yaml::from_str::<Config>(&config) {
+            Ok(config) => problems.extend(config.problems()),
+            Err(e) => problems.push(e.into()),
+        },
+        Err(e) => problems.push(anyhow!(e).context(format!("reading {}", path.display()))),
+    }
+    let mut problems: Vec<anyhow::Error> = problems
+        .into_iter()
+        .map(|e| e.context(format!("config file {}", path.display())))
+        .collect();
+
+    if let Err(e) = ObjectLayout::new(&flags.debuginfo_object_path) {
+        problems.push(e.context("--debuginfo-object-path"));
+    }
+    if !flags.debuginfod_disabled {
+        if let Err(e) = DebugInfod::from_env(flags.debuginfod_client_cache) {
+            problems.push(e.context("debuginfod environment"));
+        }
+    }
+    if let Err(e) = LabelScrubbing::new(flags.scrub_labels.clone())
+        .with_hash_key_file(flags.scrub_hash_key_file.as_deref())
+    {
+        problems.push(e.context("--scrub-labels"));
+    }
+    match Keyring::load(flags.encryption_keys_file.as_deref()) {
+        Ok(None) if flags.encryption_allow_plaintext => {
+            problems.push(anyhow!(
+                "--encryption-allow-plaintext requires encryption keys"
+            ));
+        }
+        Ok(_) => {}
+        Err(e) => problems.push(e.context("encryption keys")),
+    }
+    if let Err(e) = crate::http::http2::validate(flags) {
+        problems.push(e);
+    }
+    if let Err(e) = TrustedProxies::new(
+        &flags.trusted_proxies,
+        &flags.client_ip_header,
+        &flags.client_identity_header,
+    ) {
+        problems.push(e.context("trusted proxies"));
+    }
+    for (flag, dir) in [
+        ("--debuginfo-dir", &flags.debuginfo_dir),
+        ("--scrape-state-dir", &flags.scrape_state_dir),
+    ] {
+        if let Some(dir) = dir {
+            if let Err(e) = check_dir(dir) {
+                problems.push(e.context(format!("{} {}", flag, dir.display())));
+            }
+        }
+    }
+    problems
+}
+
+/// Checks that `dir` is a writable directory, or can be created.
+fn check_dir(dir: &Path) -> anyhow::Result<()> {
+    match std::fs::metadata(dir) {
+        Ok(metadata) if !metadata.is_dir() => Err(anyhow!("not a directory")),
+        Ok(metadata) if metadata.permissions().readonly() => Err(anyhow!("not writable")),
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => match dir.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => {
+                check_dir(parent).context("can't be created")
+            }
+            _ => Ok(()),
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Config::parse("{}").unwrap(), Config::default());
+
+        let config = Config::parse(
+            "scrape_configs:\n  - job_name: a\n  - job_name: b\n    profiles: [heap]\n",
+        )
+        .unwrap();
+        assert_eq!(config.scrape_configs.len(), 2);
+
+        let e = Config::parse("scrape_configs:\n  - job_name: a\n  - job_name: a\n").unwrap_err();
+        assert!(e.to_string().contains("duplicate scrape config"));
+        let e = Config::parse("scrape_configs:\n  - job_name: a\n    profiles: []\n").unwrap_err();
+        assert_eq!(
+            format!("{:#}", e),
+            "scrape config \"a\": profiles must not be empty"
+        );
+
+        let bucket =
+            "  - name: private\n    directory: /tmp/private\n    build_id_prefixes: [ab]\n";
+        let config = Config::parse(&format!("debuginfo_buckets:\n{}", bucket)).unwrap();
+        assert_eq!(config.debuginfo_buckets[0].build_id_prefixes, ["ab"]);
+        let e = Config::parse(&format!("debuginfo_buckets:\n{}{}", bucket, bucket)).unwrap_err();
+        assert!(e.to_string().contains("duplicate debuginfo bucket"));
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        std::fs::write(
+            &path,
+            "scrape_configs:\n  - job_name: a\n    profiles: []\n  - job_name: a\n",
+        )
+        .unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+
+        let mut flags = Flags::parse_from(["evprofiler", "--debuginfod-disabled"]);
+        flags.debuginfo_object_path = "{build_id".into();
+        flags.debuginfo_dir = Some(dir.path().join("new"));
+        flags.scrape_state_dir = Some(file.join("state"));
+        let problems: Vec<String> = check(&flags, &path)
+            .iter()
+            .map(|e| format!("{:#}", e))
+            .collect();
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert!(problems[0].ends_with("scrape config \"a\": profiles must not be empty"));
+        assert!(problems[1].ends_with("duplicate scrape config \"a\""));
+        assert!(problems[2].starts_with("--debuginfo-object-path: unterminated placeholder"));
+        assert!(problems[3].starts_with("--scrape-state-dir"));
+
+        std::fs::write(&path, "scrape_configs: []\n").unwrap();
+        flags.debuginfo_object_path = Flags::parse_from(["evprofiler"]).debuginfo_object_path;
+        flags.scrape_state_dir = None;
+        assert!(check(&flags, &path).is_empty());
+        assert!(check(&flags, &dir.path().join("missing.yaml"))[0]
+            .to_string()
+            .starts_with("config file"));
+    }
+}
@@ -0,0 +1,140 @@
+use anyhow::bail;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const MAGIC: &[u8; 4] = b"EVBF";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 4 + 1 + 4 + 8;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// BloomFilter is a lock-free bloom filter over metadata keys. It answers
+/// "definitely never seen" without touching the metadata store. Hashes are
+/// stable across processes, so the filter can be persisted.
+#[derive(Debug)]
+pub struct BloomFilter {
+    words: Vec<AtomicU64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Creates a filter sized for `expected_items` with the given false
+    /// positive rate.
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let n = expected_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-n * false_positive_rate.ln() / (ln2 * ln2)).ceil() as usize;
+        let words = bits.div_ceil(64).max(1);
+        let hashes = ((words * 64) as f64 / n * ln2).round().clamp(1.0, 16.0) as u32;
+
+        Self {
+            words: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            hashes,
+        }
+    }
+
+    pub fn insert(&self, key: &str) {
+        for bit in self.bits(key) {
+            self.words[bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+    }
+
+    /// Returns false if the key was never inserted. May return true for keys
+    /// that weren't inserted.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bits(key)
+            .all(|bit| self.words[bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+
+    /// Merges the bits of `other` into this filter. Both filters must have
+    /// the same shape.
+    pub fn union(&self, other: &BloomFilter) -> anyhow::Result<()> {
+        if self.words.len() != other.words.len() || self.hashes != other.hashes {
+            bail!("bloom filters have different shapes");
+        }
+        for (w, o) in self.words.iter().zip(other.words.iter()) {
+            w.fetch_or(o.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.words.len() * 8);
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        buf.extend_from_slice(&self.hashes.to_le_bytes());
+        buf.extend_from_slice(&(self.words.len() as u64).to_le_bytes());
+        for w in self.words.iter() {
+            buf.extend_from_slice(&w.load(Ordering::Relaxed).to_le_bytes());
+        }
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < HEADER_LEN || &data[..4] != MAGIC {
+            bail!("invalid bloom filter header");
+        }
+        if data[4] != VERSION {
+            bail!("unsupported bloom filter version {}", data[4]);
+        }
+        let hashes = u32::from_le_bytes(data[5..9].try_into()?);
+        let words = u64::from_le_bytes(data[9..17].try_into()?) as usize;
+        let body = &data[HEADER_LEN..];
+        if hashes == 0 || words == 0 || body.len() != words * 8 {
+            bail!("invalid bloom filter size");
+        }
+
+        Ok(Self {
+            words: body
+                .chunks_exact(8)
+                .map(|c| AtomicU64::new(u64::from_le_bytes(c.try_into().unwrap())))
+                .collect(),
+            hashes,
+        })
+    }
+
+    /// Bit positions of the key, derived by double hashing.
+    fn bits(&self, key: &str) -> impl Iterator<Item = usize> {
+        let h1 = fnv1a(FNV_OFFSET_BASIS, key.as_bytes());
+        let h2 = fnv1a(h1, key.as_bytes()) | 1;
+        let nbits = (self.words.len() * 64) as u64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+    }
+}
+
+fn fnv1a(seed: u64, data: &[u8]) -> u64 {
+    data.iter().fold(seed, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let filter = BloomFilter::new(1_000, 0.01);
+        for i in 0..1_000 {
+            filter.insert(&format!("build-{}/metadata", i));
+        }
+        assert!((0..1_000).all(|i| filter.may_contain(&format!("build-{}/metadata", i))));
+
+        let false_positives = (0..10_000)
+            .filter(|i| filter.may_contain(&format!("other-{}/metadata", i)))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        let decoded = BloomFilter::decode(&filter.encode()).unwrap();
+        assert!(decoded.may_contain("build-42/metadata"));
+        assert_eq!(decoded.encode(), filter.encode());
+
+        let empty = BloomFilter::new(1_000, 0.01);
+        assert!(!empty.may_contain("build-42/metadata"));
+        empty.union(&decoded).unwrap();
+        assert!(empty.may_contain("build-42/metadata"));
+
+        assert!(BloomFilter::decode(b"nope").is_err());
+        assert!(empty.union(&BloomFilter::new(10, 0.5)).is_err());
+    }
+}
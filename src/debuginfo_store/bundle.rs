@@ -0,0 +1,249 @@
+use super::metadata::{self, MetadataStore};
+use super::{BucketRoutes, ObjectLayout};
+use crate::debuginfopb::{debuginfo::Source, Debuginfo, DebuginfoType};
+use crate::storage;
+use anyhow::{bail, Context};
+use object_store::{path::Path, ObjectStore};
+use prost::Message;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+const OBJECTS_PREFIX: &str = "objects/";
+const METADATA_PREFIX: &str = "metadata/";
+
+const DEBUGINFO_TYPES: [DebuginfoType; 4] = [
+    DebuginfoType::DebuginfoUnspecified,
+    DebuginfoType::Executable,
+    DebuginfoType::Sources,
+    DebuginfoType::Symbols,
+];
+
+/// Writes a tar bundle containing the metadata and uploaded objects of the
+/// given build IDs. Metadata is exported as stored, so quality flags and
+/// upload hashes are preserved. Objects are read from the bucket recorded in
+/// their metadata and stored by upload ID, independent of the bucket layout.
+pub async fn export<W: Write>(
+    buckets: &BucketRoutes,
+    layout: &ObjectLayout,
+    build_ids: &[String],
+    out: W,
+) -> anyhow::Result<()> {
+    let mut builder = tar::Builder::new(out);
+    let bucket = buckets.default_bucket().as_ref();
+
+    for build_id in build_ids {
+        let mut found = false;
+        for debuginfo_type in DEBUGINFO_TYPES.iter() {
+            let path = MetadataStore::get_object_path(build_id, debuginfo_type);
+            let debuginfo = match metadata::read_metadata(bucket, &Path::from(path.as_str())).await
+            {
+                Ok(debuginfo) => debuginfo,
+                Err(e) => match e.downcast_ref::<object_store::Error>() {
+                    Some(object_store::Error::NotFound { .. }) => continue,
+                    _ => return Err(e),
+                },
+            };
+            found = true;
+
+            if debuginfo.source() == Source::Upload {
+                if let Some(upload) = &debuginfo.upload {
+                    let data = buckets
+                        .bucket(&debuginfo)?
+                        .get(&layout.object_path(&debuginfo)?)
+                        .await
+                        .with_context(|| format!("reading debuginfo object of {}", build_id))?
+                        .bytes()
+                        .await?;
+                    append(
+                        &mut builder,
+                        &format!("{}{}", OBJECTS_PREFIX, upload.id),
+                        &data,
+                    )?;
+                }
+            }
+
+            append(
+                &mut builder,
+                &format!("{}{}", METADATA_PREFIX, path),
+                &debuginfo.encode_to_vec(),
+            )?;
+        }
+
+        if !found {
+            bail!("no debuginfo metadata found for build_id {}", build_id);
+        }
+    }
+
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// Imports a bundle written by `export` into the buckets, placing objects
+/// according to `layout` in the bucket their build ID is routed to. Objects
+/// are written before the metadata referencing them, so an interrupted
+/// import never leaves metadata pointing at a missing object.
+pub async fn import<R: Read>(
+    buckets: &BucketRoutes,
+    layout: &ObjectLayout,
+    bundle: R,
+) -> anyhow::Result<usize> {
+    let mut archive = tar::Archive::new(bundle);
+    let mut objects = HashMap::new();
+    let mut metadata = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+
+        if let Some(path) = name.strip_prefix(OBJECTS_PREFIX) {
+            objects.insert(path.to_string(), data);
+        } else if let Some(path) = name.strip_prefix(METADATA_PREFIX) {
+            if !metadata::is_metadata_path(path) {
+                bail!("invalid metadata entry {} in bundle", name);
+            }
+            let debuginfo = Debuginfo::decode(data.as_slice())
+                .with_context(|| format!("decoding metadata {}", path))?;
+            metadata.push((path.to_string(), debuginfo));
+        } else {
+            bail!("unexpected entry {} in bundle", name);
+        }
+    }
+
+    for (_, debuginfo) in metadata.iter_mut() {
+        if debuginfo.source() != Source::Upload {
+            continue;
+        }
+        let upload_id = match debuginfo.upload.as_mut() {
+            Some(upload) => {
+                // Objects are placed in the bucket of the routes imported
+                // into, whichever bucket they were exported from.
+                upload.bucket = buckets.route(&debuginfo.build_id).to_string();
+                upload.id.clone()
+            }
+            None => continue,
+        };
+        let data = match objects.remove(&upload_id) {
+            Some(data) => data,
+            None => bail!("bundle is missing the object of upload {}", upload_id),
+        };
+        let path = layout.object_path(debuginfo)?;
+        let checksum = debuginfo
+            .upload
+            .as_ref()
+            .map_or("", |u| u.checksum.as_str());
+        storage::verify(&path, checksum, &data)?;
+        buckets.bucket(debuginfo)?.put(&path, data.into()).await?;
+    }
+
+    let imported = metadata.len();
+    for (path, debuginfo) in metadata {
+        metadata::write_metadata(buckets.default_bucket().as_ref(), &path, &debuginfo).await?;
+    }
+
+    Ok(imported)
+}
+
+fn append<W: Write>(builder: &mut tar::Builder<W>, path: &str, data: &[u8]) -> anyhow::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, path, data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debuginfopb::{debuginfo_upload, DebuginfoQuality, DebuginfoUpload};
+    use object_store::memory::InMemory;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_export_import_roundtrip() {
+        // Staging stores the objects of the build ID in a routed bucket.
+        let (staging, private): (Arc<dyn ObjectStore>, Arc<dyn ObjectStore>) =
+            (Arc::new(InMemory::new()), Arc::new(InMemory::new()));
+        let staging_buckets = BucketRoutes::new(Arc::clone(&staging)).with_route(
+            "private",
+            vec!["ab".into()],
+            Arc::clone(&private),
+        );
+        let mut debuginfo = Debuginfo {
+            build_id: "abcd".into(),
+            r#type: DebuginfoType::DebuginfoUnspecified.into(),
+            source: Source::Upload.into(),
+            upload: Some(DebuginfoUpload {
+                id: "upload-1".into(),
+                hash: "hash".into(),
+                started_at: None,
+                finished_at: None,
+                state: debuginfo_upload::State::Uploaded.into(),
+                size: 3,
+                checksum: crate::storage::checksum(b"elf"),
+                bucket: "private".into(),
+            }),
+            quality: Some(DebuginfoQuality {
+                not_valid_elf: false,
+                has_dwarf: true,
+                has_go_pclntab: false,
+                has_symtab: true,
+                has_dynsym: false,
+                symbolization_failed: false,
+                corrupted: false,
+            }),
+            debuginfod_servers: vec![],
+        };
+        private
+            .put(&Path::from("upload-1"), b"elf".to_vec().into())
+            .await
+            .unwrap();
+        metadata::write_metadata(staging.as_ref(), "abcd/metadata", &debuginfo)
+            .await
+            .unwrap();
+
+        let mut bundle = Vec::new();
+        export(
+            &staging_buckets,
+            &ObjectLayout::default(),
+            &["abcd".into()],
+            &mut bundle,
+        )
+        .await
+        .unwrap();
+
+        // Production uses a different object layout than staging, and
+        // stores every object in its default bucket.
+        let production: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let layout = ObjectLayout::new("{buildid}/{hash}").unwrap();
+        assert_eq!(
+            import(
+                &BucketRoutes::new(Arc::clone(&production)),
+                &layout,
+                bundle.as_slice()
+            )
+            .await
+            .unwrap(),
+            1
+        );
+
+        let imported = metadata::read_metadata(production.as_ref(), &Path::from("abcd/metadata"))
+            .await
+            .unwrap();
+        debuginfo.upload.as_mut().unwrap().bucket.clear();
+        assert_eq!(imported, debuginfo);
+        let object = production.get(&Path::from("abcd/hash")).await.unwrap();
+        assert_eq!(object.bytes().await.unwrap().as_ref(), b"elf");
+
+        assert!(export(
+            &staging_buckets,
+            &ObjectLayout::default(),
+            &["missing".into()],
+            Vec::new()
+        )
+        .await
+        .is_err());
+    }
+}
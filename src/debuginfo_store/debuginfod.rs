@@ -1,43 +1,164 @@
+use super::MetadataStore;
+use crate::debuginfopb::DebuginfodValidators;
+use crate::storage;
 use anyhow::{bail, Context};
-use object_store::ObjectStore;
+use chrono::{DateTime, Utc};
+use object_store::{path::Path, ObjectStore};
+use prost_types::Timestamp;
+use std::io::Read;
 use std::{sync::Arc, time::Duration};
 use tonic::Status;
 use url::Url;
 
+/// Server used when DEBUGINFOD_URLS is not set.
+const DEFAULT_SERVER: &str = "https://debuginfod.elfutils.org/";
+
 #[derive(Debug)]
 pub struct DebugInfod {
     pub upstream_servers: Vec<Url>,
-    bucket: Arc<dyn ObjectStore>,
+    /// Where downloaded objects are cached, if anywhere. Without a cache,
+    /// every lookup downloads the object again.
+    cache: Option<Arc<dyn ObjectStore>>,
     client: ureq::Agent,
+    /// Maximum size of a downloaded debuginfo object, if any.
+    max_size: Option<u64>,
+    /// Where the cache validators of downloaded objects are kept. Without it,
+    /// cached objects are never revalidated.
+    metadata: Option<MetadataStore>,
 }
 
 impl Clone for DebugInfod {
     fn clone(&self) -> Self {
         Self {
             upstream_servers: self.upstream_servers.clone(),
-            bucket: Arc::clone(&self.bucket),
+            cache: self.cache.clone(),
             client: self.client.clone(),
+            max_size: self.max_size,
+            metadata: self.metadata.clone(),
         }
     }
 }
 
 impl Default for DebugInfod {
     fn default() -> Self {
-        let url = Url::parse("https://debuginfod.elfutils.org/").unwrap();
+        let url = Url::parse(DEFAULT_SERVER).unwrap();
 
         Self {
             upstream_servers: vec![url],
-            bucket: Arc::new(crate::storage::new_memory_bucket()),
+            cache: None,
             client: ureq::AgentBuilder::new()
                 .timeout_read(Duration::from_secs(5))
                 .timeout_write(Duration::from_secs(5))
                 .redirects(2)
                 .build(),
+            max_size: None,
+            metadata: None,
         }
     }
 }
 
 impl DebugInfod {
+    /// Configures the client from the environment variables of the elfutils
+    /// debuginfod client:
+    ///
+    /// - `DEBUGINFOD_URLS`: space separated upstream servers. Set but empty
+    ///   disables lookups.
+    /// - `DEBUGINFOD_TIMEOUT`: seconds to wait for a server to connect and to
+    ///   send data. Zero waits forever.
+    /// - `DEBUGINFOD_MAXSIZE`: maximum size in bytes of a downloaded object.
+    /// - `DEBUGINFOD_CACHE_PATH`: cache directory, used with `client_cache`.
+    ///
+    /// With `client_cache`, downloads are cached on disk in the layout of the
+    /// elfutils client (`<cache>/<build id>/debuginfo`), which defaults to
+    /// `~/.cache/debuginfod_client`, so the cache is shared with gdb and
+    /// friends.
+    pub fn from_env(client_cache: bool) -> anyhow::Result<Self> {
+        Self::from_vars(|name| std::env::var(name).ok(), client_cache)
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>, client_cache: bool) -> anyhow::Result<Self> {
+        let mut debuginfod = Self::default();
+
+        if let Some(urls) = var("DEBUGINFOD_URLS") {
+            debuginfod.upstream_servers = urls
+                .split_whitespace()
+                .map(|url| {
+                    // Servers are joined with relative paths, which would
+                    // replace their last path segment without the slash.
+                    let url = if url.ends_with('/') {
+                        url.to_string()
+                    } else {
+                        format!("{}/", url)
+                    };
+                    Url::parse(&url)
+                        .with_context(|| format!("invalid DEBUGINFOD_URLS entry {}", url))
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+
+        if let Some(timeout) = var("DEBUGINFOD_TIMEOUT") {
+            let secs: u64 = timeout
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid DEBUGINFOD_TIMEOUT {:?}", timeout))?;
+            let timeout = (secs > 0).then(|| Duration::from_secs(secs));
+            let mut builder = ureq::AgentBuilder::new()
+                .timeout_write(Duration::from_secs(5))
+                .redirects(2);
+            if let Some(timeout) = timeout {
+                builder = builder.timeout_connect(timeout).timeout_read(timeout);
+            }
+            debuginfod.client = builder.build();
+        }
+
+        if let Some(max_size) = var("DEBUGINFOD_MAXSIZE") {
+            let max_size: u64 = max_size
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid DEBUGINFOD_MAXSIZE {:?}", max_size))?;
+            debuginfod.max_size = (max_size > 0).then_some(max_size);
+        }
+
+        if client_cache {
+            let dir = match var("DEBUGINFOD_CACHE_PATH") {
+                Some(dir) => std::path::PathBuf::from(dir),
+                None => match var("XDG_CACHE_HOME") {
+                    Some(cache) => std::path::PathBuf::from(cache),
+                    None => std::path::PathBuf::from(
+                        var("HOME").context("HOME is not set, set DEBUGINFOD_CACHE_PATH")?,
+                    )
+                    .join(".cache"),
+                }
+                .join("debuginfod_client"),
+            };
+            log::info!("Caching debuginfod downloads in {}", dir.display());
+            debuginfod.cache = Some(Arc::new(crate::storage::new_local_bucket(&dir)?));
+        }
+
+        Ok(debuginfod)
+    }
+
+    /// Returns a client without any upstream servers, so no network calls are
+    /// ever made.
+    pub fn disabled() -> Self {
+        Self {
+            upstream_servers: vec![],
+            ..Self::default()
+        }
+    }
+
+    /// Keeps the cache validators of downloaded objects in the metadata
+    /// store, so cached objects are revalidated with conditional requests
+    /// once the server's Cache-Control max-age has passed.
+    pub fn with_metadata(mut self, metadata: MetadataStore) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.upstream_servers.is_empty()
+    }
+
     pub async fn exists(&self, build_id: &str) -> Vec<String> {
         let mut available_servers = vec![];
 
@@ -61,39 +182,302 @@ impl DebugInfod {
     ) -> anyhow::Result<Vec<u8>> {
         let url = upstream_server.join(format!("buildid/{}/debuginfo", build_id).as_str())?;
 
-        self.request(url).await
+        self.request(upstream_server, build_id, url).await
     }
 
-    async fn request(&self, url: Url) -> anyhow::Result<Vec<u8>> {
-        let path = object_store::path::Path::from(url.as_str());
-        let res = self.bucket.get(&path).await?.bytes().await?;
-        if res.is_empty() {
-            let response =
-                self.client.get(url.as_str()).call().map_err(|err| {
-                    Status::internal(format!("Failed to fetch debuginfo: {}", err))
-                })?;
+    /// Returns the cached copy of the object if there is one and it doesn't
+    /// need to be revalidated. Otherwise asks the server, conditionally if the
+    /// cached copy has validators, and caches what it returns.
+    async fn request(&self, server: &Url, build_id: &str, url: Url) -> anyhow::Result<Vec<u8>> {
+        let path = Path::from_iter([build_id, "debuginfo"]);
+        let cached = match self.cache.as_ref().map(|cache| cache.get(&path)) {
+            None => None,
+            Some(res) => match res.await {
+                Ok(res) => Some(res.bytes().await?.to_vec()),
+                Err(object_store::Error::NotFound { .. }) => None,
+                Err(e) => return Err(e.into()),
+            },
+        };
+        let validators = self
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.validators(build_id, server.as_str()));
+        // A corrupted copy is downloaded again, unconditionally.
+        let (cached, validators) = match (cached, validators) {
+            (Some(data), Some(v)) if storage::verify(&path, &v.checksum, &data).is_err() => {
+                log::warn!(
+                    "Cached debuginfo of {} is corrupted, downloading it again",
+                    build_id
+                );
+                (None, None)
+            }
+            other => other,
+        };
+
+        let now = Utc::now();
+        let mut request = self.client.get(url.as_str());
+        match (&cached, &validators) {
+            (Some(cached), None) => return Ok(cached.clone()),
+            (Some(cached), Some(validators)) if is_fresh(validators, now) => {
+                return Ok(cached.clone())
+            }
+            (Some(_), Some(validators)) => {
+                if !validators.etag.is_empty() {
+                    request = request.set("If-None-Match", &validators.etag);
+                }
+                if !validators.last_modified.is_empty() {
+                    request = request.set("If-Modified-Since", &validators.last_modified);
+                }
+            }
+            (None, _) => {}
+        }
+
+        let response = request
+            .call()
+            .map_err(|err| Status::internal(format!("Failed to fetch debuginfo: {}", err)))?;
+        let cache_control = CacheControl::parse(response.header("Cache-Control").unwrap_or(""));
+        match (response.status(), cached) {
+            (304, Some(cached)) => {
+                log::debug!("debuginfo of {} at {} is unchanged", build_id, server);
+                if let Some(validators) = validators {
+                    self.set_validators(
+                        build_id,
+                        DebuginfodValidators {
+                            max_age_seconds: cache_control.max_age(),
+                            ..validators
+                        },
+                        now,
+                    );
+                }
+                return Ok(cached);
+            }
+            (200, _) => {}
+            (status, _) => bail!("Failed to fetch debuginfo: {}", status),
+        }
 
-            if response.status() == 200 {
-                let mut content = Vec::new();
+        let validators = DebuginfodValidators {
+            server: server.to_string(),
+            etag: response.header("ETag").unwrap_or_default().to_string(),
+            last_modified: response
+                .header("Last-Modified")
+                .unwrap_or_default()
+                .to_string(),
+            validated_at: None,
+            max_age_seconds: cache_control.max_age(),
+            checksum: String::new(),
+        };
+        let announced = response
+            .header("Content-Length")
+            .and_then(|len| len.parse::<u64>().ok());
+        let mut content = Vec::new();
+        match self.max_size {
+            Some(max_size) => {
+                if announced.is_some_and(|len| len > max_size) {
+                    bail!(
+                        "debuginfo of {} bytes exceeds DEBUGINFOD_MAXSIZE of {} bytes",
+                        announced.unwrap_or_default(),
+                        max_size
+                    );
+                }
+                response
+                    .into_reader()
+                    .take(max_size + 1)
+                    .read_to_end(&mut content)
+                    .with_context(|| "Failed to read response from the debuginfod server")?;
+                if content.len() as u64 > max_size {
+                    bail!("debuginfo exceeds DEBUGINFOD_MAXSIZE of {} bytes", max_size);
+                }
+            }
+            None => {
                 response
                     .into_reader()
                     .read_to_end(&mut content)
                     .with_context(|| "Failed to read response from the debuginfod server")?;
+            }
+        }
 
-                std::mem::drop(self.bucket.put(&path, content.clone().into()));
-                Ok(content)
-            } else {
-                bail!("Failed to fetch debuginfo: {}", response.status());
+        let Some(cache) = self.cache.as_ref().filter(|_| !cache_control.no_store) else {
+            return Ok(content);
+        };
+        if let Err(e) = cache.put(&path, content.clone().into()).await {
+            log::warn!("Failed to cache debuginfo of {}: {}", build_id, e);
+        }
+        let validators = DebuginfodValidators {
+            checksum: storage::checksum(&content),
+            ..validators
+        };
+        self.set_validators(build_id, validators, now);
+        Ok(content)
+    }
+
+    fn set_validators(
+        &self,
+        build_id: &str,
+        mut validators: DebuginfodValidators,
+        now: DateTime<Utc>,
+    ) {
+        if let Some(metadata) = &self.metadata {
+            validators.validated_at = Some(Timestamp {
+                seconds: now.timestamp(),
+                nanos: now.timestamp_subsec_nanos() as i32,
+            });
+            metadata.set_validators(build_id, validators);
+        }
+    }
+}
+
+/// Whether the cached copy may be used without revalidating it.
+fn is_fresh(validators: &DebuginfodValidators, now: DateTime<Utc>) -> bool {
+    let Some(validated_at) = validators.validated_at else {
+        return false;
+    };
+    validated_at
+        .seconds
+        .saturating_add(validators.max_age_seconds)
+        > now.timestamp()
+}
+
+/// The Cache-Control directives of a response that decide how long it may be
+/// cached.
+#[derive(Debug, Default, PartialEq)]
+struct CacheControl {
+    max_age: Option<i64>,
+    no_cache: bool,
+    no_store: bool,
+}
+
+impl CacheControl {
+    fn parse(header: &str) -> Self {
+        let mut cache_control = Self::default();
+        for directive in header.split(',').map(str::trim) {
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            match name.to_ascii_lowercase().as_str() {
+                "max-age" => cache_control.max_age = value.trim_matches('"').parse().ok(),
+                "no-cache" => cache_control.no_cache = true,
+                "no-store" => cache_control.no_store = true,
+                _ => {}
             }
-        } else {
-            Ok(res.to_vec())
         }
+        cache_control
+    }
+
+    /// Seconds the response may be used without revalidating it. Responses
+    /// without directives are revalidated every time, since build IDs are
+    /// rarely looked up again unless something changed.
+    fn max_age(&self) -> i64 {
+        if self.no_cache {
+            return 0;
+        }
+        self.max_age.unwrap_or(0).max(0)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_from_vars() {
+        let dir = tempfile::tempdir().unwrap();
+        let vars = HashMap::from([
+            (
+                "DEBUGINFOD_URLS",
+                "https://a.example.com https://b.example.com/debuginfod/",
+            ),
+            ("DEBUGINFOD_TIMEOUT", "10"),
+            ("DEBUGINFOD_MAXSIZE", "1024"),
+            ("DEBUGINFOD_CACHE_PATH", dir.path().to_str().unwrap()),
+        ]);
+        let debuginfod =
+            DebugInfod::from_vars(|name| vars.get(name).map(|v| v.to_string()), true).unwrap();
+        assert_eq!(
+            debuginfod
+                .upstream_servers
+                .iter()
+                .map(Url::as_str)
+                .collect::<Vec<_>>(),
+            [
+                "https://a.example.com/",
+                "https://b.example.com/debuginfod/"
+            ]
+        );
+        assert_eq!(debuginfod.max_size, Some(1024));
+
+        // Objects in the client cache are served without asking the servers.
+        let cached = dir.path().join("abcd");
+        std::fs::create_dir_all(&cached).unwrap();
+        std::fs::write(cached.join("debuginfo"), b"elf").unwrap();
+        let server = debuginfod.upstream_servers[0].clone();
+        assert_eq!(debuginfod.get(&server, "abcd").await.unwrap(), b"elf");
+
+        let disabled =
+            DebugInfod::from_vars(|name| (name == "DEBUGINFOD_URLS").then(String::new), false)
+                .unwrap();
+        assert!(disabled.is_disabled());
+        assert!(DebugInfod::from_vars(
+            |name| (name == "DEBUGINFOD_TIMEOUT").then(|| "soon".to_string()),
+            false
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_cache_control() {
+        assert_eq!(
+            CacheControl::parse("public, max-age=3600"),
+            CacheControl {
+                max_age: Some(3600),
+                ..Default::default()
+            }
+        );
+        assert_eq!(CacheControl::parse("max-age=60, no-cache").max_age(), 0);
+        assert!(CacheControl::parse("No-Store").no_store);
+        assert_eq!(CacheControl::parse("").max_age(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_revalidation() {
+        use axum::http::{HeaderMap, StatusCode};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Serves the object with an ETag and counts the full downloads.
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&downloads);
+        let router = axum::Router::new().route(
+            "/buildid/:id/debuginfo",
+            axum::routing::get(move |headers: HeaderMap| async move {
+                let cache_control = [("Cache-Control", "max-age=0"), ("ETag", "\"v1\"")];
+                if headers.get("If-None-Match").is_some_and(|v| v == "\"v1\"") {
+                    return (StatusCode::NOT_MODIFIED, cache_control, vec![]);
+                }
+                counter.fetch_add(1, Ordering::Relaxed);
+                (StatusCode::OK, cache_control, b"elf".to_vec())
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        // Without a cache, every lookup downloads the object.
+        let uncached = DebugInfod::disabled();
+        assert_eq!(uncached.get(&server, "abcd").await.unwrap(), b"elf");
+        assert_eq!(uncached.get(&server, "abcd").await.unwrap(), b"elf");
+        assert_eq!(downloads.load(Ordering::Relaxed), 2);
+
+        let metadata = MetadataStore::new();
+        let debuginfod = DebugInfod {
+            cache: Some(Arc::new(object_store::memory::InMemory::new())),
+            ..DebugInfod::disabled().with_metadata(metadata.clone())
+        };
+        assert_eq!(debuginfod.get(&server, "abcd").await.unwrap(), b"elf");
+        assert_eq!(debuginfod.get(&server, "abcd").await.unwrap(), b"elf");
+        assert_eq!(downloads.load(Ordering::Relaxed), 3);
+
+        let validators = metadata.validators("abcd", server.as_str()).unwrap();
+        assert_eq!(validators.etag, "\"v1\"");
+        assert!(validators.validated_at.is_some());
+    }
 
     #[tokio::test]
     async fn test_debuginfod_get() {
@@ -109,6 +493,16 @@ mod tests {
         assert_eq!(debug_.is_empty(), false);
     }
 
+    #[tokio::test]
+    async fn test_debuginfod_disabled() {
+        let debuginfod = DebugInfod::disabled();
+        assert!(debuginfod.is_disabled());
+        assert!(debuginfod
+            .exists("252f7dc22ca9d935e8334f04a0232f35359b5880")
+            .await
+            .is_empty());
+    }
+
     #[tokio::test]
     async fn test_debuginfod_exists() {
         let debuginfod = DebugInfod::default();
@@ -0,0 +1,296 @@
+use super::reasons::DebugInfoUploadReason;
+use crate::debuginfopb::{
+    debuginfo::Source, debuginfo_upload::State, Debuginfo, DebuginfoUpload,
+    ShouldInitiateUploadRequest,
+};
+
+/// What ShouldInitiateUpload answers for a build ID.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum UploadDecision {
+    /// Responds with the reason and whether an upload should be initiated.
+    Respond(DebugInfoUploadReason, bool),
+    /// The build ID was never seen, so debuginfod may serve it.
+    NewBuildId,
+    /// The stored metadata of the build ID is inconsistent.
+    Inconsistent(&'static str),
+}
+
+/// Decides whether an upload should be initiated for the request, given the
+/// stored debuginfo of its build ID, whether the build ID is tombstoned, and
+/// whether an upload in progress is stale. It has no side effects, so the
+/// state machine of uploads can be tested on its own.
+pub(crate) fn should_initiate_upload(
+    request: &ShouldInitiateUploadRequest,
+    debuginfo: Option<&Debuginfo>,
+    tombstoned: bool,
+    is_stale: impl Fn(&DebuginfoUpload) -> bool,
+) -> UploadDecision {
+    use DebugInfoUploadReason as Reason;
+    use UploadDecision::Respond;
+
+    let Some(debuginfo) = debuginfo else {
+        if tombstoned && !request.force {
+            return Respond(Reason::DebugInfoTombstoned, false);
+        }
+        return UploadDecision::NewBuildId;
+    };
+    let valid_elf = debuginfo.quality.as_ref().is_some_and(|q| !q.not_valid_elf);
+    match Source::try_from(debuginfo.source) {
+        Ok(Source::Debuginfod) if valid_elf => Respond(Reason::DebugInfodInvalid, true),
+        Ok(Source::Debuginfod) => Respond(Reason::DebugInfodSource, true),
+        Ok(Source::Upload) => {
+            let Some(upload) = &debuginfo.upload else {
+                return UploadDecision::Inconsistent("Inconsistent metadata: missing upload info");
+            };
+            match State::try_from(upload.state) {
+                Ok(State::Uploading) if is_stale(upload) => Respond(Reason::UploadStale, true),
+                Ok(State::Uploading) => Respond(Reason::UploadInProgress, false),
+                Ok(State::Uploaded) => {
+                    if debuginfo.quality.as_ref().is_some_and(|q| q.corrupted) {
+                        Respond(Reason::DebugInfoCorrupted, true)
+                    } else if !valid_elf && request.force {
+                        Respond(Reason::DebugInfoAlreadyExistsButForced, true)
+                    } else if !valid_elf {
+                        Respond(Reason::DebugInfoAlreadyExists, false)
+                    } else if request.hash.is_empty() {
+                        Respond(Reason::DebugInfoInvalid, true)
+                    } else if upload.hash == request.hash {
+                        Respond(Reason::DebugInfoEqual, false)
+                    } else {
+                        Respond(Reason::DebugInfoNotEqual, true)
+                    }
+                }
+                _ => UploadDecision::Inconsistent("Inconsistent metadata: unknown upload state"),
+            }
+        }
+        _ => UploadDecision::Inconsistent("Inconsistent metadata: unknown source"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debuginfopb::DebuginfoQuality;
+    use DebugInfoUploadReason as Reason;
+
+    /// Upload time after which uploads in progress are stale.
+    const MAX_UPLOAD_SECS: i64 = 60;
+
+    /// Stored state of the modelled build ID.
+    #[derive(Debug, Clone)]
+    enum Model {
+        Unknown,
+        Uploading {
+            hash: String,
+            started_at: i64,
+        },
+        Uploaded {
+            hash: String,
+            valid_elf: bool,
+            corrupted: bool,
+        },
+        Debuginfod {
+            valid_elf: bool,
+        },
+    }
+
+    impl Model {
+        fn debuginfo(&self) -> Option<Debuginfo> {
+            let quality = |valid_elf: bool, corrupted: bool| DebuginfoQuality {
+                not_valid_elf: !valid_elf,
+                corrupted,
+                ..Default::default()
+            };
+            let upload = |hash: &str, state: State, started_at: i64| DebuginfoUpload {
+                id: "upload".into(),
+                hash: hash.into(),
+                state: state.into(),
+                started_at: Some(prost_types::Timestamp {
+                    seconds: started_at,
+                    nanos: 0,
+                }),
+                ..Default::default()
+            };
+            let debuginfo = match self {
+                Self::Unknown => return None,
+                Self::Uploading { hash, started_at } => Debuginfo {
+                    source: Source::Upload.into(),
+                    upload: Some(upload(hash, State::Uploading, *started_at)),
+                    ..Default::default()
+                },
+                Self::Uploaded {
+                    hash,
+                    valid_elf,
+                    corrupted,
+                } => Debuginfo {
+                    source: Source::Upload.into(),
+                    upload: Some(upload(hash, State::Uploaded, 0)),
+                    quality: Some(quality(*valid_elf, *corrupted)),
+                    ..Default::default()
+                },
+                Self::Debuginfod { valid_elf } => Debuginfo {
+                    source: Source::Debuginfod.into(),
+                    quality: valid_elf.then(|| quality(true, false)),
+                    ..Default::default()
+                },
+            };
+            Some(debuginfo)
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        /// Asks whether to upload, and initiates the upload if so.
+        Initiate {
+            hash: String,
+            force: bool,
+        },
+        /// Finishes the upload in progress, if any.
+        Finish {
+            valid_elf: bool,
+            corrupted: bool,
+        },
+        Advance(i64),
+        Tombstone(bool),
+        FoundInDebuginfod {
+            valid_elf: bool,
+        },
+    }
+
+    fn op(rng: &mut fastrand::Rng) -> Op {
+        match rng.u8(0..10) {
+            0..=3 => Op::Initiate {
+                hash: ["", "a", "b"][rng.usize(0..3)].into(),
+                force: rng.bool(),
+            },
+            4 | 5 => Op::Finish {
+                valid_elf: rng.u8(0..4) > 0,
+                corrupted: rng.u8(0..8) == 0,
+            },
+            6 | 7 => Op::Advance(rng.i64(0..2 * MAX_UPLOAD_SECS)),
+            8 => Op::Tombstone(rng.bool()),
+            _ => Op::FoundInDebuginfod {
+                valid_elf: rng.bool(),
+            },
+        }
+    }
+
+    /// Runs random sequences of requests and time advancements against the
+    /// decision, checking the invariants of the upload state machine.
+    #[test]
+    fn test_should_initiate_upload_invariants() {
+        for seed in 0..500 {
+            let mut rng = fastrand::Rng::with_seed(seed);
+            let (mut model, mut now, mut tombstoned) = (Model::Unknown, 0, false);
+            let mut history = vec![];
+            for _ in 0..40 {
+                let op = op(&mut rng);
+                history.push(op.clone());
+                let context = || format!("seed {}, ops {:?}", seed, history);
+                match op {
+                    Op::Initiate { hash, force } => {
+                        let request = ShouldInitiateUploadRequest {
+                            hash: hash.clone(),
+                            force,
+                            ..Default::default()
+                        };
+                        let stale = |upload: &DebuginfoUpload| {
+                            upload.started_at.as_ref().unwrap().seconds + MAX_UPLOAD_SECS < now
+                        };
+                        let decision = should_initiate_upload(
+                            &request,
+                            model.debuginfo().as_ref(),
+                            tombstoned,
+                            stale,
+                        );
+                        let initiate = match decision {
+                            UploadDecision::Respond(reason, initiate) => {
+                                // Forced requests are only refused when the
+                                // upload would be redundant.
+                                if force && !initiate {
+                                    assert!(
+                                        matches!(
+                                            reason,
+                                            Reason::UploadInProgress | Reason::DebugInfoEqual
+                                        ),
+                                        "forced request refused with {:?}: {}",
+                                        reason,
+                                        context()
+                                    );
+                                }
+                                // Never two concurrent uploads, unless the
+                                // first one is stale.
+                                if let Model::Uploading { started_at, .. } = model {
+                                    assert_eq!(
+                                        initiate,
+                                        started_at + MAX_UPLOAD_SECS < now,
+                                        "{}",
+                                        context()
+                                    );
+                                }
+                                // Identical debuginfo isn't uploaded again.
+                                if let Model::Uploaded {
+                                    hash: stored,
+                                    valid_elf: true,
+                                    corrupted: false,
+                                } = &model
+                                {
+                                    if !hash.is_empty() && *stored == hash {
+                                        assert!(!initiate, "{}", context());
+                                    }
+                                }
+                                initiate
+                            }
+                            UploadDecision::NewBuildId => {
+                                assert!(matches!(model, Model::Unknown), "{}", context());
+                                assert!(force || !tombstoned, "{}", context());
+                                true
+                            }
+                            UploadDecision::Inconsistent(e) => panic!("{}: {}", e, context()),
+                        };
+                        if initiate {
+                            model = Model::Uploading {
+                                hash,
+                                started_at: now,
+                            };
+                        }
+                    }
+                    Op::Finish {
+                        valid_elf,
+                        corrupted,
+                    } => {
+                        if let Model::Uploading { hash, .. } = model {
+                            model = Model::Uploaded {
+                                hash,
+                                valid_elf,
+                                corrupted,
+                            };
+                            tombstoned = false;
+                        }
+                    }
+                    Op::Advance(secs) => now += secs,
+                    Op::Tombstone(set) => tombstoned = set,
+                    Op::FoundInDebuginfod { valid_elf } => {
+                        if matches!(model, Model::Unknown) {
+                            model = Model::Debuginfod { valid_elf };
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_inconsistent_metadata() {
+        let request = ShouldInitiateUploadRequest::default();
+        let debuginfo = Debuginfo {
+            source: Source::Upload.into(),
+            upload: None,
+            ..Default::default()
+        };
+        assert!(matches!(
+            should_initiate_upload(&request, Some(&debuginfo), false, |_| false),
+            UploadDecision::Inconsistent(_)
+        ));
+    }
+}
@@ -0,0 +1,156 @@
+use super::debuginfopb::{debuginfo::Source, debuginfo_upload::State, DebuginfoType};
+use super::{DebugInfod, MetadataStore};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::collections::HashSet;
+use std::sync::{Arc, LazyLock, Mutex};
+
+static BACKGROUND_CHECKS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_debuginfod_background_checks_total",
+        "Total number of debuginfod existence checks run in the background, by result.",
+        &["result"]
+    )
+    .unwrap()
+});
+
+/// BackgroundExistenceChecks looks build IDs up in debuginfod after their
+/// upload was already requested, so that a slow debuginfod server doesn't
+/// delay agents. Build IDs found in debuginfod are recorded as such, which
+/// stops further uploads of them.
+#[derive(Debug, Clone, Default)]
+pub struct BackgroundExistenceChecks {
+    /// Build IDs being checked, so that agents asking about the same build ID
+    /// at once don't start more checks.
+    pending: Arc<Mutex<HashSet<String>>>,
+}
+
+impl BackgroundExistenceChecks {
+    /// Starts checking whether debuginfod has the build ID, unless it's
+    /// being checked already.
+    pub fn start(
+        &self,
+        debuginfod: &DebugInfod,
+        metadata: &MetadataStore,
+        build_id: &str,
+        req_type: DebuginfoType,
+    ) {
+        if !self.pending.lock().unwrap().insert(build_id.to_string()) {
+            return;
+        }
+        let (pending, debuginfod, metadata, build_id) = (
+            Arc::clone(&self.pending),
+            debuginfod.clone(),
+            metadata.clone(),
+            build_id.to_string(),
+        );
+        tokio::spawn(async move {
+            let servers = debuginfod.exists(&build_id).await;
+            let result = if servers.is_empty() {
+                "not_found"
+            } else if record_found(&metadata, &build_id, &req_type, servers) {
+                "found"
+            } else {
+                "uploaded"
+            };
+            BACKGROUND_CHECKS.with_label_values(&[result]).inc();
+            pending.lock().unwrap().remove(&build_id);
+        });
+    }
+}
+
+/// Records that debuginfod serves the build ID, unless its debuginfo was
+/// uploaded in the meantime. Uploads still in progress are replaced, which
+/// fails them when they're marked as finished. Returns whether the metadata
+/// was updated.
+fn record_found(
+    metadata: &MetadataStore,
+    build_id: &str,
+    req_type: &DebuginfoType,
+    servers: Vec<String>,
+) -> bool {
+    let generation = match metadata.fetch_versioned(build_id, req_type) {
+        None => 0,
+        Some(entry) => {
+            let uploading = entry.debuginfo.source() == Source::Upload
+                && entry
+                    .debuginfo
+                    .upload
+                    .as_ref()
+                    .is_some_and(|u| u.state() == State::Uploading);
+            if !uploading {
+                return false;
+            }
+            entry.generation
+        }
+    };
+    metadata
+        .compare_and_swap(
+            super::debuginfopb::Debuginfo {
+                build_id: build_id.to_string(),
+                r#type: (*req_type).into(),
+                source: Source::Debuginfod.into(),
+                debuginfod_servers: servers,
+                ..Default::default()
+            },
+            generation,
+        )
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debuginfo_store::NewUpload;
+    use chrono::Utc;
+
+    #[test]
+    fn test_record_found() {
+        let metadata = MetadataStore::new();
+        let t = DebuginfoType::DebuginfoUnspecified;
+        let servers = || vec!["https://debuginfod.example.com/".to_string()];
+
+        assert!(record_found(&metadata, "abcd", &t, servers()));
+        assert_eq!(
+            metadata.fetch("abcd", &t).unwrap().source(),
+            Source::Debuginfod
+        );
+
+        metadata
+            .mark_as_uploading(
+                "ef01",
+                NewUpload {
+                    id: "upload-1",
+                    hash: "hash",
+                    size: 3,
+                    started_at: Utc::now(),
+                },
+                &t,
+                0,
+            )
+            .unwrap();
+        assert!(record_found(&metadata, "ef01", &t, servers()));
+        assert!(metadata
+            .mark_as_uploaded("ef01", "upload-1", &t, "", "", Utc::now())
+            .is_err());
+
+        let generation = metadata.generation("2345", &t);
+        metadata
+            .mark_as_uploading(
+                "2345",
+                NewUpload {
+                    id: "upload-2",
+                    hash: "hash",
+                    size: 3,
+                    started_at: Utc::now(),
+                },
+                &t,
+                generation,
+            )
+            .unwrap();
+        metadata
+            .mark_as_uploaded("2345", "upload-2", &t, "", "", Utc::now())
+            .unwrap();
+        assert!(!record_found(&metadata, "2345", &t, servers()));
+        assert_eq!(metadata.fetch("2345", &t).unwrap().source(), Source::Upload);
+    }
+}
@@ -1,18 +1,49 @@
-use super::DebugInfod;
+use super::{BucketRoutes, DebugInfod, ObjectLayout};
 use crate::debuginfopb::{debuginfo::Source, Debuginfo};
+use crate::storage;
 use anyhow::bail;
-use object_store::ObjectStore;
+use object_store::{path::Path, GetOptions, ObjectMeta, ObjectStore};
 use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 
 #[derive(Debug)]
 pub struct DebuginfoFetcher {
-    bucket: Arc<dyn ObjectStore>,
+    buckets: Arc<BucketRoutes>,
+    layout: ObjectLayout,
     debuginfod: DebugInfod,
+    /// Size of the ranges objects are downloaded in. Zero downloads objects
+    /// in a single request.
+    chunk_size: usize,
+    /// Maximum number of ranges of an object downloaded at the same time.
+    concurrency: usize,
 }
 
 impl DebuginfoFetcher {
-    pub fn new(bucket: Arc<dyn ObjectStore>, debuginfod: DebugInfod) -> Self {
-        Self { bucket, debuginfod }
+    pub fn new(bucket: Arc<dyn ObjectStore>, layout: ObjectLayout, debuginfod: DebugInfod) -> Self {
+        Self {
+            buckets: Arc::new(BucketRoutes::new(bucket)),
+            layout,
+            debuginfod,
+            chunk_size: 0,
+            concurrency: 1,
+        }
+    }
+
+    /// Downloads objects larger than `chunk_size` from the bucket in ranges of
+    /// that size, `concurrency` at a time, which is much faster than a single
+    /// stream for large objects in S3 and the like.
+    pub fn with_parallel_download(mut self, chunk_size: usize, concurrency: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Reads uploaded objects from the buckets they were routed to, rather
+    /// than from the bucket the fetcher was created with.
+    pub fn with_bucket_routes(mut self, buckets: Arc<BucketRoutes>) -> Self {
+        self.buckets = buckets;
+        self
     }
 
     pub async fn fetch_raw_elf(&self, dbginfo: &Debuginfo) -> anyhow::Result<Vec<u8>> {
@@ -27,24 +58,178 @@ impl DebuginfoFetcher {
     }
 
     async fn fetch_debuginfod(&self, dbginfo: &Debuginfo) -> anyhow::Result<Vec<u8>> {
+        let server = match self.debuginfod.upstream_servers.first() {
+            Some(server) => server,
+            None => bail!("No debuginfod upstream server configured"),
+        };
         let rc = self
             .debuginfod
-            .get(
-                &self.debuginfod.upstream_servers[0],
-                dbginfo.build_id.as_str(),
-            )
+            .get(server, dbginfo.build_id.as_str())
             .await?;
         Ok(rc.to_vec())
     }
 
+    /// Downloads the uploaded object and verifies it against the checksum
+    /// recorded when the upload finished. Corrupted objects fail with a
+    /// `CorruptObjectError`.
     async fn fetch_bucket(&self, dbginfo: &Debuginfo) -> anyhow::Result<Vec<u8>> {
-        let path: &str = &dbginfo.upload.as_ref().unwrap().id;
+        let path = self.layout.object_path(dbginfo)?;
+        let data = self.download(self.buckets.bucket(dbginfo)?, &path).await?;
+        let checksum = dbginfo.upload.as_ref().map_or("", |u| u.checksum.as_str());
+        storage::verify(&path, checksum, &data)?;
+        Ok(data)
+    }
 
-        let rc = self
-            .bucket
-            .get(&object_store::path::Path::from(path))
-            .await?;
+    async fn download(
+        &self,
+        bucket: &Arc<dyn ObjectStore>,
+        path: &Path,
+    ) -> anyhow::Result<Vec<u8>> {
+        if self.chunk_size == 0 || self.concurrency == 1 {
+            let rc = bucket.get(path).await?;
+            return Ok(rc.bytes().await?.to_vec());
+        }
+
+        let meta = bucket.head(path).await?;
+        if meta.size <= self.chunk_size {
+            let rc = bucket.get(path).await?;
+            return Ok(rc.bytes().await?.to_vec());
+        }
+        self.fetch_ranges(bucket, meta).await
+    }
+
+    /// Downloads the object in ranges of the chunk size concurrently. Every
+    /// range is requested with the ETag of `meta`, so that ranges of an
+    /// object replaced during the download aren't mixed.
+    async fn fetch_ranges(
+        &self,
+        bucket: &Arc<dyn ObjectStore>,
+        meta: ObjectMeta,
+    ) -> anyhow::Result<Vec<u8>> {
+        let (path, size) = (meta.location, meta.size);
+        let permits = Arc::new(Semaphore::new(self.concurrency));
+        let mut downloads = JoinSet::new();
+        for start in (0..size).step_by(self.chunk_size) {
+            let range = start..(start + self.chunk_size).min(size);
+            let bucket = Arc::clone(bucket);
+            let permits = Arc::clone(&permits);
+            let path = path.clone();
+            let options = GetOptions {
+                if_match: meta.e_tag.clone(),
+                range: Some(range.clone().into()),
+                ..Default::default()
+            };
+            downloads.spawn(async move {
+                let _permit = permits.acquire_owned().await?;
+                let data = match bucket.get_opts(&path, options).await {
+                    Ok(result) => result.bytes().await?,
+                    Err(object_store::Error::Precondition { .. }) => {
+                        bail!("{} changed while downloading it", path)
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                anyhow::Ok((range, data))
+            });
+        }
+
+        let mut buf = vec![0; size];
+        while let Some(res) = downloads.join_next().await {
+            let (range, data) = res??;
+            if data.len() != range.len() {
+                bail!(
+                    "range {:?} of {} has {} bytes, the object changed while downloading it",
+                    range,
+                    path,
+                    data.len()
+                );
+            }
+            buf[range].copy_from_slice(&data);
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debuginfopb::DebuginfoUpload;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn test_parallel_download() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let data: Vec<u8> = (0..100u8).collect();
+        bucket
+            .put(&Path::from("upload-1"), data.clone().into())
+            .await
+            .unwrap();
+        let dbginfo = Debuginfo {
+            upload: Some(DebuginfoUpload {
+                id: "upload-1".into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        for (chunk_size, concurrency) in [(0, 1), (16, 3), (10, 10), (1000, 4)] {
+            let fetcher = DebuginfoFetcher::new(
+                Arc::clone(&bucket),
+                ObjectLayout::default(),
+                DebugInfod::disabled(),
+            )
+            .with_parallel_download(chunk_size, concurrency);
+            assert_eq!(fetcher.fetch_bucket(&dbginfo).await.unwrap(), data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_object_replaced_while_downloading() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let path = Path::from("upload-1");
+        bucket.put(&path, vec![1; 100].into()).await.unwrap();
+        let meta = bucket.head(&path).await.unwrap();
+        bucket.put(&path, vec![2; 100].into()).await.unwrap();
+
+        let fetcher = DebuginfoFetcher::new(
+            Arc::clone(&bucket),
+            ObjectLayout::default(),
+            DebugInfod::disabled(),
+        )
+        .with_parallel_download(16, 3);
+        let err = fetcher.fetch_ranges(&bucket, meta).await.unwrap_err();
+        assert!(
+            err.to_string().contains("changed while downloading"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_checksum() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let path = Path::from("upload-1");
+        bucket.put(&path, b"elf".to_vec().into()).await.unwrap();
+        let mut dbginfo = Debuginfo {
+            upload: Some(DebuginfoUpload {
+                id: "upload-1".into(),
+                checksum: storage::checksum(b"elf"),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let fetcher = DebuginfoFetcher::new(
+            Arc::clone(&bucket),
+            ObjectLayout::default(),
+            DebugInfod::disabled(),
+        );
+        assert_eq!(fetcher.fetch_bucket(&dbginfo).await.unwrap(), b"elf");
+
+        bucket.put(&path, b"elg".to_vec().into()).await.unwrap();
+        let err = fetcher.fetch_bucket(&dbginfo).await.unwrap_err();
+        assert!(err.downcast_ref::<storage::CorruptObjectError>().is_some());
 
-        Ok(rc.bytes().await?.to_vec())
+        // Objects uploaded before checksums were recorded aren't verified.
+        dbginfo.upload.as_mut().unwrap().checksum.clear();
+        assert_eq!(fetcher.fetch_bucket(&dbginfo).await.unwrap(), b"elg");
     }
 }
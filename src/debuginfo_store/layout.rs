@@ -0,0 +1,176 @@
+use super::metadata;
+use crate::debuginfopb::{Debuginfo, DebuginfoType};
+use anyhow::bail;
+use object_store::path::Path;
+
+const PLACEHOLDERS: [&str; 4] = ["{buildid}", "{type}", "{hash}", "{upload_id}"];
+
+/// ObjectLayout determines the path uploaded debuginfo objects are stored at
+/// in the bucket. The template may reference `{buildid}`, `{type}`, `{hash}`
+/// and `{upload_id}`, and must contain `{hash}` or `{upload_id}` so that
+/// different uploads of a build ID don't overwrite each other. Build IDs and
+/// hashes, which agents choose, are only substituted if they are a single
+/// safe path segment, and paths of metadata objects are refused.
+///
+/// There is no `{tenant}` placeholder: debuginfo is stored once per build ID
+/// and shared by every agent uploading it, and paths are derived from the
+/// metadata of the build ID when objects are read, which records no tenant.
+/// Deployments sharing a bucket are kept apart by the bucket prefix instead.
+#[derive(Debug, Clone)]
+pub struct ObjectLayout {
+    template: String,
+}
+
+impl Default for ObjectLayout {
+    fn default() -> Self {
+        Self {
+            template: "{upload_id}".into(),
+        }
+    }
+}
+
+impl ObjectLayout {
+    pub fn new(template: &str) -> anyhow::Result<Self> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end + 1,
+                None => bail!("unterminated placeholder in object path {}", template),
+            };
+            if &rest[start..end] == "{tenant}" {
+                bail!(
+                    "the {{tenant}} placeholder in object path {} is not supported, \
+                     debuginfo is shared by all tenants; set a bucket prefix instead",
+                    template
+                );
+            }
+            if !PLACEHOLDERS.contains(&&rest[start..end]) {
+                bail!(
+                    "unknown placeholder {} in object path {}",
+                    &rest[start..end],
+                    template
+                );
+            }
+            rest = &rest[end..];
+        }
+
+        if !template.contains("{hash}") && !template.contains("{upload_id}") {
+            bail!(
+                "object path {} must contain {{hash}} or {{upload_id}}",
+                template
+            );
+        }
+
+        Ok(Self {
+            template: template.to_string(),
+        })
+    }
+
+    /// Returns the path of the object uploaded for the given debuginfo.
+    pub fn object_path(&self, debuginfo: &Debuginfo) -> anyhow::Result<Path> {
+        let upload = match &debuginfo.upload {
+            Some(upload) => upload,
+            None => bail!("Debuginfo has no upload"),
+        };
+
+        if self.template.contains("{buildid}") {
+            check_path_component("build_id", &debuginfo.build_id)?;
+        }
+        if self.template.contains("{hash}") {
+            check_path_component("hash", &upload.hash)?;
+        }
+        let path = self
+            .template
+            .replace("{buildid}", &debuginfo.build_id)
+            .replace("{type}", type_name(debuginfo.r#type()))
+            .replace("{hash}", &upload.hash)
+            .replace("{upload_id}", &upload.id);
+        // Objects must never be mistaken for the metadata stored next to them.
+        if metadata::is_store_path(&path) {
+            bail!("object path {} is reserved for metadata", path);
+        }
+
+        Ok(Path::parse(&path)?)
+    }
+}
+
+/// Checks that a value sent by agents, like a build ID or hash, can be used
+/// as a single path segment: non-empty ASCII letters, digits, `-` and `_`.
+pub(crate) fn check_path_component(name: &str, value: &str) -> anyhow::Result<()> {
+    if value.is_empty()
+        || !value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        bail!(
+            "{} {:?} contains characters other than letters, digits, - and _",
+            name,
+            value
+        );
+    }
+    Ok(())
+}
+
+fn type_name(t: DebuginfoType) -> &'static str {
+    match t {
+        DebuginfoType::Executable => "executable",
+        DebuginfoType::Sources => "sources",
+        DebuginfoType::Symbols => "symbols",
+        _ => "debuginfo",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debuginfopb::DebuginfoUpload;
+
+    #[test]
+    fn test_object_path() {
+        let debuginfo = Debuginfo {
+            build_id: "abcd".into(),
+            r#type: DebuginfoType::Executable.into(),
+            upload: Some(DebuginfoUpload {
+                id: "upload-1".into(),
+                hash: "1234".into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let layout = ObjectLayout::default();
+        assert_eq!(
+            layout.object_path(&debuginfo).unwrap(),
+            Path::from("upload-1")
+        );
+
+        let layout = ObjectLayout::new("{buildid}/{type}/{hash}").unwrap();
+        assert_eq!(
+            layout.object_path(&debuginfo).unwrap(),
+            Path::from("abcd/executable/1234")
+        );
+
+        // Values of agents can't escape their segment or name metadata.
+        let object_path = |layout: &ObjectLayout, build_id: &str, hash: &str| {
+            let mut debuginfo = debuginfo.clone();
+            debuginfo.build_id = build_id.into();
+            debuginfo.upload.as_mut().unwrap().hash = hash.into();
+            layout.object_path(&debuginfo)
+        };
+        assert!(object_path(&layout, "abcd", "x.metadata").is_err());
+        assert!(object_path(&layout, "abcd", "x/tombstone").is_err());
+        assert!(object_path(&layout, "ab/cd", "1234").is_err());
+        assert!(object_path(&layout, "..", "1234").is_err());
+        assert!(object_path(&layout, "abcd", "").is_err());
+        let layout = ObjectLayout::new("{buildid}/{hash}").unwrap();
+        assert!(object_path(&layout, "abcd", "metadata").is_err());
+        assert!(object_path(&layout, "abcd", "tombstone").is_err());
+        assert!(object_path(&layout, "abcd", "1234").is_ok());
+
+        let err = ObjectLayout::new("{tenant}/{hash}").unwrap_err();
+        assert!(err.to_string().contains("bucket prefix"));
+        assert!(ObjectLayout::new("{arch}/{hash}").is_err());
+        assert!(ObjectLayout::new("{buildid}/{type}").is_err());
+        assert!(ObjectLayout::new("{buildid/{hash}").is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use crate::memory::{MemoryUsage, MemoryWatchdog};
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tonic::Status;
+
+static UPLOADS_IN_FLIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "evprofiler_debuginfo_uploads_in_flight",
+        "Number of debuginfo uploads currently being received."
+    )
+    .unwrap()
+});
+
+static UPLOAD_BUFFERED_BYTES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "evprofiler_debuginfo_upload_buffered_bytes",
+        "Number of bytes of debuginfo uploads currently buffered in memory."
+    )
+    .unwrap()
+});
+
+static UPLOADS_REJECTED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_debuginfo_uploads_rejected_total",
+        "Total number of debuginfo uploads rejected because too many were in flight."
+    )
+    .unwrap()
+});
+
+/// UploadLimiter bounds the number of concurrent debuginfo uploads, since
+/// every upload buffers its object in memory. Uploads beyond the limit wait
+/// up to the queue timeout for a slot and are rejected afterwards. Uploads
+/// are also rejected while the memory watchdog sheds load.
+#[derive(Debug, Clone)]
+pub struct UploadLimiter {
+    slots: Option<Arc<Semaphore>>,
+    queue_timeout: Duration,
+    memory: Option<Arc<MemoryWatchdog>>,
+}
+
+impl Default for UploadLimiter {
+    fn default() -> Self {
+        Self::new(0, Duration::ZERO)
+    }
+}
+
+/// UploadPermit holds an upload slot until it is dropped.
+#[derive(Debug)]
+pub struct UploadPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+    buffered: u64,
+}
+
+impl UploadPermit {
+    /// Accounts `bytes` more of the upload as buffered in memory.
+    pub fn buffer(&mut self, bytes: u64) {
+        self.buffered += bytes;
+        UPLOAD_BUFFERED_BYTES.add(bytes as i64);
+    }
+}
+
+impl Drop for UploadPermit {
+    fn drop(&mut self) {
+        UPLOADS_IN_FLIGHT.dec();
+        UPLOAD_BUFFERED_BYTES.sub(self.buffered as i64);
+    }
+}
+
+impl UploadLimiter {
+    /// Creates a limiter allowing `max_concurrent` uploads. Zero disables the
+    /// limit.
+    pub fn new(max_concurrent: usize, queue_timeout: Duration) -> Self {
+        Self {
+            slots: (max_concurrent > 0).then(|| Arc::new(Semaphore::new(max_concurrent))),
+            queue_timeout,
+            memory: None,
+        }
+    }
+
+    /// Rejects uploads while `memory` sheds load.
+    pub fn with_memory_watchdog(mut self, memory: Arc<MemoryWatchdog>) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    pub async fn acquire(&self) -> Result<UploadPermit, Status> {
+        if let Some(memory) = &self.memory {
+            memory.check()?;
+        }
+        let permit = match &self.slots {
+            None => None,
+            Some(slots) => {
+                let acquired = match Arc::clone(slots).try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) if self.queue_timeout.is_zero() => None,
+                    Err(_) => {
+                        tokio::time::timeout(self.queue_timeout, Arc::clone(slots).acquire_owned())
+                            .await
+                            .ok()
+                            .and_then(Result::ok)
+                    }
+                };
+                match acquired {
+                    Some(permit) => Some(permit),
+                    None => {
+                        UPLOADS_REJECTED.inc();
+                        return Err(Status::resource_exhausted(
+                            "too many concurrent debuginfo uploads, retry later",
+                        ));
+                    }
+                }
+            }
+        };
+
+        UPLOADS_IN_FLIGHT.inc();
+        Ok(UploadPermit {
+            _permit: permit,
+            buffered: 0,
+        })
+    }
+}
+
+impl MemoryUsage for UploadLimiter {
+    fn memory_usage(&self) -> u64 {
+        UPLOAD_BUFFERED_BYTES.get().max(0) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_upload_limiter() {
+        let limiter = UploadLimiter::new(1, Duration::from_millis(10));
+        let permit = limiter.acquire().await.unwrap();
+        let err = limiter.acquire().await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+
+        drop(permit);
+        let _permit = limiter.acquire().await.unwrap();
+
+        let unlimited = UploadLimiter::default();
+        let _a = unlimited.acquire().await.unwrap();
+        let _b = unlimited.acquire().await.unwrap();
+    }
+}
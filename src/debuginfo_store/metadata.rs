@@ -1,35 +1,360 @@
 use self::debuginfopb::{debuginfo::Source, debuginfo_upload, DebuginfoUpload};
-use crate::debuginfopb::{self, Debuginfo, DebuginfoType};
+use super::bloom::BloomFilter;
+use crate::debuginfopb::{
+    self, Debuginfo, DebuginfoTombstone, DebuginfoType, DebuginfodValidators,
+};
+use crate::memory::MemoryUsage;
 use anyhow::bail;
 use chrono::{DateTime, Utc};
+use moka::ops::compute::{CompResult, Op};
 use moka::sync::Cache;
+use object_store::{path::Path, ObjectStore};
+use prost::Message;
 use prost_types::Timestamp;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
 
+/// Object the membership filter is persisted to, next to the metadata.
+const FILTER_PATH: &str = "debuginfo.bloom";
+const FILTER_EXPECTED_ITEMS: usize = 100_000;
+const FILTER_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// TombstonePolicy decides when reports of agents that could not extract the
+/// debuginfo of a build ID turn into a tombstone, which stops requesting its
+/// upload for `ttl`. Zero reports disables tombstones.
+#[derive(Debug, Clone, Copy)]
+pub struct TombstonePolicy {
+    pub reports: u32,
+    pub ttl: std::time::Duration,
+}
+
+impl Default for TombstonePolicy {
+    fn default() -> Self {
+        Self {
+            reports: 0,
+            ttl: std::time::Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// MetadataEntry is a debuginfo metadata entry together with its generation.
+/// The generation is incremented on every write, an absent entry has
+/// generation 0.
+#[derive(Debug, Clone)]
+pub struct MetadataEntry {
+    pub generation: u64,
+    pub debuginfo: Debuginfo,
+}
+
+/// NewUpload describes an upload being initiated.
+#[derive(Debug, Clone, Copy)]
+pub struct NewUpload<'a> {
+    pub id: &'a str,
+    pub hash: &'a str,
+    pub size: i64,
+    pub started_at: DateTime<Utc>,
+}
+
+/// ConflictError is returned when a metadata entry was modified concurrently
+/// between reading it and writing an update back.
 #[derive(Debug)]
+pub struct ConflictError {
+    pub build_id: String,
+    pub expected_generation: u64,
+    pub actual_generation: u64,
+}
+
+impl std::fmt::Display for ConflictError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "metadata for build_id {} was modified concurrently (expected generation {}, found {})",
+            self.build_id, self.expected_generation, self.actual_generation
+        )
+    }
+}
+
+impl std::error::Error for ConflictError {}
+
+#[derive(Debug, Clone)]
 pub struct MetadataStore {
-    pub store: Cache<String, Debuginfo>,
+    pub store: Cache<String, MetadataEntry>,
+    /// Filter over the keys of all entries ever written, so that lookups of
+    /// unknown build IDs don't touch the store.
+    filter: Arc<BloomFilter>,
+    /// Cache validators of objects downloaded from debuginfod, by build ID and
+    /// server.
+    validators: Cache<String, DebuginfodValidators>,
+    /// Tombstones of build IDs agents could not extract debuginfo for, and
+    /// the number of such reports that didn't lead to a tombstone yet.
+    tombstones: Cache<String, DebuginfoTombstone>,
+    unavailable_reports: Cache<String, u32>,
+    persister: Option<mpsc::UnboundedSender<(String, Vec<u8>)>>,
+}
+
+impl MemoryUsage for MetadataStore {
+    fn memory_usage(&self) -> u64 {
+        let entries = self
+            .store
+            .iter()
+            .map(|(k, v)| k.len() + v.debuginfo.encoded_len());
+        let validators = self
+            .validators
+            .iter()
+            .map(|(k, v)| k.len() + v.encoded_len());
+        let tombstones = self
+            .tombstones
+            .iter()
+            .map(|(k, v)| k.len() + v.encoded_len());
+        entries.chain(validators).chain(tombstones).sum::<usize>() as u64
+    }
 }
 
 impl MetadataStore {
     pub fn new() -> Self {
         Self {
-            store: Cache::new(10_000),
+            store: Cache::builder().build(),
+            filter: Arc::new(new_filter()),
+            validators: Cache::builder().build(),
+            tombstones: Cache::builder().build(),
+            unavailable_reports: new_reports_cache(),
+            persister: None,
         }
     }
 
-    pub fn with_store(store: Cache<String, Debuginfo>) -> Self {
-        Self { store }
+    /// Creates a store that is populated with the metadata already present in
+    /// `bucket` and writes every update back to it. Writes are persisted in
+    /// order by a background task, along with the membership filter. Entries
+    /// are never read back from the
+    /// bucket after startup, so none are evicted. Objects that can't be read
+    /// or decoded are logged and skipped.
+    pub async fn persistent(bucket: Arc<dyn ObjectStore>) -> anyhow::Result<Self> {
+        let store = Cache::builder().build();
+        let filter = Arc::new(new_filter());
+        let validators = Cache::builder().build();
+        let tombstones = Cache::builder().build();
+        let (mut loaded, mut skipped) = (0, 0);
+
+        match read_filter(bucket.as_ref()).await {
+            Ok(Some(persisted)) => {
+                if let Err(e) = filter.union(&persisted) {
+                    log::warn!("Discarding persisted debuginfo filter: {}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to read persisted debuginfo filter: {}", e),
+        }
+
+        let mut objects = bucket.list(None);
+        while let Some(meta) = objects.next().await {
+            let meta = meta?;
+            let path = meta.location.as_ref();
+            let res = if is_validators_path(path) {
+                read_message::<DebuginfodValidators>(bucket.as_ref(), &meta.location)
+                    .await
+                    .map(|message| validators.insert(path.to_string(), message))
+            } else if is_tombstone_path(path) {
+                read_message::<DebuginfoTombstone>(bucket.as_ref(), &meta.location)
+                    .await
+                    .map(|tombstone| {
+                        if !is_expired(&tombstone, Utc::now()) {
+                            tombstones.insert(path.to_string(), tombstone);
+                        }
+                    })
+            } else if is_metadata_path(path) {
+                read_metadata(bucket.as_ref(), &meta.location)
+                    .await
+                    .map(|debuginfo| {
+                        filter.insert(path);
+                        store.insert(
+                            path.to_string(),
+                            MetadataEntry {
+                                generation: 1,
+                                debuginfo,
+                            },
+                        );
+                        loaded += 1;
+                    })
+            } else {
+                continue;
+            };
+            if let Err(e) = res {
+                log::warn!("Skipping debuginfo metadata object {}: {:#}", path, e);
+                skipped += 1;
+            }
+        }
+        drop(objects);
+        log::info!(
+            "Loaded {} debuginfo metadata entries, skipped {} objects",
+            loaded,
+            skipped
+        );
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<(String, Vec<u8>)>();
+        let persisted_filter = Arc::clone(&filter);
+        tokio::spawn(async move {
+            while let Some((path, data)) = rx.recv().await {
+                if let Err(e) = bucket.put(&Path::from(path.as_str()), data.into()).await {
+                    log::error!("Failed to persist debuginfo metadata {}: {}", path, e);
+                }
+                // Write the filter once per batch of metadata writes.
+                if rx.is_empty() {
+                    let data = persisted_filter.encode();
+                    if let Err(e) = bucket.put(&Path::from(FILTER_PATH), data.into()).await {
+                        log::error!("Failed to persist debuginfo filter: {}", e);
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            store,
+            filter,
+            validators,
+            tombstones,
+            unavailable_reports: new_reports_cache(),
+            persister: Some(tx),
+        })
+    }
+
+    fn persist(&self, path: String, message: &impl Message) {
+        if let Some(persister) = &self.persister {
+            let _ = persister.send((path, message.encode_to_vec()));
+        }
+    }
+
+    /// Returns the cache validators of the debuginfo of `build_id` downloaded
+    /// from `server`.
+    pub fn validators(&self, build_id: &str, server: &str) -> Option<DebuginfodValidators> {
+        self.validators
+            .get(&Self::validators_path(build_id, server))
+    }
+
+    pub fn set_validators(&self, build_id: &str, validators: DebuginfodValidators) {
+        let path = Self::validators_path(build_id, &validators.server);
+        self.persist(path.clone(), &validators);
+        self.validators.insert(path, validators);
+    }
+
+    fn validators_path(build_id: &str, server: &str) -> String {
+        // Servers are URLs, which can't be used as path segments.
+        let server = server.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+        format!("{}/debuginfod-{:016x}.validators", build_id, server)
+    }
+
+    /// Returns the tombstone of the build ID if it hasn't expired at `now`.
+    pub fn tombstone(
+        &self,
+        build_id: &str,
+        req_type: &DebuginfoType,
+        now: DateTime<Utc>,
+    ) -> Option<DebuginfoTombstone> {
+        self.tombstones
+            .get(&Self::tombstone_path(build_id, req_type))
+            .filter(|t| !is_expired(t, now))
+    }
+
+    /// Records a report that the debuginfo of the build ID could not be
+    /// extracted. Once the policy's number of reports is reached, a tombstone
+    /// expiring after the policy's TTL is recorded and returned.
+    pub fn report_unavailable(
+        &self,
+        build_id: &str,
+        req_type: &DebuginfoType,
+        reason: &str,
+        policy: &TombstonePolicy,
+        now: DateTime<Utc>,
+    ) -> Option<DebuginfoTombstone> {
+        if policy.reports == 0 {
+            return None;
+        }
+        if let Some(tombstone) = self.tombstone(build_id, req_type, now) {
+            return Some(tombstone);
+        }
+
+        let path = Self::tombstone_path(build_id, req_type);
+        let reports = self
+            .unavailable_reports
+            .entry_by_ref(&path)
+            .and_upsert_with(|current| current.map_or(1, |e| e.into_value() + 1))
+            .into_value();
+        if reports < policy.reports {
+            return None;
+        }
+        self.unavailable_reports.invalidate(&path);
+
+        let expires_at =
+            now + chrono::Duration::from_std(policy.ttl).unwrap_or(chrono::Duration::MAX);
+        let tombstone = DebuginfoTombstone {
+            build_id: build_id.to_string(),
+            reason: reason.to_string(),
+            expires_at: Some(Timestamp {
+                seconds: expires_at.timestamp(),
+                nanos: expires_at.timestamp_subsec_nanos() as i32,
+            }),
+        };
+        self.persist(path.clone(), &tombstone);
+        self.tombstones.insert(path, tombstone.clone());
+        Some(tombstone)
+    }
+
+    /// Removes the tombstone of the build ID, once its debuginfo was uploaded
+    /// after all.
+    pub fn clear_tombstone(&self, build_id: &str, req_type: &DebuginfoType) {
+        let path = Self::tombstone_path(build_id, req_type);
+        self.unavailable_reports.invalidate(&path);
+        if self.tombstones.remove(&path).is_some() {
+            // An expired tombstone is as good as none.
+            self.persist(
+                path,
+                &DebuginfoTombstone {
+                    build_id: build_id.to_string(),
+                    ..Default::default()
+                },
+            );
+        }
+    }
+
+    fn tombstone_path(build_id: &str, req_type: &DebuginfoType) -> String {
+        match req_type {
+            DebuginfoType::Executable => format!("{}/executable.tombstone", build_id),
+            DebuginfoType::Sources => format!("{}/sources.tombstone", build_id),
+            DebuginfoType::Symbols => format!("{}/symbols.tombstone", build_id),
+            _ => format!("{}/tombstone", build_id),
+        }
     }
 
     pub fn fetch(&self, build_id: &str, req_type: &DebuginfoType) -> Option<Debuginfo> {
+        self.fetch_versioned(build_id, req_type)
+            .map(|entry| entry.debuginfo)
+    }
+
+    pub fn fetch_versioned(
+        &self,
+        build_id: &str,
+        req_type: &DebuginfoType,
+    ) -> Option<MetadataEntry> {
         let path = Self::get_object_path(build_id, req_type);
+        if !self.filter.may_contain(&path) {
+            return None;
+        }
         self.store.get(&path)
     }
 
-    fn get_object_path(build_id: &str, req_type: &DebuginfoType) -> String {
+    /// Returns the current generation of the entry, or 0 if it doesn't exist.
+    pub fn generation(&self, build_id: &str, req_type: &DebuginfoType) -> u64 {
+        self.fetch_versioned(build_id, req_type)
+            .map_or(0, |entry| entry.generation)
+    }
+
+    pub(crate) fn get_object_path(build_id: &str, req_type: &DebuginfoType) -> String {
         match req_type {
             DebuginfoType::Executable => format!("{}/executable.metadata", build_id),
             DebuginfoType::Sources => format!("{}/sources.metadata", build_id),
+            DebuginfoType::Symbols => format!("{}/symbols.metadata", build_id),
             _ => format!("{}/metadata", build_id),
         }
     }
@@ -41,16 +366,26 @@ impl MetadataStore {
         req_type: &DebuginfoType,
     ) -> anyhow::Result<()> {
         let path = Self::get_object_path(build_id, req_type);
-        let mut entry = match self.store.get(&path) {
-            Some(e) => e,
-            None => {
-                bail!("Debuginfo not found");
-            }
-        };
+        let res = self
+            .store
+            .entry(path.clone())
+            .and_compute_with(|current| match current {
+                Some(entry) => {
+                    let mut entry = entry.into_value();
+                    entry.generation += 1;
+                    entry.debuginfo.quality = Some(*quality);
+                    Op::Put(entry)
+                }
+                None => Op::Nop,
+            });
 
-        entry.quality = Some(*quality);
-        self.store.insert(path, entry);
-        Ok(())
+        match res {
+            CompResult::ReplacedWith(entry) => {
+                self.persist(path, &entry.value().debuginfo);
+                Ok(())
+            }
+            _ => bail!("Debuginfo not found"),
+        }
     }
 
     pub fn mark_as_debuginfod_source(
@@ -69,42 +404,53 @@ impl MetadataStore {
         })
     }
 
+    /// Marks the debuginfo as being uploaded. Fails with a ConflictError if
+    /// the entry's generation is no longer `expected_generation`, which means
+    /// another upload was initiated concurrently.
     pub fn mark_as_uploading(
         &self,
         build_id: &str,
-        upload_id: &str,
-        hash: &str,
+        upload: NewUpload<'_>,
         req_type: &DebuginfoType,
-        started_at: DateTime<Utc>,
+        expected_generation: u64,
     ) -> anyhow::Result<()> {
-        self.write(Debuginfo {
+        let debuginfo = Debuginfo {
             build_id: build_id.to_string(),
             r#type: (*req_type).into(),
             source: Source::Upload.into(),
             upload: Some(DebuginfoUpload {
-                id: upload_id.to_string(),
-                hash: hash.to_string(),
+                id: upload.id.to_string(),
+                hash: upload.hash.to_string(),
                 started_at: Some(Timestamp {
-                    seconds: started_at.timestamp(),
-                    nanos: started_at.timestamp_subsec_nanos() as i32,
+                    seconds: upload.started_at.timestamp(),
+                    nanos: upload.started_at.timestamp_subsec_nanos() as i32,
                 }),
                 finished_at: None,
                 state: debuginfo_upload::State::Uploading.into(),
+                size: upload.size,
+                checksum: String::new(),
+                bucket: String::new(),
             }),
             quality: None,
             debuginfod_servers: vec![],
-        })
+        };
+        self.compare_and_swap(debuginfo, expected_generation)?;
+        Ok(())
     }
 
+    /// Marks the upload as finished, recording the checksum of the stored
+    /// object if it is known and the bucket it was stored in.
     pub fn mark_as_uploaded(
         &self,
         build_id: &str,
         upload_id: &str,
         req_type: &DebuginfoType,
+        checksum: &str,
+        bucket: &str,
         finished_at: DateTime<Utc>,
     ) -> anyhow::Result<()> {
-        let debug_info = match self.fetch(build_id, req_type) {
-            Some(d) => d,
+        let (generation, debug_info) = match self.fetch_versioned(build_id, req_type) {
+            Some(entry) => (entry.generation, entry.debuginfo),
             None => bail!("Debuginfo not found"),
         };
 
@@ -122,16 +468,76 @@ impl MetadataStore {
         let mut debug_info = debug_info.clone();
         let mut debug_info_upload = debug_info_upload.clone();
         debug_info_upload.set_state(debuginfo_upload::State::Uploaded);
+        debug_info_upload.checksum = checksum.to_string();
+        debug_info_upload.bucket = bucket.to_string();
         debug_info_upload.finished_at = Some(Timestamp {
             seconds: finished_at.timestamp(),
             nanos: finished_at.timestamp_subsec_nanos() as i32,
         });
         debug_info.upload = Some(debug_info_upload);
 
-        self.write(debug_info)
+        self.compare_and_swap(debug_info, generation)?;
+        Ok(())
     }
 
+    /// Writes the debuginfo unconditionally, bumping its generation.
     pub fn write(&self, debuginfo: Debuginfo) -> anyhow::Result<()> {
+        let path = Self::path_for(&debuginfo)?;
+        self.filter.insert(&path);
+        self.store.entry(path.clone()).and_compute_with(|current| {
+            // Persisted while the entry is locked, so that writes of the
+            // entry are persisted in the order of their generations.
+            self.persist(path, &debuginfo);
+            Op::Put(MetadataEntry {
+                generation: current.map_or(0, |e| e.value().generation) + 1,
+                debuginfo,
+            })
+        });
+        Ok(())
+    }
+
+    /// Writes the debuginfo only if the stored entry is still at
+    /// `expected_generation` (0 meaning it must not exist yet). Returns the new
+    /// generation, or a ConflictError if the entry was modified in between.
+    pub fn compare_and_swap(
+        &self,
+        debuginfo: Debuginfo,
+        expected_generation: u64,
+    ) -> anyhow::Result<u64> {
+        let path = Self::path_for(&debuginfo)?;
+        let build_id = debuginfo.build_id.clone();
+        let mut actual_generation = 0;
+
+        // Added before the entry becomes visible, so lookups never miss it. A
+        // lost swap only leaves a false positive behind.
+        self.filter.insert(&path);
+        let res = self.store.entry(path.clone()).and_compute_with(|current| {
+            actual_generation = current.map_or(0, |e| e.value().generation);
+            if actual_generation != expected_generation {
+                return Op::Nop;
+            }
+
+            self.persist(path, &debuginfo);
+            Op::Put(MetadataEntry {
+                generation: actual_generation + 1,
+                debuginfo,
+            })
+        });
+
+        match res {
+            CompResult::Inserted(entry) | CompResult::ReplacedWith(entry) => {
+                Ok(entry.value().generation)
+            }
+            _ => Err(ConflictError {
+                build_id,
+                expected_generation,
+                actual_generation,
+            }
+            .into()),
+        }
+    }
+
+    fn path_for(debuginfo: &Debuginfo) -> anyhow::Result<String> {
         if debuginfo.build_id.is_empty() {
             bail!("build_id is empty. REQUIRED to write debuginfo metadata");
         }
@@ -141,8 +547,239 @@ impl MetadataStore {
             Err(_) => bail!("Invalid debuginfo type"),
         };
 
-        let path = Self::get_object_path(&debuginfo.build_id, &debuginfo_type);
-        self.store.insert(path, debuginfo);
-        Ok(())
+        Ok(Self::get_object_path(&debuginfo.build_id, &debuginfo_type))
+    }
+}
+
+/// Returns whether the path is one of the objects the store persists.
+pub(crate) fn is_store_path(path: &str) -> bool {
+    is_metadata_path(path)
+        || is_tombstone_path(path)
+        || is_validators_path(path)
+        || path == FILTER_PATH
+}
+
+fn new_filter() -> BloomFilter {
+    BloomFilter::new(FILTER_EXPECTED_ITEMS, FILTER_FALSE_POSITIVE_RATE)
+}
+
+async fn read_filter(bucket: &dyn ObjectStore) -> anyhow::Result<Option<BloomFilter>> {
+    match bucket.get(&Path::from(FILTER_PATH)).await {
+        Ok(res) => Ok(Some(BloomFilter::decode(&res.bytes().await?)?)),
+        Err(object_store::Error::NotFound { .. }) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(crate) fn is_metadata_path(path: &str) -> bool {
+    path.ends_with("/metadata") || path.ends_with(".metadata")
+}
+
+fn is_validators_path(path: &str) -> bool {
+    path.ends_with(".validators")
+}
+
+fn is_tombstone_path(path: &str) -> bool {
+    path.ends_with("/tombstone") || path.ends_with(".tombstone")
+}
+
+fn is_expired(tombstone: &DebuginfoTombstone, now: DateTime<Utc>) -> bool {
+    tombstone
+        .expires_at
+        .is_none_or(|t| t.seconds <= now.timestamp())
+}
+
+fn new_reports_cache() -> Cache<String, u32> {
+    Cache::builder()
+        .max_capacity(10_000)
+        .time_to_idle(std::time::Duration::from_secs(24 * 60 * 60))
+        .build()
+}
+
+/// Reads a persisted debuginfo metadata object.
+pub(crate) async fn read_metadata(
+    bucket: &dyn ObjectStore,
+    path: &Path,
+) -> anyhow::Result<Debuginfo> {
+    read_message(bucket, path).await
+}
+
+async fn read_message<M: Message + Default>(
+    bucket: &dyn ObjectStore,
+    path: &Path,
+) -> anyhow::Result<M> {
+    let data = bucket.get(path).await?.bytes().await?;
+    Ok(M::decode(data)?)
+}
+
+/// Persists a debuginfo metadata object at `path`.
+pub(crate) async fn write_metadata(
+    bucket: &dyn ObjectStore,
+    path: &str,
+    debuginfo: &Debuginfo,
+) -> anyhow::Result<()> {
+    bucket
+        .put(&Path::from(path), debuginfo.encode_to_vec().into())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_and_swap_conflict() {
+        let store = MetadataStore::new();
+        let now = Utc::now();
+        let t = DebuginfoType::DebuginfoUnspecified;
+
+        store
+            .mark_as_uploading(
+                "abcd",
+                NewUpload {
+                    id: "upload-1",
+                    hash: "hash",
+                    size: 3,
+                    started_at: now,
+                },
+                &t,
+                0,
+            )
+            .unwrap();
+        assert_eq!(store.generation("abcd", &t), 1);
+
+        // A second initiator that observed the entry as absent must lose.
+        let err = store
+            .mark_as_uploading(
+                "abcd",
+                NewUpload {
+                    id: "upload-2",
+                    hash: "hash",
+                    size: 3,
+                    started_at: now,
+                },
+                &t,
+                0,
+            )
+            .unwrap_err();
+        assert!(err.downcast_ref::<ConflictError>().is_some());
+
+        store
+            .mark_as_uploaded("abcd", "upload-1", &t, "sha256:00", "", now)
+            .unwrap();
+        assert_eq!(store.generation("abcd", &t), 2);
+        assert_eq!(
+            store.fetch("abcd", &t).unwrap().upload.unwrap().state(),
+            debuginfo_upload::State::Uploaded
+        );
+        assert_eq!(
+            store.fetch("abcd", &t).unwrap().upload.unwrap().checksum,
+            "sha256:00"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_persistent_store() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(object_store::memory::InMemory::new());
+        let t = DebuginfoType::DebuginfoUnspecified;
+        const ENTRIES: usize = 10_100;
+        for i in 0..ENTRIES {
+            let debuginfo = Debuginfo {
+                build_id: format!("{:04x}", i),
+                ..Default::default()
+            };
+            write_metadata(bucket.as_ref(), &format!("{:04x}/metadata", i), &debuginfo)
+                .await
+                .unwrap();
+        }
+        bucket
+            .put(&Path::from("ffff/metadata"), b"\xff".to_vec().into())
+            .await
+            .unwrap();
+
+        // Undecodable objects are skipped, and no entry is evicted.
+        let store = MetadataStore::persistent(bucket).await.unwrap();
+        store.store.run_pending_tasks();
+        assert!(store.fetch("ffff", &t).is_none());
+        for i in 0..ENTRIES {
+            assert!(store.fetch(&format!("{:04x}", i), &t).is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_membership_filter() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(object_store::memory::InMemory::new());
+        let store = MetadataStore::persistent(Arc::clone(&bucket))
+            .await
+            .unwrap();
+        let t = DebuginfoType::DebuginfoUnspecified;
+        store
+            .write(Debuginfo {
+                build_id: "abcd".to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(store.filter.may_contain("abcd/metadata"));
+        assert!(store.fetch("abcd", &t).is_some());
+        assert!(store.fetch("dcba", &t).is_none());
+
+        // The filter is persisted next to the metadata and loaded on restart.
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let persisted = read_filter(bucket.as_ref()).await.unwrap().unwrap();
+        assert!(persisted.may_contain("abcd/metadata"));
+        let restarted = MetadataStore::persistent(bucket).await.unwrap();
+        assert!(restarted.filter.may_contain("abcd/metadata"));
+        assert!(restarted.fetch("abcd", &t).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_tombstones() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(object_store::memory::InMemory::new());
+        let store = MetadataStore::persistent(Arc::clone(&bucket))
+            .await
+            .unwrap();
+        let t = DebuginfoType::DebuginfoUnspecified;
+        let now = Utc::now();
+        let policy = TombstonePolicy {
+            reports: 2,
+            ttl: std::time::Duration::from_secs(60),
+        };
+
+        assert!(store
+            .report_unavailable("abcd", &t, "stripped", &policy, now)
+            .is_none());
+        assert!(store.tombstone("abcd", &t, now).is_none());
+        let tombstone = store
+            .report_unavailable("abcd", &t, "stripped", &policy, now)
+            .unwrap();
+        assert_eq!(tombstone.reason, "stripped");
+        assert!(store.tombstone("abcd", &t, now).is_some());
+        assert!(store
+            .tombstone("abcd", &t, now + chrono::Duration::minutes(2))
+            .is_none());
+        assert!(store
+            .tombstone("abcd", &DebuginfoType::Executable, now)
+            .is_none());
+
+        // Tombstones survive restarts.
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let restarted = MetadataStore::persistent(Arc::clone(&bucket))
+            .await
+            .unwrap();
+        assert!(restarted.tombstone("abcd", &t, now).is_some());
+
+        restarted.clear_tombstone("abcd", &t);
+        assert!(restarted.tombstone("abcd", &t, now).is_none());
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let restarted = MetadataStore::persistent(bucket).await.unwrap();
+        assert!(restarted.tombstone("abcd", &t, now).is_none());
+
+        let disabled = TombstonePolicy::default();
+        for _ in 0..5 {
+            assert!(store
+                .report_unavailable("efgh", &t, "", &disabled, now)
+                .is_none());
+        }
     }
 }
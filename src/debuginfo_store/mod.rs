@@ -1,25 +1,59 @@
+mod bloom;
+pub mod bundle;
 mod debuginfod;
+mod decision;
+mod existence;
 mod fetcher;
+mod layout;
+mod limiter;
 mod metadata;
+mod oci;
+mod policy;
 mod reasons;
+mod routing;
+mod sources;
+mod staleness;
+mod strip;
 
 use self::debuginfopb::{
-    debuginfo_upload::State, upload_instructions::UploadStrategy, upload_request, DebuginfoType,
-    DebuginfoUpload, ShouldInitiateUploadRequest, UploadInstructions,
+    upload_instructions::UploadStrategy, upload_request, DebuginfoType, DebuginfoUpload,
+    ShouldInitiateUploadRequest, UploadInstructions,
 };
+use crate::agent_store::{AgentStore, UploadService};
+use crate::clock::Clock;
 use crate::debuginfopb::{
-    self, debuginfo::Source, debuginfo_service_server::DebuginfoService, BuildIdType, Debuginfo,
-    InitiateUploadRequest, InitiateUploadResponse, MarkUploadFinishedRequest,
-    MarkUploadFinishedResponse, ShouldInitiateUploadResponse, UploadRequest, UploadResponse,
+    self, debuginfo_service_server::DebuginfoService, BuildIdType, InitiateUploadRequest,
+    InitiateUploadResponse, MarkUploadFinishedRequest, MarkUploadFinishedResponse,
+    MarkUploadUnavailableRequest, MarkUploadUnavailableResponse, ShouldInitiateUploadResponse,
+    UploadRequest, UploadResponse,
 };
-use chrono::{DateTime, Duration, TimeZone, Utc};
+use crate::logging;
+use crate::mode::Mode;
+use crate::principal::Principal;
+use crate::storage::{self, bucket_error_to_status, CircuitBreaker};
+use crate::symbols::SymbolTable;
+use crate::webhooks::{Event, EventKind, Webhooks};
+use anyhow::Context;
+use chrono::{DateTime, Duration, Utc};
 pub use debuginfod::DebugInfod;
+use decision::UploadDecision;
+pub use existence::BackgroundExistenceChecks;
 pub use fetcher::DebuginfoFetcher;
-pub use metadata::MetadataStore;
-use object_store::ObjectStore;
+pub use layout::ObjectLayout;
+pub use limiter::UploadLimiter;
+pub use metadata::{ConflictError, MetadataStore, NewUpload, TombstonePolicy};
+use object_store::{path::Path, ObjectStore};
+pub use oci::{ImageExtractor, ImageReference};
+pub use policy::DebuginfodPolicy;
 use reasons::DebugInfoUploadReason;
+pub use routing::{BucketRouteConfig, BucketRoutes};
+pub use sources::{sources_router, SourceArchives};
+pub use staleness::{StalenessPolicy, UploadStaleness};
+use std::future::Future;
 use std::result::Result;
 use std::sync::Arc;
+pub use strip::SectionStripper;
+use tokio::time::Instant;
 use tokio_stream::StreamExt;
 use tonic::{async_trait, Request, Response, Status, Streaming};
 
@@ -50,8 +84,34 @@ pub struct DebuginfoStore {
     pub(crate) metadata: MetadataStore,
     pub(crate) debuginfod: DebugInfod,
     pub(crate) max_upload_duration: Duration,
+    pub(crate) upload_chunk_timeout: std::time::Duration,
     pub(crate) max_upload_size: i64,
-    pub(crate) bucket: Arc<dyn ObjectStore>,
+    /// Buckets the uploaded objects are stored in.
+    pub(crate) buckets: Arc<BucketRoutes>,
+    pub(crate) layout: ObjectLayout,
+    pub(crate) agents: Arc<AgentStore>,
+    pub(crate) upload_limiter: UploadLimiter,
+    pub(crate) staleness: UploadStaleness,
+    /// Breaker of `bucket`, checked before accepting uploads so they fail
+    /// fast while the bucket is down.
+    pub(crate) bucket_breaker: Arc<CircuitBreaker>,
+    /// Strips the sections symbolization doesn't need from uploaded
+    /// debuginfo once the upload finished. None keeps uploads as they are.
+    pub(crate) stripper: Option<SectionStripper>,
+    /// When reports of debuginfo that can't be extracted stop requesting its
+    /// upload.
+    pub(crate) tombstones: TombstonePolicy,
+    /// Uploads are refused in read-only mode.
+    pub(crate) mode: Mode,
+    /// Checks debuginfod in the background when set, requesting the upload
+    /// of unknown build IDs without waiting for debuginfod.
+    pub(crate) background_checks: Option<BackgroundExistenceChecks>,
+    /// Which build IDs may be looked up in debuginfod.
+    pub(crate) debuginfod_policy: Arc<DebuginfodPolicy>,
+    /// Notified when uploads finish or fail validation.
+    pub(crate) webhooks: Arc<Webhooks>,
+    /// Tells the time uploads are started, touched and become stale at.
+    pub(crate) clock: Arc<dyn Clock>,
 }
 
 #[async_trait]
@@ -61,10 +121,18 @@ impl DebuginfoService for DebuginfoStore {
         &self,
         request: Request<Streaming<UploadRequest>>,
     ) -> anyhow::Result<Response<UploadResponse>, Status> {
+        self.mode.check_writable()?;
         // log::info!("Upload request received");
+        self.bucket_breaker.check()?;
+        let mut permit = self.upload_limiter.acquire().await?;
+        let principal = Principal::of(&request);
         let mut stream = request.into_inner();
+        let deadline = Instant::now() + self.max_upload_duration.to_std().unwrap_or_default();
 
-        let request = match stream.message().await {
+        let request = match self
+            .within_upload_deadline(stream.message(), deadline)
+            .await?
+        {
             Ok(Some(msg)) => msg,
             Ok(None) => return Err(Status::invalid_argument("Empty request")),
 
@@ -80,68 +148,90 @@ impl DebuginfoService for DebuginfoStore {
             .data
             .ok_or_else(|| Status::invalid_argument("Missing data"))?;
         let upload_info = UploadRequestInfo::try_from(data)?;
-        let _ = self.validate_buildid(&upload_info.buildid)?;
-
-        let dbginfo = self
-            .metadata
-            .fetch(&upload_info.buildid, &upload_info.debuginfo_type)
-            .ok_or_else(|| {
-                Status::failed_precondition(
+        let (build_id, upload_id) = (upload_info.buildid.clone(), upload_info.upload_id.clone());
+        let principal = &principal;
+        logging::scope(
+            &[
+                ("build_id", &build_id),
+                ("upload_id", &upload_id),
+                ("principal", principal.id()),
+            ],
+            async move {
+                let _ = self.validate_buildid(&upload_info.buildid)?;
+
+                let dbginfo = self
+                    .metadata
+                    .fetch(&upload_info.buildid, &upload_info.debuginfo_type)
+                    .ok_or_else(|| {
+                        Status::failed_precondition(
                 "metadata not found, this indicates that the upload was not previously initiated"
             )
-            })?
-            .clone();
+                    })?
+                    .clone();
 
-        let upload = dbginfo.upload.ok_or_else(|| {
-            Status::invalid_argument(
+                let upload = dbginfo.upload.as_ref().ok_or_else(|| {
+                    Status::invalid_argument(
                 "metadata not found, this indicates that the upload was not previously initiated",
             )
-        })?;
+                })?;
 
-        if upload.id.ne(&upload_info.upload_id) {
-            return Err(Status::failed_precondition(
+                if upload.id.ne(&upload_info.upload_id) {
+                    return Err(Status::failed_precondition(
             "upload metadata not found, this indicates that the upload was not previously initiated"
         ));
-        }
-
-        let mut chunks = Vec::new();
-        while let Some(req) = stream.next().await {
-            let req = req?;
-            match req.data {
-                Some(upload_request::Data::ChunkData(chunk)) => {
-                    chunks.extend(chunk);
                 }
-                _ => {
-                    return Err(Status::invalid_argument(
-                        "provided no value or invalid data",
-                    ))
+
+                let mut chunks = Vec::new();
+                while let Some(req) = self.within_upload_deadline(stream.next(), deadline).await? {
+                    let req = req?;
+                    match req.data {
+                        Some(upload_request::Data::ChunkData(chunk)) => {
+                            // Chunks are buffered, so no more than the size
+                            // the upload was initiated with is accepted.
+                            if (chunks.len() + chunk.len()) as i64 > upload.size {
+                                return Err(Status::invalid_argument(format!(
+                                    "upload is larger than the {} bytes it was initiated with",
+                                    upload.size
+                                )));
+                            }
+                            self.agents.record_upload(
+                                principal,
+                                UploadService::Debuginfo,
+                                chunk.len() as u64,
+                            )?;
+                            self.staleness
+                                .touch(&upload_info.upload_id, self.time_now());
+                            permit.buffer(chunk.len() as u64);
+                            chunks.extend(chunk);
+                        }
+                        _ => {
+                            return Err(Status::invalid_argument(
+                                "provided no value or invalid data",
+                            ))
+                        }
+                    }
                 }
-            }
-        }
 
-        let size = chunks.len() as u64;
+                let size = chunks.len() as u64;
+                let path = self.layout.object_path(&dbginfo).map_err(|e| {
+                    Status::internal(format!("Invalid debuginfo object path: {}", e))
+                })?;
+                let bucket = self
+                    .buckets
+                    .bucket(&dbginfo)
+                    .map_err(|e| Status::internal(e.to_string()))?;
 
-        match self
-            .bucket
-            .put(
-                &object_store::path::Path::from(upload_info.upload_id),
-                chunks.into(),
-            )
-            .await
-        {
-            Ok(_) => {}
-            Err(e) => {
-                return Err(Status::internal(format!(
-                    "Failed to store debuginfo: {}",
-                    e
-                )))
-            }
-        };
+                if let Err(e) = bucket.put(&path, chunks.into()).await {
+                    return Err(bucket_error_to_status("Failed to store debuginfo", &e));
+                }
 
-        Ok(Response::new(UploadResponse {
-            build_id: upload_info.buildid,
-            size,
-        }))
+                Ok(Response::new(UploadResponse {
+                    build_id: upload_info.buildid,
+                    size,
+                }))
+            },
+        )
+        .await
     }
 
     // ShouldInitiateUpload returns whether an upload should be initiated for the
@@ -151,16 +241,32 @@ impl DebuginfoService for DebuginfoStore {
         &self,
         request: Request<ShouldInitiateUploadRequest>,
     ) -> anyhow::Result<Response<ShouldInitiateUploadResponse>, Status> {
+        self.mode.check_writable()?;
         // log::info!("ShouldInitiateUpload request received");
         let request = request.into_inner();
-        let _ = self.validate_buildid(&request.build_id)?;
-
-        let debuginfo = self.metadata.fetch(&request.build_id, &request.r#type());
-
-        match debuginfo {
-            Some(info) => self.handle_existing_debuginfo(&request, &info),
-            None => Box::pin(self.handle_new_build_id(&request)).await,
-        }
+        let build_id = request.build_id.clone();
+        logging::scope(&[("build_id", &build_id)], async move {
+            let _ = self.validate_buildid(&request.build_id)?;
+
+            let debuginfo = self.metadata.fetch(&request.build_id, &request.r#type());
+            let tombstoned = debuginfo.is_none()
+                && !request.force
+                && self
+                    .metadata
+                    .tombstone(&request.build_id, &request.r#type(), self.time_now())
+                    .is_some();
+
+            match decision::should_initiate_upload(&request, debuginfo.as_ref(), tombstoned, |u| {
+                self.is_upload_stale(u)
+            }) {
+                UploadDecision::Respond(reason, initiate) => {
+                    Ok(Response::new(reason.respond(initiate)))
+                }
+                UploadDecision::NewBuildId => Box::pin(self.handle_new_build_id(&request)).await,
+                UploadDecision::Inconsistent(e) => Err(Status::internal(e)),
+            }
+        })
+        .await
     }
 
     /// InitiateUpload returns a strategy and information to upload debug info for a given build_id.
@@ -168,265 +274,494 @@ impl DebuginfoService for DebuginfoStore {
         &self,
         request: Request<InitiateUploadRequest>,
     ) -> anyhow::Result<Response<InitiateUploadResponse>, Status> {
+        self.mode.check_writable()?;
         // log::info!("InitiateUpload request received");
 
         let request = request.into_inner();
+        let build_id = request.build_id.clone();
+        logging::scope(&[("build_id", &build_id)], async move {
+            if request.hash.is_empty() {
+                return Err(Status::invalid_argument("Hash is empty"));
+            }
+            layout::check_path_component("hash", &request.hash)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
 
-        if request.hash.is_empty() {
-            return Err(Status::invalid_argument("Hash is empty"));
-        }
+            if request.size == 0 {
+                return Err(Status::invalid_argument("Size is zero"));
+            }
+            self.bucket_breaker.check()?;
 
-        if request.size == 0 {
-            return Err(Status::invalid_argument("Size is zero"));
-        }
+            // The generation the upload decision is based on. Marking the upload
+            // fails if another request modified the metadata in the meantime.
+            let generation = self
+                .metadata
+                .generation(&request.build_id, &request.r#type());
+
+            let siup = ShouldInitiateUploadRequest {
+                build_id: request.build_id.clone(),
+                hash: request.hash.clone(),
+                force: request.force,
+                r#type: request.r#type().into(),
+                build_id_type: request.build_id_type,
+            };
+
+            let should_initiate = self.should_initiate_upload(Request::new(siup)).await?;
+            let should_initiate = should_initiate.into_inner();
+
+            if !should_initiate.should_initiate_upload {
+                if should_initiate
+                    .reason
+                    .eq_ignore_ascii_case(&DebugInfoUploadReason::DebugInfoEqual.to_string())
+                {
+                    return Err(Status::already_exists("Debuginfo already exists"));
+                }
+                return Err(Status::failed_precondition(format!(
+                    "upload should not have been attempted to be initiated, \
+                     a previous check should have failed with {}",
+                    should_initiate.reason
+                )));
+            }
 
-        let siup = ShouldInitiateUploadRequest {
-            build_id: request.build_id.clone(),
-            hash: request.hash.clone(),
-            force: request.force,
-            r#type: request.r#type().into(),
-            build_id_type: request.build_id_type,
-        };
+            if request.size > self.max_upload_size {
+                return Err(Status::invalid_argument(format!(
+                    "Upload size {} exceeds the maximum allowed size {}",
+                    request.size, self.max_upload_size,
+                )));
+            }
 
-        let should_initiate = self.should_initiate_upload(Request::new(siup)).await?;
-        let should_initiate = should_initiate.into_inner();
+            let upload_id = ulid::Ulid::new().to_string();
+            let upload_started = self.time_now();
+            // let upload_expired = upload_started + self.max_upload_duration;
 
-        if !should_initiate.should_initiate_upload {
-            if should_initiate
-                .reason
-                .eq_ignore_ascii_case(&DebugInfoUploadReason::DebugInfoEqual.to_string())
             {
-                return Err(Status::already_exists("Debuginfo already exists"));
+                let _ = self
+                    .metadata
+                    .mark_as_uploading(
+                        &request.build_id,
+                        NewUpload {
+                            id: &upload_id,
+                            hash: &request.hash,
+                            size: request.size,
+                            started_at: upload_started,
+                        },
+                        &request.r#type(),
+                        generation,
+                    )
+                    .map_err(|e| metadata_error_to_status("uploading", e))?;
             }
-            return Err(Status::failed_precondition(format!( "upload should not have been attempted to be initiated, a previous check should have failed with {}", should_initiate.reason )));
-        }
-
-        if request.size > self.max_upload_size {
-            return Err(Status::invalid_argument(format!(
-                "Upload size {} exceeds the maximum allowed size {}",
-                request.size, self.max_upload_size,
-            )));
-        }
-
-        let upload_id = ulid::Ulid::new().to_string();
-        let upload_started = self.time_now();
-        // let upload_expired = upload_started + self.max_upload_duration;
-
-        {
-            let _ = self
-                .metadata
-                .mark_as_uploading(
-                    &request.build_id,
-                    &upload_id,
-                    &request.hash,
-                    &request.r#type(),
-                    upload_started,
-                )
-                .map_err(|e| {
-                    Status::internal(format!(
-                        "Failed to mark metadata as uploading. details: {e}"
-                    ))
-                })?;
-        }
 
-        Ok(Response::new(InitiateUploadResponse {
-            upload_instructions: Some(UploadInstructions {
-                upload_id,
-                build_id: request.build_id,
-                upload_strategy: UploadStrategy::Grpc.into(),
-                signed_url: "".into(),
-                r#type: request.r#type,
-            }),
-        }))
+            Ok(Response::new(InitiateUploadResponse {
+                upload_instructions: Some(UploadInstructions {
+                    upload_id,
+                    build_id: request.build_id,
+                    upload_strategy: UploadStrategy::Grpc.into(),
+                    signed_url: "".into(),
+                    r#type: request.r#type,
+                }),
+            }))
+        })
+        .await
     }
     /// MarkUploadFinished marks the upload as finished for a given build_id.
     async fn mark_upload_finished(
         &self,
         request: Request<MarkUploadFinishedRequest>,
     ) -> anyhow::Result<Response<MarkUploadFinishedResponse>, Status> {
+        self.mode.check_writable()?;
         // log::info!("MarkUploadFinished request received");
 
         let request = request.into_inner();
-        let _ = self.validate_buildid(&request.build_id)?;
-        let _ = self
-            .metadata
-            .mark_as_uploaded(
+        let (build_id, upload_id) = (request.build_id.clone(), request.upload_id.clone());
+        logging::scope(
+            &[("build_id", &build_id), ("upload_id", &upload_id)],
+            async move {
+                let _ = self.validate_buildid(&request.build_id)?;
+                // Retries of a finished upload succeed without verifying the
+                // object again, which may have been stripped since.
+                let finished = self
+                    .metadata
+                    .fetch(&request.build_id, &request.r#type())
+                    .and_then(|dbginfo| dbginfo.upload)
+                    .is_some_and(|upload| {
+                        upload.id == request.upload_id
+                            && upload.state() == debuginfopb::debuginfo_upload::State::Uploaded
+                    });
+                if finished {
+                    return Ok(Response::new(MarkUploadFinishedResponse::default()));
+                }
+                self.verify_uploaded_object(
+                    &request.build_id,
+                    &request.upload_id,
+                    &request.r#type(),
+                )
+                .await?;
+                let checksum = self
+                    .finalize_uploaded_object(&request.build_id, &request.r#type())
+                    .await;
+                let _ = self
+                    .metadata
+                    .mark_as_uploaded(
+                        &request.build_id,
+                        &request.upload_id,
+                        &request.r#type(),
+                        &checksum,
+                        self.buckets.route(&request.build_id),
+                        self.time_now(),
+                    )
+                    .map_err(|e| metadata_error_to_status("uploaded", e))?;
+                self.staleness.finish(&request.upload_id);
+                self.metadata
+                    .clear_tombstone(&request.build_id, &request.r#type());
+                self.webhooks.notify(Event {
+                    upload_id: request.upload_id.clone(),
+                    ..Event::new(EventKind::UploadFinished, &request.build_id)
+                });
+                Ok(Response::new(MarkUploadFinishedResponse::default()))
+            },
+        )
+        .await
+    }
+
+    /// MarkUploadUnavailable records that the agent could not extract the
+    /// debuginfo of a build_id.
+    async fn mark_upload_unavailable(
+        &self,
+        request: Request<MarkUploadUnavailableRequest>,
+    ) -> anyhow::Result<Response<MarkUploadUnavailableResponse>, Status> {
+        self.mode.check_writable()?;
+        let request = request.into_inner();
+        let build_id = request.build_id.clone();
+        logging::scope(&[("build_id", &build_id)], async move {
+            let _ = self.validate_buildid(&request.build_id)?;
+            let tombstone = self.metadata.report_unavailable(
                 &request.build_id,
-                &request.upload_id,
                 &request.r#type(),
+                &request.reason,
+                &self.tombstones,
                 self.time_now(),
-            )
-            .map_err(|e| {
-                Status::internal(format!("Failed to mark metadata as uploaded. details: {e}"))
-            })?;
-        Ok(Response::new(MarkUploadFinishedResponse::default()))
+            );
+            if tombstone.is_some() {
+                log::info!(
+                    "Not requesting uploads of build_id {} for {}, \
+                     agents could not extract its debuginfo: {}",
+                    request.build_id,
+                    humantime::format_duration(self.tombstones.ttl),
+                    request.reason
+                );
+            }
+            Ok(Response::new(MarkUploadUnavailableResponse {
+                tombstoned: tombstone.is_some(),
+                expires_at: tombstone.and_then(|t| t.expires_at),
+            }))
+        })
+        .await
     }
 }
 
-impl DebuginfoStore {
-    fn validate_buildid(&self, id: &str) -> anyhow::Result<(), Status> {
-        if id.len() <= 2 {
-            return Err(Status::invalid_argument("unexpectedly short input"));
-        }
-
-        Ok(())
+/// Converts a metadata write error to a Status. Concurrent modifications are
+/// reported as Aborted so that clients know they can retry.
+fn metadata_error_to_status(state: &str, e: anyhow::Error) -> Status {
+    if e.downcast_ref::<ConflictError>().is_some() {
+        return Status::aborted(format!("Failed to mark metadata as {state}. details: {e}"));
     }
+    Status::internal(format!("Failed to mark metadata as {state}. details: {e}"))
+}
 
-    fn is_upload_stale(&self, upload: &DebuginfoUpload) -> bool {
-        match upload.started_at {
-            Some(ts) => {
-                let started_at = Utc
-                    .timestamp_opt(ts.seconds, ts.nanos as u32)
-                    .earliest()
-                    .unwrap_or(Utc::now());
-
-                started_at + (self.max_upload_duration + Duration::minutes(2)) < self.time_now()
+impl DebuginfoStore {
+    /// Stores debuginfo the server found itself, going through the upload
+    /// protocol so that it's handled like an upload of an agent. Returns
+    /// false if the build ID didn't need it.
+    pub async fn ingest(&self, build_id: &str, data: Vec<u8>) -> anyhow::Result<bool> {
+        let r#type = DebuginfoType::DebuginfoUnspecified;
+        let request = Request::new(InitiateUploadRequest {
+            build_id: build_id.to_string(),
+            size: data.len() as i64,
+            hash: evprofiler::client::hash(&data),
+            force: false,
+            r#type: r#type.into(),
+            build_id_type: BuildIdType::Gnu.into(),
+        });
+        let instructions = match self.initiate_upload(request).await {
+            Ok(response) => response
+                .into_inner()
+                .upload_instructions
+                .unwrap_or_default(),
+            Err(status)
+                if matches!(
+                    status.code(),
+                    tonic::Code::AlreadyExists | tonic::Code::FailedPrecondition
+                ) =>
+            {
+                return Ok(false)
             }
-            None => false,
-        }
-    }
-
-    fn time_now(&self) -> DateTime<Utc> {
-        Utc::now()
+            Err(status) => return Err(status.into()),
+        };
+        let debuginfo = self
+            .metadata
+            .fetch(build_id, &r#type)
+            .context("metadata of the upload disappeared")?;
+        let path = self.layout.object_path(&debuginfo)?;
+        self.buckets
+            .bucket(&debuginfo)?
+            .put(&path, data.into())
+            .await?;
+        self.mark_upload_finished(Request::new(MarkUploadFinishedRequest {
+            build_id: build_id.to_string(),
+            upload_id: instructions.upload_id,
+            r#type: r#type.into(),
+        }))
+        .await?;
+        Ok(true)
     }
 
-    fn handle_existing_debuginfo(
+    /// Verifies that the object of the upload exists in the bucket and has the
+    /// size announced when the upload was initiated, so that metadata is never
+    /// marked as uploaded for an upload that failed.
+    async fn verify_uploaded_object(
         &self,
-        request: &ShouldInitiateUploadRequest,
-        debuginfo: &Debuginfo,
-    ) -> anyhow::Result<Response<ShouldInitiateUploadResponse>, Status> {
-        match Source::try_from(debuginfo.source) {
-            Ok(Source::Debuginfod) => self.handle_debuginfod_source(debuginfo),
-            Ok(Source::Upload) => self.handle_upload_source(request, debuginfo),
-            _ => Err(Status::internal("Inconsistent metadata: unknown source")),
+        build_id: &str,
+        upload_id: &str,
+        req_type: &DebuginfoType,
+    ) -> Result<(), Status> {
+        let dbginfo = self
+            .metadata
+            .fetch(build_id, req_type)
+            .ok_or_else(|| Status::failed_precondition("Debuginfo not found"))?;
+
+        let upload = match &dbginfo.upload {
+            Some(upload) if upload.id == upload_id => upload,
+            _ => {
+                return Err(Status::failed_precondition(
+                    "upload metadata not found, this indicates that the upload was not previously initiated",
+                ))
+            }
+        };
+
+        let path = self
+            .layout
+            .object_path(&dbginfo)
+            .map_err(|e| Status::internal(format!("Invalid debuginfo object path: {}", e)))?;
+        let bucket = self
+            .buckets
+            .bucket(&dbginfo)
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let invalid = |reason: String| {
+            self.webhooks.notify(Event {
+                upload_id: upload_id.to_string(),
+                reason: reason.clone(),
+                ..Event::new(EventKind::ValidationFailed, build_id)
+            });
+            Status::failed_precondition(reason)
+        };
+        let meta = match bucket.head(&path).await {
+            Ok(meta) => meta,
+            Err(object_store::Error::NotFound { .. }) => {
+                return Err(invalid(format!(
+                "debuginfo of upload {} was not found in the bucket, the upload must be retried",
+                upload_id
+            )))
+            }
+            Err(e) => {
+                return Err(bucket_error_to_status(
+                    "Failed to check debuginfo object",
+                    &e,
+                ))
+            }
+        };
+
+        if upload.size > 0 && meta.size as i64 != upload.size {
+            return Err(invalid(format!(
+                "debuginfo of upload {} has size {}, expected {}",
+                upload_id, meta.size, upload.size
+            )));
         }
+
+        Ok(())
     }
 
-    fn handle_upload_source(
-        &self,
-        request: &ShouldInitiateUploadRequest,
-        debuginfo: &Debuginfo,
-    ) -> anyhow::Result<Response<ShouldInitiateUploadResponse>, Status> {
-        let upload = debuginfo
-            .upload
+    /// Strips the uploaded debuginfo of the sections that are not needed for
+    /// symbolization, if configured, and returns the checksum of the object
+    /// as stored. Symbol tables are stored sorted and compressed. Executables
+    /// and sources are kept as they are, since they are uploaded for their
+    /// contents. Failures to strip only mean the upload is stored unstripped;
+    /// failures to read it mean no checksum is recorded.
+    async fn finalize_uploaded_object(&self, build_id: &str, req_type: &DebuginfoType) -> String {
+        let Some((bucket, path)) = self.metadata.fetch(build_id, req_type).and_then(|dbginfo| {
+            let bucket = self.buckets.bucket(&dbginfo).ok()?;
+            Some((Arc::clone(bucket), self.layout.object_path(&dbginfo).ok()?))
+        }) else {
+            return String::new();
+        };
+        let data = match async { anyhow::Ok(bucket.get(&path).await?.bytes().await?) }.await {
+            Ok(data) => data,
+            Err(e) => {
+                log::warn!(
+                    "Failed to read debuginfo of build ID {}, no checksum is recorded: {}",
+                    build_id,
+                    e
+                );
+                return String::new();
+            }
+        };
+        if *req_type == DebuginfoType::Symbols {
+            return self
+                .compact_symbol_table(build_id, &bucket, &path, &data)
+                .await;
+        }
+        let Some(stripper) = self
+            .stripper
             .as_ref()
-            .ok_or_else(|| Status::internal("Inconsistent metadata: missing upload info"))?;
-
-        match State::try_from(upload.state) {
-            Ok(State::Uploading) => self.handle_uploading_state(upload),
-            Ok(State::Uploaded) => self.handle_uploaded_state(request, debuginfo),
-            _ => Err(Status::internal(
-                "Inconsistent metadata: unknown upload state",
-            )),
+            .filter(|_| *req_type == DebuginfoType::DebuginfoUnspecified)
+        else {
+            return storage::checksum(&data);
+        };
+
+        let res = async {
+            match stripper.strip(&data)? {
+                Some(stripped) => {
+                    let checksum = storage::checksum(&stripped);
+                    let size = stripped.len();
+                    bucket.put(&path, stripped.into()).await?;
+                    anyhow::Ok(Some((size, checksum)))
+                }
+                None => Ok(None),
+            }
+        };
+        match res.await {
+            Ok(Some((size, checksum))) => {
+                log::info!(
+                    "Stripped debuginfo of build ID {} from {} to {} bytes",
+                    build_id,
+                    data.len(),
+                    size
+                );
+                checksum
+            }
+            Ok(None) => storage::checksum(&data),
+            Err(e) => {
+                log::warn!(
+                    "Failed to strip debuginfo of build ID {}, keeping it as uploaded: {}",
+                    build_id,
+                    e
+                );
+                storage::checksum(&data)
+            }
         }
     }
 
-    fn handle_uploading_state(
+    /// Stores the uploaded symbol table sorted and compressed, and returns
+    /// the checksum of the object as stored. Tables that can't be parsed are
+    /// kept as uploaded, and the symbolizer falls back to debuginfo for them.
+    async fn compact_symbol_table(
         &self,
-        upload: &DebuginfoUpload,
-    ) -> anyhow::Result<Response<ShouldInitiateUploadResponse>, Status> {
-        if self.is_upload_stale(upload) {
-            Ok(Response::new(ShouldInitiateUploadResponse {
-                should_initiate_upload: true,
-                reason: DebugInfoUploadReason::UploadStale.to_string(),
-            }))
-        } else {
-            Ok(Response::new(ShouldInitiateUploadResponse {
-                should_initiate_upload: false,
-                reason: DebugInfoUploadReason::UploadInProgress.to_string(),
-            }))
+        build_id: &str,
+        bucket: &Arc<dyn ObjectStore>,
+        path: &Path,
+        data: &[u8],
+    ) -> String {
+        let res = async {
+            let compacted = SymbolTable::parse(data)?.encode()?;
+            let checksum = storage::checksum(&compacted);
+            bucket.put(path, compacted.into()).await?;
+            anyhow::Ok(checksum)
+        };
+        match res.await {
+            Ok(checksum) => checksum,
+            Err(e) => {
+                log::warn!(
+                    "Invalid symbol table uploaded for build ID {}, keeping it as uploaded: {:#}",
+                    build_id,
+                    e
+                );
+                storage::checksum(data)
+            }
         }
     }
 
-    fn handle_uploaded_state(
+    /// Awaits the next message of an upload stream. Fails with DeadlineExceeded
+    /// if the agent stays silent for longer than the chunk timeout or the
+    /// upload takes longer than the maximum upload duration.
+    async fn within_upload_deadline<F: Future>(
         &self,
-        request: &ShouldInitiateUploadRequest,
-        debuginfo: &Debuginfo,
-    ) -> anyhow::Result<Response<ShouldInitiateUploadResponse>, Status> {
-        if !self.is_valid_elf(debuginfo) {
-            return self.handle_invalid_elf(request);
+        next: F,
+        deadline: Instant,
+    ) -> Result<F::Output, Status> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let timeout = self.upload_chunk_timeout.min(remaining);
+
+        match tokio::time::timeout(timeout, next).await {
+            Ok(output) => Ok(output),
+            Err(_) if timeout == remaining => {
+                let max = self.max_upload_duration.to_std().unwrap_or_default();
+                Err(Status::deadline_exceeded(format!(
+                    "upload exceeded the maximum upload duration of {}",
+                    humantime::format_duration(max)
+                )))
+            }
+            Err(_) => Err(Status::deadline_exceeded(format!(
+                "no upload chunk received within {}",
+                humantime::format_duration(self.upload_chunk_timeout)
+            ))),
         }
+    }
 
-        if request.hash.is_empty() {
-            return Ok(Response::new(ShouldInitiateUploadResponse {
-                should_initiate_upload: true,
-                reason: DebugInfoUploadReason::DebugInfoInvalid.to_string(),
-            }));
+    fn validate_buildid(&self, id: &str) -> anyhow::Result<(), Status> {
+        if id.len() <= 2 {
+            return Err(Status::invalid_argument("unexpectedly short input"));
         }
+        layout::check_path_component("build_id", id)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
 
-        self.compare_hash(request, debuginfo)
+        Ok(())
     }
 
-    fn is_valid_elf(&self, debuginfo: &Debuginfo) -> bool {
-        debuginfo
-            .quality
-            .as_ref()
-            .map_or(false, |q| !q.not_valid_elf)
+    fn is_upload_stale(&self, upload: &DebuginfoUpload) -> bool {
+        self.staleness
+            .is_stale(upload, self.max_upload_duration, self.time_now())
     }
 
-    fn handle_invalid_elf(
-        &self,
-        request: &ShouldInitiateUploadRequest,
-    ) -> anyhow::Result<Response<ShouldInitiateUploadResponse>, Status> {
-        Ok(Response::new(ShouldInitiateUploadResponse {
-            should_initiate_upload: request.force,
-            reason: if request.force {
-                DebugInfoUploadReason::DebugInfoAlreadyExistsButForced.to_string()
-            } else {
-                DebugInfoUploadReason::DebugInfoAlreadyExists.to_string()
-            },
-        }))
+    fn time_now(&self) -> DateTime<Utc> {
+        self.clock.now()
     }
 
-    fn compare_hash(
+    async fn handle_new_build_id(
         &self,
         request: &ShouldInitiateUploadRequest,
-        debuginfo: &Debuginfo,
     ) -> anyhow::Result<Response<ShouldInitiateUploadResponse>, Status> {
-        match &debuginfo.upload {
-            Some(upload) if upload.hash.eq(&request.hash) => {
-                Ok(Response::new(ShouldInitiateUploadResponse {
-                    should_initiate_upload: false,
-                    reason: DebugInfoUploadReason::DebugInfoEqual.to_string(),
-                }))
-            }
-            Some(_) => Ok(Response::new(ShouldInitiateUploadResponse {
-                should_initiate_upload: true,
-                reason: DebugInfoUploadReason::DebugInfoNotEqual.to_string(),
-            })),
-            None => Ok(Response::new(ShouldInitiateUploadResponse {
-                should_initiate_upload: true,
-                reason: DebugInfoUploadReason::DebugInfoInvalid.to_string(),
-            })),
+        if self.debuginfod.is_disabled() {
+            return Ok(Response::new(
+                DebugInfoUploadReason::FirstTimeSeen.respond(true),
+            ));
         }
-    }
 
-    fn handle_debuginfod_source(
-        &self,
-        debuginfo: &Debuginfo,
-    ) -> anyhow::Result<Response<ShouldInitiateUploadResponse>, Status> {
-        Ok(Response::new(ShouldInitiateUploadResponse {
-            should_initiate_upload: true,
-            reason: if !self.is_valid_elf(debuginfo) {
-                DebugInfoUploadReason::DebugInfodSource.to_string()
-            } else {
-                DebugInfoUploadReason::DebugInfodInvalid.to_string()
-            },
-        }))
-    }
+        // debuginfod serves no symbol tables.
+        if request.r#type() == DebuginfoType::Symbols {
+            return Ok(Response::new(
+                DebugInfoUploadReason::FirstTimeSeen.respond(true),
+            ));
+        }
 
-    async fn handle_new_build_id(
-        &self,
-        request: &ShouldInitiateUploadRequest,
-    ) -> anyhow::Result<Response<ShouldInitiateUploadResponse>, Status> {
         if !matches!(
             request.build_id_type(),
             BuildIdType::Gnu | BuildIdType::UnknownUnspecified
-        ) {
-            return Ok(Response::new(ShouldInitiateUploadResponse {
-                should_initiate_upload: true,
-                reason: DebugInfoUploadReason::FirstTimeSeen.to_string(),
-            }));
+        ) || !self.debuginfod_policy.allows(&request.build_id)
+        {
+            return Ok(Response::new(
+                DebugInfoUploadReason::FirstTimeSeen.respond(true),
+            ));
+        }
+
+        if let Some(checks) = &self.background_checks {
+            checks.start(
+                &self.debuginfod,
+                &self.metadata,
+                &request.build_id,
+                request.r#type(),
+            );
+            return Ok(Response::new(
+                DebugInfoUploadReason::FirstTimeSeen.respond(true),
+            ));
         }
 
         // Check existence outside of the lock
@@ -437,15 +772,250 @@ impl DebuginfoStore {
             let _ = self
                 .metadata
                 .mark_as_debuginfod_source(exists, &build_id, &request.r#type());
-            Ok(Response::new(ShouldInitiateUploadResponse {
-                should_initiate_upload: false,
-                reason: DebugInfoUploadReason::DebugInfoInDebugInfod.to_string(),
-            }))
+            Ok(Response::new(
+                DebugInfoUploadReason::DebugInfoInDebugInfod.respond(false),
+            ))
         } else {
-            Ok(Response::new(ShouldInitiateUploadResponse {
-                should_initiate_upload: true,
-                reason: DebugInfoUploadReason::FirstTimeSeen.to_string(),
-            }))
+            Ok(Response::new(
+                DebugInfoUploadReason::FirstTimeSeen.respond(true),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debuginfopb::debuginfo_upload::State;
+    use object_store::{memory::InMemory, path::Path};
+
+    fn test_store(clock: Arc<dyn Clock>) -> DebuginfoStore {
+        DebuginfoStore {
+            metadata: MetadataStore::new(),
+            debuginfod: DebugInfod::disabled(),
+            max_upload_duration: Duration::minutes(15),
+            upload_chunk_timeout: std::time::Duration::from_secs(30),
+            max_upload_size: 1000,
+            buckets: Arc::new(BucketRoutes::new(Arc::new(InMemory::new()))),
+            layout: ObjectLayout::default(),
+            agents: Arc::new(AgentStore::default()),
+            upload_limiter: UploadLimiter::default(),
+            staleness: UploadStaleness::default(),
+            bucket_breaker: Arc::default(),
+            stripper: None,
+            tombstones: TombstonePolicy::default(),
+            mode: Mode::default(),
+            background_checks: None,
+            debuginfod_policy: Arc::default(),
+            webhooks: Arc::default(),
+            clock,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mark_upload_finished_requires_object() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let mut store = test_store(Arc::new(crate::clock::SystemClock));
+        store.buckets = Arc::new(BucketRoutes::new(Arc::clone(&bucket)));
+        let t = DebuginfoType::DebuginfoUnspecified;
+        store
+            .metadata
+            .mark_as_uploading(
+                "abcd",
+                NewUpload {
+                    id: "upload-1",
+                    hash: "hash",
+                    size: 3,
+                    started_at: Utc::now(),
+                },
+                &t,
+                0,
+            )
+            .unwrap();
+
+        let request = || {
+            Request::new(MarkUploadFinishedRequest {
+                build_id: "abcd".into(),
+                upload_id: "upload-1".into(),
+                r#type: t.into(),
+            })
+        };
+
+        let err = store.mark_upload_finished(request()).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+
+        let path = Path::from("upload-1");
+        bucket.put(&path, b"el".to_vec().into()).await.unwrap();
+        let err = store.mark_upload_finished(request()).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+
+        bucket.put(&path, b"elf".to_vec().into()).await.unwrap();
+        store.mark_upload_finished(request()).await.unwrap();
+
+        // Retries succeed even if the object was stripped since.
+        bucket.put(&path, b"e".to_vec().into()).await.unwrap();
+        store.mark_upload_finished(request()).await.unwrap();
+        assert_eq!(
+            store
+                .metadata
+                .fetch("abcd", &t)
+                .unwrap()
+                .upload
+                .unwrap()
+                .state(),
+            State::Uploaded
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ingest_routes_bucket() {
+        let (default, private): (Arc<dyn ObjectStore>, Arc<dyn ObjectStore>) =
+            (Arc::new(InMemory::new()), Arc::new(InMemory::new()));
+        let mut store = test_store(Arc::new(crate::clock::SystemClock));
+        store.buckets = Arc::new(BucketRoutes::new(Arc::clone(&default)).with_route(
+            "private",
+            vec!["ab".into()],
+            Arc::clone(&private),
+        ));
+        assert!(store.ingest("abcd", b"elf".to_vec()).await.unwrap());
+
+        let t = DebuginfoType::DebuginfoUnspecified;
+        let debuginfo = store.metadata.fetch("abcd", &t).unwrap();
+        let upload = debuginfo.upload.as_ref().unwrap();
+        assert_eq!(
+            (upload.state(), upload.bucket.as_str()),
+            (State::Uploaded, "private")
+        );
+        let path = store.layout.object_path(&debuginfo).unwrap();
+        assert!(private.head(&path).await.is_ok());
+        assert!(default.head(&path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stale_upload_boundary() {
+        use chrono::TimeZone;
+
+        let t0 = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(crate::clock::ManualClock::new(t0));
+        let mut store = test_store(clock.clone());
+        let t = DebuginfoType::DebuginfoUnspecified;
+        store
+            .metadata
+            .mark_as_uploading(
+                "abcd",
+                NewUpload {
+                    id: "upload-1",
+                    hash: "hash",
+                    size: 3,
+                    started_at: t0,
+                },
+                &t,
+                0,
+            )
+            .unwrap();
+
+        // With the default policy, the upload is stale once the maximum
+        // upload duration and the grace period have passed since it started.
+        let deadline = t0 + Duration::minutes(15) + Duration::seconds(120);
+        clock.set(deadline);
+        assert!(!initiate(&store).await);
+        clock.advance(Duration::milliseconds(1));
+        assert!(initiate(&store).await);
+
+        // By last activity, every chunk pushes the deadline back by the
+        // grace period.
+        store.staleness = UploadStaleness::new(
+            StalenessPolicy::LastActivity,
+            std::time::Duration::from_secs(60),
+        );
+        let touched = t0 + Duration::minutes(20);
+        store.staleness.touch("upload-1", touched);
+        clock.set(touched + Duration::seconds(60));
+        assert!(!initiate(&store).await);
+        clock.advance(Duration::milliseconds(1));
+        assert!(initiate(&store).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_upload_deadline() {
+        use crate::debuginfopb::UploadInfo;
+        use prost::Message;
+        use tonic::codec::{Codec, ProstCodec};
+
+        let mut store = test_store(Arc::new(crate::clock::SystemClock));
+        store.upload_limiter = UploadLimiter::new(1, std::time::Duration::ZERO);
+        let t = DebuginfoType::DebuginfoUnspecified;
+        store
+            .metadata
+            .mark_as_uploading(
+                "abcd",
+                NewUpload {
+                    id: "upload-1",
+                    hash: "hash",
+                    size: 3,
+                    started_at: Utc::now(),
+                },
+                &t,
+                0,
+            )
+            .unwrap();
+
+        // Sends the upload info and then stalls.
+        let stalled = || {
+            let info = UploadRequest {
+                data: Some(upload_request::Data::Info(UploadInfo {
+                    build_id: "abcd".into(),
+                    upload_id: "upload-1".into(),
+                    r#type: t.into(),
+                })),
+            };
+            let message = info.encode_to_vec();
+            let mut frame = vec![0];
+            frame.extend((message.len() as u32).to_be_bytes());
+            frame.extend(message);
+            let body = axum::body::Body::from_stream(
+                tokio_stream::iter([Ok::<_, std::io::Error>(frame)]).chain(tokio_stream::pending()),
+            );
+            let decoder = ProstCodec::<UploadResponse, UploadRequest>::default().decoder();
+            Request::new(Streaming::new_request(decoder, body, None, None))
+        };
+
+        let err = store.upload(stalled()).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::DeadlineExceeded);
+        assert_eq!(err.message(), "no upload chunk received within 30s");
+
+        // The upload slot is released, and the maximum upload duration
+        // applies when it ends before the chunk timeout.
+        store.max_upload_duration = Duration::seconds(10);
+        let err = store.upload(stalled()).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::DeadlineExceeded);
+        assert_eq!(
+            err.message(),
+            "upload exceeded the maximum upload duration of 10s"
+        );
+        assert!(store.upload_limiter.acquire().await.is_ok());
+    }
+
+    async fn initiate(store: &DebuginfoStore) -> bool {
+        let request = Request::new(ShouldInitiateUploadRequest {
+            build_id: "abcd".into(),
+            hash: "hash".into(),
+            r#type: DebuginfoType::DebuginfoUnspecified.into(),
+            ..Default::default()
+        });
+        let response = store.should_initiate_upload(request).await.unwrap();
+        let response = response.into_inner();
+        if response.should_initiate_upload {
+            assert_eq!(
+                response.reason,
+                DebugInfoUploadReason::UploadStale.to_string()
+            );
+        } else {
+            assert_eq!(
+                response.reason,
+                DebugInfoUploadReason::UploadInProgress.to_string()
+            );
         }
+        response.should_initiate_upload
     }
 }
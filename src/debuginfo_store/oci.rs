@@ -0,0 +1,560 @@
+use super::DebuginfoStore;
+use anyhow::{bail, ensure, Context};
+use object::{Object, ObjectSymbol};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use ring::digest::{self, SHA256, SHA512};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::io::{Read, Seek, Write};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use url::Url;
+
+static IMAGE_BINARIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_image_binaries_total",
+        "Total number of binaries with a build ID found in container image layers, by whether their debuginfo was stored.",
+        &["result"]
+    )
+    .unwrap()
+});
+
+const DOCKER_HUB: &str = "registry-1.docker.io";
+
+const MANIFEST_TYPES: [&str; 4] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+/// ImageReference names an image in a registry, parsed from references like
+/// `nginx`, `ghcr.io/org/app:v1` or `registry:5000/app@sha256:...`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ImageReference {
+    pub registry: String,
+    pub repository: String,
+    /// Tag or digest.
+    pub reference: String,
+}
+
+impl std::str::FromStr for ImageReference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (name, reference) = match s.split_once('@') {
+            Some((name, digest)) => (name, digest.to_string()),
+            None => match s.rsplit_once(':') {
+                Some((name, tag)) if !tag.contains('/') => (name, tag.to_string()),
+                _ => (s, "latest".to_string()),
+            },
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, path))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host.to_string(), path.to_string())
+            }
+            Some(_) => (DOCKER_HUB.to_string(), name.to_string()),
+            None => (DOCKER_HUB.to_string(), format!("library/{}", name)),
+        };
+        ensure!(
+            !repository.is_empty() && !reference.is_empty(),
+            "invalid image reference {:?}",
+            s
+        );
+        Ok(Self {
+            registry,
+            repository,
+            reference,
+        })
+    }
+}
+
+impl std::fmt::Display for ImageReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let separator = if self.reference.contains(':') {
+            '@'
+        } else {
+            ':'
+        };
+        write!(
+            f,
+            "{}/{}{}{}",
+            self.registry, self.repository, separator, self.reference
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    #[serde(default)]
+    media_type: String,
+    /// Platform manifests of an index.
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: String,
+    digest: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    platform: Option<Platform>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+    os: String,
+    architecture: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Token {
+    #[serde(default)]
+    token: String,
+    #[serde(default)]
+    access_token: String,
+}
+
+/// Returns the architecture of the image platform matching this server's.
+fn platform_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        arch => arch,
+    }
+}
+
+/// ImageExtractor pulls the layers of container images, finds the ELF
+/// binaries with symbols in them and stores their debuginfo, so that agents
+/// don't have to upload binaries of public images. Registries are accessed
+/// anonymously, and every image is extracted once.
+pub struct ImageExtractor {
+    client: ureq::Agent,
+    debuginfo: Arc<DebuginfoStore>,
+    /// Files larger than this are skipped.
+    max_file_size: u64,
+    /// Layers larger than this are skipped.
+    max_layer_size: u64,
+    extracted: Mutex<HashSet<ImageReference>>,
+}
+
+impl std::fmt::Debug for ImageExtractor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImageExtractor")
+            .field("max_file_size", &self.max_file_size)
+            .field("max_layer_size", &self.max_layer_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ImageExtractor {
+    pub fn new(debuginfo: Arc<DebuginfoStore>, timeout: Duration, max_file_size: u64) -> Self {
+        Self {
+            client: ureq::AgentBuilder::new()
+                .timeout_connect(timeout)
+                .timeout_read(timeout)
+                .build(),
+            debuginfo,
+            max_file_size,
+            max_layer_size: u64::MAX,
+            extracted: Mutex::default(),
+        }
+    }
+
+    /// Skips layers larger than `max_size` bytes.
+    pub fn with_max_layer_size(mut self, max_size: u64) -> Self {
+        self.max_layer_size = max_size;
+        self
+    }
+
+    /// Extracts the binaries of the image and stores the debuginfo of those
+    /// that lack it, returning how many were stored. Images extracted before
+    /// are skipped.
+    pub async fn extract(self: Arc<Self>, image: ImageReference) -> anyhow::Result<usize> {
+        if !self.extracted.lock().unwrap().insert(image.clone()) {
+            return Ok(0);
+        }
+        let handle = tokio::runtime::Handle::current();
+        let res = tokio::task::spawn_blocking({
+            let (extractor, image) = (Arc::clone(&self), image.clone());
+            move || extractor.extract_blocking(&image, &handle)
+        })
+        .await?;
+        if res.is_err() {
+            // Retried the next time the image is referenced.
+            self.extracted.lock().unwrap().remove(&image);
+        }
+        res
+    }
+
+    fn extract_blocking(
+        &self,
+        image: &ImageReference,
+        handle: &tokio::runtime::Handle,
+    ) -> anyhow::Result<usize> {
+        let mut token = None;
+        let manifest = self.manifest(image, &image.reference, &mut token)?;
+        let manifest = if manifest.manifests.is_empty() {
+            manifest
+        } else {
+            let descriptor = manifest
+                .manifests
+                .iter()
+                .find(|m| {
+                    m.platform.as_ref().is_some_and(|p| {
+                        p.os == "linux" && p.architecture == platform_architecture()
+                    })
+                })
+                .with_context(|| {
+                    format!("{} has no linux/{} image", image, platform_architecture())
+                })?;
+            self.manifest(image, &descriptor.digest, &mut token)?
+        };
+        ensure!(
+            !manifest.layers.is_empty(),
+            "{} manifest of {} has no layers",
+            manifest.media_type,
+            image
+        );
+
+        let mut stored = 0;
+        for layer in manifest.layers.iter() {
+            let gzip = layer.media_type.ends_with("gzip");
+            if !gzip && !layer.media_type.ends_with("tar") {
+                log::debug!(
+                    "Skipping layer {} of {}, {} is not supported",
+                    layer.digest,
+                    image,
+                    layer.media_type
+                );
+                continue;
+            }
+            if layer.size > self.max_layer_size {
+                log::debug!(
+                    "Skipping layer {} of {}, its {} bytes exceed the maximum of {}",
+                    layer.digest,
+                    image,
+                    layer.size,
+                    self.max_layer_size
+                );
+                continue;
+            }
+            let url = format!(
+                "https://{}/v2/{}/blobs/{}",
+                image.registry, image.repository, layer.digest
+            );
+            let blob = self.get(&url, "*/*", image, &mut token)?.into_reader();
+            let blob = verified_blob(blob, &layer.digest, layer.size)
+                .with_context(|| format!("downloading layer {} of {}", layer.digest, image))?;
+            let blob: Box<dyn Read> = if gzip {
+                Box::new(flate2::read::GzDecoder::new(blob))
+            } else {
+                Box::new(blob)
+            };
+            binaries(blob, self.max_file_size, |build_id, data| {
+                match handle.block_on(self.debuginfo.ingest(&build_id, data)) {
+                    Ok(true) => {
+                        stored += 1;
+                        IMAGE_BINARIES.with_label_values(&["stored"]).inc();
+                    }
+                    Ok(false) => IMAGE_BINARIES.with_label_values(&["skipped"]).inc(),
+                    Err(e) => {
+                        log::warn!(
+                            "Failed to store debuginfo of build_id {} of {}: {}",
+                            build_id,
+                            image,
+                            e
+                        );
+                        IMAGE_BINARIES.with_label_values(&["failed"]).inc();
+                    }
+                }
+            })
+            .with_context(|| format!("reading layer {} of {}", layer.digest, image))?;
+        }
+        Ok(stored)
+    }
+
+    fn manifest(
+        &self,
+        image: &ImageReference,
+        reference: &str,
+        token: &mut Option<String>,
+    ) -> anyhow::Result<Manifest> {
+        let url = format!(
+            "https://{}/v2/{}/manifests/{}",
+            image.registry, image.repository, reference
+        );
+        let response = self.get(&url, &MANIFEST_TYPES.join(", "), image, token)?;
+        serde_json::from_reader(response.into_reader())
+            .with_context(|| format!("decoding manifest {}", url))
+    }
+
+    /// Gets the URL, requesting an anonymous token once the registry asks
+    /// for one.
+    fn get(
+        &self,
+        url: &str,
+        accept: &str,
+        image: &ImageReference,
+        token: &mut Option<String>,
+    ) -> anyhow::Result<ureq::Response> {
+        let request = |token: &Option<String>| {
+            let request = self.client.get(url).set("Accept", accept);
+            match token {
+                Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+                None => request,
+            }
+        };
+        match request(token).call() {
+            Ok(response) => Ok(response),
+            Err(ureq::Error::Status(401, response)) if token.is_none() => {
+                let challenge = response.header("WWW-Authenticate").unwrap_or_default();
+                *token = Some(self.token(challenge, image)?);
+                Ok(request(token)
+                    .call()
+                    .with_context(|| format!("getting {}", url))?)
+            }
+            Err(e) => Err(e).with_context(|| format!("getting {}", url)),
+        }
+    }
+
+    /// Requests a pull token from the realm of a `Bearer` challenge.
+    fn token(&self, challenge: &str, image: &ImageReference) -> anyhow::Result<String> {
+        let Some(params) = challenge.strip_prefix("Bearer ") else {
+            bail!("unsupported authentication challenge {:?}", challenge);
+        };
+        let param = |name: &str| {
+            params.split(',').find_map(|p| {
+                let (key, value) = p.trim().split_once('=')?;
+                (key == name).then(|| value.trim_matches('"').to_string())
+            })
+        };
+        let realm = param("realm").context("authentication challenge without realm")?;
+        ensure!(
+            realm_allowed(&realm, &image.registry),
+            "{} may not issue tokens of registry {}",
+            realm,
+            image.registry
+        );
+        let mut request = self.client.get(&realm).query(
+            "scope",
+            &param("scope").unwrap_or_else(|| format!("repository:{}:pull", image.repository)),
+        );
+        if let Some(service) = param("service") {
+            request = request.query("service", &service);
+        }
+        let token: Token = serde_json::from_reader(
+            request
+                .call()
+                .with_context(|| format!("requesting token from {}", realm))?
+                .into_reader(),
+        )?;
+        match (token.token, token.access_token) {
+            (token, _) if !token.is_empty() => Ok(token),
+            (_, token) if !token.is_empty() => Ok(token),
+            _ => bail!("{} returned no token", realm),
+        }
+    }
+}
+
+/// Returns whether the realm of an authentication challenge of the registry
+/// may be asked for tokens: an https URL of the registry host or of a host of
+/// its parent domain, like `auth.docker.io` for `registry-1.docker.io`. Other
+/// realms could make the server request any URL.
+fn realm_allowed(realm: &str, registry: &str) -> bool {
+    let Ok(realm) = Url::parse(realm) else {
+        return false;
+    };
+    let Some(host) = realm.host_str() else {
+        return false;
+    };
+    let registry = registry.split(':').next().unwrap_or_default();
+    let parent = registry
+        .split_once('.')
+        .map(|(_, parent)| parent)
+        .filter(|parent| parent.contains('.'));
+    realm.scheme() == "https"
+        && (host == registry
+            || parent.is_some_and(|parent| {
+                host.strip_suffix(parent)
+                    .is_some_and(|sub| sub.ends_with('.'))
+            }))
+}
+
+/// Copies the blob, of `size` bytes, to a temporary file and verifies it
+/// against its digest, so that no binary of a blob that was tampered with is
+/// stored. Reading stops once the blob exceeds its size.
+fn verified_blob(blob: impl Read, expected: &str, size: u64) -> anyhow::Result<std::fs::File> {
+    let (name, _) = expected.split_once(':').unwrap_or_default();
+    let algorithm = match name {
+        "sha256" => &SHA256,
+        "sha512" => &SHA512,
+        _ => bail!("unsupported digest {}", expected),
+    };
+    let mut context = digest::Context::new(algorithm);
+    let mut file = tempfile::tempfile()?;
+    let mut buf = vec![0; 64 * 1024];
+    // One byte past the size is read to tell whether the blob is larger.
+    let mut blob = blob.take(size.saturating_add(1));
+    let mut read = 0;
+    loop {
+        let n = blob.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        read += n as u64;
+        ensure!(read <= size, "blob is larger than {} bytes", size);
+        context.update(&buf[..n]);
+        file.write_all(&buf[..n])?;
+    }
+    let actual = context
+        .finish()
+        .as_ref()
+        .iter()
+        .fold(format!("{}:", name), |mut s, b| {
+            let _ = write!(s, "{:02x}", b);
+            s
+        });
+    ensure!(
+        actual == expected,
+        "blob digest is {} instead of {}",
+        actual,
+        expected
+    );
+    file.rewind()?;
+    Ok(file)
+}
+
+/// Calls `f` with the build ID and contents of every ELF file of the layer
+/// that has a GNU build ID and symbols and is at most `max_size` bytes.
+fn binaries<R: Read>(
+    layer: R,
+    max_size: u64,
+    mut f: impl FnMut(String, Vec<u8>),
+) -> anyhow::Result<()> {
+    let mut archive = tar::Archive::new(layer);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular || entry.size() > max_size {
+            continue;
+        }
+        let mut magic = [0; 4];
+        if entry.read_exact(&mut magic).is_err() || &magic != b"\x7fELF" {
+            continue;
+        }
+        let mut data = magic.to_vec();
+        entry.read_to_end(&mut data)?;
+        let Ok(file) = object::File::parse(data.as_slice()) else {
+            continue;
+        };
+        let build_id = match file.build_id() {
+            Ok(Some(id)) if !id.is_empty() => id.iter().fold(String::new(), |mut s, b| {
+                let _ = write!(s, "{:02x}", b);
+                s
+            }),
+            _ => continue,
+        };
+        let has_symbols = file.has_debug_symbols() || file.symbols().any(|s| s.is_definition());
+        if has_symbols {
+            f(build_id, data);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_reference() {
+        let parse = |s: &str| s.parse::<ImageReference>().unwrap();
+        assert_eq!(
+            parse("nginx"),
+            ImageReference {
+                registry: DOCKER_HUB.into(),
+                repository: "library/nginx".into(),
+                reference: "latest".into(),
+            }
+        );
+        assert_eq!(parse("grafana/agent:v1").repository, "grafana/agent");
+        let image = parse("localhost:5000/app@sha256:abcd");
+        assert_eq!(image.registry, "localhost:5000");
+        assert_eq!(image.reference, "sha256:abcd");
+        assert_eq!(image.to_string(), "localhost:5000/app@sha256:abcd");
+        assert_eq!(
+            parse("ghcr.io/org/app:v1").to_string(),
+            "ghcr.io/org/app:v1"
+        );
+        assert!("app@".parse::<ImageReference>().is_err());
+    }
+
+    #[test]
+    fn test_verified_blob() {
+        let expected = crate::storage::checksum(b"layer");
+        let mut file = verified_blob(b"layer".as_slice(), &expected, 5).unwrap();
+        let mut data = vec![];
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"layer");
+
+        assert!(verified_blob(b"tampered".as_slice(), &expected, 8).is_err());
+        assert!(verified_blob(b"layer".as_slice(), "md5:abcd", 5).is_err());
+        assert!(verified_blob(b"layer".as_slice(), &expected, 4).is_err());
+    }
+
+    #[test]
+    fn test_realm_allowed() {
+        assert!(realm_allowed("https://ghcr.io/token", "ghcr.io"));
+        assert!(realm_allowed("https://auth.docker.io/token", DOCKER_HUB));
+        assert!(realm_allowed(
+            "https://registry:5000/token",
+            "registry:5000"
+        ));
+        assert!(!realm_allowed("http://ghcr.io/token", "ghcr.io"));
+        assert!(!realm_allowed("https://evil.io/token", "ghcr.io"));
+        assert!(!realm_allowed("https://evildocker.io/token", DOCKER_HUB));
+        assert!(!realm_allowed("https://169.254.169.254/", "ghcr.io"));
+        assert!(!realm_allowed("invalid", "ghcr.io"));
+    }
+
+    #[test]
+    fn test_binaries() {
+        let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let mut layer = tar::Builder::new(vec![]);
+        for (name, data) in [("bin/test", exe.as_slice()), ("etc/passwd", b"root")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o755);
+            layer.append_data(&mut header, name, data).unwrap();
+        }
+        let layer = layer.into_inner().unwrap();
+
+        let expected: Vec<String> = object::File::parse(exe.as_slice())
+            .unwrap()
+            .build_id()
+            .unwrap()
+            .map(|id| id.iter().map(|b| format!("{:02x}", b)).collect())
+            .into_iter()
+            .collect();
+        let mut found = vec![];
+        binaries(layer.as_slice(), u64::MAX, |id, data| {
+            assert_eq!(data.len(), exe.len());
+            found.push(id);
+        })
+        .unwrap();
+        assert_eq!(found, expected);
+
+        found.clear();
+        binaries(layer.as_slice(), 1024, |id, _| found.push(id)).unwrap();
+        assert!(found.is_empty());
+    }
+}
@@ -0,0 +1,137 @@
+use crate::normalizer::NormalizedWriteRawRequest;
+use moka::sync::Cache;
+use prometheus::{register_int_counter, IntCounter};
+use std::path::{Component, Path, PathBuf};
+use std::sync::LazyLock;
+
+static DENIED_LOOKUPS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_debuginfod_denied_lookups_total",
+        "Total number of build IDs not looked up in debuginfod because of the lookup policy."
+    )
+    .unwrap()
+});
+
+/// DebuginfodPolicy decides which build IDs may be looked up in debuginfod,
+/// so that build IDs of internal binaries aren't sent to public servers.
+/// Build IDs are matched by prefix against the denied prefixes, and the file
+/// the build ID was last mapped from in written profiles against the allowed
+/// directories, component by component, so that `/usr/lib` doesn't allow
+/// `/usr/libexec`. Build IDs whose file isn't known yet, or whose file is
+/// relative or escapes a directory with `..`, aren't looked up while paths
+/// are restricted.
+#[derive(Debug)]
+pub struct DebuginfodPolicy {
+    allowed_paths: Vec<PathBuf>,
+    denied_build_ids: Vec<String>,
+    /// File names of mappings by build ID.
+    origins: Cache<String, String>,
+}
+
+impl Default for DebuginfodPolicy {
+    fn default() -> Self {
+        Self::new(vec![], vec![])
+    }
+}
+
+impl DebuginfodPolicy {
+    /// Creates a policy allowing the build IDs of files under any of
+    /// `allowed_paths`, or of any file if it's empty, that don't start with
+    /// any of `denied_build_ids`.
+    pub fn new(allowed_paths: Vec<String>, denied_build_ids: Vec<String>) -> Self {
+        Self {
+            allowed_paths: allowed_paths.into_iter().map(PathBuf::from).collect(),
+            denied_build_ids: denied_build_ids
+                .into_iter()
+                .map(|p| p.to_lowercase())
+                .collect(),
+            origins: Cache::new(1_000_000),
+        }
+    }
+
+    /// Returns whether the policy needs the origins of build IDs.
+    pub fn restricts_paths(&self) -> bool {
+        !self.allowed_paths.is_empty()
+    }
+
+    /// Remembers the files the build IDs of the request were mapped from.
+    pub fn record_origins(&self, request: &NormalizedWriteRawRequest) {
+        if !self.restricts_paths() {
+            return;
+        }
+        for (build_id, file) in request.mapping_files.iter() {
+            let build_id = build_id.to_lowercase();
+            if self.origins.get(&build_id).as_ref() != Some(file) {
+                self.origins.insert(build_id, file.clone());
+            }
+        }
+    }
+
+    /// Returns whether the build ID may be looked up in debuginfod.
+    pub fn allows(&self, build_id: &str) -> bool {
+        let build_id = build_id.to_lowercase();
+        let allowed = !self
+            .denied_build_ids
+            .iter()
+            .any(|prefix| build_id.starts_with(prefix))
+            && (!self.restricts_paths()
+                || self
+                    .origins
+                    .get(&build_id)
+                    .is_some_and(|file| self.allows_path(Path::new(&file))));
+        if !allowed {
+            DENIED_LOOKUPS.inc();
+        }
+        allowed
+    }
+
+    fn allows_path(&self, file: &Path) -> bool {
+        file.is_absolute()
+            && !file.components().any(|c| c == Component::ParentDir)
+            && self.allowed_paths.iter().any(|dir| file.starts_with(dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalizer::write_raw::tests::RequestBuilder;
+
+    fn request(files: &[(&str, &str)]) -> NormalizedWriteRawRequest {
+        RequestBuilder::default().with_mapping_files(files).build()
+    }
+
+    #[test]
+    fn test_debuginfod_policy() {
+        let policy = DebuginfodPolicy::default();
+        assert!(policy.allows("abcd"));
+
+        let policy = DebuginfodPolicy::new(vec![], vec!["AB".into()]);
+        assert!(!policy.allows("abcd"));
+        assert!(policy.allows("cdef"));
+
+        let policy = DebuginfodPolicy::new(vec!["/usr/lib/".into()], vec!["ab".into()]);
+        policy.record_origins(&request(&[
+            ("abcd", "/usr/lib/libc.so.6"),
+            ("cdef", "/usr/lib/libm.so.6"),
+            ("ef01", "/opt/app/server"),
+        ]));
+        assert!(!policy.allows("abcd"));
+        assert!(policy.allows("cdef"));
+        assert!(!policy.allows("ef01"));
+        assert!(!policy.allows("2345"));
+
+        // Paths are matched by component, not by string prefix.
+        let policy = DebuginfodPolicy::new(vec!["/usr/lib".into()], vec![]);
+        policy.record_origins(&request(&[
+            ("abcd", "/usr/lib/libc.so.6"),
+            ("cdef", "/usr/libexec/secret"),
+            ("ef01", "/usr/lib/../../opt/app/server"),
+            ("2345", "usr/lib/libm.so.6"),
+        ]));
+        assert!(policy.allows("abcd"));
+        assert!(!policy.allows("cdef"));
+        assert!(!policy.allows("ef01"));
+        assert!(!policy.allows("2345"));
+    }
+}
@@ -1,3 +1,16 @@
+use crate::debuginfopb::ShouldInitiateUploadResponse;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::sync::LazyLock;
+
+static UPLOAD_DECISIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_should_initiate_upload_total",
+        "Total number of ShouldInitiateUpload decisions, by reason and whether an upload was requested.",
+        &["reason", "initiate"]
+    )
+    .unwrap()
+});
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum DebugInfoUploadReason {
     /// Debuginfo exists in debuginfod, therefore no upload is necessary.
@@ -15,15 +28,15 @@ pub enum DebugInfoUploadReason {
     /// Debuginfo already exists and is not marked as invalid, therefore no new upload is needed.
     DebugInfoAlreadyExists,
 
-    /// Debuginfo already exists and is not marked as invalid, therefore wouldn't have accepted a new upload, 
+    /// Debuginfo already exists and is not marked as invalid, therefore wouldn't have accepted a new upload,
     /// but accepting it because it's requested to be forced.
     DebugInfoAlreadyExistsButForced,
 
-    /// Debuginfo already exists but is marked as invalid, therefore a new upload is needed. 
+    /// Debuginfo already exists but is marked as invalid, therefore a new upload is needed.
     /// Hash the debuginfo and initiate the upload.
     DebugInfoInvalid,
 
-    /// Debuginfo already exists and is marked as invalid, but the proposed hash is the same as the 
+    /// Debuginfo already exists and is marked as invalid, but the proposed hash is the same as the
     /// one already available, therefore the upload is not accepted as it would result in the same invalid debuginfos.
     DebugInfoEqual,
 
@@ -35,35 +48,94 @@ pub enum DebugInfoUploadReason {
 
     /// Debuginfo is available from debuginfod already but is marked as invalid, therefore a new upload is needed.
     DebugInfodInvalid,
+
+    /// Agents repeatedly reported that they could not extract the debuginfo, therefore no upload is requested until the tombstone expires.
+    DebugInfoTombstoned,
+
+    /// Debuginfo was uploaded but the stored object is corrupted, therefore a new upload is needed.
+    DebugInfoCorrupted,
 }
 
 impl std::fmt::Display for DebugInfoUploadReason {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let r = match self {
-            Self::DebugInfoInDebugInfod => 
+            Self::DebugInfoInDebugInfod =>
             "Debuginfo exists in debuginfod, therefore no upload is necessary.",
-            Self::FirstTimeSeen => 
+            Self::FirstTimeSeen =>
             "First time we see this Build ID, and it does not exist in debuginfod, therefore please upload!",
-            Self::UploadStale => 
+            Self::UploadStale =>
             "A previous upload was started but not finished and is now stale, so it can be retried.",
-            Self::UploadInProgress => 
+            Self::UploadInProgress =>
             "A previous upload is still in-progress and not stale yet (only stale uploads can be retried).",
-            Self::DebugInfoAlreadyExists => 
+            Self::DebugInfoAlreadyExists =>
             "Debuginfo already exists and is not marked as invalid, therefore no new upload is needed.",
-            Self::DebugInfoAlreadyExistsButForced => 
+            Self::DebugInfoAlreadyExistsButForced =>
             "Debuginfo already exists and is not marked as invalid, therefore wouldn't have accepted a new upload, but accepting it because it's requested to be forced.",
-            Self::DebugInfoInvalid => 
+            Self::DebugInfoInvalid =>
             "Debuginfo already exists but is marked as invalid, therefore a new upload is needed. Hash the debuginfo and initiate the upload.",
-            Self::DebugInfoEqual => 
+            Self::DebugInfoEqual =>
             "Debuginfo already exists and is marked as invalid, but the proposed hash is the same as the one already available, therefore the upload is not accepted as it would result in the same invalid debuginfos.",
-            Self::DebugInfoNotEqual => 
+            Self::DebugInfoNotEqual =>
             "Debuginfo already exists but is marked as invalid, therefore a new upload will be accepted.",
-            Self::DebugInfodSource => 
+            Self::DebugInfodSource =>
             "Debuginfo is available from debuginfod already and not marked as invalid, therefore no new upload is needed.",
-            Self::DebugInfodInvalid => 
+            Self::DebugInfodInvalid =>
             "Debuginfo is available from debuginfod already but is marked as invalid, therefore a new upload is needed.",
+            Self::DebugInfoTombstoned =>
+            "Agents repeatedly reported that they could not extract the debuginfo, therefore no upload is requested until the tombstone expires.",
+            Self::DebugInfoCorrupted =>
+            "Debuginfo was uploaded but the stored object is corrupted, therefore a new upload is needed.",
         };
         write!(f, "{}", r)
     }
+}
+
+impl DebugInfoUploadReason {
+    /// Short name of the reason, used as metric label.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::DebugInfoInDebugInfod => "debuginfo_in_debuginfod",
+            Self::FirstTimeSeen => "first_time_seen",
+            Self::UploadStale => "upload_stale",
+            Self::UploadInProgress => "upload_in_progress",
+            Self::DebugInfoAlreadyExists => "debuginfo_already_exists",
+            Self::DebugInfoAlreadyExistsButForced => "debuginfo_already_exists_but_forced",
+            Self::DebugInfoInvalid => "debuginfo_invalid",
+            Self::DebugInfoEqual => "debuginfo_equal",
+            Self::DebugInfoNotEqual => "debuginfo_not_equal",
+            Self::DebugInfodSource => "debuginfod_source",
+            Self::DebugInfodInvalid => "debuginfod_invalid",
+            Self::DebugInfoTombstoned => "debuginfo_tombstoned",
+            Self::DebugInfoCorrupted => "debuginfo_corrupted",
+        }
+    }
+
+    /// Counts the decision and returns it as response.
+    pub fn respond(self, should_initiate_upload: bool) -> ShouldInitiateUploadResponse {
+        UPLOAD_DECISIONS
+            .with_label_values(&[self.label(), &should_initiate_upload.to_string()])
+            .inc();
+        ShouldInitiateUploadResponse {
+            should_initiate_upload,
+            reason: self.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn test_respond_counts_decision() {
+        let counter = UPLOAD_DECISIONS.with_label_values(&["upload_stale", "true"]);
+        let before = counter.get();
+        let response = DebugInfoUploadReason::UploadStale.respond(true);
+        assert!(response.should_initiate_upload);
+        assert_eq!(
+            response.reason,
+            DebugInfoUploadReason::UploadStale.to_string()
+        );
+        assert_eq!(counter.get(), before + 1);
+    }
 }
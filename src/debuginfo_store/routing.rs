@@ -0,0 +1,192 @@
+use crate::debuginfopb::{debuginfo_upload::State, Debuginfo};
+use anyhow::{bail, ensure};
+use object_store::ObjectStore;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// BucketRouteConfig routes the debuginfo objects of some build IDs to
+/// another bucket than the default one:
+///
+/// ```yaml
+/// name: private
+/// directory: /var/lib/evprofiler/private-debuginfo
+/// build_id_prefixes: [ab12, cd34]
+/// ```
+///
+/// The name is recorded in the metadata of finished uploads, so it must not
+/// change while the bucket holds objects.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketRouteConfig {
+    pub name: String,
+    /// Directory of the bucket.
+    pub directory: PathBuf,
+    /// Build IDs starting with any of these are routed to the bucket.
+    pub build_id_prefixes: Vec<String>,
+}
+
+impl BucketRouteConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        ensure!(!self.name.is_empty(), "name must not be empty");
+        ensure!(
+            !self.directory.as_os_str().is_empty(),
+            "directory must not be empty"
+        );
+        ensure!(
+            !self.build_id_prefixes.is_empty(),
+            "build_id_prefixes must not be empty"
+        );
+        for prefix in self.build_id_prefixes.iter() {
+            ensure!(
+                !prefix.is_empty() && prefix.chars().all(|c| c.is_ascii_hexdigit()),
+                "invalid build ID prefix {:?}",
+                prefix
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct BucketRoute {
+    name: String,
+    build_id_prefixes: Vec<String>,
+    bucket: Arc<dyn ObjectStore>,
+}
+
+/// BucketRoutes decides which bucket the debuginfo objects of a build ID are
+/// stored in. Uploads in progress go to the bucket of the first route
+/// matching their build ID, or to the default bucket. Finished uploads are
+/// read from the bucket recorded in their metadata, so changing the routes
+/// doesn't lose track of the objects uploaded before.
+#[derive(Debug)]
+pub struct BucketRoutes {
+    default: Arc<dyn ObjectStore>,
+    routes: Vec<BucketRoute>,
+}
+
+impl BucketRoutes {
+    pub fn new(default: Arc<dyn ObjectStore>) -> Self {
+        Self {
+            default,
+            routes: vec![],
+        }
+    }
+
+    /// Returns the default bucket, which also stores the metadata.
+    pub fn default_bucket(&self) -> &Arc<dyn ObjectStore> {
+        &self.default
+    }
+
+    /// Routes the build IDs starting with any of `build_id_prefixes` to the
+    /// bucket, unless an earlier route matches them.
+    pub fn with_route(
+        mut self,
+        name: &str,
+        build_id_prefixes: Vec<String>,
+        bucket: Arc<dyn ObjectStore>,
+    ) -> Self {
+        self.routes.push(BucketRoute {
+            name: name.to_string(),
+            build_id_prefixes: build_id_prefixes
+                .into_iter()
+                .map(|p| p.to_lowercase())
+                .collect(),
+            bucket,
+        });
+        self
+    }
+
+    /// Returns the name of the bucket new uploads of the build ID go to,
+    /// empty for the default bucket.
+    pub fn route(&self, build_id: &str) -> &str {
+        let build_id = build_id.to_lowercase();
+        self.routes
+            .iter()
+            .find(|r| r.build_id_prefixes.iter().any(|p| build_id.starts_with(p)))
+            .map_or("", |r| r.name.as_str())
+    }
+
+    /// Returns the bucket the uploaded object of the debuginfo is stored in.
+    /// Fails if the bucket recorded in its metadata isn't routed to anymore.
+    pub fn bucket(&self, debuginfo: &Debuginfo) -> anyhow::Result<&Arc<dyn ObjectStore>> {
+        let name = match &debuginfo.upload {
+            Some(upload) if upload.state() == State::Uploading => self.route(&debuginfo.build_id),
+            Some(upload) => upload.bucket.as_str(),
+            None => bail!("Debuginfo has no upload"),
+        };
+        if name.is_empty() {
+            return Ok(&self.default);
+        }
+        match self.routes.iter().find(|r| r.name == name) {
+            Some(route) => Ok(&route.bucket),
+            None => bail!(
+                "debuginfo of build ID {} is stored in bucket {:?}, which is not configured",
+                debuginfo.build_id,
+                name
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debuginfopb::DebuginfoUpload;
+    use object_store::memory::InMemory;
+
+    #[test]
+    fn test_bucket_routes() {
+        let (default, private): (Arc<dyn ObjectStore>, Arc<dyn ObjectStore>) =
+            (Arc::new(InMemory::new()), Arc::new(InMemory::new()));
+        let routes = BucketRoutes::new(Arc::clone(&default)).with_route(
+            "private",
+            vec!["AB".into()],
+            Arc::clone(&private),
+        );
+        assert_eq!(routes.route("abcd"), "private");
+        assert_eq!(routes.route("cdef"), "");
+
+        let debuginfo = |build_id: &str, state: State, bucket: &str| Debuginfo {
+            build_id: build_id.into(),
+            upload: Some(DebuginfoUpload {
+                state: state.into(),
+                bucket: bucket.into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let is = |bucket: &Arc<dyn ObjectStore>, expected: &Arc<dyn ObjectStore>| {
+            Arc::ptr_eq(bucket, expected)
+        };
+        // Uploads in progress follow the routes, finished ones their
+        // metadata.
+        let uploading = debuginfo("abcd", State::Uploading, "");
+        assert!(is(routes.bucket(&uploading).unwrap(), &private));
+        let uploaded = debuginfo("abcd", State::Uploaded, "");
+        assert!(is(routes.bucket(&uploaded).unwrap(), &default));
+        let uploaded = debuginfo("cdef", State::Uploaded, "private");
+        assert!(is(routes.bucket(&uploaded).unwrap(), &private));
+        assert!(routes
+            .bucket(&debuginfo("cdef", State::Uploaded, "public"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = BucketRouteConfig {
+            name: "private".into(),
+            directory: "/tmp/private".into(),
+            build_id_prefixes: vec!["ab12".into()],
+        };
+        config.validate().unwrap();
+        for invalid in [vec![], vec!["".into()], vec!["xyz".into()]] {
+            let config = BucketRouteConfig {
+                build_id_prefixes: invalid,
+                ..config.clone()
+            };
+            assert!(config.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use super::{BucketRoutes, MetadataStore, ObjectLayout};
+use crate::debuginfopb::{debuginfo_upload::State as UploadState, DebuginfoType};
+use crate::normalizer::DecompressionLimits;
+use crate::storage;
+use anyhow::ensure;
+use axum::{
+    extract::{Path as UrlPath, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use moka::sync::Cache;
+use object_store::ObjectStore;
+use std::io::Read;
+use std::path::{Component, Path};
+use std::sync::Arc;
+
+/// SourceArchives serves the files of the source archives of build IDs,
+/// uploaded as tarballs, optionally gzipped, with the `DEBUGINFO_TYPE_SOURCES`
+/// type through the regular upload protocol.
+#[derive(Debug)]
+pub struct SourceArchives {
+    metadata: MetadataStore,
+    buckets: Arc<BucketRoutes>,
+    layout: ObjectLayout,
+    limits: DecompressionLimits,
+    /// Decoded archives by build ID.
+    archives: Cache<String, Arc<Archive>>,
+}
+
+/// Maximum size in bytes of the files of the decoded archives kept.
+const MAX_CACHED_ARCHIVE_BYTES: u64 = 256 << 20;
+
+/// Archive holds the regular files of a decoded source archive.
+#[derive(Debug)]
+struct Archive {
+    /// Upload the archive was decoded from.
+    upload_id: String,
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl Archive {
+    fn size(&self) -> usize {
+        self.files
+            .iter()
+            .map(|(path, data)| path.len() + data.len())
+            .sum()
+    }
+}
+
+impl SourceArchives {
+    pub fn new(
+        metadata: MetadataStore,
+        bucket: Arc<dyn ObjectStore>,
+        layout: ObjectLayout,
+    ) -> Self {
+        Self {
+            metadata,
+            buckets: Arc::new(BucketRoutes::new(bucket)),
+            layout,
+            limits: DecompressionLimits::default(),
+            archives: Cache::builder()
+                .max_capacity(MAX_CACHED_ARCHIVE_BYTES)
+                .weigher(|_, archive: &Arc<Archive>| archive.size().try_into().unwrap_or(u32::MAX))
+                .build(),
+        }
+    }
+
+    /// Fails to read gzipped archives decompressing beyond `limits`.
+    pub fn with_decompression_limits(mut self, limits: DecompressionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Reads archives from the buckets they were routed to, rather than from
+    /// the bucket the archives were created with.
+    pub fn with_bucket_routes(mut self, buckets: Arc<BucketRoutes>) -> Self {
+        self.buckets = buckets;
+        self
+    }
+
+    /// Returns the content of the file of the source archive of the build ID,
+    /// or None if the build ID has no finished source upload or the archive
+    /// has no such file. Files compiled in `/home/ci/build/src/main.rs` are
+    /// found at `src/main.rs` in the archive too, the archive entry matching
+    /// the most trailing path components winning.
+    pub async fn read(&self, build_id: &str, filename: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let components = components(filename)?;
+        let Some(debuginfo) = self.metadata.fetch(build_id, &DebuginfoType::Sources) else {
+            return Ok(None);
+        };
+        let Some(upload) = debuginfo
+            .upload
+            .as_ref()
+            .filter(|u| u.state() == UploadState::Uploaded)
+        else {
+            return Ok(None);
+        };
+        let archive = match self.archives.get(build_id) {
+            Some(archive) if archive.upload_id == upload.id => archive,
+            _ => {
+                let path = self.layout.object_path(&debuginfo)?;
+                let data = self
+                    .buckets
+                    .bucket(&debuginfo)?
+                    .get(&path)
+                    .await?
+                    .bytes()
+                    .await?;
+                storage::verify(&path, &upload.checksum, &data)?;
+                let archive = Arc::new(Archive {
+                    upload_id: upload.id.clone(),
+                    files: read_files(self.limits.reader(&data))?,
+                });
+                self.archives
+                    .insert(build_id.to_string(), Arc::clone(&archive));
+                archive
+            }
+        };
+        Ok(find_file(&archive.files, &components))
+    }
+}
+
+/// Returns the normal components of the path, failing on `..`.
+fn components(filename: &str) -> anyhow::Result<Vec<&str>> {
+    let path = Path::new(filename);
+    ensure!(
+        !path.components().any(|c| c == Component::ParentDir),
+        "invalid filename {:?}",
+        filename
+    );
+    Ok(path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => c.to_str(),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Returns the paths and contents of the regular files of the tar archive.
+fn read_files(archive: impl Read) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+    let mut files = vec![];
+    for entry in tar::Archive::new(archive).entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+        files.push((path, data));
+    }
+    Ok(files)
+}
+
+/// Returns the content of the file whose path is the longest suffix of the
+/// components.
+fn find_file(files: &[(String, Vec<u8>)], components: &[&str]) -> Option<Vec<u8>> {
+    let mut found: Option<(usize, &[u8])> = None;
+    for (path, data) in files {
+        let Ok(entry_components) = self::components(path) else {
+            continue;
+        };
+        let len = entry_components.len();
+        if len == 0
+            || !components.ends_with(&entry_components)
+            || found.as_ref().is_some_and(|(l, _)| *l >= len)
+        {
+            continue;
+        }
+        found = Some((len, data));
+    }
+    found.map(|(_, data)| data.to_vec())
+}
+
+/// Builds the router serving the files of source archives at the
+/// debuginfod `/buildid/<build ID>/source/<path>` endpoint.
+pub fn sources_router(archives: Arc<SourceArchives>) -> Router {
+    Router::new()
+        .route("/buildid/:id/source/*path", get(source_file))
+        .with_state(archives)
+}
+
+async fn source_file(
+    State(archives): State<Arc<SourceArchives>>,
+    UrlPath((build_id, path)): UrlPath<(String, String)>,
+) -> Result<Response, (StatusCode, String)> {
+    if components(&path).is_err() {
+        return Err((StatusCode::BAD_REQUEST, format!("invalid path {:?}", path)));
+    }
+    match archives.read(&build_id, &path).await {
+        Ok(Some(data)) => {
+            Ok(([(header::CONTENT_TYPE, "application/octet-stream")], data).into_response())
+        }
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            format!("no source file {} for build ID {}", path, build_id),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backfill;
+    use crate::debuginfo_store::NewUpload;
+    use chrono::Utc;
+    use object_store::{memory::InMemory, path::Path as ObjectPath};
+
+    fn archive(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_read() {
+        let metadata = MetadataStore::new();
+        let bucket: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let archives = SourceArchives::new(
+            metadata.clone(),
+            Arc::clone(&bucket),
+            ObjectLayout::default(),
+        );
+        let t = DebuginfoType::Sources;
+        assert_eq!(archives.read("abcd", "src/main.rs").await.unwrap(), None);
+
+        let data = backfill::compress(&archive(&[
+            ("main.rs", "fn other() {}\n"),
+            ("./src/main.rs", "fn main() {}\n"),
+        ]))
+        .unwrap();
+        metadata
+            .mark_as_uploading(
+                "abcd",
+                NewUpload {
+                    id: "upload-1",
+                    hash: "hash",
+                    size: data.len() as i64,
+                    started_at: Utc::now(),
+                },
+                &t,
+                0,
+            )
+            .unwrap();
+        bucket
+            .put(&ObjectPath::from("upload-1"), data.into())
+            .await
+            .unwrap();
+        // Unfinished uploads aren't served.
+        assert_eq!(archives.read("abcd", "src/main.rs").await.unwrap(), None);
+
+        metadata
+            .mark_as_uploaded("abcd", "upload-1", &t, "", "", Utc::now())
+            .unwrap();
+        let file = archives.read("abcd", "/home/ci/build/src/main.rs").await;
+        assert_eq!(file.unwrap().as_deref(), Some(&b"fn main() {}\n"[..]));
+        assert_eq!(archives.read("abcd", "src/lib.rs").await.unwrap(), None);
+        assert!(archives.read("abcd", "../src/main.rs").await.is_err());
+
+        // Decoded archives are cached.
+        bucket.delete(&ObjectPath::from("upload-1")).await.unwrap();
+        let file = archives.read("abcd", "src/main.rs").await;
+        assert_eq!(file.unwrap().as_deref(), Some(&b"fn main() {}\n"[..]));
+
+        let archives = Arc::new(archives);
+        let path = |p: &str| UrlPath(("abcd".to_string(), p.to_string()));
+        let response = source_file(State(Arc::clone(&archives)), path("home/ci/build/main.rs"))
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"fn other() {}\n");
+        let err = source_file(State(Arc::clone(&archives)), path("lib.rs"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+        let err = source_file(State(archives), path("../main.rs"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_read_decompression_limits() {
+        let metadata = MetadataStore::new();
+        let bucket: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let archives = SourceArchives::new(
+            metadata.clone(),
+            Arc::clone(&bucket),
+            ObjectLayout::default(),
+        )
+        .with_decompression_limits(DecompressionLimits {
+            max_size: 1 << 20,
+            max_ratio: 0,
+        });
+        let t = DebuginfoType::Sources;
+        let content = "0".repeat(2 << 20);
+        let data = backfill::compress(&archive(&[("main.rs", &content)])).unwrap();
+        metadata
+            .mark_as_uploading(
+                "abcd",
+                NewUpload {
+                    id: "upload-1",
+                    hash: "hash",
+                    size: data.len() as i64,
+                    started_at: Utc::now(),
+                },
+                &t,
+                0,
+            )
+            .unwrap();
+        bucket
+            .put(&ObjectPath::from("upload-1"), data.into())
+            .await
+            .unwrap();
+        metadata
+            .mark_as_uploaded("abcd", "upload-1", &t, "", "", Utc::now())
+            .unwrap();
+        assert!(archives.read("abcd", "main.rs").await.is_err());
+    }
+}
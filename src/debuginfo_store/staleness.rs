@@ -0,0 +1,126 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use moka::sync::Cache;
+
+use crate::debuginfopb::DebuginfoUpload;
+
+/// What an upload's staleness is measured from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StalenessPolicy {
+    /// Uploads are stale once the maximum upload duration and the grace
+    /// period have passed since they were initiated, even if they are still
+    /// making progress.
+    StartedAt,
+    /// Uploads are stale once no chunk was received for the grace period.
+    /// Uploads that never received a chunk are measured from when they were
+    /// initiated.
+    LastActivity,
+}
+
+/// UploadStaleness decides when an upload that is still in progress may be
+/// taken over by another one. It keeps the time the last chunk was received
+/// for every upload in flight. Activity is only known in memory, so after a
+/// restart uploads are measured from when they were initiated.
+#[derive(Debug)]
+pub struct UploadStaleness {
+    policy: StalenessPolicy,
+    grace: Duration,
+    last_activity: Cache<String, DateTime<Utc>>,
+}
+
+impl UploadStaleness {
+    pub fn new(policy: StalenessPolicy, grace: std::time::Duration) -> Self {
+        Self {
+            policy,
+            grace: Duration::from_std(grace).unwrap_or(Duration::MAX),
+            last_activity: Cache::builder()
+                .max_capacity(10_000)
+                .time_to_idle(grace.max(std::time::Duration::from_secs(60)) * 2)
+                .build(),
+        }
+    }
+
+    /// Records that a chunk of the upload was received.
+    pub fn touch(&self, upload_id: &str, now: DateTime<Utc>) {
+        self.last_activity.insert(upload_id.to_string(), now);
+    }
+
+    /// Forgets the upload once it finished.
+    pub fn finish(&self, upload_id: &str) {
+        self.last_activity.invalidate(upload_id);
+    }
+
+    pub fn is_stale(
+        &self,
+        upload: &DebuginfoUpload,
+        max_upload_duration: Duration,
+        now: DateTime<Utc>,
+    ) -> bool {
+        let Some(ts) = upload.started_at else {
+            return false;
+        };
+        let started_at = Utc
+            .timestamp_opt(ts.seconds, ts.nanos as u32)
+            .earliest()
+            .unwrap_or(now);
+
+        let deadline = match self.policy {
+            StalenessPolicy::StartedAt => started_at
+                .checked_add_signed(max_upload_duration)
+                .and_then(|t| t.checked_add_signed(self.grace)),
+            StalenessPolicy::LastActivity => self
+                .last_activity
+                .get(&upload.id)
+                .unwrap_or(started_at)
+                .checked_add_signed(self.grace),
+        };
+        deadline.is_some_and(|deadline| deadline < now)
+    }
+}
+
+impl Default for UploadStaleness {
+    fn default() -> Self {
+        Self::new(
+            StalenessPolicy::StartedAt,
+            std::time::Duration::from_secs(120),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost_types::Timestamp;
+
+    #[test]
+    fn test_is_stale() {
+        let started = Utc::now() - Duration::minutes(30);
+        let upload = DebuginfoUpload {
+            id: "upload-1".into(),
+            started_at: Some(Timestamp {
+                seconds: started.timestamp(),
+                nanos: 0,
+            }),
+            ..Default::default()
+        };
+        let max = Duration::minutes(15);
+        let now = Utc::now();
+
+        let by_start = UploadStaleness::default();
+        by_start.touch("upload-1", now);
+        assert!(by_start.is_stale(&upload, max, now));
+        assert!(!by_start.is_stale(&upload, Duration::minutes(60), now));
+
+        let by_activity = UploadStaleness::new(
+            StalenessPolicy::LastActivity,
+            std::time::Duration::from_secs(300),
+        );
+        assert!(by_activity.is_stale(&upload, max, now));
+        by_activity.touch("upload-1", now - Duration::minutes(1));
+        assert!(!by_activity.is_stale(&upload, max, now));
+        assert!(by_activity.is_stale(&upload, max, now + Duration::minutes(10)));
+
+        by_activity.finish("upload-1");
+        assert!(by_activity.is_stale(&upload, max, now));
+        assert!(!by_activity.is_stale(&DebuginfoUpload::default(), max, now));
+    }
+}
@@ -0,0 +1,207 @@
+use anyhow::bail;
+use object::elf::{FileHeader32, FileHeader64, SHT_DYNSYM, SHT_NOBITS, SHT_NOTE, SHT_NULL};
+use object::elf::{SHT_STRTAB, SHT_SYMTAB, SHT_SYMTAB_SHNDX};
+use object::read::elf::{FileHeader, SectionHeader};
+use object::{Endian, Endianness, FileKind};
+use prometheus::{register_int_counter, IntCounter};
+use std::sync::LazyLock;
+
+static STRIPPED_BYTES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_debuginfo_stripped_bytes_total",
+        "Total number of bytes removed from uploaded debuginfo by stripping sections that are not needed for symbolization."
+    )
+    .unwrap()
+});
+
+/// Sections whose contents are needed for symbolization, besides the symbol,
+/// string and note tables and the DWARF sections.
+const KEEP_SECTIONS: &[&str] = &[
+    ".eh_frame",
+    ".eh_frame_hdr",
+    ".gnu_debugdata",
+    ".gnu_debuglink",
+    ".go.buildinfo",
+    ".gopclntab",
+    ".gosymtab",
+];
+
+/// SectionStripper removes the contents of the sections of uploaded
+/// executables that symbolization doesn't read, like code and data, before
+/// they are stored. Section headers are kept, so addresses still resolve to
+/// sections, the same way `objcopy --only-keep-debug` does.
+#[derive(Debug, Default)]
+pub struct SectionStripper {
+    /// Additional sections to keep the contents of.
+    keep: Vec<String>,
+}
+
+impl SectionStripper {
+    pub fn new(keep: Vec<String>) -> Self {
+        Self { keep }
+    }
+
+    fn keeps(&self, name: &[u8], sh_type: u32) -> bool {
+        if matches!(sh_type, SHT_SYMTAB | SHT_DYNSYM | SHT_STRTAB | SHT_NOTE)
+            || sh_type == SHT_SYMTAB_SHNDX
+        {
+            return true;
+        }
+        let Ok(name) = std::str::from_utf8(name) else {
+            return false;
+        };
+        name.starts_with(".debug_")
+            || name.starts_with(".zdebug_")
+            || KEEP_SECTIONS.contains(&name)
+            || self.keep.iter().any(|k| k == name)
+    }
+
+    /// Returns the ELF file without the contents of the sections that are
+    /// not kept, or None if that doesn't make it any smaller.
+    pub fn strip(&self, data: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let stripped = match FileKind::parse(data)? {
+            FileKind::Elf32 => self.strip_elf::<FileHeader32<Endianness>>(data, false)?,
+            FileKind::Elf64 => self.strip_elf::<FileHeader64<Endianness>>(data, true)?,
+            kind => bail!("cannot strip sections of {:?} files", kind),
+        };
+        if stripped.len() >= data.len() {
+            return Ok(None);
+        }
+        STRIPPED_BYTES.inc_by((data.len() - stripped.len()) as u64);
+        Ok(Some(stripped))
+    }
+
+    /// Lays the file out again as the ELF header, the program headers, the
+    /// contents of the kept sections and the section headers. Dropped
+    /// sections become SHT_NOBITS.
+    fn strip_elf<Elf: FileHeader<Endian = Endianness>>(
+        &self,
+        data: &[u8],
+        is_64: bool,
+    ) -> anyhow::Result<Vec<u8>> {
+        let header = Elf::parse(data)?;
+        let endian = header.endian()?;
+        let sections = header.sections(endian, data)?;
+        let phdrs = header.program_headers(endian, data)?;
+        let shdrs = header.section_headers(endian, data)?;
+        let ehsize = header.e_ehsize(endian) as usize;
+        let shentsize = header.e_shentsize(endian) as usize;
+        let shoff: u64 = header.e_shoff(endian).into();
+        let phdrs_size = std::mem::size_of_val(phdrs);
+        let shdrs_size = shdrs.len() * shentsize;
+
+        // Offsets of e_phoff and e_shoff in the ELF header, and of sh_type
+        // and sh_offset in a section header.
+        let (e_phoff, e_shoff, sh_offset) = if is_64 {
+            (0x20, 0x28, 0x18)
+        } else {
+            (0x1c, 0x20, 0x10)
+        };
+        let put = |out: &mut [u8], at: usize, value: u64| {
+            if is_64 {
+                out[at..at + 8].copy_from_slice(&endian.write_u64_bytes(value));
+            } else {
+                out[at..at + 4].copy_from_slice(&endian.write_u32_bytes(value as u32));
+            }
+        };
+
+        let mut out = data[..ehsize].to_vec();
+        if !phdrs.is_empty() {
+            let phoff: u64 = header.e_phoff(endian).into();
+            align(&mut out, 8);
+            let at = out.len() as u64;
+            put(&mut out, e_phoff, at);
+            out.extend_from_slice(&data[phoff as usize..phoff as usize + phdrs_size]);
+        }
+
+        let mut offsets = Vec::with_capacity(shdrs.len());
+        let mut dropped = Vec::with_capacity(shdrs.len());
+        for section in shdrs.iter() {
+            let sh_type = section.sh_type(endian);
+            let name = sections.section_name(endian, section)?;
+            if matches!(sh_type, SHT_NULL | SHT_NOBITS) || !self.keeps(name, sh_type) {
+                offsets.push(out.len() as u64);
+                dropped.push(!matches!(sh_type, SHT_NULL | SHT_NOBITS));
+                continue;
+            }
+            let alignment: u64 = section.sh_addralign(endian).into();
+            align(&mut out, alignment.max(1) as usize);
+            offsets.push(out.len() as u64);
+            dropped.push(false);
+            out.extend_from_slice(section.data(endian, data)?);
+        }
+
+        align(&mut out, 8);
+        let new_shoff = out.len();
+        put(&mut out, e_shoff, new_shoff as u64);
+        out.extend_from_slice(&data[shoff as usize..shoff as usize + shdrs_size]);
+        for (i, (offset, dropped)) in offsets.into_iter().zip(dropped).enumerate() {
+            let at = new_shoff + i * shentsize;
+            if i > 0 {
+                put(&mut out, at + sh_offset, offset);
+            }
+            if dropped {
+                out[at + 4..at + 8].copy_from_slice(&endian.write_u32_bytes(SHT_NOBITS));
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn align(out: &mut Vec<u8>, alignment: usize) {
+    let padding = (alignment - out.len() % alignment) % alignment;
+    out.resize(out.len() + padding, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object::{Object, ObjectSection, ObjectSymbol, SectionKind};
+
+    #[test]
+    fn test_strip() {
+        let data =
+            std::fs::read("src/symbols/addr_to_line/testdata/basic-cpp-no-fp-with-debuginfo")
+                .unwrap();
+        let stripped = SectionStripper::default().strip(&data).unwrap().unwrap();
+        assert!(stripped.len() < data.len());
+
+        let original = object::File::parse(&*data).unwrap();
+        let file = object::File::parse(&*stripped).unwrap();
+        assert_eq!(file.build_id().unwrap(), original.build_id().unwrap());
+        assert_eq!(file.symbols().count(), original.symbols().count());
+        let main = |f: &object::File| {
+            f.symbols()
+                .find(|s| s.name() == Ok("main"))
+                .unwrap()
+                .address()
+        };
+        assert_eq!(main(&file), main(&original));
+
+        let text = file.section_by_name(".text").unwrap();
+        assert_eq!(text.kind(), SectionKind::UninitializedData);
+        assert_eq!(
+            text.address(),
+            original.section_by_name(".text").unwrap().address()
+        );
+        assert_eq!(
+            file.section_by_name(".debug_info").unwrap().data().unwrap(),
+            original
+                .section_by_name(".debug_info")
+                .unwrap()
+                .data()
+                .unwrap()
+        );
+        assert_eq!(file.segments().count(), original.segments().count());
+
+        let keep_text = SectionStripper::new(vec![".text".into()]);
+        let stripped = keep_text.strip(&data).unwrap().unwrap();
+        let file = object::File::parse(&*stripped).unwrap();
+        assert_eq!(
+            file.section_by_name(".text").unwrap().data().unwrap(),
+            original.section_by_name(".text").unwrap().data().unwrap()
+        );
+
+        assert!(SectionStripper::default().strip(b"not an elf").is_err());
+    }
+}
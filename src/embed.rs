@@ -0,0 +1,187 @@
+use crate::backfill;
+use crate::ingester::Ingester;
+use crate::normalizer::{
+    DecompressionLimits, LabelScrubbing, Metastore, ScrubRule, StackDepthLimit, StackTrimming,
+    TimestampAction, TimestampPolicy, TrimRule, WritePipeline,
+};
+use crate::pipeline::StageTimes;
+use crate::profilestorepb::{Label, LabelSet, RawProfileSeries, RawSample, WriteRawRequest};
+use object_store::ObjectStore;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Number of profiles buffered before they're written as a segment.
+const PROFILES_PER_SEGMENT: usize = 10;
+
+/// ParcaScraper runs the profile ingestion pipeline in process, for tests and
+/// custom collectors that don't want to go through gRPC. Profiles go through
+/// the same pipeline as those written with WriteRaw and are stored as
+/// segments in the bucket, along with the functions of their stacks.
+#[derive(Debug)]
+pub struct ParcaScraper {
+    pipeline: WritePipeline,
+    ingester: Ingester,
+}
+
+impl ParcaScraper {
+    /// Stores ingested profiles in `bucket`, adding their functions to the
+    /// function table already stored in it.
+    pub async fn new(bucket: Arc<dyn ObjectStore>) -> anyhow::Result<Self> {
+        let metastore = Arc::new(Metastore::load(bucket.as_ref(), false).await?);
+        Ok(Self {
+            ingester: Ingester::new(PROFILES_PER_SEGMENT, bucket)
+                .with_metastore(Arc::clone(&metastore)),
+            pipeline: WritePipeline::new(metastore),
+        })
+    }
+
+    /// Bounds the size profiles may decompress to. Unbounded by default.
+    pub fn with_decompression_limits(mut self, max_size: u64, max_ratio: u64) -> Self {
+        self.pipeline = self
+            .pipeline
+            .with_decompression_limits(DecompressionLimits {
+                max_size,
+                max_ratio,
+            });
+        self
+    }
+
+    /// Applies `action` to profiles whose timestamp is more than `max_past`
+    /// in the past or `max_future` in the future. Zero disables a bound.
+    pub fn with_timestamp_bounds(
+        mut self,
+        max_past: Duration,
+        max_future: Duration,
+        action: TimestampAction,
+    ) -> Self {
+        self.pipeline = self.pipeline.with_timestamp_policy(TimestampPolicy {
+            max_past,
+            max_future,
+            action,
+        });
+        self
+    }
+
+    /// Truncates stacks deeper than `max_depth` frames. Zero, the default,
+    /// keeps stacks whole.
+    pub fn with_max_stack_depth(mut self, max_depth: usize) -> Self {
+        self.pipeline = self
+            .pipeline
+            .with_stack_depth_limit(StackDepthLimit { max_depth });
+        self
+    }
+
+    /// Scrubs the labels of ingested profiles with `rules`. Values of `hash`
+    /// rules are hashed with `hash_key`, a secret, and dropped without one.
+    pub fn with_scrub_rules(mut self, rules: Vec<ScrubRule>, hash_key: Option<&[u8]>) -> Self {
+        let scrubbing = LabelScrubbing::new(rules);
+        let scrubbing = match hash_key {
+            Some(key) => scrubbing.with_hash_key(key),
+            None => scrubbing,
+        };
+        self.pipeline = self.pipeline.with_label_scrubbing(scrubbing);
+        self
+    }
+
+    /// Drops or collapses the leaf frames of ingested profiles with `rules`.
+    pub fn with_trim_rules(mut self, rules: Vec<TrimRule>) -> Self {
+        self.pipeline = self.pipeline.with_stack_trimming(StackTrimming::new(rules));
+        self
+    }
+
+    /// Ingests a pprof profile, gzipped or not, as a sample of the series
+    /// with `labels`, where `__name__` names the profile. Returns the number
+    /// of samples that were stored.
+    pub async fn ingest_pprof(&self, data: &[u8], labels: &[(&str, &str)]) -> anyhow::Result<u64> {
+        let raw_profile = match backfill::is_gzip(data) {
+            true => data.to_vec(),
+            // The normalizer only accepts gzipped profiles.
+            false => backfill::compress(data)?,
+        };
+        let request = WriteRawRequest {
+            series: vec![RawProfileSeries {
+                labels: Some(LabelSet {
+                    labels: labels
+                        .iter()
+                        .map(|(name, value)| Label {
+                            name: name.to_string(),
+                            value: value.to_string(),
+                        })
+                        .collect(),
+                }),
+                samples: vec![RawSample {
+                    raw_profile,
+                    executable_info: vec![],
+                }],
+            }],
+            ..Default::default()
+        };
+
+        let (normalized, chunk) = self
+            .pipeline
+            .run(&request, &mut StageTimes::default())
+            .await?;
+        let accepted = normalized.sample_counts().iter().map(|s| s.accepted).sum();
+        if !chunk.is_empty() {
+            self.ingester.ingest(chunk).await?;
+        }
+        Ok(accepted)
+    }
+
+    /// Writes the profiles ingested so far, and their functions, to the
+    /// bucket.
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        self.ingester.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pprofpb::{Profile, Sample, ValueType};
+    use object_store::memory::InMemory;
+    use prost::Message;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_ingest_pprof() {
+        let bucket = Arc::new(InMemory::new());
+        let scraper = ParcaScraper::new(bucket.clone()).await.unwrap();
+        let profile = Profile {
+            sample_type: vec![ValueType { r#type: 1, unit: 2 }],
+            sample: vec![
+                Sample {
+                    value: vec![3],
+                    ..Default::default()
+                },
+                Sample {
+                    value: vec![0],
+                    ..Default::default()
+                },
+            ],
+            string_table: vec!["".into(), "samples".into(), "count".into()],
+            ..Default::default()
+        };
+
+        let accepted = scraper
+            .ingest_pprof(
+                &profile.encode_to_vec(),
+                &[("__name__", "cpu"), ("job", "test")],
+            )
+            .await
+            .unwrap();
+        assert_eq!(accepted, 1);
+        assert!(scraper
+            .ingest_pprof(b"not a profile", &[("__name__", "cpu")])
+            .await
+            .is_err());
+
+        scraper.flush().await.unwrap();
+        let segments: Vec<_> = bucket
+            .list(None)
+            .filter(|o| o.as_ref().unwrap().location.extension() == Some("parquet"))
+            .collect()
+            .await;
+        assert_eq!(segments.len(), 1);
+    }
+}
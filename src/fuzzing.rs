@@ -0,0 +1,98 @@
+//! Entry points of the fuzz targets in `fuzz/`, over the parsers of input
+//! received from the network. They return nothing and only panic on bugs.
+//! Run with `cargo +nightly fuzz run pprof`, or `elf`, which starts from the
+//! seeds of `fuzz/corpus`.
+
+use crate::normalizer::{DecompressionLimits, Metastore, NormalizedWriteRawRequest};
+use crate::pprofpb::Profile;
+use crate::profile::executableinfo::ExecutableInfo;
+use crate::profilestorepb::{self, Label, LabelSet, RawProfileSeries, RawSample, WriteRawRequest};
+use crate::symbols::normalize::NormalizedAddress;
+use crate::symbols::{addr_to_line, elfutils, Demangler, ElfDebugInfo};
+use object::{Object, ObjectSection};
+use prost::Message;
+use std::path::PathBuf;
+
+/// Addresses looked up in a fuzzed object file.
+const MAX_ADDRESSES: usize = 16;
+
+/// Normalizes the pprof profile, as pushed to WriteRaw by agents, which send
+/// the executable info of every mapping along.
+pub fn pprof(data: &[u8]) {
+    let Ok(raw_profile) = crate::backfill::compress(data) else {
+        return;
+    };
+    let mappings = Profile::decode(data).map_or(0, |p| p.mapping.len());
+    let request = WriteRawRequest {
+        series: vec![RawProfileSeries {
+            labels: Some(LabelSet {
+                labels: vec![Label {
+                    name: "__name__".into(),
+                    value: "process_cpu".into(),
+                }],
+            }),
+            samples: vec![RawSample {
+                raw_profile,
+                executable_info: vec![profilestorepb::ExecutableInfo::default(); mappings],
+            }],
+        }],
+        ..Default::default()
+    };
+    let _ = NormalizedWriteRawRequest::try_new(
+        &request,
+        &Metastore::default(),
+        &DecompressionLimits::default(),
+    );
+}
+
+/// Validates the object file, as uploaded debuginfo, and symbolizes its
+/// entry point and the start of its sections.
+pub fn elf(data: &[u8]) {
+    let Ok(e) = object::File::parse(data) else {
+        return;
+    };
+    let _ = (
+        elfutils::has_dwarf(&e),
+        elfutils::has_go_pcln_tab(&e),
+        elfutils::has_symtab(&e),
+        elfutils::has_dynsym(&e),
+    );
+    let _ = ExecutableInfo::try_from(&e);
+    let addresses: Vec<u64> = std::iter::once(e.entry())
+        .chain(e.sections().map(|s| s.address()))
+        .take(MAX_ADDRESSES)
+        .collect();
+
+    let debuginfo = ElfDebugInfo {
+        target_path: PathBuf::new(),
+        e,
+        quality: None,
+    };
+    let demangler = Demangler::new(false);
+    if let Ok(liner) = addr_to_line::dwarf(&debuginfo, &demangler) {
+        for address in addresses.iter() {
+            let _ = liner.pc_to_lines(NormalizedAddress(*address));
+        }
+    }
+    if let Ok(liner) = addr_to_line::symbol(&debuginfo, "", &demangler) {
+        for address in addresses.iter() {
+            let _ = liner.pc_to_lines(NormalizedAddress(*address));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_corpus() {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus");
+        for (target, f) in [("pprof", pprof as fn(&[u8])), ("elf", elf)] {
+            for entry in std::fs::read_dir(corpus.join(target)).unwrap() {
+                f(&std::fs::read(entry.unwrap().path()).unwrap());
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{LazyLock, Once};
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, Service};
+use tonic::Status;
+
+static GRPC_PANICS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_grpc_panics_total",
+        "Total number of gRPC requests whose handler panicked, by method.",
+        &["method"]
+    )
+    .unwrap()
+});
+
+/// Metadata key of the correlation ID of the requests that panicked.
+pub const CORRELATION_ID_METADATA_KEY: &str = "x-correlation-id";
+
+thread_local! {
+    /// Backtrace of the last panic of the thread, taken by the handler
+    /// catching it.
+    static BACKTRACE: Cell<Option<Backtrace>> = const { Cell::new(None) };
+}
+
+/// Records the backtraces of panics for [`CatchPanic`], on top of the
+/// current panic hook.
+fn install_hook() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            BACKTRACE.with(|b| b.set(Some(Backtrace::force_capture())));
+            previous(info);
+        }));
+    });
+}
+
+/// CatchPanicLayer wraps the gRPC services so that a panicking handler, like
+/// on an edge case of a malformed profile, fails its request with INTERNAL
+/// instead of tearing down the connection and the other requests on it.
+/// The panic is logged with its backtrace and a correlation ID, returned in
+/// the status message and the `x-correlation-id` metadata so that users can
+/// report it. Panics while streaming responses aren't caught.
+#[derive(Debug, Clone)]
+pub struct CatchPanicLayer(());
+
+impl CatchPanicLayer {
+    pub fn new() -> Self {
+        install_hook();
+        Self(())
+    }
+}
+
+impl Default for CatchPanicLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> tower_layer::Layer<S> for CatchPanicLayer {
+    type Service = CatchPanic<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CatchPanic { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CatchPanic<S> {
+    inner: S,
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+impl<S, B> Service<http::Request<B>> for CatchPanic<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let method = request.uri().path().to_string();
+        let mut future = match catch_unwind(AssertUnwindSafe(|| self.inner.call(request))) {
+            Ok(future) => Box::pin(future),
+            Err(panic) => {
+                let response = panic_response(&method, panic);
+                return Box::pin(async { Ok(response) });
+            }
+        };
+        Box::pin(std::future::poll_fn(move |cx| {
+            match catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+                Ok(poll) => poll,
+                Err(panic) => Poll::Ready(Ok(panic_response(&method, panic))),
+            }
+        }))
+    }
+}
+
+/// Logs the panic of the request to the method and returns its INTERNAL
+/// response.
+fn panic_response(method: &str, panic: Box<dyn Any + Send>) -> http::Response<BoxBody> {
+    let message = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    let correlation_id = ulid::Ulid::new().to_string();
+    let backtrace = BACKTRACE
+        .with(|b| b.take())
+        .map(|b| b.to_string())
+        .unwrap_or_default();
+    log::error!(
+        "Request to {} panicked, correlation ID {}: {}\n{}",
+        method,
+        correlation_id,
+        message,
+        backtrace
+    );
+    GRPC_PANICS.with_label_values(&[method]).inc();
+
+    let mut status = Status::internal(format!("internal error, correlation ID {}", correlation_id));
+    if let Ok(value) = correlation_id.parse() {
+        status
+            .metadata_mut()
+            .insert(CORRELATION_ID_METADATA_KEY, value);
+    }
+    status.into_http()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower_layer::Layer;
+
+    /// Panics when called on `/call`, and when polled on `/poll`.
+    #[derive(Clone)]
+    struct Panicking;
+
+    impl Service<http::Request<()>> for Panicking {
+        type Response = http::Response<BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<()>) -> Self::Future {
+            match request.uri().path() {
+                "/call" => panic!("panicked in call"),
+                "/poll" => Box::pin(async { panic!("panicked in poll") }),
+                _ => Box::pin(async { Ok(Status::ok("").into_http()) }),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_catch_panic() {
+        let mut service = CatchPanicLayer::new().layer(Panicking);
+        for path in ["/call", "/poll"] {
+            let request = http::Request::builder().uri(path).body(()).unwrap();
+            let response = service.call(request).await.unwrap();
+            let status = Status::from_header_map(response.headers()).unwrap();
+            assert_eq!(status.code(), tonic::Code::Internal);
+            let correlation_id = status.metadata().get(CORRELATION_ID_METADATA_KEY).unwrap();
+            assert!(status.message().ends_with(correlation_id.to_str().unwrap()));
+        }
+
+        let request = http::Request::builder().uri("/ok").body(()).unwrap();
+        let response = service.call(request).await.unwrap();
+        let status = Status::from_header_map(response.headers()).unwrap();
+        assert_eq!(status.code(), tonic::Code::Ok);
+    }
+}
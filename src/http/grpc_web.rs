@@ -0,0 +1,49 @@
+use axum::http::{HeaderName, HeaderValue, Method};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+const MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+const EXPOSED_HEADERS: [&str; 3] = ["grpc-status", "grpc-message", "grpc-status-details-bin"];
+const ALLOWED_HEADERS: [&str; 6] = [
+    "content-type",
+    "x-grpc-web",
+    "x-user-agent",
+    "grpc-timeout",
+    "authorization",
+    "x-agent-id",
+];
+
+/// Builds the CORS layer for grpc-web requests from browsers. `*` allows any
+/// origin, an empty list only allows same-origin requests.
+pub fn cors_layer(allowed_origins: &[String]) -> anyhow::Result<CorsLayer> {
+    let origins = if allowed_origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            allowed_origins
+                .iter()
+                .map(|o| HeaderValue::from_str(o))
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::POST, Method::OPTIONS])
+        .allow_headers(ALLOWED_HEADERS.map(HeaderName::from_static))
+        .expose_headers(EXPOSED_HEADERS.map(HeaderName::from_static))
+        .max_age(MAX_AGE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cors_layer() {
+        assert!(cors_layer(&[]).is_ok());
+        assert!(cors_layer(&["*".into()]).is_ok());
+        assert!(cors_layer(&["https://ui.example.com".into()]).is_ok());
+        assert!(cors_layer(&["bad\norigin".into()]).is_err());
+    }
+}
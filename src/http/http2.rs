@@ -0,0 +1,78 @@
+use crate::flags::Flags;
+use anyhow::ensure;
+use tonic::transport::Server;
+
+/// Smallest and largest HTTP/2 frame sizes allowed by RFC 9113.
+const MIN_FRAME_SIZE: u32 = 1 << 14;
+const MAX_FRAME_SIZE: u32 = (1 << 24) - 1;
+/// Largest HTTP/2 flow-control window.
+const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+/// Checks the HTTP/2 settings of the gRPC server, which would otherwise make
+/// it panic at the first connection.
+pub fn validate(flags: &Flags) -> anyhow::Result<()> {
+    for (flag, size) in [
+        (
+            "--grpc-initial-stream-window-size",
+            flags.grpc_initial_stream_window_size,
+        ),
+        (
+            "--grpc-initial-connection-window-size",
+            flags.grpc_initial_connection_window_size,
+        ),
+    ] {
+        ensure!(
+            size <= MAX_WINDOW_SIZE,
+            "{} must be at most {}",
+            flag,
+            MAX_WINDOW_SIZE
+        );
+    }
+    let frame_size = flags.grpc_max_frame_size;
+    ensure!(
+        frame_size == 0 || (MIN_FRAME_SIZE..=MAX_FRAME_SIZE).contains(&frame_size),
+        "--grpc-max-frame-size must be between {} and {}",
+        MIN_FRAME_SIZE,
+        MAX_FRAME_SIZE
+    );
+    Ok(())
+}
+
+/// Returns the builder of the gRPC server with the HTTP/2 settings of the
+/// flags.
+pub fn grpc_server(flags: &Flags) -> anyhow::Result<Server> {
+    validate(flags)?;
+    let nonzero = |v: u32| (v > 0).then_some(v);
+    Ok(Server::builder()
+        .initial_stream_window_size(nonzero(flags.grpc_initial_stream_window_size))
+        .initial_connection_window_size(nonzero(flags.grpc_initial_connection_window_size))
+        .http2_adaptive_window(flags.grpc_adaptive_window.then_some(true))
+        .max_frame_size(nonzero(flags.grpc_max_frame_size))
+        .max_concurrent_streams(nonzero(flags.grpc_max_concurrent_streams))
+        .http2_keepalive_interval(
+            (!flags.grpc_keepalive_interval.is_zero()).then_some(flags.grpc_keepalive_interval),
+        )
+        .http2_keepalive_timeout(Some(flags.grpc_keepalive_timeout)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_validate() {
+        let flags = |args: &[&str]| Flags::parse_from([&["evprofiler"], args].concat());
+        validate(&flags(&[])).unwrap();
+        validate(&flags(&[
+            "--grpc-initial-stream-window-size=4194304",
+            "--grpc-max-frame-size=1048576",
+        ]))
+        .unwrap();
+        assert!(validate(&flags(&["--grpc-max-frame-size=1024"])).is_err());
+        assert!(validate(&flags(&[
+            "--grpc-initial-connection-window-size=4294967295"
+        ]))
+        .is_err());
+    }
+}
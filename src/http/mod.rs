@@ -0,0 +1,65 @@
+pub mod catch_panic;
+#[cfg(feature = "grpc-web")]
+pub mod grpc_web;
+pub mod http2;
+
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::{response::IntoResponse, routing::get, Router};
+
+/// Builds the router of the HTTP server that runs next to the gRPC server.
+pub fn router() -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .route("/health", get(health))
+}
+
+/// Serves `router` until `shutdown` resolves, letting the requests in
+/// progress finish.
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    router: Router,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown)
+        .await?;
+    Ok(())
+}
+
+/// Reports the server as degraded while any bucket is unavailable.
+async fn health() -> impl IntoResponse {
+    let degraded = crate::storage::degraded_buckets();
+    if degraded.is_empty() {
+        return (StatusCode::OK, "ok".to_string());
+    }
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        format!("degraded: {} bucket unavailable", degraded.join(", ")),
+    )
+}
+
+/// Serves the metrics in the Prometheus text format, or in OpenMetrics, which
+/// carries exemplars, if the scraper accepts it.
+async fn metrics(headers: HeaderMap) -> impl IntoResponse {
+    let openmetrics = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains("application/openmetrics-text"));
+    let (res, content_type) = if openmetrics {
+        (
+            crate::metrics::encode_openmetrics(),
+            crate::metrics::OPENMETRICS_CONTENT_TYPE,
+        )
+    } else {
+        (crate::metrics::encode(), prometheus::TEXT_FORMAT)
+    };
+    match res {
+        Ok(body) => (StatusCode::OK, [(header::CONTENT_TYPE, content_type)], body),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            [(header::CONTENT_TYPE, "text/plain")],
+            e.to_string(),
+        ),
+    }
+}
@@ -0,0 +1,202 @@
+use crate::profilestorepb::SeriesSampleCounts;
+use moka::ops::compute::{CompResult, Op};
+use moka::sync::Cache;
+use prometheus::{register_int_counter, IntCounter};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tonic::{Request, Status};
+
+/// Metadata key clients can set instead of the idempotency_key field.
+pub const IDEMPOTENCY_KEY_METADATA_KEY: &str = "idempotency-key";
+
+static DUPLICATE_WRITES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_duplicate_writes_total",
+        "Total number of writes acknowledged without storing them because their idempotency key was seen before."
+    )
+    .unwrap()
+});
+
+#[derive(Debug, Clone, PartialEq)]
+enum State {
+    InProgress,
+    /// The write succeeded with the sample counts of its series.
+    Done(Vec<SeriesSampleCounts>),
+}
+
+/// Admission is the decision on a write carrying an idempotency key.
+#[derive(Debug)]
+pub enum Admission {
+    /// The write should be stored, and completed once it's durable.
+    Proceed(PendingWrite),
+    /// A write with the same key already succeeded, so it is acknowledged
+    /// with the sample counts of that write, without storing it again.
+    Duplicate(Vec<SeriesSampleCounts>),
+}
+
+/// PendingWrite is an admitted write whose outcome isn't known yet. Unless
+/// it's completed, its key is forgotten when it's dropped, whether the write
+/// failed or the request was cancelled, so that the write can be retried.
+#[derive(Debug, Default)]
+#[must_use]
+pub struct PendingWrite {
+    keys: Option<Cache<String, State>>,
+    key: String,
+}
+
+impl PendingWrite {
+    /// Records that the write is durable, so that retries are acknowledged
+    /// with the sample counts of its `series` without storing it again.
+    pub fn complete(mut self, series: &[SeriesSampleCounts]) {
+        if let Some(keys) = self.keys.take() {
+            keys.insert(std::mem::take(&mut self.key), State::Done(series.to_vec()));
+        }
+    }
+}
+
+impl Drop for PendingWrite {
+    fn drop(&mut self) {
+        if let Some(keys) = self.keys.take() {
+            keys.invalidate(&self.key);
+        }
+    }
+}
+
+/// IdempotencyKeys remembers the keys of successful writes for a window, so
+/// that agents retrying a write after an ambiguous failure don't store its
+/// samples twice. Keys are scoped per agent.
+#[derive(Debug)]
+pub struct IdempotencyKeys {
+    keys: Option<Cache<String, State>>,
+}
+
+impl Default for IdempotencyKeys {
+    fn default() -> Self {
+        Self::new(Duration::ZERO)
+    }
+}
+
+impl IdempotencyKeys {
+    /// Creates a store remembering keys for `window`. Zero disables
+    /// deduplication.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            keys: (!window.is_zero()).then(|| {
+                Cache::builder()
+                    .max_capacity(100_000)
+                    .time_to_live(window)
+                    .build()
+            }),
+        }
+    }
+
+    /// Returns the idempotency key of the request, from the field or the
+    /// header.
+    pub fn key_of<T>(request: &Request<T>, field: &str) -> Option<String> {
+        if !field.is_empty() {
+            return Some(field.to_string());
+        }
+        request
+            .metadata()
+            .get(IDEMPOTENCY_KEY_METADATA_KEY)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+    }
+
+    /// Decides whether the write of `agent` with `key` should be stored. Fails
+    /// with Aborted while a write with the same key is still in progress, so
+    /// the client retries once its outcome is known.
+    pub fn begin(&self, agent: &str, key: Option<String>) -> Result<Admission, Status> {
+        let (Some(keys), Some(key)) = (&self.keys, key) else {
+            return Ok(Admission::Proceed(PendingWrite::default()));
+        };
+
+        let key = format!("{}/{}", agent, key);
+        let res = keys
+            .entry(key.clone())
+            .and_compute_with(|current| match current {
+                Some(_) => Op::Nop,
+                None => Op::Put(State::InProgress),
+            });
+        match res {
+            CompResult::Inserted(_) => Ok(Admission::Proceed(PendingWrite {
+                keys: Some(keys.clone()),
+                key,
+            })),
+            CompResult::Unchanged(entry) => match entry.into_value() {
+                State::Done(series) => {
+                    DUPLICATE_WRITES.inc();
+                    Ok(Admission::Duplicate(series))
+                }
+                State::InProgress => Err(Status::aborted(
+                    "a write with the same idempotency key is in progress",
+                )),
+            },
+            _ => Err(Status::aborted(
+                "a write with the same idempotency key is in progress",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idempotency_keys() {
+        let keys = IdempotencyKeys::new(Duration::from_secs(60));
+        let Admission::Proceed(first) = keys.begin("agent", Some("k1".into())).unwrap() else {
+            panic!("first write must proceed");
+        };
+
+        // Retried while the first attempt is in progress.
+        let err = keys.begin("agent", Some("k1".into())).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Aborted);
+
+        // Other agents have their own keys.
+        assert!(matches!(
+            keys.begin("other", Some("k1".into())).unwrap(),
+            Admission::Proceed(_)
+        ));
+
+        let series = vec![SeriesSampleCounts {
+            accepted: 3,
+            ..Default::default()
+        }];
+        first.complete(&series);
+        let Admission::Duplicate(duplicate) = keys.begin("agent", Some("k1".into())).unwrap()
+        else {
+            panic!("retried write must be a duplicate");
+        };
+        assert_eq!(duplicate, series);
+
+        // Failed or cancelled writes can be retried.
+        let Admission::Proceed(second) = keys.begin("agent", Some("k2".into())).unwrap() else {
+            panic!("first write must proceed");
+        };
+        drop(second);
+        assert!(matches!(
+            keys.begin("agent", Some("k2".into())).unwrap(),
+            Admission::Proceed(_)
+        ));
+
+        let mut request = Request::new(());
+        assert_eq!(IdempotencyKeys::key_of(&request, ""), None);
+        request
+            .metadata_mut()
+            .insert(IDEMPOTENCY_KEY_METADATA_KEY, "header".parse().unwrap());
+        assert_eq!(IdempotencyKeys::key_of(&request, ""), Some("header".into()));
+        assert_eq!(
+            IdempotencyKeys::key_of(&request, "field"),
+            Some("field".into())
+        );
+
+        let disabled = IdempotencyKeys::default();
+        assert!(matches!(
+            disabled.begin("agent", Some("k1".into())).unwrap(),
+            Admission::Proceed(PendingWrite { keys: None, .. })
+        ));
+    }
+}
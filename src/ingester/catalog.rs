@@ -0,0 +1,302 @@
+use super::{Chunk, StringColumn};
+use crate::normalizer::POSSIBLE_METADATA_LABELS;
+use crate::profile::schema;
+use anyhow::{bail, Context};
+use arrow2::array::{BooleanArray, DictionaryArray, PrimitiveArray};
+use object_store::{path::Path, ObjectStore, PutMode, PutOptions, UpdateVersion};
+use prometheus::{register_int_counter, IntCounter};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::sync::{Arc, LazyLock};
+
+static MANIFEST_CONFLICTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_manifest_conflicts_total",
+        "Total number of partition manifest updates retried because another writer updated it first."
+    )
+    .unwrap()
+});
+
+/// Name of the manifest object of a partition.
+pub(crate) const MANIFEST: &str = "manifest.json";
+
+/// Attempts of a manifest update before giving up on conflicts.
+const MAX_UPDATE_ATTEMPTS: usize = 10;
+
+/// Serializes the updates of manifests within the process.
+static MANIFEST_LOCK: LazyLock<tokio::sync::Mutex<()>> = LazyLock::new(Default::default);
+
+/// A segment of a partition with the time range of its samples, in
+/// milliseconds, both ends inclusive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SegmentEntry {
+    pub path: String,
+    /// Series of the segment, as `<profile type>{<metadata labels>}`
+    /// selectors.
+    #[serde(default)]
+    pub series: Vec<String>,
+    pub min_timestamp: i64,
+    pub max_timestamp: i64,
+    pub size: usize,
+}
+
+impl SegmentEntry {
+    pub fn overlaps(&self, start: i64, end: i64) -> bool {
+        self.max_timestamp >= start && self.min_timestamp <= end
+    }
+}
+
+/// A segment replaced by a compaction, kept until the readers of the
+/// manifest versions still listing it are done.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct RemovedSegment {
+    pub path: String,
+    /// When the segment was removed from the manifest, in milliseconds.
+    pub removed_at: i64,
+}
+
+/// Manifest is the catalog of the segments of a partition, with their
+/// series, time ranges and sizes. Partitions are buckets of the UTC date of
+/// their samples, like `date=2024-01-01`, and the manifest of a bucketed
+/// partition is the source of truth for its segments: readers take it as a
+/// consistent snapshot instead of listing the partition, and segments are
+/// only visible once recorded. Partitions of older builds have no manifest
+/// and are named after the local date their segments were written at.
+///
+/// Manifests are updated with conditional puts, retried when another writer
+/// got there first, and overwritten on buckets without them, like the local
+/// file system, which writes objects to a temporary file that's renamed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    /// Incremented on every update.
+    #[serde(default)]
+    pub version: u64,
+    pub segments: Vec<SegmentEntry>,
+    #[serde(default)]
+    pub removed: Vec<RemovedSegment>,
+}
+
+impl Manifest {
+    /// Returns the manifest of the partition, or None if it has none.
+    pub async fn load(
+        storage: &Arc<dyn ObjectStore>,
+        partition: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        Ok(Self::load_versioned(storage, partition)
+            .await?
+            .map(|(manifest, _)| manifest))
+    }
+
+    async fn load_versioned(
+        storage: &Arc<dyn ObjectStore>,
+        partition: &str,
+    ) -> anyhow::Result<Option<(Self, UpdateVersion)>> {
+        let path = manifest_path(partition)?;
+        match storage.get(&path).await {
+            Ok(result) => {
+                let version = UpdateVersion {
+                    e_tag: result.meta.e_tag.clone(),
+                    version: result.meta.version.clone(),
+                };
+                let data = result.bytes().await?;
+                let manifest = serde_json::from_slice(&data)
+                    .with_context(|| format!("invalid manifest {}", path))?;
+                Ok(Some((manifest, version)))
+            }
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Applies `f` to the manifest of the partition, created if missing
+    /// and `create` is set, and writes it back atomically. `f` is applied
+    /// again to the latest manifest when another writer updated it
+    /// concurrently.
+    pub async fn update(
+        storage: &Arc<dyn ObjectStore>,
+        partition: &str,
+        create: bool,
+        mut f: impl FnMut(&mut Manifest),
+    ) -> anyhow::Result<()> {
+        let _guard = MANIFEST_LOCK.lock().await;
+        let path = manifest_path(partition)?;
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let (mut manifest, mode) = match Self::load_versioned(storage, partition).await? {
+                Some((manifest, version)) => (manifest, PutMode::Update(version)),
+                None if create => (Self::default(), PutMode::Create),
+                None => return Ok(()),
+            };
+            f(&mut manifest);
+            manifest.version += 1;
+            let data = serde_json::to_vec(&manifest)?;
+            match storage
+                .put_opts(&path, data.clone().into(), PutOptions::from(mode))
+                .await
+            {
+                Ok(_) => return Ok(()),
+                Err(
+                    object_store::Error::AlreadyExists { .. }
+                    | object_store::Error::Precondition { .. },
+                ) => MANIFEST_CONFLICTS.inc(),
+                // Other processes may overwrite the update, like when
+                // instances share a local directory.
+                Err(object_store::Error::NotImplemented) => {
+                    storage.put(&path, data.into()).await?;
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        bail!(
+            "manifest {} kept changing after {} attempts",
+            path,
+            MAX_UPDATE_ATTEMPTS
+        )
+    }
+
+    pub fn get(&self, path: &str) -> Option<&SegmentEntry> {
+        self.segments.iter().find(|s| s.path == path)
+    }
+
+    /// Returns the latest timestamp of the samples of the partition.
+    pub fn max_timestamp(&self) -> Option<i64> {
+        self.segments.iter().map(|s| s.max_timestamp).max()
+    }
+}
+
+pub(crate) fn manifest_path(partition: &str) -> anyhow::Result<Path> {
+    Ok(Path::parse(format!("{}/{}", partition, MANIFEST))?)
+}
+
+/// Returns the sorted series of the chunks, as recorded in manifests.
+pub(crate) fn series(chunks: &[Chunk]) -> anyhow::Result<Vec<String>> {
+    let fields = schema::create_schema().fields;
+    let mut series = BTreeSet::new();
+    for chunk in chunks {
+        let column = |name: &str| {
+            fields
+                .iter()
+                .position(|f| f.name == name)
+                .map(|i| chunk.arrays()[i].as_ref())
+                .with_context(|| format!("segment has no {} column", name))
+        };
+        let string = |name: &str| -> anyhow::Result<StringColumn> {
+            column(name)?
+                .as_any()
+                .downcast_ref::<DictionaryArray<i32>>()
+                .map(StringColumn)
+                .with_context(|| format!("column {} isn't a dictionary", name))
+        };
+        let profile_type = [
+            "name",
+            "sample_type",
+            "sample_unit",
+            "period_type",
+            "period_unit",
+        ]
+        .into_iter()
+        .map(string)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+        let durations = column("duration")?
+            .as_any()
+            .downcast_ref::<PrimitiveArray<i64>>()
+            .context("column duration isn't an integer")?;
+        let deltas = column("delta")?
+            .as_any()
+            .downcast_ref::<BooleanArray>()
+            .context("column delta isn't a boolean")?;
+        let mut labels = POSSIBLE_METADATA_LABELS
+            .iter()
+            .map(|label| Ok((*label, string(&format!("labels.{}", label))?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        labels.sort_by_key(|(label, _)| *label);
+
+        for row in 0..chunk.len() {
+            let mut key = profile_type
+                .iter()
+                .map(|c| c.get(row))
+                .collect::<Vec<_>>()
+                .join(":");
+            if super::is_delta(deltas, durations, row) {
+                key.push_str(":delta");
+            }
+            key.push('{');
+            let mut first = true;
+            for (label, values) in labels.iter().filter(|(_, v)| !v.is_null(row)) {
+                if !first {
+                    key.push_str(", ");
+                }
+                first = false;
+                let _ = write!(key, "{}={:?}", label, values.get(row));
+            }
+            key.push('}');
+            series.insert(key);
+        }
+    }
+    Ok(series.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    fn entry(path: &str) -> SegmentEntry {
+        SegmentEntry {
+            path: path.into(),
+            series: vec![],
+            min_timestamp: 0,
+            max_timestamp: 0,
+            size: 1,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_update() {
+        let storage: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        Manifest::update(&storage, "date=2024-01-01", false, |_| unreachable!())
+            .await
+            .unwrap();
+        assert_eq!(
+            Manifest::load(&storage, "date=2024-01-01").await.unwrap(),
+            None
+        );
+
+        Manifest::update(&storage, "date=2024-01-01", true, |m| {
+            m.segments.push(entry("a"))
+        })
+        .await
+        .unwrap();
+
+        // Another writer records a segment between the read and the write of
+        // the update, which is applied again on top of it.
+        let mut attempts = 0;
+        Manifest::update(&storage, "date=2024-01-01", false, |m| {
+            attempts += 1;
+            if attempts == 1 {
+                let mut other = m.clone();
+                other.segments.push(entry("b"));
+                other.version += 1;
+                let data = serde_json::to_vec(&other).unwrap();
+                let path = manifest_path("date=2024-01-01").unwrap();
+                tokio::task::block_in_place(|| {
+                    let put = storage.put(&path, data.into());
+                    tokio::runtime::Handle::current().block_on(put).unwrap();
+                });
+            }
+            m.segments.push(entry("c"));
+        })
+        .await
+        .unwrap();
+        assert_eq!(attempts, 2);
+
+        let manifest = Manifest::load(&storage, "date=2024-01-01")
+            .await
+            .unwrap()
+            .unwrap();
+        let paths: Vec<&str> = manifest.segments.iter().map(|s| s.path.as_str()).collect();
+        assert_eq!(paths, ["a", "b", "c"]);
+        assert_eq!(manifest.version, 3);
+    }
+}
@@ -0,0 +1,721 @@
+use super::catalog::{self, Manifest, RemovedSegment, SegmentEntry};
+use super::partition;
+use super::{encode_parquet, encode_parquet_with, Chunk, SEGMENT_VERSION, SEGMENT_VERSION_KEY};
+use crate::clock::{Clock, SystemClock};
+use crate::leader::{self, LeaderElection};
+use crate::profile::schema;
+use anyhow::Context;
+use arrow2::array::{new_null_array, Array, DictionaryArray};
+use arrow2::compute::{concatenate::concatenate, sort, take::take};
+use arrow2::io::parquet::{read, write::KeyValue};
+use object_store::{path::Path, ObjectMeta, ObjectStore};
+use prometheus::{register_int_counter, IntCounter};
+use std::collections::{BTreeMap, HashSet};
+use std::io::Cursor;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tokio_stream::StreamExt;
+
+static COMPACTED_SEGMENTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_compacted_segments_total",
+        "Total number of small segments merged by the compactor."
+    )
+    .unwrap()
+});
+
+/// Delay before the segments replaced in manifests by compactions are
+/// deleted, longer than queries take.
+const REMOVAL_DELAY: Duration = Duration::from_secs(600);
+
+/// Extension of merged segments of partitions without a manifest until their
+/// inputs are deleted, so that readers listing the partition don't see them.
+const PENDING_EXTENSION: &str = "pending";
+
+/// Key of the parquet metadata of pending segments with the paths of the
+/// segments they merge, one per line.
+const COMPACTED_INPUTS_KEY: &str = "evprofiler.compacted_inputs";
+
+/// Columns that identify a series, in sort order. Rows are sorted by these
+/// and then by timestamp, so the rows of a series are contiguous.
+fn sort_columns() -> Vec<String> {
+    let mut columns: Vec<String> = [
+        "name",
+        "sample_type",
+        "sample_unit",
+        "period_type",
+        "period_unit",
+    ]
+    .iter()
+    .map(|c| c.to_string())
+    .collect();
+    columns.extend(
+        crate::normalizer::POSSIBLE_METADATA_LABELS
+            .iter()
+            .map(|l| format!("labels.{}", l)),
+    );
+    columns.push("timestamp".into());
+    columns
+}
+
+/// Compactor periodically merges small parquet segments of a partition into a
+/// single segment sorted by series and timestamp.
+#[derive(Debug)]
+pub struct Compactor {
+    storage: Arc<dyn ObjectStore>,
+    interval: Duration,
+    /// Segments smaller than this many bytes are considered for compaction.
+    small_segment_bytes: usize,
+    /// Minimum number of small segments in a partition before it is compacted.
+    min_segments: usize,
+    /// Compaction only runs on the leader, if set.
+    leader: Option<Arc<LeaderElection>>,
+    /// Partitions whose samples are all older than this are deleted, if set.
+    retention: Option<Duration>,
+    /// How long segments replaced in manifests are kept for readers of the
+    /// manifest versions listing them.
+    removal_delay: Duration,
+    /// Tells the time segments are removed at and retention is measured from.
+    clock: Arc<dyn Clock>,
+}
+
+impl Compactor {
+    pub fn new(
+        storage: Arc<dyn ObjectStore>,
+        interval: Duration,
+        small_segment_bytes: usize,
+        min_segments: usize,
+    ) -> Self {
+        Self {
+            storage,
+            interval,
+            small_segment_bytes,
+            min_segments: min_segments.max(2),
+            leader: None,
+            retention: None,
+            removal_delay: REMOVAL_DELAY,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn with_leader_election(mut self, leader: Arc<LeaderElection>) -> Self {
+        self.leader = Some(leader);
+        self
+    }
+
+    /// Deletes the partitions past the retention on every round. Zero keeps
+    /// profiles forever.
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = (!retention.is_zero()).then_some(retention);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Runs compaction rounds until the process exits.
+    pub async fn run(self) {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if !leader::should_run(&self.leader) {
+                continue;
+            }
+            if let Err(e) = self.compact().await {
+                log::error!("Compaction failed: {}", e);
+            }
+            if let Err(e) = self.expire().await {
+                log::error!("Expiring partitions failed: {}", e);
+            }
+        }
+    }
+
+    /// Deletes the partitions past the retention, if set. Returns the number
+    /// of segments that were deleted.
+    async fn expire(&self) -> anyhow::Result<usize> {
+        let Some(retention) = self.retention else {
+            return Ok(0);
+        };
+        let before = self.clock.now().timestamp_millis() - retention.as_millis() as i64;
+        partition::expire(&self.storage, before).await
+    }
+
+    /// Compacts every partition with enough small segments. Returns the
+    /// number of segments that were merged.
+    pub async fn compact(&self) -> anyhow::Result<usize> {
+        self.delete_removed().await?;
+        let mut partitions: BTreeMap<String, Vec<ObjectMeta>> = BTreeMap::new();
+        let mut pending = vec![];
+        let mut objects = self.storage.list(None);
+        while let Some(meta) = objects.next().await {
+            let meta = meta?;
+            if meta.location.extension() == Some(PENDING_EXTENSION) {
+                pending.push(meta.location);
+                continue;
+            }
+            if meta.size >= self.small_segment_bytes || meta.location.extension() != Some("parquet")
+            {
+                continue;
+            }
+            let partition = match meta.location.as_ref().rsplit_once('/') {
+                Some((partition, _)) => partition.to_string(),
+                None => String::new(),
+            };
+            partitions.entry(partition).or_default().push(meta);
+        }
+        drop(objects);
+
+        // Compactions interrupted after writing their output are finished
+        // first, and their inputs are gone afterwards.
+        let mut finished = HashSet::new();
+        for path in pending {
+            let inputs = self
+                .resume_pending(&path)
+                .await
+                .with_context(|| format!("finishing compaction {}", path))?;
+            finished.extend(inputs);
+        }
+
+        let mut compacted = 0;
+        for (partition, mut segments) in partitions {
+            segments.retain(|s| !finished.contains(&s.location));
+            // Only the recorded segments of bucketed partitions are merged,
+            // not the ones being recorded or already replaced.
+            let manifest = Manifest::load(&self.storage, &partition).await?;
+            if let Some(manifest) = &manifest {
+                segments.retain(|s| manifest.get(s.location.as_ref()).is_some());
+            }
+            if segments.len() < self.min_segments {
+                continue;
+            }
+            compacted += self
+                .compact_partition(&partition, &segments, manifest.is_some())
+                .await
+                .with_context(|| format!("compacting partition {}", partition))?;
+        }
+        Ok(compacted)
+    }
+
+    async fn compact_partition(
+        &self,
+        partition: &str,
+        segments: &[ObjectMeta],
+        bucketed: bool,
+    ) -> anyhow::Result<usize> {
+        let mut arrays: Vec<Vec<Box<dyn Array>>> = vec![];
+        let mut merged = vec![];
+        for segment in segments {
+            let data = self.storage.get(&segment.location).await?.bytes().await?;
+            let chunks = match read_parquet(data.to_vec()) {
+                Ok(chunks) => chunks,
+                Err(e) => {
+                    log::warn!("Not compacting segment {}: {}", segment.location, e);
+                    continue;
+                }
+            };
+            merged.push(segment);
+            for chunk in chunks {
+                for (i, array) in chunk.into_arrays().into_iter().enumerate() {
+                    if arrays.len() <= i {
+                        arrays.push(vec![]);
+                    }
+                    arrays[i].push(array.to_boxed());
+                }
+            }
+        }
+
+        if merged.len() < 2 {
+            return Ok(0);
+        }
+
+        let columns = arrays
+            .iter()
+            .map(|parts| {
+                let parts: Vec<&dyn Array> = parts.iter().map(|a| a.as_ref()).collect();
+                concatenate(&parts)
+            })
+            .collect::<arrow2::error::Result<Vec<_>>>()?;
+        let sorted = sort_by_series(columns)?;
+
+        let time_range = partition::time_range(&sorted)?;
+        let series = catalog::series(std::slice::from_ref(&sorted))?;
+        let name = format!("{}/compacted-{}.parquet", partition, ulid::Ulid::new());
+        let path = Path::parse(&name)?;
+
+        // The merged segment is written before the inputs are removed, so an
+        // interrupted compaction never loses data. Bucketed partitions swap
+        // them in a single manifest update, and the inputs are only deleted
+        // once the readers of the previous versions are done.
+        if bucketed {
+            let buf = encode_parquet(&[sorted])?;
+            let size = buf.len();
+            self.storage.put(&path, buf.into()).await?;
+            let removed_at = self.clock.now().timestamp_millis();
+            let res = Manifest::update(&self.storage, partition, false, |manifest| {
+                manifest
+                    .segments
+                    .retain(|s| !merged.iter().any(|m| m.location.as_ref() == s.path));
+                if let Some((min_timestamp, max_timestamp)) = time_range {
+                    manifest.segments.push(SegmentEntry {
+                        path: path.to_string(),
+                        series: series.clone(),
+                        min_timestamp,
+                        max_timestamp,
+                        size,
+                    });
+                }
+                manifest
+                    .removed
+                    .extend(merged.iter().map(|m| RemovedSegment {
+                        path: m.location.to_string(),
+                        removed_at,
+                    }));
+            })
+            .await;
+            if let Err(e) = res {
+                // Segments missing from the manifest are neither read nor
+                // deleted later.
+                if let Err(e) = self.storage.delete(&path).await {
+                    log::warn!("Failed to delete unrecorded segment {}: {}", path, e);
+                }
+                return Err(e);
+            }
+        } else {
+            // Readers list the partition, so the merged segment only gets its
+            // name once the inputs are deleted. The pending segment records
+            // them, for the next round to finish an interrupted compaction.
+            let inputs: Vec<Path> = merged.iter().map(|m| m.location.clone()).collect();
+            let buf = encode_parquet_with(
+                &[sorted],
+                vec![KeyValue {
+                    key: COMPACTED_INPUTS_KEY.into(),
+                    value: Some(
+                        inputs
+                            .iter()
+                            .map(|p| p.as_ref())
+                            .collect::<Vec<_>>()
+                            .join("\n"),
+                    ),
+                }],
+            )?;
+            let pending = Path::parse(format!("{}.{}", name, PENDING_EXTENSION))?;
+            self.storage.put(&pending, buf.into()).await?;
+            self.finish_pending(&pending, &inputs).await?;
+        }
+
+        log::info!(
+            "Compacted {} segments of partition {} into {}",
+            merged.len(),
+            partition,
+            path
+        );
+        COMPACTED_SEGMENTS.inc_by(merged.len() as u64);
+        Ok(merged.len())
+    }
+
+    /// Finishes the compaction of the pending segment at `path`, and returns
+    /// the segments it merged.
+    async fn resume_pending(&self, path: &Path) -> anyhow::Result<Vec<Path>> {
+        let data = self.storage.get(path).await?.bytes().await?;
+        let metadata = read::read_metadata(&mut Cursor::new(&data))?;
+        let inputs = compacted_inputs(&metadata)?;
+        self.finish_pending(path, &inputs).await?;
+        log::info!("Finished interrupted compaction {}", path);
+        Ok(inputs)
+    }
+
+    /// Deletes the inputs of the pending segment and then gives it its final
+    /// name.
+    async fn finish_pending(&self, pending: &Path, inputs: &[Path]) -> anyhow::Result<()> {
+        for input in inputs {
+            match self.storage.delete(input).await {
+                Ok(()) | Err(object_store::Error::NotFound { .. }) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let Some(path) = pending
+            .as_ref()
+            .strip_suffix(PENDING_EXTENSION)
+            .and_then(|p| p.strip_suffix('.'))
+        else {
+            anyhow::bail!("{} is not a pending segment", pending);
+        };
+        self.storage.rename(pending, &Path::parse(path)?).await?;
+        Ok(())
+    }
+
+    /// Deletes the segments replaced in manifests for longer than the
+    /// removal delay.
+    async fn delete_removed(&self) -> anyhow::Result<()> {
+        let before = self.clock.now().timestamp_millis() - self.removal_delay.as_millis() as i64;
+        for partition in partition::partitions(&self.storage).await? {
+            let Some(manifest) = Manifest::load(&self.storage, partition.as_ref()).await? else {
+                continue;
+            };
+            let expired: Vec<String> = manifest
+                .removed
+                .iter()
+                .filter(|r| r.removed_at <= before)
+                .map(|r| r.path.clone())
+                .collect();
+            if expired.is_empty() {
+                continue;
+            }
+            for path in expired.iter() {
+                match self.storage.delete(&Path::parse(path)?).await {
+                    Ok(()) | Err(object_store::Error::NotFound { .. }) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Manifest::update(&self.storage, partition.as_ref(), false, |manifest| {
+                manifest.removed.retain(|r| !expired.contains(&r.path))
+            })
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn read_parquet(data: Vec<u8>) -> anyhow::Result<Vec<Chunk>> {
+    let mut reader = Cursor::new(data);
+    let metadata = read::read_metadata(&mut reader)?;
+    let version = segment_version(&metadata)?;
+    if version > SEGMENT_VERSION {
+        anyhow::bail!(
+            "segment format version {} is newer than the supported version {}",
+            version,
+            SEGMENT_VERSION
+        );
+    }
+    let schema = read::infer_schema(&metadata)?;
+    let reader = read::FileReader::new(reader, metadata.row_groups, schema, None, None, None);
+
+    let fields = schema::create_schema().fields;
+    let mut chunks = vec![];
+    for chunk in reader {
+        let chunk = chunk?;
+        let len = chunk.len();
+        let mut arrays: Vec<Arc<dyn Array>> = chunk
+            .into_arrays()
+            .into_iter()
+            .map(|a| Arc::from(fix_null_dictionary(a)))
+            .collect();
+        // Columns added since the segment was written are trailing ones and
+        // are null for its rows.
+        for field in fields.iter().skip(arrays.len()) {
+            arrays.push(Arc::from(new_null_array(field.data_type().clone(), len)));
+        }
+        chunks.push(Chunk::new(arrays));
+    }
+    Ok(chunks)
+}
+
+/// Returns the format version of a segment. Segments written before versions
+/// were recorded are at version 0.
+fn segment_version(metadata: &read::FileMetaData) -> anyhow::Result<u32> {
+    let version = metadata
+        .key_value_metadata()
+        .iter()
+        .flatten()
+        .find(|kv| kv.key == SEGMENT_VERSION_KEY)
+        .and_then(|kv| kv.value.as_deref());
+    match version {
+        Some(v) => Ok(v.parse()?),
+        None => Ok(0),
+    }
+}
+
+fn compacted_inputs(metadata: &read::FileMetaData) -> anyhow::Result<Vec<Path>> {
+    let Some(inputs) = metadata
+        .key_value_metadata()
+        .iter()
+        .flatten()
+        .find(|kv| kv.key == COMPACTED_INPUTS_KEY)
+        .and_then(|kv| kv.value.as_deref())
+    else {
+        anyhow::bail!("pending segment doesn't record its inputs");
+    };
+    Ok(inputs.lines().map(Path::parse).collect::<Result<_, _>>()?)
+}
+
+/// Label columns that are entirely null come back from parquet as
+/// dictionaries without values but with zeroed keys, which can't be
+/// concatenated. Replace them with a proper null array.
+fn fix_null_dictionary(array: Box<dyn Array>) -> Box<dyn Array> {
+    match array.as_any().downcast_ref::<DictionaryArray<i32>>() {
+        Some(dict) if dict.values().is_empty() => {
+            new_null_array(array.data_type().clone(), array.len())
+        }
+        _ => array,
+    }
+}
+
+fn sort_by_series(columns: Vec<Box<dyn Array>>) -> anyhow::Result<Chunk> {
+    let fields = schema::create_schema().fields;
+    let sort_columns = sort_columns()
+        .iter()
+        .filter_map(|name| fields.iter().position(|f| &f.name == name))
+        .map(|i| sort::SortColumn {
+            values: columns[i].as_ref(),
+            options: None,
+        })
+        .collect::<Vec<_>>();
+
+    let indices = sort::lexsort_to_indices::<i32>(&sort_columns, None)?;
+    let sorted = columns
+        .iter()
+        .map(|c| take(c.as_ref(), &indices).map(Arc::from))
+        .collect::<arrow2::error::Result<Vec<_>>>()?;
+    Ok(Chunk::new(sorted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow2::array::{
+        BooleanArray, Int64Array, ListArray, MutableBinaryArray, MutableDictionaryArray,
+        MutableListArray, MutableUtf8Array, TryPush,
+    };
+    use object_store::memory::InMemory;
+
+    fn segment(node: &str, timestamps: &[i64]) -> Chunk {
+        let n = timestamps.len();
+        let dict = |value: Option<&str>| {
+            let mut arr: MutableDictionaryArray<i32, MutableUtf8Array<i32>> =
+                MutableDictionaryArray::new();
+            for _ in 0..n {
+                arr.try_push(value).unwrap();
+            }
+            DictionaryArray::from(arr).arced()
+        };
+        let ints = |v: i64| Int64Array::from_vec(vec![v; n]).arced();
+
+        let mut stacktraces: MutableListArray<i32, MutableBinaryArray<i32>> =
+            MutableListArray::new();
+        for _ in 0..n {
+            stacktraces.try_push(Some(vec![Some(b"loc")])).unwrap();
+        }
+
+        let mut fields = vec![
+            ints(10),
+            dict(Some("process_cpu")),
+            ints(1),
+            dict(Some("cpu")),
+            dict(Some("nanoseconds")),
+            dict(Some("samples")),
+            dict(Some("count")),
+            ListArray::from(stacktraces).arced(),
+            Int64Array::from_vec(timestamps.to_vec()).arced(),
+            ints(1),
+        ];
+        for label in crate::normalizer::POSSIBLE_METADATA_LABELS {
+            fields.push(dict((label == "node").then_some(node)));
+        }
+        fields.push(dict(Some(r#""thread"="main""#)));
+        fields.push(dict(None));
+        fields.push(BooleanArray::from_slice(vec![true; n]).arced());
+        Chunk::new(fields)
+    }
+
+    #[tokio::test]
+    async fn test_compact() {
+        let storage: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let segments = [("b", vec![3, 1]), ("a", vec![2]), ("b", vec![2])];
+        for (i, (node, timestamps)) in segments.iter().enumerate() {
+            let buf = encode_parquet(&[segment(node, timestamps)]).unwrap();
+            storage
+                .put(
+                    &Path::from(format!("date=2024-01-01/{}.parquet", i)),
+                    buf.into(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let compactor = Compactor::new(Arc::clone(&storage), Duration::from_secs(60), 1 << 20, 3);
+        assert_eq!(compactor.compact().await.unwrap(), 3);
+        // Nothing left to compact.
+        assert_eq!(compactor.compact().await.unwrap(), 0);
+
+        let objects: Vec<ObjectMeta> = storage
+            .list(None)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(objects.len(), 1);
+
+        let data = storage.get(&objects[0].location).await.unwrap();
+        let data = data.bytes().await.unwrap().to_vec();
+        let metadata = read::read_metadata(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(segment_version(&metadata).unwrap(), SEGMENT_VERSION);
+        let chunks = read_parquet(data).unwrap();
+        assert_eq!(chunks.len(), 1);
+        let timestamps = chunks[0].arrays()[8]
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(timestamps.values().as_slice(), &[2, 1, 2, 3]);
+        let fields = schema::create_schema().fields;
+        let pprof_labels = fields
+            .iter()
+            .position(|f| f.name == "pprof_labels")
+            .unwrap();
+        let pprof_labels = chunks[0].arrays()[pprof_labels]
+            .as_any()
+            .downcast_ref::<DictionaryArray<i32>>()
+            .unwrap();
+        assert_eq!(pprof_labels.null_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_resume_pending() {
+        let storage: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let inputs = ["date=2024-01-01/0.parquet", "date=2024-01-01/1.parquet"];
+        for path in inputs {
+            let buf = encode_parquet(&[segment("a", &[1])]).unwrap();
+            storage.put(&Path::from(path), buf.into()).await.unwrap();
+        }
+        // A compaction interrupted after deleting its first input.
+        storage.delete(&Path::from(inputs[0])).await.unwrap();
+        let buf = encode_parquet_with(
+            &[segment("a", &[1, 1])],
+            vec![KeyValue {
+                key: COMPACTED_INPUTS_KEY.into(),
+                value: Some(inputs.join("\n")),
+            }],
+        )
+        .unwrap();
+        let pending = Path::from("date=2024-01-01/compacted-0.parquet.pending");
+        storage.put(&pending, buf.into()).await.unwrap();
+
+        let compactor = Compactor::new(Arc::clone(&storage), Duration::from_secs(60), 1 << 20, 2);
+        assert_eq!(compactor.compact().await.unwrap(), 0);
+        let objects: Vec<Path> = storage
+            .list(None)
+            .map(|meta| meta.unwrap().location)
+            .collect()
+            .await;
+        assert_eq!(objects, [Path::from("date=2024-01-01/compacted-0.parquet")]);
+    }
+
+    #[tokio::test]
+    async fn test_compact_bucketed() {
+        let storage: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let partition = "date=1970-01-01";
+        for (i, node) in ["a", "b", "c"].iter().enumerate() {
+            let path = format!("{}/{}.parquet", partition, i);
+            let buf = encode_parquet(&[segment(node, &[i as i64])]).unwrap();
+            storage
+                .put(&Path::from(path.as_str()), buf.into())
+                .await
+                .unwrap();
+            // The last segment isn't recorded yet.
+            if i < 2 {
+                Manifest::update(&storage, partition, true, |m| {
+                    m.segments.push(SegmentEntry {
+                        path: path.clone(),
+                        series: vec![],
+                        min_timestamp: i as i64,
+                        max_timestamp: i as i64,
+                        size: 1,
+                    })
+                })
+                .await
+                .unwrap();
+            }
+        }
+
+        let mut compactor =
+            Compactor::new(Arc::clone(&storage), Duration::from_secs(60), 1 << 20, 2);
+        assert_eq!(compactor.compact().await.unwrap(), 2);
+        let manifest = Manifest::load(&storage, partition).await.unwrap().unwrap();
+        assert_eq!(manifest.segments.len(), 1);
+        let compacted = &manifest.segments[0];
+        assert!(compacted.path.contains("compacted-"));
+        assert_eq!((compacted.min_timestamp, compacted.max_timestamp), (0, 1));
+        assert_eq!(
+            compacted.series,
+            [
+                r#"process_cpu:samples:count:cpu:nanoseconds:delta{node="a"}"#,
+                r#"process_cpu:samples:count:cpu:nanoseconds:delta{node="b"}"#
+            ]
+        );
+        assert_eq!(manifest.removed.len(), 2);
+        let count = |storage: Arc<dyn ObjectStore>| async move {
+            storage
+                .list(None)
+                .filter(|o| o.as_ref().unwrap().location.extension() == Some("parquet"))
+                .collect::<Vec<_>>()
+                .await
+                .len()
+        };
+        // Replaced segments are kept for the readers of older versions.
+        assert_eq!(count(Arc::clone(&storage)).await, 4);
+
+        compactor.removal_delay = Duration::ZERO;
+        assert_eq!(compactor.compact().await.unwrap(), 0);
+        assert_eq!(count(Arc::clone(&storage)).await, 2);
+        let manifest = Manifest::load(&storage, partition).await.unwrap().unwrap();
+        assert!(manifest.removed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_clock_boundaries() {
+        use crate::clock::ManualClock;
+        use chrono::DateTime;
+
+        let storage: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let partition = "date=1970-01-01";
+        let (kept, removed) = ("date=1970-01-01/0.parquet", "date=1970-01-01/1.parquet");
+        for path in [kept, removed] {
+            let buf = encode_parquet(&[segment("a", &[1000])]).unwrap();
+            storage.put(&Path::from(path), buf.into()).await.unwrap();
+        }
+        Manifest::update(&storage, partition, true, |m| {
+            m.segments.push(SegmentEntry {
+                path: kept.into(),
+                series: vec![],
+                min_timestamp: 1000,
+                max_timestamp: 1000,
+                size: 1,
+            });
+            m.removed.push(RemovedSegment {
+                path: removed.into(),
+                removed_at: 2000,
+            });
+        })
+        .await
+        .unwrap();
+
+        let at = |millis: i64| DateTime::from_timestamp_millis(millis).unwrap();
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let compactor = Compactor::new(Arc::clone(&storage), Duration::from_secs(60), 1 << 20, 2)
+            .with_retention(Duration::from_secs(86400))
+            .with_clock(clock.clone());
+        let exists = |path: &'static str| {
+            let storage = Arc::clone(&storage);
+            async move { storage.head(&Path::from(path)).await.is_ok() }
+        };
+
+        // Removed segments are deleted once the removal delay has passed.
+        clock.set(at(2000 + REMOVAL_DELAY.as_millis() as i64 - 1));
+        compactor.compact().await.unwrap();
+        assert!(exists(removed).await);
+        clock.advance(chrono::Duration::milliseconds(1));
+        compactor.compact().await.unwrap();
+        assert!(!exists(removed).await);
+
+        // Partitions are deleted once their last sample is past the retention.
+        clock.set(at(1000 + 86_400_000));
+        assert_eq!(compactor.expire().await.unwrap(), 0);
+        assert!(exists(kept).await);
+        clock.advance(chrono::Duration::milliseconds(1));
+        assert_eq!(compactor.expire().await.unwrap(), 1);
+        assert!(!exists(kept).await);
+    }
+}
@@ -0,0 +1,225 @@
+use crate::clock::{Clock, SystemClock};
+use chrono::{DateTime, Utc};
+use prometheus::{
+    exponential_buckets, register_gauge_vec, register_histogram, register_int_counter_vec,
+    GaugeVec, Histogram, IntCounterVec,
+};
+use std::collections::VecDeque;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+fn lag_buckets() -> Vec<f64> {
+    exponential_buckets(0.1, 2.0, 14).unwrap()
+}
+
+static RECEIVE_LAG: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "evprofiler_write_receive_lag_seconds",
+        "Time between the newest sample of a write and the server receiving the write.",
+        lag_buckets()
+    )
+    .unwrap()
+});
+
+static COMMIT_LAG: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "evprofiler_write_commit_lag_seconds",
+        "Time between the server receiving a write and its samples being written to the bucket.",
+        lag_buckets()
+    )
+    .unwrap()
+});
+
+static END_TO_END_LAG: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "evprofiler_write_end_to_end_lag_seconds",
+        "Time between the newest sample of a write and its samples being written to the bucket.",
+        lag_buckets()
+    )
+    .unwrap()
+});
+
+static SLO_WRITES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_write_latency_slo_writes_total",
+        "Total number of committed writes, by whether their end-to-end lag met the SLO.",
+        &["result"]
+    )
+    .unwrap()
+});
+
+static SLO_BURN_RATE: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "evprofiler_write_latency_slo_burn_rate",
+        "Rate the error budget of the write latency SLO is spent at over the window, 1 spending it exactly.",
+        &["window"]
+    )
+    .unwrap()
+});
+
+/// Windows the burn rate is computed over, in minutes, for the fast and slow
+/// windows of multi-window burn rate alerts.
+const BURN_RATE_WINDOWS: [(&str, i64); 2] = [("5m", 5), ("1h", 60)];
+
+/// Writes committed in a minute, and how many of them breached the SLO.
+#[derive(Debug, Clone, Copy)]
+struct Minute {
+    minute: i64,
+    writes: u64,
+    breached: u64,
+}
+
+/// WriteLatency observes how long the samples of writes take to become
+/// durable: from the newest sample of a write to the server receiving it,
+/// and from there to the segment holding it being written to the bucket.
+/// With an SLO on the end-to-end lag, it also exposes the rate its error
+/// budget is burnt at, so that operators can alert on ingestion lag.
+#[derive(Debug)]
+pub struct WriteLatency {
+    /// Maximum end-to-end lag of writes meeting the SLO, None without SLO.
+    slo: Option<Duration>,
+    /// Fraction of writes that must meet the SLO.
+    objective: f64,
+    clock: Arc<dyn Clock>,
+    minutes: Mutex<VecDeque<Minute>>,
+}
+
+impl Default for WriteLatency {
+    fn default() -> Self {
+        Self::new(Duration::ZERO, 0.99)
+    }
+}
+
+impl WriteLatency {
+    /// Creates a tracker of an SLO of `objective` of the writes becoming
+    /// durable within `slo`. Zero disables the SLO, the lags are still
+    /// observed.
+    pub fn new(slo: Duration, objective: f64) -> Self {
+        Self {
+            slo: (!slo.is_zero()).then_some(slo),
+            objective,
+            clock: Arc::new(SystemClock),
+            minutes: Mutex::default(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Observes a write received at `received` whose newest sample is at
+    /// `newest_sample` milliseconds, committed now. Lags are clamped to zero,
+    /// since the clocks of agents may be ahead of the server's.
+    pub fn observe(&self, newest_sample: Option<i64>, received: DateTime<Utc>) {
+        let committed = self.clock.now();
+        let seconds = |from: DateTime<Utc>, to: DateTime<Utc>| {
+            (to - from).to_std().unwrap_or_default().as_secs_f64()
+        };
+        let sample = newest_sample.and_then(DateTime::from_timestamp_millis);
+        if let Some(sample) = sample {
+            RECEIVE_LAG.observe(seconds(sample, received));
+            END_TO_END_LAG.observe(seconds(sample, committed));
+        }
+        COMMIT_LAG.observe(seconds(received, committed));
+
+        let Some(slo) = self.slo else {
+            return;
+        };
+        let lag = seconds(sample.unwrap_or(received), committed);
+        let breached = lag > slo.as_secs_f64();
+        SLO_WRITES
+            .with_label_values(&[if breached { "breached" } else { "met" }])
+            .inc();
+        for (window, rate) in self.record(committed, breached) {
+            SLO_BURN_RATE.with_label_values(&[window]).set(rate);
+        }
+    }
+
+    /// Records a write committed at `now` and returns the burn rates of the
+    /// windows.
+    fn record(&self, now: DateTime<Utc>, breached: bool) -> Vec<(&'static str, f64)> {
+        let minute = now.timestamp().div_euclid(60);
+        let mut minutes = self.minutes.lock().unwrap();
+        match minutes.back_mut() {
+            Some(last) if last.minute == minute => {
+                last.writes += 1;
+                last.breached += u64::from(breached);
+            }
+            _ => minutes.push_back(Minute {
+                minute,
+                writes: 1,
+                breached: u64::from(breached),
+            }),
+        }
+        let longest = BURN_RATE_WINDOWS.iter().map(|(_, m)| *m).max().unwrap_or(0);
+        while minutes
+            .front()
+            .is_some_and(|m| m.minute <= minute - longest)
+        {
+            minutes.pop_front();
+        }
+
+        let budget = (1.0 - self.objective).max(f64::EPSILON);
+        BURN_RATE_WINDOWS
+            .iter()
+            .map(|(window, length)| {
+                let (writes, breached) = minutes
+                    .iter()
+                    .filter(|m| m.minute > minute - length)
+                    .fold((0, 0), |(w, b), m| (w + m.writes, b + m.breached));
+                let rate = match writes {
+                    0 => 0.0,
+                    _ => breached as f64 / writes as f64 / budget,
+                };
+                (*window, rate)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_burn_rate() {
+        let t0 = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(t0));
+        let latency = WriteLatency::new(Duration::from_secs(60), 0.9).with_clock(clock.clone());
+
+        let assert_rates = |rates: Vec<(&str, f64)>, expected: [f64; 2]| {
+            for ((_, rate), expected) in rates.into_iter().zip(expected) {
+                assert!((rate - expected).abs() < 1e-9, "{} != {}", rate, expected);
+            }
+        };
+
+        // One write in ten breaching spends the budget exactly.
+        for i in 0..9 {
+            latency.record(clock.now(), i == 0);
+        }
+        assert_rates(latency.record(clock.now(), false), [1.0, 1.0]);
+        // Once the breach is out of the fast window, only the slow one still
+        // burns.
+        clock.advance(chrono::Duration::minutes(5));
+        assert_rates(latency.record(clock.now(), false), [0.0, 1.0 / 11.0 / 0.1]);
+        clock.advance(chrono::Duration::minutes(60));
+        assert_rates(latency.record(clock.now(), true), [10.0, 10.0]);
+        assert_eq!(latency.minutes.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_observe_slo() {
+        let t0 = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(t0 + chrono::Duration::seconds(61)));
+        let latency = WriteLatency::new(Duration::from_secs(60), 0.99).with_clock(clock.clone());
+        let breached = || SLO_WRITES.with_label_values(&["breached"]).get();
+        let before = breached();
+        latency.observe(Some(t0.timestamp_millis()), t0);
+        assert_eq!(breached(), before + 1);
+        // Samples from agents whose clock is ahead don't count as lagging.
+        latency.observe(Some(t0.timestamp_millis() + 3_600_000), t0);
+        assert_eq!(breached(), before + 1);
+    }
+}
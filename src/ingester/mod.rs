@@ -1,31 +1,61 @@
 mod bla;
+mod catalog;
+mod compactor;
+mod latency;
+mod partition;
 
 use anyhow::bail;
 use arrow2::{
-    array::Array,
+    array::{Array, BooleanArray, DictionaryArray, PrimitiveArray, Utf8Array},
     chunk::Chunk as Achunk,
     datatypes::{DataType, PhysicalType},
     error::Result,
     io::parquet::{read::ParquetError, write::*},
 };
 use bla::Bla;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use object_store::{path::Path, ObjectStore};
 use rayon::prelude::*;
 use std::{
     collections::VecDeque,
     sync::{Arc, Mutex},
+    time::Duration,
 };
+use tokio::sync::oneshot;
 
+use crate::normalizer::Metastore;
 use crate::profile::schema;
+pub(crate) use catalog::{Manifest, SegmentEntry};
+pub(crate) use compactor::read_parquet;
+pub use compactor::Compactor;
+pub use latency::WriteLatency;
+pub(crate) use partition::{partition_date, partitions};
 
 type Chunk = Achunk<Arc<dyn Array>>;
 
+/// Key of the parquet metadata entry holding the segment format version.
+pub(crate) const SEGMENT_VERSION_KEY: &str = "evprofiler.segment_version";
+/// Format version of the segments written by this build. Version 2 added the
+/// pprof sample label columns, version 3 the delta column.
+pub(crate) const SEGMENT_VERSION: u32 = 3;
+
+/// A chunk waiting to be persisted, with the time the write it came from
+/// was received, if its latency is observed, and who to tell once it's
+/// recorded in a segment.
+#[derive(Debug)]
+struct Buffered {
+    chunk: Chunk,
+    received: Option<DateTime<Utc>>,
+    committed: Option<oneshot::Sender<()>>,
+}
+
 #[derive(Debug)]
 pub struct Ingester {
-    chunks: Mutex<Vec<Chunk>>,
+    chunks: Mutex<Vec<Buffered>>,
     max_size: usize,
     storage: Arc<dyn ObjectStore>,
+    latency: Arc<WriteLatency>,
+    metastore: Option<Arc<Metastore>>,
 }
 
 impl Ingester {
@@ -34,133 +64,297 @@ impl Ingester {
             chunks: vec![].into(),
             max_size,
             storage,
+            latency: Arc::default(),
+            metastore: None,
         }
     }
 
+    /// Persists the function table of the metastore before every segment,
+    /// so that the functions the segments reference outlive the process.
+    pub fn with_metastore(mut self, metastore: Arc<Metastore>) -> Self {
+        self.metastore = Some(metastore);
+        self
+    }
+
+    pub fn with_write_latency(mut self, latency: Arc<WriteLatency>) -> Self {
+        self.latency = latency;
+        self
+    }
+
     pub async fn ingest(&self, chunk: Achunk<Arc<dyn Array>>) -> anyhow::Result<()> {
+        self.buffer(Buffered {
+            chunk,
+            received: None,
+            committed: None,
+        })
+    }
+
+    /// Ingests the chunk of a write received at `received`, observing how
+    /// long its samples take to be persisted. The returned receiver resolves
+    /// once the chunk is recorded in a segment, and fails if it couldn't be.
+    pub async fn ingest_write(
+        &self,
+        chunk: Achunk<Arc<dyn Array>>,
+        received: DateTime<Utc>,
+    ) -> anyhow::Result<oneshot::Receiver<()>> {
+        let (committed, receiver) = oneshot::channel();
+        self.buffer(Buffered {
+            chunk,
+            received: Some(received),
+            committed: Some(committed),
+        })?;
+        Ok(receiver)
+    }
+
+    fn buffer(&self, buffered: Buffered) -> anyhow::Result<()> {
         let mut chunks = self.chunks.lock().unwrap();
-        chunks.push(chunk);
+        chunks.push(buffered);
 
         let is_full = chunks.len() >= self.max_size;
 
         log::info!("Ingested a chunk");
 
         if is_full {
-            let c = chunks.clone();
-            chunks.clear();
-            let s = Arc::clone(&self.storage);
-            tokio::spawn(Self::persist(c, s));
+            tokio::spawn(self.persist(std::mem::take(&mut *chunks)));
         }
 
         Ok(())
     }
 
-    async fn persist(chunks: Vec<Chunk>, storage: Arc<dyn ObjectStore>) -> anyhow::Result<()> {
-        log::info!("Chunks max_size met. Trying to persist.");
-        let schema = schema::create_schema();
-        let options = WriteOptions {
-            write_statistics: true,
-            compression: CompressionOptions::Snappy,
-            version: Version::V2,
-            data_pagesize_limit: None,
-        };
+    /// Persists the buffered chunks, regardless of whether the buffer is full.
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let chunks = std::mem::take(&mut *self.chunks.lock().unwrap());
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        self.persist(chunks).await
+    }
 
-        let encoding_map = |data_type: &DataType| match data_type.to_physical_type() {
-            PhysicalType::Dictionary(_) => Encoding::RleDictionary,
-            _ => Encoding::Plain,
-        };
+    /// Persists the buffered chunks every `interval`, so that writes don't
+    /// wait for a full segment to become durable.
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.flush().await {
+                log::error!("Failed to persist the buffered chunks: {}", e);
+            }
+        }
+    }
 
-        let encodings = (&schema.fields)
-            .iter()
-            .map(|f| transverse(&f.data_type, encoding_map))
-            .collect::<Vec<_>>();
-
-        let parquet_schema = to_parquet_schema(&schema)
-            .map_err(|e| anyhow::anyhow!("Failed to create Parquet schema: {}", e))?;
-
-        log::info!("Did I come here --just after creating parquet schema--??");
-
-        let row_groups = chunks.iter().map(|chunk| {
-            let columns = chunk
-                .columns()
-                .par_iter()
-                .zip(parquet_schema.fields().to_vec())
-                .zip(encodings.par_iter())
-                .flat_map(move |((array, type_), encoding)| {
-                    let encoded_columns =
-                        array_to_columns(array, type_, options, encoding).unwrap();
-                    encoded_columns
-                        .into_iter()
-                        .map(|encoded_pages| {
-                            let encoded_pages = DynIter::new(encoded_pages.into_iter().map(|x| {
-                                x.map_err(|e| ParquetError::InvalidParameter(e.to_string()))
-                            }));
-                            encoded_pages
-                                .map(|page| {
-                                    compress(page?, vec![], options.compression)
-                                        .map_err(|x| x.into())
-                                })
-                                .collect::<Result<VecDeque<_>>>()
-                        })
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Result<Vec<VecDeque<CompressedPage>>>>()?;
-
-            let row_group = DynIter::new(
-                columns
-                    .into_iter()
-                    .map(|column| Result::Ok(DynStreamingIterator::new(Bla::new(column)))),
-            );
-            Result::Ok(row_group)
-        });
-
-        log::info!("row_groups: {:?}", row_groups.len());
-        let mut buf: Vec<u8> = vec![];
-        let mut writer = match FileWriter::try_new(&mut buf, schema, options) {
-            Ok(fw) => fw,
-            Err(e) => {
-                log::error!("{}", e);
-                bail!("{}", e)
+    /// Persists the chunks as a segment per partition of their samples,
+    /// recorded in the manifest of the partition once written, after the
+    /// functions they reference. The latency of the writes is observed, and
+    /// they're told they're committed, once all their segments are recorded.
+    fn persist(
+        &self,
+        buffered: Vec<Buffered>,
+    ) -> impl std::future::Future<Output = anyhow::Result<()>> + Send + 'static {
+        let storage = Arc::clone(&self.storage);
+        let latency = Arc::clone(&self.latency);
+        let metastore = self.metastore.clone();
+        async move {
+            if let Some(metastore) = metastore {
+                metastore.persist(storage.as_ref()).await?;
             }
-        };
+            Self::write_segments(buffered, storage, latency).await
+        }
+    }
 
-        for group in row_groups {
-            let group = match group {
-                Ok(g) => g,
-                Err(e) => {
-                    log::error!("{}", e);
-                    bail!("{}", e)
-                }
-            };
-            match writer.write(group) {
+    async fn write_segments(
+        buffered: Vec<Buffered>,
+        storage: Arc<dyn ObjectStore>,
+        latency: Arc<WriteLatency>,
+    ) -> anyhow::Result<()> {
+        log::info!("Chunks max_size met. Trying to persist.");
+        let timestamp = Utc::now().timestamp();
+        let chunks: Vec<Chunk> = buffered.iter().map(|b| b.chunk.clone()).collect();
+        let mut committed = true;
+        for (partition, bucket) in partition::split(&chunks)? {
+            let buf = encode_parquet(&bucket.chunks)?;
+            log::info!("buf::: {:#?}", buf.len());
+
+            let p = Path::parse(format!(
+                "{}/{}-{}.parquet",
+                partition,
+                timestamp,
+                ulid::Ulid::new()
+            ))?;
+            let size = buf.len();
+            let series = catalog::series(&bucket.chunks)?;
+            match storage.put(&p, buf.into()).await {
                 Ok(_) => {}
                 Err(e) => {
                     log::error!("{}", e);
+                    committed = false;
+                    continue;
                 }
             };
+            let entry = SegmentEntry {
+                path: p.to_string(),
+                series,
+                min_timestamp: bucket.min_timestamp,
+                max_timestamp: bucket.max_timestamp,
+                size,
+            };
+            // Segments are only visible to readers once recorded.
+            if let Err(e) = Manifest::update(&storage, &partition, true, |m| {
+                m.segments.push(entry.clone())
+            })
+            .await
+            {
+                log::error!("Failed to record {} in its manifest: {}", p, e);
+                committed = false;
+            }
+            log::info!("Persisted the parquet chunks to {}", p);
         }
-        let _size = match writer.end(None) {
-            Ok(_) => {}
-            Err(e) => {
-                log::error!("{}", e);
+        if committed {
+            for b in buffered {
+                if let Some(received) = b.received {
+                    let newest = partition::time_range(&b.chunk)?.map(|(_, max)| max);
+                    latency.observe(newest, received);
+                }
+                if let Some(committed) = b.committed {
+                    let _ = committed.send(());
+                }
             }
-        };
+        }
+        Ok(())
+    }
+}
 
-        log::info!("buf::: {:#?}", buf.len());
-        let current_date = chrono::Local::now().date_naive();
-        let timestamp = Utc::now().timestamp();
+/// StringColumn reads the values of a dictionary encoded string column.
+pub(crate) struct StringColumn<'a>(pub &'a DictionaryArray<i32>);
+
+impl StringColumn<'_> {
+    pub fn is_null(&self, row: usize) -> bool {
+        self.0.is_null(row)
+    }
+
+    pub fn get(&self, row: usize) -> &str {
+        if self.0.is_null(row) {
+            return "";
+        }
+        match self.0.values().as_any().downcast_ref::<Utf8Array<i32>>() {
+            Some(values) => values.value(self.0.key_value(row)),
+            None => "",
+        }
+    }
+}
+
+/// Returns whether the sample of the row is from a delta profile. Segments
+/// written before the delta column have it null, and their samples are
+/// deltas when their profile covers a duration, as they were told apart then.
+pub(crate) fn is_delta(deltas: &BooleanArray, durations: &PrimitiveArray<i64>, row: usize) -> bool {
+    match deltas.is_null(row) {
+        true => durations.value(row) > 0,
+        false => deltas.value(row),
+    }
+}
+
+/// Encodes the chunks as a parquet file with one row group per chunk.
+pub(crate) fn encode_parquet(chunks: &[Chunk]) -> anyhow::Result<Vec<u8>> {
+    encode_parquet_with(chunks, vec![])
+}
+
+/// Like encode_parquet, with additional key-value metadata in the footer.
+pub(crate) fn encode_parquet_with(
+    chunks: &[Chunk],
+    key_values: Vec<KeyValue>,
+) -> anyhow::Result<Vec<u8>> {
+    let schema = schema::create_schema();
+    let options = WriteOptions {
+        write_statistics: true,
+        compression: CompressionOptions::Snappy,
+        version: Version::V2,
+        data_pagesize_limit: None,
+    };
+
+    let encoding_map = |data_type: &DataType| match data_type.to_physical_type() {
+        PhysicalType::Dictionary(_) => Encoding::RleDictionary,
+        _ => Encoding::Plain,
+    };
 
-        let p = Path::parse(&format!(
-            "date={}/{}.parquet",
-            current_date.format("%Y-%m-%d").to_string(),
-            timestamp
-        ))?;
+    let encodings = (&schema.fields)
+        .iter()
+        .map(|f| transverse(&f.data_type, encoding_map))
+        .collect::<Vec<_>>();
 
-        match storage.put(&p, buf.into()).await {
+    let parquet_schema = to_parquet_schema(&schema)
+        .map_err(|e| anyhow::anyhow!("Failed to create Parquet schema: {}", e))?;
+
+    log::info!("Did I come here --just after creating parquet schema--??");
+
+    let row_groups = chunks.iter().map(|chunk| {
+        let columns = chunk
+            .columns()
+            .par_iter()
+            .zip(parquet_schema.fields().to_vec())
+            .zip(encodings.par_iter())
+            .flat_map(move |((array, type_), encoding)| {
+                let encoded_columns = array_to_columns(array, type_, options, encoding).unwrap();
+                encoded_columns
+                    .into_iter()
+                    .map(|encoded_pages| {
+                        let encoded_pages =
+                            DynIter::new(encoded_pages.into_iter().map(|x| {
+                                x.map_err(|e| ParquetError::InvalidParameter(e.to_string()))
+                            }));
+                        encoded_pages
+                            .map(|page| {
+                                compress(page?, vec![], options.compression).map_err(|x| x.into())
+                            })
+                            .collect::<Result<VecDeque<_>>>()
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Result<Vec<VecDeque<CompressedPage>>>>()?;
+
+        let row_group = DynIter::new(
+            columns
+                .into_iter()
+                .map(|column| Result::Ok(DynStreamingIterator::new(Bla::new(column)))),
+        );
+        Result::Ok(row_group)
+    });
+
+    log::info!("row_groups: {:?}", row_groups.len());
+    let mut buf: Vec<u8> = vec![];
+    let mut writer = match FileWriter::try_new(&mut buf, schema, options) {
+        Ok(fw) => fw,
+        Err(e) => {
+            log::error!("{}", e);
+            bail!("{}", e)
+        }
+    };
+
+    for group in row_groups {
+        let group = match group {
+            Ok(g) => g,
+            Err(e) => {
+                log::error!("{}", e);
+                bail!("{}", e)
+            }
+        };
+        match writer.write(group) {
             Ok(_) => {}
-            Err(e) => log::error!("{}", e),
+            Err(e) => {
+                log::error!("{}", e);
+            }
         };
-        log::info!("Persisted the parquet chunks to {}", p);
-        Ok(())
     }
+    let version = KeyValue {
+        key: SEGMENT_VERSION_KEY.into(),
+        value: Some(SEGMENT_VERSION.to_string()),
+    };
+    let key_values = std::iter::once(version).chain(key_values).collect();
+    let _size = match writer.end(Some(key_values)) {
+        Ok(_) => {}
+        Err(e) => {
+            log::error!("{}", e);
+        }
+    };
+
+    Ok(buf)
 }
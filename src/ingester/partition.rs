@@ -0,0 +1,258 @@
+use super::catalog::{manifest_path, Manifest};
+use super::Chunk;
+use crate::normalizer::FUNCTIONS_PREFIX;
+use crate::profile::schema;
+use anyhow::Context;
+use arrow2::array::PrimitiveArray;
+use arrow2::compute::take::take;
+use chrono::{DateTime, NaiveDate, TimeDelta};
+use object_store::{path::Path, ObjectStore};
+use prometheus::{register_int_counter, IntCounter};
+use std::collections::BTreeMap;
+use std::sync::{Arc, LazyLock};
+use tokio_stream::StreamExt;
+
+static EXPIRED_SEGMENTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_expired_segments_total",
+        "Total number of segments deleted because their samples are past the retention."
+    )
+    .unwrap()
+});
+
+/// Returns the partition of the samples at the timestamp, in milliseconds.
+pub(crate) fn partition(timestamp: i64) -> String {
+    let date = DateTime::from_timestamp_millis(timestamp)
+        .unwrap_or_default()
+        .date_naive();
+    format!("date={}", date.format("%Y-%m-%d"))
+}
+
+/// Returns the date of the `date=YYYY-MM-DD` partition of the path.
+pub(crate) fn partition_date(path: &Path) -> Option<NaiveDate> {
+    path.parts()
+        .find_map(|p| p.as_ref().strip_prefix("date=").map(str::to_string))
+        .and_then(|d| NaiveDate::parse_from_str(&d, "%Y-%m-%d").ok())
+}
+
+/// Chunks of the samples of a partition.
+#[derive(Debug, Default)]
+pub(crate) struct Bucket {
+    pub chunks: Vec<Chunk>,
+    pub min_timestamp: i64,
+    pub max_timestamp: i64,
+}
+
+/// Splits the rows of the chunks by the partition of their timestamp.
+pub(crate) fn split(chunks: &[Chunk]) -> anyhow::Result<BTreeMap<String, Bucket>> {
+    let mut buckets: BTreeMap<String, Bucket> = BTreeMap::new();
+    for chunk in chunks {
+        let timestamps = timestamps(chunk)?;
+        let mut rows: BTreeMap<String, Vec<i32>> = BTreeMap::new();
+        for (row, timestamp) in timestamps.values_iter().enumerate() {
+            rows.entry(partition(*timestamp))
+                .or_default()
+                .push(row as i32);
+        }
+        let whole = rows.len() == 1;
+        for (partition, rows) in rows {
+            let (min, max) = rows
+                .iter()
+                .map(|&row| timestamps.value(row as usize))
+                .fold((i64::MAX, i64::MIN), |(min, max), t| {
+                    (min.min(t), max.max(t))
+                });
+            let chunk = if whole {
+                chunk.clone()
+            } else {
+                let indices = PrimitiveArray::from_vec(rows);
+                let arrays = chunk
+                    .arrays()
+                    .iter()
+                    .map(|a| take(a.as_ref(), &indices).map(Arc::from))
+                    .collect::<arrow2::error::Result<Vec<_>>>()?;
+                Chunk::new(arrays)
+            };
+            let bucket = buckets.entry(partition).or_insert_with(|| Bucket {
+                min_timestamp: i64::MAX,
+                max_timestamp: i64::MIN,
+                ..Default::default()
+            });
+            bucket.chunks.push(chunk);
+            bucket.min_timestamp = bucket.min_timestamp.min(min);
+            bucket.max_timestamp = bucket.max_timestamp.max(max);
+        }
+    }
+    Ok(buckets)
+}
+
+/// Returns the time range of the samples of the chunk, if it has any.
+pub(crate) fn time_range(chunk: &Chunk) -> anyhow::Result<Option<(i64, i64)>> {
+    let timestamps = timestamps(chunk)?;
+    let min = timestamps.values_iter().min().copied();
+    let max = timestamps.values_iter().max().copied();
+    Ok(min.zip(max))
+}
+
+fn timestamps(chunk: &Chunk) -> anyhow::Result<&PrimitiveArray<i64>> {
+    let fields = schema::create_schema().fields;
+    fields
+        .iter()
+        .position(|f| f.name == "timestamp")
+        .and_then(|i| chunk.arrays()[i].as_any().downcast_ref())
+        .context("segment has no timestamp column")
+}
+
+/// Returns the partitions of the storage, leaving out the function table.
+pub(crate) async fn partitions(storage: &Arc<dyn ObjectStore>) -> anyhow::Result<Vec<Path>> {
+    let mut partitions = storage.list_with_delimiter(None).await?.common_prefixes;
+    partitions.retain(|p| p.as_ref() != FUNCTIONS_PREFIX);
+    Ok(partitions)
+}
+
+/// Deletes the partitions whose samples are all before the timestamp, in
+/// milliseconds, and returns the number of deleted segments. Only the
+/// manifests of the partitions are read. Partitions without one are named
+/// after the date they were written at, and are deleted a day later than
+/// bucketed ones.
+pub(crate) async fn expire(storage: &Arc<dyn ObjectStore>, before: i64) -> anyhow::Result<usize> {
+    let Some(before_date) = DateTime::from_timestamp_millis(before).map(|t| t.date_naive()) else {
+        return Ok(0);
+    };
+    let mut expired = 0;
+    for partition in partitions(storage).await? {
+        let Some(date) = partition_date(&partition) else {
+            continue;
+        };
+        if date > before_date {
+            continue;
+        }
+        let expire = match Manifest::load(storage, partition.as_ref()).await? {
+            Some(manifest) => manifest.max_timestamp().is_none_or(|max| max < before),
+            None => date + TimeDelta::days(1) < before_date,
+        };
+        if !expire {
+            continue;
+        }
+        let objects: Vec<Path> = storage
+            .list(Some(&partition))
+            .map(|meta| meta.map(|meta| meta.location))
+            .collect::<Result<_, _>>()
+            .await?;
+        // The manifest goes last, so an interrupted expiry is retried.
+        let manifest = manifest_path(partition.as_ref())?;
+        for object in objects.iter().filter(|o| **o != manifest) {
+            storage.delete(object).await?;
+            if object.extension() == Some("parquet") {
+                expired += 1;
+            }
+        }
+        if objects.contains(&manifest) {
+            storage.delete(&manifest).await?;
+        }
+        log::info!("Expired partition {}", partition);
+    }
+    EXPIRED_SEGMENTS.inc_by(expired as u64);
+    Ok(expired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingester::{encode_parquet, SegmentEntry};
+    use crate::profile::schema;
+    use arrow2::array::new_null_array;
+    use object_store::memory::InMemory;
+
+    fn chunk(timestamps: &[i64]) -> Chunk {
+        let fields = schema::create_schema().fields;
+        Chunk::new(
+            fields
+                .iter()
+                .map(|f| match f.name.as_str() {
+                    "timestamp" => PrimitiveArray::from_vec(timestamps.to_vec()).arced(),
+                    _ => Arc::from(new_null_array(f.data_type().clone(), timestamps.len())),
+                })
+                .collect(),
+        )
+    }
+
+    const DAY: i64 = 86_400_000;
+
+    #[test]
+    fn test_split() {
+        let buckets = split(&[chunk(&[DAY + 5, 3, DAY]), chunk(&[7])]).unwrap();
+        let ranges: Vec<_> = buckets
+            .iter()
+            .map(|(p, b)| {
+                let rows: usize = b.chunks.iter().map(|c| c.len()).sum();
+                (p.as_str(), rows, b.min_timestamp, b.max_timestamp)
+            })
+            .collect();
+        assert_eq!(
+            ranges,
+            [
+                ("date=1970-01-01", 2, 3, 7),
+                ("date=1970-01-02", 2, DAY, DAY + 5)
+            ]
+        );
+        assert_eq!(
+            time_range(&buckets["date=1970-01-02"].chunks[0]).unwrap(),
+            Some((DAY, DAY + 5))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expire() {
+        let storage: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let data = encode_parquet(&[chunk(&[1])]).unwrap();
+        for path in [
+            "date=1970-01-02/a.parquet",
+            "date=1970-01-03/b.parquet",
+            "date=1970-01-04/c.parquet",
+        ] {
+            storage
+                .put(&Path::from(path), data.clone().into())
+                .await
+                .unwrap();
+        }
+        for (partition, max) in [
+            ("date=1970-01-02", 2 * DAY - 1),
+            ("date=1970-01-03", 2 * DAY + 10),
+        ] {
+            Manifest::update(&storage, partition, true, |m| {
+                m.segments.push(SegmentEntry {
+                    path: format!("{}/x.parquet", partition),
+                    series: vec![],
+                    min_timestamp: max,
+                    max_timestamp: max,
+                    size: 1,
+                })
+            })
+            .await
+            .unwrap();
+        }
+        // Unbucketed partitions are kept a day longer.
+        let legacy = Path::from("date=1970-01-01/d.parquet");
+        storage.put(&legacy, data.into()).await.unwrap();
+
+        assert_eq!(expire(&storage, 2 * DAY + 5).await.unwrap(), 2);
+        let remaining: Vec<String> = storage
+            .list(None)
+            .map(|m| m.unwrap().location.to_string())
+            .collect()
+            .await;
+        assert_eq!(
+            remaining,
+            [
+                "date=1970-01-03/b.parquet",
+                "date=1970-01-03/manifest.json",
+                "date=1970-01-04/c.parquet",
+            ]
+        );
+        assert_eq!(
+            Manifest::load(&storage, "date=1970-01-02").await.unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,356 @@
+use crate::metapb;
+use crate::profile::Location;
+use crate::profilestorepb::WriteRawRequest;
+use crate::symbolizerpb::SymbolizedLocations;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use prost::Message;
+use serde_json::{json, Value};
+use std::sync::{Arc, LazyLock};
+
+static MESSAGES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_kafka_messages_total",
+        "Total number of messages published to Kafka, by topic and result.",
+        &["topic", "result"]
+    )
+    .unwrap()
+});
+
+/// Serialization of the published messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum KafkaFormat {
+    /// Protobuf encoded messages.
+    #[default]
+    Proto,
+    /// JSON objects with the field names of the protobuf messages, and bytes
+    /// in base64.
+    Json,
+}
+
+/// Publisher publishes keyed messages to topics.
+#[tonic::async_trait]
+pub trait Publisher: Send + Sync + std::fmt::Debug {
+    async fn publish(&self, topic: &str, key: &str, payload: Vec<u8>) -> anyhow::Result<()>;
+}
+
+/// KafkaForwarder publishes the accepted WriteRaw requests, keyed by the
+/// pushing agent, and optionally the symbolized locations, keyed by build ID,
+/// so that consumers can process profiles in their own pipelines.
+#[derive(Debug)]
+pub struct KafkaForwarder {
+    publisher: Arc<dyn Publisher>,
+    format: KafkaFormat,
+    topic: String,
+    symbolization_topic: Option<String>,
+}
+
+impl KafkaForwarder {
+    pub fn new(publisher: Arc<dyn Publisher>, topic: String, format: KafkaFormat) -> Self {
+        Self {
+            publisher,
+            format,
+            topic,
+            symbolization_topic: None,
+        }
+    }
+
+    /// Publishes the symbolized locations to `topic`, as one
+    /// SymbolizedLocations message per symbolized batch of a build ID.
+    pub fn with_symbolization_topic(mut self, topic: String) -> Self {
+        self.symbolization_topic = Some(topic);
+        self
+    }
+
+    /// Publishes the request pushed by `agent`, whose labels must have been
+    /// scrubbed already.
+    pub async fn publish_write(
+        &self,
+        agent: &str,
+        request: &WriteRawRequest,
+    ) -> anyhow::Result<()> {
+        let payload = match self.format {
+            KafkaFormat::Proto => request.encode_to_vec(),
+            KafkaFormat::Json => serde_json::to_vec(&write_raw_json(request))?,
+        };
+        self.publish(&self.topic, agent, payload).await
+    }
+
+    /// Publishes the symbolized locations of `build_id`, if enabled.
+    pub async fn publish_symbolized(
+        &self,
+        build_id: &str,
+        locations: &[Location],
+    ) -> anyhow::Result<()> {
+        let Some(topic) = &self.symbolization_topic else {
+            return Ok(());
+        };
+        let message = symbolized_locations(build_id, locations);
+        if message.locations.is_empty() {
+            return Ok(());
+        }
+        let payload = match self.format {
+            KafkaFormat::Proto => message.encode_to_vec(),
+            KafkaFormat::Json => serde_json::to_vec(&message)?,
+        };
+        self.publish(topic, build_id, payload).await
+    }
+
+    async fn publish(&self, topic: &str, key: &str, payload: Vec<u8>) -> anyhow::Result<()> {
+        let res = self.publisher.publish(topic, key, payload).await;
+        let result = if res.is_ok() { "published" } else { "failed" };
+        MESSAGES.with_label_values(&[topic, result]).inc();
+        res
+    }
+}
+
+/// Returns the locations that have lines, referencing their functions and
+/// mappings by index.
+fn symbolized_locations(build_id: &str, locations: &[Location]) -> SymbolizedLocations {
+    let mut message = SymbolizedLocations {
+        build_id: build_id.to_string(),
+        ..Default::default()
+    };
+    for location in locations.iter().filter(|l| !l.lines.is_empty()) {
+        let mapping_index = location.mapping.as_ref().map_or(0, |mapping| {
+            let mapping = metapb::Mapping {
+                build_id: build_id.to_string(),
+                ..mapping.clone()
+            };
+            let index = message.mappings.iter().position(|m| *m == mapping);
+            index.unwrap_or_else(|| {
+                message.mappings.push(mapping);
+                message.mappings.len() - 1
+            }) as u32
+        });
+        let lines = location
+            .lines
+            .iter()
+            .map(|line| {
+                let function = line.function.clone().unwrap_or_default();
+                let index = message.functions.iter().position(|f| *f == function);
+                let function_index = index.unwrap_or_else(|| {
+                    message.functions.push(function);
+                    message.functions.len() - 1
+                });
+                metapb::Line {
+                    function_id: message.functions[function_index].id.clone(),
+                    line: line.line,
+                    function_index: function_index as u32,
+                }
+            })
+            .collect();
+        message.locations.push(metapb::Location {
+            id: location.id.clone(),
+            address: location.address,
+            mapping_id: String::new(),
+            is_folded: location.is_folded,
+            lines,
+            mapping_index,
+        });
+    }
+    message
+}
+
+/// Returns the request as JSON, with the raw profiles in base64.
+fn write_raw_json(request: &WriteRawRequest) -> Value {
+    let series: Vec<Value> = request
+        .series
+        .iter()
+        .map(|series| {
+            let labels: Vec<Value> = series
+                .labels
+                .iter()
+                .flat_map(|ls| ls.labels.iter())
+                .map(|l| json!({"name": l.name, "value": l.value}))
+                .collect();
+            let samples: Vec<Value> = series
+                .samples
+                .iter()
+                .map(|sample| {
+                    let executable_info: Vec<Value> = sample
+                        .executable_info
+                        .iter()
+                        .map(|info| {
+                            let segment = info.load_segment.as_ref().map(
+                                |segment| json!({"offset": segment.offset, "vaddr": segment.vaddr}),
+                            );
+                            json!({"elf_type": info.elf_type, "load_segment": segment})
+                        })
+                        .collect();
+                    json!({
+                        "raw_profile": STANDARD.encode(&sample.raw_profile),
+                        "executable_info": executable_info,
+                    })
+                })
+                .collect();
+            json!({"labels": {"labels": labels}, "samples": samples})
+        })
+        .collect();
+    json!({"series": series, "normalized": request.normalized})
+}
+
+/// Returns a publisher to the comma separated Kafka `brokers`.
+#[cfg(feature = "kafka")]
+pub fn connect(brokers: &str) -> anyhow::Result<Arc<dyn Publisher>> {
+    Ok(Arc::new(KafkaProducer::new(brokers)?))
+}
+
+#[cfg(not(feature = "kafka"))]
+pub fn connect(_: &str) -> anyhow::Result<Arc<dyn Publisher>> {
+    anyhow::bail!("publishing to Kafka requires the kafka feature")
+}
+
+/// KafkaProducer publishes messages to the Kafka brokers.
+#[cfg(feature = "kafka")]
+struct KafkaProducer {
+    producer: rdkafka::producer::FutureProducer,
+}
+
+#[cfg(feature = "kafka")]
+impl std::fmt::Debug for KafkaProducer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KafkaProducer").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaProducer {
+    fn new(brokers: &str) -> anyhow::Result<Self> {
+        let producer = rdkafka::ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", "30000")
+            .create()?;
+        Ok(Self { producer })
+    }
+}
+
+#[cfg(feature = "kafka")]
+#[tonic::async_trait]
+impl Publisher for KafkaProducer {
+    async fn publish(&self, topic: &str, key: &str, payload: Vec<u8>) -> anyhow::Result<()> {
+        let record = rdkafka::producer::FutureRecord::to(topic)
+            .key(key)
+            .payload(&payload);
+        self.producer
+            .send(record, std::time::Duration::ZERO)
+            .await
+            .map_err(|(e, _)| e)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metapb::{Function, Mapping};
+    use crate::profile::LocationLine;
+    use crate::profilestorepb::{Label, LabelSet, RawProfileSeries, RawSample};
+    use std::sync::Mutex;
+
+    /// Publisher keeping the published messages as (topic, key, payload).
+    #[derive(Debug, Default)]
+    struct FakePublisher {
+        messages: Mutex<Vec<(String, String, Vec<u8>)>>,
+    }
+
+    #[tonic::async_trait]
+    impl Publisher for FakePublisher {
+        async fn publish(&self, topic: &str, key: &str, payload: Vec<u8>) -> anyhow::Result<()> {
+            let message = (topic.to_string(), key.to_string(), payload);
+            self.messages.lock().unwrap().push(message);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let publisher = Arc::new(FakePublisher::default());
+        let request = WriteRawRequest {
+            series: vec![RawProfileSeries {
+                labels: Some(LabelSet {
+                    labels: vec![Label {
+                        name: "node".into(),
+                        value: "api".into(),
+                    }],
+                }),
+                samples: vec![RawSample {
+                    raw_profile: b"pprof".to_vec(),
+                    executable_info: vec![],
+                }],
+            }],
+            normalized: true,
+            ..Default::default()
+        };
+        let main = Function {
+            name: "main".into(),
+            ..Default::default()
+        };
+        let location = |address, lines: Vec<LocationLine>| Location {
+            address,
+            mapping: Some(Mapping {
+                start: 0x1000,
+                ..Default::default()
+            }),
+            lines,
+            ..Default::default()
+        };
+        let line = |line| LocationLine {
+            line,
+            function: Some(main.clone()),
+        };
+        let locations = [
+            location(0x1010, vec![line(3)]),
+            location(0x1020, vec![line(4)]),
+            location(0x1030, vec![]),
+        ];
+
+        let forwarder =
+            KafkaForwarder::new(publisher.clone(), "profiles".into(), KafkaFormat::Proto);
+        forwarder.publish_write("agent", &request).await.unwrap();
+        // Symbolization results aren't published without a topic.
+        forwarder
+            .publish_symbolized("b1", &locations)
+            .await
+            .unwrap();
+
+        let forwarder =
+            KafkaForwarder::new(publisher.clone(), "profiles".into(), KafkaFormat::Json)
+                .with_symbolization_topic("symbols".into());
+        forwarder.publish_write("agent", &request).await.unwrap();
+        forwarder
+            .publish_symbolized("b1", &locations)
+            .await
+            .unwrap();
+
+        let messages = publisher.messages.lock().unwrap();
+        assert_eq!(messages.len(), 3);
+        let (topic, key, payload) = &messages[0];
+        assert_eq!((topic.as_str(), key.as_str()), ("profiles", "agent"));
+        assert_eq!(
+            WriteRawRequest::decode(payload.as_slice()).unwrap(),
+            request
+        );
+
+        let json: Value = serde_json::from_slice(&messages[1].2).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "series": [{
+                    "labels": {"labels": [{"name": "node", "value": "api"}]},
+                    "samples": [{"raw_profile": "cHByb2Y=", "executable_info": []}],
+                }],
+                "normalized": true,
+            })
+        );
+
+        let (topic, key, payload) = &messages[2];
+        assert_eq!((topic.as_str(), key.as_str()), ("symbols", "b1"));
+        let symbolized: SymbolizedLocations = serde_json::from_slice(payload).unwrap();
+        assert_eq!(symbolized.locations.len(), 2);
+        assert_eq!(symbolized.functions, [main]);
+        assert_eq!(symbolized.mappings.len(), 1);
+        assert_eq!(symbolized.mappings[0].build_id, "b1");
+        assert_eq!(symbolized.locations[1].lines[0].line, 4);
+    }
+}
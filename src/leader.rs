@@ -0,0 +1,220 @@
+use object_store::{path::Path, ObjectStore, PutMode, PutOptions, UpdateVersion};
+use prometheus::{register_int_gauge, IntGauge};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+static IS_LEADER: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "evprofiler_leader",
+        "Whether this instance holds the leader lease and runs background jobs."
+    )
+    .unwrap()
+});
+
+/// Path of the lease in the bucket shared by the instances.
+const LEASE_PATH: &str = "leader/lease";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Lease {
+    holder: String,
+    /// Unix time in milliseconds after which the lease may be taken over.
+    expires_at: i64,
+}
+
+/// LeaderElection elects one of the instances sharing a bucket to run the
+/// background jobs, like compaction and symbolization, while all of them
+/// serve reads and writes. The leader holds a lease object in the bucket that
+/// it renews every third of the lease duration; the other instances take it
+/// over once it expires. Lease expiry relies on the clocks of the instances
+/// being roughly in sync.
+#[derive(Debug)]
+pub struct LeaderElection {
+    bucket: Arc<dyn ObjectStore>,
+    id: String,
+    lease_duration: Duration,
+    /// When the lease was last acquired or renewed, if this instance holds
+    /// it.
+    renewed_at: Mutex<Option<Instant>>,
+    /// Whether this instance was the leader when it was last reported.
+    leader: AtomicBool,
+}
+
+impl LeaderElection {
+    pub fn new(bucket: Arc<dyn ObjectStore>, id: String, lease_duration: Duration) -> Self {
+        Self {
+            bucket,
+            id,
+            lease_duration,
+            renewed_at: Mutex::new(None),
+            leader: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns whether this instance holds the lease: it acquired or renewed
+    /// it less than a lease duration ago. Leadership is lost once the lease
+    /// can't be renewed in time, whatever the reason, since another instance
+    /// may take it over.
+    pub fn is_leader(&self) -> bool {
+        self.renewed_at
+            .lock()
+            .unwrap()
+            .is_some_and(|t| t.elapsed() < self.lease_duration)
+    }
+
+    /// Records the outcome of a campaign started at `started`.
+    fn set_leader(&self, leader: bool, started: Instant) {
+        *self.renewed_at.lock().unwrap() = leader.then_some(started);
+        self.report();
+    }
+
+    /// Logs and exports changes of leadership.
+    fn report(&self) {
+        let leader = self.is_leader();
+        if self.leader.swap(leader, Ordering::Relaxed) != leader {
+            if leader {
+                log::info!("Instance {} became the leader", self.id);
+            } else {
+                log::info!("Instance {} is no longer the leader", self.id);
+            }
+        }
+        IS_LEADER.set(leader as i64);
+    }
+
+    /// Acquires or renews the lease, returning whether this instance is the
+    /// leader.
+    pub async fn campaign(&self) -> anyhow::Result<bool> {
+        // The lease is only counted from before it was written, so that it
+        // never outlives the lease other instances see.
+        let started = Instant::now();
+        let res = self.renew().await;
+        match &res {
+            Ok(leader) => self.set_leader(*leader, started),
+            Err(_) => self.report(),
+        }
+        res
+    }
+
+    async fn renew(&self) -> anyhow::Result<bool> {
+        let path = Path::from(LEASE_PATH);
+        let now = chrono::Utc::now().timestamp_millis();
+        let lease = Lease {
+            holder: self.id.clone(),
+            expires_at: now + self.lease_duration.as_millis() as i64,
+        };
+        let payload = serde_json::to_vec(&lease)?;
+
+        let mode = match self.bucket.get(&path).await {
+            Ok(res) => {
+                let version = UpdateVersion {
+                    e_tag: res.meta.e_tag.clone(),
+                    version: res.meta.version.clone(),
+                };
+                let current: Lease = serde_json::from_slice(&res.bytes().await?)?;
+                if current.holder != self.id && current.expires_at > now {
+                    return Ok(false);
+                }
+                PutMode::Update(version)
+            }
+            Err(object_store::Error::NotFound { .. }) => PutMode::Create,
+            Err(e) => return Err(e.into()),
+        };
+
+        match self
+            .bucket
+            .put_opts(&path, payload.clone().into(), PutOptions::from(mode))
+            .await
+        {
+            // Buckets without conditional updates, like the local file
+            // system, are overwritten and read back, so that the last writer
+            // wins.
+            Err(object_store::Error::NotImplemented) => {
+                self.bucket.put(&path, payload.into()).await?;
+                let current: Lease =
+                    serde_json::from_slice(&self.bucket.get(&path).await?.bytes().await?)?;
+                Ok(current.holder == self.id)
+            }
+            Ok(_) => Ok(true),
+            Err(
+                object_store::Error::AlreadyExists { .. }
+                | object_store::Error::Precondition { .. },
+            ) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Keeps campaigning for the lease until the process exits.
+    pub async fn run(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(self.lease_duration / 3);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.campaign().await {
+                log::warn!("Failed to renew the leader lease: {}", e);
+            }
+        }
+    }
+}
+
+/// Returns whether background jobs should run, which they do on every
+/// instance unless leader election is enabled.
+pub fn should_run(leader: &Option<Arc<LeaderElection>>) -> bool {
+    leader.as_ref().is_none_or(|l| l.is_leader())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn test_leader_election() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let a = LeaderElection::new(Arc::clone(&bucket), "a".into(), Duration::from_secs(60));
+        let b = LeaderElection::new(Arc::clone(&bucket), "b".into(), Duration::from_secs(60));
+        assert!(a.campaign().await.unwrap());
+        assert!(!b.campaign().await.unwrap());
+        assert!(a.campaign().await.unwrap());
+        assert!(a.is_leader() && !b.is_leader());
+        assert!(should_run(&None));
+        assert!(!should_run(&Some(Arc::new(b))));
+
+        // An expired lease is taken over.
+        let c = LeaderElection::new(Arc::clone(&bucket), "c".into(), Duration::from_secs(60));
+        bucket
+            .put(
+                &Path::from(LEASE_PATH),
+                serde_json::to_vec(&Lease {
+                    holder: "a".into(),
+                    expires_at: 0,
+                })
+                .unwrap()
+                .into(),
+            )
+            .await
+            .unwrap();
+        assert!(c.campaign().await.unwrap());
+        assert!(!a.campaign().await.unwrap());
+        assert!(!a.is_leader());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_leadership_expires() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let a = LeaderElection::new(Arc::clone(&bucket), "a".into(), Duration::from_secs(60));
+        assert!(a.campaign().await.unwrap());
+
+        // Leadership outlives failed renewals until the lease expires.
+        let path = Path::from(LEASE_PATH);
+        bucket.put(&path, "invalid".into()).await.unwrap();
+        assert!(a.campaign().await.is_err());
+        assert!(a.is_leader());
+        tokio::time::advance(Duration::from_secs(40)).await;
+        assert!(a.campaign().await.is_err());
+        assert!(a.is_leader());
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert!(!a.is_leader());
+        assert!(!should_run(&Some(Arc::new(a))));
+    }
+}
@@ -0,0 +1,72 @@
+//! Client library for evprofiler, so that agents written in Rust can push
+//! debuginfo without reimplementing the upload protocol. The server itself is
+//! the `evprofiler` binary.
+//!
+//! With the `embed` feature, [`ParcaScraper`] runs the profile ingestion
+//! pipeline in process.
+
+pub mod client;
+#[cfg(feature = "embed")]
+mod embed;
+
+// The modules of the pipeline are shared with the binary, which uses all of
+// them and reports their dead code. The embedding API only uses part of them.
+#[cfg(feature = "embed")]
+#[allow(dead_code, unused_imports)]
+mod backfill;
+#[cfg(feature = "embed")]
+#[allow(dead_code, unused_imports)]
+mod clock;
+#[cfg(feature = "embed")]
+#[allow(dead_code, unused_imports)]
+mod ingester;
+#[cfg(feature = "embed")]
+#[allow(dead_code, unused_imports)]
+mod leader;
+#[cfg(feature = "embed")]
+#[allow(dead_code, unused_imports)]
+mod memory;
+#[cfg(feature = "embed")]
+#[allow(dead_code, unused_imports)]
+mod metrics;
+#[cfg(feature = "embed")]
+#[allow(dead_code, unused_imports)]
+mod normalizer;
+#[cfg(feature = "embed")]
+#[allow(dead_code, unused_imports)]
+mod pipeline;
+#[cfg(feature = "embed")]
+#[allow(dead_code, unused_imports)]
+mod profile;
+#[cfg(feature = "fuzzing")]
+#[allow(dead_code, unused_imports)]
+mod symbols;
+
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+
+#[cfg(feature = "embed")]
+pub use embed::ParcaScraper;
+#[cfg(feature = "embed")]
+pub use normalizer::{ScrubRule, TimestampAction, TrimRule};
+
+pub mod debuginfopb {
+    tonic::include_proto!("parca.debuginfo.v1alpha1");
+}
+
+#[cfg(feature = "embed")]
+pub(crate) mod profilestorepb {
+    tonic::include_proto!("parca.profilestore.v1alpha1");
+}
+
+#[cfg(feature = "embed")]
+#[allow(dead_code)]
+pub(crate) mod metapb {
+    tonic::include_proto!("parca.metastore.v1alpha1");
+}
+
+#[cfg(feature = "embed")]
+pub(crate) mod pprofpb {
+    tonic::include_proto!("perftools.profiles");
+}
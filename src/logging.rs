@@ -0,0 +1,252 @@
+use anyhow::Context;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::future::Future;
+use std::io::Write;
+use std::str::FromStr;
+
+tokio::task_local! {
+    /// Fields of the request the task is handling, added to its log lines.
+    static FIELDS: Vec<(&'static str, String)>;
+}
+
+/// Format of the log lines written to stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Colored lines for humans.
+    Text,
+    /// One JSON object per line, for log aggregation.
+    Json,
+}
+
+/// Directive sets the level of the logs of a module, or of all modules that
+/// aren't set otherwise, given as `<level>` or `<module>=<level>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Directive {
+    module: Option<String>,
+    level: LevelFilter,
+}
+
+impl FromStr for Directive {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (module, level) = match s.split_once('=') {
+            Some((module, level)) => (Some(module.trim().to_string()), level),
+            None => (None, s),
+        };
+        let level = LevelFilter::from_str(level.trim())
+            .with_context(|| format!("invalid log level {:?}", level))?;
+        Ok(Self { module, level })
+    }
+}
+
+/// Logger filters log lines by the level of their module and writes them in
+/// `format`, with the fields of the request being handled.
+struct Logger {
+    format: LogFormat,
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+    text: Box<dyn Log>,
+}
+
+/// Sets up logging. `RUST_LOG` is honored, with the directives taking
+/// precedence.
+pub fn init(format: LogFormat, directives: &[Directive]) -> anyhow::Result<()> {
+    let mut all = vec![];
+    if let Ok(rust_log) = std::env::var("RUST_LOG") {
+        for directive in rust_log.split(',').filter(|d| !d.trim().is_empty()) {
+            all.push(directive.parse().context("invalid RUST_LOG")?);
+        }
+    }
+    all.extend_from_slice(directives);
+
+    let logger = Logger::new(format, &all);
+    log::set_max_level(logger.max_level());
+    log::set_boxed_logger(Box::new(logger))?;
+    Ok(())
+}
+
+impl Logger {
+    fn new(format: LogFormat, directives: &[Directive]) -> Self {
+        let mut default = LevelFilter::Info;
+        let mut modules: Vec<(String, LevelFilter)> = vec![];
+        for directive in directives {
+            match &directive.module {
+                Some(module) => {
+                    modules.retain(|(m, _)| m != module);
+                    modules.push((module.clone(), directive.level));
+                }
+                None => default = directive.level,
+            }
+        }
+        // The most specific module wins.
+        modules.sort_by_key(|(m, _)| std::cmp::Reverse(m.len()));
+
+        let mut text = colog::basic_builder();
+        text.filter_level(LevelFilter::Trace);
+        Self {
+            format,
+            default,
+            modules,
+            text: Box::new(text.build()),
+        }
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, std::cmp::max)
+    }
+
+    /// Returns the level of the target. Modules of this crate may be given
+    /// without the crate name, like `debuginfo_store`.
+    fn level(&self, target: &str) -> LevelFilter {
+        let local = target.strip_prefix("evprofiler::");
+        let within = |target: &str, module: &str| {
+            target
+                .strip_prefix(module)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        };
+        self.modules
+            .iter()
+            .find(|(module, _)| within(target, module) || local.is_some_and(|t| within(t, module)))
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let fields = fields();
+        match self.format {
+            LogFormat::Json => {
+                let line = json_line(record, &fields, chrono::Utc::now());
+                let _ = writeln!(std::io::stderr().lock(), "{}", line);
+            }
+            LogFormat::Text if fields.is_empty() => self.text.log(record),
+            LogFormat::Text => {
+                let suffix: String = fields
+                    .iter()
+                    .map(|(name, value)| format!(" {}={}", name, value))
+                    .collect();
+                self.text.log(
+                    &Record::builder()
+                        .args(format_args!("{}{}", record.args(), suffix))
+                        .level(record.level())
+                        .target(record.target())
+                        .module_path(record.module_path())
+                        .file(record.file())
+                        .line(record.line())
+                        .build(),
+                );
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.text.flush();
+    }
+}
+
+fn json_line(
+    record: &Record,
+    fields: &[(&'static str, String)],
+    time: chrono::DateTime<chrono::Utc>,
+) -> String {
+    let mut line = serde_json::Map::new();
+    line.insert(
+        "time".into(),
+        time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+            .into(),
+    );
+    line.insert(
+        "level".into(),
+        record.level().as_str().to_lowercase().into(),
+    );
+    line.insert("target".into(), record.target().into());
+    line.insert("msg".into(), record.args().to_string().into());
+    for (name, value) in fields {
+        line.insert(name.to_string(), value.as_str().into());
+    }
+    serde_json::Value::Object(line).to_string()
+}
+
+/// Runs `f` with `fields` added to its log lines, on top of the fields of the
+/// enclosing scope.
+pub async fn scope<F: Future>(fields: &[(&'static str, &str)], f: F) -> F::Output {
+    let mut all = self::fields();
+    for (name, value) in fields {
+        all.retain(|(n, _)| n != name);
+        all.push((name, value.to_string()));
+    }
+    FIELDS.scope(all, f).await
+}
+
+/// Returns the fields of the request the current task is handling.
+fn fields() -> Vec<(&'static str, String)> {
+    FIELDS.try_with(Clone::clone).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        let directives: Vec<Directive> = ["warn", "debuginfo_store=debug", "tonic=error"]
+            .iter()
+            .map(|d| d.parse().unwrap())
+            .collect();
+        let logger = Logger::new(LogFormat::Text, &directives);
+        assert_eq!(logger.max_level(), LevelFilter::Debug);
+        assert_eq!(
+            logger.level("evprofiler::debuginfo_store"),
+            LevelFilter::Debug
+        );
+        assert_eq!(
+            logger.level("evprofiler::debuginfo_store::fetcher"),
+            LevelFilter::Debug
+        );
+        assert_eq!(
+            logger.level("evprofiler::debuginfo_stores"),
+            LevelFilter::Warn
+        );
+        assert_eq!(logger.level("tonic::transport"), LevelFilter::Error);
+        assert_eq!(logger.level("evprofiler::scrape"), LevelFilter::Warn);
+
+        assert!("loud".parse::<Directive>().is_err());
+        assert!("scrape=loud".parse::<Directive>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_json_line() {
+        let time = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let line = scope(&[("build_id", "abcd")], async {
+            scope(&[("upload_id", "u1")], async {
+                json_line(
+                    &Record::builder()
+                        .args(format_args!("stored \"{}\"", 1))
+                        .level(log::Level::Info)
+                        .target("evprofiler::debuginfo_store")
+                        .build(),
+                    &fields(),
+                    time,
+                )
+            })
+            .await
+        })
+        .await;
+        assert_eq!(
+            line,
+            r#"{"build_id":"abcd","level":"info","msg":"stored \"1\"","target":"evprofiler::debuginfo_store","time":"2023-11-14T22:13:20.000Z","upload_id":"u1"}"#
+        );
+        assert!(fields().is_empty());
+    }
+}
@@ -1,26 +1,47 @@
-use chrono::TimeDelta;
+use clap::Parser;
 use debuginfo_store::DebuginfoFetcher;
-use debuginfopb::debuginfo_service_server::DebuginfoServiceServer;
 use ingester::Ingester;
 use object_store::{local, ObjectStore};
-use profilestorepb::{
-    agents_service_server::AgentsServiceServer,
-    profile_store_service_server::ProfileStoreServiceServer,
-};
 use std::sync::Arc;
-use tonic::{codec::CompressionEncoding, transport::Server};
 
 mod agent_store;
+mod alerting;
+mod allocator;
+mod backfill;
+mod bench;
+mod clickhouse;
+mod clock;
 mod columnquery;
+mod config;
 mod dal;
 mod debuginfo_store;
+mod flags;
+mod http;
+mod idempotency;
 mod ingester;
+mod kafka;
+mod leader;
+mod logging;
+mod memory;
+mod metrics;
+mod mode;
 mod normalizer;
+mod pipeline;
+mod principal;
 mod profile;
 mod profile_store;
+mod query;
+mod replay;
+mod sampling;
+mod scrape;
+mod server;
 mod storage;
 mod symbolizer;
 mod symbols;
+mod systemd;
+#[cfg(test)]
+mod testing;
+mod webhooks;
 
 pub(crate) mod profilestorepb {
     tonic::include_proto!("parca.profilestore.v1alpha1");
@@ -38,13 +59,109 @@ pub(crate) mod debuginfopb {
     tonic::include_proto!("parca.debuginfo.v1alpha1");
 }
 
+// The query API references messages of other packages by their relative
+// package path, so it is nested in the package hierarchy.
+pub(crate) mod parca {
+    pub(crate) mod metastore {
+        pub(crate) mod v1alpha1 {
+            pub(crate) use crate::metapb::*;
+        }
+    }
+
+    pub(crate) mod profilestore {
+        pub(crate) mod v1alpha1 {
+            pub(crate) use crate::profilestorepb::*;
+        }
+    }
+
+    pub(crate) mod query {
+        pub(crate) mod v1alpha1 {
+            tonic::include_proto!("parca.query.v1alpha1");
+        }
+    }
+
+    pub(crate) mod scrape {
+        pub(crate) mod v1alpha1 {
+            tonic::include_proto!("parca.scrape.v1alpha1");
+        }
+    }
+
+    pub(crate) mod symbolizer {
+        pub(crate) mod v1alpha1 {
+            tonic::include_proto!("parca.symbolizer.v1alpha1");
+        }
+    }
+}
+
+pub(crate) use parca::query::v1alpha1 as querypb;
+pub(crate) use parca::scrape::v1alpha1 as scrapepb;
+pub(crate) use parca::symbolizer::v1alpha1 as symbolizerpb;
+
+pub(crate) mod adminpb {
+    tonic::include_proto!("parca.admin.v1alpha1");
+}
+
+pub(crate) mod tracespb {
+    tonic::include_proto!("parca.traces.v1alpha1");
+}
+
+pub(crate) mod flightpb {
+    tonic::include_proto!("arrow.flight.protocol");
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    colog::init();
+    let mut flags = flags::Flags::parse();
+    logging::init(flags.log_format, &flags.log_level)?;
+    if let Some(flags::Command::CheckConfig { path }) = &flags.command {
+        let Some(path) = path.as_ref().or(flags.config_file.as_ref()) else {
+            anyhow::bail!("no configuration file to check, pass its path or --config-file");
+        };
+        let problems = config::check(&flags, path);
+        for problem in problems.iter() {
+            eprintln!("{:#}", problem);
+        }
+        anyhow::ensure!(problems.is_empty(), "found {} problems", problems.len());
+        println!("{} is valid", path.display());
+        return Ok(());
+    }
+    let config = match &flags.config_file {
+        Some(path) => config::Config::load(path)?,
+        None => config::Config::default(),
+    };
+    let webhooks = Arc::new(webhooks::Webhooks::new(&config.webhooks)?);
+    let grpc_builder = http::http2::grpc_server(&flags)?;
+    let authenticator = principal::Authenticator::new(principal::TrustedProxies::new(
+        &flags.trusted_proxies,
+        &flags.client_ip_header,
+        &flags.client_identity_header,
+    )?);
+
+    let debuginfod_bucket: Arc<dyn ObjectStore> = match &flags.debuginfo_dir {
+        Some(dir) => Arc::new(storage::new_local_bucket(dir)?),
+        None => Arc::new(storage::new_memory_bucket()),
+    };
+    let debuginfod_bucket = storage::with_prefix(debuginfod_bucket, &flags.debuginfo_bucket_prefix);
+    let debuginfo_breaker = Arc::new(storage::CircuitBreaker::new(
+        "debuginfo",
+        flags.bucket_failure_threshold,
+        flags.bucket_probe_interval,
+    ));
+    let debuginfod_bucket =
+        storage::with_circuit_breaker(debuginfod_bucket, Arc::clone(&debuginfo_breaker));
+    let encryption =
+        storage::Keyring::load(flags.encryption_keys_file.as_deref())?.map(|keyring| {
+            Arc::new(
+                storage::Encryption::new(keyring).with_strict(!flags.encryption_allow_plaintext),
+            )
+        });
+    anyhow::ensure!(
+        encryption.is_some() || !flags.encryption_allow_plaintext,
+        "--encryption-allow-plaintext requires encryption keys"
+    );
+    let debuginfod_bucket = storage::with_encryption(debuginfod_bucket, encryption.clone());
+    let object_layout = debuginfo_store::ObjectLayout::new(&flags.debuginfo_object_path)?;
 
-    let metadata_store = debuginfo_store::MetadataStore::new();
-    let debuginfod = debuginfo_store::DebugInfod::default();
-    let debuginfod_bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
     let stackrace_bucket: Arc<dyn ObjectStore> = Arc::new(
         match local::LocalFileSystem::new_with_prefix("evprofiler-data") {
             Ok(s) => s,
@@ -54,48 +171,595 @@ async fn main() -> anyhow::Result<()> {
             }
         },
     );
-    let ingester = Arc::new(Ingester::new(10, Arc::clone(&stackrace_bucket)));
-    let symbolizer = Arc::new(symbolizer::Symbolizer::new(
-        debuginfo_store::MetadataStore::with_store(metadata_store.store.clone()),
-        DebuginfoFetcher::new(Arc::clone(&debuginfod_bucket), debuginfod.clone()),
+
+    let stackrace_bucket = storage::with_circuit_breaker(
+        stackrace_bucket,
+        Arc::new(storage::CircuitBreaker::new(
+            "profile",
+            flags.bucket_failure_threshold,
+            flags.bucket_probe_interval,
+        )),
+    );
+    let stackrace_bucket = storage::with_encryption(stackrace_bucket, encryption.clone());
+
+    // Read-only replicas leave migrating the shared storage to the writers.
+    if !flags.mode.is_read_only() {
+        storage::migrate(
+            Arc::clone(&debuginfod_bucket),
+            "debuginfo",
+            storage::DEBUGINFO_MIGRATIONS,
+        )
+        .await?;
+        storage::migrate(
+            Arc::clone(&stackrace_bucket),
+            "profile",
+            storage::PROFILE_MIGRATIONS,
+        )
+        .await?;
+    }
+
+    let route_buckets = config
+        .debuginfo_buckets
+        .iter()
+        .map(|route| {
+            let bucket: Arc<dyn ObjectStore> =
+                Arc::new(storage::new_local_bucket(&route.directory)?);
+            anyhow::Ok((route, storage::with_encryption(bucket, encryption.clone())))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let bucket_routes =
+        |default: &Arc<dyn ObjectStore>,
+         wrap: &dyn Fn(Arc<dyn ObjectStore>) -> Arc<dyn ObjectStore>| {
+            route_buckets.iter().fold(
+                debuginfo_store::BucketRoutes::new(Arc::clone(default)),
+                |routes, (route, bucket)| {
+                    routes.with_route(
+                        &route.name,
+                        route.build_id_prefixes.clone(),
+                        wrap(Arc::clone(bucket)),
+                    )
+                },
+            )
+        };
+
+    if let Some(command) = flags.command.take() {
+        return run_command(
+            command,
+            &flags,
+            bucket_routes(&debuginfod_bucket, &|bucket| bucket),
+            &object_layout,
+            stackrace_bucket,
+        )
+        .await;
+    }
+
+    // Objects written from now on are accounted as they are written, those
+    // written before are counted in the background.
+    let debuginfo_usage = Arc::new(storage::StorageUsage::new(
+        "debuginfo",
+        storage::debuginfo_objects,
+    ));
+    let profile_usage = Arc::new(storage::StorageUsage::new(
+        "profile",
+        storage::profile_objects,
     ));
+    for (usage, bucket) in [
+        (&debuginfo_usage, &debuginfod_bucket),
+        (&profile_usage, &stackrace_bucket),
+    ] {
+        let (usage, bucket) = (Arc::clone(usage), Arc::clone(bucket));
+        tokio::spawn(async move {
+            if let Err(e) = usage.scan(bucket.as_ref()).await {
+                log::warn!("Counting the objects of the bucket failed: {}", e);
+            }
+        });
+    }
+    let debuginfod_bucket = storage::with_usage(debuginfod_bucket, Arc::clone(&debuginfo_usage));
+    let stackrace_bucket = storage::with_usage(stackrace_bucket, Arc::clone(&profile_usage));
+    let debuginfo_buckets = Arc::new(bucket_routes(&debuginfod_bucket, &|bucket| {
+        storage::with_usage(bucket, Arc::clone(&debuginfo_usage))
+    }));
+    let storage_usage = vec![debuginfo_usage, profile_usage];
+    if !flags.storage_usage_report_interval.is_zero() {
+        tokio::spawn(storage::report_usage(
+            storage_usage.clone(),
+            flags.storage_usage_report_interval,
+        ));
+    }
 
-    log::info!("Starting Server");
+    let metadata_store = match flags.debuginfo_dir {
+        Some(_) => {
+            debuginfo_store::MetadataStore::persistent(Arc::clone(&debuginfod_bucket)).await?
+        }
+        None => debuginfo_store::MetadataStore::new(),
+    };
+    let debuginfod = if flags.debuginfod_disabled {
+        log::info!("debuginfod lookups are disabled");
+        debuginfo_store::DebugInfod::disabled()
+    } else {
+        let debuginfod = debuginfo_store::DebugInfod::from_env(flags.debuginfod_client_cache)?
+            .with_metadata(metadata_store.clone());
+        if debuginfod.is_disabled() {
+            log::info!("debuginfod lookups are disabled by an empty DEBUGINFOD_URLS");
+        }
+        debuginfod
+    };
+    let agent_store = Arc::new(agent_store::AgentStore::new(
+        match flags.agent_upload_quota_bytes {
+            0 => None,
+            max_bytes => Some(agent_store::AgentQuota {
+                max_bytes,
+                window: flags.agent_upload_quota_window,
+            }),
+        },
+    ));
+    if flags.mode.is_read_only() {
+        log::info!("Running read-only, writes and uploads are refused");
+    }
+    let writable = !flags.mode.is_read_only();
+    let clock: Arc<dyn clock::Clock> = Arc::new(clock::SystemClock);
+    let leader_election = (writable && !flags.leader_lease_duration.is_zero()).then(|| {
+        let id = flags
+            .instance_id
+            .clone()
+            .unwrap_or_else(|| ulid::Ulid::new().to_string());
+        log::info!("Leader election is enabled, instance ID is {}", id);
+        Arc::new(leader::LeaderElection::new(
+            Arc::clone(&stackrace_bucket),
+            id,
+            flags.leader_lease_duration,
+        ))
+    });
+    if let Some(leader_election) = &leader_election {
+        tokio::spawn(Arc::clone(leader_election).run());
+    }
+    let write_latency =
+        ingester::WriteLatency::new(flags.write_latency_slo, flags.write_latency_slo_objective)
+            .with_clock(Arc::clone(&clock));
+    let metastore =
+        Arc::new(normalizer::Metastore::load(stackrace_bucket.as_ref(), writable).await?);
+    let ingester = Arc::new(
+        Ingester::new(10, Arc::clone(&stackrace_bucket))
+            .with_write_latency(Arc::new(write_latency))
+            .with_metastore(Arc::clone(&metastore)),
+    );
+    if writable && !flags.segment_flush_interval.is_zero() {
+        tokio::spawn(Arc::clone(&ingester).run(flags.segment_flush_interval));
+    }
+    if writable && !flags.compaction_interval.is_zero() {
+        let mut compactor = ingester::Compactor::new(
+            Arc::clone(&stackrace_bucket),
+            flags.compaction_interval,
+            flags.compaction_small_segment_bytes,
+            flags.compaction_min_segments,
+        )
+        .with_retention(flags.profile_retention)
+        .with_clock(Arc::clone(&clock));
+        if let Some(leader_election) = &leader_election {
+            compactor = compactor.with_leader_election(Arc::clone(leader_election));
+        }
+        tokio::spawn(compactor.run());
+    }
+    let symbolizer = Arc::new(
+        symbolizer::Symbolizer::new(
+            metadata_store.clone(),
+            DebuginfoFetcher::new(
+                Arc::clone(&debuginfod_bucket),
+                object_layout.clone(),
+                debuginfod.clone(),
+            )
+            .with_parallel_download(
+                flags.debuginfo_download_chunk_size,
+                flags.debuginfo_download_concurrency,
+            )
+            .with_bucket_routes(Arc::clone(&debuginfo_buckets)),
+            symbolizer::FrameLimits {
+                max_inline_depth: flags.symbolizer_max_inline_depth,
+                max_frames: flags.symbolizer_max_frames,
+            },
+            symbols::Demangler::with_config(
+                false,
+                symbols::DemangleConfig {
+                    languages: flags.demangle_languages.clone(),
+                    raw: flags.demangle_raw_names,
+                },
+            ),
+        )
+        .with_budget(symbolizer::SymbolizationBudget::new(
+            flags.symbolization_timeout,
+            flags.symbolization_max_failures,
+        ))
+        .with_webhooks(Arc::clone(&webhooks)),
+    );
 
-    let addr = "[::1]:3333".parse().unwrap();
+    log::info!("Starting Server with the {} allocator", allocator::NAME);
 
-    log::info!("Attaching ProfileStoreService to the server");
-    let profile_store_impl = profile_store::ProfileStore::new(symbolizer, ingester);
+    let sample_reader =
+        query::SampleReader::new(Arc::clone(&stackrace_bucket), Arc::clone(&metastore));
+    let source_archives = Arc::new(
+        debuginfo_store::SourceArchives::new(
+            metadata_store.clone(),
+            Arc::clone(&debuginfod_bucket),
+            object_layout.clone(),
+        )
+        .with_bucket_routes(Arc::clone(&debuginfo_buckets))
+        .with_decompression_limits(normalizer::DecompressionLimits {
+            max_size: flags.max_decompressed_profile_bytes,
+            max_ratio: flags.max_decompression_ratio,
+        }),
+    );
+    let sources = Arc::new(
+        query::Sources::new(flags.source_roots.clone()).with_archives(Arc::clone(&source_archives)),
+    );
+    let services = server::ServicesBuilder::new(
+        &flags,
+        server::Stores {
+            metadata: metadata_store.clone(),
+            debuginfod,
+            debuginfo_buckets,
+            layout: object_layout,
+            bucket_breaker: debuginfo_breaker,
+            metastore: Arc::clone(&metastore),
+            ingester: Arc::clone(&ingester),
+            symbolizer: Arc::clone(&symbolizer),
+            agents: agent_store,
+            sample_reader: sample_reader.clone(),
+            sources,
+            webhooks,
+            clock,
+        },
+    );
+    let shared = services.shared();
+    let query_executor = Arc::clone(&shared.query_executor);
 
-    log::info!("Attaching AgentsService to the server");
-    let agent_store_impl = agent_store::AgentStore::default();
+    log::info!("Attaching ProfileStoreService to the server");
+    {
+        // The index is rebuilt from the segment catalog in the background,
+        // so that series stored before the restart are queryable.
+        let series_index = Arc::clone(&shared.series_index);
+        let storage = Arc::clone(&stackrace_bucket);
+        tokio::spawn(async move {
+            match series_index.load(&storage).await {
+                Ok(series) => log::info!("Loaded {} series into the series index", series),
+                Err(e) => log::warn!("Failed to load the series index: {:#}", e),
+            }
+        });
+    }
+    let memory_watchdog = (!flags.memory_report_interval.is_zero()).then(|| {
+        let mut watchdog = memory::MemoryWatchdog::new(flags.memory_limit)
+            .with_structure("metadata", Arc::new(metadata_store.clone()))
+            .with_structure("functions", Arc::clone(&metastore) as _)
+            .with_structure("symbolizer_cache", Arc::clone(&symbolizer) as _)
+            .with_structure("uploads", Arc::new(shared.upload_limiter.clone()))
+            .with_structure("queries", Arc::clone(&shared.query_executor) as _);
+        if let Some(payloads) = &shared.payloads {
+            watchdog = watchdog.with_structure("payloads", Arc::clone(payloads) as _);
+        }
+        let watchdog = Arc::new(watchdog);
+        tokio::spawn(Arc::clone(&watchdog).run(flags.memory_report_interval));
+        tokio::spawn(allocator::run(flags.memory_report_interval));
+        watchdog
+    });
+    let services = match &memory_watchdog {
+        Some(watchdog) => services.with_memory_watchdog(Arc::clone(watchdog)),
+        None => services,
+    };
+    let kafka_forwarder = match &flags.kafka_brokers {
+        Some(brokers) => {
+            let mut forwarder = kafka::KafkaForwarder::new(
+                kafka::connect(brokers)?,
+                flags.kafka_topic.clone(),
+                flags.kafka_format,
+            );
+            if let Some(topic) = &flags.kafka_symbolization_topic {
+                forwarder = forwarder.with_symbolization_topic(topic.clone());
+            }
+            log::info!(
+                "Publishing written profiles to Kafka topic {}",
+                flags.kafka_topic
+            );
+            Some(Arc::new(forwarder))
+        }
+        None => None,
+    };
+    let services = match &kafka_forwarder {
+        Some(forwarder) => services.with_kafka_forwarder(Arc::clone(forwarder)),
+        None => services,
+    };
+    let services = if !writable || flags.symbolization_queue_interval.is_zero() {
+        services
+    } else {
+        let mut queue = symbolizer::SymbolizationQueue::new(flags.symbolization_queue_dir.clone())?
+            .with_limits(
+                flags.symbolization_queue_max_addresses,
+                flags.symbolization_queue_max_age,
+            );
+        if let Some(leader_election) = &leader_election {
+            queue = queue.with_leader_election(Arc::clone(leader_election));
+        }
+        if let Some(forwarder) = &kafka_forwarder {
+            queue = queue.with_kafka_forwarder(Arc::clone(forwarder));
+        }
+        let queue = Arc::new(queue);
+        tokio::spawn(
+            Arc::clone(&queue).run(Arc::clone(&symbolizer), flags.symbolization_queue_interval),
+        );
+        services.with_symbolization_queue(queue)
+    };
+    let clickhouse_sink = match &flags.clickhouse_url {
+        Some(url) => {
+            let sink = Arc::new(
+                clickhouse::ClickHouseSink::new(
+                    url,
+                    &flags.clickhouse_table,
+                    Arc::clone(&metastore),
+                )?
+                .with_batch_size(flags.clickhouse_batch_size)
+                .with_async_insert(flags.clickhouse_async_insert),
+            );
+            tokio::spawn(Arc::clone(&sink).run(flags.clickhouse_flush_interval));
+            log::info!(
+                "Writing samples to ClickHouse table {}",
+                flags.clickhouse_table
+            );
+            Some(sink)
+        }
+        None => None,
+    };
+    let services = match &clickhouse_sink {
+        Some(sink) => services.with_clickhouse_sink(Arc::clone(sink)),
+        None => services,
+    };
+    let services = match &flags.export_dir {
+        Some(dir) => services.with_profile_export(Arc::new(
+            query::ParquetExport::new(
+                sample_reader.clone(),
+                Arc::new(storage::new_local_bucket(dir)?),
+            )
+            .with_query_executor(Arc::clone(&query_executor)),
+        )),
+        None => services,
+    };
+    if !config.alerting.rules.is_empty() {
+        let mut evaluator = alerting::RuleEvaluator::new(&config.alerting, sample_reader.clone())?
+            .with_query_executor(Arc::clone(&query_executor));
+        if let Some(leader_election) = &leader_election {
+            evaluator = evaluator.with_leader_election(Arc::clone(leader_election));
+        }
+        log::info!("Evaluating {} alerting rules", config.alerting.rules.len());
+        tokio::spawn(Arc::new(evaluator).run());
+    }
+    let diff_reports = flags.diff_version_label.as_ref().map(|label| {
+        let mut reports = query::DiffReports::new(sample_reader.clone(), label.clone())
+            .with_window(flags.diff_report_window)
+            .with_top(flags.diff_report_top)
+            .with_query_executor(Arc::clone(&query_executor));
+        if let Some(leader_election) = &leader_election {
+            reports = reports.with_leader_election(Arc::clone(leader_election));
+        }
+        let reports = Arc::new(reports);
+        tokio::spawn(Arc::clone(&reports).run(flags.diff_report_interval));
+        reports
+    });
+    let services = match &diff_reports {
+        Some(reports) => services.with_diff_reports(Arc::clone(reports)),
+        None => services,
+    };
 
     log::info!("Attaching DebugInfo to the server");
-    let debug_store_impl = debuginfo_store::DebuginfoStore {
-        metadata: metadata_store,
-        debuginfod,
-        max_upload_duration: TimeDelta::new(60 * 15, 0).unwrap(),
-        max_upload_size: 1000000000,
-        bucket: Arc::clone(&debuginfod_bucket),
-    };
+    let services = services.with_storage_usage(storage_usage).build()?;
 
-    log::info!("Starting server at {}", addr);
-    Server::builder()
-        .add_service(
-            ProfileStoreServiceServer::new(profile_store_impl)
-                .accept_compressed(CompressionEncoding::Gzip)
-                .max_decoding_message_size(1000000000)
-                .max_encoding_message_size(1000000000),
+    log::info!("Starting HTTP server at {}", flags.http_address);
+    let mut router = http::router();
+    let scrapes = flags.scrape_api || !config.scrape_configs.is_empty();
+    if scrapes && !writable {
+        log::warn!("Scraping is disabled in read-only mode");
+    }
+    let target_health = (writable && scrapes).then(|| Arc::new(scrape::TargetHealth::default()));
+    if let Some(health) = &target_health {
+        let mut scraper = scrape::Scraper::new(
+            Arc::clone(&services.profile_store),
+            scrape::PprofClient::new(flags.scrape_timeout),
+            Arc::clone(health),
         )
-        .add_service(AgentsServiceServer::new(agent_store_impl))
-        .add_service(
-            DebuginfoServiceServer::new(debug_store_impl)
-                .accept_compressed(CompressionEncoding::Gzip)
-                .max_decoding_message_size(1000000000)
-                .max_encoding_message_size(1000000000),
+        .with_max_concurrent_scrapes(flags.max_concurrent_scrapes)
+        .with_adhoc_targets(&config.scrape_configs, &flags.scrape_api_targets)?
+        .with_delta_profiles(
+            scrape::DeltaProfiles::new(flags.scrape_state_dir.clone())?
+                .with_decompression_limits(*services.profile_store.decompression()),
         )
-        .serve(addr)
-        .await?;
+        .with_debuginfo_discovery(scrape::DebuginfoDiscovery::new(Arc::clone(
+            &services.debuginfo,
+        )));
+        if flags.extract_image_debuginfo {
+            scraper = scraper.with_image_extractor(Arc::new(
+                debuginfo_store::ImageExtractor::new(
+                    Arc::clone(&services.debuginfo),
+                    flags.image_pull_timeout,
+                    flags.image_max_binary_bytes,
+                )
+                .with_max_layer_size(flags.image_max_layer_bytes),
+            ));
+        }
+        let scraper = Arc::new(scraper);
+        scraper.start(&config.scrape_configs)?;
+        router = router.merge(scrape::router(scraper, flags.scrape_api));
+    }
+    router = router.merge(query::render_router(
+        sample_reader,
+        Arc::new(query::QueryCache::new(flags.query_cache_bytes)),
+        query_executor,
+    ));
+    router = router.merge(debuginfo_store::sources_router(source_archives));
+    if let Some(reports) = &diff_reports {
+        router = router.merge(query::diff_router(Arc::clone(reports)));
+    }
+    let mut listeners = systemd::Listeners::from_env()?;
+    let http_listener = systemd::listen(&mut listeners, "http", flags.http_address).await?;
+    let grpc_listener = systemd::listen(&mut listeners, "grpc", flags.grpc_address).await?;
+    for name in listeners.unused() {
+        log::warn!("Ignoring socket {:?} passed by systemd", name);
+    }
+    let (stop, stopped) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        log::info!("Shutting down");
+        let _ = stop.send(true);
+    });
+    let stopped = move || {
+        let mut stopped = stopped.clone();
+        async move {
+            let _ = stopped.wait_for(|stopped| *stopped).await;
+        }
+    };
+    let http_server = http::serve(http_listener, router, stopped());
+
+    log::info!("Starting server at {}", flags.grpc_address);
+    // grpc-web requests are translated to gRPC, so browsers can call the
+    // services without a proxy.
+    #[cfg(feature = "grpc-web")]
+    let builder = grpc_builder
+        .accept_http1(true)
+        .layer(http::grpc_web::cors_layer(&flags.cors_allowed_origins)?)
+        .layer(tonic_web::GrpcWebLayer::new());
+    #[cfg(not(feature = "grpc-web"))]
+    let builder = {
+        if !flags.cors_allowed_origins.is_empty() {
+            log::warn!("--cors-allowed-origins has no effect without the grpc-web feature");
+        }
+        grpc_builder
+    };
+    let services = match target_health {
+        Some(health) => services.with_target_health(health),
+        None => services,
+    };
+    let grpc_server = builder
+        .layer(server::layer(authenticator))
+        .add_routes(services.routes())
+        .serve_with_incoming_shutdown(
+            tokio_stream::wrappers::TcpListenerStream::new(grpc_listener),
+            stopped(),
+        );
+
+    if systemd::notify("READY=1")? {
+        if let Some(interval) = systemd::watchdog_interval() {
+            tokio::spawn(systemd::watchdog(interval));
+        }
+    }
+    tokio::try_join!(http_server, async { Ok(grpc_server.await?) })?;
+
+    // Buffered samples would be lost on exit.
+    if let Some(sink) = &clickhouse_sink {
+        if let Err(e) = sink.flush().await {
+            log::error!("Failed to write samples to ClickHouse on shutdown: {}", e);
+        }
+    }
+    if writable {
+        if let Err(e) = ingester.flush().await {
+            log::error!("Failed to write buffered samples on shutdown: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Resolves once the process is asked to stop, with SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).ok();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        Some(_) = async { terminate.as_mut()?.recv().await } => {}
+    }
+}
+
+async fn run_command(
+    command: flags::Command,
+    flags: &flags::Flags,
+    buckets: debuginfo_store::BucketRoutes,
+    layout: &debuginfo_store::ObjectLayout,
+    profiles_bucket: Arc<dyn ObjectStore>,
+) -> anyhow::Result<()> {
+    let persistent = flags.debuginfo_dir.is_some();
+    let is_debuginfo_command = matches!(
+        command,
+        flags::Command::ExportDebuginfo { .. } | flags::Command::ImportDebuginfo { .. }
+    );
+    if is_debuginfo_command && !persistent {
+        anyhow::bail!("--debuginfo-dir is required to export or import debuginfo");
+    }
+
+    match command {
+        flags::Command::ExportDebuginfo { build_ids, out } => {
+            let file = std::io::BufWriter::new(std::fs::File::create(&out)?);
+            debuginfo_store::bundle::export(&buckets, layout, &build_ids, file).await?;
+            log::info!(
+                "Exported {} build IDs to {}",
+                build_ids.len(),
+                out.display()
+            );
+        }
+        flags::Command::ImportDebuginfo { bundle } => {
+            let file = std::io::BufReader::new(std::fs::File::open(&bundle)?);
+            let imported = debuginfo_store::bundle::import(&buckets, layout, file).await?;
+            log::info!(
+                "Imported {} debuginfo entries from {}",
+                imported,
+                bundle.display()
+            );
+        }
+        flags::Command::Ingest {
+            dir,
+            labels,
+            name,
+            manifest,
+        } => {
+            let backfill = backfill::Backfill::new(&name, &labels, manifest.as_deref())?;
+            // The functions of the backfilled profiles are added to the
+            // table of the server, which reads the segments back.
+            let metastore =
+                Arc::new(normalizer::Metastore::load(profiles_bucket.as_ref(), false).await?);
+            // Segments are flushed explicitly by the backfill.
+            let ingester =
+                Ingester::new(usize::MAX, profiles_bucket).with_metastore(Arc::clone(&metastore));
+            // Historical profiles are older than the timestamp policy allows.
+            let pipeline = server::write_pipeline(flags, Arc::clone(&metastore))?
+                .with_timestamp_policy(normalizer::TimestampPolicy::default());
+            let ingested = backfill.run(&dir, &pipeline, &ingester).await?;
+            log::info!("Ingested {} profiles from {}", ingested, dir.display());
+        }
+        flags::Command::Bench {
+            remote,
+            requests,
+            concurrency,
+            stack_depth,
+            samples,
+            functions,
+            cardinality,
+            debuginfo_metadata,
+        } => {
+            if debuginfo_metadata {
+                let report = tokio::task::spawn_blocking(move || {
+                    bench::debuginfo_metadata(requests, concurrency)
+                })
+                .await?;
+                log::info!("{}", report);
+                return Ok(());
+            }
+            let target = match remote {
+                Some(addr) => bench::Target::Remote(addr),
+                None => bench::Target::Local,
+            };
+            let shape = bench::ProfileShape {
+                stack_depth,
+                samples,
+                functions,
+                cardinality,
+            };
+            let report = bench::run(target, shape, requests, concurrency).await?;
+            log::info!("{}", report);
+        }
+        flags::Command::CheckConfig { .. } => unreachable!("checked before storage is opened"),
+    }
 
     Ok(())
 }
@@ -0,0 +1,253 @@
+use prometheus::{
+    register_int_counter, register_int_gauge, register_int_gauge_vec, IntCounter, IntGauge,
+    IntGaugeVec,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tonic::Status;
+
+static RSS_BYTES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "evprofiler_memory_rss_bytes",
+        "Resident set size of the server as last read by the memory watchdog."
+    )
+    .unwrap()
+});
+
+static STRUCTURE_BYTES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "evprofiler_memory_structure_bytes",
+        "Estimated size of the major in-memory structures, by structure.",
+        &["structure"]
+    )
+    .unwrap()
+});
+
+static SHEDDING: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "evprofiler_memory_shedding",
+        "Whether uploads are rejected because the memory limit is approached."
+    )
+    .unwrap()
+});
+
+static SHED_REQUESTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_memory_shed_requests_total",
+        "Total number of uploads rejected because the memory limit was approached."
+    )
+    .unwrap()
+});
+
+/// Fraction of the limit above which uploads are rejected.
+const SHED_RATIO: f64 = 0.9;
+/// Fraction of the limit below which uploads are accepted again, so that
+/// shedding doesn't flap around the threshold.
+const RECOVER_RATIO: f64 = 0.8;
+
+/// MemoryUsage is implemented by in-memory structures that can estimate how
+/// many bytes they hold. Estimates count the payloads, not the overhead of
+/// the containers.
+pub trait MemoryUsage: Send + Sync {
+    fn memory_usage(&self) -> u64;
+}
+
+/// MemoryLimitError is returned for uploads while the memory limit is
+/// approached, as ResourceExhausted by the RPCs.
+#[derive(Debug)]
+pub struct MemoryLimitError;
+
+impl std::fmt::Display for MemoryLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the server is running out of memory, retry later")
+    }
+}
+
+impl std::error::Error for MemoryLimitError {}
+
+impl From<MemoryLimitError> for Status {
+    fn from(e: MemoryLimitError) -> Self {
+        Status::resource_exhausted(e.to_string())
+    }
+}
+
+/// MemoryWatchdog periodically reads the resident set size of the server and
+/// estimates the sizes of the major in-memory structures, exporting and
+/// logging them. With a limit, uploads are rejected from when the RSS
+/// approaches the limit until it dropped well below it again, rather than
+/// letting the server be killed for running out of memory.
+#[derive(Default)]
+pub struct MemoryWatchdog {
+    limit: u64,
+    structures: Vec<(&'static str, Arc<dyn MemoryUsage>)>,
+    shedding: AtomicBool,
+}
+
+impl std::fmt::Debug for MemoryWatchdog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryWatchdog")
+            .field("limit", &self.limit)
+            .field("shedding", &self.shedding)
+            .finish()
+    }
+}
+
+impl MemoryWatchdog {
+    /// Creates a watchdog shedding uploads when the RSS approaches `limit`
+    /// bytes. Zero disables shedding.
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    /// Reports the size of `structure` as `name`.
+    pub fn with_structure(mut self, name: &'static str, structure: Arc<dyn MemoryUsage>) -> Self {
+        self.structures.push((name, structure));
+        self
+    }
+
+    /// Fails while uploads are shed.
+    pub fn check(&self) -> Result<(), MemoryLimitError> {
+        if self.shedding.load(Ordering::Relaxed) {
+            SHED_REQUESTS.inc();
+            return Err(MemoryLimitError);
+        }
+        Ok(())
+    }
+
+    /// Reads the memory usage every `interval`.
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let rss = rss_bytes();
+            if rss.is_none() && self.limit > 0 {
+                log::warn!("Can't read the RSS of the server, the memory limit isn't enforced");
+            }
+            self.update(rss);
+        }
+    }
+
+    fn update(&self, rss: Option<u64>) {
+        let mut breakdown = vec![];
+        for (name, structure) in self.structures.iter() {
+            let bytes = structure.memory_usage();
+            STRUCTURE_BYTES.with_label_values(&[name]).set(bytes as i64);
+            breakdown.push(format!("{} {}", name, format_bytes(bytes)));
+        }
+        let Some(rss) = rss else {
+            log::info!("Memory usage: {}", breakdown.join(", "));
+            return;
+        };
+        RSS_BYTES.set(rss as i64);
+        log::info!(
+            "Memory usage: rss {}, {}",
+            format_bytes(rss),
+            breakdown.join(", ")
+        );
+        if self.limit == 0 {
+            return;
+        }
+
+        let was_shedding = self.shedding.load(Ordering::Relaxed);
+        let shedding = if was_shedding {
+            rss as f64 > self.limit as f64 * RECOVER_RATIO
+        } else {
+            rss as f64 > self.limit as f64 * SHED_RATIO
+        };
+        if shedding != was_shedding {
+            if shedding {
+                log::warn!(
+                    "RSS {} approaches the memory limit of {}, rejecting uploads",
+                    format_bytes(rss),
+                    format_bytes(self.limit)
+                );
+            } else {
+                log::info!(
+                    "RSS {} is below the memory limit again, accepting uploads",
+                    format_bytes(rss)
+                );
+            }
+        }
+        self.shedding.store(shedding, Ordering::Relaxed);
+        SHEDDING.set(shedding as i64);
+    }
+}
+
+/// Returns the resident set size of the process on Linux.
+fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    parse_rss(&status)
+}
+
+fn parse_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line
+        .trim_start_matches("VmRSS:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed(u64);
+
+    impl MemoryUsage for Fixed {
+        fn memory_usage(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_parse_rss() {
+        let status = "Name:\tevprofiler\nVmPeak:\t  20000 kB\nVmRSS:\t   1024 kB\n";
+        assert_eq!(parse_rss(status), Some(1 << 20));
+        assert_eq!(parse_rss("Name:\tevprofiler\n"), None);
+        assert!(rss_bytes().is_none_or(|rss| rss > 0));
+    }
+
+    #[test]
+    fn test_memory_watchdog() {
+        let watchdog = MemoryWatchdog::new(1000).with_structure("fixed", Arc::new(Fixed(10)));
+        watchdog.update(Some(850));
+        assert!(watchdog.check().is_ok());
+        watchdog.update(Some(950));
+        assert_eq!(
+            Status::from(watchdog.check().unwrap_err()).code(),
+            tonic::Code::ResourceExhausted
+        );
+        watchdog.update(Some(850));
+        assert!(watchdog.check().is_err());
+        watchdog.update(Some(750));
+        assert!(watchdog.check().is_ok());
+
+        let unlimited = MemoryWatchdog::new(0);
+        unlimited.update(Some(u64::MAX));
+        assert!(unlimited.check().is_ok());
+        assert_eq!(format_bytes(1536), "1.5KiB");
+    }
+}
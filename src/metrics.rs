@@ -0,0 +1,269 @@
+use prometheus::core::Collector;
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::{Encoder, Histogram, TextEncoder};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{LazyLock, Mutex};
+
+/// Content type of the OpenMetrics text format.
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Encodes all metrics registered in the default registry using the
+/// Prometheus text exposition format.
+pub fn encode() -> anyhow::Result<String> {
+    let mut buf = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Exemplar links an observation of a histogram bucket to the trace it was
+/// made in.
+#[derive(Debug, Clone, PartialEq)]
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    timestamp: f64,
+}
+
+/// Key of the bucket an exemplar belongs to: the metric name, its labels and
+/// the upper bound of the bucket.
+type BucketKey = (String, String, u64);
+
+/// Latest exemplar of every histogram bucket. The prometheus crate doesn't
+/// keep exemplars, so they're added when encoding OpenMetrics.
+static EXEMPLARS: LazyLock<Mutex<HashMap<BucketKey, Exemplar>>> = LazyLock::new(Mutex::default);
+
+/// Observes `value` in `histogram` and, with a trace ID, keeps it as the
+/// exemplar of the bucket the value falls into.
+pub fn observe_with_exemplar(histogram: &Histogram, value: f64, trace_id: Option<&str>) {
+    histogram.observe(value);
+    let Some(trace_id) = trace_id else {
+        return;
+    };
+    let exemplar = Exemplar {
+        trace_id: trace_id.to_string(),
+        value,
+        timestamp: chrono::Utc::now().timestamp_millis() as f64 / 1000.0,
+    };
+    for family in histogram.collect() {
+        for metric in family.get_metric() {
+            let upper_bound = metric
+                .get_histogram()
+                .get_bucket()
+                .iter()
+                .map(|b| b.get_upper_bound())
+                .find(|bound| value <= *bound)
+                .unwrap_or(f64::INFINITY);
+            let key = (
+                family.get_name().to_string(),
+                labels(metric.get_label(), None),
+                upper_bound.to_bits(),
+            );
+            EXEMPLARS.lock().unwrap().insert(key, exemplar.clone());
+        }
+    }
+}
+
+/// Encodes all metrics registered in the default registry using the
+/// OpenMetrics text format, which carries the exemplars of histogram buckets.
+pub fn encode_openmetrics() -> anyhow::Result<String> {
+    let exemplars = EXEMPLARS.lock().unwrap().clone();
+    let mut out = String::new();
+    for family in prometheus::gather() {
+        write_family(&mut out, &family, &exemplars)?;
+    }
+    out.push_str("# EOF\n");
+    Ok(out)
+}
+
+fn write_family(
+    out: &mut String,
+    family: &MetricFamily,
+    exemplars: &HashMap<BucketKey, Exemplar>,
+) -> std::fmt::Result {
+    // Untyped metrics are only produced by custom collectors, none of which
+    // are registered.
+    if family.get_field_type() == MetricType::UNTYPED {
+        return Ok(());
+    }
+    let name = family.get_name();
+    // OpenMetrics names counter families without the suffix of their samples.
+    let (family_name, kind) = match family.get_field_type() {
+        MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+        MetricType::GAUGE => (name, "gauge"),
+        MetricType::HISTOGRAM => (name, "histogram"),
+        MetricType::SUMMARY => (name, "summary"),
+        MetricType::UNTYPED => (name, "unknown"),
+    };
+    writeln!(out, "# TYPE {} {}", family_name, kind)?;
+    writeln!(out, "# HELP {} {}", family_name, escape(family.get_help()))?;
+
+    for metric in family.get_metric() {
+        let pairs = metric.get_label();
+        match family.get_field_type() {
+            MetricType::COUNTER => writeln!(
+                out,
+                "{}_total{} {}",
+                family_name,
+                labels(pairs, None),
+                float(metric.get_counter().get_value())
+            )?,
+            MetricType::GAUGE => writeln!(
+                out,
+                "{}{} {}",
+                name,
+                labels(pairs, None),
+                float(metric.get_gauge().get_value())
+            )?,
+            MetricType::HISTOGRAM => write_histogram(out, name, metric, exemplars)?,
+            MetricType::UNTYPED => {}
+            MetricType::SUMMARY => {
+                let summary = metric.get_summary();
+                for q in summary.get_quantile() {
+                    let quantile = float(q.get_quantile());
+                    writeln!(
+                        out,
+                        "{}{} {}",
+                        name,
+                        labels(pairs, Some(("quantile", &quantile))),
+                        float(q.get_value())
+                    )?;
+                }
+                writeln!(
+                    out,
+                    "{}_sum{} {}",
+                    name,
+                    labels(pairs, None),
+                    float(summary.get_sample_sum())
+                )?;
+                writeln!(
+                    out,
+                    "{}_count{} {}",
+                    name,
+                    labels(pairs, None),
+                    summary.get_sample_count()
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_histogram(
+    out: &mut String,
+    name: &str,
+    metric: &Metric,
+    exemplars: &HashMap<BucketKey, Exemplar>,
+) -> std::fmt::Result {
+    let pairs = metric.get_label();
+    let histogram = metric.get_histogram();
+    let key = labels(pairs, None);
+    let mut buckets: Vec<(f64, u64)> = histogram
+        .get_bucket()
+        .iter()
+        .map(|b| (b.get_upper_bound(), b.get_cumulative_count()))
+        .collect();
+    if buckets.last().is_none_or(|(bound, _)| bound.is_finite()) {
+        buckets.push((f64::INFINITY, histogram.get_sample_count()));
+    }
+
+    for (bound, count) in buckets {
+        let le = float(bound);
+        write!(
+            out,
+            "{}_bucket{} {}",
+            name,
+            labels(pairs, Some(("le", &le))),
+            count
+        )?;
+        let exemplar = exemplars.get(&(name.to_string(), key.clone(), bound.to_bits()));
+        if let Some(e) = exemplar {
+            write!(
+                out,
+                " # {{trace_id=\"{}\"}} {} {}",
+                escape(&e.trace_id),
+                float(e.value),
+                float(e.timestamp)
+            )?;
+        }
+        out.push('\n');
+    }
+    writeln!(
+        out,
+        "{}_sum{} {}",
+        name,
+        key,
+        float(histogram.get_sample_sum())
+    )?;
+    writeln!(
+        out,
+        "{}_count{} {}",
+        name,
+        key,
+        histogram.get_sample_count()
+    )
+}
+
+/// Formats the label pairs, with an extra one, as `{name="value",...}`.
+fn labels(pairs: &[LabelPair], extra: Option<(&str, &str)>) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|p| (p.get_name(), p.get_value()))
+        .chain(extra)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if pairs.is_empty() {
+        return String::new();
+    }
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+fn float(v: f64) -> String {
+    match v {
+        f64::INFINITY => "+Inf".into(),
+        f64::NEG_INFINITY => "-Inf".into(),
+        v if v.is_nan() => "NaN".into(),
+        v => v.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{histogram_opts, Counter};
+
+    #[test]
+    fn test_encode_openmetrics() {
+        let histogram = Histogram::with_opts(
+            histogram_opts!("test_latency_seconds", "Test \"latency\".", vec![0.1, 1.0])
+                .const_label("stage", "decode"),
+        )
+        .unwrap();
+        let counter = Counter::new("test_requests_total", "Test requests.").unwrap();
+        prometheus::register(Box::new(histogram.clone())).unwrap();
+        prometheus::register(Box::new(counter.clone())).unwrap();
+
+        observe_with_exemplar(&histogram, 0.5, Some("4bf92f3577b34da6a3ce929d0e0e4736"));
+        observe_with_exemplar(&histogram, 2.0, None);
+        counter.inc();
+
+        let out = encode_openmetrics().unwrap();
+        assert!(out.ends_with("# EOF\n"));
+        assert!(out.contains("# HELP test_latency_seconds Test \\\"latency\\\".\n"));
+        assert!(out.contains("test_latency_seconds_bucket{stage=\"decode\",le=\"0.1\"} 0\n"));
+        assert!(out.contains(
+            "test_latency_seconds_bucket{stage=\"decode\",le=\"1\"} 1 # {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"} 0.5 "
+        ));
+        assert!(out.contains("test_latency_seconds_bucket{stage=\"decode\",le=\"+Inf\"} 2\n"));
+        assert!(out.contains("test_latency_seconds_count{stage=\"decode\"} 2\n"));
+        assert!(out.contains("# TYPE test_requests counter\n"));
+        assert!(out.contains("\ntest_requests_total 1\n"));
+    }
+}
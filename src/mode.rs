@@ -0,0 +1,59 @@
+use tonic::Status;
+
+/// Mode the server runs in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Mode {
+    /// Accepts writes and uploads, and runs the background jobs.
+    #[default]
+    ReadWrite,
+    /// Serves queries from the shared storage, refusing writes and uploads.
+    /// Background jobs that write to storage don't run.
+    ReadOnly,
+}
+
+impl Mode {
+    pub fn is_read_only(self) -> bool {
+        self == Mode::ReadOnly
+    }
+
+    /// Fails when writes aren't accepted.
+    pub fn check_writable(self) -> Result<(), ReadOnlyError> {
+        if self.is_read_only() {
+            return Err(ReadOnlyError);
+        }
+        Ok(())
+    }
+}
+
+/// ReadOnlyError is returned for writes to a read-only server, as
+/// FailedPrecondition by the RPCs.
+#[derive(Debug)]
+pub struct ReadOnlyError;
+
+impl std::fmt::Display for ReadOnlyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the server is read-only and doesn't accept writes")
+    }
+}
+
+impl std::error::Error for ReadOnlyError {}
+
+impl From<ReadOnlyError> for Status {
+    fn from(e: ReadOnlyError) -> Self {
+        Status::failed_precondition(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_writable() {
+        assert!(Mode::ReadWrite.check_writable().is_ok());
+        assert_eq!(
+            Status::from(Mode::ReadOnly.check_writable().unwrap_err()).code(),
+            tonic::Code::FailedPrecondition
+        );
+    }
+}
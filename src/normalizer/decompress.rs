@@ -0,0 +1,175 @@
+use anyhow::Context;
+use flate2::read::GzDecoder;
+use std::io::Read;
+
+/// DecompressionLimits bounds how large a gzipped profile may get when it is
+/// decompressed, so that a small payload can't expand into gigabytes. Zero
+/// disables a limit.
+#[derive(Debug, Clone, Copy)]
+pub struct DecompressionLimits {
+    /// Maximum decompressed size in bytes.
+    pub max_size: u64,
+    /// Maximum ratio of decompressed to compressed size.
+    pub max_ratio: u64,
+}
+
+impl Default for DecompressionLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 512 << 20,
+            max_ratio: 200,
+        }
+    }
+}
+
+/// DecompressionLimitError is returned when a profile exceeds the
+/// decompression limits.
+#[derive(Debug)]
+pub struct DecompressionLimitError {
+    pub compressed_size: u64,
+    pub limit: u64,
+}
+
+impl std::fmt::Display for DecompressionLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "profile of {} compressed bytes decompresses to more than {} bytes",
+            self.compressed_size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for DecompressionLimitError {}
+
+impl DecompressionLimits {
+    /// Returns the maximum decompressed size of a payload of `compressed_size`
+    /// bytes, if any.
+    fn limit(&self, compressed_size: u64) -> Option<u64> {
+        let by_ratio = compressed_size.saturating_mul(self.max_ratio);
+        [self.max_size, by_ratio]
+            .into_iter()
+            .filter(|l| *l > 0)
+            .min()
+    }
+
+    /// Returns a reader of the decompressed gzipped data, failing with a
+    /// DecompressionLimitError as soon as the output exceeds the limits.
+    /// Data that isn't gzipped is read as is.
+    pub fn reader<'a>(&self, data: &'a [u8]) -> Box<dyn Read + 'a> {
+        let decoder = GzDecoder::new(data);
+        if decoder.header().is_none() {
+            return Box::new(data);
+        }
+        Box::new(LimitedReader {
+            inner: decoder,
+            read: 0,
+            compressed_size: data.len() as u64,
+            limit: self.limit(data.len() as u64),
+        })
+    }
+
+    /// Decompresses the gzipped data, aborting as soon as the output exceeds
+    /// the limits. Data that isn't gzipped decompresses to nothing.
+    pub fn decompress(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        let decoder = GzDecoder::new(data);
+        if decoder.header().is_none() {
+            return Ok(decompressed);
+        }
+
+        let compressed_size = data.len() as u64;
+        match self.limit(compressed_size) {
+            Some(limit) => {
+                // Read one byte past the limit to tell whether it was exceeded.
+                decoder
+                    .take(limit.saturating_add(1))
+                    .read_to_end(&mut decompressed)
+                    .context("Failed to decompress gzip")?;
+                if decompressed.len() as u64 > limit {
+                    return Err(DecompressionLimitError {
+                        compressed_size,
+                        limit,
+                    }
+                    .into());
+                }
+            }
+            None => {
+                let mut decoder = decoder;
+                decoder
+                    .read_to_end(&mut decompressed)
+                    .context("Failed to decompress gzip")?;
+            }
+        }
+        Ok(decompressed)
+    }
+}
+
+/// LimitedReader fails once more than `limit` bytes were read.
+struct LimitedReader<R> {
+    inner: R,
+    read: u64,
+    compressed_size: u64,
+    limit: Option<u64>,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        match self.limit {
+            Some(limit) if self.read > limit => {
+                Err(std::io::Error::other(DecompressionLimitError {
+                    compressed_size: self.compressed_size,
+                    limit,
+                }))
+            }
+            _ => Ok(n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    #[test]
+    fn test_decompress() {
+        let mut encoder = GzEncoder::new(vec![], Compression::best());
+        encoder.write_all(&vec![0; 1 << 20]).unwrap();
+        let data = encoder.finish().unwrap();
+
+        let unlimited = DecompressionLimits {
+            max_size: 0,
+            max_ratio: 0,
+        };
+        assert_eq!(unlimited.decompress(&data).unwrap().len(), 1 << 20);
+
+        let by_size = DecompressionLimits {
+            max_size: 1 << 19,
+            max_ratio: 0,
+        };
+        let err = by_size.decompress(&data).unwrap_err();
+        assert!(err.downcast_ref::<DecompressionLimitError>().is_some());
+
+        // A megabyte of zeros compresses by far more than 200x.
+        assert!(DecompressionLimits::default().decompress(&data).is_err());
+        assert!(DecompressionLimits::default()
+            .decompress(b"not gzip")
+            .unwrap()
+            .is_empty());
+
+        let read = |limits: DecompressionLimits, data: &[u8]| {
+            let mut decompressed = vec![];
+            limits.reader(data).read_to_end(&mut decompressed)?;
+            std::io::Result::Ok(decompressed)
+        };
+        assert_eq!(read(unlimited, &data).unwrap().len(), 1 << 20);
+        let err = read(by_size, &data).unwrap_err();
+        let err = err.into_inner().unwrap();
+        assert!(err.downcast_ref::<DecompressionLimitError>().is_some());
+        assert_eq!(read(by_size, b"not gzip").unwrap(), b"not gzip");
+    }
+}
@@ -0,0 +1,222 @@
+use super::utils::{labels_from_sample, NANOS_PER_MILLI};
+use super::{NormalizedProfile, NormalizedSample};
+use crate::pprofpb::{Location, Mapping, Profile};
+use crate::profile::{Meta, PprofLocations, ValueType};
+use anyhow::bail;
+use std::collections::HashMap;
+
+/// Returns whether the profile is a locations-only payload, which agents send
+/// to save the cost of naming frames: samples have a single value and no
+/// sample type, and locations carry addresses and mappings but no lines. The
+/// string table holds nothing but the build IDs of the mappings and may be
+/// left out entirely. Frames are named by symbolization on the server.
+pub fn is_locations_only(p: &Profile) -> bool {
+    p.sample_type.is_empty()
+        && p.function.is_empty()
+        && p.location.iter().all(|l| l.line.is_empty())
+        && !p.sample.is_empty()
+}
+
+/// Validates the IDs and string table indices of a locations-only profile,
+/// whose string table may be empty.
+pub fn validate(p: &Profile) -> anyhow::Result<()> {
+    let string = |i: i64, what: &str| {
+        if i < 0 || (i > 0 && i as usize >= p.string_table.len()) {
+            bail!("{} index {} out of bounds", what, i);
+        }
+        Ok(())
+    };
+    if p.string_table.first().is_some_and(|s| !s.is_empty()) {
+        bail!("first string table element is expected to be empty");
+    }
+    for (i, mapping) in p.mapping.iter().enumerate() {
+        if mapping.id != (i + 1) as u64 {
+            bail!("mapping id is not sequential");
+        }
+        string(mapping.build_id, "mapping build_id")?;
+        string(mapping.filename, "mapping filename")?;
+    }
+    for (i, location) in p.location.iter().enumerate() {
+        if location.id != (i + 1) as u64 {
+            bail!("location id is not sequential");
+        }
+        if location.mapping_id > p.mapping.len() as u64 {
+            bail!("location mapping_id index out of bounds");
+        }
+    }
+    for (i, sample) in p.sample.iter().enumerate() {
+        if sample.value.len() != 1 {
+            bail!(
+                "sample {} of a locations-only profile has {} values, expected 1",
+                i,
+                sample.value.len()
+            );
+        }
+        if let Some(id) = sample
+            .location_id
+            .iter()
+            .find(|id| **id == 0 || **id > p.location.len() as u64)
+        {
+            bail!("sample {} has location_id {} out of bounds", i, id);
+        }
+        for label in sample.label.iter() {
+            if label.key == 0 {
+                bail!("sample {} has label key 0", i);
+            }
+            string(label.key, "label key")?;
+            string(label.str, "label str")?;
+        }
+    }
+    Ok(())
+}
+
+/// Normalizes a validated locations-only profile. Its samples are counted as
+/// `samples`, since there's no sample type to name them by.
+pub fn normalize(
+    name: &str,
+    delta: Option<bool>,
+    p: &Profile,
+) -> anyhow::Result<NormalizedProfile> {
+    let meta = Meta {
+        name: name.to_string(),
+        timestamp: p.time_nanos / NANOS_PER_MILLI,
+        duration: p.duration_nanos,
+        period: p.period,
+        delta: super::utils::is_delta(delta, p),
+        period_type: ValueType {
+            type_: String::new(),
+            unit: String::new(),
+        },
+        sample_type: ValueType {
+            type_: "samples".into(),
+            unit: "count".into(),
+        },
+    };
+
+    let string = |i: i64| p.string_table.get(i as usize).cloned().unwrap_or_default();
+    let mut samples = Vec::with_capacity(p.sample.len());
+    for sample in p.sample.iter() {
+        if sample.value[0] == 0 {
+            continue;
+        }
+        let locations = sample
+            .location_id
+            .iter()
+            .map(|id| {
+                let location = &p.location[*id as usize - 1];
+                let mapping = match location.mapping_id {
+                    0 => None,
+                    id => Some(&p.mapping[id as usize - 1]),
+                };
+                encode_location(location, mapping, string)
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let (label, num_label) = if sample.label.is_empty() {
+            (HashMap::new(), HashMap::new())
+        } else {
+            labels_from_sample(&HashMap::new(), &p.string_table, &sample.label)
+        };
+        samples.push(NormalizedSample {
+            locations,
+            value: sample.value[0],
+            diff_value: 0,
+            label,
+            num_label,
+        });
+    }
+    Ok(NormalizedProfile::new(samples, meta))
+}
+
+fn encode_location(
+    location: &Location,
+    mapping: Option<&Mapping>,
+    string: impl Fn(i64) -> String,
+) -> anyhow::Result<Vec<u8>> {
+    PprofLocations {
+        address: location.address,
+        number_of_lines: 0,
+        build_id: mapping.map(|m| string(m.build_id)).unwrap_or_default(),
+        file_name: mapping.map(|m| string(m.filename)).unwrap_or_default(),
+        mapping_memory_start: mapping.map_or(0, |m| m.memory_start),
+        mapping_memory_end: mapping.map_or(0, |m| m.memory_limit),
+        mapping_file_offset: mapping.map_or(0, |m| m.file_offset),
+        functions: vec![],
+    }
+    .encode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pprofpb::Sample;
+
+    fn profile(string_table: Vec<String>, build_id: i64) -> Profile {
+        Profile {
+            mapping: vec![Mapping {
+                id: 1,
+                memory_start: 0x1000,
+                memory_limit: 0x2000,
+                build_id,
+                ..Default::default()
+            }],
+            location: vec![
+                Location {
+                    id: 1,
+                    mapping_id: 1,
+                    address: 0x1234,
+                    ..Default::default()
+                },
+                Location {
+                    id: 2,
+                    address: 0x10,
+                    ..Default::default()
+                },
+            ],
+            sample: vec![
+                Sample {
+                    location_id: vec![1, 2],
+                    value: vec![3],
+                    ..Default::default()
+                },
+                Sample {
+                    location_id: vec![2],
+                    value: vec![0],
+                    ..Default::default()
+                },
+            ],
+            string_table,
+            time_nanos: 5 * NANOS_PER_MILLI,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_locations_only() {
+        let p = profile(vec!["".into(), "abcd".into()], 1);
+        assert!(is_locations_only(&p));
+        validate(&p).unwrap();
+        let np = normalize("parca_agent_cpu", None, &p).unwrap();
+        assert_eq!(np.meta.timestamp, 5);
+        assert_eq!(np.meta.sample_type.type_, "samples");
+        assert_eq!(np.samples.len(), 1);
+        assert_eq!(np.samples[0].value, 3);
+        let leaf = PprofLocations::decode(&np.samples[0].locations[0]).unwrap();
+        assert_eq!(leaf.address, 0x1234);
+        assert_eq!(leaf.build_id, "abcd");
+        assert_eq!(leaf.number_of_lines, 0);
+        let root = PprofLocations::decode(&np.samples[0].locations[1]).unwrap();
+        assert_eq!(root.build_id, "");
+
+        // The string table may be left out.
+        let p = profile(vec![], 0);
+        validate(&p).unwrap();
+        assert_eq!(normalize("cpu", None, &p).unwrap().samples.len(), 1);
+
+        assert!(validate(&profile(vec!["".into()], 1)).is_err());
+        let mut p = profile(vec![], 0);
+        p.sample[0].value.push(1);
+        assert!(validate(&p).is_err());
+        p.sample_type.push(Default::default());
+        assert!(!is_locations_only(&p));
+    }
+}
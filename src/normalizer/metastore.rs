@@ -0,0 +1,263 @@
+use crate::memory::MemoryUsage;
+use crate::metapb::{Function, FunctionsResponse};
+use anyhow::Context;
+use object_store::{path::Path, ObjectStore};
+use prost::Message;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+use tokio_stream::StreamExt;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Prefix of the objects of the function table in the profile bucket.
+pub(crate) const FUNCTIONS_PREFIX: &str = "functions";
+
+/// Metastore interns functions referenced by normalized stacktraces. A
+/// function is identified by a stable 64-bit ID derived from its name, system
+/// name and filename, so the same function gets the same ID across profiles
+/// and restarts, and serialized stacktraces only carry the ID.
+///
+/// The functions are persisted as a table next to the segments referencing
+/// them, see [`Metastore::persist`], and loaded back at startup. They're
+/// never evicted, since any stored segment may reference them.
+#[derive(Debug, Default)]
+pub struct Metastore {
+    functions: RwLock<HashMap<String, Function>>,
+    /// IDs of the functions interned since the table was last persisted.
+    pending: Mutex<Vec<String>>,
+    /// Held while persisting, so that segments written after a persist
+    /// returns never reference functions still being written by another.
+    persisting: tokio::sync::Mutex<()>,
+    /// Objects of the table already loaded or written.
+    loaded: Mutex<HashSet<Path>>,
+}
+
+impl Metastore {
+    /// Loads the function table persisted in the storage. With `compact`,
+    /// the objects of the table are merged into one.
+    pub async fn load(storage: &dyn ObjectStore, compact: bool) -> anyhow::Result<Self> {
+        let metastore = Self::default();
+        metastore.refresh(storage).await?;
+        let objects: Vec<Path> = metastore.loaded.lock().unwrap().iter().cloned().collect();
+        log::info!(
+            "Loaded {} functions from {} objects",
+            metastore.functions.read().unwrap().len(),
+            objects.len()
+        );
+
+        if compact && objects.len() > 1 {
+            // The merged table is written before the objects it replaces are
+            // deleted, so that an interrupted compaction only leaves
+            // duplicates.
+            let all: Vec<String> = metastore
+                .functions
+                .read()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect();
+            metastore.write(storage, &all).await?;
+            for object in objects.iter() {
+                match storage.delete(object).await {
+                    Ok(()) | Err(object_store::Error::NotFound { .. }) => {}
+                    Err(e) => return Err(e.into()),
+                }
+                metastore.loaded.lock().unwrap().remove(object);
+            }
+        }
+        Ok(metastore)
+    }
+
+    /// Loads the objects of the function table written since the last load,
+    /// like the ones of the writers a read-only replica reads the segments
+    /// of.
+    pub async fn refresh(&self, storage: &dyn ObjectStore) -> anyhow::Result<()> {
+        let objects: Vec<Path> = storage
+            .list(Some(&Path::from(FUNCTIONS_PREFIX)))
+            .map(|meta| meta.map(|meta| meta.location))
+            .collect::<Result<_, _>>()
+            .await?;
+        for object in objects {
+            if self.loaded.lock().unwrap().contains(&object) {
+                continue;
+            }
+            let data = match storage.get(&object).await {
+                Ok(res) => res.bytes().await?,
+                // Deleted by a compaction since it was listed.
+                Err(object_store::Error::NotFound { .. }) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let table = FunctionsResponse::decode(data)
+                .with_context(|| format!("decoding function table {}", object))?;
+            let mut functions = self.functions.write().unwrap();
+            for function in table.functions {
+                functions.entry(function.id.clone()).or_insert(function);
+            }
+            self.loaded.lock().unwrap().insert(object);
+        }
+        Ok(())
+    }
+
+    /// Interns the function and returns a reference to it, which only holds
+    /// the function ID and the line of the location.
+    pub fn intern(&self, function: &Function) -> Function {
+        let id = function_id(function);
+        let known = self.functions.read().unwrap().contains_key(&id);
+        if !known {
+            let mut functions = self.functions.write().unwrap();
+            if !functions.contains_key(&id) {
+                functions.insert(
+                    id.clone(),
+                    Function {
+                        id: id.clone(),
+                        start_line: 0,
+                        ..function.clone()
+                    },
+                );
+                self.pending.lock().unwrap().push(id.clone());
+            }
+        }
+
+        Function {
+            id,
+            start_line: function.start_line,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the interned function with the given ID.
+    pub fn function(&self, id: &str) -> Option<Function> {
+        self.functions.read().unwrap().get(id).cloned()
+    }
+
+    /// Persists the functions interned since the last call to the storage.
+    /// Segments must only be written once the functions they reference are
+    /// persisted, so writers call it before writing segments.
+    pub async fn persist(&self, storage: &dyn ObjectStore) -> anyhow::Result<()> {
+        let _persisting = self.persisting.lock().await;
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return Ok(());
+        }
+        if let Err(e) = self.write(storage, &pending).await {
+            self.pending.lock().unwrap().extend(pending);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Writes the functions with the IDs as a new object of the table.
+    async fn write(&self, storage: &dyn ObjectStore, ids: &[String]) -> anyhow::Result<()> {
+        let table = {
+            let functions = self.functions.read().unwrap();
+            FunctionsResponse {
+                functions: ids
+                    .iter()
+                    .filter_map(|id| functions.get(id).cloned())
+                    .collect(),
+            }
+        };
+        let path = Path::from(format!("{}/{}.pb", FUNCTIONS_PREFIX, ulid::Ulid::new()));
+        storage
+            .put(&path, table.encode_to_vec().into())
+            .await
+            .with_context(|| format!("writing function table {}", path))?;
+        self.loaded.lock().unwrap().insert(path);
+        Ok(())
+    }
+}
+
+impl MemoryUsage for Metastore {
+    fn memory_usage(&self) -> u64 {
+        self.functions
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| k.len() + v.encoded_len())
+            .sum::<usize>() as u64
+    }
+}
+
+fn function_id(function: &Function) -> String {
+    let mut hash = FNV_OFFSET_BASIS;
+    for field in [&function.name, &function.system_name, &function.filename] {
+        // Hash the length first, so that fields can't run into each other.
+        for byte in (field.len() as u64)
+            .to_le_bytes()
+            .iter()
+            .chain(field.as_bytes())
+        {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let store = Metastore::default();
+        let function = |line| Function {
+            start_line: line,
+            name: "main".into(),
+            system_name: "main".into(),
+            filename: "main.c".into(),
+            ..Default::default()
+        };
+
+        let a = store.intern(&function(10));
+        let b = store.intern(&function(12));
+        assert_eq!(a.id, b.id);
+        assert_eq!(a.id, function_id(&function(0)));
+        assert!(a.name.is_empty() && a.filename.is_empty());
+        assert_eq!(b.start_line, 12);
+
+        let interned = store.function(&a.id).unwrap();
+        assert_eq!(interned.name, "main");
+        assert_eq!(interned.filename, "main.c");
+
+        let other = store.intern(&Function {
+            name: "mainmain.c".into(),
+            ..Default::default()
+        });
+        assert_ne!(a.id, other.id);
+    }
+
+    #[tokio::test]
+    async fn test_persist() {
+        let storage = object_store::memory::InMemory::new();
+        let store = Metastore::default();
+        let function = |name: &str| Function {
+            name: name.into(),
+            filename: "main.c".into(),
+            ..Default::default()
+        };
+        let a = store.intern(&function("a"));
+        store.persist(&storage).await.unwrap();
+        // Only new functions are written again.
+        let b = store.intern(&function("b"));
+        store.intern(&function("a"));
+        store.persist(&storage).await.unwrap();
+        store.persist(&storage).await.unwrap();
+
+        let loaded = Metastore::load(&storage, true).await.unwrap();
+        assert_eq!(loaded.function(&a.id).unwrap().name, "a");
+        assert_eq!(loaded.function(&b.id).unwrap().name, "b");
+        let objects = storage.list(None).collect::<Vec<_>>().await;
+        assert_eq!(objects.len(), 1);
+        let loaded = Metastore::load(&storage, false).await.unwrap();
+        assert_eq!(loaded.function(&b.id).unwrap().filename, "main.c");
+
+        // Functions persisted by other writers are picked up on refresh.
+        let c = store.intern(&function("c"));
+        store.persist(&storage).await.unwrap();
+        assert!(loaded.function(&c.id).is_none());
+        loaded.refresh(&storage).await.unwrap();
+        assert_eq!(loaded.function(&c.id).unwrap().name, "c");
+    }
+}
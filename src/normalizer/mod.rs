@@ -1,13 +1,30 @@
+mod decompress;
+mod locations_only;
+mod metastore;
+mod pipeline;
 mod profile;
 mod sample;
+mod scrub;
 mod series;
+mod stack;
+mod timestamp;
+mod trim;
 mod utils;
-mod write_raw;
+pub(crate) mod write_raw;
 
-use profile::NormalizedProfile;
-pub use sample::NormalizedSample;
+pub use decompress::{DecompressionLimitError, DecompressionLimits};
+pub use metastore::Metastore;
+pub(crate) use metastore::FUNCTIONS_PREFIX;
+pub use pipeline::WritePipeline;
+pub use profile::NormalizedProfile;
+pub use sample::{decode_labels, decode_num_labels, NormalizedSample};
+pub use scrub::{LabelScrubbing, ScrubRule};
 pub use series::Series;
-pub use utils::write_raw_request_to_arrow_chunk;
+pub use stack::StackDepthLimit;
+pub use timestamp::{TimestampAction, TimestampOutOfBoundsError, TimestampPolicy};
+pub use trim::{StackTrimming, TrimRule};
+pub use utils::normalized_request_to_arrow_chunk;
+pub use write_raw::{NormalizedWriteRawRequest, DELTA_LABEL};
 
 pub const POSSIBLE_METADATA_LABELS: [&str; 20] = [
     "pid",
@@ -0,0 +1,103 @@
+use super::{
+    normalized_request_to_arrow_chunk, DecompressionLimits, LabelScrubbing, Metastore,
+    NormalizedWriteRawRequest, StackDepthLimit, StackTrimming, TimestampPolicy,
+};
+use crate::pipeline::{Stage, StageTimes};
+use crate::profilestorepb::WriteRawRequest;
+use arrow2::{array::Array, chunk::Chunk};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// WritePipeline turns written requests into the Arrow chunks that are
+/// stored. Every path profiles are written through, WriteRaw, replays,
+/// backfills and embedded ingestion, runs the same pipeline, so that they
+/// store the same samples for the same request.
+#[derive(Debug, Clone)]
+pub struct WritePipeline {
+    metastore: Arc<Metastore>,
+    decompression: DecompressionLimits,
+    timestamps: TimestampPolicy,
+    stack_depth: StackDepthLimit,
+    trimming: StackTrimming,
+    scrubbing: LabelScrubbing,
+}
+
+impl WritePipeline {
+    /// Interns the functions of written profiles in `metastore`.
+    pub fn new(metastore: Arc<Metastore>) -> Self {
+        Self {
+            metastore,
+            decompression: DecompressionLimits::default(),
+            timestamps: TimestampPolicy::default(),
+            stack_depth: StackDepthLimit::default(),
+            trimming: StackTrimming::default(),
+            scrubbing: LabelScrubbing::default(),
+        }
+    }
+
+    /// Bounds the size written profiles may decompress to with `limits`.
+    pub fn with_decompression_limits(mut self, limits: DecompressionLimits) -> Self {
+        self.decompression = limits;
+        self
+    }
+
+    /// Bounds the timestamps of written profiles with `policy`.
+    pub fn with_timestamp_policy(mut self, policy: TimestampPolicy) -> Self {
+        self.timestamps = policy;
+        self
+    }
+
+    /// Truncates the stacks of written profiles to `limit`.
+    pub fn with_stack_depth_limit(mut self, limit: StackDepthLimit) -> Self {
+        self.stack_depth = limit;
+        self
+    }
+
+    /// Drops or collapses the noisy leaf frames of written profiles with
+    /// `trimming`.
+    pub fn with_stack_trimming(mut self, trimming: StackTrimming) -> Self {
+        self.trimming = trimming;
+        self
+    }
+
+    /// Scrubs the labels of written profiles with `scrubbing`.
+    pub fn with_label_scrubbing(mut self, scrubbing: LabelScrubbing) -> Self {
+        self.scrubbing = scrubbing;
+        self
+    }
+
+    pub fn decompression(&self) -> &DecompressionLimits {
+        &self.decompression
+    }
+
+    pub fn scrubbing(&self) -> &LabelScrubbing {
+        &self.scrubbing
+    }
+
+    /// Normalizes the request, applies the timestamp policy, the stack depth
+    /// limit, the stack trimming and the label scrubbing, in that order, and
+    /// converts the result to an Arrow chunk. The time spent is accounted to
+    /// `times`. Fails with a DecompressionLimitError or a
+    /// TimestampOutOfBoundsError if the request is rejected.
+    pub async fn run(
+        &self,
+        request: &WriteRawRequest,
+        times: &mut StageTimes,
+    ) -> anyhow::Result<(NormalizedWriteRawRequest, Chunk<Arc<dyn Array>>)> {
+        let mut normalized = NormalizedWriteRawRequest::try_new_timed(
+            request,
+            &self.metastore,
+            &self.decompression,
+            times,
+        )?;
+        let started = Instant::now();
+        self.timestamps
+            .apply(&mut normalized, chrono::Utc::now().timestamp_millis())?;
+        self.stack_depth.apply(&mut normalized, &self.metastore)?;
+        self.trimming.apply(&mut normalized, &self.metastore)?;
+        self.scrubbing.apply(&mut normalized);
+        let chunk = normalized_request_to_arrow_chunk(&normalized).await;
+        times.add(Stage::Normalize, started.elapsed());
+        Ok((normalized, chunk?))
+    }
+}
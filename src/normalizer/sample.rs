@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use serde::{Deserialize, Serialize};
 
@@ -10,3 +10,133 @@ pub struct NormalizedSample {
     pub(crate) label: HashMap<String, String>,
     pub(crate) num_label: HashMap<String, i64>,
 }
+
+impl NormalizedSample {
+    /// Encodes the pprof labels of the sample as comma separated
+    /// `"key"="value"` pairs sorted by key, or None if it has none.
+    pub fn encoded_labels(&self) -> Option<String> {
+        encode(self.label.iter().map(|(k, v)| (k, quote(v))))
+    }
+
+    /// Encodes the numeric pprof labels of the sample as comma separated
+    /// `"key"=value` pairs sorted by key, or None if it has none.
+    pub fn encoded_num_labels(&self) -> Option<String> {
+        encode(self.num_label.iter().map(|(k, v)| (k, v.to_string())))
+    }
+}
+
+fn encode<'a>(pairs: impl Iterator<Item = (&'a String, String)>) -> Option<String> {
+    let sorted: BTreeMap<&String, String> = pairs.collect();
+    if sorted.is_empty() {
+        return None;
+    }
+    let pairs: Vec<String> = sorted
+        .into_iter()
+        .map(|(k, v)| format!("{}={}", quote(k), v))
+        .collect();
+    Some(pairs.join(","))
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Decodes pprof labels encoded by [`NormalizedSample::encoded_labels`], or
+/// returns None if they're malformed.
+pub fn decode_labels(encoded: &str) -> Option<Vec<(String, String)>> {
+    let mut chars = encoded.chars();
+    let mut labels = vec![];
+    loop {
+        let key = unquote(&mut chars)?;
+        if chars.next() != Some('=') {
+            return None;
+        }
+        labels.push((key, unquote(&mut chars)?));
+        match chars.next() {
+            Some(',') => continue,
+            Some(_) => return None,
+            None => return Some(labels),
+        }
+    }
+}
+
+/// Decodes numeric pprof labels encoded by
+/// [`NormalizedSample::encoded_num_labels`], or returns None if they're
+/// malformed.
+pub fn decode_num_labels(encoded: &str) -> Option<Vec<(String, i64)>> {
+    let mut chars = encoded.chars();
+    let mut labels = vec![];
+    loop {
+        let key = unquote(&mut chars)?;
+        if chars.next() != Some('=') {
+            return None;
+        }
+        let value: String = chars.by_ref().take_while(|c| *c != ',').collect();
+        labels.push((key, value.parse().ok()?));
+        if chars.as_str().is_empty() {
+            return Some(labels);
+        }
+    }
+}
+
+fn unquote(chars: &mut std::str::Chars) -> Option<String> {
+    if chars.next() != Some('"') {
+        return None;
+    }
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '\\' => s.push(chars.next()?),
+            '"' => return Some(s),
+            c => s.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_labels() {
+        let sample = NormalizedSample {
+            locations: vec![],
+            value: 1,
+            diff_value: 0,
+            label: HashMap::from([
+                ("trace_id".to_string(), "ab\"c\\,=".to_string()),
+                ("goroutine".to_string(), "12".to_string()),
+            ]),
+            num_label: HashMap::from([("bytes".to_string(), -4096)]),
+        };
+
+        assert_eq!(
+            sample.encoded_labels().unwrap(),
+            r#""goroutine"="12","trace_id"="ab\"c\\,=""#
+        );
+        assert_eq!(sample.encoded_num_labels().unwrap(), r#""bytes"=-4096"#);
+        let mut decoded = decode_labels(&sample.encoded_labels().unwrap()).unwrap();
+        decoded.sort();
+        assert_eq!(
+            decoded,
+            vec![
+                ("goroutine".to_string(), "12".to_string()),
+                ("trace_id".to_string(), "ab\"c\\,=".to_string()),
+            ]
+        );
+        assert_eq!(decode_labels(r#""a"="b"x"#), None);
+        assert_eq!(
+            decode_num_labels(r#""bytes"=-4096,"pid"=42"#),
+            Some(vec![("bytes".to_string(), -4096), ("pid".to_string(), 42)])
+        );
+        assert_eq!(decode_num_labels(r#""pid"=4x"#), None);
+
+        let unlabeled = NormalizedSample {
+            label: HashMap::new(),
+            num_label: HashMap::new(),
+            ..sample
+        };
+        assert_eq!(unlabeled.encoded_labels(), None);
+        assert_eq!(unlabeled.encoded_num_labels(), None);
+    }
+}
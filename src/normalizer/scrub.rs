@@ -0,0 +1,426 @@
+use super::{DecompressionLimits, NormalizedWriteRawRequest};
+use crate::pprofpb::Profile;
+use crate::profilestorepb::WriteRawRequest;
+use anyhow::{bail, Context};
+use flate2::{write::GzEncoder, Compression};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use prost::Message;
+use ring::hmac;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+static SCRUBBED_LABELS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_scrubbed_labels_total",
+        "Total number of label values of written profiles that were scrubbed, by label and action.",
+        &["label", "action"]
+    )
+    .unwrap()
+});
+
+/// Number of hex digits of the HMAC-SHA256 tag hashed values are replaced by.
+const HASH_LENGTH: usize = 16;
+
+/// What is done with the values of a scrubbed label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrubAction {
+    /// Removes the label.
+    Drop,
+    /// Replaces the value by a prefix of its HMAC-SHA256 tag under the
+    /// scrubbing's hash key, which keeps equal values grouped without letting
+    /// short values like PIDs be guessed from it. The label is dropped if no
+    /// key is set.
+    Hash,
+    /// Keeps the first characters of the value.
+    Truncate(usize),
+}
+
+impl ScrubAction {
+    fn name(&self) -> &'static str {
+        match self {
+            ScrubAction::Drop => "drop",
+            ScrubAction::Hash => "hash",
+            ScrubAction::Truncate(_) => "truncate",
+        }
+    }
+}
+
+/// ScrubRule scrubs the values of a label, given as `<label>=drop`,
+/// `<label>=hash` or `<label>=truncate:<length>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrubRule {
+    pub label: String,
+    pub action: ScrubAction,
+}
+
+impl FromStr for ScrubRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let Some((label, action)) = s.split_once('=') else {
+            bail!("invalid scrub rule {:?}, expected <label>=<action>", s);
+        };
+        let action = match action.split_once(':') {
+            None if action == "drop" => ScrubAction::Drop,
+            None if action == "hash" => ScrubAction::Hash,
+            Some(("truncate", length)) => ScrubAction::Truncate(
+                length
+                    .parse()
+                    .with_context(|| format!("invalid truncate length {:?}", length))?,
+            ),
+            _ => bail!(
+                "invalid scrub action {:?}, expected drop, hash or truncate:<length>",
+                action
+            ),
+        };
+        Ok(Self {
+            label: label.trim().to_string(),
+            action,
+        })
+    }
+}
+
+/// LabelScrubbing drops, hashes or truncates the values of labels of written
+/// profiles before they're indexed or stored, for environments where labels
+/// like `pid` or `cmdline` must not be kept. Both series labels and pprof
+/// sample labels are scrubbed; numeric sample labels can't hold a hash or a
+/// truncated value and are dropped by any rule.
+#[derive(Debug, Clone, Default)]
+pub struct LabelScrubbing {
+    rules: HashMap<String, ScrubAction>,
+    hash_key: Option<hmac::Key>,
+}
+
+impl LabelScrubbing {
+    pub fn new(rules: Vec<ScrubRule>) -> Self {
+        Self {
+            rules: rules.into_iter().map(|r| (r.label, r.action)).collect(),
+            hash_key: None,
+        }
+    }
+
+    /// Hashes values with `key`, a secret, so that they can't be guessed by
+    /// hashing candidate values.
+    pub fn with_hash_key(mut self, key: &[u8]) -> Self {
+        self.hash_key = Some(hmac::Key::new(hmac::HMAC_SHA256, key));
+        self
+    }
+
+    /// Returns whether values are hashed, which needs a hash key.
+    pub fn hashes(&self) -> bool {
+        self.rules.values().any(|a| *a == ScrubAction::Hash)
+    }
+
+    /// Hashes values with the key read from `path`. Fails if values are
+    /// hashed and no key is given.
+    pub fn with_hash_key_file(self, path: Option<&std::path::Path>) -> anyhow::Result<Self> {
+        let Some(path) = path else {
+            if self.hashes() {
+                bail!("hashing label values requires a hash key");
+            }
+            return Ok(self);
+        };
+        let key = std::fs::read(path)
+            .with_context(|| format!("reading label hash key {}", path.display()))?;
+        let key = key.trim_ascii();
+        if key.is_empty() {
+            bail!("label hash key {} is empty", path.display());
+        }
+        Ok(self.with_hash_key(key))
+    }
+
+    /// Scrubs the labels of the request.
+    pub fn apply(&self, request: &mut NormalizedWriteRawRequest) {
+        if self.rules.is_empty() {
+            return;
+        }
+        for series in request.series.iter_mut() {
+            self.scrub(&mut series.labels);
+            for profile in series.samples.iter_mut().flatten() {
+                for sample in profile.samples.iter_mut() {
+                    self.scrub(&mut sample.label);
+                    sample.num_label.retain(|name, _| {
+                        let Some(action) = self.rules.get(name) else {
+                            return true;
+                        };
+                        SCRUBBED_LABELS
+                            .with_label_values(&[name, action.name()])
+                            .inc();
+                        false
+                    });
+                }
+            }
+        }
+        request.all_label_names.retain(|name| {
+            self.rules.get(name) != Some(&ScrubAction::Drop)
+                || request.series.iter().any(|s| s.labels.contains_key(name))
+        });
+    }
+
+    /// Scrubs the labels of a request as it was received, for the copies of
+    /// it that are kept or published before it's normalized. Series labels
+    /// are scrubbed, and the sample labels of its profiles, which are
+    /// decoded and gzipped again if any of them is scrubbed.
+    pub fn apply_raw(
+        &self,
+        request: &mut WriteRawRequest,
+        limits: &DecompressionLimits,
+    ) -> anyhow::Result<()> {
+        if self.rules.is_empty() {
+            return Ok(());
+        }
+        for series in request.series.iter_mut() {
+            if let Some(labels) = &mut series.labels {
+                labels
+                    .labels
+                    .retain_mut(|l| self.scrub_value(&l.name, &mut l.value));
+            }
+            for sample in series.samples.iter_mut() {
+                let data = limits.decompress(&sample.raw_profile)?;
+                if data.is_empty() {
+                    continue;
+                }
+                let mut profile = Profile::decode(data.as_slice())?;
+                if self.scrub_profile(&mut profile) {
+                    let mut encoder = GzEncoder::new(vec![], Compression::default());
+                    encoder.write_all(&profile.encode_to_vec())?;
+                    sample.raw_profile = encoder.finish()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Scrubs the sample labels of the profile, adding scrubbed values to its
+    /// string table. Returns whether any label was scrubbed.
+    fn scrub_profile(&self, profile: &mut Profile) -> bool {
+        let mut scrubbed = false;
+        let Profile {
+            sample,
+            string_table,
+            ..
+        } = profile;
+        for sample in sample.iter_mut() {
+            sample.label.retain_mut(|label| {
+                let Some(name) = string_table.get(label.key as usize).cloned() else {
+                    return true;
+                };
+                if !self.rules.contains_key(&name) {
+                    return true;
+                }
+                scrubbed = true;
+                let mut value = match string_table.get(label.str as usize) {
+                    Some(value) if label.str != 0 => value.clone(),
+                    // Numeric labels are dropped by any rule.
+                    _ => return false,
+                };
+                if !self.scrub_value(&name, &mut value) {
+                    return false;
+                }
+                label.str = string_table.len() as i64;
+                label.num = 0;
+                string_table.push(value);
+                true
+            });
+        }
+        scrubbed
+    }
+
+    fn scrub(&self, labels: &mut HashMap<String, String>) {
+        labels.retain(|name, value| {
+            if let Some(action) = self.rules.get(name) {
+                SCRUBBED_LABELS
+                    .with_label_values(&[name, action.name()])
+                    .inc();
+            }
+            self.scrub_value(name, value)
+        });
+    }
+
+    /// Scrubs the value of the label, returning whether the label is kept.
+    fn scrub_value(&self, name: &str, value: &mut String) -> bool {
+        let Some(action) = self.rules.get(name) else {
+            return true;
+        };
+        match action {
+            ScrubAction::Drop => return false,
+            ScrubAction::Hash => match &self.hash_key {
+                Some(key) => *value = hash(key, value),
+                None => return false,
+            },
+            ScrubAction::Truncate(length) => {
+                if let Some((end, _)) = value.char_indices().nth(*length) {
+                    value.truncate(end);
+                }
+            }
+        }
+        true
+    }
+}
+
+fn hash(key: &hmac::Key, value: &str) -> String {
+    let mut hash = String::with_capacity(HASH_LENGTH);
+    for byte in hmac::sign(key, value.as_bytes()).as_ref() {
+        let _ = write!(hash, "{:02x}", byte);
+        if hash.len() >= HASH_LENGTH {
+            break;
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalizer::write_raw::tests::{labels_map, profile, sample, RequestBuilder};
+
+    #[test]
+    fn test_scrub_rule() {
+        assert_eq!(
+            "pid=drop".parse::<ScrubRule>().unwrap(),
+            ScrubRule {
+                label: "pid".into(),
+                action: ScrubAction::Drop
+            }
+        );
+        assert_eq!(
+            "cmdline=truncate:8".parse::<ScrubRule>().unwrap().action,
+            ScrubAction::Truncate(8)
+        );
+        assert_eq!(
+            "comm=hash".parse::<ScrubRule>().unwrap().action,
+            ScrubAction::Hash
+        );
+        assert!("pid".parse::<ScrubRule>().is_err());
+        assert!("pid=encrypt".parse::<ScrubRule>().is_err());
+        assert!("pid=truncate:x".parse::<ScrubRule>().is_err());
+    }
+
+    #[test]
+    fn test_label_scrubbing() {
+        let request = || {
+            let mut sample = sample(vec![], 1);
+            sample.label = labels_map(&[("thread", "worker-1"), ("cmdline", "/bin/app --secret")]);
+            sample.num_label = [("pid".to_string(), 42)].into();
+            RequestBuilder::default()
+                .with_series(
+                    &[("job", "api"), ("pid", "42"), ("comm", "app")],
+                    vec![profile(0, vec![sample])],
+                )
+                .with_label_names(&["job", "pid", "comm"])
+                .build()
+        };
+
+        let scrubbing = LabelScrubbing::new(
+            ["pid=drop", "comm=hash", "cmdline=truncate:4"]
+                .iter()
+                .map(|r| r.parse().unwrap())
+                .collect(),
+        );
+        assert!(scrubbing.hashes());
+        let mut unkeyed = request();
+        scrubbing.apply(&mut unkeyed);
+        let mut request = request();
+        scrubbing.with_hash_key(b"secret").apply(&mut request);
+
+        // Hashed labels are dropped without a key.
+        assert!(!unkeyed.series[0].labels.contains_key("comm"));
+        let series = &request.series[0];
+        assert_eq!(series.labels.len(), 2);
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        assert_eq!(series.labels["comm"], hash(&key, "app"));
+        assert_ne!(
+            hash(&key, "app"),
+            hash(&hmac::Key::new(hmac::HMAC_SHA256, b"other"), "app")
+        );
+        assert_eq!(series.labels["comm"].len(), HASH_LENGTH);
+        let sample = &series.samples[0][0].samples[0];
+        assert_eq!(sample.label["cmdline"], "/bin");
+        assert_eq!(sample.label["thread"], "worker-1");
+        assert!(sample.num_label.is_empty());
+        assert_eq!(request.all_label_names, ["job", "comm"]);
+    }
+
+    #[test]
+    fn test_label_scrubbing_raw() {
+        use crate::pprofpb::{self, Sample};
+        use crate::profilestorepb::{Label, LabelSet, RawProfileSeries, RawSample};
+
+        let label = |key, str, num| pprofpb::Label {
+            key,
+            str,
+            num,
+            ..Default::default()
+        };
+        let profile = Profile {
+            sample: vec![Sample {
+                value: vec![1],
+                label: vec![label(1, 2, 0), label(3, 4, 0), label(5, 0, 42)],
+                ..Default::default()
+            }],
+            string_table: ["", "cmdline", "/bin/app --secret", "thread", "w1", "pid"]
+                .map(String::from)
+                .to_vec(),
+            ..Default::default()
+        };
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&profile.encode_to_vec()).unwrap();
+        let mut request = WriteRawRequest {
+            series: vec![RawProfileSeries {
+                labels: Some(LabelSet {
+                    labels: [("job", "api"), ("pid", "42")]
+                        .map(|(name, value)| Label {
+                            name: name.into(),
+                            value: value.into(),
+                        })
+                        .to_vec(),
+                }),
+                samples: vec![RawSample {
+                    raw_profile: encoder.finish().unwrap(),
+                    executable_info: vec![],
+                }],
+            }],
+            ..Default::default()
+        };
+
+        let limits = DecompressionLimits::default();
+        LabelScrubbing::new(vec![
+            "pid=drop".parse().unwrap(),
+            "cmdline=truncate:4".parse().unwrap(),
+        ])
+        .apply_raw(&mut request, &limits)
+        .unwrap();
+
+        let series = &request.series[0];
+        let names: Vec<_> = series
+            .labels
+            .as_ref()
+            .unwrap()
+            .labels
+            .iter()
+            .map(|l| &l.name)
+            .collect();
+        assert_eq!(names, ["job"]);
+        let scrubbed = Profile::decode(
+            limits
+                .decompress(&series.samples[0].raw_profile)
+                .unwrap()
+                .as_slice(),
+        )
+        .unwrap();
+        let labels: Vec<_> = scrubbed.sample[0]
+            .label
+            .iter()
+            .map(|l| {
+                (
+                    scrubbed.string_table[l.key as usize].as_str(),
+                    scrubbed.string_table[l.str as usize].as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(labels, [("cmdline", "/bin"), ("thread", "w1")]);
+    }
+}
@@ -1,9 +1,15 @@
 use super::NormalizedProfile;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Series {
     pub(crate) labels: HashMap<String, String>,
     pub(crate) samples: Vec<Vec<NormalizedProfile>>,
+    /// Number of samples that were not normalized, by reason.
+    #[serde(default)]
+    pub(crate) dropped: BTreeMap<String, u64>,
+    /// Number of samples whose profile timestamp was clamped.
+    #[serde(default)]
+    pub(crate) clamped: u64,
 }
@@ -0,0 +1,128 @@
+use super::{Metastore, NormalizedWriteRawRequest};
+use crate::metapb::Function;
+use crate::profile::PprofLocations;
+use prometheus::{
+    exponential_buckets, register_histogram, register_int_counter, Histogram, IntCounter,
+};
+use std::sync::LazyLock;
+
+static STACK_DEPTH: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "evprofiler_stack_depth",
+        "Number of frames of the stacktraces of written samples, before truncation.",
+        exponential_buckets(1.0, 2.0, 15).unwrap()
+    )
+    .unwrap()
+});
+
+static TRUNCATED_STACKS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_truncated_stacks_total",
+        "Total number of stacktraces of written samples that were truncated to the maximum stack depth."
+    )
+    .unwrap()
+});
+
+/// Name of the frame that replaces the root-most frames of truncated stacks.
+pub const TRUNCATED_FRAME: &str = "[truncated]";
+
+/// StackDepthLimit bounds the number of frames of stacktraces, so that
+/// pathological stacks don't blow up storage and flamegraphs. Zero disables
+/// the limit.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StackDepthLimit {
+    pub max_depth: usize,
+}
+
+impl StackDepthLimit {
+    /// Truncates the stacks of the request that are deeper than the limit.
+    /// Stacks are stored leaf first, so the leaf-most frames are kept and the
+    /// rest is replaced by a single `[truncated]` frame, which counts towards
+    /// the limit.
+    pub fn apply(
+        &self,
+        request: &mut NormalizedWriteRawRequest,
+        metastore: &Metastore,
+    ) -> anyhow::Result<()> {
+        let mut truncated_frame = None;
+        for series in request.series.iter_mut() {
+            for profile in series.samples.iter_mut().flatten() {
+                for sample in profile.samples.iter_mut() {
+                    let depth = sample.locations.len();
+                    STACK_DEPTH.observe(depth as f64);
+                    if self.max_depth == 0 || depth <= self.max_depth {
+                        continue;
+                    }
+                    if truncated_frame.is_none() {
+                        truncated_frame = Some(Self::truncated_frame(metastore)?);
+                    }
+                    sample.locations.truncate(self.max_depth - 1);
+                    sample.locations.extend(truncated_frame.clone());
+                    TRUNCATED_STACKS.inc();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn truncated_frame(metastore: &Metastore) -> anyhow::Result<Vec<u8>> {
+        PprofLocations {
+            address: 0,
+            number_of_lines: 1,
+            build_id: String::new(),
+            file_name: String::new(),
+            mapping_memory_start: 0,
+            mapping_memory_end: 0,
+            mapping_file_offset: 0,
+            functions: vec![metastore.intern(&Function {
+                name: TRUNCATED_FRAME.into(),
+                ..Default::default()
+            })],
+        }
+        .encode()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalizer::write_raw::tests::{profile, sample, RequestBuilder};
+
+    fn request(depths: &[usize]) -> NormalizedWriteRawRequest {
+        let samples = depths
+            .iter()
+            .map(|&depth| sample((0..depth).map(|i| vec![i as u8]).collect(), 1))
+            .collect();
+        RequestBuilder::default()
+            .with_series(&[], vec![profile(0, samples)])
+            .build()
+    }
+
+    #[test]
+    fn test_stack_depth_limit() {
+        let metastore = Metastore::default();
+        let mut r = request(&[2, 3, 5]);
+        StackDepthLimit { max_depth: 3 }
+            .apply(&mut r, &metastore)
+            .unwrap();
+        let stacks: Vec<_> = r.series[0].samples[0][0]
+            .samples
+            .iter()
+            .map(|s| &s.locations)
+            .collect();
+        assert_eq!(stacks[0], &[vec![0], vec![1]]);
+        assert_eq!(stacks[1], &[vec![0], vec![1], vec![2]]);
+        assert_eq!(stacks[2].len(), 3);
+        assert_eq!(&stacks[2][..2], &[vec![0], vec![1]]);
+
+        let frame = PprofLocations::decode(&stacks[2][2]).unwrap();
+        let function = metastore.function(&frame.functions[0].id).unwrap();
+        assert_eq!(function.name, TRUNCATED_FRAME);
+
+        let mut r = request(&[5]);
+        StackDepthLimit::default()
+            .apply(&mut r, &metastore)
+            .unwrap();
+        assert_eq!(r.series[0].samples[0][0].samples[0].locations.len(), 5);
+    }
+}
@@ -0,0 +1,174 @@
+use super::NormalizedWriteRawRequest;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+static OUT_OF_BOUNDS_SAMPLES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_out_of_bounds_timestamp_samples_total",
+        "Total number of samples of profiles whose timestamp was too far in the past or future, by what was done about it.",
+        &["direction", "action"]
+    )
+    .unwrap()
+});
+
+/// What is done with profiles whose timestamp is out of bounds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TimestampAction {
+    /// Store the profile as is. Out of bounds profiles are only counted.
+    Accept,
+    /// Move the timestamp to the nearest bound.
+    Clamp,
+    /// Reject the whole request.
+    Reject,
+}
+
+/// TimestampOutOfBoundsError is returned when a request is rejected because
+/// of a profile's timestamp.
+#[derive(Debug)]
+pub struct TimestampOutOfBoundsError {
+    pub timestamp: i64,
+    pub min: i64,
+    pub max: i64,
+}
+
+impl std::fmt::Display for TimestampOutOfBoundsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "profile timestamp {}ms is outside of the accepted range [{}ms, {}ms]",
+            self.timestamp, self.min, self.max
+        )
+    }
+}
+
+impl std::error::Error for TimestampOutOfBoundsError {}
+
+/// TimestampPolicy bounds how far a profile's timestamp may be from the
+/// server's clock, so that agents with bogus clocks don't write into
+/// arbitrary partitions. Zero disables a bound.
+#[derive(Debug, Clone, Copy)]
+pub struct TimestampPolicy {
+    pub max_past: Duration,
+    pub max_future: Duration,
+    pub action: TimestampAction,
+}
+
+impl Default for TimestampPolicy {
+    fn default() -> Self {
+        Self {
+            max_past: Duration::ZERO,
+            max_future: Duration::ZERO,
+            action: TimestampAction::Accept,
+        }
+    }
+}
+
+impl TimestampPolicy {
+    /// Applies the policy to the profiles of the request, relative to `now`
+    /// in milliseconds. Fails with a TimestampOutOfBoundsError if the action
+    /// is to reject and any profile is out of bounds; nothing is modified in
+    /// that case.
+    pub fn apply(&self, request: &mut NormalizedWriteRawRequest, now: i64) -> anyhow::Result<()> {
+        let bound = |d: Duration| (!d.is_zero()).then_some(d.as_millis() as i64);
+        let min = bound(self.max_past).map_or(i64::MIN, |d| now.saturating_sub(d));
+        let max = bound(self.max_future).map_or(i64::MAX, |d| now.saturating_add(d));
+
+        if self.action == TimestampAction::Reject {
+            let out_of_bounds = request
+                .series
+                .iter()
+                .flat_map(|s| s.samples.iter().flatten())
+                .find(|p| !(min..=max).contains(&p.meta.timestamp));
+            if let Some(p) = out_of_bounds {
+                self.count(p.meta.timestamp < min, p.samples.len());
+                return Err(TimestampOutOfBoundsError {
+                    timestamp: p.meta.timestamp,
+                    min,
+                    max,
+                }
+                .into());
+            }
+            return Ok(());
+        }
+
+        for series in request.series.iter_mut() {
+            for profile in series.samples.iter_mut().flatten() {
+                let timestamp = profile.meta.timestamp;
+                if (min..=max).contains(&timestamp) {
+                    continue;
+                }
+                self.count(timestamp < min, profile.samples.len());
+                if self.action == TimestampAction::Clamp {
+                    profile.meta.timestamp = timestamp.clamp(min, max);
+                    series.clamped += profile.samples.len() as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn count(&self, past: bool, samples: usize) {
+        let action = match self.action {
+            TimestampAction::Accept => "accept",
+            TimestampAction::Clamp => "clamp",
+            TimestampAction::Reject => "reject",
+        };
+        OUT_OF_BOUNDS_SAMPLES
+            .with_label_values(&[if past { "past" } else { "future" }, action])
+            .inc_by(samples as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalizer::write_raw::tests::{profile, RequestBuilder};
+
+    fn request(timestamps: &[i64]) -> NormalizedWriteRawRequest {
+        let profiles = timestamps
+            .iter()
+            .map(|&timestamp| profile(timestamp, vec![]))
+            .collect();
+        RequestBuilder::default().with_series(&[], profiles).build()
+    }
+
+    fn timestamps(request: &NormalizedWriteRawRequest) -> Vec<i64> {
+        request.series[0].samples[0]
+            .iter()
+            .map(|p| p.meta.timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn test_timestamp_policy() {
+        let now = 1_000_000;
+        let policy = |action| TimestampPolicy {
+            max_past: Duration::from_secs(100),
+            max_future: Duration::from_secs(10),
+            action,
+        };
+
+        let mut r = request(&[0, now, now + 60_000]);
+        policy(TimestampAction::Accept).apply(&mut r, now).unwrap();
+        assert_eq!(timestamps(&r), [0, now, now + 60_000]);
+
+        policy(TimestampAction::Clamp).apply(&mut r, now).unwrap();
+        assert_eq!(timestamps(&r), [now - 100_000, now, now + 10_000]);
+
+        let mut r = request(&[now, now + 60_000]);
+        let err = policy(TimestampAction::Reject)
+            .apply(&mut r, now)
+            .unwrap_err();
+        assert!(err.is::<TimestampOutOfBoundsError>());
+        assert_eq!(timestamps(&r), [now, now + 60_000]);
+
+        let mut r = request(&[0, i64::MAX]);
+        TimestampPolicy {
+            action: TimestampAction::Reject,
+            ..Default::default()
+        }
+        .apply(&mut r, now)
+        .unwrap();
+    }
+}
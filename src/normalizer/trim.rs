@@ -0,0 +1,220 @@
+use super::{Metastore, NormalizedWriteRawRequest};
+use crate::metapb::Function;
+use crate::profile::PprofLocations;
+use anyhow::{bail, Context};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use regex::Regex;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+static TRIMMED_FRAMES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_trimmed_frames_total",
+        "Total number of leaf frames of written samples that were dropped or collapsed, by action.",
+        &["action"]
+    )
+    .unwrap()
+});
+
+/// What is done with the leaf frames matching a trim rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrimAction {
+    /// Removes the frames, so that their caller becomes the leaf.
+    Drop,
+    /// Replaces the frames by a single frame with the name.
+    Collapse(String),
+}
+
+/// TrimRule trims the leaf frames whose function matches a regular
+/// expression, given as `<regex>=drop` or `<regex>=collapse:<name>`.
+#[derive(Debug, Clone)]
+pub struct TrimRule {
+    pub pattern: Regex,
+    pub action: TrimAction,
+}
+
+impl FromStr for TrimRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let Some((pattern, action)) = s.rsplit_once('=') else {
+            bail!("invalid trim rule {:?}, expected <regex>=<action>", s);
+        };
+        let action = match action.split_once(':') {
+            None if action == "drop" => TrimAction::Drop,
+            Some(("collapse", name)) if !name.is_empty() => TrimAction::Collapse(name.into()),
+            _ => bail!(
+                "invalid trim action {:?}, expected drop or collapse:<name>",
+                action
+            ),
+        };
+        Ok(Self {
+            pattern: Regex::new(pattern)
+                .with_context(|| format!("invalid trim pattern {:?}", pattern))?,
+            action,
+        })
+    }
+}
+
+/// StackTrimming drops or collapses known noisy leaf frames of written
+/// samples, like `epoll_wait` or allocator internals, before they're stored.
+/// Only the leaf-most frames are trimmed, as long as they match a rule, and
+/// the root frame is always kept. Frames are matched by their innermost
+/// function, so frames that are only symbolized after ingestion are kept.
+#[derive(Debug, Clone, Default)]
+pub struct StackTrimming {
+    rules: Vec<TrimRule>,
+}
+
+impl StackTrimming {
+    pub fn new(rules: Vec<TrimRule>) -> Self {
+        Self { rules }
+    }
+
+    /// Trims the stacks of the request. The first matching rule of a frame
+    /// applies.
+    pub fn apply(
+        &self,
+        request: &mut NormalizedWriteRawRequest,
+        metastore: &Metastore,
+    ) -> anyhow::Result<()> {
+        if self.rules.is_empty() {
+            return Ok(());
+        }
+        let mut collapsed_frames: HashMap<usize, Vec<u8>> = HashMap::new();
+        for series in request.series.iter_mut() {
+            for profile in series.samples.iter_mut().flatten() {
+                for sample in profile.samples.iter_mut() {
+                    let locations = &mut sample.locations;
+                    while locations.len() > 1 {
+                        let Some(rule) = self.rule(&locations[0], metastore)? else {
+                            break;
+                        };
+                        match &self.rules[rule].action {
+                            TrimAction::Drop => {
+                                locations.remove(0);
+                                TRIMMED_FRAMES.with_label_values(&["drop"]).inc();
+                            }
+                            TrimAction::Collapse(name) => {
+                                let mut end = 1;
+                                while end < locations.len() - 1
+                                    && self.rule(&locations[end], metastore)? == Some(rule)
+                                {
+                                    end += 1;
+                                }
+                                let frame = match collapsed_frames.get(&rule) {
+                                    Some(frame) => frame.clone(),
+                                    None => {
+                                        let frame = collapsed_frame(name, metastore)?;
+                                        collapsed_frames.insert(rule, frame.clone());
+                                        frame
+                                    }
+                                };
+                                locations.splice(..end, [frame]);
+                                TRIMMED_FRAMES
+                                    .with_label_values(&["collapse"])
+                                    .inc_by(end as u64);
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the index of the first rule matching the innermost function
+    /// of the encoded location.
+    fn rule(&self, location: &[u8], metastore: &Metastore) -> anyhow::Result<Option<usize>> {
+        if location.is_empty() {
+            return Ok(None);
+        }
+        let location = PprofLocations::decode(location)?;
+        let Some(function) = location.functions.first() else {
+            return Ok(None);
+        };
+        let interned = metastore.function(&function.id);
+        let name = &interned.as_ref().unwrap_or(function).name;
+        Ok(self.rules.iter().position(|r| r.pattern.is_match(name)))
+    }
+}
+
+fn collapsed_frame(name: &str, metastore: &Metastore) -> anyhow::Result<Vec<u8>> {
+    PprofLocations {
+        address: 0,
+        number_of_lines: 1,
+        build_id: String::new(),
+        file_name: String::new(),
+        mapping_memory_start: 0,
+        mapping_memory_end: 0,
+        mapping_file_offset: 0,
+        functions: vec![metastore.intern(&Function {
+            name: name.into(),
+            ..Default::default()
+        })],
+    }
+    .encode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalizer::write_raw::tests::{profile, sample, RequestBuilder};
+
+    #[test]
+    fn test_stack_trimming() {
+        let metastore = Metastore::default();
+        let frame = |name: &str| collapsed_frame(name, &metastore).unwrap();
+        let stacks: [&[&str]; 3] = [
+            &["epoll_wait", "netpoll", "main"],
+            &["je_malloc_small", "je_malloc", "alloc", "main"],
+            &["epoll_wait"],
+        ];
+        let samples = stacks
+            .iter()
+            .map(|stack| sample(stack.iter().map(|f| frame(f)).collect(), 1))
+            .collect();
+        let mut request = RequestBuilder::default()
+            .with_series(&[], vec![profile(0, samples)])
+            .build();
+
+        let rules = ["^epoll_wait$=drop", "^je_=collapse:[allocator]"]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect();
+        StackTrimming::new(rules)
+            .apply(&mut request, &metastore)
+            .unwrap();
+        let names: Vec<Vec<String>> = request.series[0].samples[0][0]
+            .samples
+            .iter()
+            .map(|s| {
+                s.locations
+                    .iter()
+                    .map(|l| {
+                        let location = PprofLocations::decode(l).unwrap();
+                        metastore.function(&location.functions[0].id).unwrap().name
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                vec!["netpoll", "main"],
+                vec!["[allocator]", "alloc", "main"],
+                vec!["epoll_wait"],
+            ]
+        );
+
+        assert!("epoll_wait".parse::<TrimRule>().is_err());
+        assert!("(=drop".parse::<TrimRule>().is_err());
+        assert!("x=collapse:".parse::<TrimRule>().is_err());
+        assert_eq!(
+            "a=b=collapse:[c]".parse::<TrimRule>().unwrap().action,
+            TrimAction::Collapse("[c]".into())
+        );
+    }
+}
@@ -1,19 +1,21 @@
 use super::profile::NormalizedProfile;
 use super::write_raw::NormalizedWriteRawRequest;
-use super::{NormalizedSample, POSSIBLE_METADATA_LABELS};
+use super::{Metastore, NormalizedSample, POSSIBLE_METADATA_LABELS};
+use crate::pipeline::{Stage, StageTimes};
 use crate::pprofpb::{Function, Location, Mapping, Profile, Sample};
 use crate::profile::{Meta, PprofLocations, ValueType};
-use crate::profilestorepb::{ExecutableInfo, WriteRawRequest};
+use crate::profilestorepb::ExecutableInfo;
 use anyhow::bail;
 use arrow2::array::{
-    Array, DictionaryArray, Int64Array, ListArray, MutableArray, MutableBinaryArray,
-    MutableDictionaryArray, MutableListArray, MutablePrimitiveArray, MutableUtf8Array, TryPush,
+    Array, BooleanArray, DictionaryArray, Int64Array, ListArray, MutableArray, MutableBinaryArray,
+    MutableBooleanArray, MutableDictionaryArray, MutableListArray, MutablePrimitiveArray,
+    MutableUtf8Array, TryPush,
 };
 use arrow2::chunk::Chunk;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
-const NANOS_PER_MILLI: i64 = 1_000_000;
+pub(super) const NANOS_PER_MILLI: i64 = 1_000_000;
 
 pub fn validate_pprof_profile(
     profile: &Profile,
@@ -180,15 +182,18 @@ pub fn label_names_from_profile(
 
 pub fn normalize_pprof(
     name: &str,
+    delta: Option<bool>,
     taken_label_names: &HashMap<String, String>,
     p: &Profile,
+    metastore: &Metastore,
+    times: &mut StageTimes,
 ) -> anyhow::Result<Vec<NormalizedProfile>> {
     let mut profiles: Vec<NormalizedProfile> = Vec::with_capacity(p.sample_type.len());
 
     for i in 0..p.sample_type.len() {
         let np: NormalizedProfile = NormalizedProfile::new(
             Vec::with_capacity(p.sample.len()),
-            meta_from_pprof(p, name, i),
+            meta_from_pprof(p, name, delta, i),
         );
         profiles.push(np);
     }
@@ -212,6 +217,8 @@ pub fn normalize_pprof(
                     p.function.as_slice(),
                     p.mapping.as_slice(),
                     p.string_table.as_slice(),
+                    metastore,
+                    times,
                 )?,
                 value: sample.value[i],
                 label: labels.clone(),
@@ -224,7 +231,13 @@ pub fn normalize_pprof(
     Ok(profiles)
 }
 
-fn meta_from_pprof(p: &Profile, name: &str, sample_index: usize) -> Meta {
+/// Returns whether the profile is a delta, as told by the delta label of its
+/// series if any.
+pub(super) fn is_delta(delta: Option<bool>, p: &Profile) -> bool {
+    delta.unwrap_or(p.duration_nanos > 0)
+}
+
+fn meta_from_pprof(p: &Profile, name: &str, delta: Option<bool>, sample_index: usize) -> Meta {
     let period_type = match p.period_type {
         Some(pt) => ValueType {
             type_: p.string_table[pt.r#type as usize].clone(),
@@ -252,6 +265,7 @@ fn meta_from_pprof(p: &Profile, name: &str, sample_index: usize) -> Meta {
         timestamp: p.time_nanos / NANOS_PER_MILLI,
         duration: p.duration_nanos,
         period: p.period,
+        delta: is_delta(delta, p),
         period_type,
         sample_type,
     }
@@ -310,6 +324,8 @@ fn serialize_pprof_stacktrace(
     functions: &[Function],
     mappings: &[Mapping],
     string_table: &[String],
+    metastore: &Metastore,
+    times: &mut StageTimes,
 ) -> anyhow::Result<Vec<Vec<u8>>> {
     let mut stacktrace = Vec::with_capacity(ids.len());
 
@@ -319,17 +335,19 @@ fn serialize_pprof_stacktrace(
             0 => None,
             _ => Some(&mappings[location.mapping_id as usize - 1]),
         };
-        stacktrace.push(PprofLocations::new(location, mapping, functions, string_table).encode()?)
+        let mut pl = PprofLocations::new(location, mapping, functions, string_table);
+        pl.functions = times.time(Stage::Intern, || {
+            pl.functions.iter().map(|f| metastore.intern(f)).collect()
+        });
+        stacktrace.push(pl.encode()?)
     }
 
     Ok(stacktrace)
 }
 
-pub async fn write_raw_request_to_arrow_chunk(
-    request: &WriteRawRequest,
+pub async fn normalized_request_to_arrow_chunk(
+    normalized_request: &NormalizedWriteRawRequest,
 ) -> anyhow::Result<Chunk<Arc<dyn Array>>> {
-    let normalized_request = NormalizedWriteRawRequest::try_from(request)?;
-
     let mut duration_column = MutablePrimitiveArray::new();
     let mut name_column: MutableDictionaryArray<i32, MutableUtf8Array<i32>> =
         MutableDictionaryArray::new();
@@ -346,6 +364,11 @@ pub async fn write_raw_request_to_arrow_chunk(
         MutableListArray::new();
     let mut timestamp_column = MutablePrimitiveArray::new();
     let mut value_column = MutablePrimitiveArray::new();
+    let mut pprof_labels_column: MutableDictionaryArray<i32, MutableUtf8Array<i32>> =
+        MutableDictionaryArray::new();
+    let mut pprof_num_labels_column: MutableDictionaryArray<i32, MutableUtf8Array<i32>> =
+        MutableDictionaryArray::new();
+    let mut delta_column = MutableBooleanArray::new();
 
     for series in normalized_request.series.iter() {
         for profiles in series.samples.iter() {
@@ -376,6 +399,9 @@ pub async fn write_raw_request_to_arrow_chunk(
                     }
                     timestamp_column.push(Some(p.meta.timestamp));
                     value_column.push(Some(ns.value));
+                    pprof_labels_column.try_push(ns.encoded_labels())?;
+                    pprof_num_labels_column.try_push(ns.encoded_num_labels())?;
+                    delta_column.push(Some(p.meta.delta));
                 }
             }
         }
@@ -422,5 +448,9 @@ pub async fn write_raw_request_to_arrow_chunk(
         fields.push(arr.arced());
     }
 
+    fields.push(DictionaryArray::from(pprof_labels_column).arced());
+    fields.push(DictionaryArray::from(pprof_num_labels_column).arced());
+    fields.push(BooleanArray::from(delta_column).arced());
+
     Ok(Chunk::new(fields))
 }
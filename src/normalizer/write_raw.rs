@@ -1,29 +1,60 @@
-use super::{NormalizedProfile, Series};
+use super::{locations_only, DecompressionLimits, Metastore, NormalizedProfile, Series};
+use crate::pipeline::{Stage, StageTimes};
 use crate::pprofpb::Profile;
-use crate::profilestorepb::WriteRawRequest;
+use crate::profilestorepb::{SeriesSampleCounts, WriteRawRequest};
 use anyhow::bail;
-use flate2::read::GzDecoder;
 use prost::Message;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::io::Read;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Reason samples with a value of zero are dropped with, since they don't
+/// contribute to any profile.
+pub const DROPPED_ZERO_VALUE: &str = "zero_value";
+
+/// Label writers tell delta profiles from cumulative ones with, set to `true`
+/// or `false`. Profiles of series without it are deltas when they cover a
+/// duration, like pprof CPU profiles.
+pub const DELTA_LABEL: &str = "__delta__";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NormalizedWriteRawRequest {
     pub(crate) series: Vec<Series>,
     pub(crate) all_label_names: Vec<String>,
+    /// File names of the mappings of the profiles, by build ID.
+    #[serde(default)]
+    pub(crate) mapping_files: BTreeMap<String, String>,
 }
 
-impl TryFrom<&WriteRawRequest> for NormalizedWriteRawRequest {
-    type Error = anyhow::Error;
+impl NormalizedWriteRawRequest {
+    /// Normalizes the request, interning the functions of its stacktraces in
+    /// the metastore. Fails with a DecompressionLimitError if a profile
+    /// exceeds the decompression limits.
+    pub fn try_new(
+        request: &WriteRawRequest,
+        metastore: &Metastore,
+        limits: &DecompressionLimits,
+    ) -> anyhow::Result<Self> {
+        Self::try_new_timed(request, metastore, limits, &mut StageTimes::default())
+    }
 
-    fn try_from(request: &WriteRawRequest) -> anyhow::Result<Self> {
+    /// Like `try_new`, accounting the time spent decoding, normalizing and
+    /// interning to `times`.
+    pub fn try_new_timed(
+        request: &WriteRawRequest,
+        metastore: &Metastore,
+        limits: &DecompressionLimits,
+        times: &mut StageTimes,
+    ) -> anyhow::Result<Self> {
+        let started = std::time::Instant::now();
+        let (decoded, interned) = (times.get(Stage::Decode), times.get(Stage::Intern));
         let mut all_label_names: HashSet<String> = HashSet::new();
         let mut series: Vec<Series> = Vec::with_capacity(request.series.len());
+        let mut mapping_files = BTreeMap::new();
 
         for raw_series in request.series.iter() {
             let mut ls: HashMap<String, String> = HashMap::new();
             let mut name: String = "".into();
+            let mut delta = None;
 
             if let Some(label_set) = &raw_series.labels {
                 for label in label_set.labels.iter() {
@@ -31,6 +62,14 @@ impl TryFrom<&WriteRawRequest> for NormalizedWriteRawRequest {
                         name = label.value.clone();
                         continue;
                     }
+                    if label.name == DELTA_LABEL {
+                        delta = match label.value.as_str() {
+                            "true" => Some(true),
+                            "false" => Some(false),
+                            value => bail!("Invalid {} label {:?}", DELTA_LABEL, value),
+                        };
+                        continue;
+                    }
 
                     if ls.contains_key(&label.name) {
                         bail!("Duplicate label {} in series", label.name);
@@ -47,25 +86,31 @@ impl TryFrom<&WriteRawRequest> for NormalizedWriteRawRequest {
 
             let mut samples: Vec<Vec<NormalizedProfile>> =
                 Vec::with_capacity(raw_series.samples.len());
+            let mut dropped = BTreeMap::new();
 
             for sample in raw_series.samples.iter() {
-                let mut decompressed = Vec::new();
+                let p = times.time(Stage::Decode, || {
+                    let decompressed = limits.decompress(sample.raw_profile.as_slice())?;
+                    anyhow::Ok(Profile::decode(decompressed.as_slice())?)
+                })?;
 
-                let mut decoder = GzDecoder::new(sample.raw_profile.as_slice());
-                if decoder.header().is_some() {
-                    if let Err(e) = decoder.read_to_end(&mut decompressed) {
-                        bail!("Failed to decompress gzip: {}", e);
-                    }
+                if locations_only::is_locations_only(&p) {
+                    locations_only::validate(&p)?;
+                    record_mapping_files(&p, &mut mapping_files);
+                    super::utils::label_names_from_profile(
+                        &ls,
+                        p.string_table.as_slice(),
+                        p.sample.as_slice(),
+                        &mut all_label_names,
+                    );
+                    samples.push(vec![locations_only::normalize(name.as_str(), delta, &p)?]);
+                    count_zero_values(&p, &mut dropped);
+                    continue;
                 }
 
-                //let path: PathBuf = "/tmp".into();
-                //let mut file = std::fs::File::create(&path.join("pp"))?;
-                //let _ = file.write_all(decompressed.as_slice())?;
-
-                let p = Profile::decode(decompressed.as_slice())?;
-
                 // let _ =
                 super::utils::validate_pprof_profile(&p, sample.executable_info.as_slice())?;
+                record_mapping_files(&p, &mut mapping_files);
 
                 super::utils::label_names_from_profile(
                     &ls,
@@ -75,22 +120,271 @@ impl TryFrom<&WriteRawRequest> for NormalizedWriteRawRequest {
                 );
 
                 let np: Vec<NormalizedProfile> =
-                    super::utils::normalize_pprof(name.as_str(), &ls, &p)?;
+                    super::utils::normalize_pprof(name.as_str(), delta, &ls, &p, metastore, times)?;
 
                 samples.push(np);
+                count_zero_values(&p, &mut dropped);
             }
 
             series.push(Series {
                 labels: ls,
                 samples,
+                dropped,
+                clamped: 0,
             });
         }
 
         let all_label_names = Vec::from_iter(all_label_names);
+        let other = (times.get(Stage::Decode) - decoded) + (times.get(Stage::Intern) - interned);
+        times.add(Stage::Normalize, started.elapsed().saturating_sub(other));
 
         Ok(NormalizedWriteRawRequest {
             series,
             all_label_names,
+            mapping_files,
         })
     }
+
+    /// Returns how many samples of every series were stored, clamped or
+    /// dropped, in the order of the request.
+    pub fn sample_counts(&self) -> Vec<SeriesSampleCounts> {
+        self.series
+            .iter()
+            .map(|series| SeriesSampleCounts {
+                accepted: series
+                    .samples
+                    .iter()
+                    .flatten()
+                    .map(|p| p.samples.len() as u64)
+                    .sum(),
+                clamped: series.clamped,
+                dropped: series.dropped.clone().into_iter().collect(),
+            })
+            .collect()
+    }
+}
+
+/// Records the file names of the mappings of the profile that have a build
+/// ID.
+fn record_mapping_files(p: &Profile, files: &mut BTreeMap<String, String>) {
+    let string = |i: i64| p.string_table.get(i as usize).filter(|s| !s.is_empty());
+    for mapping in p.mapping.iter() {
+        if let (Some(build_id), Some(file)) = (string(mapping.build_id), string(mapping.filename)) {
+            files.insert(build_id.clone(), file.clone());
+        }
+    }
+}
+
+/// Counts the values of the profile's samples that are zero, which aren't
+/// normalized.
+fn count_zero_values(p: &Profile, dropped: &mut BTreeMap<String, u64>) {
+    let zero = p.sample.iter().flat_map(|s| &s.value).filter(|v| **v == 0);
+    match zero.count() as u64 {
+        0 => {}
+        n => *dropped.entry(DROPPED_ZERO_VALUE.to_string()).or_default() += n,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::normalizer::NormalizedSample;
+    use crate::pprofpb::{Sample, ValueType};
+    use crate::profile::Meta;
+    use crate::profilestorepb::{Label, LabelSet, RawProfileSeries, RawSample};
+
+    /// Builds normalized requests for tests.
+    pub(crate) struct RequestBuilder {
+        request: NormalizedWriteRawRequest,
+    }
+
+    impl Default for RequestBuilder {
+        fn default() -> Self {
+            Self {
+                request: NormalizedWriteRawRequest {
+                    series: vec![],
+                    all_label_names: vec![],
+                    mapping_files: Default::default(),
+                },
+            }
+        }
+    }
+
+    impl RequestBuilder {
+        /// Adds a series with the labels and a single group of profiles.
+        pub(crate) fn with_series(
+            mut self,
+            labels: &[(&str, &str)],
+            profiles: Vec<NormalizedProfile>,
+        ) -> Self {
+            self.request.series.push(Series {
+                labels: labels_map(labels),
+                samples: vec![profiles],
+                ..Default::default()
+            });
+            self
+        }
+
+        pub(crate) fn with_label_names(mut self, names: &[&str]) -> Self {
+            self.request.all_label_names = names.iter().map(|n| n.to_string()).collect();
+            self
+        }
+
+        /// Sets the file names of the mappings, by build ID.
+        pub(crate) fn with_mapping_files(mut self, files: &[(&str, &str)]) -> Self {
+            self.request.mapping_files = files
+                .iter()
+                .map(|(id, file)| (id.to_string(), file.to_string()))
+                .collect();
+            self
+        }
+
+        pub(crate) fn build(self) -> NormalizedWriteRawRequest {
+            self.request
+        }
+    }
+
+    /// Returns a delta CPU profile of the samples, like the ones
+    /// parca-agent writes.
+    pub(crate) fn profile(timestamp: i64, samples: Vec<NormalizedSample>) -> NormalizedProfile {
+        let value_type = |type_: &str, unit: &str| crate::profile::ValueType {
+            type_: type_.into(),
+            unit: unit.into(),
+        };
+        NormalizedProfile::new(
+            samples,
+            Meta {
+                name: "process_cpu".into(),
+                period_type: value_type("cpu", "nanoseconds"),
+                sample_type: value_type("samples", "count"),
+                timestamp,
+                duration: 10,
+                period: 1,
+                delta: true,
+            },
+        )
+    }
+
+    /// Returns a sample of the locations without labels.
+    pub(crate) fn sample(locations: Vec<Vec<u8>>, value: i64) -> NormalizedSample {
+        NormalizedSample {
+            locations,
+            value,
+            diff_value: 0,
+            label: Default::default(),
+            num_label: Default::default(),
+        }
+    }
+
+    pub(crate) fn labels_map(labels: &[(&str, &str)]) -> HashMap<String, String> {
+        labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_sample_counts() {
+        let profile = Profile {
+            sample_type: vec![
+                ValueType { r#type: 1, unit: 2 },
+                ValueType { r#type: 3, unit: 2 },
+            ],
+            sample: vec![
+                Sample {
+                    value: vec![1, 0],
+                    ..Default::default()
+                },
+                Sample {
+                    value: vec![0, 0],
+                    ..Default::default()
+                },
+                Sample {
+                    value: vec![2, 3],
+                    ..Default::default()
+                },
+            ],
+            string_table: vec![
+                "".into(),
+                "alloc_objects".into(),
+                "count".into(),
+                "inuse_objects".into(),
+            ],
+            ..Default::default()
+        };
+        let request = WriteRawRequest {
+            series: vec![RawProfileSeries {
+                labels: Some(LabelSet {
+                    labels: vec![Label {
+                        name: "__name__".into(),
+                        value: "memory".into(),
+                    }],
+                }),
+                samples: vec![RawSample {
+                    raw_profile: crate::backfill::compress(&profile.encode_to_vec()).unwrap(),
+                    executable_info: vec![],
+                }],
+            }],
+            ..Default::default()
+        };
+        let normalized = NormalizedWriteRawRequest::try_new(
+            &request,
+            &Metastore::default(),
+            &DecompressionLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            normalized.sample_counts(),
+            [SeriesSampleCounts {
+                accepted: 3,
+                clamped: 0,
+                dropped: [(DROPPED_ZERO_VALUE.to_string(), 3)].into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_delta_label() {
+        let profile = Profile {
+            sample_type: vec![ValueType { r#type: 1, unit: 2 }],
+            sample: vec![Sample {
+                value: vec![1],
+                ..Default::default()
+            }],
+            string_table: vec!["".into(), "samples".into(), "count".into()],
+            duration_nanos: 10,
+            ..Default::default()
+        };
+        let delta = |delta: Option<&str>| {
+            let labels = [("__name__", "process_cpu")]
+                .into_iter()
+                .chain(delta.map(|d| (DELTA_LABEL, d)))
+                .map(|(name, value)| Label {
+                    name: name.into(),
+                    value: value.into(),
+                })
+                .collect();
+            let request = WriteRawRequest {
+                series: vec![RawProfileSeries {
+                    labels: Some(LabelSet { labels }),
+                    samples: vec![RawSample {
+                        raw_profile: crate::backfill::compress(&profile.encode_to_vec()).unwrap(),
+                        executable_info: vec![],
+                    }],
+                }],
+                ..Default::default()
+            };
+            let normalized = NormalizedWriteRawRequest::try_new(
+                &request,
+                &Metastore::default(),
+                &DecompressionLimits::default(),
+            )?;
+            assert!(!normalized.series[0].labels.contains_key(DELTA_LABEL));
+            anyhow::Ok(normalized.series[0].samples[0][0].meta.delta)
+        };
+        assert!(delta(None).unwrap());
+        assert!(!delta(Some("false")).unwrap());
+        assert!(delta(Some("true")).unwrap());
+        assert!(delta(Some("yes")).is_err());
+    }
 }
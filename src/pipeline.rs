@@ -0,0 +1,152 @@
+use crate::metrics;
+use prometheus::{exponential_buckets, register_histogram_vec, HistogramVec};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+static STAGE_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "evprofiler_ingest_stage_duration_seconds",
+        "Time spent in each stage of the profile ingestion pipeline, per request.",
+        &["stage"],
+        exponential_buckets(0.0001, 4.0, 10).unwrap()
+    )
+    .unwrap()
+});
+
+/// Stage of the profile ingestion pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Decompressing and decoding the pprof profiles.
+    Decode,
+    /// Validating the profiles and converting them to normalized samples and
+    /// Arrow chunks.
+    Normalize,
+    /// Interning the functions of the stacktraces in the metastore.
+    Intern,
+    /// Resolving addresses to lines, which happens when profiles are queried.
+    Symbolize,
+    /// Writing the chunks to the ingester.
+    Persist,
+}
+
+impl Stage {
+    const ALL: [Stage; 5] = [
+        Stage::Decode,
+        Stage::Normalize,
+        Stage::Intern,
+        Stage::Symbolize,
+        Stage::Persist,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Decode => "decode",
+            Stage::Normalize => "normalize",
+            Stage::Intern => "intern",
+            Stage::Symbolize => "symbolize",
+            Stage::Persist => "persist",
+        }
+    }
+}
+
+/// Records that a request spent `duration` in `stage`. With a trace ID, the
+/// observation is kept as the exemplar of its bucket.
+pub fn observe(stage: Stage, duration: Duration, trace_id: Option<&str>) {
+    metrics::observe_with_exemplar(
+        &STAGE_DURATION.with_label_values(&[stage.as_str()]),
+        duration.as_secs_f64(),
+        trace_id,
+    );
+}
+
+/// StageTimes accumulates the time a request spends in each stage, since
+/// stages like interning are interleaved with others.
+#[derive(Debug, Default)]
+pub struct StageTimes {
+    durations: [Option<Duration>; 5],
+}
+
+impl StageTimes {
+    /// Runs `f`, accounting its duration to `stage`.
+    pub fn time<T>(&mut self, stage: Stage, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let res = f();
+        self.add(stage, started.elapsed());
+        res
+    }
+
+    pub fn add(&mut self, stage: Stage, duration: Duration) {
+        let total = &mut self.durations[stage as usize];
+        *total = Some(total.unwrap_or_default() + duration);
+    }
+
+    pub fn get(&self, stage: Stage) -> Duration {
+        self.durations[stage as usize].unwrap_or_default()
+    }
+
+    /// Observes the time spent in the stages the request went through.
+    pub fn observe(&self, trace_id: Option<&str>) {
+        for stage in Stage::ALL {
+            if let Some(duration) = self.durations[stage as usize] {
+                observe(stage, duration, trace_id);
+            }
+        }
+    }
+}
+
+/// Returns the trace ID of a W3C `traceparent` header, if the trace is
+/// sampled.
+pub fn sampled_trace_id(traceparent: &str) -> Option<&str> {
+    let mut parts = traceparent.trim().split('-');
+    let (version, trace_id, parent_id, flags) =
+        (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let is_hex = |s: &str, len| s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit());
+    if !is_hex(version, 2) || version == "ff" || !is_hex(parent_id, 16) || !is_hex(flags, 2) {
+        return None;
+    }
+    if !is_hex(trace_id, 32) || trace_id.bytes().all(|b| b == b'0') {
+        return None;
+    }
+    let sampled = u8::from_str_radix(flags, 16).ok()? & 1 == 1;
+    sampled.then_some(trace_id)
+}
+
+/// Returns the sampled trace ID the client propagated with the request.
+pub fn trace_id<T>(request: &tonic::Request<T>) -> Option<String> {
+    let traceparent = request.metadata().get("traceparent")?.to_str().ok()?;
+    sampled_trace_id(traceparent).map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampled_trace_id() {
+        let id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        assert_eq!(
+            sampled_trace_id(&format!("00-{}-00f067aa0ba902b7-01", id)),
+            Some(id)
+        );
+        for traceparent in [
+            format!("00-{}-00f067aa0ba902b7-00", id),
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01".into(),
+            format!("00-{}-01", id),
+            format!("ff-{}-00f067aa0ba902b7-01", id),
+            "not a traceparent".into(),
+        ] {
+            assert_eq!(sampled_trace_id(&traceparent), None, "{}", traceparent);
+        }
+    }
+
+    #[test]
+    fn test_stage_times() {
+        let mut times = StageTimes::default();
+        assert_eq!(times.time(Stage::Intern, || 1), 1);
+        times.add(Stage::Decode, Duration::from_millis(2));
+        times.add(Stage::Decode, Duration::from_millis(3));
+        assert_eq!(times.get(Stage::Decode), Duration::from_millis(5));
+        assert_eq!(times.durations[Stage::Persist as usize], None);
+        assert!(times.durations[Stage::Intern as usize].is_some());
+    }
+}
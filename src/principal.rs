@@ -0,0 +1,301 @@
+use crate::agent_store;
+use anyhow::{ensure, Context};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tonic::metadata::AsciiMetadataKey;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+/// Principal is the identity a request is made by. It's resolved once per
+/// request by [`Authenticator`] and stored in the request extensions, so that
+/// upload quotas, agent records and log lines of the services use the same
+/// identity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    id: String,
+    /// Identity of the sender the client can't choose.
+    peer: String,
+}
+
+impl Principal {
+    pub fn new(id: impl Into<String>) -> Self {
+        let id = id.into();
+        Self {
+            peer: id.clone(),
+            id,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the identity verified by a trusted proxy, or the client
+    /// address. Unlike the id, which agents can set with the `x-agent-id`
+    /// metadata, clients can't choose it, so upload quotas are enforced on
+    /// it.
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    /// Returns the principal of the request. Requests that didn't pass
+    /// through the [`Authenticator`], like in-process calls, are identified the
+    /// same way it would without trusted proxies.
+    pub fn of<T>(request: &Request<T>) -> Self {
+        match request.extensions().get::<Principal>() {
+            Some(principal) => principal.clone(),
+            None => Self::identify(request, &TrustedProxies::default()),
+        }
+    }
+
+    /// Identifies the sender of the request by the identity forwarded by a
+    /// trusted proxy, the `x-agent-id` metadata, or the client address.
+    fn identify<T>(request: &Request<T>, proxies: &TrustedProxies) -> Self {
+        if let Some(identity) = proxies.forwarded_identity(request) {
+            return Self::new(identity);
+        }
+        let client = proxies
+            .forwarded_ip(request)
+            .or_else(|| request.remote_addr().map(|addr| addr.ip()));
+        Self {
+            id: agent_store::agent_id(request, client),
+            peer: client.map_or_else(|| "unknown".into(), |ip| ip.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for Principal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.id)
+    }
+}
+
+/// A network of trusted proxies, like `10.0.0.0/8`, or a single address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Network {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid address {:?}", s))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .with_context(|| format!("invalid prefix length {:?}", s))?,
+            None => bits,
+        };
+        ensure!(prefix <= bits, "prefix length of {:?} is too long", s);
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Network {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// TrustedProxies are the gateways in front of the server whose headers
+/// about the clients they forward requests of are honored: the client
+/// address, from a header like `x-forwarded-for`, and the identity the
+/// gateway verified, like the subject of the client's mTLS certificate. The
+/// headers of requests from other peers are ignored, since clients could
+/// set them to impersonate others.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<Network>,
+    client_ip_header: Option<AsciiMetadataKey>,
+    identity_header: Option<AsciiMetadataKey>,
+}
+
+impl TrustedProxies {
+    /// Trusts the proxies in `networks`, addresses or CIDR ranges, to set
+    /// the headers. Empty headers aren't honored.
+    pub fn new(
+        networks: &[String],
+        client_ip_header: &str,
+        identity_header: &str,
+    ) -> anyhow::Result<Self> {
+        let header = |name: &str| -> anyhow::Result<Option<AsciiMetadataKey>> {
+            if name.is_empty() {
+                return Ok(None);
+            }
+            let key = AsciiMetadataKey::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid header {:?}", name))?;
+            Ok(Some(key))
+        };
+        Ok(Self {
+            networks: networks
+                .iter()
+                .map(|n| n.parse())
+                .collect::<anyhow::Result<_>>()?,
+            client_ip_header: header(client_ip_header)?,
+            identity_header: header(identity_header)?,
+        })
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|n| n.contains(ip))
+    }
+
+    /// Returns the value of the header if the request comes from a trusted
+    /// proxy.
+    fn header<'a, T>(
+        &self,
+        request: &'a Request<T>,
+        header: &Option<AsciiMetadataKey>,
+    ) -> Option<&'a str> {
+        let peer = request.remote_addr()?.ip();
+        if !self.trusts(peer) {
+            return None;
+        }
+        let value = request.metadata().get(header.as_ref()?)?.to_str().ok()?;
+        Some(value.trim()).filter(|v| !v.is_empty())
+    }
+
+    /// Returns the identity of the client verified by the trusted proxy the
+    /// request came through.
+    fn forwarded_identity<T>(&self, request: &Request<T>) -> Option<String> {
+        self.header(request, &self.identity_header)
+            .map(str::to_string)
+    }
+
+    /// Returns the address of the client the request was forwarded for by
+    /// trusted proxies: the last address of the header that isn't a trusted
+    /// proxy, since earlier ones can be set by the client.
+    fn forwarded_ip<T>(&self, request: &Request<T>) -> Option<IpAddr> {
+        let addrs: Vec<IpAddr> = self
+            .header(request, &self.client_ip_header)?
+            .split(',')
+            .map(|addr| addr.trim().parse().ok())
+            .collect::<Option<_>>()?;
+        addrs
+            .iter()
+            .rev()
+            .find(|addr| !self.trusts(**addr))
+            .or(addrs.first())
+            .copied()
+    }
+}
+
+/// Authenticator is the interceptor resolving the principal of gRPC requests
+/// into their extensions.
+#[derive(Debug, Clone, Default)]
+pub struct Authenticator {
+    proxies: Arc<TrustedProxies>,
+}
+
+impl Authenticator {
+    /// Honors the headers of the trusted proxies to identify the clients of
+    /// the requests they forward.
+    pub fn new(proxies: TrustedProxies) -> Self {
+        Self {
+            proxies: Arc::new(proxies),
+        }
+    }
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let principal = Principal::identify(&request, &self.proxies);
+        request.extensions_mut().insert(principal);
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_store::AGENT_ID_METADATA_KEY;
+    use std::net::SocketAddr;
+    use tonic::transport::server::TcpConnectInfo;
+
+    #[test]
+    fn test_principal_of() {
+        let mut request = Request::new(());
+        assert_eq!(Principal::of(&request).id(), "unknown");
+
+        request
+            .metadata_mut()
+            .insert(AGENT_ID_METADATA_KEY, "agent-1".parse().unwrap());
+        let request = Authenticator::default().call(request).unwrap();
+        let principal = request.extensions().get::<Principal>().unwrap();
+        assert_eq!(principal.id(), "agent-1");
+        assert_eq!(principal.peer(), "unknown");
+
+        let mut request = request.map(|_| 42);
+        request.extensions_mut().insert(Principal::new("agent-2"));
+        assert_eq!(Principal::of(&request).id(), "agent-2");
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let proxies = TrustedProxies::new(
+            &["10.0.0.0/8".into(), "fd00::1".into()],
+            "X-Forwarded-For",
+            "x-client-subject",
+        )
+        .unwrap();
+        let request = |peer: &str, headers: &[(&'static str, &str)]| {
+            let mut request = Request::new(());
+            request.extensions_mut().insert(TcpConnectInfo {
+                local_addr: None,
+                remote_addr: Some(SocketAddr::new(peer.parse().unwrap(), 1234)),
+            });
+            for (name, value) in headers {
+                request.metadata_mut().insert(*name, value.parse().unwrap());
+            }
+            Authenticator::new(proxies.clone()).call(request).unwrap()
+        };
+        let id = |request: Request<()>| Principal::of(&request).id().to_string();
+        let peer = |request: Request<()>| Principal::of(&request).peer().to_string();
+
+        // Addresses appended by trusted proxies are skipped, the ones before
+        // the client's could be spoofed.
+        let forwarded = [("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.1.1.1")];
+        assert_eq!(id(request("10.0.0.1", &forwarded)), "1.2.3.4");
+        assert_eq!(id(request("::ffff:10.0.0.1", &forwarded)), "1.2.3.4");
+        assert_eq!(id(request("fd00::1", &forwarded)), "1.2.3.4");
+        assert_eq!(id(request("192.168.0.1", &forwarded)), "192.168.0.1");
+        assert_eq!(
+            id(request("10.0.0.1", &[("x-forwarded-for", "invalid")])),
+            "10.0.0.1"
+        );
+
+        let identity = [
+            ("x-client-subject", "CN=agent-1"),
+            ("x-agent-id", "agent-2"),
+        ];
+        assert_eq!(id(request("10.0.0.1", &identity)), "CN=agent-1");
+        assert_eq!(id(request("192.168.0.1", &identity)), "agent-2");
+        assert_eq!(peer(request("10.0.0.1", &identity)), "CN=agent-1");
+        assert_eq!(peer(request("192.168.0.1", &identity)), "192.168.0.1");
+
+        for invalid in ["10.0.0.0/33", "10.0.0", "::/abc"] {
+            assert!(TrustedProxies::new(&[invalid.into()], "", "").is_err());
+        }
+        assert!(TrustedProxies::new(&[], "invalid header", "").is_err());
+    }
+}
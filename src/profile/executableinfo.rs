@@ -23,6 +23,16 @@ pub struct ExecutableInfo {
 }
 
 impl ExecutableInfo {
+    /// Creates the info of an object of the kind with the loadable segments,
+    /// for binaries only known by their symbol table.
+    pub(crate) fn new(elf_type: ObjectKind, prog_headers: Vec<ProgHeader>) -> Self {
+        Self {
+            elf_type,
+            text_prog_hdr_indx: -1,
+            prog_headers,
+        }
+    }
+
     /// FindProgramHeader returns the program segment that matches the current
     /// mapping and the given address, or an error if it cannot find a unique program
     /// header.
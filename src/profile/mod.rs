@@ -1,7 +1,6 @@
 mod encode;
 pub mod executableinfo;
 pub mod schema;
-mod utils;
 
 use crate::metapb::{Function, Mapping};
 pub use encode::PprofLocations;
@@ -46,4 +45,8 @@ pub struct Meta {
     pub timestamp: i64,
     pub duration: i64,
     pub period: i64,
+    /// Whether the samples are deltas over the duration of the profile,
+    /// rather than cumulative since the process started.
+    #[serde(default)]
+    pub delta: bool,
 }
@@ -2,12 +2,15 @@ use arrow2::datatypes::{DataType, Field, IntegerType, Schema};
 
 use crate::normalizer::POSSIBLE_METADATA_LABELS;
 
+const COLUMN_DELTA: &str = "delta";
 const COLUMN_DURATION: &str = "duration";
 const COLUMN_LABELS: &str = "labels";
 const COLUMN_NAME: &str = "name";
 const COLUMN_PERIOD: &str = "period";
 const COLUMN_PERIOD_TYPE: &str = "period_type";
 const COLUMN_PERIOD_UNIT: &str = "period_unit";
+const COLUMN_PPROF_LABELS: &str = "pprof_labels";
+const COLUMN_PPROF_NUM_LABELS: &str = "pprof_num_labels";
 const COLUMN_SAMPLE_TYPE: &str = "sample_type";
 const COLUMN_SAMPLE_UNIT: &str = "sample_unit";
 const COLUMN_STACKTRACE: &str = "stacktrace";
@@ -65,5 +68,20 @@ pub fn create_schema() -> Schema {
         ));
     }
 
+    // Per sample pprof labels, encoded by NormalizedSample::encoded_labels.
+    // They come last so that segments written before they existed only lack
+    // trailing columns.
+    for column in [COLUMN_PPROF_LABELS, COLUMN_PPROF_NUM_LABELS] {
+        fields.push(Field::new(
+            column,
+            DataType::Dictionary(IntegerType::Int32, Box::new(DataType::Utf8), false),
+            true,
+        ));
+    }
+
+    // Whether the profile of the sample is a delta, null in segments written
+    // before it was recorded.
+    fields.push(Field::new(COLUMN_DELTA, DataType::Boolean, true));
+
     Schema::from(fields)
 }
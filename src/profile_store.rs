@@ -1,16 +1,64 @@
+use crate::agent_store::{AgentStore, UploadService};
+use crate::clickhouse::ClickHouseSink;
+use crate::debuginfo_store::DebuginfodPolicy;
+use crate::idempotency::{Admission, IdempotencyKeys, PendingWrite};
+use crate::kafka::KafkaForwarder;
+use crate::memory::MemoryWatchdog;
+use crate::mode::Mode;
+use crate::pipeline::{self, Stage, StageTimes};
+use crate::pprofpb::Profile;
+use crate::principal::Principal;
 use crate::profilestorepb::profile_store_service_server::ProfileStoreService;
-use crate::profilestorepb::{WriteRawRequest, WriteRawResponse, WriteRequest, WriteResponse};
-use crate::{ingester, normalizer, symbolizer};
-use anyhow::bail;
-use std::sync::Arc;
+use crate::profilestorepb::{
+    RawSample, SeriesSampleCounts, WriteRawRequest, WriteRawResponse, WriteRequest, WriteResponse,
+};
+use crate::sampling::{AdaptiveSampling, InFlight, DROPPED_SAMPLED_OUT};
+use crate::{ingester, normalizer, query, replay, symbolizer};
+use anyhow::{bail, Context};
+use arrow2::{array::Array, chunk::Chunk};
+use chrono::{DateTime, Utc};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use prost::Message;
+use std::collections::BTreeMap;
+use std::sync::{Arc, LazyLock};
+use std::time::Instant;
 use std::{pin::Pin, result::Result};
+use tokio::sync::oneshot;
 use tokio_stream::Stream;
 use tonic::{Request, Response, Status, Streaming};
 
+static DROPPED_SAMPLES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_dropped_samples_total",
+        "Total number of samples of written profiles that were not stored, by reason.",
+        &["reason"]
+    )
+    .unwrap()
+});
+
 #[derive(Debug)]
 pub struct ProfileStore {
     symbolizer: Arc<symbolizer::Symbolizer>,
     ingester: Arc<ingester::Ingester>,
+    agents: Arc<AgentStore>,
+    pipeline: normalizer::WritePipeline,
+    index: Arc<query::SeriesIndex>,
+    payloads: Option<Arc<replay::PayloadBuffer>>,
+    idempotency: IdempotencyKeys,
+    traces: Arc<query::TraceIndex>,
+    stats: Arc<query::IngestionStats>,
+    symbolization_queue: Option<Arc<symbolizer::SymbolizationQueue>>,
+    mode: Mode,
+    debuginfod_policy: Option<Arc<DebuginfodPolicy>>,
+    sampling: AdaptiveSampling,
+    memory: Option<Arc<MemoryWatchdog>>,
+    clickhouse: Option<Arc<ClickHouseSink>>,
+    /// Whether samples are stored by the ingester, false if they're only
+    /// written to ClickHouse.
+    local_storage: bool,
+    kafka: Option<Arc<KafkaForwarder>>,
+    /// Whether requests are only published to Kafka, without being written.
+    forward_only: bool,
 }
 
 #[tonic::async_trait]
@@ -20,11 +68,54 @@ impl ProfileStoreService for ProfileStore {
         &self,
         request: Request<WriteRawRequest>,
     ) -> anyhow::Result<Response<WriteRawResponse>, Status> {
-        let _ = match self.write_series(&request.into_inner()).await {
-            Ok(_) => (),
+        self.mode.check_writable()?;
+        if let Some(memory) = &self.memory {
+            memory.check()?;
+        }
+        let principal = Principal::of(&request);
+        let agent = principal.to_string();
+        let started = Instant::now();
+        let trace_id = pipeline::trace_id(&request);
+        let key = IdempotencyKeys::key_of(&request, &request.get_ref().idempotency_key);
+        // Dropping the pending write, on any failure or if the request is
+        // cancelled, forgets its key, so that the write can be retried.
+        let pending = match self.idempotency.begin(&agent, key)? {
+            Admission::Proceed(pending) => pending,
+            Admission::Duplicate(series) => return Ok(Response::new(WriteRawResponse { series })),
+        };
+        let mut request = request.into_inner();
+        self.account_upload(&principal, &request)?;
+        if let Some(payloads) = &self.payloads {
+            match self.scrubbed(&request) {
+                Ok(scrubbed) => {
+                    payloads.record(&agent, scrubbed);
+                }
+                Err(e) => log::debug!("Not keeping the payload of agent {}: {}", agent, e),
+            }
+        }
+        // The write stays in flight until its samples are persisted.
+        let (in_flight, sampled_out) = self.sampling.admit(&mut request);
+
+        let res = self
+            .forward(&agent, &request, trace_id, pending, in_flight, sampled_out)
+            .await;
+        self.agents.record_push(
+            &agent,
+            started.elapsed(),
+            res.as_ref().err().map(|e| e.to_string()),
+        );
+
+        let series = match res {
+            Ok(series) => series,
+            Err(e)
+                if e.is::<normalizer::DecompressionLimitError>()
+                    || e.is::<normalizer::TimestampOutOfBoundsError>() =>
+            {
+                return Err(Status::invalid_argument(e.to_string()))
+            }
             Err(e) => return Err(Status::internal(e.to_string())),
         };
-        return Ok(Response::new(WriteRawResponse {}));
+        return Ok(Response::new(WriteRawResponse { series }));
     }
     /// Server streaming response type for the Write method.
     type WriteStream =
@@ -39,6 +130,7 @@ impl ProfileStoreService for ProfileStore {
         &self,
         request: Request<Streaming<WriteRequest>>,
     ) -> anyhow::Result<Response<Self::WriteStream>, Status> {
+        self.mode.check_writable()?;
         let mut stream = request.into_inner();
 
         log::info!("Received ProfileStoreService::write request",);
@@ -58,16 +150,410 @@ impl ProfileStoreService for ProfileStore {
 }
 
 impl ProfileStore {
-    pub fn new(symbolizer: Arc<symbolizer::Symbolizer>, ingester: Arc<ingester::Ingester>) -> Self {
+    pub fn new(
+        symbolizer: Arc<symbolizer::Symbolizer>,
+        ingester: Arc<ingester::Ingester>,
+        agents: Arc<AgentStore>,
+        pipeline: normalizer::WritePipeline,
+        index: Arc<query::SeriesIndex>,
+        payloads: Option<Arc<replay::PayloadBuffer>>,
+    ) -> Self {
         Self {
             symbolizer: Arc::clone(&symbolizer),
             ingester: Arc::clone(&ingester),
+            agents,
+            pipeline,
+            index,
+            payloads,
+            idempotency: IdempotencyKeys::default(),
+            traces: Arc::default(),
+            stats: Arc::default(),
+            symbolization_queue: None,
+            mode: Mode::default(),
+            debuginfod_policy: None,
+            sampling: AdaptiveSampling::default(),
+            memory: None,
+            clickhouse: None,
+            local_storage: true,
+            kafka: None,
+            forward_only: false,
         }
     }
 
-    pub async fn write_series(&self, request: &WriteRawRequest) -> anyhow::Result<()> {
-        let chunk = match normalizer::write_raw_request_to_arrow_chunk(request).await {
+    /// Publishes the accepted WriteRaw requests with `kafka`, with their
+    /// labels scrubbed. Requests are only published, and not normalized or
+    /// stored, if `forward_only` is set.
+    pub fn with_kafka_forwarder(mut self, kafka: Arc<KafkaForwarder>, forward_only: bool) -> Self {
+        self.kafka = Some(kafka);
+        self.forward_only = forward_only;
+        self
+    }
+
+    /// Also writes the samples of written profiles to `sink`, or only to it
+    /// unless `local_storage` is set.
+    pub fn with_clickhouse_sink(mut self, sink: Arc<ClickHouseSink>, local_storage: bool) -> Self {
+        self.clickhouse = Some(sink);
+        self.local_storage = local_storage;
+        self
+    }
+
+    /// Records the files build IDs are mapped from for `policy`.
+    pub fn with_debuginfod_policy(mut self, policy: Arc<DebuginfodPolicy>) -> Self {
+        self.debuginfod_policy = Some(policy);
+        self
+    }
+
+    /// Rejects writes while `memory` sheds load.
+    pub fn with_memory_watchdog(mut self, memory: Arc<MemoryWatchdog>) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// Drops series of written profiles with `sampling` while overloaded.
+    pub fn with_adaptive_sampling(mut self, sampling: AdaptiveSampling) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    /// Refuses writes if `mode` is read-only.
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Indexes samples carrying trace or span IDs in `traces`.
+    pub fn with_trace_index(mut self, traces: Arc<query::TraceIndex>) -> Self {
+        self.traces = traces;
+        self
+    }
+
+    /// Tracks the ingestion rates of the written series in `stats`.
+    pub fn with_ingestion_stats(mut self, stats: Arc<query::IngestionStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Queues the unsymbolized addresses of written profiles in `queue`.
+    pub fn with_symbolization_queue(mut self, queue: Arc<symbolizer::SymbolizationQueue>) -> Self {
+        self.symbolization_queue = Some(queue);
+        self
+    }
+
+    /// Returns the limits gzipped profiles are decompressed within.
+    pub fn decompression(&self) -> &normalizer::DecompressionLimits {
+        self.pipeline.decompression()
+    }
+
+    /// Deduplicates writes carrying an idempotency key with `keys`.
+    pub fn with_idempotency(mut self, keys: IdempotencyKeys) -> Self {
+        self.idempotency = keys;
+        self
+    }
+
+    /// Accounts the raw profile bytes of the request to the pushing agent and
+    /// to the node each series belongs to.
+    fn account_upload(
+        &self,
+        principal: &Principal,
+        request: &WriteRawRequest,
+    ) -> Result<(), Status> {
+        let mut total = 0;
+        let mut per_node: Vec<(&str, u64)> = vec![];
+
+        for series in request.series.iter() {
+            let bytes: u64 = series
+                .samples
+                .iter()
+                .map(|s| s.raw_profile.len() as u64)
+                .sum();
+            total += bytes;
+
+            let node = series.labels.as_ref().and_then(|ls| {
+                ls.labels
+                    .iter()
+                    .find(|l| l.name == "node")
+                    .map(|l| l.value.as_str())
+            });
+            if let Some(node) = node {
+                per_node.push((node, bytes));
+            }
+        }
+
+        self.agents
+            .record_upload(principal, UploadService::ProfileStore, total)?;
+        for (node, bytes) in per_node {
+            self.agents.record_node_upload(node, bytes);
+        }
+        Ok(())
+    }
+
+    /// Returns a copy of the request with its labels scrubbed, for the copies
+    /// that are kept or published as received.
+    fn scrubbed(&self, request: &WriteRawRequest) -> anyhow::Result<WriteRawRequest> {
+        let mut scrubbed = request.clone();
+        self.pipeline
+            .scrubbing()
+            .apply_raw(&mut scrubbed, self.pipeline.decompression())?;
+        Ok(scrubbed)
+    }
+
+    /// Runs the request through the write pipeline and observes its series,
+    /// accounting the time spent in each stage to `times`. Returns the chunk
+    /// with the sample counts of the series.
+    async fn normalize(
+        &self,
+        request: &WriteRawRequest,
+        times: &mut StageTimes,
+    ) -> anyhow::Result<(Chunk<Arc<dyn Array>>, Vec<SeriesSampleCounts>)> {
+        let (normalized, chunk) = self.pipeline.run(request, times).await?;
+        self.observe(request, &normalized)?;
+        Ok((chunk, normalized.sample_counts()))
+    }
+
+    /// Adds the series of a stored request to the series and trace indexes
+    /// and the ingestion stats, and queues its unsymbolized addresses.
+    fn observe(
+        &self,
+        request: &WriteRawRequest,
+        normalized: &normalizer::NormalizedWriteRawRequest,
+    ) -> anyhow::Result<()> {
+        self.index.observe(normalized);
+        self.traces.observe(normalized);
+        self.stats.observe(request, normalized);
+        if let Some(policy) = &self.debuginfod_policy {
+            policy.record_origins(normalized);
+        }
+        if let Some(queue) = &self.symbolization_queue {
+            queue.push_request(normalized)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the request through the write path, recording what every step
+    /// produced in `trace`. The result is only stored if `store` is set.
+    /// Replayed payloads were scrubbed when they were kept, so their labels
+    /// aren't scrubbed again.
+    pub async fn replay(
+        &self,
+        request: &WriteRawRequest,
+        store: bool,
+        trace: &mut Vec<String>,
+    ) -> anyhow::Result<()> {
+        if store {
+            self.mode.check_writable()?;
+        }
+        for (i, series) in request.series.iter().enumerate() {
+            let labels = series
+                .labels
+                .iter()
+                .flat_map(|ls| ls.labels.iter())
+                .map(|l| format!("{}={:?}", l.name, l.value))
+                .collect::<Vec<_>>();
+            trace.push(format!(
+                "series {}: {{{}}}, {} raw profiles",
+                i,
+                labels.join(", "),
+                series.samples.len()
+            ));
+            for (j, sample) in series.samples.iter().enumerate() {
+                let decompressed = self
+                    .pipeline
+                    .decompression()
+                    .decompress(&sample.raw_profile)?;
+                trace.push(format!(
+                    "series {} profile {}: {} bytes, {} bytes decompressed, {} executable infos",
+                    i,
+                    j,
+                    sample.raw_profile.len(),
+                    decompressed.len(),
+                    sample.executable_info.len()
+                ));
+            }
+        }
+
+        let pipeline = self
+            .pipeline
+            .clone()
+            .with_label_scrubbing(normalizer::LabelScrubbing::default());
+        let (normalized, chunk) = pipeline.run(request, &mut StageTimes::default()).await?;
+        for (i, series) in normalized.series.iter().enumerate() {
+            for profile in series.samples.iter().flatten() {
+                let meta = &profile.meta;
+                trace.push(format!(
+                    "series {} normalized: {}:{}:{}:{}:{} at {}ms, {} samples, {} frames",
+                    i,
+                    meta.name,
+                    meta.sample_type.type_,
+                    meta.sample_type.unit,
+                    meta.period_type.type_,
+                    meta.period_type.unit,
+                    meta.timestamp,
+                    profile.samples.len(),
+                    profile
+                        .samples
+                        .iter()
+                        .map(|s| s.locations.len())
+                        .sum::<usize>()
+                ));
+            }
+        }
+        self.replay_symbolization(&normalized, trace).await?;
+
+        trace.push(format!("arrow chunk: {} rows", chunk.len()));
+        if !store {
+            trace.push("not stored".into());
+            return Ok(());
+        }
+        self.observe(request, &normalized)?;
+        if !chunk.is_empty() {
+            self.persist(chunk, Utc::now()).await?;
+        }
+        trace.push("stored".into());
+        Ok(())
+    }
+
+    /// Symbolizes the unsymbolized addresses of the request, recording how
+    /// many of every build ID resolved in `trace`. Nothing is accounted to
+    /// the build IDs, so replays don't poison them.
+    async fn replay_symbolization(
+        &self,
+        normalized: &normalizer::NormalizedWriteRawRequest,
+        trace: &mut Vec<String>,
+    ) -> anyhow::Result<()> {
+        for (build_id, addresses) in symbolizer::pending_addresses(normalized)? {
+            let mut request = symbolizer::SymbolizationRequest::from_pending(&build_id, &addresses);
+            match self.symbolizer.dry_run(&mut request).await {
+                Ok(_) => trace.push(format!(
+                    "build_id {} symbolized: {} of {} addresses",
+                    build_id,
+                    request.mappings[0]
+                        .locations
+                        .iter()
+                        .filter(|l| !l.lines.is_empty())
+                        .count(),
+                    addresses.len()
+                )),
+                Err(e) => trace.push(format!("build_id {} not symbolized: {:#}", build_id, e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Publishes the request to Kafka, if enabled, and writes its series,
+    /// unless requests are only forwarded. A failure to publish fails the
+    /// request only if it isn't written either.
+    async fn forward(
+        &self,
+        agent: &str,
+        request: &WriteRawRequest,
+        trace_id: Option<String>,
+        pending: PendingWrite,
+        in_flight: InFlight,
+        sampled_out: BTreeMap<usize, Vec<RawSample>>,
+    ) -> anyhow::Result<Vec<SeriesSampleCounts>> {
+        if let Some(kafka) = &self.kafka {
+            let res = match self.scrubbed(request) {
+                Ok(scrubbed) => kafka.publish_write(agent, &scrubbed).await,
+                Err(e) => Err(e),
+            };
+            if self.forward_only {
+                res?;
+                let mut series = self.forwarded_counts(request)?;
+                self.count_sampled_out(&mut series, sampled_out);
+                pending.complete(&series);
+                return Ok(series);
+            }
+            if let Err(e) = res {
+                log::error!("Failed to publish the request to Kafka: {}", e);
+            }
+        }
+        self.write_series(request, trace_id, pending, in_flight, sampled_out)
+            .await
+    }
+
+    /// Returns the number of pprof samples of the raw profiles.
+    fn sample_count(&self, samples: &[RawSample]) -> anyhow::Result<u64> {
+        let mut count = 0;
+        for sample in samples {
+            let data = self
+                .pipeline
+                .decompression()
+                .decompress(&sample.raw_profile)?;
+            count += Profile::decode(data.as_slice())?.sample.len() as u64;
+        }
+        Ok(count)
+    }
+
+    /// Returns how many samples of every series of a published request were
+    /// forwarded, in the order of the request.
+    fn forwarded_counts(
+        &self,
+        request: &WriteRawRequest,
+    ) -> anyhow::Result<Vec<SeriesSampleCounts>> {
+        request
+            .series
+            .iter()
+            .map(|series| {
+                Ok(SeriesSampleCounts {
+                    accepted: self.sample_count(&series.samples)?,
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    /// Counts the samples of the series dropped by adaptive sampling as
+    /// dropped, by the index of their series.
+    fn count_sampled_out(
+        &self,
+        series: &mut [SeriesSampleCounts],
+        sampled_out: BTreeMap<usize, Vec<RawSample>>,
+    ) {
+        for (i, samples) in sampled_out {
+            let Some(counts) = series.get_mut(i) else {
+                continue;
+            };
+            match self.sample_count(&samples) {
+                Ok(count) => {
+                    DROPPED_SAMPLES
+                        .with_label_values(&[DROPPED_SAMPLED_OUT])
+                        .inc_by(count);
+                    *counts
+                        .dropped
+                        .entry(DROPPED_SAMPLED_OUT.to_string())
+                        .or_default() += count;
+                }
+                Err(e) => log::debug!("Failed to count sampled out samples: {}", e),
+            }
+        }
+    }
+
+    /// Writes the series of the request and returns how many of their samples
+    /// were stored, counting the `sampled_out` ones as dropped. The time spent
+    /// in every stage is observed with `trace_id` as exemplar, the ID of the
+    /// sampled trace the request was sent in. The pending write is completed
+    /// with the counts once its samples are durable, and the write stays
+    /// `in_flight` until they are persisted.
+    pub async fn write_series(
+        &self,
+        request: &WriteRawRequest,
+        trace_id: Option<String>,
+        pending: PendingWrite,
+        in_flight: InFlight,
+        sampled_out: BTreeMap<usize, Vec<RawSample>>,
+    ) -> anyhow::Result<Vec<SeriesSampleCounts>> {
+        let received = Utc::now();
+        let mut times = StageTimes::default();
+        let res = self.normalize(request, &mut times).await;
+        times.observe(trace_id.as_deref());
+        let (chunk, mut series) = match res {
             Ok(record) => record,
+            Err(e)
+                if e.is::<normalizer::DecompressionLimitError>()
+                    || e.is::<normalizer::TimestampOutOfBoundsError>() =>
+            {
+                return Err(e)
+            }
             Err(e) => {
                 bail!(
                     "Failed to normalize WriteRawRequest to Arrow Record, details: {}",
@@ -75,12 +561,76 @@ impl ProfileStore {
                 );
             }
         };
+        for (reason, count) in series.iter().flat_map(|s| s.dropped.iter()) {
+            DROPPED_SAMPLES.with_label_values(&[reason]).inc_by(*count);
+        }
+        self.count_sampled_out(&mut series, sampled_out);
         if chunk.is_empty() {
-            return Ok(());
+            pending.complete(&series);
+            return Ok(series);
         }
 
-        let ingester = Arc::clone(&self.ingester);
-        tokio::spawn(async move { ingester.ingest(chunk).await });
-        Ok(())
+        let persist = self.persist(chunk, received);
+        if !self.local_storage {
+            // Samples written only to ClickHouse aren't kept anywhere else,
+            // so the write fails unless they're inserted.
+            if let Some(inserted) = persist.await? {
+                inserted
+                    .await
+                    .context("Failed to insert the samples into ClickHouse")?;
+            }
+            pending.complete(&series);
+            drop(in_flight);
+            return Ok(series);
+        }
+        let counts = series.clone();
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let res = persist.await;
+            drop(in_flight);
+            pipeline::observe(Stage::Persist, started.elapsed(), trace_id.as_deref());
+            match res {
+                Ok(Some(committed)) => {
+                    if committed.await.is_ok() {
+                        pending.complete(&counts);
+                    }
+                }
+                Ok(None) => pending.complete(&counts),
+                Err(e) => log::error!("Failed to persist the samples of a write: {}", e),
+            }
+        });
+        Ok(series)
+    }
+
+    /// Writes the chunk to ClickHouse, if enabled, and to the ingester, which
+    /// observes its latency from `received`. Resolves to the receiver told
+    /// once the ingester recorded the chunk in a segment, or once ClickHouse
+    /// inserted it if it's only written there. Failures to write to
+    /// ClickHouse are only logged if the chunk is also stored.
+    fn persist(
+        &self,
+        chunk: Chunk<Arc<dyn Array>>,
+        received: DateTime<Utc>,
+    ) -> impl std::future::Future<Output = anyhow::Result<Option<oneshot::Receiver<()>>>> + Send + 'static
+    {
+        let ingester = self.local_storage.then(|| Arc::clone(&self.ingester));
+        let clickhouse = self.clickhouse.clone();
+        async move {
+            let inserted = match clickhouse {
+                Some(sink) => match sink.write(&chunk).await {
+                    Ok(inserted) => Some(inserted),
+                    Err(e) if ingester.is_some() => {
+                        log::error!("Failed to write samples to ClickHouse: {}", e);
+                        None
+                    }
+                    Err(e) => return Err(e),
+                },
+                None => None,
+            };
+            match ingester {
+                Some(ingester) => Ok(Some(ingester.ingest_write(chunk, received).await?)),
+                None => Ok(inserted),
+            }
+        }
     }
 }
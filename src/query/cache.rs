@@ -0,0 +1,123 @@
+use axum::{
+    body::Bytes,
+    http::{HeaderName, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use moka::sync::Cache;
+use object_store::path::Path;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::sync::{Arc, LazyLock};
+
+static QUERY_CACHE_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_query_cache_requests_total",
+        "Total number of rendered reports looked up in the query cache, by result.",
+        &["result"]
+    )
+    .unwrap()
+});
+
+/// A rendered report, with its response headers.
+#[derive(Debug, Clone)]
+pub(crate) struct Rendered {
+    pub headers: Vec<(HeaderName, &'static str)>,
+    pub body: Bytes,
+}
+
+impl IntoResponse for Rendered {
+    fn into_response(self) -> Response {
+        let mut response = self.body.into_response();
+        for (name, value) in self.headers {
+            response
+                .headers_mut()
+                .insert(name, HeaderValue::from_static(value));
+        }
+        response
+    }
+}
+
+#[derive(Debug)]
+struct CachedReport {
+    /// Segments the report was rendered from.
+    segments: Vec<Path>,
+    rendered: Rendered,
+}
+
+/// QueryCache keeps recently rendered reports, keyed by the report type,
+/// selector, time range and options, so that dashboards refreshing the same
+/// reports don't read the segments again. A cached report is only served
+/// while the segments overlapping its time range are the ones it was
+/// rendered from, so new segments of the range, or compacted ones,
+/// invalidate it.
+#[derive(Debug, Default)]
+pub struct QueryCache {
+    reports: Option<Cache<String, Arc<CachedReport>>>,
+}
+
+impl QueryCache {
+    /// Creates a cache of up to `max_bytes` of rendered reports. Zero
+    /// disables caching.
+    pub fn new(max_bytes: u64) -> Self {
+        let reports = (max_bytes > 0).then(|| {
+            Cache::builder()
+                .max_capacity(max_bytes)
+                .weigher(|key: &String, report: &Arc<CachedReport>| {
+                    (key.len() + report.rendered.body.len())
+                        .try_into()
+                        .unwrap_or(u32::MAX)
+                })
+                .build()
+        });
+        Self { reports }
+    }
+
+    /// Returns the report cached under the key if it was rendered from the
+    /// segments.
+    pub(crate) fn get(&self, key: &str, segments: &[Path]) -> Option<Rendered> {
+        let reports = self.reports.as_ref()?;
+        let result = match reports.get(key) {
+            Some(report) if report.segments == segments => {
+                QUERY_CACHE_REQUESTS.with_label_values(&["hit"]).inc();
+                return Some(report.rendered.clone());
+            }
+            Some(_) => "stale",
+            None => "miss",
+        };
+        QUERY_CACHE_REQUESTS.with_label_values(&[result]).inc();
+        None
+    }
+
+    pub(crate) fn insert(&self, key: String, segments: Vec<Path>, rendered: Rendered) {
+        if let Some(reports) = &self.reports {
+            reports.insert(key, Arc::new(CachedReport { segments, rendered }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header;
+
+    #[test]
+    fn test_query_cache() {
+        let rendered = |body: &'static str| Rendered {
+            headers: vec![(header::CONTENT_TYPE, "text/plain")],
+            body: Bytes::from_static(body.as_bytes()),
+        };
+        let segments = vec![Path::from("date=2024-01-01/1.parquet")];
+
+        let cache = QueryCache::new(1 << 20);
+        assert!(cache.get("folded", &segments).is_none());
+        cache.insert("folded".into(), segments.clone(), rendered("main 1\n"));
+        assert_eq!(cache.get("folded", &segments).unwrap().body, "main 1\n");
+        // A new segment of the range invalidates the report.
+        let mut written = segments.clone();
+        written.push(Path::from("date=2024-01-01/2.parquet"));
+        assert!(cache.get("folded", &written).is_none());
+
+        let disabled = QueryCache::new(0);
+        disabled.insert("folded".into(), segments.clone(), rendered("main 1\n"));
+        assert!(disabled.get("folded", &segments).is_none());
+    }
+}
@@ -0,0 +1,220 @@
+use super::samples::StoredSample;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+
+/// DotOptions prune the call graph like the flags of `pprof -dot` with the
+/// same names.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct DotOptions {
+    /// Maximum number of nodes, the ones with the highest cumulative value.
+    pub nodecount: usize,
+    /// Nodes below this fraction of the total are dropped.
+    pub nodefraction: f64,
+    /// Edges below this fraction of the total are dropped.
+    pub edgefraction: f64,
+}
+
+impl Default for DotOptions {
+    fn default() -> Self {
+        Self {
+            nodecount: 80,
+            nodefraction: 0.005,
+            edgefraction: 0.001,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    /// Value of the samples with the function as their leaf.
+    flat: i64,
+    /// Value of the samples with the function anywhere on their stack.
+    cum: i64,
+}
+
+/// CallGraph has a node per function and an edge per call between
+/// functions, weighted with the value of the samples through the call.
+#[derive(Debug, Default)]
+pub(crate) struct CallGraph {
+    nodes: HashMap<String, Node>,
+    /// Weights of the edges from caller to callee.
+    edges: HashMap<(String, String), i64>,
+    total: i64,
+}
+
+impl CallGraph {
+    pub fn new(samples: &[StoredSample]) -> Self {
+        let mut graph = Self::default();
+        for sample in samples {
+            let names: Vec<String> = sample.stacktrace.iter().map(|f| f.name()).collect();
+            graph.total += sample.value;
+            if let Some(leaf) = names.first() {
+                graph.nodes.entry(leaf.clone()).or_default().flat += sample.value;
+            }
+            // Recursive functions and calls count once per sample.
+            let mut seen = HashSet::new();
+            for name in names.iter() {
+                if seen.insert(name) {
+                    graph.nodes.entry(name.clone()).or_default().cum += sample.value;
+                }
+            }
+            let mut seen = HashSet::new();
+            for call in names.windows(2) {
+                let (callee, caller) = (&call[0], &call[1]);
+                if seen.insert((caller, callee)) {
+                    *graph
+                        .edges
+                        .entry((caller.clone(), callee.clone()))
+                        .or_default() += sample.value;
+                }
+            }
+        }
+        graph
+    }
+
+    /// Renders the graph in the DOT language of GraphViz, like `pprof -dot`:
+    /// nodes are labeled with their flat and cumulative values, with a font
+    /// growing with the flat value, and edges with the value through the
+    /// call, with a width growing with it.
+    pub fn to_dot(&self, title: &str, options: &DotOptions) -> String {
+        let min_node = (self.total as f64 * options.nodefraction) as i64;
+        let mut nodes: Vec<(&String, &Node)> = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.cum > 0 && node.cum >= min_node)
+            .collect();
+        nodes.sort_by(|a, b| b.1.cum.cmp(&a.1.cum).then_with(|| a.0.cmp(b.0)));
+        nodes.truncate(options.nodecount);
+        let ids: BTreeMap<&str, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _))| (name.as_str(), i + 1))
+            .collect();
+
+        let percent = |value: i64| match self.total {
+            0 => 0.0,
+            total => value as f64 * 100.0 / total as f64,
+        };
+        let shown: i64 = nodes.iter().map(|(_, node)| node.flat).sum();
+        let mut dot = String::new();
+        let _ = writeln!(dot, "digraph \"{}\" {{", escape(title));
+        dot.push_str("node [style=filled fillcolor=\"#f8f8f8\"]\n");
+        let _ = writeln!(
+            dot,
+            "subgraph cluster_L {{ \"{}\" [shape=box fontsize=16 label=\"{}\\lShowing nodes accounting for {}, {:.2}% of {} total\\l\"] }}",
+            escape(title),
+            escape(title),
+            shown,
+            percent(shown),
+            self.total
+        );
+
+        let max_flat = nodes.iter().map(|(_, n)| n.flat).max().unwrap_or(0).max(1);
+        for (name, node) in nodes.iter() {
+            let font_size = 8.0 + (16.0 * (node.flat as f64 / max_flat as f64).sqrt()).ceil();
+            let mut label = format!(
+                "{}\\n{} ({:.2}%)",
+                escape(name),
+                node.flat,
+                percent(node.flat)
+            );
+            if node.cum != node.flat {
+                let _ = write!(label, "\\nof {} ({:.2}%)", node.cum, percent(node.cum));
+            }
+            let _ = writeln!(
+                dot,
+                "N{} [label=\"{}\" fontsize={} shape=box tooltip=\"{} ({})\"]",
+                ids[name.as_str()],
+                label,
+                font_size,
+                escape(name),
+                node.cum
+            );
+        }
+
+        let min_edge = (self.total as f64 * options.edgefraction) as i64;
+        let mut edges: Vec<(usize, usize, i64)> = self
+            .edges
+            .iter()
+            .filter(|(_, &weight)| weight > 0 && weight >= min_edge)
+            .filter_map(|((caller, callee), &weight)| {
+                Some((
+                    *ids.get(caller.as_str())?,
+                    *ids.get(callee.as_str())?,
+                    weight,
+                ))
+            })
+            .collect();
+        edges.sort();
+        for (caller, callee, weight) in edges {
+            let ratio = weight as f64 / self.total.max(1) as f64;
+            let _ = writeln!(
+                dot,
+                "N{} -> N{} [label=\" {}\" weight={} penwidth={}]",
+                caller,
+                callee,
+                weight,
+                1 + (ratio * 100.0) as i64,
+                1 + (ratio * 5.0) as i64
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Escapes the text for a quoted DOT string.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::samples::Frame;
+
+    #[test]
+    fn test_to_dot() {
+        let sample = |functions: &[&str], value| StoredSample {
+            profile_type: "process_cpu:samples:count:cpu:nanoseconds:delta".into(),
+            labels: BTreeMap::new(),
+            timestamp: 0,
+            duration: 0,
+            period: 1,
+            value,
+            stacktrace: functions
+                .iter()
+                .map(|f| Frame {
+                    function: f.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+        };
+        let samples = [
+            sample(&["leaf", "main"], 6),
+            sample(&["main"], 2),
+            sample(&["fib", "fib", "main"], 1000),
+            sample(&["tiny", "main"], 1),
+        ];
+        let graph = CallGraph::new(&samples);
+        assert_eq!(graph.nodes["fib"].cum, 1000);
+        assert_eq!(graph.edges[&("fib".to_string(), "fib".to_string())], 1000);
+
+        let dot = graph.to_dot("cpu", &DotOptions::default());
+        assert_eq!(
+            dot,
+            "digraph \"cpu\" {\n\
+             node [style=filled fillcolor=\"#f8f8f8\"]\n\
+             subgraph cluster_L { \"cpu\" [shape=box fontsize=16 label=\"cpu\\lShowing nodes accounting for 1008, 99.90% of 1009 total\\l\"] }\n\
+             N1 [label=\"main\\n2 (0.20%)\\nof 1009 (100.00%)\" fontsize=9 shape=box tooltip=\"main (1009)\"]\n\
+             N2 [label=\"fib\\n1000 (99.11%)\" fontsize=24 shape=box tooltip=\"fib (1000)\"]\n\
+             N3 [label=\"leaf\\n6 (0.59%)\" fontsize=10 shape=box tooltip=\"leaf (6)\"]\n\
+             N1 -> N2 [label=\" 1000\" weight=100 penwidth=5]\n\
+             N1 -> N3 [label=\" 6\" weight=1 penwidth=1]\n\
+             N2 -> N2 [label=\" 1000\" weight=100 penwidth=5]\n\
+             }\n"
+        );
+    }
+}
@@ -0,0 +1,310 @@
+use super::executor::QueryExecutor;
+use super::samples::SampleReader;
+use super::TimeRange;
+use crate::adminpb::{DiffReport, FunctionRegression};
+use crate::leader::{self, LeaderElection};
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use prost_types::Timestamp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Samples of a version of a profile type.
+#[derive(Debug, Default)]
+struct VersionSamples {
+    /// Timestamp of the newest sample in milliseconds.
+    last_seen: i64,
+    total: i64,
+    /// Value of the samples with the function on their stack, by function.
+    functions: HashMap<String, i64>,
+}
+
+impl VersionSamples {
+    fn share(&self, function: &str) -> f64 {
+        match self.total {
+            0 => 0.0,
+            total => *self.functions.get(function).unwrap_or(&0) as f64 / total as f64,
+        }
+    }
+}
+
+/// DiffReports periodically compares the latest two versions of the profiled
+/// targets, identified by a label, and keeps a report of the functions whose
+/// cumulative share grew the most for each profile type. Shares are relative
+/// to the samples of each version, so versions handling different load
+/// compare fairly. With leader election, only the leader computes reports;
+/// the other instances serve the ones they computed while they led, if any.
+#[derive(Debug)]
+pub struct DiffReports {
+    reader: SampleReader,
+    executor: Arc<QueryExecutor>,
+    label: String,
+    window: Duration,
+    top: usize,
+    reports: RwLock<Vec<DiffReport>>,
+    /// Reports are only computed on the leader, if set.
+    leader: Option<Arc<LeaderElection>>,
+}
+
+impl DiffReports {
+    /// Creates the reports of the versions in the `label` label.
+    pub fn new(reader: SampleReader, label: String) -> Self {
+        Self {
+            reader,
+            executor: Arc::default(),
+            label,
+            window: Duration::from_secs(60 * 60),
+            top: 20,
+            reports: RwLock::default(),
+            leader: None,
+        }
+    }
+
+    pub fn with_leader_election(mut self, leader: Arc<LeaderElection>) -> Self {
+        self.leader = Some(leader);
+        self
+    }
+
+    /// Reads the samples with the executor, which bounds the concurrency and
+    /// memory of the reports along with the other queries.
+    pub fn with_query_executor(mut self, executor: Arc<QueryExecutor>) -> Self {
+        self.executor = executor;
+        self
+    }
+
+    /// Compares the samples of the last `window`.
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Keeps the `top` regressed functions per report.
+    pub fn with_top(mut self, top: usize) -> Self {
+        self.top = top;
+        self
+    }
+
+    /// Recomputes the reports every `interval`.
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if !leader::should_run(&self.leader) {
+                continue;
+            }
+            if let Err(e) = self.refresh(Utc::now()).await {
+                log::warn!("Failed to compute version diff reports: {:#}", e);
+            }
+        }
+    }
+
+    /// Returns the latest reports, of all profile types if `profile_type` is
+    /// empty.
+    pub fn reports(&self, profile_type: &str) -> Vec<DiffReport> {
+        let reports = self.reports.read().unwrap();
+        reports
+            .iter()
+            .filter(|r| profile_type.is_empty() || r.profile_type == profile_type)
+            .cloned()
+            .collect()
+    }
+
+    /// Computes the reports over the samples of the window before `now`.
+    async fn refresh(&self, now: DateTime<Utc>) -> anyhow::Result<()> {
+        let range = TimeRange {
+            start: (now - TimeDelta::from_std(self.window)?).timestamp_millis(),
+            end: now.timestamp_millis(),
+        };
+        let mut profile_types: BTreeMap<String, HashMap<String, VersionSamples>> = BTreeMap::new();
+        let mut query = self.executor.start().await;
+        for segment in self.reader.segments(range).await? {
+            let samples = self.reader.read_segment(&segment, &[], range).await?;
+            let size = samples.iter().map(|s| s.memory_usage()).sum();
+            query.reserve(size)?;
+            for sample in samples {
+                let Some(version) = sample.labels.get(&self.label).filter(|v| !v.is_empty()) else {
+                    continue;
+                };
+                let versions = profile_types.entry(sample.profile_type).or_default();
+                let entry = versions.entry(version.clone()).or_default();
+                entry.last_seen = entry.last_seen.max(sample.timestamp);
+                entry.total += sample.value;
+                // Recursive functions count once per sample.
+                let functions: HashSet<String> =
+                    sample.stacktrace.iter().map(|f| f.name()).collect();
+                for function in functions {
+                    *entry.functions.entry(function).or_default() += sample.value;
+                }
+            }
+            query.release(size);
+        }
+
+        let generated_at = Timestamp {
+            seconds: now.timestamp(),
+            nanos: now.timestamp_subsec_nanos() as i32,
+        };
+        let reports = profile_types
+            .into_iter()
+            .filter_map(|(profile_type, versions)| {
+                let mut versions: Vec<_> = versions.into_iter().collect();
+                versions.sort_by(|a, b| (b.1.last_seen, &b.0).cmp(&(a.1.last_seen, &a.0)));
+                let [(latest_version, latest), (previous_version, previous), ..] =
+                    versions.as_slice()
+                else {
+                    return None;
+                };
+                let mut regressions: Vec<FunctionRegression> = latest
+                    .functions
+                    .keys()
+                    .map(|function| FunctionRegression {
+                        function: function.clone(),
+                        previous_share: previous.share(function),
+                        latest_share: latest.share(function),
+                    })
+                    .filter(|r| r.latest_share > r.previous_share)
+                    .collect();
+                let growth = |r: &FunctionRegression| r.latest_share - r.previous_share;
+                regressions.sort_by(|a, b| {
+                    growth(b)
+                        .total_cmp(&growth(a))
+                        .then_with(|| a.function.cmp(&b.function))
+                });
+                regressions.truncate(self.top);
+                Some(DiffReport {
+                    profile_type,
+                    latest_version: latest_version.clone(),
+                    previous_version: previous_version.clone(),
+                    generated_at: Some(generated_at),
+                    regressions,
+                })
+            })
+            .collect();
+        *self.reports.write().unwrap() = reports;
+        Ok(())
+    }
+}
+
+/// Builds the router of the status page of the reports.
+pub fn diff_router(reports: Arc<DiffReports>) -> Router {
+    Router::new()
+        .route("/status/diff-reports", get(status_page))
+        .with_state(reports)
+}
+
+/// Renders the latest reports as an HTML page.
+async fn status_page(State(reports): State<Arc<DiffReports>>) -> Response {
+    let mut page = String::from(
+        "<!DOCTYPE html>\n<html><head><title>Version diff reports</title></head><body>\n\
+         <h1>Version diff reports</h1>\n",
+    );
+    let reports = reports.reports("");
+    if reports.is_empty() {
+        page.push_str("<p>No profile type has samples of two versions yet.</p>\n");
+    }
+    for report in reports {
+        let _ = write!(
+            page,
+            "<h2>{}: {} &rarr; {}</h2>\n<table>\n\
+             <tr><th>Function</th><th>Previous</th><th>Latest</th><th>Change</th></tr>\n",
+            escape(&report.profile_type),
+            escape(&report.previous_version),
+            escape(&report.latest_version),
+        );
+        for r in report.regressions {
+            let _ = writeln!(
+                page,
+                "<tr><td>{}</td><td>{:.2}%</td><td>{:.2}%</td><td>+{:.2}%</td></tr>",
+                escape(&r.function),
+                r.previous_share * 100.0,
+                r.latest_share * 100.0,
+                (r.latest_share - r.previous_share) * 100.0,
+            );
+        }
+        page.push_str("</table>\n");
+    }
+    page.push_str("</body></html>\n");
+    ([(header::CONTENT_TYPE, "text/html; charset=utf-8")], page).into_response()
+}
+
+/// Escapes the text for HTML, since function names like C++ templates hold
+/// angle brackets.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalizer::Metastore;
+    use crate::query::samples::tests::segment;
+    use object_store::{memory::InMemory, path::Path, ObjectStore};
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let storage: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let metastore = Arc::new(Metastore::default());
+        let segments = [
+            (
+                "v1",
+                1_000,
+                &[(&["parse", "main"][..], 1), (&["encode", "main"], 3)],
+            ),
+            (
+                "v2",
+                2_000,
+                &[(&["parse", "main"][..], 3), (&["encode", "main"], 1)],
+            ),
+            ("", 3_000, &[(&["idle"][..], 10), (&["idle"], 10)]),
+        ];
+        for (i, (version, timestamp, stacks)) in segments.into_iter().enumerate() {
+            let data = segment(&metastore, version, timestamp, stacks).await;
+            let path = Path::from(format!("date=1970-01-01/{}.parquet", i));
+            storage.put(&path, data.into()).await.unwrap();
+        }
+        let reports = DiffReports::new(SampleReader::new(storage, metastore), "node".into())
+            .with_window(Duration::from_secs(60));
+        reports
+            .refresh(DateTime::from_timestamp_millis(10_000).unwrap())
+            .await
+            .unwrap();
+
+        let all = reports.reports("");
+        assert_eq!(all.len(), 1);
+        let report = &all[0];
+        assert_eq!(
+            (
+                report.latest_version.as_str(),
+                report.previous_version.as_str()
+            ),
+            ("v2", "v1")
+        );
+        assert_eq!(
+            report.regressions,
+            [FunctionRegression {
+                function: "parse".into(),
+                previous_share: 0.25,
+                latest_share: 0.75,
+            }]
+        );
+        assert!(reports.reports("memory:alloc:bytes").is_empty());
+
+        let page = status_page(State(Arc::new(reports))).await;
+        let body = axum::body::to_bytes(page.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("v1 &rarr; v2"));
+        assert!(body.contains("<td>parse</td><td>25.00%</td><td>75.00%</td><td>+50.00%</td>"));
+    }
+}
@@ -0,0 +1,159 @@
+use crate::memory::MemoryUsage;
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tonic::Status;
+
+static QUERIES_IN_FLIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "evprofiler_queries_in_flight",
+        "Number of queries over stored samples currently running."
+    )
+    .unwrap()
+});
+
+static QUERIES_ABORTED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_queries_aborted_total",
+        "Total number of queries aborted because they exceeded their memory budget."
+    )
+    .unwrap()
+});
+
+/// QueryMemoryError is returned when a query needs more memory than its
+/// budget, as ResourceExhausted by the RPCs.
+#[derive(Debug)]
+pub struct QueryMemoryError {
+    pub limit: u64,
+}
+
+impl std::fmt::Display for QueryMemoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "query exceeds its memory budget of {} bytes, narrow its selector or time range",
+            self.limit
+        )
+    }
+}
+
+impl std::error::Error for QueryMemoryError {}
+
+impl From<QueryMemoryError> for Status {
+    fn from(e: QueryMemoryError) -> Self {
+        Status::resource_exhausted(e.to_string())
+    }
+}
+
+/// QueryExecutor bounds the queries over stored samples, so that they don't
+/// starve ingestion: queries beyond the concurrency limit wait for a slot,
+/// and every query accounts the memory of its sample buffers and merged
+/// structures, being aborted once it exceeds its budget.
+#[derive(Debug, Default)]
+pub struct QueryExecutor {
+    slots: Option<Arc<Semaphore>>,
+    max_query_bytes: u64,
+    /// Bytes accounted by the running queries.
+    in_use: Arc<AtomicU64>,
+}
+
+impl QueryExecutor {
+    /// Creates an executor running up to `max_concurrent` queries with up to
+    /// `max_query_bytes` each. Zero disables either limit.
+    pub fn new(max_concurrent: usize, max_query_bytes: u64) -> Self {
+        Self {
+            slots: (max_concurrent > 0).then(|| Arc::new(Semaphore::new(max_concurrent))),
+            max_query_bytes,
+            in_use: Arc::default(),
+        }
+    }
+
+    /// Waits for a query slot.
+    pub async fn start(&self) -> QueryPermit {
+        let permit = match &self.slots {
+            Some(slots) => Arc::clone(slots).acquire_owned().await.ok(),
+            None => None,
+        };
+        QUERIES_IN_FLIGHT.inc();
+        QueryPermit {
+            _permit: permit,
+            limit: self.max_query_bytes,
+            reserved: 0,
+            in_use: Arc::clone(&self.in_use),
+        }
+    }
+}
+
+impl MemoryUsage for QueryExecutor {
+    fn memory_usage(&self) -> u64 {
+        self.in_use.load(Ordering::Relaxed)
+    }
+}
+
+/// QueryPermit holds a query slot and accounts the memory of the query
+/// until it is dropped.
+#[derive(Debug)]
+pub struct QueryPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+    limit: u64,
+    reserved: u64,
+    in_use: Arc<AtomicU64>,
+}
+
+impl QueryPermit {
+    /// Accounts `bytes` more memory for the query, failing if that exceeds
+    /// its budget.
+    pub fn reserve(&mut self, bytes: u64) -> Result<(), QueryMemoryError> {
+        if self.limit > 0 && self.reserved + bytes > self.limit {
+            QUERIES_ABORTED.inc();
+            return Err(QueryMemoryError { limit: self.limit });
+        }
+        self.reserved += bytes;
+        self.in_use.fetch_add(bytes, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Releases `bytes` of the memory accounted for the query.
+    pub fn release(&mut self, bytes: u64) {
+        let bytes = bytes.min(self.reserved);
+        self.reserved -= bytes;
+        self.in_use.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+impl Drop for QueryPermit {
+    fn drop(&mut self) {
+        QUERIES_IN_FLIGHT.dec();
+        self.in_use.fetch_sub(self.reserved, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_query_executor() {
+        let executor = QueryExecutor::new(1, 100);
+        let mut query = executor.start().await;
+        query.reserve(60).unwrap();
+        assert!(query.reserve(60).is_err());
+        query.release(30);
+        query.reserve(60).unwrap();
+        assert_eq!(executor.memory_usage(), 90);
+
+        // The second query waits for the slot of the first one.
+        let waiting = tokio::time::timeout(Duration::from_millis(10), executor.start()).await;
+        assert!(waiting.is_err());
+        drop(query);
+        assert_eq!(executor.memory_usage(), 0);
+        let mut query = executor.start().await;
+        query.reserve(100).unwrap();
+
+        let unlimited = QueryExecutor::default();
+        let mut query = unlimited.start().await;
+        query.reserve(u32::MAX as u64).unwrap();
+    }
+}
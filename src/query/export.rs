@@ -0,0 +1,290 @@
+use super::executor::QueryExecutor;
+use super::samples::{Frame, SampleReader, StoredSample};
+use super::{Selector, TimeRange};
+use arrow2::array::{
+    Array, MapArray, MutableListArray, MutableUtf8Array, PrimitiveArray, StructArray, TryExtend,
+    Utf8Array,
+};
+use arrow2::chunk::Chunk;
+use arrow2::datatypes::{DataType, Field, Schema, TimeUnit};
+use arrow2::io::parquet::write::{
+    transverse, CompressionOptions, Encoding, FileWriter, RowGroupIterator, Version, WriteOptions,
+};
+use arrow2::offset::Offsets;
+use chrono::DateTime;
+use object_store::{path::Path, ObjectStore};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Number of rows after which an exported file is written.
+const ROWS_PER_FILE: usize = 1 << 20;
+
+/// Returns the schema samples are exported with. Each row is a sample with
+/// its labels and its stacktrace as function names, leaf first.
+pub fn schema() -> Schema {
+    let entries = Field::new(
+        "entries",
+        DataType::Struct(vec![
+            Field::new("keys", DataType::Utf8, false),
+            Field::new("values", DataType::Utf8, false),
+        ]),
+        false,
+    );
+    Schema::from(vec![
+        Field::new("profile_type", DataType::Utf8, false),
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Millisecond, None),
+            false,
+        ),
+        Field::new("duration", DataType::Int64, false),
+        Field::new("period", DataType::Int64, false),
+        Field::new("value", DataType::Int64, false),
+        Field::new("labels", DataType::Map(Box::new(entries), false), false),
+        Field::new(
+            "stacktrace",
+            DataType::List(Box::new(Field::new("item", DataType::Utf8, true))),
+            false,
+        ),
+    ])
+}
+
+/// Converts the samples to a record batch of the export [`schema`].
+pub fn chunk(samples: &[StoredSample]) -> anyhow::Result<Chunk<Box<dyn Array>>> {
+    let fields = schema().fields;
+    let int = |f: fn(&StoredSample) -> i64| {
+        PrimitiveArray::from_vec(samples.iter().map(f).collect()).boxed()
+    };
+
+    let offsets = Offsets::<i32>::try_from_lengths(samples.iter().map(|s| s.labels.len()))?;
+    let keys = Utf8Array::<i32>::from_iter_values(samples.iter().flat_map(|s| s.labels.keys()));
+    let values = Utf8Array::<i32>::from_iter_values(samples.iter().flat_map(|s| s.labels.values()));
+    let DataType::Map(entries, _) = fields[5].data_type() else {
+        unreachable!("labels are a map");
+    };
+    let entries = StructArray::new(
+        entries.data_type().clone(),
+        vec![keys.boxed(), values.boxed()],
+        None,
+    );
+    let labels = MapArray::new(
+        fields[5].data_type().clone(),
+        offsets.into(),
+        entries.boxed(),
+        None,
+    );
+
+    let mut stacktraces = MutableListArray::<i32, MutableUtf8Array<i32>>::new();
+    stacktraces.try_extend(samples.iter().map(|s| {
+        Some(
+            s.stacktrace
+                .iter()
+                .map(|f| Some(Frame::name(f)))
+                .collect::<Vec<_>>(),
+        )
+    }))?;
+
+    Ok(Chunk::try_new(vec![
+        Utf8Array::<i32>::from_iter_values(samples.iter().map(|s| &s.profile_type)).boxed(),
+        PrimitiveArray::from_vec(samples.iter().map(|s| s.timestamp).collect())
+            .to(fields[1].data_type().clone())
+            .boxed(),
+        int(|s| s.duration),
+        int(|s| s.period),
+        int(|s| s.value),
+        labels.boxed(),
+        stacktraces.into_box(),
+    ])?)
+}
+
+/// ExportSummary describes the files written by an export.
+#[derive(Debug, Default)]
+pub struct ExportSummary {
+    pub files: Vec<String>,
+    pub samples: u64,
+}
+
+/// ParquetExport writes the stored samples to Parquet files for offline
+/// analysis, partitioned like `date=2024-01-01/profile_type=<type>/` by the
+/// date of the samples and their profile type.
+#[derive(Debug)]
+pub struct ParquetExport {
+    reader: SampleReader,
+    destination: Arc<dyn ObjectStore>,
+    executor: Arc<QueryExecutor>,
+}
+
+impl ParquetExport {
+    /// Creates an export reading samples with `reader` and writing files to
+    /// `destination`.
+    pub fn new(reader: SampleReader, destination: Arc<dyn ObjectStore>) -> Self {
+        Self {
+            reader,
+            destination,
+            executor: Arc::default(),
+        }
+    }
+
+    /// Runs the exports with the executor, which bounds their concurrency
+    /// and the memory of the samples they buffer.
+    pub fn with_query_executor(mut self, executor: Arc<QueryExecutor>) -> Self {
+        self.executor = executor;
+        self
+    }
+
+    /// Exports the samples matching any of the selectors within the time
+    /// range to files under `prefix`. Segments are read one at a time and a
+    /// partition is written out whenever it reaches `ROWS_PER_FILE` rows, so
+    /// the export doesn't hold the whole range in memory. The buffered
+    /// samples are accounted by the executor until they're written.
+    pub async fn run(
+        &self,
+        selectors: &[Selector],
+        range: TimeRange,
+        prefix: &Path,
+    ) -> anyhow::Result<ExportSummary> {
+        let mut summary = ExportSummary::default();
+        let mut partitions: BTreeMap<String, Vec<StoredSample>> = BTreeMap::new();
+        let mut query = self.executor.start().await;
+        let size = |samples: &[StoredSample]| samples.iter().map(|s| s.memory_usage()).sum();
+        for segment in self.reader.segments(range).await? {
+            let samples = self.reader.read_segment(&segment, selectors, range).await?;
+            query.reserve(size(&samples))?;
+            for sample in samples {
+                let partition = partition(&sample);
+                let samples = partitions.entry(partition.clone()).or_default();
+                samples.push(sample);
+                if samples.len() >= ROWS_PER_FILE {
+                    let samples = std::mem::take(samples);
+                    self.write(prefix, &partition, &samples, &mut summary)
+                        .await?;
+                    query.release(size(&samples));
+                }
+            }
+        }
+        for (partition, samples) in partitions {
+            if !samples.is_empty() {
+                self.write(prefix, &partition, &samples, &mut summary)
+                    .await?;
+            }
+        }
+        Ok(summary)
+    }
+
+    async fn write(
+        &self,
+        prefix: &Path,
+        partition: &str,
+        samples: &[StoredSample],
+        summary: &mut ExportSummary,
+    ) -> anyhow::Result<()> {
+        let path = Path::from(format!(
+            "{}/{}/part-{}.parquet",
+            prefix,
+            partition,
+            ulid::Ulid::new()
+        ));
+        let data = encode_parquet(chunk(samples)?)?;
+        self.destination.put(&path, data.into()).await?;
+        log::info!("Exported {} samples to {}", samples.len(), path);
+        summary.files.push(path.to_string());
+        summary.samples += samples.len() as u64;
+        Ok(())
+    }
+}
+
+/// Returns the partition of the sample, by the date of its timestamp and its
+/// profile type.
+fn partition(sample: &StoredSample) -> String {
+    let date = DateTime::from_timestamp_millis(sample.timestamp)
+        .map(|t| t.format("%Y-%m-%d").to_string())
+        .unwrap_or_default();
+    format!("date={}/profile_type={}", date, sample.profile_type)
+}
+
+fn encode_parquet(chunk: Chunk<Box<dyn Array>>) -> anyhow::Result<Vec<u8>> {
+    let schema = schema();
+    let options = WriteOptions {
+        write_statistics: true,
+        compression: CompressionOptions::Snappy,
+        version: Version::V2,
+        data_pagesize_limit: None,
+    };
+    let encodings = schema
+        .fields
+        .iter()
+        .map(|f| transverse(f.data_type(), |_| Encoding::Plain))
+        .collect();
+    let row_groups =
+        RowGroupIterator::try_new(std::iter::once(Ok(chunk)), &schema, options, encodings)?;
+
+    let mut buf = vec![];
+    let mut writer = FileWriter::try_new(&mut buf, schema, options)?;
+    for group in row_groups {
+        writer.write(group?)?;
+    }
+    writer.end(None)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalizer::Metastore;
+    use crate::query::samples::tests::segment;
+    use arrow2::io::parquet::read;
+    use object_store::memory::InMemory;
+    use std::io::Cursor;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_parquet_export() {
+        let storage: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let metastore = Arc::new(Metastore::default());
+        for (i, (node, timestamp)) in [
+            ("api", 1_704_067_200_000),
+            ("api", 1_704_153_600_000),
+            ("db", 1_704_153_600_000),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let data = segment(&metastore, node, timestamp, &[(&["leaf", "main"], 2)]).await;
+            let path = Path::from(format!("date=2024-01-02/{}.parquet", i));
+            storage.put(&path, data.into()).await.unwrap();
+        }
+
+        let destination: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let export = ParquetExport::new(
+            SampleReader::new(storage, metastore),
+            Arc::clone(&destination),
+        );
+        let selector = Selector::parse(r#"{node="api"}"#).unwrap();
+        let summary = export
+            .run(&[selector], TimeRange::default(), &Path::from("exports/1"))
+            .await
+            .unwrap();
+        assert_eq!(summary.samples, 2);
+        assert_eq!(summary.files.len(), 2);
+        assert!(summary.files[0].starts_with(
+            "exports/1/date=2024-01-01/profile_type=process_cpu:samples:count:cpu:nanoseconds:delta/part-"
+        ));
+
+        let files: Vec<_> = destination.list(None).collect().await;
+        assert_eq!(files.len(), 2);
+        let data = destination
+            .get(&Path::from(summary.files[1].as_str()))
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        let mut reader = Cursor::new(data.to_vec());
+        let metadata = read::read_metadata(&mut reader).unwrap();
+        assert_eq!(read::infer_schema(&metadata).unwrap(), schema());
+        let chunks = read::FileReader::new(reader, metadata.row_groups, schema(), None, None, None)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(chunks[0].len(), 1);
+    }
+}
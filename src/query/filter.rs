@@ -0,0 +1,221 @@
+use super::samples::{Frame, StoredSample};
+use regex::Regex;
+use serde::Deserialize;
+
+/// Frames of language runtimes hidden by `hide_runtime`: the Go scheduler
+/// and allocator, the entry points of the C library and the ones of Rust's
+/// standard library.
+const RUNTIME_FRAMES: &str = r"^(runtime\.|runtime/internal/|_rt0_|__libc_start|_start$|__GI_|std::rt::|std::sys::|std::panicking::|std::panic::|core::ops::function::)";
+
+/// How frames are collapsed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Collapse {
+    /// A frame per function.
+    #[default]
+    Function,
+    /// A frame per mapping, consecutive frames of a mapping merged.
+    Module,
+}
+
+/// FrameFilters rewrite the stacks of the samples before they are rendered,
+/// like the flags of pprof with the same names. Regular expressions match
+/// anywhere in the frame names.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct FrameFilters {
+    /// Only samples with a matching frame are kept.
+    pub focus: Option<String>,
+    /// Samples with a matching frame are dropped.
+    pub ignore: Option<String>,
+    /// Matching frames are removed from the stacks.
+    pub hide: Option<String>,
+    /// The callees of the matching frame closest to the root are removed
+    /// from the stacks, so that it becomes the leaf.
+    pub prune_from: Option<String>,
+    /// Frames of language runtimes are removed from the stacks.
+    pub hide_runtime: bool,
+    pub collapse: Collapse,
+    /// Comma separated labels, like `pid,tid`, whose values are added as
+    /// root frames, outermost first, like pprof's `-tagroot`, so that each
+    /// process or thread gets its own flame graph. Samples without a label
+    /// get no frame for it.
+    pub group_by: Option<String>,
+}
+
+impl FrameFilters {
+    /// Applies the filters to the samples. Fails on invalid regular
+    /// expressions.
+    pub fn apply(&self, samples: Vec<StoredSample>) -> anyhow::Result<Vec<StoredSample>> {
+        let compile = |re: &Option<String>| re.as_deref().map(Regex::new).transpose();
+        let focus = compile(&self.focus)?;
+        let ignore = compile(&self.ignore)?;
+        let hide = compile(&self.hide)?;
+        let prune_from = compile(&self.prune_from)?;
+        let runtime = self
+            .hide_runtime
+            .then(|| Regex::new(RUNTIME_FRAMES))
+            .transpose()?;
+        let group_by: Vec<&str> = self
+            .group_by
+            .iter()
+            .flat_map(|labels| labels.split(','))
+            .map(str::trim)
+            .filter(|label| !label.is_empty())
+            .collect();
+
+        let matches = |re: &Regex, sample: &StoredSample| {
+            sample.stacktrace.iter().any(|f| re.is_match(&f.name()))
+        };
+        Ok(samples
+            .into_iter()
+            .filter(|s| focus.as_ref().is_none_or(|re| matches(re, s)))
+            .filter(|s| !ignore.as_ref().is_some_and(|re| matches(re, s)))
+            .map(|mut sample| {
+                // Stacks are leaf first.
+                if let Some(re) = &prune_from {
+                    if let Some(i) = sample
+                        .stacktrace
+                        .iter()
+                        .rposition(|f| re.is_match(&f.name()))
+                    {
+                        sample.stacktrace.drain(..i);
+                    }
+                }
+                for re in hide.iter().chain(runtime.iter()) {
+                    sample.stacktrace.retain(|f| !re.is_match(&f.name()));
+                }
+                if self.collapse == Collapse::Module {
+                    sample.stacktrace = sample.stacktrace.iter().map(module).collect();
+                    sample.stacktrace.dedup();
+                }
+                for label in group_by.iter().rev() {
+                    if let Some(value) = sample.labels.get(*label) {
+                        sample.stacktrace.push(Frame {
+                            function: format!("{}={}", label, value),
+                            ..Default::default()
+                        });
+                    }
+                }
+                sample
+            })
+            .collect())
+    }
+}
+
+/// Returns the frame of the mapping of the frame, named after its file.
+fn module(frame: &Frame) -> Frame {
+    let name = frame.mapping.rsplit('/').next().unwrap_or_default();
+    Frame {
+        function: if name.is_empty() { "[unknown]" } else { name }.to_string(),
+        mapping: frame.mapping.clone(),
+        build_id: frame.build_id.clone(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn sample(frames: &[(&str, &str)]) -> StoredSample {
+        StoredSample {
+            profile_type: String::new(),
+            labels: BTreeMap::new(),
+            timestamp: 0,
+            duration: 0,
+            period: 1,
+            value: 1,
+            stacktrace: frames
+                .iter()
+                .map(|(function, mapping)| Frame {
+                    function: function.to_string(),
+                    mapping: mapping.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+        }
+    }
+
+    fn stacks(filters: FrameFilters, samples: &[StoredSample]) -> Vec<Vec<String>> {
+        filters
+            .apply(samples.to_vec())
+            .unwrap()
+            .iter()
+            .map(|s| s.stacktrace.iter().map(|f| f.name()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_apply() {
+        let samples = [
+            sample(&[
+                ("memcpy", "/lib/libc.so.6"),
+                ("runtime.cgocall", "/bin/api"),
+                ("main.encode", "/bin/api"),
+                ("main.main", "/bin/api"),
+            ]),
+            sample(&[
+                ("runtime.mallocgc", "/bin/api"),
+                ("main.parse", "/bin/api"),
+                ("main.main", "/bin/api"),
+            ]),
+        ];
+        let filters = |f: fn(&mut FrameFilters)| {
+            let mut filters = FrameFilters::default();
+            f(&mut filters);
+            filters
+        };
+
+        assert_eq!(
+            stacks(filters(|f| f.focus = Some("encode".into())), &samples).len(),
+            1
+        );
+        assert_eq!(
+            stacks(filters(|f| f.ignore = Some("^main\\.".into())), &samples).len(),
+            0
+        );
+        assert_eq!(
+            stacks(filters(|f| f.hide_runtime = true), &samples),
+            [
+                vec!["memcpy", "main.encode", "main.main"],
+                vec!["main.parse", "main.main"]
+            ]
+        );
+        assert_eq!(
+            stacks(
+                filters(|f| f.prune_from = Some("^main\\.(encode|parse)$".into())),
+                &samples
+            ),
+            [
+                vec!["main.encode", "main.main"],
+                vec!["main.parse", "main.main"]
+            ]
+        );
+        assert_eq!(
+            stacks(filters(|f| f.hide = Some("main".into())), &samples),
+            [vec!["memcpy", "runtime.cgocall"], vec!["runtime.mallocgc"]]
+        );
+        assert_eq!(
+            stacks(filters(|f| f.collapse = Collapse::Module), &samples),
+            [vec!["libc.so.6", "api"], vec!["api"]]
+        );
+        let mut threads = samples.to_vec();
+        threads[0].labels.insert("pid".into(), "1".into());
+        threads[0].labels.insert("tid".into(), "2".into());
+        assert_eq!(
+            stacks(
+                filters(|f| {
+                    f.group_by = Some("pid, tid".into());
+                    f.collapse = Collapse::Module;
+                }),
+                &threads
+            ),
+            [vec!["libc.so.6", "api", "tid=2", "pid=1"], vec!["api"]]
+        );
+        assert!(filters(|f| f.focus = Some("(".into()))
+            .apply(samples.to_vec())
+            .is_err());
+    }
+}
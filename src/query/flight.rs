@@ -0,0 +1,329 @@
+use super::executor::QueryExecutor;
+use super::samples::SampleReader;
+use super::{export, Selector, TimeRange};
+use crate::flightpb::flight_descriptor::DescriptorType;
+use crate::flightpb::flight_service_server::FlightService;
+use crate::flightpb::{
+    Action, ActionType, Criteria, Empty, FlightData, FlightDescriptor, FlightEndpoint, FlightInfo,
+    HandshakeRequest, HandshakeResponse, PutResult, SchemaResult, Ticket,
+};
+use arrow2::io::flight;
+use serde::Deserialize;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status, Streaming};
+
+/// Maximum number of rows of a record batch.
+const BATCH_ROWS: usize = 64 * 1024;
+
+type FlightStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// ExportCommand is the JSON command of flight descriptors and tickets,
+/// selecting the samples to export.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExportCommand {
+    /// Selector of the series, all series if empty.
+    #[serde(default)]
+    query: String,
+    /// Start of the time range in milliseconds.
+    start: Option<i64>,
+    /// End of the time range in milliseconds.
+    end: Option<i64>,
+}
+
+impl ExportCommand {
+    fn parse(command: &[u8]) -> anyhow::Result<(Vec<Selector>, TimeRange)> {
+        let command: ExportCommand = serde_json::from_slice(command)?;
+        let selectors = match command.query.trim() {
+            "" => vec![],
+            query => vec![Selector::parse(query)?],
+        };
+        let default = TimeRange::default();
+        let range = TimeRange {
+            start: command.start.unwrap_or(default.start),
+            end: command.end.unwrap_or(default.end),
+        };
+        Ok((selectors, range))
+    }
+}
+
+/// Flight serves the stored samples over Arrow Flight, so they can be loaded
+/// into dataframes without a bespoke exporter. Flights are described by a
+/// JSON command like `{"query": "{namespace=\"prod\"}",
+/// "start": 1700000000000, "end": 1700003600000}`, which is also the ticket of
+/// their single endpoint. Each row is a sample with its labels and its
+/// stacktrace as function names, leaf first.
+#[derive(Debug)]
+pub struct Flight {
+    reader: SampleReader,
+    executor: Arc<QueryExecutor>,
+}
+
+impl Flight {
+    pub fn new(reader: SampleReader) -> Self {
+        Self {
+            reader,
+            executor: Arc::default(),
+        }
+    }
+
+    /// Runs the exports with the executor, which bounds their concurrency
+    /// and memory.
+    pub fn with_query_executor(mut self, executor: Arc<QueryExecutor>) -> Self {
+        self.executor = executor;
+        self
+    }
+}
+
+impl From<arrow_format::flight::data::FlightData> for FlightData {
+    fn from(data: arrow_format::flight::data::FlightData) -> Self {
+        FlightData {
+            data_header: data.data_header,
+            data_body: data.data_body,
+            ..Default::default()
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl FlightService for Flight {
+    type HandshakeStream = FlightStream<HandshakeResponse>;
+    type ListFlightsStream = FlightStream<FlightInfo>;
+    type DoGetStream = FlightStream<FlightData>;
+    type DoPutStream = FlightStream<PutResult>;
+    type DoExchangeStream = FlightStream<FlightData>;
+    type DoActionStream = FlightStream<crate::flightpb::Result>;
+    type ListActionsStream = FlightStream<ActionType>;
+
+    async fn handshake(
+        &self,
+        _: Request<Streaming<HandshakeRequest>>,
+    ) -> Result<Response<Self::HandshakeStream>, Status> {
+        Err(Status::unimplemented("Handshake is not implemented"))
+    }
+
+    async fn list_flights(
+        &self,
+        _: Request<Criteria>,
+    ) -> Result<Response<Self::ListFlightsStream>, Status> {
+        Err(Status::unimplemented("ListFlights is not implemented"))
+    }
+
+    async fn get_flight_info(
+        &self,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        let descriptor = request.into_inner();
+        if descriptor.r#type() != DescriptorType::Cmd {
+            return Err(Status::invalid_argument(
+                "only command descriptors are supported",
+            ));
+        }
+        ExportCommand::parse(&descriptor.cmd)
+            .map_err(|e| Status::invalid_argument(format!("invalid command: {}", e)))?;
+        let schema = flight::serialize_schema_to_info(&export::schema(), None)
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(FlightInfo {
+            schema,
+            endpoint: vec![FlightEndpoint {
+                ticket: Some(Ticket {
+                    ticket: descriptor.cmd.clone(),
+                }),
+                ..Default::default()
+            }],
+            flight_descriptor: Some(descriptor),
+            total_records: -1,
+            total_bytes: -1,
+            ordered: false,
+            app_metadata: vec![],
+        }))
+    }
+
+    async fn get_schema(
+        &self,
+        _: Request<FlightDescriptor>,
+    ) -> Result<Response<SchemaResult>, Status> {
+        Ok(Response::new(SchemaResult {
+            schema: flight::serialize_schema_to_result(&export::schema(), None).schema,
+        }))
+    }
+
+    async fn do_get(
+        &self,
+        request: Request<Ticket>,
+    ) -> Result<Response<Self::DoGetStream>, Status> {
+        let (selectors, range) = ExportCommand::parse(&request.into_inner().ticket)
+            .map_err(|e| Status::invalid_argument(format!("invalid ticket: {}", e)))?;
+        let segments = self
+            .reader
+            .segments(range)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let reader = self.reader.clone();
+        let mut query = self.executor.start().await;
+
+        let stream = async_stream::try_stream! {
+            let schema = export::schema();
+            let ipc_fields = flight::default_ipc_fields(&schema.fields);
+            yield flight::serialize_schema(&schema, Some(&ipc_fields)).into();
+
+            // Segments are read one at a time, so the export doesn't hold more
+            // than one segment in memory.
+            for segment in segments {
+                let samples = reader
+                    .read_segment(&segment, &selectors, range)
+                    .await
+                    .map_err(|e| Status::internal(format!("reading {}: {}", segment, e)))?;
+                let size = samples.iter().map(|s| s.memory_usage()).sum();
+                query.reserve(size)?;
+                for samples in samples.chunks(BATCH_ROWS) {
+                    let chunk = export::chunk(samples).map_err(|e| Status::internal(e.to_string()))?;
+                    let options = flight::WriteOptions { compression: None };
+                    let (dictionaries, batch) = flight::serialize_batch(&chunk, &ipc_fields, &options)
+                        .map_err(|e| Status::internal(e.to_string()))?;
+                    for dictionary in dictionaries {
+                        yield dictionary.into();
+                    }
+                    yield batch.into();
+                }
+                query.release(size);
+            }
+        };
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn do_put(
+        &self,
+        _: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoPutStream>, Status> {
+        Err(Status::unimplemented("profiles are written with WriteRaw"))
+    }
+
+    async fn do_exchange(
+        &self,
+        _: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoExchangeStream>, Status> {
+        Err(Status::unimplemented("DoExchange is not implemented"))
+    }
+
+    async fn do_action(
+        &self,
+        _: Request<Action>,
+    ) -> Result<Response<Self::DoActionStream>, Status> {
+        Err(Status::unimplemented("no actions are supported"))
+    }
+
+    async fn list_actions(
+        &self,
+        _: Request<Empty>,
+    ) -> Result<Response<Self::ListActionsStream>, Status> {
+        Ok(Response::new(Box::pin(tokio_stream::empty())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalizer::Metastore;
+    use crate::query::samples::tests::segment;
+    use arrow2::array::{ListArray, Utf8Array};
+    use arrow2::io::ipc::read::{read_stream_metadata, StreamReader, StreamState};
+    use object_store::{memory::InMemory, path::Path, ObjectStore};
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use tokio_stream::StreamExt;
+
+    /// Frames the flight data as an Arrow IPC stream.
+    fn ipc_stream(messages: &[FlightData]) -> Vec<u8> {
+        let mut buf = vec![];
+        for m in messages {
+            let padding = (8 - m.data_header.len() % 8) % 8;
+            buf.extend(u32::MAX.to_le_bytes());
+            buf.extend(((m.data_header.len() + padding) as i32).to_le_bytes());
+            buf.extend(&m.data_header);
+            buf.extend(vec![0; padding]);
+            buf.extend(&m.data_body);
+        }
+        buf.extend(u32::MAX.to_le_bytes());
+        buf.extend(0i32.to_le_bytes());
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_do_get() {
+        let storage: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let metastore = Arc::new(Metastore::default());
+        for (i, node) in ["api", "db"].iter().enumerate() {
+            let data = segment(&metastore, node, 1_000, &[(&["leaf", "main"], 3)]).await;
+            let path = Path::from(format!("date=2024-01-01/{}.parquet", i));
+            storage.put(&path, data.into()).await.unwrap();
+        }
+        let reader = SampleReader::new(storage, metastore);
+        let flight = Flight::new(reader.clone());
+
+        let command = br#"{"query": "{node=\"api\"}", "start": 0}"#.to_vec();
+        let info = flight
+            .get_flight_info(Request::new(FlightDescriptor {
+                r#type: DescriptorType::Cmd as i32,
+                cmd: command.clone(),
+                path: vec![],
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let ticket = info.endpoint[0].ticket.clone().unwrap();
+        assert_eq!(ticket.ticket, command);
+
+        let messages: Vec<FlightData> = flight
+            .do_get(Request::new(ticket))
+            .await
+            .unwrap()
+            .into_inner()
+            .collect::<Result<_, _>>()
+            .await
+            .unwrap();
+        let mut stream = Cursor::new(ipc_stream(&messages));
+        let metadata = read_stream_metadata(&mut stream).unwrap();
+        assert_eq!(metadata.schema, export::schema());
+        let chunks: Vec<_> = StreamReader::new(stream, metadata, None)
+            .map(|state| match state.unwrap() {
+                StreamState::Some(chunk) => chunk,
+                StreamState::Waiting => unreachable!(),
+            })
+            .collect();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].len(), 1);
+        let stacktraces = chunks[0].arrays()[6]
+            .as_any()
+            .downcast_ref::<ListArray<i32>>()
+            .unwrap()
+            .value(0);
+        let frames = stacktraces
+            .as_any()
+            .downcast_ref::<Utf8Array<i32>>()
+            .unwrap();
+        assert_eq!(frames.values_iter().collect::<Vec<_>>(), ["leaf", "main"]);
+
+        let invalid = flight
+            .do_get(Request::new(Ticket {
+                ticket: b"{\"query\": \"{job=api}\"}".to_vec(),
+            }))
+            .await;
+        assert_eq!(invalid.err().unwrap().code(), tonic::Code::InvalidArgument);
+
+        // Exports exceeding the memory budget of the executor are aborted.
+        let flight = Flight::new(reader).with_query_executor(Arc::new(QueryExecutor::new(1, 1)));
+        let messages: Vec<_> = flight
+            .do_get(Request::new(Ticket { ticket: command }))
+            .await
+            .unwrap()
+            .into_inner()
+            .collect()
+            .await;
+        let status = messages.last().unwrap().as_ref().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    }
+}
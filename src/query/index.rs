@@ -0,0 +1,266 @@
+use super::selector::Selector;
+use crate::ingester::{partitions, Manifest};
+use crate::normalizer::NormalizedWriteRawRequest;
+use crate::querypb::ProfileType;
+use object_store::ObjectStore;
+use prometheus::{register_int_counter, IntCounter};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, LazyLock, RwLock};
+
+static DROPPED_SERIES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_series_index_dropped_total",
+        "Total number of series left out of the series index because it was full."
+    )
+    .unwrap()
+});
+
+/// Default maximum number of series of the index.
+const DEFAULT_MAX_SERIES: usize = 1_000_000;
+
+/// A series is identified by its profile type and label set.
+type SeriesKey = (String, BTreeMap<String, String>);
+
+#[derive(Debug)]
+struct SeriesEntry {
+    labels: BTreeMap<String, String>,
+    profile_type: String,
+    min_timestamp: i64,
+    max_timestamp: i64,
+}
+
+/// SeriesIndex keeps track of the series that were ingested, keyed by their
+/// profile type and label set, to answer metadata queries without scanning
+/// the stored profiles. It holds up to a maximum number of series, and the
+/// series written once it is full are left out of it.
+#[derive(Debug)]
+pub struct SeriesIndex {
+    series: RwLock<HashMap<SeriesKey, SeriesEntry>>,
+    profile_types: RwLock<BTreeMap<String, ProfileType>>,
+    max_series: usize,
+}
+
+impl Default for SeriesIndex {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_SERIES)
+    }
+}
+
+/// Time range of a query in milliseconds, both ends inclusive.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeRange {
+    pub start: i64,
+    pub end: i64,
+}
+
+impl Default for TimeRange {
+    fn default() -> Self {
+        Self {
+            start: i64::MIN,
+            end: i64::MAX,
+        }
+    }
+}
+
+impl SeriesIndex {
+    /// Creates an index of up to `max_series` series.
+    pub fn new(max_series: usize) -> Self {
+        Self {
+            series: Default::default(),
+            profile_types: Default::default(),
+            max_series,
+        }
+    }
+
+    /// Adds the series recorded in the manifests of the partitions of the
+    /// storage to the index, so that it covers the profiles stored before
+    /// the process started. Manifests only record the metadata labels of
+    /// the series, and partitions of older builds have none. Returns the
+    /// number of series in the index.
+    pub async fn load(&self, storage: &Arc<dyn ObjectStore>) -> anyhow::Result<usize> {
+        for partition in partitions(storage).await? {
+            let Some(manifest) = Manifest::load(storage, partition.as_ref()).await? else {
+                continue;
+            };
+            for segment in manifest.segments.iter() {
+                for series in segment.series.iter() {
+                    let Some((profile_type, labels)) = parse_series(series) else {
+                        log::debug!("Skipping unparsable series {} of the catalog", series);
+                        continue;
+                    };
+                    self.insert(
+                        profile_type,
+                        labels,
+                        segment.min_timestamp,
+                        segment.max_timestamp,
+                    );
+                }
+            }
+        }
+        Ok(self.series.read().unwrap().len())
+    }
+
+    /// Adds the series of a normalized request to the index.
+    pub fn observe(&self, request: &NormalizedWriteRawRequest) {
+        for s in request.series.iter() {
+            for profile in s.samples.iter().flatten() {
+                let meta = &profile.meta;
+                let profile_type = ProfileType {
+                    name: meta.name.clone(),
+                    sample_type: meta.sample_type.type_.clone(),
+                    sample_unit: meta.sample_type.unit.clone(),
+                    period_type: meta.period_type.type_.clone(),
+                    period_unit: meta.period_type.unit.clone(),
+                    delta: meta.delta,
+                };
+
+                for sample in profile.samples.iter() {
+                    let mut labels: BTreeMap<String, String> = s
+                        .labels
+                        .iter()
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect();
+                    for (k, v) in sample.label.iter() {
+                        labels.entry(k.clone()).or_insert_with(|| v.clone());
+                    }
+
+                    self.insert(profile_type.clone(), labels, meta.timestamp, meta.timestamp);
+                }
+            }
+        }
+    }
+
+    /// Adds the series to the index, or extends its time range if it is
+    /// already indexed.
+    fn insert(
+        &self,
+        profile_type: ProfileType,
+        labels: BTreeMap<String, String>,
+        min_timestamp: i64,
+        max_timestamp: i64,
+    ) {
+        let key = profile_type_key(&profile_type);
+        self.profile_types
+            .write()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert(profile_type);
+        let mut series = self.series.write().unwrap();
+        if let Some(e) = series.get_mut(&(key.clone(), labels.clone())) {
+            e.min_timestamp = e.min_timestamp.min(min_timestamp);
+            e.max_timestamp = e.max_timestamp.max(max_timestamp);
+            return;
+        }
+        if series.len() >= self.max_series {
+            DROPPED_SERIES.inc();
+            return;
+        }
+        series.insert(
+            (key.clone(), labels.clone()),
+            SeriesEntry {
+                labels,
+                profile_type: key,
+                min_timestamp,
+                max_timestamp,
+            },
+        );
+    }
+
+    /// Returns the profile types that were ingested.
+    pub fn profile_types(&self) -> Vec<ProfileType> {
+        self.profile_types
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the sorted label names of the series matching any of the
+    /// selectors within the time range.
+    pub fn label_names(&self, selectors: &[Selector], range: TimeRange) -> Vec<String> {
+        let mut names = BTreeSet::new();
+        self.for_each_match(selectors, range, |e| {
+            names.extend(e.labels.keys().cloned());
+        });
+        names.into_iter().collect()
+    }
+
+    /// Returns the sorted values of the label of the series matching any of
+    /// the selectors within the time range.
+    pub fn label_values(
+        &self,
+        name: &str,
+        selectors: &[Selector],
+        range: TimeRange,
+    ) -> Vec<String> {
+        let mut values = BTreeSet::new();
+        self.for_each_match(selectors, range, |e| {
+            if let Some(v) = e.labels.get(name) {
+                values.insert(v.clone());
+            }
+        });
+        values.into_iter().collect()
+    }
+
+    fn for_each_match(
+        &self,
+        selectors: &[Selector],
+        range: TimeRange,
+        mut f: impl FnMut(&SeriesEntry),
+    ) {
+        let series = self.series.read().unwrap();
+        for entry in series.values() {
+            if entry.max_timestamp < range.start || entry.min_timestamp > range.end {
+                continue;
+            }
+            if !selectors.is_empty()
+                && !selectors
+                    .iter()
+                    .any(|s| s.matches(&entry.profile_type, &entry.labels))
+            {
+                continue;
+            }
+            f(entry);
+        }
+    }
+}
+
+/// Parses a series in its `<profile type>{<labels>}` form, as recorded in
+/// segment manifests.
+fn parse_series(series: &str) -> Option<(ProfileType, BTreeMap<String, String>)> {
+    let selector = Selector::parse(series).ok()?;
+    let profile_type = parse_profile_type(selector.profile_type.as_deref()?)?;
+    Some((profile_type, selector.labels()?))
+}
+
+/// Parses a profile type in its string form, see [`profile_type_key`].
+fn parse_profile_type(key: &str) -> Option<ProfileType> {
+    let parts: Vec<&str> = key.split(':').collect();
+    let delta = match parts.len() {
+        5 => false,
+        6 if parts[5] == "delta" => true,
+        _ => return None,
+    };
+    Some(ProfileType {
+        name: parts[0].into(),
+        sample_type: parts[1].into(),
+        sample_unit: parts[2].into(),
+        period_type: parts[3].into(),
+        period_unit: parts[4].into(),
+        delta,
+    })
+}
+
+/// Returns the profile type in its `name:sample_type:sample_unit:period_type:period_unit[:delta]`
+/// string form, as used in query selectors.
+pub fn profile_type_key(pt: &ProfileType) -> String {
+    let mut key = format!(
+        "{}:{}:{}:{}:{}",
+        pt.name, pt.sample_type, pt.sample_unit, pt.period_type, pt.period_unit
+    );
+    if pt.delta {
+        key.push_str(":delta");
+    }
+    key
+}
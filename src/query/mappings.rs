@@ -0,0 +1,99 @@
+use super::samples::StoredSample;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+/// Values of the samples in a mapping.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct MappingValues {
+    /// File name of the mapping, like `/usr/lib/libc.so.6`.
+    pub mapping: String,
+    pub build_id: String,
+    /// Value of the samples whose leaf is in the mapping.
+    pub flat: i64,
+    /// Value of the samples with any frame in the mapping.
+    pub cumulative: i64,
+}
+
+/// MappingReport aggregates the samples by the binary or shared object their
+/// frames are in rather than by function, which doesn't need the frames to
+/// be symbolized.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct MappingReport {
+    pub total: i64,
+    /// Mappings by decreasing cumulative value.
+    pub mappings: Vec<MappingValues>,
+}
+
+impl MappingReport {
+    pub fn new(samples: &[StoredSample]) -> Self {
+        let mut total = 0;
+        let mut mappings: HashMap<(&str, &str), MappingValues> = HashMap::new();
+        for sample in samples {
+            total += sample.value;
+            // Frames of a mapping count once per sample.
+            let mut seen = HashSet::new();
+            for (i, frame) in sample.stacktrace.iter().enumerate() {
+                let key = (frame.mapping.as_str(), frame.build_id.as_str());
+                let values = mappings.entry(key).or_insert_with(|| MappingValues {
+                    mapping: frame.mapping.clone(),
+                    build_id: frame.build_id.clone(),
+                    ..Default::default()
+                });
+                if i == 0 {
+                    values.flat += sample.value;
+                }
+                if seen.insert(key) {
+                    values.cumulative += sample.value;
+                }
+            }
+        }
+        let mut mappings: Vec<MappingValues> = mappings.into_values().collect();
+        mappings.sort_by(|a, b| {
+            (b.cumulative, b.flat)
+                .cmp(&(a.cumulative, a.flat))
+                .then_with(|| (&a.mapping, &a.build_id).cmp(&(&b.mapping, &b.build_id)))
+        });
+        Self { total, mappings }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::samples::Frame;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_mapping_report() {
+        let frame = |mapping: &str| Frame {
+            address: 0x10,
+            mapping: mapping.into(),
+            build_id: format!("{}-id", mapping),
+            ..Default::default()
+        };
+        let sample = |stacktrace, value| StoredSample {
+            profile_type: String::new(),
+            labels: BTreeMap::new(),
+            timestamp: 0,
+            duration: 0,
+            period: 1,
+            value,
+            stacktrace,
+        };
+        let samples = [
+            sample(vec![frame("libc"), frame("api"), frame("api")], 5),
+            sample(vec![frame("api")], 2),
+            sample(vec![frame("libssl"), frame("libc"), frame("api")], 1),
+        ];
+
+        let report = MappingReport::new(&samples);
+        assert_eq!(report.total, 8);
+        let values: Vec<_> = report
+            .mappings
+            .iter()
+            .map(|m| (m.mapping.as_str(), m.flat, m.cumulative))
+            .collect();
+        assert_eq!(values, [("api", 2, 8), ("libc", 5, 6), ("libssl", 1, 1)]);
+        assert_eq!(report.mappings[0].build_id, "api-id");
+    }
+}
@@ -0,0 +1,477 @@
+mod cache;
+mod callgraph;
+mod diff;
+mod executor;
+mod export;
+mod filter;
+mod flight;
+mod index;
+mod mappings;
+mod render;
+pub(crate) mod samples;
+mod selector;
+mod source;
+mod speedscope;
+mod stats;
+mod stream;
+mod traces;
+
+use crate::querypb::query_request::{Mode, Options, ReportType};
+use crate::querypb::query_response::Report;
+use crate::querypb::query_service_server::QueryService;
+use crate::querypb::{
+    LabelsRequest, LabelsResponse, ProfileTypesRequest, ProfileTypesResponse, QueryRangeRequest,
+    QueryRangeResponse, QueryRequest, QueryResponse, QueryStreamResponse, SeriesRequest,
+    SeriesResponse, ShareProfileRequest, ShareProfileResponse, Source, ValuesRequest,
+    ValuesResponse,
+};
+pub use cache::QueryCache;
+pub use diff::{diff_router, DiffReports};
+pub use executor::{QueryExecutor, QueryMemoryError};
+pub use export::ParquetExport;
+pub use flight::Flight;
+pub use index::{SeriesIndex, TimeRange};
+use prost_types::Timestamp;
+pub use render::render_router;
+pub(crate) use samples::decode_chunk;
+pub use samples::{SampleReader, StoredSample};
+pub use selector::Selector;
+pub use source::Sources;
+use source::{SourceLines, SourceReport};
+pub use stats::IngestionStats;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status};
+pub use traces::{TraceIndex, Traces};
+
+/// Query serves the query API. Only the metadata RPCs used to populate
+/// selectors in UIs and the source report are implemented. QueryStream sends
+/// the reports of Query in chunks: record batches of at most
+/// [`stream::BATCH_ROWS`] rows and content of at most
+/// [`stream::CONTENT_BYTES`] bytes, so that large reports don't hit the
+/// message size limits.
+#[derive(Debug)]
+pub struct Query {
+    index: Arc<SeriesIndex>,
+    source_report: Option<SourceReport>,
+    executor: Arc<QueryExecutor>,
+}
+
+impl Query {
+    pub fn new(index: Arc<SeriesIndex>) -> Self {
+        Self {
+            index,
+            source_report: None,
+            executor: Arc::default(),
+        }
+    }
+
+    /// Serves source reports of the samples of `reader`, annotating the
+    /// files found by `sources`.
+    pub fn with_source_report(mut self, reader: SampleReader, sources: Arc<Sources>) -> Self {
+        self.source_report = Some(SourceReport { reader, sources });
+        self
+    }
+
+    /// Runs the reports over stored samples with the executor, which bounds
+    /// their concurrency and memory.
+    pub fn with_query_executor(mut self, executor: Arc<QueryExecutor>) -> Self {
+        self.executor = executor;
+        self
+    }
+}
+
+pub(crate) fn parse_selectors(
+    matchers: &[String],
+    profile_type: Option<&str>,
+) -> Result<Vec<Selector>, Status> {
+    let mut selectors = matchers
+        .iter()
+        .map(|m| Selector::parse(m).map_err(|e| Status::invalid_argument(e.to_string())))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if let Some(pt) = profile_type.filter(|pt| !pt.is_empty()) {
+        if selectors.is_empty() {
+            selectors.push(Selector::default());
+        }
+        for s in selectors.iter_mut() {
+            s.profile_type = Some(pt.to_string());
+        }
+    }
+    Ok(selectors)
+}
+
+pub(crate) fn time_range(start: Option<Timestamp>, end: Option<Timestamp>) -> TimeRange {
+    let millis = |t: Timestamp| t.seconds * 1000 + t.nanos as i64 / 1_000_000;
+    let default = TimeRange::default();
+    TimeRange {
+        start: start.map_or(default.start, millis),
+        end: end.map_or(default.end, millis),
+    }
+}
+
+/// SourceRequest is a validated query of the source report, the only
+/// report Query and QueryStream implement. QueryStream encodes the record
+/// batches of the values of the lines as they're sent, instead of splitting
+/// the record of the whole report.
+struct SourceRequest<'a> {
+    report: &'a SourceReport,
+    build_id: String,
+    filename: String,
+    source_only: bool,
+    selector: Selector,
+    range: TimeRange,
+}
+
+impl<'a> SourceRequest<'a> {
+    fn parse(mut request: QueryRequest, report: Option<&'a SourceReport>) -> Result<Self, Status> {
+        if request.report_type() != ReportType::Source {
+            return Err(Status::unimplemented(
+                "only the source report is implemented",
+            ));
+        }
+        let report =
+            report.ok_or_else(|| Status::failed_precondition("source reports are disabled"))?;
+        let reference = request
+            .source_reference
+            .take()
+            .ok_or_else(|| Status::invalid_argument("source_reference is required"))?;
+        if reference.filename.is_empty() {
+            return Err(Status::invalid_argument(
+                "source_reference.filename is required",
+            ));
+        }
+        let (query, range) = match (request.mode(), request.options) {
+            (Mode::Merge, Some(Options::Merge(merge))) => {
+                (merge.query, time_range(merge.start, merge.end))
+            }
+            (Mode::SingleUnspecified, Some(Options::Single(single))) => {
+                (single.query, time_range(single.time, single.time))
+            }
+            (Mode::Diff, _) => {
+                return Err(Status::unimplemented("diff queries are not implemented"))
+            }
+            _ => return Err(Status::invalid_argument("options don't match the mode")),
+        };
+        let selector = parse_selectors(&[query], None)?.swap_remove(0);
+        if selector.profile_type.is_none() {
+            return Err(Status::invalid_argument("query must select a profile type"));
+        }
+        Ok(Self {
+            report,
+            build_id: reference.build_id,
+            filename: reference.filename,
+            source_only: reference.source_only,
+            selector,
+            range,
+        })
+    }
+
+    /// Returns the source file with the values of its lines.
+    async fn lines(self, executor: &QueryExecutor) -> Result<SourceLines, Status> {
+        self.report
+            .lines(
+                &self.build_id,
+                &self.filename,
+                self.source_only,
+                self.selector,
+                self.range,
+                executor,
+            )
+            .await
+            .map_err(|e| match e.downcast::<QueryMemoryError>() {
+                Ok(e) => e.into(),
+                Err(e) => Status::internal(format!("source report failed: {:#}", e)),
+            })?
+            .ok_or_else(|| Status::not_found(format!("source file {} not found", self.filename)))
+    }
+}
+
+#[tonic::async_trait]
+impl QueryService for Query {
+    type QueryStreamStream =
+        Pin<Box<dyn Stream<Item = Result<QueryStreamResponse, Status>> + Send>>;
+
+    async fn query_range(
+        &self,
+        _: Request<QueryRangeRequest>,
+    ) -> Result<Response<QueryRangeResponse>, Status> {
+        Err(Status::unimplemented("QueryRange is not implemented"))
+    }
+
+    async fn query(
+        &self,
+        request: Request<QueryRequest>,
+    ) -> Result<Response<QueryResponse>, Status> {
+        let report = SourceRequest::parse(request.into_inner(), self.source_report.as_ref())?
+            .lines(&self.executor)
+            .await?;
+        let record = match &report.lines {
+            Some(lines) => source::encode_record(lines)
+                .map_err(|e| Status::internal(format!("encoding the record: {}", e)))?,
+            None => vec![],
+        };
+        Ok(Response::new(QueryResponse {
+            report: Some(Report::Source(Source {
+                record,
+                source: report.source,
+                unit: report.unit,
+            })),
+            total: 0,
+            filtered: 0,
+        }))
+    }
+
+    async fn query_stream(
+        &self,
+        request: Request<QueryRequest>,
+    ) -> Result<Response<Self::QueryStreamStream>, Status> {
+        let report = SourceRequest::parse(request.into_inner(), self.source_report.as_ref())?
+            .lines(&self.executor)
+            .await?;
+        Ok(Response::new(Box::pin(stream::source_report(
+            report,
+            stream::BATCH_ROWS,
+        ))))
+    }
+
+    async fn series(&self, _: Request<SeriesRequest>) -> Result<Response<SeriesResponse>, Status> {
+        Err(Status::unimplemented("Series is not implemented"))
+    }
+
+    async fn profile_types(
+        &self,
+        _: Request<ProfileTypesRequest>,
+    ) -> Result<Response<ProfileTypesResponse>, Status> {
+        Ok(Response::new(ProfileTypesResponse {
+            types: self.index.profile_types(),
+        }))
+    }
+
+    async fn labels(
+        &self,
+        request: Request<LabelsRequest>,
+    ) -> Result<Response<LabelsResponse>, Status> {
+        let request = request.into_inner();
+        let selectors = parse_selectors(&request.r#match, request.profile_type.as_deref())?;
+
+        Ok(Response::new(LabelsResponse {
+            label_names: self
+                .index
+                .label_names(&selectors, time_range(request.start, request.end)),
+            warnings: vec![],
+        }))
+    }
+
+    async fn values(
+        &self,
+        request: Request<ValuesRequest>,
+    ) -> Result<Response<ValuesResponse>, Status> {
+        let request = request.into_inner();
+        if request.label_name.is_empty() {
+            return Err(Status::invalid_argument("label_name is required"));
+        }
+        let selectors = parse_selectors(&request.r#match, request.profile_type.as_deref())?;
+
+        Ok(Response::new(ValuesResponse {
+            label_values: self.index.label_values(
+                &request.label_name,
+                &selectors,
+                time_range(request.start, request.end),
+            ),
+            warnings: vec![],
+        }))
+    }
+
+    async fn share_profile(
+        &self,
+        _: Request<ShareProfileRequest>,
+    ) -> Result<Response<ShareProfileResponse>, Status> {
+        Err(Status::unimplemented("ShareProfile is not implemented"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalizer::write_raw::tests::{labels_map, profile, sample, RequestBuilder};
+    use crate::normalizer::{Metastore, NormalizedWriteRawRequest};
+    use crate::querypb::query_stream_response::Chunk;
+    use crate::querypb::{MergeProfile, SourceReference};
+    use object_store::memory::InMemory;
+    use tokio_stream::StreamExt;
+
+    fn request(job: &str, timestamp: i64) -> NormalizedWriteRawRequest {
+        let mut sample = sample(vec![], 1);
+        sample.label = labels_map(&[("thread", "main")]);
+        RequestBuilder::default()
+            .with_series(&[("job", job)], vec![profile(timestamp, vec![sample])])
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_labels_and_values() {
+        let index = Arc::new(SeriesIndex::default());
+        index.observe(&request("api", 1_000));
+        index.observe(&request("db", 5_000));
+        let query = Query::new(index);
+
+        let types = query
+            .profile_types(Request::new(ProfileTypesRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .types;
+        assert_eq!(types.len(), 1);
+        assert!(types[0].delta);
+
+        let labels = query
+            .labels(Request::new(LabelsRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(labels.label_names, vec!["job", "thread"]);
+
+        let values = query
+            .values(Request::new(ValuesRequest {
+                label_name: "job".into(),
+                r#match: vec![r#"{job=~"a.*|d.*"}"#.into()],
+                start: Some(Timestamp {
+                    seconds: 2,
+                    nanos: 0,
+                }),
+                profile_type: Some("process_cpu:samples:count:cpu:nanoseconds:delta".into()),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(values.label_values, vec!["db"]);
+
+        let values = query
+            .values(Request::new(ValuesRequest {
+                label_name: "job".into(),
+                profile_type: Some("memory:alloc_space:bytes:space:bytes".into()),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(values.label_values.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_series_index_load() {
+        let storage: Arc<dyn object_store::ObjectStore> = Arc::new(InMemory::new());
+        crate::ingester::Manifest::update(&storage, "date=2024-01-01", true, |m| {
+            m.segments.push(crate::ingester::SegmentEntry {
+                path: "date=2024-01-01/a.parquet".into(),
+                series: vec![
+                    r#"process_cpu:samples:count:cpu:nanoseconds:delta{node="a"}"#.into(),
+                    r#"memory:alloc_space:bytes:space:bytes{node="b"}"#.into(),
+                ],
+                min_timestamp: 1_000,
+                max_timestamp: 2_000,
+                size: 1,
+            })
+        })
+        .await
+        .unwrap();
+
+        let index = SeriesIndex::new(1);
+        assert_eq!(index.load(&storage).await.unwrap(), 1);
+        let mut types: Vec<String> = index
+            .profile_types()
+            .iter()
+            .map(index::profile_type_key)
+            .collect();
+        types.sort();
+        assert_eq!(
+            types,
+            vec![
+                "memory:alloc_space:bytes:space:bytes",
+                "process_cpu:samples:count:cpu:nanoseconds:delta"
+            ]
+        );
+        let range = TimeRange {
+            start: 1_500,
+            end: 3_000,
+        };
+        assert_eq!(index.label_values("node", &[], range), vec!["a"]);
+        // The index is full, so new series are left out of it.
+        index.observe(&request("api", 1_000));
+        assert!(index
+            .label_values("job", &[], TimeRange::default())
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_query_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = "// main\n".repeat(stream::CONTENT_BYTES / 8 + 1);
+        std::fs::write(dir.path().join("main.rs"), &source).unwrap();
+        let storage: Arc<dyn object_store::ObjectStore> = Arc::new(InMemory::new());
+        let metastore = Arc::new(Metastore::default());
+        let segment = samples::tests::segment(
+            &metastore,
+            "api",
+            1_704_067_200_000,
+            &[(&["leaf", "main"], 5), (&["main"], 2)],
+        )
+        .await;
+        storage
+            .put(&"date=2024-01-01/1.parquet".into(), segment.into())
+            .await
+            .unwrap();
+        let reader = SampleReader::new(storage, metastore);
+        let sources = Arc::new(Sources::new(vec![dir.path().into()]));
+        let query = Query::new(Arc::default()).with_source_report(reader, sources);
+
+        let request = QueryRequest {
+            mode: Mode::Merge.into(),
+            report_type: ReportType::Source.into(),
+            options: Some(Options::Merge(MergeProfile {
+                query: "process_cpu:samples:count:cpu:nanoseconds:delta{}".into(),
+                ..Default::default()
+            })),
+            source_reference: Some(SourceReference {
+                filename: "main.rs".into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let chunks: Vec<Chunk> = query
+            .query_stream(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner()
+            .map(|m| m.unwrap().chunk.unwrap())
+            .collect()
+            .await;
+        let Some(Chunk::Header(QueryResponse {
+            report: Some(Report::Source(header)),
+            ..
+        })) = chunks.first()
+        else {
+            panic!("missing header");
+        };
+        assert!(header.source.is_empty() && header.record.is_empty());
+        assert_eq!(header.unit, "count");
+        // The values of the lines of main.rs are sent in a record batch.
+        let Chunk::RecordBatch(batch) = &chunks[1] else {
+            panic!("missing record batch");
+        };
+        let [_, flat, cumulative] = stream::tests::columns(batch);
+        assert_eq!((flat, cumulative), (vec![2], vec![7]));
+        let content: Vec<u8> = chunks[2..]
+            .iter()
+            .flat_map(|c| match c {
+                Chunk::Content(content) => content.clone(),
+                _ => panic!("unexpected chunk {:?}", c),
+            })
+            .collect();
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(content, source.as_bytes());
+    }
+}
@@ -0,0 +1,586 @@
+use super::cache::{QueryCache, Rendered};
+use super::callgraph::{CallGraph, DotOptions};
+use super::executor::{QueryExecutor, QueryMemoryError};
+use super::filter::FrameFilters;
+use super::mappings::MappingReport;
+use super::samples::{Frame, SampleReader, StoredSample};
+use super::{speedscope, Selector, TimeRange};
+use crate::pprofpb;
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use prost::Message;
+use serde::Deserialize;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// RenderParams select the samples merged into a rendered profile.
+#[derive(Debug, Deserialize)]
+struct RenderParams {
+    /// Selector of the series, which must select a single profile type,
+    /// like `process_cpu:samples:count:cpu:nanoseconds:delta{job="api"}`.
+    query: String,
+    /// Start of the time range in milliseconds.
+    start: Option<i64>,
+    /// End of the time range in milliseconds.
+    end: Option<i64>,
+}
+
+type RenderError = (StatusCode, String);
+
+/// State of the render handlers.
+#[derive(Debug, Clone)]
+struct RenderState {
+    reader: SampleReader,
+    cache: Arc<QueryCache>,
+    executor: Arc<QueryExecutor>,
+}
+
+/// Builds the router rendering the stored samples as merged profiles for
+/// other tools:
+///
+/// - `/pprof/profile` serves gzipped pprof, for
+///   `go tool pprof -http=: 'http://host/pprof/profile?query=...'`.
+/// - `/render/speedscope` serves Speedscope JSON.
+/// - `/render/folded` serves folded stacks, for `flamegraph.pl`.
+/// - `/render/dot` serves the call graph in GraphViz DOT, pruned with the
+///   `nodecount`, `nodefraction` and `edgefraction` parameters of
+///   `pprof -dot`.
+/// - `/render/mappings` serves the values of the binaries and shared
+///   objects as JSON, which doesn't need the frames to be symbolized.
+///
+/// The stacks of all of them are rewritten with the `focus`, `ignore`,
+/// `hide`, `prune_from`, `hide_runtime` and `collapse` parameters, and
+/// grouped by process or thread with `group_by=pid,tid`, see
+/// [`FrameFilters`]. Rendered reports are kept in the cache until segments
+/// overlapping their time range are written. Others are rendered by the
+/// executor, and fail with 429 Too Many Requests beyond the memory budget of
+/// queries.
+pub fn render_router(
+    reader: SampleReader,
+    cache: Arc<QueryCache>,
+    executor: Arc<QueryExecutor>,
+) -> Router {
+    Router::new()
+        .route("/pprof/profile", get(pprof_profile))
+        .route("/render/speedscope", get(speedscope))
+        .route("/render/folded", get(folded))
+        .route("/render/dot", get(dot))
+        .route("/render/mappings", get(mappings))
+        .with_state(RenderState {
+            reader,
+            cache,
+            executor,
+        })
+}
+
+/// Renders the samples selected by the params, with the filters applied,
+/// with `f` called with their profile type, or returns the cached report.
+/// The key of the report must cover its options besides the params and
+/// filters.
+async fn render(
+    state: &RenderState,
+    report: String,
+    params: &RenderParams,
+    filters: &FrameFilters,
+    f: impl FnOnce(&str, &[StoredSample]) -> Result<Rendered, RenderError>,
+) -> Result<Response, RenderError> {
+    let bad_request = |e: String| (StatusCode::BAD_REQUEST, e);
+    let selector =
+        Selector::parse(&params.query).map_err(|e| bad_request(format!("invalid query: {}", e)))?;
+    let Some(profile_type) = selector.profile_type.clone() else {
+        return Err(bad_request(
+            "query must select a profile type, like process_cpu:samples:count:cpu:nanoseconds:delta{...}".into(),
+        ));
+    };
+    let default = TimeRange::default();
+    let range = TimeRange {
+        start: params.start.unwrap_or(default.start),
+        end: params.end.unwrap_or(default.end),
+    };
+
+    let internal = |e: anyhow::Error| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e));
+    let reader = &state.reader;
+    let segments = reader.segments(range).await.map_err(internal)?;
+    let key = format!("{} {:?} {:?}", report, params, filters);
+    if let Some(rendered) = state.cache.get(&key, &segments) {
+        return Ok(rendered.into_response());
+    }
+    let exhausted = |e: QueryMemoryError| (StatusCode::TOO_MANY_REQUESTS, e.to_string());
+    let mut query = state.executor.start().await;
+    let selectors = [selector];
+    let mut samples = vec![];
+    for segment in segments.iter() {
+        let segment_samples = reader
+            .read_segment(segment, &selectors, range)
+            .await
+            .map_err(internal)?;
+        query
+            .reserve(segment_samples.iter().map(|s| s.memory_usage()).sum())
+            .map_err(exhausted)?;
+        samples.extend(segment_samples);
+    }
+    let samples = filters
+        .apply(samples)
+        .map_err(|e| bad_request(format!("invalid filter: {}", e)))?;
+    let rendered = f(&profile_type, &samples)?;
+    query
+        .reserve(rendered.body.len() as u64)
+        .map_err(exhausted)?;
+    state.cache.insert(key, segments, rendered.clone());
+    Ok(rendered.into_response())
+}
+
+/// Returns the rendered JSON of the value.
+fn json(value: &impl serde::Serialize) -> Result<Rendered, RenderError> {
+    let body = serde_json::to_vec(value)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Rendered {
+        headers: vec![(header::CONTENT_TYPE, "application/json")],
+        body: body.into(),
+    })
+}
+
+/// Serves the selected samples as a single gzipped pprof profile.
+async fn pprof_profile(
+    State(state): State<RenderState>,
+    Query(params): Query<RenderParams>,
+    Query(filters): Query<FrameFilters>,
+) -> Result<Response, RenderError> {
+    render(
+        &state,
+        "pprof".into(),
+        &params,
+        &filters,
+        |profile_type, samples| {
+            let profile = to_pprof(profile_type, samples);
+            let data = crate::backfill::compress(&profile.encode_to_vec())
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            Ok(Rendered {
+                headers: vec![
+                    (header::CONTENT_TYPE, "application/octet-stream"),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        "attachment; filename=\"profile.pb.gz\"",
+                    ),
+                ],
+                body: data.into(),
+            })
+        },
+    )
+    .await
+}
+
+/// Serves the selected samples as Speedscope JSON, which can be dropped
+/// into speedscope.app as is.
+async fn speedscope(
+    State(state): State<RenderState>,
+    Query(params): Query<RenderParams>,
+    Query(filters): Query<FrameFilters>,
+) -> Result<Response, RenderError> {
+    render(
+        &state,
+        "speedscope".into(),
+        &params,
+        &filters,
+        |profile_type, samples| {
+            let mut rendered = json(&speedscope::to_speedscope(profile_type, samples))?;
+            rendered.headers.push((
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"profile.speedscope.json\"",
+            ));
+            Ok(rendered)
+        },
+    )
+    .await
+}
+
+/// Serves the selected samples as folded stacks.
+async fn folded(
+    State(state): State<RenderState>,
+    Query(params): Query<RenderParams>,
+    Query(filters): Query<FrameFilters>,
+) -> Result<Response, RenderError> {
+    render(&state, "folded".into(), &params, &filters, |_, samples| {
+        Ok(Rendered {
+            headers: vec![(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            body: to_folded(samples).into(),
+        })
+    })
+    .await
+}
+
+/// Serves the call graph of the selected samples in GraphViz DOT.
+async fn dot(
+    State(state): State<RenderState>,
+    Query(params): Query<RenderParams>,
+    Query(filters): Query<FrameFilters>,
+    Query(options): Query<DotOptions>,
+) -> Result<Response, RenderError> {
+    let report = format!("dot {:?}", options);
+    render(
+        &state,
+        report,
+        &params,
+        &filters,
+        |profile_type, samples| {
+            Ok(Rendered {
+                headers: vec![(header::CONTENT_TYPE, "text/vnd.graphviz")],
+                body: CallGraph::new(samples)
+                    .to_dot(profile_type, &options)
+                    .into(),
+            })
+        },
+    )
+    .await
+}
+
+/// Serves the values of the mappings of the selected samples, by
+/// decreasing cumulative value.
+async fn mappings(
+    State(state): State<RenderState>,
+    Query(params): Query<RenderParams>,
+    Query(filters): Query<FrameFilters>,
+) -> Result<Response, RenderError> {
+    render(
+        &state,
+        "mappings".into(),
+        &params,
+        &filters,
+        |_, samples| json(&MappingReport::new(samples)),
+    )
+    .await
+}
+
+/// Returns the samples in the folded stack format of Brendan Gregg's
+/// FlameGraph tools: a line per stack with its frames, root first, separated
+/// by semicolons and followed by the summed value. Lines are sorted by stack.
+pub(crate) fn to_folded(samples: &[StoredSample]) -> String {
+    let mut stacks: BTreeMap<String, i64> = BTreeMap::new();
+    for sample in samples {
+        let stack = sample
+            .stacktrace
+            .iter()
+            .rev()
+            // Semicolons separate frames, and newlines lines.
+            .map(|frame| frame.name().replace([';', '\n'], "_"))
+            .collect::<Vec<_>>()
+            .join(";");
+        *stacks.entry(stack).or_default() += sample.value;
+    }
+    let mut folded = String::new();
+    for (stack, value) in stacks {
+        let _ = writeln!(folded, "{} {}", stack, value);
+    }
+    folded
+}
+
+/// StringTable interns the strings of a pprof profile.
+#[derive(Debug)]
+struct StringTable {
+    strings: Vec<String>,
+    indices: HashMap<String, i64>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        Self {
+            strings: vec![String::new()],
+            indices: HashMap::from([(String::new(), 0)]),
+        }
+    }
+
+    fn get(&mut self, s: &str) -> i64 {
+        if let Some(&index) = self.indices.get(s) {
+            return index;
+        }
+        let index = self.strings.len() as i64;
+        self.strings.push(s.to_string());
+        self.indices.insert(s.to_string(), index);
+        index
+    }
+}
+
+/// Merges the samples of the profile type, in its selector form, into a
+/// pprof profile. Every distinct frame is its own location, and samples
+/// with the same stacktrace and labels are summed.
+pub(crate) fn to_pprof(profile_type: &str, samples: &[StoredSample]) -> pprofpb::Profile {
+    let mut strings = StringTable::new();
+    let mut profile = pprofpb::Profile::default();
+    // Profile types are `name:sample_type:sample_unit:period_type:period_unit`.
+    let parts: Vec<&str> = profile_type.split(':').collect();
+    let part = |i: usize| parts.get(i).copied().unwrap_or_default();
+    profile.sample_type = vec![pprofpb::ValueType {
+        r#type: strings.get(part(1)),
+        unit: strings.get(part(2)),
+    }];
+    profile.period_type = Some(pprofpb::ValueType {
+        r#type: strings.get(part(3)),
+        unit: strings.get(part(4)),
+    });
+
+    let mut mappings: HashMap<(&str, &str), u64> = HashMap::new();
+    let mut functions: HashMap<(&str, &str), u64> = HashMap::new();
+    let mut locations: HashMap<&Frame, u64> = HashMap::new();
+    let mut merged: HashMap<(Vec<u64>, &BTreeMap<String, String>), usize> = HashMap::new();
+    for sample in samples {
+        let location_ids: Vec<u64> = sample
+            .stacktrace
+            .iter()
+            .map(|frame| {
+                if let Some(&id) = locations.get(frame) {
+                    return id;
+                }
+                let mapping_id = match mappings.get(&(frame.mapping.as_str(), &frame.build_id)) {
+                    Some(&id) => id,
+                    None => {
+                        let id = profile.mapping.len() as u64 + 1;
+                        profile.mapping.push(pprofpb::Mapping {
+                            id,
+                            filename: strings.get(&frame.mapping),
+                            build_id: strings.get(&frame.build_id),
+                            has_functions: true,
+                            ..Default::default()
+                        });
+                        mappings.insert((&frame.mapping, &frame.build_id), id);
+                        id
+                    }
+                };
+                let mut line = vec![];
+                if !frame.function.is_empty() {
+                    let key = (frame.function.as_str(), frame.filename.as_str());
+                    let function_id = match functions.get(&key) {
+                        Some(&id) => id,
+                        None => {
+                            let id = profile.function.len() as u64 + 1;
+                            profile.function.push(pprofpb::Function {
+                                id,
+                                name: strings.get(&frame.function),
+                                system_name: strings.get(&frame.function),
+                                filename: strings.get(&frame.filename),
+                                start_line: 0,
+                            });
+                            functions.insert(key, id);
+                            id
+                        }
+                    };
+                    line.push(pprofpb::Line {
+                        function_id,
+                        line: frame.line,
+                    });
+                }
+                let id = profile.location.len() as u64 + 1;
+                profile.location.push(pprofpb::Location {
+                    id,
+                    mapping_id,
+                    address: frame.address,
+                    line,
+                    is_folded: false,
+                });
+                locations.insert(frame, id);
+                id
+            })
+            .collect();
+        match merged.entry((location_ids, &sample.labels)) {
+            Entry::Occupied(entry) => profile.sample[*entry.get()].value[0] += sample.value,
+            Entry::Vacant(entry) => {
+                let (location_ids, labels) = entry.key();
+                profile.sample.push(pprofpb::Sample {
+                    location_id: location_ids.clone(),
+                    value: vec![sample.value],
+                    label: labels
+                        .iter()
+                        .map(|(k, v)| pprofpb::Label {
+                            key: strings.get(k),
+                            str: strings.get(v),
+                            ..Default::default()
+                        })
+                        .collect(),
+                });
+                entry.insert(profile.sample.len() - 1);
+            }
+        }
+        profile.period = profile.period.max(sample.period);
+    }
+
+    let start = samples
+        .iter()
+        .map(|s| s.timestamp)
+        .min()
+        .unwrap_or_default();
+    let end = samples
+        .iter()
+        .map(|s| s.timestamp + s.duration / 1_000_000)
+        .max()
+        .unwrap_or_default();
+    profile.time_nanos = start * 1_000_000;
+    profile.duration_nanos = (end - start) * 1_000_000;
+    profile.string_table = strings.strings;
+    profile
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backfill::decompress;
+    use crate::normalizer::Metastore;
+    use crate::query::samples::tests::segment;
+    use object_store::{memory::InMemory, path::Path, ObjectStore};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_render() {
+        let storage: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let metastore = Arc::new(Metastore::default());
+        for (i, node) in ["api", "db"].iter().enumerate() {
+            let stacks: &[(&[&str], i64)] = &[(&["leaf", "main"], 3), (&["main"], 1)];
+            let data = segment(&metastore, node, 1_000, stacks).await;
+            let path = Path::from(format!("date=1970-01-01/{}.parquet", i));
+            storage.put(&path, data.into()).await.unwrap();
+        }
+        let reader = SampleReader::new(Arc::clone(&storage), Arc::clone(&metastore));
+        let state = RenderState {
+            reader,
+            cache: Arc::new(QueryCache::new(1 << 20)),
+            executor: Arc::default(),
+        };
+        let params = |query: &str| {
+            Query(RenderParams {
+                query: query.into(),
+                start: Some(0),
+                end: None,
+            })
+        };
+
+        let response = pprof_profile(
+            State(state.clone()),
+            params("process_cpu:samples:count:cpu:nanoseconds:delta{node=\"api\"}"),
+            Query(FrameFilters::default()),
+        )
+        .await
+        .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let profile = pprofpb::Profile::decode(decompress(&body).unwrap().as_slice()).unwrap();
+        let string = |i: i64| profile.string_table[i as usize].as_str();
+        assert_eq!(string(profile.sample_type[0].r#type), "samples");
+        assert_eq!(profile.sample.len(), 2);
+        assert_eq!(profile.sample[0].value, [3]);
+        assert_eq!(profile.sample[0].location_id.len(), 2);
+        // Both stacks share the location of main.
+        assert_eq!(profile.location.len(), 2);
+        assert_eq!(
+            profile
+                .function
+                .iter()
+                .map(|f| string(f.name))
+                .collect::<Vec<_>>(),
+            ["leaf", "main"]
+        );
+        let labels: Vec<_> = profile.sample[0]
+            .label
+            .iter()
+            .map(|l| (string(l.key), string(l.str)))
+            .collect();
+        assert!(labels.contains(&("node", "api")));
+
+        let response = folded(
+            State(state.clone()),
+            params("process_cpu:samples:count:cpu:nanoseconds:delta"),
+            Query(FrameFilters::default()),
+        )
+        .await
+        .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "main 2\nmain;leaf 6\n");
+
+        let response = folded(
+            State(state.clone()),
+            params("process_cpu:samples:count:cpu:nanoseconds:delta"),
+            Query(FrameFilters {
+                focus: Some("^leaf$".into()),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "main;leaf 6\n");
+
+        let response = mappings(
+            State(state.clone()),
+            params("process_cpu:samples:count:cpu:nanoseconds:delta"),
+            Query(FrameFilters::default()),
+        )
+        .await
+        .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report["total"], 8);
+        assert_eq!(report["mappings"].as_array().unwrap().len(), 1);
+        assert_eq!(report["mappings"][0]["cumulative"], 8);
+
+        // Reports are rendered again once a segment of their range is written.
+        let data = segment(&metastore, "db", 2_000, &[(&["main"], 1)]).await;
+        let path = Path::from("date=1970-01-01/2.parquet");
+        storage.put(&path, data.into()).await.unwrap();
+        let response = folded(
+            State(state.clone()),
+            params("process_cpu:samples:count:cpu:nanoseconds:delta"),
+            Query(FrameFilters::default()),
+        )
+        .await
+        .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "main 3\nmain;leaf 6\n");
+
+        let err = pprof_profile(
+            State(state.clone()),
+            params("{node=\"api\"}"),
+            Query(FrameFilters::default()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        let err = folded(
+            State(state.clone()),
+            params("process_cpu:samples:count:cpu:nanoseconds:delta"),
+            Query(FrameFilters {
+                ignore: Some("[".into()),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+
+        // Queries are aborted beyond their memory budget.
+        let state = RenderState {
+            executor: Arc::new(QueryExecutor::new(1, 100)),
+            ..state
+        };
+        let err = folded(
+            State(state),
+            params("process_cpu:samples:count:cpu:nanoseconds:delta{node=\"db\"}"),
+            Query(FrameFilters::default()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -0,0 +1,481 @@
+use super::index::profile_type_key;
+use super::{Selector, TimeRange};
+use crate::ingester::{is_delta, partition_date, partitions, read_parquet, Manifest, StringColumn};
+use crate::normalizer::{decode_labels, decode_num_labels, Metastore};
+use crate::profile::schema;
+use crate::profile::PprofLocations;
+use crate::querypb::ProfileType;
+use crate::symbolizer::fallback::fallback_name;
+use arrow2::array::{Array, BinaryArray, BooleanArray, ListArray, PrimitiveArray};
+use arrow2::chunk::Chunk;
+use chrono::{DateTime, TimeDelta};
+use object_store::{path::Path, ObjectStore};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio_stream::StreamExt;
+
+/// Frame is a single, possibly inlined, function of a stacktrace.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Frame {
+    /// Name of the function, empty if the location isn't symbolized.
+    pub function: String,
+    pub filename: String,
+    pub line: i64,
+    pub address: u64,
+    /// File name of the mapping the address is in.
+    pub mapping: String,
+    pub build_id: String,
+}
+
+impl Frame {
+    /// Returns the function name. Frames that aren't symbolized are named
+    /// after the kind of code they're in, like `[kernel]`, or by their
+    /// mapping and address.
+    pub fn name(&self) -> String {
+        if !self.function.is_empty() {
+            return self.function.clone();
+        }
+        if let Some(name) = fallback_name(self.address, &self.mapping) {
+            return name.into();
+        }
+        match self.mapping.rsplit('/').next().filter(|m| !m.is_empty()) {
+            Some(mapping) => format!("{} 0x{:x}", mapping, self.address),
+            None => format!("0x{:x}", self.address),
+        }
+    }
+}
+
+/// StoredSample is a sample read back from the stored segments, with its
+/// stacktrace resolved to frames.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredSample {
+    /// Profile type in its selector form, see [`profile_type_key`].
+    pub profile_type: String,
+    /// Labels of the series, along with the pprof labels of the sample.
+    /// Numeric pprof labels, like the `pid` and `tid` of process and thread
+    /// profilers, hold their decimal value.
+    pub labels: BTreeMap<String, String>,
+    /// Timestamp of the profile in milliseconds.
+    pub timestamp: i64,
+    pub duration: i64,
+    pub period: i64,
+    pub value: i64,
+    /// Frames of the stacktrace, leaf first.
+    pub stacktrace: Vec<Frame>,
+}
+
+impl StoredSample {
+    /// Returns an estimate of the heap and inline size of the sample, as
+    /// accounted by queries.
+    pub fn memory_usage(&self) -> u64 {
+        let labels: usize = self
+            .labels
+            .iter()
+            .map(|(k, v)| k.len() + v.len() + 2 * std::mem::size_of::<String>())
+            .sum();
+        let frames: usize = self
+            .stacktrace
+            .iter()
+            .map(|f| {
+                std::mem::size_of::<Frame>()
+                    + f.function.len()
+                    + f.filename.len()
+                    + f.mapping.len()
+                    + f.build_id.len()
+            })
+            .sum();
+        (std::mem::size_of::<Self>() + self.profile_type.len() + labels + frames) as u64
+    }
+}
+
+/// SampleReader reads the samples of the stored segments back, for exports
+/// and reports over stored data. Functions are resolved through the metastore,
+/// which holds the function table persisted with the segments.
+#[derive(Debug, Clone)]
+pub struct SampleReader {
+    storage: Arc<dyn ObjectStore>,
+    metastore: Arc<Metastore>,
+}
+
+impl SampleReader {
+    pub fn new(storage: Arc<dyn ObjectStore>, metastore: Arc<Metastore>) -> Self {
+        Self { storage, metastore }
+    }
+
+    /// Returns the segments that can hold samples within the time range.
+    /// Partitions are buckets of the date of their samples, and the segments
+    /// of bucketed partitions are read from their manifest, pruned by their
+    /// time ranges. Partitions of older builds are named after the date
+    /// their segments were written at, which is never before the date of
+    /// their samples, so only the ones before the range are skipped.
+    pub async fn segments(&self, range: TimeRange) -> anyhow::Result<Vec<Path>> {
+        // Segments written since the metastore was loaded may reference
+        // functions persisted by other writers.
+        self.metastore.refresh(self.storage.as_ref()).await?;
+        // A day of slack, since older partitions are named by the local date.
+        let first_date = DateTime::from_timestamp_millis(range.start)
+            .map(|start| start.date_naive() - TimeDelta::days(1));
+        let last_date = DateTime::from_timestamp_millis(range.end).map(|end| end.date_naive());
+        let mut segments = vec![];
+        for partition in partitions(&self.storage).await? {
+            let date = partition_date(&partition);
+            if date.zip(first_date).is_some_and(|(d, first)| d < first) {
+                continue;
+            }
+            if let Some(manifest) = Manifest::load(&self.storage, partition.as_ref()).await? {
+                if date.zip(last_date).is_some_and(|(d, last)| d > last) {
+                    continue;
+                }
+                segments.extend(
+                    manifest
+                        .segments
+                        .iter()
+                        .filter(|s| s.overlaps(range.start, range.end))
+                        .map(|s| Path::from(s.path.as_str())),
+                );
+                continue;
+            }
+            let mut objects = self.storage.list(Some(&partition));
+            while let Some(meta) = objects.next().await {
+                let meta = meta?;
+                if meta.location.extension() == Some("parquet") {
+                    segments.push(meta.location);
+                }
+            }
+        }
+        segments.sort();
+        Ok(segments)
+    }
+
+    /// Returns the samples of the segment matching any of the selectors
+    /// within the time range.
+    pub async fn read_segment(
+        &self,
+        segment: &Path,
+        selectors: &[Selector],
+        range: TimeRange,
+    ) -> anyhow::Result<Vec<StoredSample>> {
+        let data = self.storage.get(segment).await?.bytes().await?;
+        let mut samples = vec![];
+        for chunk in read_parquet(data.to_vec())? {
+            decode_chunk(&chunk, &self.metastore, selectors, range, &mut samples)?;
+        }
+        Ok(samples)
+    }
+}
+
+/// Appends the samples of the chunk matching any of the selectors within the
+/// time range to `samples`, resolving their functions through the metastore.
+pub(crate) fn decode_chunk(
+    chunk: &Chunk<Arc<dyn Array>>,
+    metastore: &Metastore,
+    selectors: &[Selector],
+    range: TimeRange,
+    samples: &mut Vec<StoredSample>,
+) -> anyhow::Result<()> {
+    let fields = schema::create_schema().fields;
+    let column = |name: &str| {
+        fields
+            .iter()
+            .position(|f| f.name == name)
+            .map(|i| chunk.arrays()[i].as_ref())
+            .ok_or_else(|| anyhow::anyhow!("segment has no {} column", name))
+    };
+    let int = |name: &str| -> anyhow::Result<&PrimitiveArray<i64>> {
+        column(name)?
+            .as_any()
+            .downcast_ref()
+            .ok_or_else(|| anyhow::anyhow!("column {} isn't an integer", name))
+    };
+    let string = |name: &str| -> anyhow::Result<StringColumn> {
+        column(name)?
+            .as_any()
+            .downcast_ref()
+            .map(StringColumn)
+            .ok_or_else(|| anyhow::anyhow!("column {} isn't a dictionary", name))
+    };
+
+    let timestamps = int("timestamp")?;
+    let (durations, periods, values) = (int("duration")?, int("period")?, int("value")?);
+    let (name, sample_type, sample_unit) = (
+        string("name")?,
+        string("sample_type")?,
+        string("sample_unit")?,
+    );
+    let (period_type, period_unit) = (string("period_type")?, string("period_unit")?);
+    let deltas = column("delta")?
+        .as_any()
+        .downcast_ref::<BooleanArray>()
+        .ok_or_else(|| anyhow::anyhow!("column delta isn't a boolean"))?;
+    let pprof_labels = string("pprof_labels")?;
+    let pprof_num_labels = string("pprof_num_labels")?;
+    let labels = fields
+        .iter()
+        .filter_map(|f| f.name.strip_prefix("labels."))
+        .map(|l| Ok((l.to_string(), string(&format!("labels.{}", l))?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let stacktraces = column("stacktrace")?
+        .as_any()
+        .downcast_ref::<ListArray<i32>>()
+        .ok_or_else(|| anyhow::anyhow!("column stacktrace isn't a list"))?;
+
+    for row in 0..chunk.len() {
+        let timestamp = timestamps.value(row);
+        if timestamp < range.start || timestamp > range.end {
+            continue;
+        }
+        let profile_type = profile_type_key(&ProfileType {
+            name: name.get(row).into(),
+            sample_type: sample_type.get(row).into(),
+            sample_unit: sample_unit.get(row).into(),
+            period_type: period_type.get(row).into(),
+            period_unit: period_unit.get(row).into(),
+            delta: is_delta(deltas, durations, row),
+        });
+
+        let mut sample_labels: BTreeMap<String, String> = labels
+            .iter()
+            .filter(|(_, column)| !column.is_null(row))
+            .map(|(l, column)| (l.clone(), column.get(row).to_string()))
+            .collect();
+        if !pprof_labels.is_null(row) {
+            for (k, v) in decode_labels(pprof_labels.get(row)).unwrap_or_default() {
+                sample_labels.entry(k).or_insert(v);
+            }
+        }
+        if !pprof_num_labels.is_null(row) {
+            for (k, v) in decode_num_labels(pprof_num_labels.get(row)).unwrap_or_default() {
+                sample_labels.entry(k).or_insert_with(|| v.to_string());
+            }
+        }
+        if !selectors.is_empty()
+            && !selectors
+                .iter()
+                .any(|s| s.matches(&profile_type, &sample_labels))
+        {
+            continue;
+        }
+
+        let mut stacktrace = vec![];
+        if !stacktraces.is_null(row) {
+            let locations = stacktraces.value(row);
+            let locations = locations
+                .as_any()
+                .downcast_ref::<BinaryArray<i32>>()
+                .ok_or_else(|| anyhow::anyhow!("stacktrace items aren't binary"))?;
+            for location in locations.values_iter() {
+                resolve(
+                    metastore,
+                    &PprofLocations::decode(location)?,
+                    &mut stacktrace,
+                );
+            }
+        }
+
+        samples.push(StoredSample {
+            profile_type,
+            labels: sample_labels,
+            timestamp,
+            duration: durations.value(row),
+            period: periods.value(row),
+            value: values.value(row),
+            stacktrace,
+        });
+    }
+    Ok(())
+}
+
+/// Appends the frames of the location, innermost inlined function first.
+fn resolve(metastore: &Metastore, location: &PprofLocations, frames: &mut Vec<Frame>) {
+    let frame = Frame {
+        address: location.address,
+        mapping: location.file_name.clone(),
+        build_id: location.build_id.clone(),
+        ..Default::default()
+    };
+    if location.functions.is_empty() {
+        frames.push(frame);
+        return;
+    }
+    for function in location.functions.iter() {
+        let interned = metastore.function(&function.id);
+        let interned = interned.as_ref().unwrap_or(function);
+        frames.push(Frame {
+            function: interned.name.clone(),
+            filename: interned.filename.clone(),
+            line: function.start_line,
+            ..frame.clone()
+        });
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::ingester::{encode_parquet, SegmentEntry};
+    use crate::metapb::Function;
+    use crate::normalizer::normalized_request_to_arrow_chunk;
+    use crate::normalizer::write_raw::tests::{labels_map, profile, sample, RequestBuilder};
+    use object_store::memory::InMemory;
+    use std::collections::HashMap;
+
+    /// Returns a segment of `process_cpu` samples of the `node`, one per
+    /// stacktrace of function names, leaf first, with its value.
+    pub(crate) async fn segment(
+        metastore: &Metastore,
+        node: &str,
+        timestamp: i64,
+        stacks: &[(&[&str], i64)],
+    ) -> Vec<u8> {
+        let chunk = chunk(metastore, node, timestamp, stacks).await;
+        encode_parquet(&[chunk]).unwrap()
+    }
+
+    /// Returns the chunk of the samples of [`segment`].
+    pub(crate) async fn chunk(
+        metastore: &Metastore,
+        node: &str,
+        timestamp: i64,
+        stacks: &[(&[&str], i64)],
+    ) -> Chunk<Arc<dyn Array>> {
+        let samples = stacks
+            .iter()
+            .map(|(functions, value)| {
+                let locations = functions
+                    .iter()
+                    .map(|f| {
+                        let function = metastore.intern(&Function {
+                            name: f.to_string(),
+                            filename: format!("{}.rs", f),
+                            ..Default::default()
+                        });
+                        PprofLocations {
+                            address: 0x1000,
+                            number_of_lines: 1,
+                            build_id: "b1".into(),
+                            file_name: "/usr/bin/app".into(),
+                            mapping_memory_start: 0,
+                            mapping_memory_end: 0,
+                            mapping_file_offset: 0,
+                            functions: vec![function],
+                        }
+                        .encode()
+                        .unwrap()
+                    })
+                    .collect();
+                let mut sample = sample(locations, *value);
+                sample.label = labels_map(&[("thread", "main")]);
+                sample.num_label = HashMap::from([("tid".to_string(), 7)]);
+                sample
+            })
+            .collect();
+        let request = RequestBuilder::default()
+            .with_series(&[("node", node)], vec![profile(timestamp, samples)])
+            .build();
+        normalized_request_to_arrow_chunk(&request).await.unwrap()
+    }
+
+    async fn read(
+        reader: &SampleReader,
+        selectors: &[Selector],
+        range: TimeRange,
+    ) -> Vec<StoredSample> {
+        let mut samples = vec![];
+        for segment in reader.segments(range).await.unwrap() {
+            samples.extend(
+                reader
+                    .read_segment(&segment, selectors, range)
+                    .await
+                    .unwrap(),
+            );
+        }
+        samples
+    }
+
+    #[tokio::test]
+    async fn test_read_samples() {
+        let storage: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let metastore = Arc::new(Metastore::default());
+        for (path, node, timestamp) in [
+            ("date=2024-01-01/1.parquet", "api", 1_704_067_200_000),
+            ("date=2024-03-01/2.parquet", "db", 1_709_251_200_000),
+        ] {
+            let data = segment(&metastore, node, timestamp, &[(&["leaf", "main"], 5)]).await;
+            storage.put(&Path::from(path), data.into()).await.unwrap();
+        }
+
+        let reader = SampleReader::new(storage, metastore);
+        let samples = read(&reader, &[], TimeRange::default()).await;
+        assert_eq!(samples.len(), 2);
+        let sample = &samples[0];
+        assert_eq!(
+            sample.profile_type,
+            "process_cpu:samples:count:cpu:nanoseconds:delta"
+        );
+        assert_eq!(sample.labels["node"], "api");
+        assert_eq!(sample.labels["thread"], "main");
+        assert_eq!(sample.labels["tid"], "7");
+        assert_eq!(sample.value, 5);
+        let names: Vec<String> = sample.stacktrace.iter().map(Frame::name).collect();
+        assert_eq!(names, vec!["leaf", "main"]);
+        assert_eq!(sample.stacktrace[0].filename, "leaf.rs");
+
+        let selector = Selector::parse(r#"{thread="main", node=~"d.*"}"#).unwrap();
+        let samples = read(&reader, &[selector], TimeRange::default()).await;
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].labels["node"], "db");
+
+        // The partition of January is pruned.
+        let range = TimeRange {
+            start: 1_709_000_000_000,
+            end: i64::MAX,
+        };
+        assert_eq!(reader.segments(range).await.unwrap().len(), 1);
+
+        // Segments of bucketed partitions are read from their manifest.
+        let unrecorded = segment(
+            &reader.metastore,
+            "db",
+            1_709_251_200_000,
+            &[(&["leaf"], 1)],
+        )
+        .await;
+        let unrecorded_path = Path::from("date=2024-03-01/3.parquet");
+        reader
+            .storage
+            .put(&unrecorded_path, unrecorded.into())
+            .await
+            .unwrap();
+        Manifest::update(&reader.storage, "date=2024-03-01", true, |m| {
+            m.segments.push(SegmentEntry {
+                path: "date=2024-03-01/2.parquet".into(),
+                series: vec![],
+                min_timestamp: 1_709_251_200_000,
+                max_timestamp: 1_709_251_200_000,
+                size: 1,
+            })
+        })
+        .await
+        .unwrap();
+        let range = |start, end| TimeRange { start, end };
+        let segments = reader.segments(range(1_709_251_200_001, i64::MAX)).await;
+        assert_eq!(segments.unwrap().len(), 0);
+        let segments = reader
+            .segments(range(1_704_067_200_000, 1_704_067_200_000))
+            .await;
+        assert_eq!(segments.unwrap(), [Path::from("date=2024-01-01/1.parquet")]);
+        assert_eq!(read(&reader, &[], TimeRange::default()).await.len(), 2);
+
+        let unsymbolized = Frame {
+            address: 0x2a,
+            mapping: "/usr/lib/libc.so.6".into(),
+            ..Default::default()
+        };
+        assert_eq!(unsymbolized.name(), "libc.so.6 0x2a");
+        let kernel = Frame {
+            address: 0xffffffff81000000,
+            ..Default::default()
+        };
+        assert_eq!(kernel.name(), "[kernel]");
+    }
+}
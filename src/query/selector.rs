@@ -0,0 +1,174 @@
+use anyhow::{bail, Context};
+use regex::Regex;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone)]
+enum MatchOp {
+    Equal(String),
+    NotEqual(String),
+    Regex(Regex),
+    NotRegex(Regex),
+}
+
+/// Matcher matches the value of a single label. An absent label matches as
+/// the empty string, like in Prometheus.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    name: String,
+    op: MatchOp,
+}
+
+impl Matcher {
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        let value = labels.get(&self.name).map(String::as_str).unwrap_or("");
+        match &self.op {
+            MatchOp::Equal(v) => value == v,
+            MatchOp::NotEqual(v) => value != v,
+            MatchOp::Regex(re) => re.is_match(value),
+            MatchOp::NotRegex(re) => !re.is_match(value),
+        }
+    }
+}
+
+/// Selector is a parsed series selector such as
+/// `process_cpu:samples:count:cpu:nanoseconds:delta{job="api", pod=~"api-.*"}`.
+/// Both the profile type and the matchers are optional.
+#[derive(Debug, Clone, Default)]
+pub struct Selector {
+    pub profile_type: Option<String>,
+    pub matchers: Vec<Matcher>,
+}
+
+impl Selector {
+    pub fn parse(input: &str) -> anyhow::Result<Self> {
+        let input = input.trim();
+        let (profile_type, rest) = match input.find('{') {
+            Some(i) => (input[..i].trim(), &input[i..]),
+            None => (input, ""),
+        };
+
+        let mut selector = Selector {
+            profile_type: (!profile_type.is_empty()).then(|| profile_type.to_string()),
+            matchers: vec![],
+        };
+        if rest.is_empty() {
+            return Ok(selector);
+        }
+
+        let body = match rest.strip_prefix('{').and_then(|r| r.strip_suffix('}')) {
+            Some(body) => body,
+            None => bail!("invalid selector {}: unbalanced braces", input),
+        };
+
+        let mut chars = body.chars().peekable();
+        loop {
+            while chars.peek().is_some_and(|c| c.is_whitespace() || *c == ',') {
+                chars.next();
+            }
+            if chars.peek().is_none() {
+                break;
+            }
+
+            let mut name = String::new();
+            while let Some(c) = chars.next_if(|c| *c != '=' && *c != '!') {
+                name.push(c);
+            }
+            let name = name.trim().to_string();
+            if name.is_empty() {
+                bail!("invalid selector {}: empty label name", input);
+            }
+
+            let negated = chars.next_if_eq(&'!').is_some();
+            let is_regex = match (chars.next(), chars.next_if_eq(&'~')) {
+                (Some('='), tilde) => tilde.is_some(),
+                (Some('~'), None) if negated => true,
+                _ => bail!("invalid selector {}: expected =, !=, =~ or !~", input),
+            };
+
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.next() != Some('"') {
+                bail!("invalid selector {}: label values must be quoted", input);
+            }
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    Some('\\') => value.extend(chars.next()),
+                    Some('"') => break,
+                    Some(c) => value.push(c),
+                    None => bail!("invalid selector {}: unterminated label value", input),
+                }
+            }
+
+            let op = match (negated, is_regex) {
+                (false, false) => MatchOp::Equal(value),
+                (true, false) => MatchOp::NotEqual(value),
+                (negated, true) => {
+                    let re = Regex::new(&format!("^(?:{})$", value))
+                        .with_context(|| format!("invalid regex in selector {}", input))?;
+                    if negated {
+                        MatchOp::NotRegex(re)
+                    } else {
+                        MatchOp::Regex(re)
+                    }
+                }
+            };
+            selector.matchers.push(Matcher { name, op });
+        }
+
+        Ok(selector)
+    }
+
+    /// Returns the labels the selector matches exactly, or None if it has
+    /// matchers other than equalities.
+    pub fn labels(&self) -> Option<BTreeMap<String, String>> {
+        self.matchers
+            .iter()
+            .map(|m| match &m.op {
+                MatchOp::Equal(v) => Some((m.name.clone(), v.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn matches(&self, profile_type: &str, labels: &BTreeMap<String, String>) -> bool {
+        if let Some(pt) = &self.profile_type {
+            if pt != profile_type {
+                return false;
+            }
+        }
+        self.matchers.iter().all(|m| m.matches(labels))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_selector() {
+        let labels: BTreeMap<String, String> = [("job", "api"), ("pod", "api-1")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        let s = Selector::parse(
+            r#"process_cpu:samples:count:cpu:nanoseconds:delta{job="api", pod=~"api-.*", zone!="b"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            s.profile_type.as_deref(),
+            Some("process_cpu:samples:count:cpu:nanoseconds:delta")
+        );
+        assert_eq!(s.matchers.len(), 3);
+        assert!(s.matches("process_cpu:samples:count:cpu:nanoseconds:delta", &labels));
+        assert!(!s.matches("memory:inuse_space:bytes:space:bytes", &labels));
+
+        let s = Selector::parse(r#"{pod!~"api-.*"}"#).unwrap();
+        assert!(s.profile_type.is_none());
+        assert!(!s.matches("", &labels));
+
+        assert!(Selector::parse("{}").unwrap().matches("", &labels));
+        assert!(Selector::parse(r#"{job=api}"#).is_err());
+        assert!(Selector::parse(r#"{job="api""#).is_err());
+    }
+}
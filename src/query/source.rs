@@ -0,0 +1,277 @@
+use super::executor::QueryExecutor;
+use super::samples::{SampleReader, StoredSample};
+use super::{Selector, TimeRange};
+use crate::debuginfo_store::SourceArchives;
+use anyhow::Context;
+use arrow2::array::{Array, Int64Array};
+use arrow2::chunk::Chunk;
+use arrow2::datatypes::{DataType, Field, Schema};
+use arrow2::io::ipc::write::{StreamWriter, WriteOptions};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+/// Sources finds the source files the source report annotates.
+#[derive(Debug, Default)]
+pub struct Sources {
+    roots: Vec<PathBuf>,
+    /// Source archives uploaded for build IDs, looked up before the roots.
+    archives: Option<Arc<SourceArchives>>,
+}
+
+impl Sources {
+    /// Looks up source files under the `roots` directories.
+    pub fn new(roots: Vec<PathBuf>) -> Self {
+        Self {
+            roots,
+            archives: None,
+        }
+    }
+
+    /// Looks up source files in the source archives of their build ID first.
+    pub fn with_archives(mut self, archives: Arc<SourceArchives>) -> Self {
+        self.archives = Some(archives);
+        self
+    }
+
+    /// Returns the content of the source file of the build ID, if found.
+    /// Files are looked up in the source archive of the build ID, then under
+    /// every root with their full path, then with fewer and fewer leading
+    /// directories, so that files compiled in `/home/ci/build/src/main.rs`
+    /// are found at `<root>/src/main.rs`.
+    pub async fn read(&self, build_id: &str, filename: &str) -> anyhow::Result<Option<String>> {
+        if let Some(archives) = self.archives.as_ref().filter(|_| !build_id.is_empty()) {
+            if let Some(source) = archives.read(build_id, filename).await? {
+                return Ok(Some(String::from_utf8_lossy(&source).into_owned()));
+            }
+        }
+        anyhow::ensure!(
+            !Path::new(filename)
+                .components()
+                .any(|c| c == Component::ParentDir),
+            "invalid filename {:?}",
+            filename
+        );
+        let components: Vec<&str> = Path::new(filename)
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(c) => c.to_str(),
+                _ => None,
+            })
+            .collect();
+        for root in self.roots.iter() {
+            for skip in 0..components.len() {
+                let path = components[skip..]
+                    .iter()
+                    .fold(root.clone(), |path, c| path.join(c));
+                match std::fs::read_to_string(&path) {
+                    Ok(source) => return Ok(Some(source)),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Flat and cumulative values of a source line.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LineValues {
+    pub flat: i64,
+    pub cumulative: i64,
+}
+
+/// Returns the values of the lines of the file, by line number. A line's
+/// cumulative value holds the samples with any frame at the line, and its
+/// flat value the samples whose leaf is at the line. Frames of other build
+/// IDs are ignored, unless `build_id` is empty.
+pub(crate) fn line_values(
+    samples: &[StoredSample],
+    build_id: &str,
+    filename: &str,
+) -> BTreeMap<i64, LineValues> {
+    let mut lines: BTreeMap<i64, LineValues> = BTreeMap::new();
+    for sample in samples {
+        let mut seen = HashSet::new();
+        for (i, frame) in sample.stacktrace.iter().enumerate() {
+            if frame.filename != filename || (!build_id.is_empty() && frame.build_id != build_id) {
+                continue;
+            }
+            let values = lines.entry(frame.line).or_default();
+            if i == 0 {
+                values.flat += sample.value;
+            }
+            // Recursive calls count once per sample.
+            if seen.insert(frame.line) {
+                values.cumulative += sample.value;
+            }
+        }
+    }
+    lines
+}
+
+/// Encodes the line values as an Arrow IPC stream, with the
+/// `line_number`, `flat` and `cumulative` columns.
+pub(crate) fn encode_record(lines: &[(i64, LineValues)]) -> anyhow::Result<Vec<u8>> {
+    let schema = Schema::from(vec![
+        Field::new("line_number", DataType::Int64, false),
+        Field::new("flat", DataType::Int64, false),
+        Field::new("cumulative", DataType::Int64, false),
+    ]);
+    let column = |values: Vec<i64>| Int64Array::from_vec(values).boxed();
+    let chunk: Chunk<Box<dyn Array>> = Chunk::try_new(vec![
+        column(lines.iter().map(|(line, _)| *line).collect()),
+        column(lines.iter().map(|(_, v)| v.flat).collect()),
+        column(lines.iter().map(|(_, v)| v.cumulative).collect()),
+    ])?;
+
+    let mut record = vec![];
+    let mut writer = StreamWriter::new(&mut record, WriteOptions { compression: None });
+    writer.start(&schema, None)?;
+    writer.write(&chunk, None)?;
+    writer.finish()?;
+    Ok(record)
+}
+
+/// SourceReport annotates the lines of source files with the values of the
+/// stored samples.
+#[derive(Debug)]
+pub(crate) struct SourceReport {
+    pub reader: SampleReader,
+    pub sources: Arc<Sources>,
+}
+
+/// Source file of a source report, with the values of its lines by line
+/// number, unless only the source was asked for.
+#[derive(Debug)]
+pub(crate) struct SourceLines {
+    pub source: String,
+    pub unit: String,
+    pub lines: Option<Vec<(i64, LineValues)>>,
+}
+
+impl SourceReport {
+    /// Returns the source file with the values of the lines of the selected
+    /// samples, or None if the file isn't found. Values are left out if
+    /// `source_only`. The samples of every segment are accounted by the
+    /// executor while they're aggregated.
+    pub async fn lines(
+        &self,
+        build_id: &str,
+        filename: &str,
+        source_only: bool,
+        selector: Selector,
+        range: TimeRange,
+        executor: &QueryExecutor,
+    ) -> anyhow::Result<Option<SourceLines>> {
+        let Some(source) = self.sources.read(build_id, filename).await? else {
+            return Ok(None);
+        };
+        let unit = selector
+            .profile_type
+            .as_deref()
+            .and_then(|pt| pt.split(':').nth(2))
+            .unwrap_or_default()
+            .to_string();
+        if source_only {
+            return Ok(Some(SourceLines {
+                source,
+                unit,
+                lines: None,
+            }));
+        }
+
+        let mut query = executor.start().await;
+        let selectors = [selector];
+        let mut lines = BTreeMap::new();
+        for segment in self.reader.segments(range).await? {
+            let samples = self
+                .reader
+                .read_segment(&segment, &selectors, range)
+                .await?;
+            let size = samples.iter().map(|s| s.memory_usage()).sum();
+            query.reserve(size)?;
+            for (line, values) in line_values(&samples, build_id, filename) {
+                let total: &mut LineValues = lines.entry(line).or_default();
+                total.flat += values.flat;
+                total.cumulative += values.cumulative;
+            }
+            query.release(size);
+        }
+        Ok(Some(SourceLines {
+            source,
+            unit,
+            lines: Some(lines.into_iter().collect()),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::samples::Frame;
+
+    #[tokio::test]
+    async fn test_read() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/main.rs"), "fn main() {}\n").unwrap();
+        let sources = Sources::new(vec![PathBuf::from("/nonexistent"), dir.path().into()]);
+
+        let source = sources.read("b1", "/home/ci/build/src/main.rs").await;
+        assert_eq!(source.unwrap().as_deref(), Some("fn main() {}\n"));
+        assert_eq!(sources.read("b1", "src/lib.rs").await.unwrap(), None);
+        assert!(sources.read("b1", "../src/main.rs").await.is_err());
+    }
+
+    #[test]
+    fn test_line_values() {
+        let frame = |filename: &str, line| Frame {
+            function: "f".into(),
+            filename: filename.into(),
+            line,
+            build_id: "b1".into(),
+            ..Default::default()
+        };
+        let sample = |stacktrace, value| StoredSample {
+            profile_type: String::new(),
+            labels: BTreeMap::new(),
+            timestamp: 0,
+            duration: 0,
+            period: 1,
+            value,
+            stacktrace,
+        };
+        let samples = [
+            sample(vec![frame("main.rs", 3), frame("main.rs", 10)], 2),
+            sample(
+                vec![frame("lib.rs", 1), frame("main.rs", 3), frame("main.rs", 3)],
+                5,
+            ),
+        ];
+        let lines = line_values(&samples, "b1", "main.rs");
+        assert_eq!(
+            lines,
+            BTreeMap::from([
+                (
+                    3,
+                    LineValues {
+                        flat: 2,
+                        cumulative: 7
+                    }
+                ),
+                (
+                    10,
+                    LineValues {
+                        flat: 0,
+                        cumulative: 2
+                    }
+                ),
+            ])
+        );
+        assert!(line_values(&samples, "b2", "main.rs").is_empty());
+        let lines: Vec<_> = lines.into_iter().collect();
+        assert!(!encode_record(&lines).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,173 @@
+use super::samples::StoredSample;
+use serde::Serialize;
+use std::collections::HashMap;
+
+const SCHEMA: &str = "https://www.speedscope.app/file-format-schema.json";
+
+/// File is a Speedscope file, see
+/// <https://github.com/jlfwong/speedscope/wiki/Importing-from-custom-sources>.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct File {
+    #[serde(rename = "$schema")]
+    schema: &'static str,
+    shared: Shared,
+    profiles: Vec<SampledProfile>,
+    name: String,
+    active_profile_index: usize,
+    exporter: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct Shared {
+    frames: Vec<FrameInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct FrameInfo {
+    name: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    file: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SampledProfile {
+    r#type: &'static str,
+    name: String,
+    unit: &'static str,
+    start_value: i64,
+    end_value: i64,
+    /// Stacks as indices of the shared frames, root first.
+    samples: Vec<Vec<usize>>,
+    weights: Vec<i64>,
+}
+
+/// Returns the unit of the sample unit Speedscope knows, `none` otherwise.
+fn unit(sample_unit: &str) -> &'static str {
+    match sample_unit {
+        "nanoseconds" => "nanoseconds",
+        "microseconds" => "microseconds",
+        "milliseconds" => "milliseconds",
+        "seconds" => "seconds",
+        "bytes" => "bytes",
+        _ => "none",
+    }
+}
+
+/// Merges the samples of the profile type, in its selector form, into a
+/// single sampled Speedscope profile, summing the samples of the same stack.
+pub(crate) fn to_speedscope(profile_type: &str, samples: &[StoredSample]) -> File {
+    let mut frames = vec![];
+    let mut frame_indices: HashMap<(String, &str, i64), usize> = HashMap::new();
+    let mut stacks: HashMap<Vec<usize>, usize> = HashMap::new();
+    let mut profile = SampledProfile {
+        r#type: "sampled",
+        name: profile_type.to_string(),
+        unit: unit(profile_type.split(':').nth(2).unwrap_or_default()),
+        start_value: 0,
+        end_value: 0,
+        samples: vec![],
+        weights: vec![],
+    };
+    for sample in samples {
+        let stack: Vec<usize> = sample
+            .stacktrace
+            .iter()
+            .rev()
+            .map(|frame| {
+                let key = (frame.name(), frame.filename.as_str(), frame.line);
+                *frame_indices
+                    .entry(key)
+                    .or_insert_with_key(|(name, file, line)| {
+                        frames.push(FrameInfo {
+                            name: name.clone(),
+                            file: file.to_string(),
+                            line: (*line > 0).then_some(*line),
+                        });
+                        frames.len() - 1
+                    })
+            })
+            .collect();
+        match stacks.get(&stack) {
+            Some(&i) => profile.weights[i] += sample.value,
+            None => {
+                stacks.insert(stack.clone(), profile.samples.len());
+                profile.samples.push(stack);
+                profile.weights.push(sample.value);
+            }
+        }
+        profile.end_value += sample.value;
+    }
+    File {
+        schema: SCHEMA,
+        shared: Shared { frames },
+        profiles: vec![profile],
+        name: profile_type.to_string(),
+        active_profile_index: 0,
+        exporter: "evprofiler",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::samples::Frame;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_to_speedscope() {
+        let frame = |function: &str| Frame {
+            function: function.into(),
+            filename: format!("{}.go", function),
+            line: 7,
+            ..Default::default()
+        };
+        let sample = |stacktrace: Vec<Frame>, value| StoredSample {
+            profile_type: "memory:alloc_space:bytes:space:bytes".into(),
+            labels: BTreeMap::new(),
+            timestamp: 0,
+            duration: 0,
+            period: 1,
+            value,
+            stacktrace,
+        };
+        let unsymbolized = Frame {
+            address: 0x10,
+            ..Default::default()
+        };
+        let samples = [
+            sample(vec![frame("leaf"), frame("main")], 3),
+            sample(vec![unsymbolized, frame("main")], 1),
+            sample(vec![frame("leaf"), frame("main")], 2),
+        ];
+
+        let file = to_speedscope("memory:alloc_space:bytes:space:bytes", &samples);
+        assert_eq!(
+            serde_json::to_value(&file).unwrap(),
+            json!({
+                "$schema": SCHEMA,
+                "shared": {"frames": [
+                    {"name": "main", "file": "main.go", "line": 7},
+                    {"name": "leaf", "file": "leaf.go", "line": 7},
+                    {"name": "0x10"},
+                ]},
+                "profiles": [{
+                    "type": "sampled",
+                    "name": "memory:alloc_space:bytes:space:bytes",
+                    "unit": "bytes",
+                    "startValue": 0,
+                    "endValue": 6,
+                    "samples": [[0, 1], [0, 2]],
+                    "weights": [5, 1],
+                }],
+                "name": "memory:alloc_space:bytes:space:bytes",
+                "activeProfileIndex": 0,
+                "exporter": "evprofiler",
+            })
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use crate::adminpb::{get_series_stats_request::SortBy, SeriesStats};
+use crate::normalizer::NormalizedWriteRawRequest;
+use crate::profilestorepb::WriteRawRequest;
+use chrono::{DateTime, Utc};
+use moka::sync::Cache;
+use prometheus::{register_gauge_vec, GaugeVec};
+use prost_types::Timestamp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+static TOP_SERIES_SAMPLES: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "evprofiler_top_series_samples_per_second",
+        "Rate of accepted samples of the series written the most samples to.",
+        &["series"]
+    )
+    .unwrap()
+});
+
+static TOP_SERIES_BYTES: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "evprofiler_top_series_bytes_per_second",
+        "Rate of raw profile bytes of the series written the most bytes to.",
+        &["series"]
+    )
+    .unwrap()
+});
+
+/// Time rates are averaged over.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+/// How long series that aren't written are kept.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3600);
+/// Number of series exported as metrics, so that their cardinality stays
+/// bounded.
+const TOP_SERIES_METRICS: usize = 10;
+/// How often the exported series are updated.
+const METRICS_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Rate is an exponentially weighted moving average of a per-second rate.
+#[derive(Debug, Clone, Copy)]
+struct Rate {
+    value: f64,
+    updated: Instant,
+}
+
+impl Rate {
+    fn new(now: Instant) -> Self {
+        Self {
+            value: 0.0,
+            updated: now,
+        }
+    }
+
+    fn at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.value * (-elapsed.as_secs_f64() / RATE_WINDOW.as_secs_f64()).exp()
+    }
+
+    fn add(&mut self, n: u64, now: Instant) {
+        self.value = self.at(now) + n as f64 / RATE_WINDOW.as_secs_f64();
+        self.updated = now;
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    labels: BTreeMap<String, String>,
+    samples: Rate,
+    bytes: Rate,
+    total_samples: u64,
+    total_bytes: u64,
+    last_write_at: DateTime<Utc>,
+    sample_types: BTreeSet<String>,
+}
+
+impl Entry {
+    fn stats(&self, now: Instant) -> SeriesStats {
+        SeriesStats {
+            labels: self.labels.clone().into_iter().collect(),
+            samples_per_second: self.samples.at(now),
+            bytes_per_second: self.bytes.at(now),
+            last_write_at: Some(Timestamp {
+                seconds: self.last_write_at.timestamp(),
+                nanos: self.last_write_at.timestamp_subsec_nanos() as i32,
+            }),
+            sample_types: self.sample_types.iter().cloned().collect(),
+            samples: self.total_samples,
+            bytes: self.total_bytes,
+        }
+    }
+}
+
+/// IngestionStats tracks how much the recently written series are written,
+/// to find the targets dominating resources. Series are tracked with the
+/// labels they're stored with, so scrubbed labels don't show up.
+#[derive(Debug)]
+pub struct IngestionStats {
+    series: Option<Cache<String, Arc<Mutex<Entry>>>>,
+    metrics_updated: Mutex<Option<Instant>>,
+}
+
+impl Default for IngestionStats {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl IngestionStats {
+    /// Creates stats of up to `max_series` series. Zero disables the stats.
+    pub fn new(max_series: u64) -> Self {
+        Self {
+            series: (max_series > 0).then(|| {
+                Cache::builder()
+                    .max_capacity(max_series)
+                    .time_to_idle(IDLE_TIMEOUT)
+                    .build()
+            }),
+            metrics_updated: Mutex::new(None),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.series.is_some()
+    }
+
+    /// Accounts the series of the request, whose normalized form is
+    /// `normalized`.
+    pub fn observe(&self, request: &WriteRawRequest, normalized: &NormalizedWriteRawRequest) {
+        let Some(series) = &self.series else {
+            return;
+        };
+        let (now, wall) = (Instant::now(), Utc::now());
+        for (raw, normalized) in request.series.iter().zip(normalized.series.iter()) {
+            let mut labels: BTreeMap<String, String> =
+                normalized.labels.clone().into_iter().collect();
+            if let Some(name) = raw
+                .labels
+                .iter()
+                .flat_map(|ls| ls.labels.iter())
+                .find(|l| l.name == "__name__")
+            {
+                labels.insert(name.name.clone(), name.value.clone());
+            }
+            let key = format_labels(&labels);
+            let entry = series.get_with(key, || {
+                Arc::new(Mutex::new(Entry {
+                    labels,
+                    samples: Rate::new(now),
+                    bytes: Rate::new(now),
+                    total_samples: 0,
+                    total_bytes: 0,
+                    last_write_at: wall,
+                    sample_types: BTreeSet::new(),
+                }))
+            });
+
+            let samples = normalized
+                .samples
+                .iter()
+                .flatten()
+                .map(|p| p.samples.len() as u64)
+                .sum();
+            let bytes = raw.samples.iter().map(|s| s.raw_profile.len() as u64).sum();
+            let mut entry = entry.lock().unwrap();
+            entry.samples.add(samples, now);
+            entry.bytes.add(bytes, now);
+            entry.total_samples += samples;
+            entry.total_bytes += bytes;
+            entry.last_write_at = wall;
+            for profile in normalized.samples.iter().flatten() {
+                let sample_type = &profile.meta.sample_type;
+                entry
+                    .sample_types
+                    .insert(format!("{}:{}", sample_type.type_, sample_type.unit));
+            }
+        }
+        self.update_metrics(now);
+    }
+
+    /// Returns the stats of up to `limit` series with the highest rate.
+    pub fn top(&self, limit: usize, sort_by: SortBy) -> Vec<SeriesStats> {
+        let Some(series) = &self.series else {
+            return vec![];
+        };
+        let now = Instant::now();
+        let mut stats = series
+            .iter()
+            .map(|(_, entry)| entry.lock().unwrap().stats(now))
+            .collect::<Vec<_>>();
+        let rate = |s: &SeriesStats| match sort_by {
+            SortBy::BytesUnspecified => s.bytes_per_second,
+            SortBy::Samples => s.samples_per_second,
+        };
+        stats.sort_by(|a, b| rate(b).total_cmp(&rate(a)));
+        stats.truncate(limit);
+        stats
+    }
+
+    /// Exports the rates of the noisiest series, at most every
+    /// `METRICS_INTERVAL`.
+    fn update_metrics(&self, now: Instant) {
+        {
+            let mut updated = self.metrics_updated.lock().unwrap();
+            if updated.is_some_and(|t| now.duration_since(t) < METRICS_INTERVAL) {
+                return;
+            }
+            *updated = Some(now);
+        }
+        for (gauge, sort_by) in [
+            (&*TOP_SERIES_BYTES, SortBy::BytesUnspecified),
+            (&*TOP_SERIES_SAMPLES, SortBy::Samples),
+        ] {
+            gauge.reset();
+            for stats in self.top(TOP_SERIES_METRICS, sort_by) {
+                let labels = stats.labels.into_iter().collect::<BTreeMap<_, _>>();
+                let rate = match sort_by {
+                    SortBy::BytesUnspecified => stats.bytes_per_second,
+                    SortBy::Samples => stats.samples_per_second,
+                };
+                gauge
+                    .with_label_values(&[&format_labels(&labels)])
+                    .set(rate);
+            }
+        }
+    }
+}
+
+/// Formats labels like `{job="api", pod="api-1"}`.
+fn format_labels(labels: &BTreeMap<String, String>) -> String {
+    let labels = labels
+        .iter()
+        .map(|(k, v)| format!("{}={:?}", k, v))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", labels.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalizer::write_raw::tests::RequestBuilder;
+    use crate::profilestorepb::{Label, LabelSet, RawProfileSeries, RawSample};
+
+    fn series(pod: &str, bytes: usize) -> RawProfileSeries {
+        RawProfileSeries {
+            labels: Some(LabelSet {
+                labels: vec![
+                    Label {
+                        name: "__name__".into(),
+                        value: "cpu".into(),
+                    },
+                    Label {
+                        name: "pod".into(),
+                        value: pod.into(),
+                    },
+                ],
+            }),
+            samples: vec![RawSample {
+                raw_profile: vec![0; bytes],
+                executable_info: vec![],
+            }],
+        }
+    }
+
+    #[test]
+    fn test_ingestion_stats() {
+        let stats = IngestionStats::new(100);
+        stats.observe(
+            &WriteRawRequest {
+                series: vec![series("a", 10), series("b", 1000)],
+                ..Default::default()
+            },
+            &RequestBuilder::default()
+                .with_series(&[("pod", "a")], vec![])
+                .with_series(&[("pod", "b")], vec![])
+                .build(),
+        );
+
+        let top = stats.top(1, SortBy::BytesUnspecified);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].labels["pod"], "b");
+        assert_eq!(top[0].labels["__name__"], "cpu");
+        assert_eq!(top[0].bytes, 1000);
+        assert!(top[0].bytes_per_second > 0.0);
+        assert_eq!(stats.top(10, SortBy::Samples).len(), 2);
+        assert!(IngestionStats::default()
+            .top(10, SortBy::Samples)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_rate() {
+        let now = Instant::now();
+        let mut rate = Rate::new(now);
+        for i in 1..=600 {
+            rate.add(100, now + Duration::from_secs(i));
+        }
+        let at = rate.at(now + Duration::from_secs(600));
+        assert!((99.0..101.0).contains(&at), "rate {}", at);
+        assert!(rate.at(now + Duration::from_secs(1200)) < 1.0);
+    }
+}
@@ -0,0 +1,132 @@
+use super::source::{encode_record, SourceLines};
+use crate::querypb::query_response::Report;
+use crate::querypb::query_stream_response::Chunk;
+use crate::querypb::{QueryResponse, QueryStreamResponse, Source};
+use tokio_stream::Stream;
+use tonic::Status;
+
+/// Maximum number of rows of the record batches of streamed reports.
+pub(crate) const BATCH_ROWS: usize = 64 * 1024;
+/// Maximum size of the content chunks of streamed reports.
+pub(crate) const CONTENT_BYTES: usize = 1 << 20;
+
+/// Streams the source report: a header with its unit, the values of the
+/// lines in record batches of at most `rows` rows, each encoded as it's
+/// sent, then the source in chunks of at most [`CONTENT_BYTES`] bytes. A
+/// report without lines still sends a batch, so that its schema is sent.
+pub(crate) fn source_report(
+    report: SourceLines,
+    rows: usize,
+) -> impl Stream<Item = Result<QueryStreamResponse, Status>> + Send {
+    async_stream::stream! {
+        let message = |chunk| QueryStreamResponse { chunk: Some(chunk) };
+        yield Ok(message(Chunk::Header(QueryResponse {
+            report: Some(Report::Source(Source {
+                record: vec![],
+                source: String::new(),
+                unit: report.unit,
+            })),
+            total: 0,
+            filtered: 0,
+        })));
+        if let Some(lines) = report.lines {
+            let mut batches: Vec<&[_]> = lines.chunks(rows.max(1)).collect();
+            if batches.is_empty() {
+                batches.push(&[]);
+            }
+            for batch in batches {
+                match encode_record(batch) {
+                    Ok(batch) => yield Ok(message(Chunk::RecordBatch(batch))),
+                    Err(e) => {
+                        yield Err(Status::internal(format!("encoding the record: {}", e)));
+                        return;
+                    }
+                }
+            }
+        }
+        for content in report.source.as_bytes().chunks(CONTENT_BYTES) {
+            yield Ok(message(Chunk::Content(content.to_vec())));
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::query::source::LineValues;
+    use arrow2::array::Int64Array;
+    use arrow2::io::ipc::read::{read_stream_metadata, StreamReader, StreamState};
+    use std::io::Cursor;
+    use tokio_stream::StreamExt;
+
+    /// Returns the line number, flat and cumulative columns of the record
+    /// batch.
+    pub(crate) fn columns(batch: &[u8]) -> [Vec<i64>; 3] {
+        let mut reader = Cursor::new(batch);
+        let metadata = read_stream_metadata(&mut reader).unwrap();
+        let mut columns: [Vec<i64>; 3] = Default::default();
+        for state in StreamReader::new(reader, metadata, None) {
+            let StreamState::Some(chunk) = state.unwrap() else {
+                panic!("truncated batch");
+            };
+            for (values, array) in columns.iter_mut().zip(chunk.arrays()) {
+                let array = array.as_any().downcast_ref::<Int64Array>().unwrap();
+                values.extend(array.values().iter());
+            }
+        }
+        columns
+    }
+
+    fn line_numbers(batch: &[u8]) -> Vec<i64> {
+        let [lines, _, _] = columns(batch);
+        lines
+    }
+
+    async fn collect(report: SourceLines, rows: usize) -> Vec<Chunk> {
+        source_report(report, rows)
+            .map(|m| m.unwrap().chunk.unwrap())
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_source_report() {
+        let report = |lines| SourceLines {
+            source: "fn main() {}\n".into(),
+            unit: "nanoseconds".into(),
+            lines,
+        };
+        let lines = (0..10).map(|line| (line, LineValues::default())).collect();
+        let chunks = collect(report(Some(lines)), 4).await;
+        let Chunk::Header(QueryResponse {
+            report: Some(Report::Source(header)),
+            ..
+        }) = &chunks[0]
+        else {
+            panic!("missing header");
+        };
+        assert_eq!(header.unit, "nanoseconds");
+        let batches: Vec<Vec<i64>> = chunks[1..4]
+            .iter()
+            .map(|c| match c {
+                Chunk::RecordBatch(batch) => line_numbers(batch),
+                _ => panic!("unexpected chunk {:?}", c),
+            })
+            .collect();
+        assert_eq!(
+            batches,
+            vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]
+        );
+        assert!(matches!(&chunks[4], Chunk::Content(c) if c == b"fn main() {}\n"));
+        assert_eq!(chunks.len(), 5);
+
+        // The schema of a report without lines is still sent, and none of a
+        // report of the source only.
+        let chunks = collect(report(Some(vec![])), 4).await;
+        assert!(matches!(&chunks[1], Chunk::RecordBatch(b) if line_numbers(b).is_empty()));
+        assert_eq!(chunks.len(), 3);
+        let chunks = collect(report(None), 4).await;
+        assert!(matches!(chunks[1], Chunk::Content(_)));
+        assert_eq!(chunks.len(), 2);
+    }
+}
@@ -0,0 +1,439 @@
+use super::executor::{QueryExecutor, QueryMemoryError};
+use super::index::{profile_type_key, TimeRange};
+use super::samples::SampleReader;
+use super::selector::Selector;
+use crate::normalizer::{Metastore, NormalizedWriteRawRequest};
+use crate::profile::PprofLocations;
+use crate::querypb::ProfileType;
+use crate::symbolizer::fallback::fallback_name;
+use crate::tracespb::trace_service_server::TraceService;
+use crate::tracespb::{ProfilesForTraceRequest, ProfilesForTraceResponse, TraceSample};
+use moka::sync::Cache;
+use prost_types::Timestamp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tonic::{Request, Response, Status};
+
+/// Maximum number of samples kept per trace or span ID. Later samples of the
+/// ID are dropped.
+const MAX_SAMPLES_PER_TRACE: usize = 10_000;
+
+#[derive(Debug)]
+struct IndexedSample {
+    profile_type: String,
+    labels: HashMap<String, String>,
+    timestamp: i64,
+    value: i64,
+    locations: Vec<Vec<u8>>,
+}
+
+impl IndexedSample {
+    /// Returns an estimate of the heap and inline size of the sample, as
+    /// weighed by the index.
+    fn memory_usage(&self) -> u64 {
+        let labels: usize = self.labels.iter().map(|(k, v)| k.len() + v.len()).sum();
+        let locations: usize = self.locations.iter().map(|l| l.len()).sum();
+        (size_of::<Self>() + self.profile_type.len() + labels + locations) as u64
+    }
+}
+
+/// Samples of a trace or span ID.
+type TraceSamples = Arc<Mutex<Vec<Arc<IndexedSample>>>>;
+
+/// TraceIndex keeps the recently ingested samples that carry a trace or span
+/// ID in one of the configured pprof labels, keyed by the ID, so that the
+/// stacks a trace ran can be looked up without scanning stored profiles.
+/// The index is weighed by the size of its samples.
+#[derive(Debug)]
+pub struct TraceIndex {
+    label_names: Vec<String>,
+    retention: Duration,
+    traces: Option<Cache<String, TraceSamples>>,
+    metastore: Arc<Metastore>,
+}
+
+impl Default for TraceIndex {
+    fn default() -> Self {
+        Self::new(vec![], Duration::ZERO, 0, Arc::default())
+    }
+}
+
+impl TraceIndex {
+    /// Creates an index of the samples labeled with any of `label_names`,
+    /// keeping up to `max_bytes` of samples for `retention`. Zero retention
+    /// or no label names disable the index.
+    pub fn new(
+        label_names: Vec<String>,
+        retention: Duration,
+        max_bytes: u64,
+        metastore: Arc<Metastore>,
+    ) -> Self {
+        let traces = (!retention.is_zero() && !label_names.is_empty()).then(|| {
+            Cache::builder()
+                .max_capacity(max_bytes)
+                .weigher(|_, samples: &TraceSamples| {
+                    let size: u64 = samples
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .map(|s| s.memory_usage())
+                        .sum();
+                    size.try_into().unwrap_or(u32::MAX)
+                })
+                .time_to_live(retention)
+                .build()
+        });
+        Self {
+            label_names,
+            retention,
+            traces,
+            metastore,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.traces.is_some()
+    }
+
+    /// Adds the samples of a normalized request that carry a trace or span
+    /// ID to the index.
+    pub fn observe(&self, request: &NormalizedWriteRawRequest) {
+        let Some(traces) = &self.traces else {
+            return;
+        };
+
+        for s in request.series.iter() {
+            for profile in s.samples.iter().flatten() {
+                let meta = &profile.meta;
+                let profile_type = profile_type_key(&ProfileType {
+                    name: meta.name.clone(),
+                    sample_type: meta.sample_type.type_.clone(),
+                    sample_unit: meta.sample_type.unit.clone(),
+                    period_type: meta.period_type.type_.clone(),
+                    period_unit: meta.period_type.unit.clone(),
+                    delta: meta.delta,
+                });
+
+                for sample in profile.samples.iter() {
+                    let ids: Vec<&String> = self
+                        .label_names
+                        .iter()
+                        .filter_map(|name| sample.label.get(name))
+                        .filter(|id| !id.is_empty())
+                        .collect();
+                    if ids.is_empty() {
+                        continue;
+                    }
+
+                    let mut labels = s.labels.clone();
+                    for (k, v) in sample.label.iter() {
+                        labels.entry(k.clone()).or_insert_with(|| v.clone());
+                    }
+                    let indexed = Arc::new(IndexedSample {
+                        profile_type: profile_type.clone(),
+                        labels,
+                        timestamp: meta.timestamp,
+                        value: sample.value,
+                        locations: sample.locations.clone(),
+                    });
+                    for id in ids {
+                        let samples = traces.get_with_by_ref(id.as_str(), Arc::default);
+                        {
+                            let mut samples = samples.lock().unwrap();
+                            if samples.len() >= MAX_SAMPLES_PER_TRACE {
+                                continue;
+                            }
+                            samples.push(Arc::clone(&indexed));
+                        }
+                        // Inserted again, so that the cache weighs the sample.
+                        traces.insert(id.clone(), samples);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the samples labeled with the trace or span ID, oldest first.
+    pub fn samples(&self, id: &str) -> Vec<TraceSample> {
+        let Some(samples) = self.traces.as_ref().and_then(|t| t.get(id)) else {
+            return vec![];
+        };
+        let mut samples: Vec<TraceSample> = samples
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|s| TraceSample {
+                profile_type: s.profile_type.clone(),
+                labels: s.labels.clone(),
+                timestamp: Some(timestamp(s.timestamp)),
+                value: s.value,
+                stack: s.locations.iter().flat_map(|l| self.frames(l)).collect(),
+            })
+            .collect();
+        samples.sort_by_key(|s| s.timestamp.map(|t| (t.seconds, t.nanos)));
+        samples
+    }
+
+    /// Resolves an encoded location to its function names, innermost inlined
+    /// function first, or to its address if it has no named function.
+    fn frames(&self, location: &[u8]) -> Vec<String> {
+        let Ok(location) = PprofLocations::decode(location) else {
+            return vec!["<invalid location>".into()];
+        };
+        let names: Vec<String> = location
+            .functions
+            .iter()
+            .filter_map(|f| self.metastore.function(&f.id))
+            .map(|f| f.name)
+            .filter(|name| !name.is_empty())
+            .collect();
+        if names.is_empty() {
+            let name = fallback_name(location.address, &location.file_name)
+                .map(String::from)
+                .unwrap_or_else(|| format!("0x{:x}", location.address));
+            return vec![name];
+        }
+        names
+    }
+}
+
+/// Returns the timestamp in milliseconds as a protobuf timestamp.
+fn timestamp(millis: i64) -> Timestamp {
+    Timestamp {
+        seconds: millis.div_euclid(1000),
+        nanos: (millis.rem_euclid(1000) * 1_000_000) as i32,
+    }
+}
+
+/// Traces serves the trace correlation API. IDs that aren't in the index,
+/// like the ones of samples ingested before a restart, are looked up in the
+/// pprof labels of the samples stored within the retention of the index.
+#[derive(Debug)]
+pub struct Traces {
+    index: Arc<TraceIndex>,
+    stored: Option<(SampleReader, Arc<QueryExecutor>)>,
+}
+
+impl Traces {
+    pub fn new(index: Arc<TraceIndex>) -> Self {
+        Self {
+            index,
+            stored: None,
+        }
+    }
+
+    /// Looks up the IDs missing from the index in the samples read by
+    /// `reader`, run with `executor`.
+    pub fn with_sample_reader(
+        mut self,
+        reader: SampleReader,
+        executor: Arc<QueryExecutor>,
+    ) -> Self {
+        self.stored = Some((reader, executor));
+        self
+    }
+
+    /// Returns the stored samples labeled with the trace or span ID within
+    /// the retention of the index, oldest first. Stored samples only keep
+    /// the metadata labels of their series.
+    async fn stored_samples(&self, id: &str) -> anyhow::Result<Vec<TraceSample>> {
+        let Some((reader, executor)) = &self.stored else {
+            return Ok(vec![]);
+        };
+        let now = chrono::Utc::now().timestamp_millis();
+        let range = TimeRange {
+            start: now.saturating_sub(self.index.retention.as_millis() as i64),
+            end: now,
+        };
+        let selectors = self
+            .index
+            .label_names
+            .iter()
+            .map(|name| Selector::parse(&format!("{{{}={:?}}}", name, id)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut query = executor.start().await;
+        let mut samples = vec![];
+        for segment in reader.segments(range).await? {
+            let segment_samples = reader.read_segment(&segment, &selectors, range).await?;
+            query.reserve(segment_samples.iter().map(|s| s.memory_usage()).sum())?;
+            samples.extend(segment_samples.into_iter().map(|s| TraceSample {
+                timestamp: Some(timestamp(s.timestamp)),
+                value: s.value,
+                stack: s.stacktrace.iter().map(|f| f.name()).collect(),
+                labels: s.labels.into_iter().collect(),
+                profile_type: s.profile_type,
+            }));
+        }
+        samples.sort_by_key(|s| s.timestamp.map(|t| (t.seconds, t.nanos)));
+        Ok(samples)
+    }
+}
+
+#[tonic::async_trait]
+impl TraceService for Traces {
+    async fn profiles_for_trace(
+        &self,
+        request: Request<ProfilesForTraceRequest>,
+    ) -> Result<Response<ProfilesForTraceResponse>, Status> {
+        let request = request.into_inner();
+        if request.trace_id.is_empty() {
+            return Err(Status::invalid_argument("trace_id is required"));
+        }
+        let mut samples = self.index.samples(&request.trace_id);
+        if samples.is_empty() {
+            samples =
+                self.stored_samples(&request.trace_id)
+                    .await
+                    .map_err(|e| match e.downcast::<QueryMemoryError>() {
+                        Ok(e) => e.into(),
+                        Err(e) => Status::internal(format!("trace lookup failed: {:#}", e)),
+                    })?;
+        }
+        Ok(Response::new(ProfilesForTraceResponse { samples }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingester::encode_parquet;
+    use crate::metapb::Function;
+    use crate::normalizer::normalized_request_to_arrow_chunk;
+    use crate::normalizer::write_raw::tests::{labels_map, profile, sample, RequestBuilder};
+    use object_store::memory::InMemory;
+    use object_store::path::Path;
+
+    fn request(
+        metastore: &Metastore,
+        samples: Vec<(i64, &[(&str, &str)])>,
+    ) -> NormalizedWriteRawRequest {
+        let location = |name: &str, address| {
+            let function = metastore.intern(&Function {
+                name: name.into(),
+                ..Default::default()
+            });
+            PprofLocations {
+                address,
+                number_of_lines: 1,
+                build_id: String::new(),
+                file_name: String::new(),
+                mapping_memory_start: 0,
+                mapping_memory_end: 0,
+                mapping_file_offset: 0,
+                functions: vec![function],
+            }
+            .encode()
+            .unwrap()
+        };
+        let profiles = samples
+            .into_iter()
+            .map(|(timestamp, labels)| {
+                let mut sample = sample(vec![location("handle", 0x10), location("", 0x20)], 3);
+                sample.label = labels_map(labels);
+                profile(timestamp, vec![sample])
+            })
+            .collect();
+        RequestBuilder::default()
+            .with_series(&[("job", "api")], profiles)
+            .build()
+    }
+
+    #[test]
+    fn test_trace_index() {
+        let metastore = Arc::new(Metastore::default());
+        let index = TraceIndex::new(
+            vec!["trace_id".into(), "span_id".into()],
+            Duration::from_secs(60),
+            1 << 20,
+            Arc::clone(&metastore),
+        );
+        index.observe(&request(
+            &metastore,
+            vec![
+                (2000, &[("trace_id", "t1"), ("span_id", "s1")]),
+                (1000, &[("trace_id", "t1")]),
+                (3000, &[("goroutine", "7")]),
+            ],
+        ));
+
+        let samples = index.samples("t1");
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].timestamp.unwrap().seconds, 1);
+        assert_eq!(
+            samples[0].profile_type,
+            "process_cpu:samples:count:cpu:nanoseconds:delta"
+        );
+        assert_eq!(samples[0].labels["job"], "api");
+        assert_eq!(samples[0].labels["trace_id"], "t1");
+        assert_eq!(samples[0].value, 3);
+        assert_eq!(samples[0].stack, ["handle", "0x20"]);
+
+        assert_eq!(index.samples("s1").len(), 1);
+        assert!(index.samples("7").is_empty());
+
+        // Samples are weighed by their size.
+        let small = TraceIndex::new(
+            vec!["trace_id".into()],
+            Duration::from_secs(60),
+            64,
+            Arc::clone(&metastore),
+        );
+        small.observe(&request(&metastore, vec![(1000, &[("trace_id", "t1")])]));
+        small.traces.as_ref().unwrap().run_pending_tasks();
+        assert!(small.samples("t1").is_empty());
+
+        let disabled = TraceIndex::default();
+        disabled.observe(&request(&metastore, vec![(1000, &[("trace_id", "t1")])]));
+        assert!(disabled.samples("t1").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stored_samples() {
+        let storage: Arc<dyn object_store::ObjectStore> = Arc::new(InMemory::new());
+        let metastore = Arc::new(Metastore::default());
+        let now = chrono::Utc::now().timestamp_millis();
+        let written = request(
+            &metastore,
+            vec![
+                (now - 1000, &[("trace_id", "t1")]),
+                (now - 2000, &[("span_id", "t1")]),
+                (now - 3000, &[("trace_id", "t2")]),
+            ],
+        );
+        let chunk = normalized_request_to_arrow_chunk(&written).await.unwrap();
+        storage
+            .put(
+                &Path::from(format!(
+                    "date={}/1.parquet",
+                    chrono::Utc::now().date_naive()
+                )),
+                encode_parquet(&[chunk]).unwrap().into(),
+            )
+            .await
+            .unwrap();
+
+        // Samples ingested before a restart are only in the storage.
+        let index = Arc::new(TraceIndex::new(
+            vec!["trace_id".into(), "span_id".into()],
+            Duration::from_secs(60),
+            1 << 20,
+            Arc::clone(&metastore),
+        ));
+        let reader = SampleReader::new(storage, metastore);
+        let traces = Traces::new(index).with_sample_reader(reader, Arc::default());
+        let samples = traces
+            .profiles_for_trace(Request::new(ProfilesForTraceRequest {
+                trace_id: "t1".into(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .samples;
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].labels["span_id"], "t1");
+        assert_eq!(samples[1].labels["trace_id"], "t1");
+        assert_eq!(samples[1].stack, ["handle", "0x20"]);
+    }
+}
@@ -0,0 +1,439 @@
+use crate::adminpb::admin_service_server::AdminService;
+use crate::adminpb::{
+    ExportProfilesRequest, ExportProfilesResponse, GetDiffReportsRequest, GetDiffReportsResponse,
+    GetSeriesStatsRequest, GetSeriesStatsResponse, GetStorageUsageRequest, GetStorageUsageResponse,
+    ListPayloadsRequest, ListPayloadsResponse, Payload, ReplayPayloadRequest,
+    ReplayPayloadResponse, SymbolizeRequest, SymbolizeResponse, SymbolizedAddress, SymbolizedFrame,
+};
+use crate::memory::MemoryUsage;
+use crate::metapb::Mapping;
+use crate::profile::Location;
+use crate::profile_store::ProfileStore;
+use crate::profilestorepb::WriteRawRequest;
+use crate::query::{self, DiffReports, IngestionStats, ParquetExport};
+use crate::storage::StorageUsage;
+use crate::symbolizer::{SymbolizationRequest, SymbolizationRequestMappingAddrs, Symbolizer};
+use chrono::{DateTime, Utc};
+use moka::sync::Cache;
+use prost::Message;
+use prost_types::Timestamp;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::{Request, Response, Status};
+
+/// Number of series returned by GetSeriesStats unless requested otherwise.
+const DEFAULT_SERIES_STATS_LIMIT: usize = 100;
+
+#[derive(Debug)]
+struct StoredPayload {
+    agent: String,
+    received_at: DateTime<Utc>,
+    request: WriteRawRequest,
+}
+
+impl StoredPayload {
+    /// Size of the raw profiles of the payload.
+    fn size(&self) -> u64 {
+        self.request
+            .series
+            .iter()
+            .flat_map(|s| s.samples.iter())
+            .map(|s| s.raw_profile.len() as u64)
+            .sum()
+    }
+}
+
+/// PayloadBuffer keeps recently received WriteRaw payloads for a while, so
+/// that issues reported on specific payloads can be reproduced by replaying
+/// them. Payloads are kept with their labels scrubbed.
+#[derive(Debug)]
+pub struct PayloadBuffer {
+    payloads: Cache<String, Arc<StoredPayload>>,
+}
+
+impl MemoryUsage for PayloadBuffer {
+    fn memory_usage(&self) -> u64 {
+        self.payloads
+            .iter()
+            .map(|(_, p)| p.request.encoded_len())
+            .sum::<usize>() as u64
+    }
+}
+
+impl PayloadBuffer {
+    /// Keeps payloads for `retention`, evicting the oldest ones once their
+    /// raw profiles take more than `max_bytes`.
+    pub fn new(retention: Duration, max_bytes: u64) -> Self {
+        Self {
+            payloads: Cache::builder()
+                .weigher(|_, p: &Arc<StoredPayload>| p.size().try_into().unwrap_or(u32::MAX))
+                .max_capacity(max_bytes)
+                .time_to_live(retention)
+                .build(),
+        }
+    }
+
+    /// Keeps the payload, whose labels must have been scrubbed, and returns
+    /// its ID.
+    pub fn record(&self, agent: &str, request: WriteRawRequest) -> String {
+        let id = ulid::Ulid::new().to_string();
+        self.payloads.insert(
+            id.clone(),
+            Arc::new(StoredPayload {
+                agent: agent.to_string(),
+                received_at: Utc::now(),
+                request,
+            }),
+        );
+        id
+    }
+
+    /// Returns the kept payloads, oldest first.
+    fn list(&self) -> Vec<Payload> {
+        let mut payloads: Vec<Payload> = self
+            .payloads
+            .iter()
+            .map(|(id, p)| Payload {
+                id: id.to_string(),
+                agent: p.agent.clone(),
+                received_at: Some(Timestamp {
+                    seconds: p.received_at.timestamp(),
+                    nanos: p.received_at.timestamp_subsec_nanos() as i32,
+                }),
+                size: p.size(),
+                series: p.request.series.len() as u32,
+            })
+            .collect();
+        // ULIDs sort by creation time, up to the millisecond.
+        payloads.sort_by(|a, b| a.id.cmp(&b.id));
+        payloads
+    }
+}
+
+/// Admin serves the debugging endpoints of the admin API.
+#[derive(Debug)]
+pub struct Admin {
+    store: Arc<ProfileStore>,
+    payloads: Option<Arc<PayloadBuffer>>,
+    storage_usage: Vec<Arc<StorageUsage>>,
+    symbolizer: Option<Arc<Symbolizer>>,
+    stats: Arc<IngestionStats>,
+    export: Option<Arc<ParquetExport>>,
+    diff_reports: Option<Arc<DiffReports>>,
+    /// Bearer token requests must carry.
+    token: String,
+}
+
+impl Admin {
+    /// Creates the admin API, only serving requests carrying `token` as
+    /// bearer token. Payloads can only be listed and replayed if `payloads`
+    /// are kept.
+    pub fn new(
+        store: Arc<ProfileStore>,
+        payloads: Option<Arc<PayloadBuffer>>,
+        token: String,
+    ) -> Self {
+        Self {
+            store,
+            payloads,
+            storage_usage: vec![],
+            symbolizer: None,
+            stats: Arc::default(),
+            export: None,
+            diff_reports: None,
+            token,
+        }
+    }
+
+    /// Fails unless the request carries the admin token.
+    fn authorize<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let given = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        // Digests are compared, so that the time taken doesn't tell how much
+        // of the token is right.
+        let digest = |v: &str| ring::digest::digest(&ring::digest::SHA256, v.as_bytes());
+        if digest(given).as_ref() != digest(&self.token).as_ref() {
+            return Err(Status::unauthenticated("invalid admin token"));
+        }
+        Ok(())
+    }
+
+    pub fn with_storage_usage(mut self, storage_usage: Vec<Arc<StorageUsage>>) -> Self {
+        self.storage_usage = storage_usage;
+        self
+    }
+
+    /// Serves the series stats of `stats`.
+    pub fn with_ingestion_stats(mut self, stats: Arc<IngestionStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Symbolizes addresses on request with `symbolizer`.
+    pub fn with_symbolizer(mut self, symbolizer: Arc<Symbolizer>) -> Self {
+        self.symbolizer = Some(symbolizer);
+        self
+    }
+
+    /// Exports stored samples on request with `export`.
+    pub fn with_profile_export(mut self, export: Arc<ParquetExport>) -> Self {
+        self.export = Some(export);
+        self
+    }
+
+    /// Serves the version diff reports of `reports`.
+    pub fn with_diff_reports(mut self, reports: Arc<DiffReports>) -> Self {
+        self.diff_reports = Some(reports);
+        self
+    }
+}
+
+fn payloads_not_kept() -> Status {
+    Status::failed_precondition("payloads are not kept, set --replay-retention")
+}
+
+#[tonic::async_trait]
+impl AdminService for Admin {
+    async fn list_payloads(
+        &self,
+        request: Request<ListPayloadsRequest>,
+    ) -> Result<Response<ListPayloadsResponse>, Status> {
+        self.authorize(&request)?;
+        Ok(Response::new(ListPayloadsResponse {
+            payloads: self.payloads.as_ref().ok_or_else(payloads_not_kept)?.list(),
+        }))
+    }
+
+    async fn replay_payload(
+        &self,
+        request: Request<ReplayPayloadRequest>,
+    ) -> Result<Response<ReplayPayloadResponse>, Status> {
+        self.authorize(&request)?;
+        let request = request.into_inner();
+        let payload = self
+            .payloads
+            .as_ref()
+            .ok_or_else(payloads_not_kept)?
+            .payloads
+            .get(&request.id)
+            .ok_or_else(|| {
+                Status::not_found(format!("payload {} not found or expired", request.id))
+            })?;
+
+        log::info!(
+            "Replaying payload {} of agent {} (store: {})",
+            request.id,
+            payload.agent,
+            request.store
+        );
+        let mut trace = vec![];
+        let res = self
+            .store
+            .replay(&payload.request, request.store, &mut trace)
+            .await;
+        for line in trace.iter() {
+            log::debug!("Replay {}: {}", request.id, line);
+        }
+
+        Ok(Response::new(ReplayPayloadResponse {
+            trace,
+            error: res.err().map(|e| e.to_string()).unwrap_or_default(),
+        }))
+    }
+
+    async fn get_storage_usage(
+        &self,
+        request: Request<GetStorageUsageRequest>,
+    ) -> Result<Response<GetStorageUsageResponse>, Status> {
+        self.authorize(&request)?;
+        Ok(Response::new(GetStorageUsageResponse {
+            buckets: self.storage_usage.iter().map(|u| u.report()).collect(),
+        }))
+    }
+
+    async fn get_series_stats(
+        &self,
+        request: Request<GetSeriesStatsRequest>,
+    ) -> Result<Response<GetSeriesStatsResponse>, Status> {
+        self.authorize(&request)?;
+        if !self.stats.is_enabled() {
+            return Err(Status::failed_precondition(
+                "series stats are not tracked, set --max-tracked-series",
+            ));
+        }
+        let request = request.into_inner();
+        let limit = match request.limit {
+            0 => DEFAULT_SERIES_STATS_LIMIT,
+            limit => limit as usize,
+        };
+        Ok(Response::new(GetSeriesStatsResponse {
+            series: self.stats.top(limit, request.sort_by()),
+        }))
+    }
+
+    async fn export_profiles(
+        &self,
+        request: Request<ExportProfilesRequest>,
+    ) -> Result<Response<ExportProfilesResponse>, Status> {
+        self.authorize(&request)?;
+        let export = self
+            .export
+            .as_ref()
+            .ok_or_else(|| Status::failed_precondition("exports are disabled, set --export-dir"))?;
+        let request = request.into_inner();
+        let destination = object_store::path::Path::parse(&request.destination)
+            .map_err(|e| Status::invalid_argument(format!("invalid destination: {}", e)))?;
+        if destination.parts().count() == 0 {
+            return Err(Status::invalid_argument("destination is required"));
+        }
+        let matchers = match request.query.trim() {
+            "" => vec![],
+            query => vec![query.to_string()],
+        };
+        let selectors = query::parse_selectors(&matchers, None)?;
+        let range = query::time_range(request.start, request.end);
+
+        let summary = export
+            .run(&selectors, range, &destination)
+            .await
+            .map_err(|e| match e.downcast::<query::QueryMemoryError>() {
+                Ok(e) => e.into(),
+                Err(e) => Status::internal(format!("export failed: {:#}", e)),
+            })?;
+        Ok(Response::new(ExportProfilesResponse {
+            files: summary.files,
+            samples: summary.samples,
+        }))
+    }
+
+    async fn get_diff_reports(
+        &self,
+        request: Request<GetDiffReportsRequest>,
+    ) -> Result<Response<GetDiffReportsResponse>, Status> {
+        self.authorize(&request)?;
+        let reports = self.diff_reports.as_ref().ok_or_else(|| {
+            Status::failed_precondition("diff reports are disabled, set --diff-version-label")
+        })?;
+        Ok(Response::new(GetDiffReportsResponse {
+            reports: reports.reports(&request.into_inner().profile_type),
+        }))
+    }
+
+    async fn symbolize(
+        &self,
+        request: Request<SymbolizeRequest>,
+    ) -> Result<Response<SymbolizeResponse>, Status> {
+        self.authorize(&request)?;
+        let symbolizer = self
+            .symbolizer
+            .as_ref()
+            .ok_or_else(|| Status::failed_precondition("symbolization is not enabled"))?;
+        let request = request.into_inner();
+        if request.build_id.is_empty() {
+            return Err(Status::invalid_argument("build_id is required"));
+        }
+        let mapping = Mapping {
+            start: request.mapping_start,
+            limit: request.mapping_limit,
+            offset: request.mapping_offset,
+            ..Default::default()
+        };
+        let mut symbolization = SymbolizationRequest {
+            build_id: request.build_id.clone(),
+            mappings: vec![SymbolizationRequestMappingAddrs {
+                locations: request
+                    .addresses
+                    .iter()
+                    .map(|&address| Location {
+                        id: String::new(),
+                        address,
+                        is_folded: false,
+                        mapping: Some(mapping.clone()),
+                        lines: vec![],
+                    })
+                    .collect(),
+            }],
+        };
+
+        log::info!(
+            "Symbolizing {} addresses of build_id {} on request",
+            request.addresses.len(),
+            request.build_id
+        );
+        let (failures, error) = match symbolizer.dry_run(&mut symbolization).await {
+            Ok(failures) => (failures, String::new()),
+            Err(e) => (vec![], e.to_string()),
+        };
+        let addresses = symbolization
+            .mappings
+            .swap_remove(0)
+            .locations
+            .into_iter()
+            .map(|location| SymbolizedAddress {
+                address: location.address,
+                error: failures
+                    .iter()
+                    .find(|(address, _)| *address == location.address)
+                    .map(|(_, e)| e.clone())
+                    .unwrap_or_default(),
+                frames: location
+                    .lines
+                    .into_iter()
+                    .map(|line| {
+                        let function = line.function.unwrap_or_default();
+                        SymbolizedFrame {
+                            function_name: function.name,
+                            system_name: function.system_name,
+                            filename: function.filename,
+                            line: line.line,
+                        }
+                    })
+                    .collect(),
+            })
+            .collect();
+        Ok(Response::new(SymbolizeResponse { addresses, error }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profilestorepb::{RawProfileSeries, RawSample};
+
+    #[test]
+    fn test_payload_buffer() {
+        let buffer = PayloadBuffer::new(Duration::from_secs(60), 10);
+        let request = WriteRawRequest {
+            series: vec![RawProfileSeries {
+                labels: None,
+                samples: vec![RawSample {
+                    raw_profile: vec![0; 5],
+                    executable_info: vec![],
+                }],
+            }],
+            ..Default::default()
+        };
+
+        let first = buffer.record("agent-a", request.clone());
+        let second = buffer.record("agent-b", WriteRawRequest::default());
+        buffer.payloads.run_pending_tasks();
+
+        let payloads = buffer.list();
+        assert_eq!(payloads.len(), 2);
+        let a = payloads.iter().find(|p| p.id == first).unwrap();
+        assert_eq!(a.agent, "agent-a");
+        assert_eq!((a.size, a.series), (5, 1));
+        assert!(payloads.iter().any(|p| p.id == second));
+
+        // Payloads are weighed by the size of their raw profiles.
+        let mut large = request;
+        large.series[0].samples[0].raw_profile = vec![0; 8];
+        buffer.record("agent-a", large);
+        buffer.payloads.run_pending_tasks();
+        assert!(buffer.payloads.weighted_size() <= 10);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::profilestorepb::{RawProfileSeries, RawSample, WriteRawRequest};
+use prometheus::{register_gauge, register_int_counter, Gauge, IntCounter};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+
+static DROP_FRACTION: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "evprofiler_adaptive_sampling_drop_fraction",
+        "Fraction of the series of written profiles currently dropped because of overload."
+    )
+    .unwrap()
+});
+
+static DROPPED_PROFILES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_adaptive_sampling_dropped_profiles_total",
+        "Total number of written profiles dropped by adaptive sampling because of overload."
+    )
+    .unwrap()
+});
+
+/// Reason the samples of series dropped by adaptive sampling are reported
+/// with.
+pub const DROPPED_SAMPLED_OUT: &str = "sampled_out";
+
+/// How much the drop fraction changes per write, so that it takes dozens of
+/// writes in a row over the limit to drop the maximum fraction.
+const STEP: f64 = 0.02;
+
+/// AdaptiveSampling drops a fraction of the series of written profiles while
+/// more writes are in flight than the server keeps up with, rather than
+/// queuing them until it runs out of memory. The fraction grows with every
+/// write while overloaded and shrinks back once the overload is over.
+/// Whether a series is dropped depends on its labels, so that the kept series
+/// stay complete.
+#[derive(Debug, Default)]
+pub struct AdaptiveSampling {
+    max_in_flight: usize,
+    max_drop_fraction: f64,
+    in_flight: Arc<AtomicUsize>,
+    drop_fraction: Mutex<f64>,
+}
+
+/// InFlight counts a write as in flight until it is dropped, which should be
+/// once its samples are persisted.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    in_flight: Option<Arc<AtomicUsize>>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(in_flight) = &self.in_flight {
+            in_flight.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl AdaptiveSampling {
+    /// Creates a sampler considering the server overloaded with more than
+    /// `max_in_flight` writes in flight, dropping up to `max_drop_fraction`
+    /// of the series then. Zero disables sampling.
+    pub fn new(max_in_flight: usize, max_drop_fraction: f64) -> Self {
+        Self {
+            max_in_flight,
+            max_drop_fraction: max_drop_fraction.clamp(0.0, 1.0),
+            ..Default::default()
+        }
+    }
+
+    /// Counts the write as in flight and takes the samples of the series of
+    /// the request that are sampled out, which are returned by the index of
+    /// their series. Dropped series are kept without samples, so that the
+    /// response still has a count for each series.
+    pub fn admit(
+        &self,
+        request: &mut WriteRawRequest,
+    ) -> (InFlight, BTreeMap<usize, Vec<RawSample>>) {
+        let mut sampled_out = BTreeMap::new();
+        if self.max_in_flight == 0 {
+            return (InFlight::default(), sampled_out);
+        }
+        let in_flight = self.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        let fraction = {
+            let mut fraction = self.drop_fraction.lock().unwrap();
+            *fraction = if in_flight > self.max_in_flight {
+                (*fraction + STEP).min(self.max_drop_fraction)
+            } else {
+                (*fraction - STEP).max(0.0)
+            };
+            *fraction
+        };
+        DROP_FRACTION.set(fraction);
+
+        if fraction > 0.0 {
+            for (i, series) in request.series.iter_mut().enumerate() {
+                if position(series) < fraction && !series.samples.is_empty() {
+                    DROPPED_PROFILES.inc_by(series.samples.len() as u64);
+                    sampled_out.insert(i, std::mem::take(&mut series.samples));
+                }
+            }
+        }
+        let in_flight = InFlight {
+            in_flight: Some(Arc::clone(&self.in_flight)),
+        };
+        (in_flight, sampled_out)
+    }
+}
+
+/// Returns the position of the series in [0, 1), by its labels.
+fn position(series: &RawProfileSeries) -> f64 {
+    let mut labels = series
+        .labels
+        .iter()
+        .flat_map(|ls| ls.labels.iter())
+        .map(|l| (l.name.as_str(), l.value.as_str()))
+        .collect::<Vec<_>>();
+    labels.sort_unstable();
+    let mut hasher = DefaultHasher::new();
+    labels.hash(&mut hasher);
+    hasher.finish() as f64 / (u64::MAX as f64 + 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profilestorepb::{Label, LabelSet, RawSample};
+
+    fn request(series: usize) -> WriteRawRequest {
+        WriteRawRequest {
+            series: (0..series)
+                .map(|i| RawProfileSeries {
+                    labels: Some(LabelSet {
+                        labels: vec![Label {
+                            name: "pod".into(),
+                            value: format!("pod-{}", i),
+                        }],
+                    }),
+                    samples: vec![RawSample::default()],
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn kept(request: &WriteRawRequest) -> usize {
+        request
+            .series
+            .iter()
+            .filter(|s| !s.samples.is_empty())
+            .count()
+    }
+
+    #[test]
+    fn test_adaptive_sampling() {
+        let disabled = AdaptiveSampling::default();
+        let mut r = request(100);
+        let (_in_flight, sampled_out) = disabled.admit(&mut r);
+        assert_eq!(kept(&r), 100);
+        assert!(sampled_out.is_empty());
+
+        let sampling = AdaptiveSampling::new(2, 0.5);
+        let held = (0..100)
+            .map(|_| sampling.admit(&mut request(0)).0)
+            .collect::<Vec<_>>();
+        assert_eq!(*sampling.drop_fraction.lock().unwrap(), 0.5);
+        let mut r = request(1000);
+        let (in_flight, sampled_out) = sampling.admit(&mut r);
+        assert_eq!(sampling.in_flight.load(Ordering::Relaxed), 101);
+        drop(in_flight);
+        assert!((400..600).contains(&kept(&r)), "kept {}", kept(&r));
+        assert_eq!(sampled_out.len(), 1000 - kept(&r));
+        assert!(sampled_out.keys().all(|i| r.series[*i].samples.is_empty()));
+
+        // The same series are dropped from every write.
+        let mut again = request(1000);
+        drop(sampling.admit(&mut again));
+        assert_eq!(r, again);
+
+        drop(held);
+        for _ in 0..25 {
+            drop(sampling.admit(&mut request(0)));
+        }
+        let mut r = request(1000);
+        drop(sampling.admit(&mut r));
+        assert_eq!(kept(&r), 1000);
+    }
+}
@@ -0,0 +1,330 @@
+use super::{check_profile, parse_target, PprofClient};
+use crate::debuginfo_store::ImageReference;
+use anyhow::{bail, ensure, Context};
+use axum::http::{HeaderName, HeaderValue};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+/// Deserializes a duration in humantime format, like `15s`.
+pub fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    humantime::parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+fn default_scrape_interval() -> Duration {
+    Duration::from_secs(15)
+}
+
+fn default_scrape_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_profile_duration() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_delta_profiles() -> bool {
+    true
+}
+
+fn default_profiles() -> Vec<String> {
+    ["profile", "allocs", "goroutine", "mutex", "block"]
+        .map(String::from)
+        .to_vec()
+}
+
+/// ScrapeConfig configures how the targets of a job are scraped:
+///
+/// ```yaml
+/// job_name: api
+/// scrape_interval: 15s
+/// profile_duration: 10s
+/// profiles: [profile, heap]
+/// delta_profiles: true
+/// discover_debuginfo: true
+/// headers:
+///   Authorization: Bearer secret
+/// tls_config:
+///   ca_file: /etc/ssl/ca.pem
+/// static_configs:
+///   - targets: ["api-0:6060", "https://api-1:6060"]
+///     labels:
+///       env: prod
+///     images: ["registry.example.com/api:v3"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScrapeConfig {
+    pub job_name: String,
+    /// How often the targets are scraped.
+    #[serde(default = "default_scrape_interval", deserialize_with = "duration")]
+    pub scrape_interval: Duration,
+    /// Time a scrape may take on top of the profile duration.
+    #[serde(default = "default_scrape_timeout", deserialize_with = "duration")]
+    pub scrape_timeout: Duration,
+    /// Duration of CPU profiles, passed as the `seconds` parameter.
+    #[serde(default = "default_profile_duration", deserialize_with = "duration")]
+    pub profile_duration: Duration,
+    /// pprof endpoints pulled from every target, by their name below
+    /// `/debug/pprof/`.
+    #[serde(default = "default_profiles")]
+    pub profiles: Vec<String>,
+    /// HTTP headers sent with every scrape, like credentials.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Store cumulative profiles, like allocations, as the difference to the
+    /// previous scrape of the target.
+    #[serde(default = "default_delta_profiles")]
+    pub delta_profiles: bool,
+    /// Check whether debuginfo is available for the binaries mapped in the
+    /// profiles, which looks them up in debuginfod, and list the build IDs
+    /// without debuginfo in the target's health.
+    #[serde(default)]
+    pub discover_debuginfo: bool,
+    #[serde(default)]
+    pub tls_config: Option<TlsConfig>,
+    #[serde(default)]
+    pub static_configs: Vec<StaticConfig>,
+}
+
+/// StaticConfig lists targets sharing the same labels.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticConfig {
+    /// Targets as URL or `host:port`.
+    pub targets: Vec<String>,
+    /// Labels added to the profiles of the targets.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Container images the targets run. The debuginfo of their binaries is
+    /// extracted from the image layers when enabled.
+    #[serde(default)]
+    pub images: Vec<String>,
+}
+
+/// TlsConfig configures the TLS connections to targets served over HTTPS.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file of CA certificates trusted in addition to the system's web
+    /// PKI roots.
+    pub ca_file: Option<PathBuf>,
+    /// PEM files of the client certificate and key, for targets requiring
+    /// client authentication.
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    pub fn client_config(&self) -> anyhow::Result<Arc<rustls::ClientConfig>> {
+        let read = |path: &PathBuf| {
+            std::fs::read(path).with_context(|| format!("reading {}", path.display()))
+        };
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        if let Some(ca_file) = &self.ca_file {
+            for cert in CertificateDer::pem_slice_iter(&read(ca_file)?) {
+                let cert = cert.with_context(|| format!("parsing {}", ca_file.display()))?;
+                roots.add(cert)?;
+            }
+        }
+
+        let builder = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+        let config = match (&self.cert_file, &self.key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let certs = CertificateDer::pem_slice_iter(&read(cert_file)?)
+                    .collect::<Result<Vec<_>, _>>()
+                    .with_context(|| format!("parsing {}", cert_file.display()))?;
+                let key = PrivateKeyDer::from_pem_slice(&read(key_file)?)
+                    .with_context(|| format!("parsing {}", key_file.display()))?;
+                builder.with_client_auth_cert(certs, key)?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => bail!("cert_file and key_file must be set together"),
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl ScrapeConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        ensure!(!self.job_name.is_empty(), "job_name is required");
+        ensure!(
+            !self.scrape_interval.is_zero(),
+            "scrape_interval must be positive"
+        );
+        ensure!(
+            !self.scrape_timeout.is_zero(),
+            "scrape_timeout must be positive"
+        );
+
+        ensure!(!self.profiles.is_empty(), "profiles must not be empty");
+        let mut profiles = HashSet::new();
+        for profile in self.profiles.iter() {
+            check_profile(profile)?;
+            ensure!(profiles.insert(profile), "duplicate profile {:?}", profile);
+        }
+        if profiles.contains(&"profile".to_string()) {
+            ensure!(
+                self.profile_duration >= Duration::from_secs(1),
+                "profile_duration must be at least 1s"
+            );
+            ensure!(
+                self.profile_duration < self.scrape_interval,
+                "profile_duration {} must be shorter than scrape_interval {}",
+                humantime::format_duration(self.profile_duration),
+                humantime::format_duration(self.scrape_interval)
+            );
+        }
+
+        for (name, value) in self.headers.iter() {
+            HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid header name {:?}", name))?;
+            HeaderValue::from_str(value)
+                .with_context(|| format!("invalid value of header {:?}", name))?;
+        }
+        self.client().context("invalid tls_config")?;
+        self.targets()?;
+        self.images()?;
+        Ok(())
+    }
+
+    /// Returns the distinct container images of the targets of the job.
+    pub fn images(&self) -> anyhow::Result<Vec<ImageReference>> {
+        let mut images = vec![];
+        for image in self.static_configs.iter().flat_map(|c| c.images.iter()) {
+            let image: ImageReference = image.parse()?;
+            if !images.contains(&image) {
+                images.push(image);
+            }
+        }
+        Ok(images)
+    }
+
+    /// Returns the client targets of the job are scraped with.
+    pub fn client(&self) -> anyhow::Result<PprofClient> {
+        let tls = self
+            .tls_config
+            .as_ref()
+            .map(TlsConfig::client_config)
+            .transpose()?;
+        Ok(PprofClient::build(self.scrape_timeout, tls).with_headers(
+            self.headers
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        ))
+    }
+
+    /// Returns the targets of the job with their labels.
+    pub fn targets(&self) -> anyhow::Result<Vec<(Url, BTreeMap<String, String>)>> {
+        let mut targets = vec![];
+        let mut seen = HashSet::new();
+        for config in self.static_configs.iter() {
+            for name in config.labels.keys() {
+                ensure!(is_label_name(name), "invalid label name {:?}", name);
+                ensure!(
+                    !name.starts_with("__") && name != "job" && name != "instance",
+                    "label {:?} is reserved",
+                    name
+                );
+            }
+            for target in config.targets.iter() {
+                let url = parse_target(target)?;
+                ensure!(seen.insert(url.clone()), "duplicate target {:?}", target);
+                targets.push((url, config.labels.clone()));
+            }
+        }
+        Ok(targets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> anyhow::Result<ScrapeConfig> {
+        let config: ScrapeConfig = serde_yaml::from_str(yaml)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn test_scrape_config() {
+        let config = parse(
+            r#"
+job_name: api
+scrape_interval: 30s
+profiles: [profile, heap]
+headers:
+  Authorization: Bearer secret
+static_configs:
+  - targets: ["api-0:6060", "https://api-1:6060"]
+    labels:
+      env: prod
+    images: [api:v3, api:v3]
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.scrape_interval, Duration::from_secs(30));
+        assert_eq!(config.profile_duration, Duration::from_secs(10));
+        assert_eq!(config.profiles, ["profile", "heap"]);
+        let targets = config.targets().unwrap();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[1].0.as_str(), "https://api-1:6060/");
+        assert_eq!(targets[1].1["env"], "prod");
+        let images = config.images().unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].repository, "library/api");
+
+        let defaults = parse("job_name: api").unwrap();
+        assert_eq!(defaults.profiles.len(), 5);
+
+        for (yaml, err) in [
+            ("job_name: ''", "job_name is required"),
+            ("job_name: a\nscrape_interval: 5s", "profile_duration 10s"),
+            ("job_name: a\nprofiles: [heap, heap]", "duplicate profile"),
+            ("job_name: a\nprofiles: [../x]", "invalid profile"),
+            ("job_name: a\nheaders: {'a b': c}", "invalid header name"),
+            ("job_name: a\nunknown: 1", "unknown field"),
+            (
+                "job_name: a\nstatic_configs: [{targets: ['ftp://x']}]",
+                "invalid target",
+            ),
+            (
+                "job_name: a\nstatic_configs: [{targets: [x], labels: {job: y}}]",
+                "reserved",
+            ),
+            (
+                "job_name: a\nstatic_configs: [{targets: [x], images: ['a@']}]",
+                "invalid image reference",
+            ),
+            (
+                "job_name: a\ntls_config: {cert_file: /cert.pem}",
+                "invalid tls_config",
+            ),
+        ] {
+            let e = parse(yaml).unwrap_err();
+            assert!(format!("{:#}", e).contains(err), "{}: {:#}", yaml, e);
+        }
+    }
+}
@@ -0,0 +1,375 @@
+use super::schedule::fnv1a;
+use crate::backfill;
+use crate::normalizer::DecompressionLimits;
+use crate::pprofpb::{Location, Profile, Sample};
+use anyhow::Context;
+use prost::Message;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io::{Read, Write as _};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Sample types whose values Go's runtime accumulates since the process
+/// started, rather than measuring the current state.
+const CUMULATIVE_SAMPLE_TYPES: &[&str] = &["alloc_objects", "alloc_space", "contentions", "delay"];
+
+/// Values of the samples of a scraped profile, by sample key.
+#[derive(Debug)]
+struct Previous {
+    time_nanos: i64,
+    values: HashMap<String, Vec<i64>>,
+}
+
+/// DeltaProfiles turns cumulative profiles, like allocations or contentions,
+/// into profiles of what happened since the previous scrape of the same
+/// target, so that stored values cover the scrape interval. The previous
+/// scrape of every target is written to `dir`, if set, so that the first
+/// scrape after a restart has something to subtract from. State that can't
+/// be decoded is ignored, and overwritten by the next scrape.
+#[derive(Debug, Default)]
+pub struct DeltaProfiles {
+    dir: Option<PathBuf>,
+    previous: Mutex<HashMap<String, Previous>>,
+    limits: DecompressionLimits,
+}
+
+impl DeltaProfiles {
+    pub fn new(dir: Option<PathBuf>) -> anyhow::Result<Self> {
+        if let Some(dir) = &dir {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("creating scrape state directory {}", dir.display()))?;
+        }
+        Ok(Self {
+            dir,
+            previous: Mutex::default(),
+            limits: DecompressionLimits::default(),
+        })
+    }
+
+    /// Fails to decode scraped profiles decompressing beyond `limits`.
+    pub fn with_decompression_limits(mut self, limits: DecompressionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Decodes the profile, gzipped or not, within the decompression limits.
+    fn decode(&self, data: &[u8]) -> anyhow::Result<Profile> {
+        let mut decompressed = vec![];
+        self.limits.reader(data).read_to_end(&mut decompressed)?;
+        Ok(Profile::decode(decompressed.as_slice())?)
+    }
+
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{:016x}.pb.gz", fnv1a(key))))
+    }
+
+    /// Returns the profile of the changes since the previous scrape of the
+    /// target identified by `key`. Profiles without cumulative sample types
+    /// are returned as is. Returns None on the first scrape of a cumulative
+    /// profile, as there is nothing to subtract yet.
+    pub fn apply(&self, key: &str, data: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut profile = self.decode(data)?;
+        let cumulative: Vec<usize> = profile
+            .sample_type
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| {
+                let name = string(&profile, t.r#type);
+                CUMULATIVE_SAMPLE_TYPES.contains(&name)
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if cumulative.is_empty() {
+            return Ok(Some(data.to_vec()));
+        }
+
+        let keys = merge_samples(&mut profile);
+        let current = Previous {
+            time_nanos: profile.time_nanos,
+            values: keys
+                .iter()
+                .cloned()
+                .zip(profile.sample.iter().map(|s| s.value.clone()))
+                .collect(),
+        };
+        let previous = self.previous.lock().unwrap().remove(key);
+        let previous = match previous {
+            Some(previous) => Some(previous),
+            None => self.load(key),
+        };
+        self.store(key, &profile)?;
+
+        let delta = previous
+            .filter(|previous| previous.time_nanos < profile.time_nanos)
+            .map(|previous| {
+                subtract(&mut profile, &keys, &previous, &cumulative);
+                backfill::compress(&profile.encode_to_vec())
+            })
+            .transpose()?;
+        self.previous
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), current);
+        Ok(delta)
+    }
+
+    /// Reads the previous scrape of the target from the state directory.
+    /// State that can't be read is treated as missing.
+    fn load(&self, key: &str) -> Option<Previous> {
+        let path = self.path(key)?;
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                log::warn!("Failed to read scrape state {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let mut profile = match self.decode(&data) {
+            Ok(profile) => profile,
+            Err(e) => {
+                log::warn!("Ignoring invalid scrape state {}: {:#}", path.display(), e);
+                return None;
+            }
+        };
+        Some(Previous {
+            time_nanos: profile.time_nanos,
+            values: merge_samples(&mut profile)
+                .into_iter()
+                .zip(profile.sample.iter().map(|s| s.value.clone()))
+                .collect(),
+        })
+    }
+
+    /// Writes the scrape of the target to the state directory, replacing the
+    /// previous one at once, so that a crash never leaves a partial state.
+    fn store(&self, key: &str, profile: &Profile) -> anyhow::Result<()> {
+        let (Some(dir), Some(path)) = (&self.dir, self.path(key)) else {
+            return Ok(());
+        };
+        let write = || -> anyhow::Result<()> {
+            let mut file = tempfile::NamedTempFile::new_in(dir)?;
+            file.write_all(&backfill::compress(&profile.encode_to_vec())?)?;
+            file.persist(&path)?;
+            Ok(())
+        };
+        write().with_context(|| format!("writing scrape state {}", path.display()))
+    }
+}
+
+/// Subtracts the previous values of the cumulative sample types from the
+/// merged samples of the profile, whose keys are `keys`. Values that
+/// decreased, because the target restarted, are kept. Samples without any
+/// change are dropped.
+fn subtract(profile: &mut Profile, keys: &[String], previous: &Previous, cumulative: &[usize]) {
+    for (sample, key) in profile.sample.iter_mut().zip(keys) {
+        let Some(before) = previous.values.get(key) else {
+            continue;
+        };
+        for &i in cumulative {
+            let (Some(value), Some(before)) = (sample.value.get_mut(i), before.get(i)) else {
+                continue;
+            };
+            if *value >= *before {
+                *value -= before;
+            }
+        }
+    }
+    profile.sample.retain(|s| s.value.iter().any(|v| *v != 0));
+    profile.duration_nanos = profile.time_nanos - previous.time_nanos;
+}
+
+fn string(profile: &Profile, index: i64) -> &str {
+    profile
+        .string_table
+        .get(index as usize)
+        .map_or("", String::as_str)
+}
+
+/// Merges the samples of the profile with the same key into the first of
+/// them, summing their values, as profiles may contain several samples of
+/// the same stack and labels. Returns the keys of the merged samples.
+fn merge_samples(profile: &mut Profile) -> Vec<String> {
+    let keys = sample_keys(profile);
+    let mut merged: Vec<Sample> = Vec::with_capacity(profile.sample.len());
+    let mut merged_keys = Vec::with_capacity(keys.len());
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for (sample, key) in std::mem::take(&mut profile.sample).into_iter().zip(keys) {
+        match index.get(&key) {
+            Some(&i) => {
+                let values = &mut merged[i].value;
+                for (value, other) in values.iter_mut().zip(sample.value) {
+                    *value += other;
+                }
+            }
+            None => {
+                index.insert(key.clone(), merged.len());
+                merged_keys.push(key);
+                merged.push(sample);
+            }
+        }
+    }
+    profile.sample = merged;
+    merged_keys
+}
+
+/// Returns keys identifying the samples across profiles of the same target
+/// by their stack and labels, as location and function IDs are local to a
+/// profile.
+fn sample_keys(profile: &Profile) -> Vec<String> {
+    let locations: HashMap<u64, &Location> = profile.location.iter().map(|l| (l.id, l)).collect();
+    let functions: HashMap<u64, &str> = profile
+        .function
+        .iter()
+        .map(|f| (f.id, string(profile, f.name)))
+        .collect();
+
+    let key = |sample: &Sample| {
+        let mut key = String::new();
+        for location in sample.location_id.iter().filter_map(|id| locations.get(id)) {
+            let _ = write!(key, "{:x}", location.address);
+            for line in location.line.iter() {
+                let _ = write!(key, ";{}", functions.get(&line.function_id).unwrap_or(&""));
+            }
+            key.push('|');
+        }
+        for label in sample.label.iter() {
+            let _ = write!(
+                key,
+                "{}={}{},",
+                string(profile, label.key),
+                string(profile, label.str),
+                label.num
+            );
+        }
+        key
+    };
+    profile.sample.iter().map(key).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pprofpb::{Function, Line, ValueType};
+
+    fn profile(time_nanos: i64, samples: &[(u64, [i64; 2])]) -> Vec<u8> {
+        let strings = ["", "alloc_objects", "count", "inuse_objects", "main", "run"];
+        Profile {
+            sample_type: vec![
+                ValueType { r#type: 1, unit: 2 },
+                ValueType { r#type: 3, unit: 2 },
+            ],
+            sample: samples
+                .iter()
+                .map(|(location, value)| Sample {
+                    location_id: vec![*location],
+                    value: value.to_vec(),
+                    label: vec![],
+                })
+                .collect(),
+            location: (1..=2)
+                .map(|id| Location {
+                    id,
+                    address: 0x1000 * id,
+                    line: vec![Line {
+                        function_id: id,
+                        line: 1,
+                    }],
+                    ..Default::default()
+                })
+                .collect(),
+            function: (1..=2)
+                .map(|id| Function {
+                    id,
+                    name: 3 + id as i64,
+                    ..Default::default()
+                })
+                .collect(),
+            string_table: strings.map(String::from).to_vec(),
+            time_nanos,
+            ..Default::default()
+        }
+        .encode_to_vec()
+    }
+
+    fn decode(data: &[u8]) -> Profile {
+        Profile::decode(backfill::decompress(data).unwrap().as_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_delta_profiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let deltas = DeltaProfiles::new(Some(dir.path().to_path_buf())).unwrap();
+        assert!(deltas
+            .apply("a", &profile(1, &[(1, [10, 3]), (2, [5, 1])]))
+            .unwrap()
+            .is_none());
+
+        let delta = deltas
+            .apply("a", &profile(11, &[(1, [15, 4]), (2, [5, 0])]))
+            .unwrap()
+            .unwrap();
+        let delta = decode(&delta);
+        assert_eq!(delta.duration_nanos, 10);
+        assert_eq!(delta.sample.len(), 1);
+        assert_eq!(delta.sample[0].value, [5, 4]);
+
+        // A restarted target starts counting from zero again.
+        let delta = deltas
+            .apply("a", &profile(21, &[(1, [2, 1])]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(decode(&delta).sample[0].value, [2, 1]);
+
+        // The previous scrape survives restarts of the scraper.
+        let restarted = DeltaProfiles::new(Some(dir.path().to_path_buf())).unwrap();
+        let delta = restarted
+            .apply("a", &profile(31, &[(1, [3, 1])]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(decode(&delta).sample[0].value, [1, 1]);
+        assert!(restarted.apply("b", &profile(31, &[])).unwrap().is_none());
+
+        // Invalid state is treated as missing, and replaced.
+        std::fs::write(restarted.path("c").unwrap(), b"invalid").unwrap();
+        assert!(restarted
+            .apply("c", &profile(1, &[(1, [3, 1])]))
+            .unwrap()
+            .is_none());
+        let restarted = DeltaProfiles::new(Some(dir.path().to_path_buf())).unwrap();
+        let delta = restarted
+            .apply("c", &profile(11, &[(1, [4, 1])]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(decode(&delta).sample[0].value, [1, 1]);
+
+        // Samples of the same stack are summed before subtracting, and the
+        // in-use values are summed as well.
+        let deltas = DeltaProfiles::default();
+        assert!(deltas
+            .apply("d", &profile(1, &[(1, [10, 3]), (1, [5, 1])]))
+            .unwrap()
+            .is_none());
+        let delta = deltas
+            .apply("d", &profile(11, &[(1, [20, 5]), (1, [5, 1])]))
+            .unwrap()
+            .unwrap();
+        let delta = decode(&delta);
+        assert_eq!(delta.sample.len(), 1);
+        assert_eq!(delta.sample[0].value, [10, 6]);
+
+        let cpu = Profile {
+            sample_type: vec![ValueType { r#type: 1, unit: 1 }],
+            string_table: vec!["".into(), "samples".into()],
+            ..Default::default()
+        }
+        .encode_to_vec();
+        assert_eq!(
+            DeltaProfiles::default().apply("c", &cpu).unwrap(),
+            Some(cpu)
+        );
+    }
+}
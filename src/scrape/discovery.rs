@@ -0,0 +1,128 @@
+use crate::backfill;
+use crate::debuginfo_store::DebuginfoStore;
+use crate::debuginfopb::debuginfo_service_server::DebuginfoService;
+use crate::debuginfopb::{BuildIdType, ShouldInitiateUploadRequest};
+use crate::pprofpb::Profile;
+use moka::sync::Cache;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use prost::Message;
+use std::collections::BTreeSet;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tonic::Request;
+
+static DISCOVERED_BUILD_IDS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_scrape_discovered_build_ids_total",
+        "Total number of build IDs found in scraped profiles that were checked for debuginfo, by whether it is available.",
+        &["result"]
+    )
+    .unwrap()
+});
+
+/// How long the availability of the debuginfo of a build ID is remembered
+/// before it is checked again.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// DebuginfoDiscovery checks whether debuginfo is available for the
+/// binaries mapped in scraped profiles, the same way agents do before
+/// uploading it. Checking looks build IDs up in debuginfod, so targets
+/// without an agent get symbolized when their binaries are found there.
+pub struct DebuginfoDiscovery {
+    debuginfo: Arc<DebuginfoStore>,
+    /// Whether the debuginfo of recently checked build IDs is missing.
+    missing: Cache<String, bool>,
+}
+
+impl std::fmt::Debug for DebuginfoDiscovery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DebuginfoDiscovery")
+            .field("checked", &self.missing.entry_count())
+            .finish_non_exhaustive()
+    }
+}
+
+impl DebuginfoDiscovery {
+    pub fn new(debuginfo: Arc<DebuginfoStore>) -> Self {
+        Self {
+            debuginfo,
+            missing: Cache::builder()
+                .max_capacity(100_000)
+                .time_to_live(RECHECK_INTERVAL)
+                .build(),
+        }
+    }
+
+    /// Checks the build IDs of the binaries mapped in the profile and returns
+    /// those whose debuginfo is missing.
+    pub async fn discover(&self, data: &[u8]) -> anyhow::Result<Vec<String>> {
+        let mut missing = vec![];
+        for build_id in build_ids(data)? {
+            let is_missing = match self.missing.get(&build_id) {
+                Some(is_missing) => is_missing,
+                None => {
+                    let request = Request::new(ShouldInitiateUploadRequest {
+                        build_id: build_id.clone(),
+                        build_id_type: BuildIdType::Gnu.into(),
+                        ..Default::default()
+                    });
+                    let is_missing = match self.debuginfo.should_initiate_upload(request).await {
+                        Ok(response) => response.into_inner().should_initiate_upload,
+                        Err(e) => {
+                            log::debug!("Checking debuginfo of build ID {}: {}", build_id, e);
+                            DISCOVERED_BUILD_IDS.with_label_values(&["invalid"]).inc();
+                            continue;
+                        }
+                    };
+                    DISCOVERED_BUILD_IDS
+                        .with_label_values(&[if is_missing { "missing" } else { "available" }])
+                        .inc();
+                    self.missing.insert(build_id.clone(), is_missing);
+                    is_missing
+                }
+            };
+            if is_missing {
+                missing.push(build_id);
+            }
+        }
+        Ok(missing)
+    }
+}
+
+/// Returns the distinct build IDs of the mappings of the profile.
+fn build_ids(data: &[u8]) -> anyhow::Result<BTreeSet<String>> {
+    let profile = Profile::decode(backfill::decompress(data)?.as_slice())?;
+    Ok(profile
+        .mapping
+        .iter()
+        .filter_map(|m| profile.string_table.get(m.build_id as usize))
+        .filter(|id| !id.is_empty())
+        .cloned()
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pprofpb::Mapping;
+
+    #[test]
+    fn test_build_ids() {
+        let mapping = |id, build_id| Mapping {
+            id,
+            build_id,
+            ..Default::default()
+        };
+        let profile = Profile {
+            mapping: vec![mapping(1, 1), mapping(2, 0), mapping(3, 1), mapping(4, 2)],
+            string_table: vec!["".into(), "abcd".into(), "ef01".into()],
+            ..Default::default()
+        };
+        let data = backfill::compress(&profile.encode_to_vec()).unwrap();
+        assert_eq!(
+            build_ids(&data).unwrap().into_iter().collect::<Vec<_>>(),
+            ["abcd", "ef01"]
+        );
+        assert!(build_ids(b"not a profile").is_err());
+    }
+}
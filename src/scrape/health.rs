@@ -0,0 +1,281 @@
+use crate::profilestorepb::{Label, LabelSet};
+use crate::scrapepb::scrape_service_server::ScrapeService;
+use crate::scrapepb::target::Health;
+use crate::scrapepb::targets_request::State;
+use crate::scrapepb::{Target, Targets, TargetsRequest, TargetsResponse};
+use chrono::{DateTime, Utc};
+use prometheus::{register_gauge_vec, register_int_counter_vec, GaugeVec, IntCounterVec};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tonic::{Request, Response, Status};
+
+static SCRAPES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_scrapes_total",
+        "Total number of scrapes of targets, by job, instance, profile and result.",
+        &["job", "instance", "profile", "result"]
+    )
+    .unwrap()
+});
+
+static UP: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "evprofiler_scrape_up",
+        "Whether the last scrape of the target succeeded.",
+        &["job", "instance", "profile"]
+    )
+    .unwrap()
+});
+
+static SCRAPE_DURATION: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "evprofiler_scrape_duration_seconds",
+        "Duration of the last scrape of the target.",
+        &["job", "instance", "profile"]
+    )
+    .unwrap()
+});
+
+#[derive(Debug, Clone, Default)]
+struct TargetStatus {
+    labels: BTreeMap<String, String>,
+    last_scrape: DateTime<Utc>,
+    last_duration: Duration,
+    last_error: Option<String>,
+    /// Command line of the target's process, if it was fetched.
+    cmdline: String,
+    /// Build IDs mapped by the target without debuginfo.
+    missing_debuginfo: Vec<String>,
+}
+
+/// TargetHealth keeps the outcome of the last scrape of every target, so
+/// that misconfigured pprof endpoints are visible.
+#[derive(Debug, Default)]
+pub struct TargetHealth {
+    /// Status by job and URL of the scraped pprof endpoint.
+    targets: Mutex<BTreeMap<(String, String), TargetStatus>>,
+}
+
+/// Health of a target as served by the HTTP API.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct TargetSummary {
+    pub job: String,
+    pub url: String,
+    pub labels: BTreeMap<String, String>,
+    pub health: &'static str,
+    pub last_error: String,
+    pub last_scrape: String,
+    pub last_scrape_duration_seconds: f64,
+    pub cmdline: String,
+    pub missing_debuginfo: Vec<String>,
+}
+
+impl TargetHealth {
+    /// Records the outcome of a scrape of the pprof endpoint at `url` that
+    /// started at `started_at`.
+    pub fn record(
+        &self,
+        url: &str,
+        labels: &BTreeMap<String, String>,
+        started_at: DateTime<Utc>,
+        duration: Duration,
+        error: Option<String>,
+    ) {
+        let label = |name: &str| labels.get(name).map_or("", String::as_str);
+        let job = label("job").to_string();
+        let values = [label("job"), label("instance"), label("__name__")];
+        let (result, up) = match error {
+            None => ("success", 1.0),
+            Some(_) => ("failure", 0.0),
+        };
+        SCRAPES
+            .with_label_values(&[values[0], values[1], values[2], result])
+            .inc();
+        UP.with_label_values(&values).set(up);
+        SCRAPE_DURATION
+            .with_label_values(&values)
+            .set(duration.as_secs_f64());
+
+        let mut targets = self.targets.lock().unwrap();
+        let status = targets.entry((job, url.to_string())).or_default();
+        status.labels = labels.clone();
+        status.last_scrape = started_at;
+        status.last_duration = duration;
+        status.last_error = error;
+    }
+
+    /// Records the build IDs without debuginfo found in the profiles of the
+    /// target, and the command line of its process if it was fetched.
+    pub fn record_discovery(
+        &self,
+        job: &str,
+        url: &str,
+        cmdline: Option<String>,
+        missing_debuginfo: Vec<String>,
+    ) {
+        let mut targets = self.targets.lock().unwrap();
+        let status = targets
+            .entry((job.to_string(), url.to_string()))
+            .or_default();
+        if let Some(cmdline) = cmdline {
+            status.cmdline = cmdline;
+        }
+        status.missing_debuginfo = missing_debuginfo;
+    }
+
+    /// Returns the command line of the target's process, if it is known.
+    pub fn cmdline(&self, job: &str, url: &str) -> Option<String> {
+        self.targets
+            .lock()
+            .unwrap()
+            .get(&(job.to_string(), url.to_string()))
+            .map(|status| status.cmdline.clone())
+            .filter(|cmdline| !cmdline.is_empty())
+    }
+
+    /// Returns the health of all targets, ordered by job and URL.
+    pub fn summaries(&self) -> Vec<TargetSummary> {
+        self.targets
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((job, url), status)| TargetSummary {
+                job: job.clone(),
+                url: url.clone(),
+                labels: status.labels.clone(),
+                health: if status.last_error.is_none() {
+                    "good"
+                } else {
+                    "bad"
+                },
+                last_error: status.last_error.clone().unwrap_or_default(),
+                last_scrape: status.last_scrape.to_rfc3339(),
+                last_scrape_duration_seconds: status.last_duration.as_secs_f64(),
+                cmdline: status.cmdline.clone(),
+                missing_debuginfo: status.missing_debuginfo.clone(),
+            })
+            .collect()
+    }
+
+    /// Returns the targets grouped by job.
+    fn grouped(&self) -> HashMap<String, Targets> {
+        let mut targets: HashMap<String, Targets> = HashMap::new();
+        for ((job, url), status) in self.targets.lock().unwrap().iter() {
+            targets
+                .entry(job.clone())
+                .or_default()
+                .targets
+                .push(Target {
+                    discovered_labels: None,
+                    labels: Some(LabelSet {
+                        labels: status
+                            .labels
+                            .iter()
+                            .map(|(name, value)| Label {
+                                name: name.clone(),
+                                value: value.clone(),
+                            })
+                            .collect(),
+                    }),
+                    last_error: status.last_error.clone().unwrap_or_default(),
+                    last_scrape: Some(prost_types::Timestamp {
+                        seconds: status.last_scrape.timestamp(),
+                        nanos: status.last_scrape.timestamp_subsec_nanos() as i32,
+                    }),
+                    last_scrape_duration: Some(prost_types::Duration {
+                        seconds: status.last_duration.as_secs() as i64,
+                        nanos: status.last_duration.subsec_nanos() as i32,
+                    }),
+                    url: url.clone(),
+                    health: if status.last_error.is_none() {
+                        Health::Good
+                    } else {
+                        Health::Bad
+                    } as i32,
+                });
+        }
+        targets
+    }
+}
+
+#[tonic::async_trait]
+impl ScrapeService for TargetHealth {
+    async fn targets(
+        &self,
+        request: Request<TargetsRequest>,
+    ) -> Result<Response<TargetsResponse>, Status> {
+        // Targets are only known once scraped, so none of them are dropped.
+        let targets = match request.into_inner().state() {
+            State::Dropped => HashMap::new(),
+            State::AnyUnspecified | State::Active => self.grouped(),
+        };
+        Ok(Response::new(TargetsResponse { targets }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_target_health() {
+        let health = TargetHealth::default();
+        let labels = BTreeMap::from([
+            ("__name__".to_string(), "memory".to_string()),
+            ("instance".to_string(), "localhost:6060".to_string()),
+            ("job".to_string(), "api".to_string()),
+        ]);
+        let url = "http://localhost:6060/debug/pprof/heap";
+        let now = Utc::now();
+        health.record(url, &labels, now, Duration::from_millis(500), None);
+        health.record(
+            url,
+            &labels,
+            now,
+            Duration::from_secs(1),
+            Some("connection refused".into()),
+        );
+
+        health.record_discovery(
+            "api",
+            url,
+            Some("./api --port 6060".into()),
+            vec!["abcd".into()],
+        );
+        assert_eq!(health.cmdline("api", url).unwrap(), "./api --port 6060");
+        assert_eq!(health.cmdline("api", "other"), None);
+
+        let summaries = health.summaries();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].job, "api");
+        assert_eq!(summaries[0].health, "bad");
+        assert_eq!(summaries[0].last_error, "connection refused");
+        assert_eq!(summaries[0].last_scrape_duration_seconds, 1.0);
+        assert_eq!(summaries[0].missing_debuginfo, ["abcd"]);
+        assert_eq!(
+            UP.with_label_values(&["api", "localhost:6060", "memory"])
+                .get(),
+            0.0
+        );
+
+        let response = health
+            .targets(Request::new(TargetsRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        let target = &response.targets["api"].targets[0];
+        assert_eq!(target.url, url);
+        assert_eq!(target.health(), Health::Bad);
+
+        let dropped = health
+            .targets(Request::new(TargetsRequest {
+                state: State::Dropped as i32,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(dropped.targets.is_empty());
+    }
+}
@@ -0,0 +1,742 @@
+mod config;
+mod delta;
+mod discovery;
+mod health;
+mod schedule;
+
+use crate::backfill;
+use crate::debuginfo_store::ImageExtractor;
+use crate::normalizer::DELTA_LABEL;
+use crate::principal::Principal;
+use crate::profile_store::ProfileStore;
+use crate::profilestorepb::profile_store_service_server::ProfileStoreService;
+use crate::profilestorepb::{Label, LabelSet, RawProfileSeries, RawSample, WriteRawRequest};
+use anyhow::{bail, Context};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::io::Read;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Semaphore;
+use url::Url;
+
+pub use config::{duration, ScrapeConfig};
+pub use delta::DeltaProfiles;
+pub use discovery::DebuginfoDiscovery;
+pub use health::{TargetHealth, TargetSummary};
+
+/// Maximum size of a scraped profile.
+const MAX_PROFILE_SIZE: u64 = 256 << 20;
+
+/// Value of the job label of profiles scraped on demand.
+const ADHOC_JOB: &str = "adhoc";
+
+/// Maximum duration of profiles scraped on demand, which hold a scrape slot
+/// and a connection to the target for as long.
+const MAX_ADHOC_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// Returns the name profiles pulled from a pprof endpoint are stored under,
+/// following Parca's scrape configuration.
+pub fn profile_name(profile: &str) -> &str {
+    match profile {
+        "profile" => "process_cpu",
+        "heap" | "allocs" => "memory",
+        other => other,
+    }
+}
+
+/// Parses a scrape target, given as URL or as `host:port` of a plain HTTP
+/// endpoint.
+pub fn parse_target(target: &str) -> anyhow::Result<Url> {
+    let url = if target.contains("://") {
+        Url::parse(target)
+    } else {
+        Url::parse(&format!("http://{}", target))
+    }
+    .with_context(|| format!("invalid target {:?}", target))?;
+    if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+        bail!("invalid target {:?}: expected an http(s) URL", target);
+    }
+    Ok(url)
+}
+
+/// Checks that the profile names a pprof endpoint below `/debug/pprof/`.
+pub fn check_profile(profile: &str) -> anyhow::Result<()> {
+    if profile.is_empty()
+        || !profile
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        bail!("invalid profile {:?}", profile);
+    }
+    Ok(())
+}
+
+/// Returns the URL of the pprof endpoint of the target, asking for a profile
+/// covering `duration` unless it is zero.
+fn profile_url(target: &Url, profile: &str, duration: Duration) -> anyhow::Result<Url> {
+    check_profile(profile)?;
+    let mut url = target.clone();
+    url.set_path(&format!(
+        "{}/debug/pprof/{}",
+        target.path().trim_end_matches('/'),
+        profile
+    ));
+    if !duration.is_zero() {
+        url.query_pairs_mut()
+            .append_pair("seconds", &duration.as_secs().max(1).to_string());
+    }
+    Ok(url)
+}
+
+/// Formats labels the way series are selected in queries.
+fn series_id(labels: &BTreeMap<String, String>) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}={:?}", name, value))
+        .collect();
+    format!("{{{}}}", labels.join(", "))
+}
+
+/// PprofClient pulls profiles from the pprof HTTP endpoints of targets.
+#[derive(Debug, Clone)]
+pub struct PprofClient {
+    client: ureq::Agent,
+    /// Time a scrape may take on top of the requested profile duration.
+    timeout: Duration,
+    /// Headers sent with every request.
+    headers: Vec<(String, String)>,
+}
+
+impl PprofClient {
+    pub fn new(timeout: Duration) -> Self {
+        Self::build(timeout, None)
+    }
+
+    /// Creates a client connecting to HTTPS targets with `tls`, or with the
+    /// web PKI roots if unset.
+    pub fn build(timeout: Duration, tls: Option<Arc<rustls::ClientConfig>>) -> Self {
+        let mut builder = ureq::AgentBuilder::new()
+            .timeout_connect(timeout)
+            .redirects(2);
+        if let Some(tls) = tls {
+            builder = builder.tls_config(tls);
+        }
+        Self {
+            client: builder.build(),
+            timeout,
+            headers: vec![],
+        }
+    }
+
+    pub fn with_headers(mut self, headers: Vec<(String, String)>) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Pulls a single profile from the target. CPU profiles and delta
+    /// profiles of other types cover `duration`, so the request takes at
+    /// least that long.
+    pub async fn fetch(
+        &self,
+        target: &Url,
+        profile: &str,
+        duration: Duration,
+    ) -> anyhow::Result<Vec<u8>> {
+        let url = profile_url(target, profile, duration)?;
+        let mut request = self
+            .client
+            .get(url.as_str())
+            .timeout(duration + self.timeout);
+        for (name, value) in self.headers.iter() {
+            request = request.set(name, value);
+        }
+        tokio::task::spawn_blocking(move || {
+            let response = request
+                .call()
+                .with_context(|| format!("scraping {}", url))?;
+            let mut data = vec![];
+            response
+                .into_reader()
+                .take(MAX_PROFILE_SIZE + 1)
+                .read_to_end(&mut data)
+                .with_context(|| format!("reading profile from {}", url))?;
+            if data.len() as u64 > MAX_PROFILE_SIZE {
+                bail!("profile exceeds {} bytes", MAX_PROFILE_SIZE);
+            }
+            Ok(data)
+        })
+        .await?
+    }
+}
+
+/// Scraper pulls profiles from the pprof endpoints of targets and stores
+/// them through the write path of pushed profiles.
+#[derive(Debug)]
+pub struct Scraper {
+    store: Arc<ProfileStore>,
+    client: PprofClient,
+    health: Arc<TargetHealth>,
+    /// Bounds the number of scrapes running at the same time, if set.
+    slots: Option<Arc<Semaphore>>,
+    deltas: DeltaProfiles,
+    discovery: Option<DebuginfoDiscovery>,
+    images: Option<Arc<ImageExtractor>>,
+    /// Instances ad-hoc scrapes may pull profiles from.
+    adhoc_targets: HashSet<String>,
+}
+
+impl Scraper {
+    pub fn new(store: Arc<ProfileStore>, client: PprofClient, health: Arc<TargetHealth>) -> Self {
+        Self {
+            store,
+            client,
+            health,
+            slots: None,
+            deltas: DeltaProfiles::default(),
+            discovery: None,
+            images: None,
+            adhoc_targets: HashSet::new(),
+        }
+    }
+
+    /// Lets ad-hoc scrapes pull profiles from the targets of the configs and
+    /// the `allowed` targets only, so that the scrape API can't be used to
+    /// make requests to arbitrary hosts.
+    pub fn with_adhoc_targets(
+        mut self,
+        configs: &[ScrapeConfig],
+        allowed: &[String],
+    ) -> anyhow::Result<Self> {
+        self.adhoc_targets = adhoc_targets(configs, allowed)?;
+        Ok(self)
+    }
+
+    /// Returns whether ad-hoc scrapes may pull profiles from the target.
+    fn allows_adhoc(&self, target: &Url) -> bool {
+        self.adhoc_targets.contains(&instance(target))
+    }
+
+    /// Extracts the debuginfo of the binaries in the container images of the
+    /// targets with `images`.
+    pub fn with_image_extractor(mut self, images: Arc<ImageExtractor>) -> Self {
+        self.images = Some(images);
+        self
+    }
+
+    /// Checks whether debuginfo is available for the binaries in profiles of
+    /// targets with debuginfo discovery enabled.
+    pub fn with_debuginfo_discovery(mut self, discovery: DebuginfoDiscovery) -> Self {
+        self.discovery = Some(discovery);
+        self
+    }
+
+    /// Keeps the previous scrapes of cumulative profiles in `deltas`.
+    pub fn with_delta_profiles(mut self, deltas: DeltaProfiles) -> Self {
+        self.deltas = deltas;
+        self
+    }
+
+    /// Runs at most `max` scrapes at the same time. Zero disables the limit.
+    pub fn with_max_concurrent_scrapes(mut self, max: usize) -> Self {
+        self.slots = (max > 0).then(|| Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// Scrapes the targets of the configs at their interval, until the
+    /// process exits.
+    pub fn start(self: &Arc<Self>, configs: &[ScrapeConfig]) -> anyhow::Result<()> {
+        for config in configs {
+            let client = config.client()?;
+            if let Some(extractor) = &self.images {
+                for image in config.images()? {
+                    let extractor = Arc::clone(extractor);
+                    tokio::spawn(async move {
+                        match extractor.extract(image.clone()).await {
+                            Ok(stored) => log::info!(
+                                "Stored debuginfo of {} binaries of image {}",
+                                stored,
+                                image
+                            ),
+                            Err(e) => log::warn!("Failed to extract image {}: {:#}", image, e),
+                        }
+                    });
+                }
+            }
+            for (target, labels) in config.targets()? {
+                for profile in config.profiles.iter() {
+                    let mut labels = labels.clone();
+                    labels.insert("__name__".into(), profile_name(profile).to_string());
+                    labels.insert("instance".into(), instance(&target));
+                    labels.insert("job".into(), config.job_name.clone());
+                    let duration = match profile.as_str() {
+                        "profile" => config.profile_duration,
+                        _ => Duration::ZERO,
+                    };
+                    let scrape = TargetScrape {
+                        client: client.clone(),
+                        target: target.clone(),
+                        profile: profile.clone(),
+                        duration,
+                        labels,
+                        delta: config.delta_profiles,
+                        discover: config.discover_debuginfo,
+                    };
+                    tokio::spawn(Arc::clone(self).scrape_loop(scrape, config.scrape_interval));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Scrapes the target at its interval. Targets are spread over the
+    /// interval, so that scrapes of the fleet don't happen all at once.
+    async fn scrape_loop(self: Arc<Self>, scrape: TargetScrape, interval: Duration) {
+        let delay = schedule::first_delay(&scrape.key(), interval, SystemTime::now());
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + delay, interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.scrape(&scrape).await {
+                log::warn!("Scraping {} of {}: {}", scrape.profile, scrape.target, e);
+            }
+        }
+    }
+
+    /// Scrapes the profile once and stores it. Returns the identifier of the
+    /// series it was stored in.
+    pub async fn scrape_once(
+        &self,
+        target: &Url,
+        profile: &str,
+        duration: Duration,
+    ) -> Result<String, ScrapeError> {
+        let scrape = TargetScrape {
+            client: self.client.clone(),
+            target: target.clone(),
+            profile: profile.to_string(),
+            duration,
+            delta: false,
+            discover: false,
+            labels: BTreeMap::from([
+                ("__name__".to_string(), profile_name(profile).to_string()),
+                ("instance".to_string(), instance(target)),
+                ("job".to_string(), ADHOC_JOB.to_string()),
+            ]),
+        };
+        self.scrape(&scrape).await?;
+        Ok(series_id(&scrape.labels))
+    }
+
+    /// Scrapes and stores a profile, recording the outcome in the target's
+    /// health.
+    async fn scrape(&self, scrape: &TargetScrape) -> Result<(), ScrapeError> {
+        let _permit = match &self.slots {
+            Some(slots) => Some(
+                Arc::clone(slots)
+                    .acquire_owned()
+                    .await
+                    .map_err(|e| ScrapeError::Fetch(e.into()))?,
+            ),
+            None => None,
+        };
+        let started_at = Utc::now();
+        let started = Instant::now();
+        let res = self.fetch_and_store(scrape).await;
+        self.health.record(
+            &scrape.url(),
+            &scrape.labels,
+            started_at,
+            started.elapsed(),
+            res.as_ref().err().map(ToString::to_string),
+        );
+        res
+    }
+
+    async fn fetch_and_store(&self, scrape: &TargetScrape) -> Result<(), ScrapeError> {
+        let data = scrape
+            .client
+            .fetch(&scrape.target, &scrape.profile, scrape.duration)
+            .await
+            .map_err(ScrapeError::Fetch)?;
+        if scrape.discover {
+            self.discover(scrape, &data).await;
+        }
+        let data = match scrape.delta {
+            true => match self.deltas.apply(&scrape.key(), &data) {
+                Ok(Some(delta)) => delta,
+                // The first scrape of a cumulative profile is only kept
+                // to subtract it from the next one.
+                Ok(None) => return Ok(()),
+                Err(e) => return Err(ScrapeError::Store(e)),
+            },
+            false => data,
+        };
+        // Profiles requested over a duration, like CPU profiles, are deltas,
+        // others are cumulative unless turned into deltas.
+        let delta = scrape.delta || !scrape.duration.is_zero();
+        let request = write_request(&scrape.labels, delta, data).map_err(ScrapeError::Store)?;
+        // Scrapes are written like pushes of an agent named after the job, so
+        // that they are accounted, sampled and forwarded the same way.
+        let mut request = tonic::Request::new(request);
+        request
+            .extensions_mut()
+            .insert(Principal::new(format!("scrape/{}", scrape.labels["job"])));
+        self.store
+            .write_raw(request)
+            .await
+            .map(|_| ())
+            .map_err(|e| ScrapeError::Store(e.into()))
+    }
+}
+
+impl Scraper {
+    /// Records the build IDs of the profile whose debuginfo is missing in the
+    /// health of the target. The command line of the process is fetched the
+    /// first time, to tell which binaries they belong to.
+    async fn discover(&self, scrape: &TargetScrape, data: &[u8]) {
+        let Some(discovery) = &self.discovery else {
+            return;
+        };
+        let missing = match discovery.discover(data).await {
+            Ok(missing) => missing,
+            Err(e) => {
+                log::warn!("Discovering build IDs of {}: {}", scrape.url(), e);
+                return;
+            }
+        };
+
+        let job = &scrape.labels["job"];
+        let url = scrape.url();
+        let mut cmdline = None;
+        if !missing.is_empty() && self.health.cmdline(job, &url).is_none() {
+            cmdline = match scrape
+                .client
+                .fetch(&scrape.target, "cmdline", Duration::ZERO)
+                .await
+            {
+                Ok(data) => Some(
+                    String::from_utf8_lossy(&data)
+                        .split(['\0', '\n'])
+                        .filter(|arg| !arg.is_empty())
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                ),
+                Err(e) => {
+                    log::debug!("Fetching command line of {}: {}", scrape.target, e);
+                    None
+                }
+            };
+            log::warn!(
+                "No debuginfo for build IDs {} of {} ({}), upload it to symbolize its profiles",
+                missing.join(", "),
+                scrape.target,
+                cmdline.as_deref().unwrap_or("unknown command line"),
+            );
+        }
+        self.health.record_discovery(job, &url, cmdline, missing);
+    }
+}
+
+/// A profile pulled from a target.
+#[derive(Debug)]
+struct TargetScrape {
+    client: PprofClient,
+    target: Url,
+    profile: String,
+    duration: Duration,
+    labels: BTreeMap<String, String>,
+    /// Whether cumulative profiles are stored as the difference to the
+    /// previous scrape.
+    delta: bool,
+    /// Whether the debuginfo of the binaries in the profiles is checked.
+    discover: bool,
+}
+
+impl TargetScrape {
+    /// Returns the key identifying the target across restarts.
+    fn key(&self) -> String {
+        format!("{}{}", self.labels["job"], self.url())
+    }
+
+    /// Returns the URL of the pprof endpoint, which identifies the target.
+    fn url(&self) -> String {
+        profile_url(&self.target, &self.profile, Duration::ZERO)
+            .map_or(self.target.to_string(), String::from)
+    }
+}
+
+/// Returns the `host:port` of the target.
+fn instance(target: &Url) -> String {
+    match target.port_or_known_default() {
+        Some(port) => format!("{}:{}", target.host_str().unwrap_or_default(), port),
+        None => target.host_str().unwrap_or_default().to_string(),
+    }
+}
+
+/// Returns the instances of the targets of the configs and of the `allowed`
+/// targets.
+fn adhoc_targets(configs: &[ScrapeConfig], allowed: &[String]) -> anyhow::Result<HashSet<String>> {
+    let mut targets = HashSet::new();
+    for config in configs {
+        for (target, _) in config.targets()? {
+            targets.insert(instance(&target));
+        }
+    }
+    for target in allowed {
+        let target = parse_target(target)
+            .with_context(|| format!("invalid ad-hoc scrape target {}", target))?;
+        targets.insert(instance(&target));
+    }
+    Ok(targets)
+}
+
+fn write_request(
+    labels: &BTreeMap<String, String>,
+    delta: bool,
+    data: Vec<u8>,
+) -> anyhow::Result<WriteRawRequest> {
+    // The normalizer only accepts gzipped profiles.
+    let raw_profile = if backfill::is_gzip(&data) {
+        data
+    } else {
+        backfill::compress(&data)?
+    };
+    Ok(WriteRawRequest {
+        series: vec![RawProfileSeries {
+            labels: Some(LabelSet {
+                labels: labels
+                    .iter()
+                    .map(|(name, value)| Label {
+                        name: name.clone(),
+                        value: value.clone(),
+                    })
+                    .chain([Label {
+                        name: DELTA_LABEL.into(),
+                        value: delta.to_string(),
+                    }])
+                    .collect(),
+            }),
+            samples: vec![RawSample {
+                raw_profile,
+                executable_info: vec![],
+            }],
+        }],
+        ..Default::default()
+    })
+}
+
+/// ScrapeError tells failing to pull a profile from failing to store it.
+#[derive(Debug)]
+pub enum ScrapeError {
+    Fetch(anyhow::Error),
+    Store(anyhow::Error),
+}
+
+impl std::fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fetch(e) => write!(f, "failed to scrape profile: {:#}", e),
+            Self::Store(e) => write!(f, "failed to store profile: {:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for ScrapeError {}
+
+#[derive(Debug, Deserialize)]
+struct ScrapeParams {
+    target: String,
+    profile: String,
+    duration: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ScrapeResponse {
+    series: String,
+}
+
+/// Routes of the target health API, and of the ad-hoc scrape API if
+/// `adhoc` is set.
+pub fn router(scraper: Arc<Scraper>, adhoc: bool) -> Router {
+    let mut router = Router::new().route("/api/v1/targets", get(targets));
+    if adhoc {
+        router = router.route("/api/v1/scrape", post(scrape));
+    }
+    router.with_state(scraper)
+}
+
+/// Lists the health of the scraped targets.
+async fn targets(State(scraper): State<Arc<Scraper>>) -> Json<Vec<TargetSummary>> {
+    Json(scraper.health.summaries())
+}
+
+/// Parses the duration of an ad-hoc scrape, at most `MAX_ADHOC_DURATION`.
+fn adhoc_duration(duration: Option<&str>) -> Result<Duration, String> {
+    let Some(duration) = duration else {
+        return Ok(Duration::ZERO);
+    };
+    let duration =
+        humantime::parse_duration(duration).map_err(|e| format!("invalid duration: {}", e))?;
+    if duration > MAX_ADHOC_DURATION {
+        return Err(format!(
+            "duration {} is longer than the maximum of {}",
+            humantime::format_duration(duration),
+            humantime::format_duration(MAX_ADHOC_DURATION)
+        ));
+    }
+    Ok(duration)
+}
+
+/// Scrapes a profile of a target on demand and stores it, for example
+/// `POST /api/v1/scrape?target=host:6060&profile=heap&duration=30s`.
+async fn scrape(
+    State(scraper): State<Arc<Scraper>>,
+    Query(params): Query<ScrapeParams>,
+) -> Response {
+    let duration = match adhoc_duration(params.duration.as_deref()) {
+        Ok(duration) => duration,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let target = match parse_target(&params.target) {
+        Ok(target) => target,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    if let Err(e) = profile_url(&target, &params.profile, duration) {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+    if !scraper.allows_adhoc(&target) {
+        return (
+            StatusCode::FORBIDDEN,
+            format!("{} is not an allowed scrape target", instance(&target)),
+        )
+            .into_response();
+    }
+
+    match scraper
+        .scrape_once(&target, &params.profile, duration)
+        .await
+    {
+        Ok(series) => Json(ScrapeResponse { series }).into_response(),
+        Err(e @ ScrapeError::Fetch(_)) => (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+        Err(e @ ScrapeError::Store(_)) => {
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_url() {
+        let target = parse_target("localhost:6060").unwrap();
+        assert_eq!(instance(&target), "localhost:6060");
+        assert_eq!(
+            profile_url(&target, "heap", Duration::ZERO)
+                .unwrap()
+                .as_str(),
+            "http://localhost:6060/debug/pprof/heap"
+        );
+
+        let target = parse_target("https://example.com/app/").unwrap();
+        assert_eq!(instance(&target), "example.com:443");
+        assert_eq!(
+            profile_url(&target, "profile", Duration::from_secs(30))
+                .unwrap()
+                .as_str(),
+            "https://example.com/app/debug/pprof/profile?seconds=30"
+        );
+
+        assert!(profile_url(&target, "../admin", Duration::ZERO).is_err());
+        assert!(profile_url(&target, "", Duration::ZERO).is_err());
+        assert!(parse_target("ftp://example.com").is_err());
+        assert!(parse_target("").is_err());
+    }
+
+    #[test]
+    fn test_adhoc_duration() {
+        assert_eq!(adhoc_duration(None), Ok(Duration::ZERO));
+        assert_eq!(adhoc_duration(Some("30s")), Ok(Duration::from_secs(30)));
+        assert_eq!(adhoc_duration(Some("5m")), Ok(MAX_ADHOC_DURATION));
+        assert!(adhoc_duration(Some("6m")).is_err());
+        assert!(adhoc_duration(Some("forever")).is_err());
+    }
+
+    #[test]
+    fn test_adhoc_targets() {
+        let config: ScrapeConfig = serde_yaml::from_str(
+            r#"
+job_name: api
+static_configs:
+  - targets: ["api-0:6060", "https://api-1"]
+"#,
+        )
+        .unwrap();
+        let targets = adhoc_targets(&[config], &["http://debug:8080/app".into()]).unwrap();
+        let allows = |target| targets.contains(&instance(&parse_target(target).unwrap()));
+        assert!(allows("api-0:6060"));
+        assert!(allows("https://api-1:443"));
+        assert!(allows("debug:8080"));
+        assert!(!allows("api-0:6061"));
+        assert!(!allows("169.254.169.254"));
+        assert!(adhoc_targets(&[], &["ftp://debug".into()]).is_err());
+    }
+
+    #[test]
+    fn test_write_request() {
+        let labels = BTreeMap::from([
+            ("__name__".to_string(), profile_name("heap").to_string()),
+            ("instance".to_string(), "localhost:6060".to_string()),
+        ]);
+        assert_eq!(
+            series_id(&labels),
+            r#"{__name__="memory", instance="localhost:6060"}"#
+        );
+
+        let request = write_request(&labels, false, b"profile".to_vec()).unwrap();
+        let series = &request.series[0];
+        let written = &series.labels.as_ref().unwrap().labels;
+        assert_eq!(written.len(), 3);
+        assert_eq!(written[2].name, DELTA_LABEL);
+        assert_eq!(written[2].value, "false");
+        assert!(backfill::is_gzip(&series.samples[0].raw_profile));
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route(
+            "/debug/pprof/heap",
+            axum::routing::get(
+                |Query(params): Query<BTreeMap<String, String>>| async move {
+                    format!(
+                        "seconds={}",
+                        params.get("seconds").cloned().unwrap_or_default()
+                    )
+                },
+            ),
+        );
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let client = PprofClient::new(Duration::from_secs(5));
+        let target = parse_target(&addr.to_string()).unwrap();
+        let data = client
+            .fetch(&target, "heap", Duration::from_secs(2))
+            .await
+            .unwrap();
+        assert_eq!(data, b"seconds=2");
+        assert!(client
+            .fetch(&target, "goroutine", Duration::ZERO)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Returns the offset of the scrapes of the target identified by `key`
+/// within every interval. Offsets are derived from a hash of the key, so
+/// targets are spread over the interval and keep their offset across
+/// restarts.
+pub fn offset(key: &str, interval: Duration) -> Duration {
+    let interval = interval.as_nanos() as u64;
+    if interval == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_nanos(fnv1a(key) % interval)
+}
+
+/// Hashes the key with FNV-1a, which is stable across processes.
+pub fn fnv1a(key: &str) -> u64 {
+    key.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Returns how long to wait from `now` until the first scrape of the target,
+/// so that it is scraped at its offset within intervals aligned to the Unix
+/// epoch.
+pub fn first_delay(key: &str, interval: Duration, now: SystemTime) -> Duration {
+    let nanos = interval.as_nanos() as u64;
+    if nanos == 0 {
+        return Duration::ZERO;
+    }
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let phase = (since_epoch.as_nanos() % nanos as u128) as u64;
+    let offset = offset(key, interval).as_nanos() as u64;
+    Duration::from_nanos((offset + nanos - phase) % nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_delay() {
+        let interval = Duration::from_secs(15);
+        let offset = offset("a", interval);
+        assert!(offset < interval);
+        assert_eq!(super::offset("a", interval), offset);
+        assert_ne!(super::offset("b", interval), offset);
+
+        // Scrapes happen at the offset within every interval, whenever the
+        // scraper starts.
+        for start in [0, 7, 14, 1_000_003] {
+            let now = UNIX_EPOCH + Duration::from_secs(start);
+            let first = now + first_delay("a", interval, now);
+            let phase = first.duration_since(UNIX_EPOCH).unwrap().as_nanos() % interval.as_nanos();
+            assert_eq!(phase, offset.as_nanos());
+            assert!(first_delay("a", interval, now) < interval);
+        }
+
+        assert_eq!(
+            first_delay("a", Duration::ZERO, SystemTime::now()),
+            Duration::ZERO
+        );
+    }
+}
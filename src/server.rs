@@ -0,0 +1,393 @@
+//! Wiring of the gRPC services of the server. main builds the services with
+//! ServicesBuilder from the flags, and so does TestServer from the default
+//! flags, so that tests run them with the same options, mode and layers.
+
+use crate::adminpb::admin_service_server::AdminServiceServer;
+use crate::agent_store::AgentStore;
+use crate::clickhouse::ClickHouseSink;
+use crate::clock::Clock;
+use crate::debuginfo_store::{
+    self, BucketRoutes, DebugInfod, DebuginfoStore, DebuginfodPolicy, MetadataStore, ObjectLayout,
+    UploadLimiter,
+};
+use crate::debuginfopb::debuginfo_service_server::DebuginfoServiceServer;
+use crate::flags::Flags;
+use crate::flightpb::flight_service_server::FlightServiceServer;
+use crate::http::catch_panic::CatchPanicLayer;
+use crate::ingester::Ingester;
+use crate::kafka::KafkaForwarder;
+use crate::memory::MemoryWatchdog;
+use crate::normalizer::{self, Metastore};
+use crate::principal::Authenticator;
+use crate::profile_store::ProfileStore;
+use crate::profilestorepb::agents_service_server::AgentsServiceServer;
+use crate::profilestorepb::profile_store_service_server::ProfileStoreServiceServer;
+use crate::querypb::query_service_server::QueryServiceServer;
+use crate::scrapepb::scrape_service_server::ScrapeServiceServer;
+use crate::storage::{CircuitBreaker, StorageUsage};
+use crate::symbolizer::{SymbolizationQueue, Symbolizer};
+use crate::tracespb::trace_service_server::TraceServiceServer;
+use crate::webhooks::Webhooks;
+use crate::{idempotency, query, replay, sampling, scrape};
+use anyhow::Context;
+use chrono::TimeDelta;
+use std::sync::Arc;
+use tonic::codec::CompressionEncoding;
+use tonic::service::interceptor::InterceptorLayer;
+use tonic::service::Routes;
+use tower_layer::Stack;
+
+/// Maximum size of the messages of the profile store and debuginfo services.
+const MAX_MESSAGE_SIZE: usize = 1_000_000_000;
+
+/// Returns the layers of the gRPC services: requests are authenticated, and
+/// panicking handlers fail their request instead of the connection.
+pub fn layer(
+    authenticator: Authenticator,
+) -> Stack<CatchPanicLayer, InterceptorLayer<Authenticator>> {
+    Stack::new(
+        CatchPanicLayer::new(),
+        tonic::service::interceptor(authenticator),
+    )
+}
+
+/// Builds the pipeline written profiles go through, interning their
+/// functions in `metastore`.
+pub fn write_pipeline(
+    flags: &Flags,
+    metastore: Arc<Metastore>,
+) -> anyhow::Result<normalizer::WritePipeline> {
+    let scrubbing = normalizer::LabelScrubbing::new(flags.scrub_labels.clone())
+        .with_hash_key_file(flags.scrub_hash_key_file.as_deref())?;
+    Ok(normalizer::WritePipeline::new(metastore)
+        .with_decompression_limits(normalizer::DecompressionLimits {
+            max_size: flags.max_decompressed_profile_bytes,
+            max_ratio: flags.max_decompression_ratio,
+        })
+        .with_timestamp_policy(normalizer::TimestampPolicy {
+            max_past: flags.max_profile_age,
+            max_future: flags.max_profile_future,
+            action: flags.out_of_bounds_timestamps,
+        })
+        .with_stack_depth_limit(normalizer::StackDepthLimit {
+            max_depth: flags.max_stack_depth,
+        })
+        .with_label_scrubbing(scrubbing)
+        .with_stack_trimming(normalizer::StackTrimming::new(flags.trim_frames.clone())))
+}
+
+/// Stores are what the services are built over, shared with the background
+/// tasks of the server.
+pub struct Stores {
+    pub metadata: MetadataStore,
+    pub debuginfod: DebugInfod,
+    pub debuginfo_buckets: Arc<BucketRoutes>,
+    pub layout: ObjectLayout,
+    /// Breaker of the default debuginfo bucket.
+    pub bucket_breaker: Arc<CircuitBreaker>,
+    pub metastore: Arc<Metastore>,
+    pub ingester: Arc<Ingester>,
+    pub symbolizer: Arc<Symbolizer>,
+    pub agents: Arc<AgentStore>,
+    pub sample_reader: query::SampleReader,
+    pub sources: Arc<query::Sources>,
+    pub webhooks: Arc<Webhooks>,
+    pub clock: Arc<dyn Clock>,
+}
+
+/// Indexes and limits the services share with the rest of the server, set
+/// up from the flags.
+pub struct Shared {
+    pub series_index: Arc<query::SeriesIndex>,
+    pub trace_index: Arc<query::TraceIndex>,
+    pub ingestion_stats: Arc<query::IngestionStats>,
+    pub payloads: Option<Arc<replay::PayloadBuffer>>,
+    pub query_executor: Arc<query::QueryExecutor>,
+    pub debuginfod_policy: Arc<DebuginfodPolicy>,
+    pub upload_limiter: UploadLimiter,
+}
+
+impl Shared {
+    fn new(flags: &Flags, metastore: &Arc<Metastore>) -> Self {
+        Self {
+            series_index: Arc::new(query::SeriesIndex::new(flags.max_indexed_series)),
+            trace_index: Arc::new(query::TraceIndex::new(
+                flags.trace_id_labels.clone(),
+                flags.trace_index_retention,
+                flags.trace_index_max_bytes,
+                Arc::clone(metastore),
+            )),
+            ingestion_stats: Arc::new(query::IngestionStats::new(flags.max_tracked_series)),
+            payloads: (!flags.replay_retention.is_zero()).then(|| {
+                Arc::new(replay::PayloadBuffer::new(
+                    flags.replay_retention,
+                    flags.replay_max_bytes,
+                ))
+            }),
+            query_executor: Arc::new(query::QueryExecutor::new(
+                flags.max_concurrent_queries,
+                flags.max_query_bytes,
+            )),
+            debuginfod_policy: Arc::new(DebuginfodPolicy::new(
+                flags.debuginfod_allowed_paths.clone(),
+                flags.debuginfod_denied_build_ids.clone(),
+            )),
+            upload_limiter: UploadLimiter::new(
+                flags.max_concurrent_uploads,
+                flags.upload_queue_timeout,
+            ),
+        }
+    }
+}
+
+/// ServicesBuilder builds the gRPC services from the flags and the stores.
+/// The parts that run background tasks are built by the server and passed
+/// in with the `with_*` methods.
+pub struct ServicesBuilder<'a> {
+    flags: &'a Flags,
+    stores: Stores,
+    shared: Shared,
+    memory_watchdog: Option<Arc<MemoryWatchdog>>,
+    kafka_forwarder: Option<Arc<KafkaForwarder>>,
+    symbolization_queue: Option<Arc<SymbolizationQueue>>,
+    clickhouse_sink: Option<Arc<ClickHouseSink>>,
+    profile_export: Option<Arc<query::ParquetExport>>,
+    diff_reports: Option<Arc<query::DiffReports>>,
+    storage_usage: Vec<Arc<StorageUsage>>,
+}
+
+impl<'a> ServicesBuilder<'a> {
+    pub fn new(flags: &'a Flags, stores: Stores) -> Self {
+        Self {
+            flags,
+            shared: Shared::new(flags, &stores.metastore),
+            stores,
+            memory_watchdog: None,
+            kafka_forwarder: None,
+            symbolization_queue: None,
+            clickhouse_sink: None,
+            profile_export: None,
+            diff_reports: None,
+            storage_usage: vec![],
+        }
+    }
+
+    pub fn shared(&self) -> &Shared {
+        &self.shared
+    }
+
+    /// Sheds writes and uploads while `watchdog` reports memory pressure.
+    pub fn with_memory_watchdog(mut self, watchdog: Arc<MemoryWatchdog>) -> Self {
+        self.memory_watchdog = Some(watchdog);
+        self
+    }
+
+    /// Publishes written profiles with `forwarder`.
+    pub fn with_kafka_forwarder(mut self, forwarder: Arc<KafkaForwarder>) -> Self {
+        self.kafka_forwarder = Some(forwarder);
+        self
+    }
+
+    /// Queues the addresses of written profiles for symbolization in `queue`.
+    pub fn with_symbolization_queue(mut self, queue: Arc<SymbolizationQueue>) -> Self {
+        self.symbolization_queue = Some(queue);
+        self
+    }
+
+    /// Writes the samples of written profiles to `sink`.
+    pub fn with_clickhouse_sink(mut self, sink: Arc<ClickHouseSink>) -> Self {
+        self.clickhouse_sink = Some(sink);
+        self
+    }
+
+    /// Serves exports of stored profiles with `export` on the admin API.
+    pub fn with_profile_export(mut self, export: Arc<query::ParquetExport>) -> Self {
+        self.profile_export = Some(export);
+        self
+    }
+
+    /// Serves the diff reports of `reports` on the admin API.
+    pub fn with_diff_reports(mut self, reports: Arc<query::DiffReports>) -> Self {
+        self.diff_reports = Some(reports);
+        self
+    }
+
+    /// Reports the usage of the buckets on the admin API.
+    pub fn with_storage_usage(mut self, storage_usage: Vec<Arc<StorageUsage>>) -> Self {
+        self.storage_usage = storage_usage;
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<Services> {
+        let (flags, stores, shared) = (self.flags, self.stores, self.shared);
+
+        let mut profile_store = ProfileStore::new(
+            Arc::clone(&stores.symbolizer),
+            stores.ingester,
+            Arc::clone(&stores.agents),
+            write_pipeline(flags, Arc::clone(&stores.metastore))?,
+            Arc::clone(&shared.series_index),
+            shared.payloads.clone(),
+        )
+        .with_idempotency(idempotency::IdempotencyKeys::new(flags.idempotency_window))
+        .with_adaptive_sampling(sampling::AdaptiveSampling::new(
+            flags.adaptive_sampling_max_in_flight,
+            flags.adaptive_sampling_max_drop_fraction,
+        ))
+        .with_trace_index(Arc::clone(&shared.trace_index))
+        .with_ingestion_stats(Arc::clone(&shared.ingestion_stats))
+        .with_mode(flags.mode)
+        .with_debuginfod_policy(Arc::clone(&shared.debuginfod_policy));
+        if let Some(watchdog) = &self.memory_watchdog {
+            profile_store = profile_store.with_memory_watchdog(Arc::clone(watchdog));
+        }
+        if let Some(forwarder) = self.kafka_forwarder {
+            profile_store = profile_store.with_kafka_forwarder(forwarder, flags.kafka_forward_only);
+        }
+        if let Some(queue) = self.symbolization_queue {
+            profile_store = profile_store.with_symbolization_queue(queue);
+        }
+        if let Some(sink) = self.clickhouse_sink {
+            profile_store = profile_store.with_clickhouse_sink(sink, !flags.clickhouse_only);
+        }
+        let profile_store = Arc::new(profile_store);
+
+        let upload_limiter = match &self.memory_watchdog {
+            Some(watchdog) => shared
+                .upload_limiter
+                .with_memory_watchdog(Arc::clone(watchdog)),
+            None => shared.upload_limiter,
+        };
+        let debuginfo = Arc::new(DebuginfoStore {
+            metadata: stores.metadata,
+            debuginfod: stores.debuginfod,
+            max_upload_duration: TimeDelta::new(60 * 15, 0).unwrap(),
+            upload_chunk_timeout: flags.upload_chunk_timeout,
+            max_upload_size: MAX_MESSAGE_SIZE as i64,
+            buckets: stores.debuginfo_buckets,
+            layout: stores.layout,
+            agents: Arc::clone(&stores.agents),
+            upload_limiter,
+            staleness: debuginfo_store::UploadStaleness::new(
+                flags.stale_upload_policy,
+                flags.stale_upload_grace,
+            ),
+            bucket_breaker: stores.bucket_breaker,
+            stripper: flags
+                .strip_uploaded_debuginfo
+                .then(|| debuginfo_store::SectionStripper::new(flags.strip_keep_sections.clone())),
+            tombstones: debuginfo_store::TombstonePolicy {
+                reports: flags.unavailable_debuginfo_reports,
+                ttl: flags.unavailable_debuginfo_ttl,
+            },
+            mode: flags.mode,
+            background_checks: flags
+                .debuginfod_background_checks
+                .then(debuginfo_store::BackgroundExistenceChecks::default),
+            debuginfod_policy: shared.debuginfod_policy,
+            webhooks: stores.webhooks,
+            clock: stores.clock,
+        });
+
+        let query = query::Query::new(shared.series_index)
+            .with_source_report(stores.sample_reader.clone(), stores.sources)
+            .with_query_executor(Arc::clone(&shared.query_executor));
+        let traces = shared.trace_index.is_enabled().then(|| {
+            query::Traces::new(shared.trace_index).with_sample_reader(
+                stores.sample_reader.clone(),
+                Arc::clone(&shared.query_executor),
+            )
+        });
+        let flight = query::Flight::new(stores.sample_reader)
+            .with_query_executor(Arc::clone(&shared.query_executor));
+
+        // The admin API can replay payloads and read every stored profile,
+        // so it's only served to clients holding the admin token.
+        let admin = match &flags.admin_token_file {
+            Some(path) => {
+                let token = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading admin token {}", path.display()))?;
+                anyhow::ensure!(!token.trim().is_empty(), "{} is empty", path.display());
+                let mut admin = replay::Admin::new(
+                    Arc::clone(&profile_store),
+                    shared.payloads,
+                    token.trim().to_string(),
+                )
+                .with_storage_usage(self.storage_usage)
+                .with_ingestion_stats(shared.ingestion_stats)
+                .with_symbolizer(stores.symbolizer);
+                if let Some(export) = self.profile_export {
+                    admin = admin.with_profile_export(export);
+                }
+                if let Some(reports) = self.diff_reports {
+                    admin = admin.with_diff_reports(reports);
+                }
+                Some(admin)
+            }
+            None => {
+                log::info!("Admin API disabled, set --admin-token-file to serve it");
+                None
+            }
+        };
+
+        Ok(Services {
+            profile_store,
+            debuginfo,
+            agents: stores.agents,
+            admin,
+            query,
+            flight,
+            traces,
+            scrape: None,
+        })
+    }
+}
+
+/// Services are the gRPC services of the server.
+pub struct Services {
+    pub profile_store: Arc<ProfileStore>,
+    pub debuginfo: Arc<DebuginfoStore>,
+    agents: Arc<AgentStore>,
+    admin: Option<replay::Admin>,
+    query: query::Query,
+    flight: query::Flight,
+    traces: Option<query::Traces>,
+    scrape: Option<Arc<scrape::TargetHealth>>,
+}
+
+impl Services {
+    /// Serves the scrape API with the health of the targets.
+    pub fn with_target_health(mut self, health: Arc<scrape::TargetHealth>) -> Self {
+        self.scrape = Some(health);
+        self
+    }
+
+    /// Returns the routes of the services, to be served behind [`layer`].
+    pub fn routes(self) -> Routes {
+        let mut routes = Routes::new(
+            ProfileStoreServiceServer::from_arc(self.profile_store)
+                .accept_compressed(CompressionEncoding::Gzip)
+                .max_decoding_message_size(MAX_MESSAGE_SIZE)
+                .max_encoding_message_size(MAX_MESSAGE_SIZE),
+        )
+        .add_service(AgentsServiceServer::from_arc(self.agents))
+        .add_service(QueryServiceServer::new(self.query))
+        .add_service(FlightServiceServer::new(self.flight))
+        .add_service(
+            DebuginfoServiceServer::from_arc(self.debuginfo)
+                .accept_compressed(CompressionEncoding::Gzip)
+                .max_decoding_message_size(MAX_MESSAGE_SIZE)
+                .max_encoding_message_size(MAX_MESSAGE_SIZE),
+        );
+        if let Some(admin) = self.admin {
+            routes = routes.add_service(AdminServiceServer::new(admin));
+        }
+        if let Some(health) = self.scrape {
+            routes = routes.add_service(ScrapeServiceServer::from_arc(health));
+        }
+        if let Some(traces) = self.traces {
+            routes = routes.add_service(TraceServiceServer::new(traces));
+        }
+        routes
+    }
+}
@@ -0,0 +1,347 @@
+use object_store::{
+    path::Path, GetOptions, GetResult, ListResult, MultipartUpload, ObjectMeta, ObjectStore,
+    PutMultipartOpts, PutOptions, PutPayload, PutResult, Result,
+};
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use std::collections::BTreeSet;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tokio_stream::{Stream, StreamExt};
+use tonic::Status;
+
+static CIRCUIT_OPEN: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "evprofiler_bucket_circuit_open",
+        "Whether operations on the bucket fail fast because it is unreachable.",
+        &["bucket"]
+    )
+    .unwrap()
+});
+
+/// Buckets whose circuit breaker is open.
+static DEGRADED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Returns the names of the buckets that are currently unavailable.
+pub fn degraded_buckets() -> Vec<String> {
+    DEGRADED.lock().unwrap().iter().cloned().collect()
+}
+
+/// CircuitOpenError is returned instead of calling the bucket while its
+/// circuit breaker is open.
+#[derive(Debug)]
+pub struct CircuitOpenError {
+    pub bucket: String,
+    pub retry_after: Duration,
+}
+
+impl std::fmt::Display for CircuitOpenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bucket is unavailable, retry after {}",
+            self.bucket,
+            humantime::format_duration(self.retry_after)
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpenError {}
+
+/// CircuitBreaker stops calling a bucket after `failure_threshold` operations
+/// failed in a row, so that requests fail fast instead of piling up while the
+/// bucket is down. While open, the bucket is probed every `probe_interval`
+/// and the breaker closes on the first successful probe.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    bucket: String,
+    failure_threshold: u32,
+    probe_interval: Duration,
+    failures: AtomicU32,
+    open: AtomicBool,
+}
+
+impl CircuitBreaker {
+    /// Creates a breaker for the bucket called `bucket`. A zero threshold
+    /// disables the breaker.
+    pub fn new(bucket: &str, failure_threshold: u32, probe_interval: Duration) -> Self {
+        CIRCUIT_OPEN.with_label_values(&[bucket]).set(0);
+        Self {
+            bucket: bucket.to_string(),
+            failure_threshold,
+            probe_interval,
+            failures: AtomicU32::new(0),
+            open: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Acquire)
+    }
+
+    /// Fails with Unavailable while the breaker is open, telling the client
+    /// when to retry.
+    pub fn check(&self) -> Result<(), Status> {
+        match self.open_error() {
+            Some(e) => Err(unavailable(&e)),
+            None => Ok(()),
+        }
+    }
+
+    fn open_error(&self) -> Option<CircuitOpenError> {
+        self.is_open().then(|| CircuitOpenError {
+            bucket: self.bucket.clone(),
+            retry_after: self.probe_interval,
+        })
+    }
+
+    /// Records the outcome of an operation. Returns whether the breaker
+    /// opened because of it.
+    fn record<T>(&self, res: &Result<T>) -> bool {
+        match res {
+            Err(e) if is_outage(e) => {
+                let failures = self.failures.fetch_add(1, Ordering::AcqRel) + 1;
+                self.failure_threshold > 0
+                    && failures >= self.failure_threshold
+                    && !self.open.swap(true, Ordering::AcqRel)
+            }
+            _ => {
+                self.failures.store(0, Ordering::Release);
+                false
+            }
+        }
+    }
+
+    fn close(&self) {
+        self.failures.store(0, Ordering::Release);
+        self.open.store(false, Ordering::Release);
+        CIRCUIT_OPEN.with_label_values(&[&self.bucket]).set(0);
+        DEGRADED.lock().unwrap().remove(&self.bucket);
+    }
+}
+
+/// The default breaker never opens.
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new("unnamed", 0, Duration::ZERO)
+    }
+}
+
+/// Whether the error means the bucket couldn't be reached, rather than the
+/// operation being rejected.
+fn is_outage(e: &object_store::Error) -> bool {
+    !matches!(
+        e,
+        object_store::Error::NotFound { .. }
+            | object_store::Error::AlreadyExists { .. }
+            | object_store::Error::Precondition { .. }
+            | object_store::Error::NotModified { .. }
+            | object_store::Error::InvalidPath { .. }
+            | object_store::Error::NotSupported { .. }
+            | object_store::Error::NotImplemented
+    )
+}
+
+fn unavailable(e: &CircuitOpenError) -> Status {
+    let mut status = Status::unavailable(e.to_string());
+    if let Ok(value) = e.retry_after.as_secs().max(1).to_string().parse() {
+        status.metadata_mut().insert("retry-after", value);
+    }
+    status
+}
+
+/// Converts an error of a bucket operation to a Status. Operations rejected
+/// by an open circuit breaker are reported as Unavailable with a retry-after
+/// hint, everything else as Internal.
+pub fn bucket_error_to_status(context: &str, e: &object_store::Error) -> Status {
+    if let object_store::Error::Generic { source, .. } = e {
+        if let Some(open) = source.downcast_ref::<CircuitOpenError>() {
+            return unavailable(open);
+        }
+    }
+    Status::internal(format!("{}: {}", context, e))
+}
+
+/// Wraps the bucket so that its operations go through the circuit breaker.
+pub fn with_circuit_breaker(
+    bucket: Arc<dyn ObjectStore>,
+    breaker: Arc<CircuitBreaker>,
+) -> Arc<dyn ObjectStore> {
+    if breaker.failure_threshold == 0 {
+        return bucket;
+    }
+    Arc::new(BreakerStore {
+        inner: bucket,
+        breaker,
+    })
+}
+
+#[derive(Debug)]
+struct BreakerStore {
+    inner: Arc<dyn ObjectStore>,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl std::fmt::Display for BreakerStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CircuitBreaker({})", self.inner)
+    }
+}
+
+impl BreakerStore {
+    fn check(&self) -> Result<()> {
+        match self.breaker.open_error() {
+            Some(e) => Err(object_store::Error::Generic {
+                store: "CircuitBreaker",
+                source: Box::new(e),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn record<T>(&self, res: Result<T>) -> Result<T> {
+        if self.breaker.record(&res) {
+            log::error!(
+                "{} bucket failed {} times in a row, failing fast until it recovers",
+                self.breaker.bucket,
+                self.breaker.failure_threshold
+            );
+            CIRCUIT_OPEN
+                .with_label_values(&[&self.breaker.bucket])
+                .set(1);
+            DEGRADED.lock().unwrap().insert(self.breaker.bucket.clone());
+            tokio::spawn(probe(Arc::clone(&self.inner), Arc::clone(&self.breaker)));
+        }
+        res
+    }
+}
+
+/// Probes the bucket until it responds again, then closes the breaker.
+async fn probe(bucket: Arc<dyn ObjectStore>, breaker: Arc<CircuitBreaker>) {
+    let path = Path::from("format.version");
+    loop {
+        tokio::time::sleep(breaker.probe_interval).await;
+        match bucket.head(&path).await {
+            Err(e) if is_outage(&e) => {
+                log::warn!("{} bucket is still unavailable: {}", breaker.bucket, e);
+            }
+            _ => {
+                log::info!("{} bucket recovered", breaker.bucket);
+                breaker.close();
+                return;
+            }
+        }
+    }
+}
+
+type BoxStream<'a, T> = Pin<Box<dyn Stream<Item = T> + Send + 'a>>;
+
+#[tonic::async_trait]
+impl ObjectStore for BreakerStore {
+    async fn put_opts(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> Result<PutResult> {
+        self.check()?;
+        self.record(self.inner.put_opts(location, payload, opts).await)
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        location: &Path,
+        opts: PutMultipartOpts,
+    ) -> Result<Box<dyn MultipartUpload>> {
+        self.check()?;
+        self.record(self.inner.put_multipart_opts(location, opts).await)
+    }
+
+    async fn get_opts(&self, location: &Path, options: GetOptions) -> Result<GetResult> {
+        self.check()?;
+        self.record(self.inner.get_opts(location, options).await)
+    }
+
+    async fn delete(&self, location: &Path) -> Result<()> {
+        self.check()?;
+        self.record(self.inner.delete(location).await)
+    }
+
+    fn list(&self, prefix: Option<&Path>) -> BoxStream<'_, Result<ObjectMeta>> {
+        if let Err(e) = self.check() {
+            return Box::pin(tokio_stream::once(Err(e)));
+        }
+        Box::pin(self.inner.list(prefix).map(|res| self.record(res)))
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> Result<ListResult> {
+        self.check()?;
+        self.record(self.inner.list_with_delimiter(prefix).await)
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        self.check()?;
+        self.record(self.inner.copy(from, to).await)
+    }
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> Result<()> {
+        self.check()?;
+        self.record(self.inner.copy_if_not_exists(from, to).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("bucket");
+        let local: Arc<dyn ObjectStore> =
+            Arc::new(crate::storage::new_local_bucket(&root).unwrap());
+        let breaker = Arc::new(CircuitBreaker::new("test", 2, Duration::from_millis(10)));
+        let bucket = with_circuit_breaker(local, Arc::clone(&breaker));
+
+        // Missing objects are not outages.
+        assert!(bucket.head(&Path::from("missing")).await.is_err());
+        assert!(bucket.head(&Path::from("missing")).await.is_err());
+        assert!(!breaker.is_open());
+
+        // Writing into a removed directory that is now a file fails for real.
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::write(&root, b"").unwrap();
+        for _ in 0..2 {
+            assert!(bucket
+                .put(&Path::from("a/b"), b"x".to_vec().into())
+                .await
+                .is_err());
+        }
+        assert!(breaker.is_open());
+        let err = bucket.head(&Path::from("a/b")).await.unwrap_err();
+        let status = bucket_error_to_status("head", &err);
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert!(status.metadata().get("retry-after").is_some());
+        assert_eq!(
+            breaker.check().unwrap_err().code(),
+            tonic::Code::Unavailable
+        );
+
+        // The probe closes the breaker once the bucket is back.
+        std::fs::remove_file(&root).unwrap();
+        std::fs::create_dir_all(&root).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!breaker.is_open());
+        bucket
+            .put(&Path::from("a/b"), b"x".to_vec().into())
+            .await
+            .unwrap();
+
+        let unbroken: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let disabled =
+            with_circuit_breaker(Arc::clone(&unbroken), Arc::new(CircuitBreaker::default()));
+        assert!(Arc::ptr_eq(&disabled, &unbroken));
+    }
+}
@@ -0,0 +1,86 @@
+use object_store::path::Path;
+use prometheus::{register_int_counter, IntCounter};
+use ring::digest::{digest, SHA256};
+use std::fmt::Write;
+use std::sync::LazyLock;
+
+static CORRUPT_OBJECTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_corrupt_objects_total",
+        "Total number of objects read from a bucket that didn't match their checksum."
+    )
+    .unwrap()
+});
+
+const SHA256_PREFIX: &str = "sha256:";
+
+/// Returns the checksum of the object, as `sha256:<hex>`.
+pub fn checksum(data: &[u8]) -> String {
+    let mut checksum = SHA256_PREFIX.to_string();
+    for byte in digest(&SHA256, data).as_ref() {
+        let _ = write!(checksum, "{:02x}", byte);
+    }
+    checksum
+}
+
+/// CorruptObjectError is returned when an object read from a bucket doesn't
+/// match the checksum recorded when it was written.
+#[derive(Debug)]
+pub struct CorruptObjectError {
+    pub path: Path,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl std::fmt::Display for CorruptObjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "object {} is corrupted, its checksum is {} instead of {}",
+            self.path, self.actual, self.expected
+        )
+    }
+}
+
+impl std::error::Error for CorruptObjectError {}
+
+/// Verifies the object at `path` against the checksum recorded for it.
+/// Objects without a checksum, or with one of an unknown algorithm, are not
+/// verified.
+pub fn verify(path: &Path, expected: &str, data: &[u8]) -> Result<(), CorruptObjectError> {
+    if !expected.starts_with(SHA256_PREFIX) {
+        return Ok(());
+    }
+    let actual = checksum(data);
+    if actual == expected {
+        return Ok(());
+    }
+    CORRUPT_OBJECTS.inc();
+    Err(CorruptObjectError {
+        path: path.clone(),
+        expected: expected.to_string(),
+        actual,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let sum = checksum(b"elf");
+        assert_eq!(
+            sum,
+            "sha256:780d84b20d7ae7e6292919399348bdbf96025270136198083fc8a4da398b5ca9"
+        );
+        let path = Path::from("upload-1");
+        assert!(verify(&path, &sum, b"elf").is_ok());
+        assert!(verify(&path, "", b"elf").is_ok());
+        assert!(verify(&path, "crc32c:00000000", b"elf").is_ok());
+
+        let e = verify(&path, &sum, b"elg").unwrap_err();
+        assert_eq!(e.expected, sum);
+        assert!(e.to_string().starts_with("object upload-1 is corrupted"));
+    }
+}
@@ -0,0 +1,563 @@
+use anyhow::{anyhow, bail, ensure, Context};
+use object_store::{
+    path::Path, GetOptions, GetRange, GetResult, GetResultPayload, ListResult, MultipartUpload,
+    ObjectMeta, ObjectStore, PutMode, PutMultipartOpts, PutOptions, PutPayload, PutResult, Result,
+};
+use prost::bytes::Bytes;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_stream::Stream;
+
+/// Environment variable holding the encryption keys when no keys file is
+/// given, for secrets injected by a KMS or the orchestrator.
+pub const KEYS_ENV: &str = "EVPROFILER_ENCRYPTION_KEYS";
+
+const MAGIC: &[u8; 8] = b"EVPENC01";
+/// Encrypted objects start with a header holding the magic and the ID of the
+/// key they were encrypted with.
+const HEADER_LEN: usize = 64;
+const MAX_KEY_ID_LEN: usize = HEADER_LEN - MAGIC.len() - 1;
+/// Objects are sealed in chunks of this size, so that ranges can be read
+/// without downloading and decrypting the whole object.
+const CHUNK_SIZE: usize = 64 << 10;
+const TAG_LEN: usize = 16;
+const SEALED_CHUNK_SIZE: usize = NONCE_LEN + CHUNK_SIZE + TAG_LEN;
+
+/// Keyring holds the AES-256 keys objects are encrypted with, by key ID. New
+/// objects are encrypted with the first key, the others are kept to read
+/// objects written before the keys were rotated.
+pub struct Keyring {
+    active: String,
+    keys: HashMap<String, LessSafeKey>,
+}
+
+impl std::fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keyring")
+            .field("active", &self.active)
+            .field("keys", &self.keys.len())
+            .finish()
+    }
+}
+
+impl Keyring {
+    /// Reads the keys from `path` if set, or else from the environment.
+    /// Returns None if neither holds keys.
+    pub fn load(path: Option<&std::path::Path>) -> anyhow::Result<Option<Self>> {
+        let keys = match path {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("reading encryption keys {}", path.display()))?,
+            None => match std::env::var(KEYS_ENV) {
+                Ok(keys) => keys,
+                Err(_) => return Ok(None),
+            },
+        };
+        Self::parse(&keys).map(Some)
+    }
+
+    /// Parses keys given as `<id>:<hex encoded 32 byte key>`, separated by
+    /// whitespace.
+    pub fn parse(keys: &str) -> anyhow::Result<Self> {
+        let mut active = None;
+        let mut parsed = HashMap::new();
+        for entry in keys.split_whitespace() {
+            let Some((id, key)) = entry.split_once(':') else {
+                bail!("encryption key must be <id>:<hex key>");
+            };
+            ensure!(
+                !id.is_empty() && id.len() <= MAX_KEY_ID_LEN,
+                "encryption key ID must have 1 to {} bytes",
+                MAX_KEY_ID_LEN
+            );
+            let key = decode_hex(key)
+                .filter(|key| key.len() == AES_256_GCM.key_len())
+                .ok_or_else(|| anyhow!("encryption key {} must be 32 hex encoded bytes", id))?;
+            let key = LessSafeKey::new(
+                UnboundKey::new(&AES_256_GCM, &key).map_err(|_| anyhow!("invalid key {}", id))?,
+            );
+            ensure!(
+                parsed.insert(id.to_string(), key).is_none(),
+                "duplicate encryption key {}",
+                id
+            );
+            active.get_or_insert_with(|| id.to_string());
+        }
+        Ok(Self {
+            active: active.ok_or_else(|| anyhow!("no encryption keys"))?,
+            keys: parsed,
+        })
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok().filter(|p| p.len() == 2)?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+/// Returns the ID of the key the object was encrypted with, or None if the
+/// object isn't encrypted.
+fn key_id(header: &[u8]) -> Option<&str> {
+    if header.len() < HEADER_LEN || !header.starts_with(MAGIC) {
+        return None;
+    }
+    let len = header[MAGIC.len()] as usize;
+    std::str::from_utf8(header.get(MAGIC.len() + 1..MAGIC.len() + 1 + len)?).ok()
+}
+
+/// Returns the size of the plaintext of an encrypted object of `len` bytes.
+/// Every encrypted object has at least one chunk, empty objects a chunk
+/// without data.
+fn plaintext_len(len: usize) -> Option<usize> {
+    let body = len.checked_sub(HEADER_LEN).filter(|body| *body > 0)?;
+    let (chunks, rest) = (body / SEALED_CHUNK_SIZE, body % SEALED_CHUNK_SIZE);
+    match rest {
+        0 => Some(chunks * CHUNK_SIZE),
+        rest if rest >= NONCE_LEN + TAG_LEN => {
+            Some(chunks * CHUNK_SIZE + rest - NONCE_LEN - TAG_LEN)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the number of sealed chunks of an encrypted object of `len`
+/// bytes.
+fn sealed_chunks(len: usize) -> usize {
+    (len - HEADER_LEN).div_ceil(SEALED_CHUNK_SIZE)
+}
+
+/// Chunks are bound to the object header and location, their position and
+/// whether they are the last chunk of the object, so that they can't be
+/// swapped or moved between objects, objects can't be moved to another
+/// location, and objects truncated at a chunk boundary fail to decrypt.
+fn chunk_aad(header: &[u8], location: &Path, index: usize, last: bool) -> Vec<u8> {
+    let location = location.as_ref().as_bytes();
+    let mut aad = header[..HEADER_LEN].to_vec();
+    aad.extend_from_slice(&(location.len() as u64).to_le_bytes());
+    aad.extend_from_slice(location);
+    aad.extend_from_slice(&(index as u64).to_le_bytes());
+    aad.push(last as u8);
+    aad
+}
+
+#[derive(Debug)]
+pub struct Encryption {
+    keyring: Keyring,
+    rng: SystemRandom,
+    strict: bool,
+}
+
+impl Encryption {
+    /// Encrypts objects with the active key of the keyring. Reads of objects
+    /// that aren't encrypted are rejected.
+    pub fn new(keyring: Keyring) -> Self {
+        Self {
+            keyring,
+            rng: SystemRandom::new(),
+            strict: true,
+        }
+    }
+
+    /// Serves objects that aren't encrypted as they are if `strict` is
+    /// unset. This is only meant for migrating buckets written before
+    /// encryption was enabled, since plaintext objects planted in the
+    /// buckets are served too.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    fn seal(&self, location: &Path, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let id = &self.keyring.active;
+        let key = &self.keyring.keys[id];
+        let mut sealed = Vec::with_capacity(
+            HEADER_LEN
+                + plaintext.len()
+                + plaintext.len().div_ceil(CHUNK_SIZE) * (NONCE_LEN + TAG_LEN),
+        );
+        sealed.extend_from_slice(MAGIC);
+        sealed.push(id.len() as u8);
+        sealed.extend_from_slice(id.as_bytes());
+        sealed.resize(HEADER_LEN, 0);
+        let header = sealed.clone();
+
+        let chunks = plaintext.len().div_ceil(CHUNK_SIZE).max(1);
+        for i in 0..chunks {
+            let chunk = &plaintext[(i * CHUNK_SIZE).min(plaintext.len())
+                ..((i + 1) * CHUNK_SIZE).min(plaintext.len())];
+            let mut nonce = [0; NONCE_LEN];
+            self.rng
+                .fill(&mut nonce)
+                .map_err(|_| anyhow!("generating nonce"))?;
+            let mut data = chunk.to_vec();
+            let tag = key
+                .seal_in_place_separate_tag(
+                    Nonce::assume_unique_for_key(nonce),
+                    Aad::from(chunk_aad(&header, location, i, i + 1 == chunks)),
+                    &mut data,
+                )
+                .map_err(|_| anyhow!("encrypting object"))?;
+            sealed.extend_from_slice(&nonce);
+            sealed.extend_from_slice(&data);
+            sealed.extend_from_slice(tag.as_ref());
+        }
+        Ok(sealed)
+    }
+
+    /// Decrypts the sealed chunks in `data`, the first of which is chunk
+    /// `first` of the object at `location` of `chunks` chunks.
+    fn open(
+        &self,
+        location: &Path,
+        header: &[u8],
+        first: usize,
+        chunks: usize,
+        data: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let id = key_id(header).ok_or_else(|| anyhow!("object is not encrypted"))?;
+        let key = self
+            .keyring
+            .keys
+            .get(id)
+            .ok_or_else(|| anyhow!("object is encrypted with unknown key {}", id))?;
+        let mut plaintext = Vec::with_capacity(data.len());
+        for (i, chunk) in data.chunks(SEALED_CHUNK_SIZE).enumerate() {
+            ensure!(
+                chunk.len() >= NONCE_LEN + TAG_LEN,
+                "truncated encrypted object"
+            );
+            let index = first + i;
+            let (nonce, sealed) = chunk.split_at(NONCE_LEN);
+            let mut sealed = sealed.to_vec();
+            let opened = key
+                .open_in_place(
+                    Nonce::try_assume_unique_for_key(nonce)
+                        .map_err(|_| anyhow!("invalid nonce"))?,
+                    Aad::from(chunk_aad(header, location, index, index + 1 == chunks)),
+                    &mut sealed,
+                )
+                .map_err(|_| anyhow!("decrypting object with key {} failed", id))?;
+            plaintext.extend_from_slice(opened);
+        }
+        Ok(plaintext)
+    }
+}
+
+fn error(e: anyhow::Error) -> object_store::Error {
+    object_store::Error::Generic {
+        store: "Encryption",
+        source: e.into(),
+    }
+}
+
+/// Resolves the requested range of an object of `len` bytes.
+fn resolve(range: Option<&GetRange>, len: usize) -> anyhow::Result<Range<usize>> {
+    let range = match range {
+        None => 0..len,
+        Some(GetRange::Bounded(r)) => r.start..r.end.min(len),
+        Some(GetRange::Offset(o)) => *o..len,
+        Some(GetRange::Suffix(n)) => len.saturating_sub(*n)..len,
+    };
+    ensure!(
+        range.start <= range.end && (range.start < len || range.is_empty()),
+        "range {:?} is invalid for an object of {} bytes",
+        range,
+        len
+    );
+    Ok(range)
+}
+
+/// Encrypts the objects written to the bucket with AES-256-GCM and decrypts
+/// them when they are read. Objects written before encryption was enabled are
+/// only read as they are if the encryption isn't strict. Listings report the
+/// size of the stored objects, which includes the encryption overhead.
+pub fn with_encryption(
+    bucket: Arc<dyn ObjectStore>,
+    encryption: Option<Arc<Encryption>>,
+) -> Arc<dyn ObjectStore> {
+    match encryption {
+        Some(encryption) => Arc::new(EncryptedStore {
+            inner: bucket,
+            encryption,
+        }),
+        None => bucket,
+    }
+}
+
+#[derive(Debug)]
+struct EncryptedStore {
+    inner: Arc<dyn ObjectStore>,
+    encryption: Arc<Encryption>,
+}
+
+impl std::fmt::Display for EncryptedStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Encrypted({})", self.inner)
+    }
+}
+
+impl EncryptedStore {
+    /// Returns the metadata of the stored object and its header, or None if
+    /// it isn't encrypted. Fails for objects that aren't encrypted if the
+    /// encryption is strict.
+    async fn header(&self, location: &Path) -> Result<(ObjectMeta, Option<Bytes>)> {
+        let meta = self.inner.head(location).await?;
+        let header = if meta.size < HEADER_LEN {
+            None
+        } else {
+            let header = self.inner.get_range(location, 0..HEADER_LEN).await?;
+            key_id(&header).is_some().then_some(header)
+        };
+        if header.is_none() && self.encryption.strict {
+            return Err(error(anyhow!("object {} is not encrypted", location)));
+        }
+        Ok((meta, header))
+    }
+}
+
+type BoxStream<'a, T> = Pin<Box<dyn Stream<Item = T> + Send + 'a>>;
+
+#[tonic::async_trait]
+impl ObjectStore for EncryptedStore {
+    async fn put_opts(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> Result<PutResult> {
+        let plaintext: Vec<u8> = payload.iter().flat_map(|b| b.iter().copied()).collect();
+        let sealed = self.encryption.seal(location, &plaintext).map_err(error)?;
+        self.inner.put_opts(location, sealed.into(), opts).await
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        _: &Path,
+        _: PutMultipartOpts,
+    ) -> Result<Box<dyn MultipartUpload>> {
+        Err(object_store::Error::NotSupported {
+            source: "multipart uploads of encrypted objects".into(),
+        })
+    }
+
+    async fn get_opts(&self, location: &Path, options: GetOptions) -> Result<GetResult> {
+        if options.head {
+            let meta = self.head(location).await?;
+            return Ok(GetResult {
+                range: 0..meta.size,
+                payload: GetResultPayload::Stream(Box::pin(tokio_stream::empty())),
+                meta,
+                attributes: Default::default(),
+            });
+        }
+        let (meta, Some(header)) = self.header(location).await? else {
+            return self.inner.get_opts(location, options).await;
+        };
+        let len = plaintext_len(meta.size)
+            .ok_or_else(|| error(anyhow!("encrypted object {} is truncated", location)))?;
+        let range = resolve(options.range.as_ref(), len).map_err(error)?;
+        let chunks = sealed_chunks(meta.size);
+        // Reads reaching the end of the object include its last chunk, so
+        // that truncated objects are detected.
+        let (data, attributes) = if range.is_empty() && range.end < len {
+            (Bytes::new(), Default::default())
+        } else {
+            let first = (range.start / CHUNK_SIZE).min(chunks - 1);
+            let last = if range.end == len {
+                chunks - 1
+            } else {
+                (range.end - 1) / CHUNK_SIZE
+            };
+            let sealed = HEADER_LEN + first * SEALED_CHUNK_SIZE
+                ..(HEADER_LEN + (last + 1) * SEALED_CHUNK_SIZE).min(meta.size);
+            let res = self
+                .inner
+                .get_opts(
+                    location,
+                    GetOptions {
+                        range: Some(GetRange::Bounded(sealed)),
+                        ..options
+                    },
+                )
+                .await?;
+            let attributes = res.attributes.clone();
+            let opened = self
+                .encryption
+                .open(location, &header, first, chunks, &res.bytes().await?)
+                .map_err(error)?;
+            let offset = first * CHUNK_SIZE;
+            (
+                Bytes::copy_from_slice(&opened[range.start - offset..range.end - offset]),
+                attributes,
+            )
+        };
+        Ok(GetResult {
+            payload: GetResultPayload::Stream(Box::pin(tokio_stream::once(Ok(data)))),
+            meta: ObjectMeta { size: len, ..meta },
+            range,
+            attributes,
+        })
+    }
+
+    async fn head(&self, location: &Path) -> Result<ObjectMeta> {
+        let (meta, header) = self.header(location).await?;
+        if header.is_none() {
+            return Ok(meta);
+        }
+        Ok(ObjectMeta {
+            size: plaintext_len(meta.size)
+                .ok_or_else(|| error(anyhow!("encrypted object {} is truncated", location)))?,
+            ..meta
+        })
+    }
+
+    async fn delete(&self, location: &Path) -> Result<()> {
+        self.inner.delete(location).await
+    }
+
+    fn list(&self, prefix: Option<&Path>) -> BoxStream<'_, Result<ObjectMeta>> {
+        self.inner.list(prefix)
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> Result<ListResult> {
+        self.inner.list_with_delimiter(prefix).await
+    }
+
+    // Objects are bound to their location, so copies are encrypted again.
+    async fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        let data = self.get(from).await?.bytes().await?;
+        self.put(to, data.into()).await?;
+        Ok(())
+    }
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> Result<()> {
+        let data = self.get(from).await?.bytes().await?;
+        self.put_opts(to, data.into(), PutMode::Create.into())
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    const KEY_A: &str = "a:000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_B: &str = "b:1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    fn encrypted(inner: &Arc<dyn ObjectStore>, keys: &str) -> Arc<dyn ObjectStore> {
+        let encryption = Encryption::new(Keyring::parse(keys).unwrap());
+        with_encryption(Arc::clone(inner), Some(Arc::new(encryption)))
+    }
+
+    #[tokio::test]
+    async fn test_encryption() {
+        let inner: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let bucket = encrypted(&inner, KEY_A);
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let path = Path::from("object");
+        bucket.put(&path, data.clone().into()).await.unwrap();
+
+        // The stored object doesn't contain the plaintext.
+        let stored = inner.get(&path).await.unwrap().bytes().await.unwrap();
+        assert_eq!(plaintext_len(stored.len()), Some(data.len()));
+        assert!(!stored.windows(64).any(|w| w == &data[1000..1064]));
+
+        assert_eq!(
+            bucket.get(&path).await.unwrap().bytes().await.unwrap(),
+            data
+        );
+        assert_eq!(bucket.head(&path).await.unwrap().size, data.len());
+        for range in [
+            0..10,
+            CHUNK_SIZE - 5..CHUNK_SIZE + 5,
+            2 * CHUNK_SIZE..data.len(),
+        ] {
+            let got = bucket.get_range(&path, range.clone()).await.unwrap();
+            assert_eq!(got, data[range]);
+        }
+
+        // Rotated keys still decrypt older objects, unknown keys don't.
+        let rotated = encrypted(&inner, &format!("{} {}", KEY_B, KEY_A));
+        assert_eq!(
+            rotated.get(&path).await.unwrap().bytes().await.unwrap(),
+            data
+        );
+        let err = encrypted(&inner, KEY_B).get(&path).await.unwrap_err();
+        assert!(err.to_string().contains("unknown key a"), "{}", err);
+
+        // Tampered objects fail to decrypt.
+        let mut tampered = stored.to_vec();
+        tampered[HEADER_LEN + NONCE_LEN] ^= 1;
+        inner.put(&path, tampered.into()).await.unwrap();
+        assert!(bucket.get(&path).await.is_err());
+
+        // Objects truncated at a chunk boundary fail to decrypt.
+        let truncated = stored[..HEADER_LEN + 2 * SEALED_CHUNK_SIZE].to_vec();
+        inner.put(&path, truncated.into()).await.unwrap();
+        assert!(bucket.get(&path).await.is_err());
+        let header = stored[..HEADER_LEN].to_vec();
+        inner.put(&path, header.into()).await.unwrap();
+        assert!(bucket.get(&path).await.is_err());
+
+        // Objects can't be moved to another location, but can be copied.
+        bucket.put(&path, data.clone().into()).await.unwrap();
+        let moved = Path::from("moved");
+        inner.copy(&path, &moved).await.unwrap();
+        assert!(bucket.get(&moved).await.is_err());
+        bucket.copy(&path, &moved).await.unwrap();
+        assert_eq!(
+            bucket.get(&moved).await.unwrap().bytes().await.unwrap(),
+            data
+        );
+        assert!(bucket.copy_if_not_exists(&path, &moved).await.is_err());
+
+        // Objects that aren't encrypted are rejected, unless the encryption
+        // isn't strict.
+        let plain = Path::from("plain");
+        inner.put(&plain, b"plain".to_vec().into()).await.unwrap();
+        assert!(bucket.get(&plain).await.is_err());
+        assert!(bucket.head(&plain).await.is_err());
+        let encryption = Encryption::new(Keyring::parse(KEY_A).unwrap()).with_strict(false);
+        let lenient = with_encryption(Arc::clone(&inner), Some(Arc::new(encryption)));
+        assert_eq!(
+            lenient.get(&plain).await.unwrap().bytes().await.unwrap(),
+            "plain"
+        );
+        assert_eq!(lenient.head(&plain).await.unwrap().size, 5);
+
+        let empty = Path::from("empty");
+        bucket.put(&empty, Vec::new().into()).await.unwrap();
+        assert!(bucket
+            .get(&empty)
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_keyring() {
+        assert!(Keyring::parse("").is_err());
+        assert!(Keyring::parse("a:00").is_err());
+        assert!(Keyring::parse("nokey").is_err());
+        assert!(Keyring::parse(&format!("{} {}", KEY_A, KEY_A)).is_err());
+        assert_eq!(
+            Keyring::parse(&format!("{}\n{}\n", KEY_B, KEY_A))
+                .unwrap()
+                .active,
+            "b"
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use anyhow::{bail, Context};
+use object_store::{path::Path, ObjectStore};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// Object holding the format version of the data in a bucket.
+const VERSION_PATH: &str = "format.version";
+
+type MigrationFuture = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+
+/// Migration upgrades the data of a bucket to `version` from the previous
+/// version.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub run: fn(Arc<dyn ObjectStore>) -> MigrationFuture,
+}
+
+/// Migrations of the debuginfo bucket, holding debuginfo objects and their
+/// metadata.
+pub const DEBUGINFO_MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    description: "introduce the format version marker",
+    run: noop,
+}];
+
+/// Migrations of the profile bucket, holding parquet segments and the
+/// function table they reference.
+pub const PROFILE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "introduce the format version marker",
+        run: noop,
+    },
+    // The functions referenced by the segments of earlier versions were only
+    // kept in memory, so their frames stay unnamed.
+    Migration {
+        version: 2,
+        description: "persist the function table next to the segments",
+        run: noop,
+    },
+];
+
+fn noop(_: Arc<dyn ObjectStore>) -> MigrationFuture {
+    Box::pin(async { Ok(()) })
+}
+
+/// Upgrades the data of the bucket to the latest version by running the
+/// migrations it hasn't seen yet, in order. Data without a version marker is
+/// at version 0. Fails if the data was written by a newer version, so it is
+/// never modified by code that doesn't understand it.
+pub async fn migrate(
+    bucket: Arc<dyn ObjectStore>,
+    name: &str,
+    migrations: &[Migration],
+) -> anyhow::Result<u32> {
+    let latest = migrations.last().map_or(0, |m| m.version);
+    let initial = read_version(bucket.as_ref()).await?;
+    let mut current = initial;
+    if initial > latest {
+        bail!(
+            "{} data is at format version {}, but this build only supports up to version {}",
+            name,
+            initial,
+            latest
+        );
+    }
+
+    for migration in migrations.iter().filter(|m| m.version > initial) {
+        log::info!(
+            "Migrating {} data to format version {}: {}",
+            name,
+            migration.version,
+            migration.description
+        );
+        (migration.run)(Arc::clone(&bucket))
+            .await
+            .with_context(|| format!("migrating {} data to version {}", name, migration.version))?;
+
+        // Recorded after every step, so an interrupted run resumes where it
+        // stopped.
+        bucket
+            .put(
+                &Path::from(VERSION_PATH),
+                migration.version.to_string().into_bytes().into(),
+            )
+            .await?;
+        current = migration.version;
+    }
+    Ok(current)
+}
+
+async fn read_version(bucket: &dyn ObjectStore) -> anyhow::Result<u32> {
+    match bucket.get(&Path::from(VERSION_PATH)).await {
+        Ok(res) => {
+            let data = res.bytes().await?;
+            let version = std::str::from_utf8(&data)?.trim();
+            version
+                .parse()
+                .with_context(|| format!("invalid format version {:?}", version))
+        }
+        Err(object_store::Error::NotFound { .. }) => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    fn mark(bucket: Arc<dyn ObjectStore>) -> MigrationFuture {
+        Box::pin(async move {
+            bucket
+                .put(&Path::from("migrated"), b"yes".to_vec().into())
+                .await?;
+            Ok(())
+        })
+    }
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let migrations = [
+            Migration {
+                version: 1,
+                description: "noop",
+                run: noop,
+            },
+            Migration {
+                version: 2,
+                description: "mark",
+                run: mark,
+            },
+        ];
+
+        assert_eq!(
+            migrate(Arc::clone(&bucket), "test", &migrations[..1])
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(bucket.head(&Path::from("migrated")).await.is_err());
+
+        assert_eq!(
+            migrate(Arc::clone(&bucket), "test", &migrations)
+                .await
+                .unwrap(),
+            2
+        );
+        assert!(bucket.head(&Path::from("migrated")).await.is_ok());
+        assert_eq!(read_version(bucket.as_ref()).await.unwrap(), 2);
+
+        // Data of a newer version is left alone.
+        assert!(migrate(Arc::clone(&bucket), "test", &migrations[..1])
+            .await
+            .is_err());
+    }
+}
@@ -1,5 +1,32 @@
-use object_store::{memory::InMemory, ObjectStore};
+mod breaker;
+mod checksum;
+mod encryption;
+mod migrate;
+mod usage;
+
+pub use breaker::{bucket_error_to_status, degraded_buckets, with_circuit_breaker, CircuitBreaker};
+pub use checksum::{checksum, verify, CorruptObjectError};
+pub use encryption::{with_encryption, Encryption, Keyring};
+pub use migrate::{migrate, DEBUGINFO_MIGRATIONS, PROFILE_MIGRATIONS};
+use object_store::{local::LocalFileSystem, memory::InMemory, prefix::PrefixStore, ObjectStore};
+use std::path::Path;
+use std::sync::Arc;
+pub use usage::{debuginfo_objects, profile_objects, report_usage, with_usage, StorageUsage};
 
 pub fn new_memory_bucket() -> impl ObjectStore {
     InMemory::new()
 }
+
+pub fn new_local_bucket(dir: &Path) -> anyhow::Result<impl ObjectStore> {
+    std::fs::create_dir_all(dir)?;
+    Ok(LocalFileSystem::new_with_prefix(dir)?)
+}
+
+/// Scopes all objects of the bucket under `prefix`. An empty prefix returns
+/// the bucket unchanged.
+pub fn with_prefix(bucket: Arc<dyn ObjectStore>, prefix: &str) -> Arc<dyn ObjectStore> {
+    if prefix.is_empty() {
+        return bucket;
+    }
+    Arc::new(PrefixStore::new(bucket, prefix))
+}
@@ -0,0 +1,438 @@
+use crate::adminpb;
+use object_store::{
+    path::Path, GetOptions, GetResult, ListResult, MultipartUpload, ObjectMeta, ObjectStore,
+    PutMultipartOpts, PutOptions, PutPayload, PutResult, Result, UploadPart,
+};
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tokio_stream::{Stream, StreamExt};
+
+static BUCKET_OBJECTS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "evprofiler_bucket_objects",
+        "Number of objects in the bucket, by category.",
+        &["bucket", "category"]
+    )
+    .unwrap()
+});
+
+static BUCKET_BYTES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "evprofiler_bucket_bytes",
+        "Total size in bytes of the objects in the bucket, by category.",
+        &["bucket", "category"]
+    )
+    .unwrap()
+});
+
+/// Returns the category of an object and, for partitioned objects, its
+/// partition.
+pub type Classifier = fn(&Path) -> (&'static str, Option<String>);
+
+/// Classifies the objects of the debuginfo bucket into debuginfo objects and
+/// the metadata about them.
+pub fn debuginfo_objects(path: &Path) -> (&'static str, Option<String>) {
+    let name = path.filename().unwrap_or_default();
+    let is_metadata = ["metadata", "tombstone", "validators", ".bloom", ".version"]
+        .iter()
+        .any(|suffix| name.ends_with(suffix));
+    match is_metadata {
+        true => ("metadata", None),
+        false => ("debuginfo", None),
+    }
+}
+
+/// Classifies the objects of the profile bucket into segments, partitioned
+/// by the day they were written, and metadata.
+pub fn profile_objects(path: &Path) -> (&'static str, Option<String>) {
+    if path.extension() != Some("parquet") {
+        return ("metadata", None);
+    }
+    let partition = path
+        .parts()
+        .next()
+        .filter(|part| part.as_ref().contains('='))
+        .map(|part| part.as_ref().to_string());
+    ("segments", partition)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, size: u64) {
+        self.objects += 1;
+        self.bytes += size;
+    }
+
+    fn remove(&mut self, size: u64) {
+        self.objects = self.objects.saturating_sub(1);
+        self.bytes = self.bytes.saturating_sub(size);
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Size of every object, to account for overwritten and deleted objects.
+    sizes: HashMap<Path, u64>,
+    categories: BTreeMap<&'static str, Usage>,
+    partitions: BTreeMap<String, Usage>,
+    /// Objects deleted while the bucket is scanned, which the scan must not
+    /// count if it lists them.
+    deleted: HashSet<Path>,
+    scanning: bool,
+    scanned: bool,
+}
+
+/// StorageUsage keeps the number and size of the objects of a bucket up to
+/// date as they are written and deleted, so that usage is known without
+/// listing the bucket. Objects that existed before the server started are
+/// counted once by `scan`.
+#[derive(Debug)]
+pub struct StorageUsage {
+    bucket: String,
+    classify: Classifier,
+    state: Mutex<State>,
+}
+
+impl StorageUsage {
+    pub fn new(bucket: &str, classify: Classifier) -> Self {
+        Self {
+            bucket: bucket.to_string(),
+            classify,
+            state: Mutex::default(),
+        }
+    }
+
+    fn update(&self, state: &mut State, path: &Path, size: Option<u64>) {
+        let previous = match size {
+            Some(size) => state.sizes.insert(path.clone(), size),
+            None => state.sizes.remove(path),
+        };
+        let (category, partition) = (self.classify)(path);
+        let usage = state.categories.entry(category).or_default();
+        if let Some(previous) = previous {
+            usage.remove(previous);
+        }
+        if let Some(size) = size {
+            usage.add(size);
+        }
+        BUCKET_OBJECTS
+            .with_label_values(&[&self.bucket, category])
+            .set(usage.objects as i64);
+        BUCKET_BYTES
+            .with_label_values(&[&self.bucket, category])
+            .set(usage.bytes as i64);
+
+        if let Some(partition) = partition {
+            let usage = state.partitions.entry(partition.clone()).or_default();
+            if let Some(previous) = previous {
+                usage.remove(previous);
+            }
+            if let Some(size) = size {
+                usage.add(size);
+            }
+            if usage.objects == 0 {
+                state.partitions.remove(&partition);
+            }
+        }
+    }
+
+    fn record_put(&self, path: &Path, size: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.deleted.remove(path);
+        self.update(&mut state, path, Some(size));
+    }
+
+    fn record_delete(&self, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        if state.scanning {
+            state.deleted.insert(path.clone());
+        }
+        self.update(&mut state, path, None);
+    }
+
+    fn record_copy(&self, from: &Path, to: &Path) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(size) = state.sizes.get(from).copied() {
+            state.deleted.remove(to);
+            self.update(&mut state, to, Some(size));
+        }
+    }
+
+    /// Counts the objects that are in the bucket already. Objects written
+    /// while scanning are counted with their latest size.
+    pub async fn scan(&self, bucket: &dyn ObjectStore) -> Result<()> {
+        self.state.lock().unwrap().scanning = true;
+        let mut objects = bucket.list(None);
+        let res = loop {
+            let meta = match objects.next().await {
+                Some(Ok(meta)) => meta,
+                Some(Err(e)) => break Err(e),
+                None => break Ok(()),
+            };
+            let mut state = self.state.lock().unwrap();
+            if !state.sizes.contains_key(&meta.location) && !state.deleted.contains(&meta.location)
+            {
+                self.update(&mut state, &meta.location, Some(meta.size as u64));
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.scanning = false;
+        state.scanned = res.is_ok();
+        state.deleted.clear();
+        res
+    }
+
+    pub fn report(&self) -> adminpb::BucketUsage {
+        let usage = |(name, usage): (&str, &Usage)| adminpb::StorageUsage {
+            name: name.to_string(),
+            objects: usage.objects,
+            bytes: usage.bytes,
+        };
+        let state = self.state.lock().unwrap();
+        adminpb::BucketUsage {
+            bucket: self.bucket.clone(),
+            complete: state.scanned,
+            categories: state
+                .categories
+                .iter()
+                .map(|(k, v)| usage((k, v)))
+                .collect(),
+            partitions: state
+                .partitions
+                .iter()
+                .map(|(k, v)| usage((k, v)))
+                .collect(),
+        }
+    }
+}
+
+/// Logs the usage of the buckets every `interval`.
+pub async fn report_usage(usages: Vec<Arc<StorageUsage>>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        for usage in usages.iter() {
+            let report = usage.report();
+            let categories: Vec<String> = report
+                .categories
+                .iter()
+                .map(|c| format!("{} objects of {} in {} bytes", c.objects, c.name, c.bytes))
+                .collect();
+            log::info!(
+                "{} bucket holds {}{}",
+                report.bucket,
+                categories.join(", "),
+                if report.complete {
+                    ""
+                } else {
+                    " (still counting)"
+                }
+            );
+        }
+    }
+}
+
+/// Wraps the bucket so that the objects written and deleted through it are
+/// accounted in `usage`.
+pub fn with_usage(bucket: Arc<dyn ObjectStore>, usage: Arc<StorageUsage>) -> Arc<dyn ObjectStore> {
+    Arc::new(UsageStore {
+        inner: bucket,
+        usage,
+    })
+}
+
+#[derive(Debug)]
+struct UsageStore {
+    inner: Arc<dyn ObjectStore>,
+    usage: Arc<StorageUsage>,
+}
+
+impl std::fmt::Display for UsageStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Usage({})", self.inner)
+    }
+}
+
+/// UsageUpload accounts a multipart upload once it is complete.
+#[derive(Debug)]
+struct UsageUpload {
+    inner: Box<dyn MultipartUpload>,
+    usage: Arc<StorageUsage>,
+    location: Path,
+    size: u64,
+}
+
+#[tonic::async_trait]
+impl MultipartUpload for UsageUpload {
+    fn put_part(&mut self, data: PutPayload) -> UploadPart {
+        self.size += data.content_length() as u64;
+        self.inner.put_part(data)
+    }
+
+    async fn complete(&mut self) -> Result<PutResult> {
+        let res = self.inner.complete().await?;
+        self.usage.record_put(&self.location, self.size);
+        Ok(res)
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        self.inner.abort().await
+    }
+}
+
+type BoxStream<'a, T> = Pin<Box<dyn Stream<Item = T> + Send + 'a>>;
+
+#[tonic::async_trait]
+impl ObjectStore for UsageStore {
+    async fn put_opts(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> Result<PutResult> {
+        let size = payload.content_length() as u64;
+        let res = self.inner.put_opts(location, payload, opts).await?;
+        self.usage.record_put(location, size);
+        Ok(res)
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        location: &Path,
+        opts: PutMultipartOpts,
+    ) -> Result<Box<dyn MultipartUpload>> {
+        let inner = self.inner.put_multipart_opts(location, opts).await?;
+        Ok(Box::new(UsageUpload {
+            inner,
+            usage: Arc::clone(&self.usage),
+            location: location.clone(),
+            size: 0,
+        }))
+    }
+
+    async fn get_opts(&self, location: &Path, options: GetOptions) -> Result<GetResult> {
+        self.inner.get_opts(location, options).await
+    }
+
+    async fn delete(&self, location: &Path) -> Result<()> {
+        self.inner.delete(location).await?;
+        self.usage.record_delete(location);
+        Ok(())
+    }
+
+    fn list(&self, prefix: Option<&Path>) -> BoxStream<'_, Result<ObjectMeta>> {
+        self.inner.list(prefix)
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> Result<ListResult> {
+        self.inner.list_with_delimiter(prefix).await
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner.copy(from, to).await?;
+        self.usage.record_copy(from, to);
+        Ok(())
+    }
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner.copy_if_not_exists(from, to).await?;
+        self.usage.record_copy(from, to);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    fn category(usage: &StorageUsage, category: &str) -> Usage {
+        let state = usage.state.lock().unwrap();
+        state.categories.get(category).copied().unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn test_storage_usage() {
+        let inner: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        inner
+            .put(&Path::from("abcd/metadata"), vec![0; 10].into())
+            .await
+            .unwrap();
+        inner
+            .put(&Path::from("upload-1"), vec![0; 100].into())
+            .await
+            .unwrap();
+
+        let usage = Arc::new(StorageUsage::new("test", debuginfo_objects));
+        let bucket = with_usage(Arc::clone(&inner), Arc::clone(&usage));
+        // Objects written before the scan are counted with their latest size.
+        bucket
+            .put(&Path::from("upload-1"), vec![0; 50].into())
+            .await
+            .unwrap();
+        assert!(!usage.report().complete);
+        usage.scan(inner.as_ref()).await.unwrap();
+        assert_eq!(
+            category(&usage, "metadata"),
+            Usage {
+                objects: 1,
+                bytes: 10
+            }
+        );
+        assert_eq!(
+            category(&usage, "debuginfo"),
+            Usage {
+                objects: 1,
+                bytes: 50
+            }
+        );
+
+        bucket
+            .copy(&Path::from("upload-1"), &Path::from("upload-2"))
+            .await
+            .unwrap();
+        bucket.delete(&Path::from("abcd/metadata")).await.unwrap();
+        let mut upload = bucket.put_multipart(&Path::from("upload-3")).await.unwrap();
+        upload.put_part(vec![0; 5].into()).await.unwrap();
+        upload.complete().await.unwrap();
+        assert_eq!(category(&usage, "metadata"), Usage::default());
+        assert_eq!(
+            category(&usage, "debuginfo"),
+            Usage {
+                objects: 3,
+                bytes: 105
+            }
+        );
+        assert!(usage.report().complete);
+    }
+
+    #[test]
+    fn test_profile_objects() {
+        assert_eq!(
+            profile_objects(&Path::from("date=2024-01-02/1-abc.parquet")),
+            ("segments", Some("date=2024-01-02".to_string()))
+        );
+        assert_eq!(
+            profile_objects(&Path::from("format.version")),
+            ("metadata", None)
+        );
+
+        let usage = StorageUsage::new("profile", profile_objects);
+        let mut state = usage.state.lock().unwrap();
+        let path = Path::from("date=2024-01-02/1-abc.parquet");
+        usage.update(&mut state, &path, Some(7));
+        assert_eq!(state.partitions["date=2024-01-02"].bytes, 7);
+        usage.update(&mut state, &path, None);
+        assert!(state.partitions.is_empty());
+    }
+}
@@ -1,6 +1,7 @@
 use crate::profile::LocationLine;
 
 use super::normalize::NormalizedAddress;
+use crate::memory::MemoryUsage;
 use moka::sync::Cache;
 
 #[derive(Debug, Clone)]
@@ -14,6 +15,15 @@ impl Default for SymbolizerCache {
     }
 }
 
+impl MemoryUsage for SymbolizerCache {
+    fn memory_usage(&self) -> u64 {
+        self.c
+            .iter()
+            .map(|(k, v)| k.len() + v.iter().map(Vec::len).sum::<usize>())
+            .sum::<usize>() as u64
+    }
+}
+
 impl SymbolizerCache {
     pub fn new(cap: u64) -> Self {
         let c = Cache::new(cap);
@@ -0,0 +1,47 @@
+/// Addresses at or above this are in the kernel half of the address space on
+/// 64-bit architectures.
+const KERNEL_SPACE_START: u64 = 0xffff_8000_0000_0000;
+
+/// Returns the name of a frame that can't be symbolized because of the kind
+/// of memory it's in, like kernel, JIT compiled or vDSO code, or None if the
+/// frame is in a regular mapping.
+pub fn fallback_name(address: u64, mapping_file: &str) -> Option<&'static str> {
+    if mapping_file.starts_with("[kernel")
+        || (mapping_file.is_empty() && address >= KERNEL_SPACE_START)
+    {
+        return Some("[kernel]");
+    }
+    if is_jit_mapping(mapping_file) {
+        return Some("[jit]");
+    }
+    if mapping_file.starts_with("[vdso]") {
+        return Some("[vdso]");
+    }
+    None
+}
+
+/// JIT compiled code lives in anonymous or memfd mappings, or is described by
+/// a perf map file.
+fn is_jit_mapping(file: &str) -> bool {
+    let name = file.rsplit('/').next().unwrap_or(file);
+    file.starts_with("[anon")
+        || file.starts_with("//anon")
+        || file.starts_with("/memfd:")
+        || (name.starts_with("perf-") && name.ends_with(".map"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fallback_name() {
+        assert_eq!(fallback_name(0x1234, ""), None);
+        assert_eq!(fallback_name(0x1234, "/usr/lib/libc.so.6"), None);
+        assert_eq!(fallback_name(0xffffffff81000000, ""), Some("[kernel]"));
+        assert_eq!(fallback_name(0x10, "[kernel.kallsyms]"), Some("[kernel]"));
+        assert_eq!(fallback_name(0x10, "//anon"), Some("[jit]"));
+        assert_eq!(fallback_name(0x10, "/tmp/perf-42.map"), Some("[jit]"));
+        assert_eq!(fallback_name(0x10, "[vdso]"), Some("[vdso]"));
+    }
+}
@@ -0,0 +1,103 @@
+use crate::profile::LocationLine;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::sync::LazyLock;
+
+static TRUNCATED_FRAMES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_symbolizer_truncated_frames_total",
+        "Total number of inlined frames dropped by the symbolizer, by the limit that was hit.",
+        &["limit"]
+    )
+    .unwrap()
+});
+
+/// FrameLimits bounds the number of frames the symbolizer produces when
+/// expanding inlined functions. Zero disables a limit.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameLimits {
+    /// Maximum number of inlined frames per location, on top of the frame of
+    /// the function the address belongs to.
+    pub max_inline_depth: usize,
+    /// Maximum number of frames per symbolization request. Once reached, the
+    /// remaining locations are not expanded anymore.
+    pub max_frames: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            max_inline_depth: 32,
+            max_frames: 0,
+        }
+    }
+}
+
+impl FrameLimits {
+    /// Truncates the lines of a location to the limits, given the number of
+    /// frames already produced for the request. Lines are ordered from the
+    /// innermost inlined function outwards, so the innermost frames are
+    /// dropped and the stack keeps its outer shape.
+    pub fn truncate(&self, lines: &mut Vec<LocationLine>, produced: usize) {
+        if self.max_inline_depth > 0 && lines.len() > self.max_inline_depth + 1 {
+            let dropped = lines.len() - (self.max_inline_depth + 1);
+            lines.drain(..dropped);
+            TRUNCATED_FRAMES
+                .with_label_values(&["inline_depth"])
+                .inc_by(dropped as u64);
+        }
+
+        if self.max_frames > 0 && produced + lines.len() > self.max_frames {
+            let keep = self.max_frames.saturating_sub(produced).max(1);
+            if lines.len() > keep {
+                let dropped = lines.len() - keep;
+                lines.drain(..dropped);
+                TRUNCATED_FRAMES
+                    .with_label_values(&["total_frames"])
+                    .inc_by(dropped as u64);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(n: i64) -> Vec<LocationLine> {
+        (0..n)
+            .map(|line| LocationLine {
+                line,
+                function: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_truncate() {
+        let limits = FrameLimits {
+            max_inline_depth: 2,
+            max_frames: 5,
+        };
+
+        let mut l = lines(5);
+        limits.truncate(&mut l, 0);
+        assert_eq!(l.iter().map(|l| l.line).collect::<Vec<_>>(), vec![2, 3, 4]);
+
+        let mut l = lines(3);
+        limits.truncate(&mut l, 4);
+        assert_eq!(l.iter().map(|l| l.line).collect::<Vec<_>>(), vec![2]);
+
+        // The outermost frame is always kept.
+        let mut l = lines(2);
+        limits.truncate(&mut l, 10);
+        assert_eq!(l.len(), 1);
+
+        let mut l = lines(50);
+        FrameLimits {
+            max_inline_depth: 0,
+            max_frames: 0,
+        }
+        .truncate(&mut l, 100);
+        assert_eq!(l.len(), 50);
+    }
+}
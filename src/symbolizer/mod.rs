@@ -1,10 +1,18 @@
 mod cache;
+pub mod fallback;
+mod limits;
 pub mod liner;
-pub mod normalize;
+mod poison;
+mod queue;
 
 use self::debuginfopb::Debuginfo;
 use crate::debuginfo_store::DebuginfoFetcher;
-use crate::symbols::{elfutils, Demangler};
+use crate::memory::MemoryUsage;
+use crate::metapb::Function;
+use crate::profile::LocationLine;
+use crate::storage;
+use crate::symbols::{elfutils, normalize, Demangler, ElfDebugInfo, SymbolTable};
+use crate::webhooks::{Event, EventKind, Webhooks};
 use crate::{debuginfo_store::MetadataStore, profile::Location};
 use crate::{
     debuginfopb::{self, DebuginfoQuality, DebuginfoType},
@@ -12,10 +20,15 @@ use crate::{
 };
 use anyhow::{bail, Context};
 pub use cache::SymbolizerCache;
+pub use limits::FrameLimits;
 use liner::Liner;
 use normalize::NormalizedAddress;
+pub use poison::SymbolizationBudget;
+pub use queue::{pending_addresses, SymbolizationQueue};
 use std::io::Write;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use tonic::Status;
 
 #[derive(Debug)]
@@ -24,75 +37,237 @@ pub struct Symbolizer {
     cache: SymbolizerCache,
     metadata: MetadataStore,
     fetcher: DebuginfoFetcher,
+    limits: FrameLimits,
+    budget: SymbolizationBudget,
     temp_dir: PathBuf,
+    /// Notified when debuginfo turns out invalid or keeps failing.
+    webhooks: Arc<Webhooks>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SymbolizationRequestMappingAddrs {
     /// This slice is used to store the symbolization result directly.
     pub locations: Vec<Location>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SymbolizationRequest {
     pub build_id: String,
     pub mappings: Vec<SymbolizationRequestMappingAddrs>,
 }
 
-#[derive(Debug)]
-pub struct ElfDebugInfo<'data> {
-    pub(crate) target_path: PathBuf,
-    pub(crate) e: object::File<'data>,
-    pub(crate) quality: Option<DebuginfoQuality>,
+impl MemoryUsage for Symbolizer {
+    fn memory_usage(&self) -> u64 {
+        self.cache.memory_usage()
+    }
 }
 
 impl Symbolizer {
-    pub fn new(metadata: MetadataStore, fetcher: DebuginfoFetcher) -> Self {
+    pub fn new(
+        metadata: MetadataStore,
+        fetcher: DebuginfoFetcher,
+        limits: FrameLimits,
+        demangler: Demangler,
+    ) -> Self {
         Self {
-            demangler: Demangler::new(false),
+            demangler,
             cache: SymbolizerCache::default(),
             metadata,
             fetcher,
+            limits,
+            budget: SymbolizationBudget::default(),
             temp_dir: PathBuf::from("/tmp"),
+            webhooks: Arc::default(),
         }
     }
 
-    pub async fn symbolize(&self, request: &mut SymbolizationRequest) -> anyhow::Result<()> {
-        log::info!("Symbolizing request for build_id: {}", request.build_id);
+    /// Notifies `webhooks` of debuginfo that fails validation or
+    /// symbolization.
+    pub fn with_webhooks(mut self, webhooks: Arc<Webhooks>) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    /// Bounds the time spent symbolizing a build ID and poisons build IDs
+    /// that fail repeatedly with `budget`.
+    pub fn with_budget(mut self, budget: SymbolizationBudget) -> Self {
+        self.budget = budget;
+        self
+    }
 
-        let build_id = &request.build_id;
+    /// Resolves the locations of the request in place. Locations that can't
+    /// be resolved are left without lines, and are named by the query path.
+    async fn resolve(self: &Arc<Self>, request: &mut SymbolizationRequest) -> anyhow::Result<()> {
+        let build_id = request.build_id.clone();
+        if let Some(table) = self.symbol_table(&build_id).await {
+            return self.resolve_symbols(request, &table, &mut vec![]);
+        }
 
-        let mut dbginfo_md = {
-            self.metadata
-                .fetch(build_id, &DebuginfoType::DebuginfoUnspecified)
-                .ok_or_else(|| {
-                    Status::not_found(format!("Debuginfo for build_id {} not found", build_id))
-                })?
-                .clone()
-        };
+        let dbginfo_md = self
+            .metadata
+            .fetch(&build_id, &DebuginfoType::DebuginfoUnspecified)
+            .ok_or_else(|| {
+                Status::not_found(format!("Debuginfo for build_id {} not found", build_id))
+            })?;
 
         if let Some(q) = &dbginfo_md.quality {
             Self::check_quality(q)?;
         }
         let _ = Self::validate_source(&dbginfo_md);
 
+        // Failures to fetch or parse the debuginfo count toward the build
+        // ID's budget just like failures to resolve its locations.
+        let res = match self.fetcher.fetch_raw_elf(&dbginfo_md).await {
+            Ok(raw_data) => self
+                .resolve_debuginfo(request, &dbginfo_md, raw_data)
+                .await
+                .map(|_| ()),
+            Err(e) if e.downcast_ref::<storage::CorruptObjectError>().is_some() => {
+                log::error!(
+                    "Debuginfo of build_id {} is corrupted, requesting a new upload: {}",
+                    build_id,
+                    e
+                );
+                let quality = DebuginfoQuality {
+                    corrupted: true,
+                    ..dbginfo_md.quality.unwrap_or_default()
+                };
+                self.update_quality(&build_id, quality)?;
+                self.webhooks.notify(Event {
+                    reason: format!("debuginfo is corrupted: {}", e),
+                    ..Event::new(EventKind::ValidationFailed, &build_id)
+                });
+                Err(e)
+            }
+            Err(e) => Err(e),
+        };
+        match &res {
+            Ok(()) => self.budget.record_success(&build_id),
+            Err(e) if self.budget.record_failure(&build_id) => {
+                log::warn!(
+                    "Symbolizing build_id {} failed repeatedly, it won't be symbolized anymore",
+                    build_id
+                );
+                self.webhooks.notify(Event {
+                    reason: e.to_string(),
+                    ..Event::new(EventKind::SymbolizationFailed, &build_id)
+                });
+                // The quality may have been updated while parsing the
+                // debuginfo.
+                let quality = self
+                    .metadata
+                    .fetch(&build_id, &DebuginfoType::DebuginfoUnspecified)
+                    .and_then(|d| d.quality)
+                    .unwrap_or_default();
+                let quality = DebuginfoQuality {
+                    symbolization_failed: true,
+                    ..quality
+                };
+                self.update_quality(&build_id, quality)?;
+            }
+            Err(_) => {}
+        }
+        res
+    }
+
+    /// Resolves the locations of the request with the stored debuginfo, like
+    /// `resolve`, but without accounting failures to the build ID. Returns why each unresolved address failed,
+    /// so that operators can find out why a frame is unknown.
+    pub async fn dry_run(
+        self: &Arc<Self>,
+        request: &mut SymbolizationRequest,
+    ) -> anyhow::Result<Vec<(u64, String)>> {
+        let build_id = request.build_id.clone();
+        if let Some(table) = self.symbol_table(&build_id).await {
+            let mut failures = vec![];
+            self.resolve_symbols(request, &table, &mut failures)?;
+            return Ok(failures);
+        }
+        let dbginfo_md = self
+            .metadata
+            .fetch(&build_id, &DebuginfoType::DebuginfoUnspecified)
+            .with_context(|| format!("Debuginfo for build_id {} not found", build_id))?;
+        if let Some(q) = &dbginfo_md.quality {
+            Self::check_quality(q)?;
+        }
+        Self::validate_source(&dbginfo_md)?;
+
         let raw_data = self.fetcher.fetch_raw_elf(&dbginfo_md).await?;
-        let elf_debug_info = self.get_debug_info(&request.build_id, &mut dbginfo_md, &raw_data)?;
+        self.resolve_debuginfo(request, &dbginfo_md, raw_data).await
+    }
 
+    /// Parses the debuginfo and resolves the locations of the request with
+    /// it on the blocking pool, so that pathological DWARF doesn't stall the
+    /// runtime. Fails once the budget's timeout is exceeded, and the blocking
+    /// work stops at the next location. Returns the addresses that couldn't
+    /// be resolved and why.
+    async fn resolve_debuginfo(
+        self: &Arc<Self>,
+        request: &mut SymbolizationRequest,
+        dbginfo_md: &Debuginfo,
+        raw_data: Vec<u8>,
+    ) -> anyhow::Result<Vec<(u64, String)>> {
+        let symbolizer = Arc::clone(self);
+        let mut resolved = request.clone();
+        let mut dbginfo_md = dbginfo_md.clone();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&cancelled);
+        let task = tokio::task::spawn_blocking(move || {
+            let build_id = resolved.build_id.clone();
+            let elf_debug_info =
+                symbolizer.get_debug_info(&build_id, &mut dbginfo_md, &raw_data)?;
+            let mut failures = vec![];
+            symbolizer.resolve_locations(&mut resolved, &elf_debug_info, &stop, &mut failures)?;
+            anyhow::Ok((resolved, failures))
+        });
+        let res = if self.budget.timeout.is_zero() {
+            task.await
+        } else {
+            match tokio::time::timeout(self.budget.timeout, task).await {
+                Ok(res) => res,
+                Err(_) => {
+                    cancelled.store(true, Ordering::Relaxed);
+                    self.budget.record_timeout();
+                    bail!(
+                        "symbolizing build_id {} took longer than {}",
+                        request.build_id,
+                        humantime::format_duration(self.budget.timeout)
+                    );
+                }
+            }
+        };
+        let (resolved, failures) = res.context("symbolization task failed")??;
+        *request = resolved;
+        Ok(failures)
+    }
+
+    /// Resolves the locations of the request with the debuginfo. Fails once
+    /// `cancelled` is set, which is checked between locations. The addresses
+    /// that couldn't be resolved are added to `failures`.
+    fn resolve_locations(
+        &self,
+        request: &mut SymbolizationRequest,
+        elf_debug_info: &ElfDebugInfo,
+        cancelled: &AtomicBool,
+        failures: &mut Vec<(u64, String)>,
+    ) -> anyhow::Result<()> {
         let mut l = Liner::new(
             &request.build_id,
-            &elf_debug_info,
+            elf_debug_info,
             &self.cache,
             &self.demangler,
         );
-
         let ei = ExecutableInfo::try_from(&elf_debug_info.e)?;
+        let mut produced = 0;
 
         for mapping in request.mappings.iter_mut() {
             for location in mapping.locations.iter_mut() {
+                if cancelled.load(Ordering::Relaxed) {
+                    bail!("symbolizing build_id {} was cancelled", request.build_id);
+                }
                 let mapping = match &location.mapping {
                     Some(mapping) => mapping,
-                    None => bail!("Mapping not found"),
+                    None => continue,
                 };
                 let addr = NormalizedAddress::try_new(
                     location.address,
@@ -104,10 +279,90 @@ impl Symbolizer {
                         file: String::new(),
                     },
                 )?;
-                location.lines = l.pc_to_lines(addr)?;
+                match l.pc_to_lines(addr) {
+                    Ok(mut lines) => {
+                        self.limits.truncate(&mut lines, produced);
+                        produced += lines.len();
+                        location.lines = lines;
+                    }
+                    Err(e) => {
+                        log::debug!(
+                            "Failed to resolve address {:#x} of build_id {}: {}",
+                            location.address,
+                            request.build_id,
+                            e
+                        );
+                        failures.push((location.address, e.to_string()));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the symbol table uploaded for the build ID, if any. Tables
+    /// that can't be read are ignored, so the debuginfo is used instead.
+    async fn symbol_table(&self, build_id: &str) -> Option<SymbolTable> {
+        let dbginfo = self.metadata.fetch(build_id, &DebuginfoType::Symbols)?;
+        Self::validate_source(&dbginfo).ok()?;
+        let res = async { SymbolTable::parse(&self.fetcher.fetch_raw_elf(&dbginfo).await?) };
+        match res.await {
+            Ok(table) => Some(table),
+            Err(e) => {
+                log::warn!(
+                    "Failed to read the symbol table of build_id {}, using its debuginfo: {:#}",
+                    build_id,
+                    e
+                );
+                None
             }
         }
+    }
 
+    /// Resolves the locations of the request with the symbol table of the
+    /// build ID, which only knows function names. The addresses that
+    /// couldn't be resolved are added to `failures`.
+    fn resolve_symbols(
+        &self,
+        request: &mut SymbolizationRequest,
+        table: &SymbolTable,
+        failures: &mut Vec<(u64, String)>,
+    ) -> anyhow::Result<()> {
+        let ei = table.executable_info();
+        let mut produced = 0;
+        for mapping in request.mappings.iter_mut() {
+            for location in mapping.locations.iter_mut() {
+                let Some(mapping) = &location.mapping else {
+                    continue;
+                };
+                let addr = NormalizedAddress::try_new(
+                    location.address,
+                    &ei,
+                    &Mapping {
+                        start: mapping.start,
+                        end: mapping.limit,
+                        offset: mapping.offset,
+                        file: String::new(),
+                    },
+                )?;
+                let Some(symbol) = table.lookup(addr.0) else {
+                    failures.push((location.address, "no symbol covers the address".into()));
+                    continue;
+                };
+                let mut lines = vec![LocationLine {
+                    line: 0,
+                    function: Some(self.demangler.demangle(&Function {
+                        system_name: symbol.name.clone(),
+                        filename: "?".into(),
+                        ..Default::default()
+                    })),
+                }];
+                self.limits.truncate(&mut lines, produced);
+                produced += lines.len();
+                location.lines = lines;
+            }
+        }
         Ok(())
     }
 
@@ -116,6 +371,14 @@ impl Symbolizer {
             bail!("Not a valid ELF file");
         }
 
+        if q.corrupted {
+            bail!("The stored debuginfo is corrupted, waiting for a new upload");
+        }
+
+        if q.symbolization_failed {
+            bail!("Symbolization failed repeatedly with this debuginfo, skipping it");
+        }
+
         if !(q.has_dwarf || q.has_go_pclntab || q.has_symtab || q.has_dynsym) {
             bail!("Trying to Symbolize but it has none of the quality evprofiler needs. Check debuginfo quality: {:?}", q);
         }
@@ -182,8 +445,14 @@ impl Symbolizer {
                 has_go_pclntab: false,
                 has_symtab: false,
                 has_dynsym: false,
+                symbolization_failed: false,
+                corrupted: false,
             };
             let _ = self.update_quality(build_id, quality);
+            self.webhooks.notify(Event {
+                reason: format!("not an object file: {}", e),
+                ..Event::new(EventKind::ValidationFailed, build_id)
+            });
             Status::internal(format!("Failed to parse object file: {}", e))
         })?;
 
@@ -198,8 +467,14 @@ impl Symbolizer {
                     has_go_pclntab: false,
                     has_symtab: false,
                     has_dynsym: false,
+                    symbolization_failed: false,
+                    corrupted: false,
                 };
                 let _ = self.update_quality(build_id, quality);
+                self.webhooks.notify(Event {
+                    reason: "not a valid ELF file".into(),
+                    ..Event::new(EventKind::ValidationFailed, build_id)
+                });
                 bail!("Not a valid ELF file");
             }
         }
@@ -211,6 +486,8 @@ impl Symbolizer {
                 has_go_pclntab: elfutils::has_go_pcln_tab(&file),
                 has_symtab: elfutils::has_symtab(&file),
                 has_dynsym: elfutils::has_dynsym(&file),
+                symbolization_failed: false,
+                corrupted: false,
             };
 
             // log::warn!(
@@ -265,3 +542,169 @@ impl Symbolizer {
 //        };
 //    }
 //}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debuginfo_store::{DebugInfod, NewUpload, ObjectLayout};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let metadata = MetadataStore::new();
+        let symbolizer = Arc::new(Symbolizer::new(
+            metadata.clone(),
+            DebuginfoFetcher::new(
+                Arc::new(storage::new_memory_bucket()),
+                ObjectLayout::default(),
+                DebugInfod::disabled(),
+            ),
+            FrameLimits::default(),
+            Demangler::new(false),
+        ));
+        let request = || SymbolizationRequest {
+            build_id: "abcd".into(),
+            mappings: vec![SymbolizationRequestMappingAddrs {
+                locations: vec![Location {
+                    address: 0x1234,
+                    ..Default::default()
+                }],
+            }],
+        };
+
+        let err = symbolizer.dry_run(&mut request()).await.unwrap_err();
+        assert!(err.to_string().contains("not found"), "{}", err);
+
+        let t = DebuginfoType::DebuginfoUnspecified;
+        metadata
+            .mark_as_uploading(
+                "abcd",
+                NewUpload {
+                    id: "upload-1",
+                    hash: "hash",
+                    size: 3,
+                    started_at: chrono::Utc::now(),
+                },
+                &t,
+                0,
+            )
+            .unwrap();
+        let err = symbolizer.dry_run(&mut request()).await.unwrap_err();
+        assert!(err.to_string().contains("not uploaded yet"), "{}", err);
+        assert_eq!(metadata.fetch("abcd", &t).unwrap().quality, None);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_failures_poison() {
+        let metadata = MetadataStore::new();
+        let symbolizer = Arc::new(
+            Symbolizer::new(
+                metadata.clone(),
+                DebuginfoFetcher::new(
+                    Arc::new(storage::new_memory_bucket()),
+                    ObjectLayout::default(),
+                    DebugInfod::disabled(),
+                ),
+                FrameLimits::default(),
+                Demangler::new(false),
+            )
+            .with_budget(SymbolizationBudget::new(std::time::Duration::ZERO, 2)),
+        );
+        let t = DebuginfoType::DebuginfoUnspecified;
+        metadata
+            .mark_as_uploading(
+                "abcd",
+                NewUpload {
+                    id: "upload-1",
+                    hash: "hash",
+                    size: 0,
+                    started_at: chrono::Utc::now(),
+                },
+                &t,
+                0,
+            )
+            .unwrap();
+        metadata
+            .mark_as_uploaded("abcd", "upload-1", &t, "", "", chrono::Utc::now())
+            .unwrap();
+        let mut request = SymbolizationRequest {
+            build_id: "abcd".into(),
+            mappings: vec![],
+        };
+
+        // The uploaded object is missing, so fetching it fails.
+        assert!(symbolizer.resolve(&mut request).await.is_err());
+        assert_eq!(metadata.fetch("abcd", &t).unwrap().quality, None);
+        assert!(symbolizer.resolve(&mut request).await.is_err());
+        let quality = metadata.fetch("abcd", &t).unwrap().quality.unwrap();
+        assert!(quality.symbolization_failed);
+    }
+
+    #[tokio::test]
+    async fn test_symbol_table() {
+        let metadata = MetadataStore::new();
+        let bucket = Arc::new(storage::new_memory_bucket());
+        let symbolizer = Arc::new(Symbolizer::new(
+            metadata.clone(),
+            DebuginfoFetcher::new(
+                Arc::clone(&bucket) as _,
+                ObjectLayout::default(),
+                DebugInfod::disabled(),
+            ),
+            FrameLimits::default(),
+            Demangler::new(false),
+        ));
+        let table = "kind executable\nsegment 0 400000 2000\n401000 100 main.main\n";
+        let t = DebuginfoType::Symbols;
+        metadata
+            .mark_as_uploading(
+                "abcd",
+                NewUpload {
+                    id: "upload-1",
+                    hash: "hash",
+                    size: 0,
+                    started_at: chrono::Utc::now(),
+                },
+                &t,
+                0,
+            )
+            .unwrap();
+        metadata
+            .mark_as_uploaded("abcd", "upload-1", &t, "", "", chrono::Utc::now())
+            .unwrap();
+        object_store::ObjectStore::put(
+            bucket.as_ref(),
+            &object_store::path::Path::from("upload-1"),
+            table.as_bytes().to_vec().into(),
+        )
+        .await
+        .unwrap();
+
+        let location = |address| Location {
+            address,
+            mapping: Some(crate::metapb::Mapping {
+                start: 0x400000,
+                limit: 0x402000,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut request = SymbolizationRequest {
+            build_id: "abcd".into(),
+            mappings: vec![SymbolizationRequestMappingAddrs {
+                locations: vec![location(0x401010), location(0x401800)],
+            }],
+        };
+        let failures = symbolizer.dry_run(&mut request).await.unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, 0x401800);
+
+        symbolizer.resolve(&mut request).await.unwrap();
+        let function = request.mappings[0].locations[0].lines[0]
+            .function
+            .as_ref()
+            .unwrap();
+        assert_eq!(function.name, "main.main");
+        assert!(request.mappings[0].locations[1].lines.is_empty());
+    }
+}
@@ -0,0 +1,102 @@
+use moka::sync::Cache;
+use prometheus::{register_int_counter, IntCounter};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+static TIMEOUTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_symbolization_timeouts_total",
+        "Total number of symbolization requests that were aborted because they took longer than the symbolization timeout."
+    )
+    .unwrap()
+});
+
+static POISONED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_symbolization_poisoned_build_ids_total",
+        "Total number of build IDs that are not symbolized anymore because symbolizing them failed repeatedly."
+    )
+    .unwrap()
+});
+
+/// SymbolizationBudget bounds how long symbolizing the locations of a build
+/// ID may take, and counts the consecutive failures of every build ID so that
+/// debuginfo that keeps failing, like pathological DWARF, stops being retried.
+#[derive(Debug)]
+pub struct SymbolizationBudget {
+    /// Maximum time spent resolving the locations of a request. Zero disables
+    /// the timeout.
+    pub timeout: Duration,
+    /// Number of failures in a row after which a build ID is poisoned. Zero
+    /// never poisons build IDs.
+    max_failures: u32,
+    failures: Cache<String, u32>,
+}
+
+impl Default for SymbolizationBudget {
+    fn default() -> Self {
+        Self::new(Duration::ZERO, 0)
+    }
+}
+
+impl SymbolizationBudget {
+    pub fn new(timeout: Duration, max_failures: u32) -> Self {
+        Self {
+            timeout,
+            max_failures,
+            failures: Cache::builder()
+                .max_capacity(10_000)
+                .time_to_idle(Duration::from_secs(24 * 60 * 60))
+                .build(),
+        }
+    }
+
+    pub fn record_timeout(&self) {
+        TIMEOUTS.inc();
+    }
+
+    /// Records a failure to symbolize the build ID. Returns true once it
+    /// failed `max_failures` times in a row and should be poisoned.
+    pub fn record_failure(&self, build_id: &str) -> bool {
+        if self.max_failures == 0 {
+            return false;
+        }
+        let failures = self
+            .failures
+            .entry_by_ref(build_id)
+            .and_upsert_with(|current| current.map_or(1, |e| e.into_value() + 1))
+            .into_value();
+        if failures < self.max_failures {
+            return false;
+        }
+        self.failures.invalidate(build_id);
+        POISONED.inc();
+        true
+    }
+
+    pub fn record_success(&self, build_id: &str) {
+        self.failures.invalidate(build_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_failure() {
+        let budget = SymbolizationBudget::new(Duration::from_secs(1), 3);
+        assert!(!budget.record_failure("a"));
+        assert!(!budget.record_failure("a"));
+        budget.record_success("a");
+        assert!(!budget.record_failure("a"));
+        assert!(!budget.record_failure("b"));
+        assert!(!budget.record_failure("a"));
+        assert!(budget.record_failure("a"));
+
+        let disabled = SymbolizationBudget::default();
+        for _ in 0..10 {
+            assert!(!disabled.record_failure("a"));
+        }
+    }
+}
@@ -0,0 +1,514 @@
+use super::{SymbolizationRequest, SymbolizationRequestMappingAddrs, Symbolizer};
+use crate::debuginfopb::DebuginfoType;
+use crate::kafka::KafkaForwarder;
+use crate::leader::{self, LeaderElection};
+use crate::metapb::Mapping;
+use crate::normalizer::NormalizedWriteRawRequest;
+use crate::pipeline::{self, Stage};
+use crate::profile::{Location, PprofLocations};
+use anyhow::Context;
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static PENDING_ADDRESSES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "evprofiler_symbolization_queue_addresses",
+        "Number of unique addresses waiting to be symbolized."
+    )
+    .unwrap()
+});
+
+static SYMBOLIZED_ADDRESSES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_symbolization_queue_symbolized_total",
+        "Total number of queued addresses that were symbolized."
+    )
+    .unwrap()
+});
+
+static DROPPED_ADDRESSES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "evprofiler_symbolization_queue_dropped_total",
+        "Total number of addresses dropped from the symbolization queue."
+    )
+    .unwrap()
+});
+
+/// Interval at which changed build IDs are written to the queue directory.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Address of an unsymbolized location, with the mapping it was found in,
+/// which is needed to normalize it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PendingAddress {
+    pub address: u64,
+    pub start: u64,
+    pub limit: u64,
+    pub offset: u64,
+}
+
+impl SymbolizationRequest {
+    /// Creates the request symbolizing the addresses of the build ID.
+    pub fn from_pending(build_id: &str, addresses: &[PendingAddress]) -> Self {
+        Self {
+            build_id: build_id.to_string(),
+            mappings: vec![SymbolizationRequestMappingAddrs {
+                locations: addresses
+                    .iter()
+                    .map(|a| Location {
+                        id: String::new(),
+                        address: a.address,
+                        is_folded: false,
+                        mapping: Some(Mapping {
+                            start: a.start,
+                            limit: a.limit,
+                            offset: a.offset,
+                            ..Default::default()
+                        }),
+                        lines: vec![],
+                    })
+                    .collect(),
+            }],
+        }
+    }
+}
+
+/// Returns the addresses of the locations of the request that have a build ID
+/// but no lines, by build ID.
+pub fn pending_addresses(
+    request: &NormalizedWriteRawRequest,
+) -> anyhow::Result<BTreeMap<String, Vec<PendingAddress>>> {
+    let encoded: HashSet<&Vec<u8>> = request
+        .series
+        .iter()
+        .flat_map(|s| s.samples.iter().flatten())
+        .flat_map(|p| p.samples.iter())
+        .flat_map(|s| s.locations.iter())
+        .collect();
+    let mut addresses: BTreeMap<String, Vec<PendingAddress>> = BTreeMap::new();
+    for location in encoded {
+        let location = PprofLocations::decode(location)?;
+        if location.build_id.is_empty() || location.number_of_lines > 0 || location.address == 0 {
+            continue;
+        }
+        addresses
+            .entry(location.build_id)
+            .or_default()
+            .push(PendingAddress {
+                address: location.address,
+                start: location.mapping_memory_start,
+                limit: location.mapping_memory_end,
+                offset: location.mapping_file_offset,
+            });
+    }
+    Ok(addresses)
+}
+
+/// Addresses queued for a build ID.
+#[derive(Debug, Default)]
+struct Queued {
+    /// Seconds since the epoch at which the build ID was first queued.
+    since: u64,
+    addresses: BTreeSet<PendingAddress>,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    addresses: BTreeMap<String, Queued>,
+    /// Number of queued addresses, across build IDs.
+    len: usize,
+    /// Build IDs whose addresses changed since they were last written.
+    dirty: HashSet<String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// SymbolizationQueue keeps the addresses of written profiles that still have
+/// to be symbolized, sorted and deduplicated by build ID, so that an address
+/// seen in many profiles is symbolized once. The addresses of every build ID
+/// are written to `dir`, if set, so that pending work survives restarts.
+/// The queue holds at most `max_addresses` addresses, and the addresses of
+/// build IDs whose debuginfo doesn't show up within `max_age` are dropped.
+#[derive(Debug, Default)]
+pub struct SymbolizationQueue {
+    dir: Option<PathBuf>,
+    pending: Mutex<Pending>,
+    max_addresses: usize,
+    max_age: Duration,
+    /// Addresses are only queued and symbolized on the leader, if set, as
+    /// the other instances never symbolize them.
+    leader: Option<Arc<LeaderElection>>,
+    kafka: Option<Arc<KafkaForwarder>>,
+}
+
+impl SymbolizationQueue {
+    /// Creates the queue, loading the addresses left in `dir`.
+    pub fn new(dir: Option<PathBuf>) -> anyhow::Result<Self> {
+        let mut pending = Pending::default();
+        if let Some(dir) = &dir {
+            std::fs::create_dir_all(dir).with_context(|| {
+                format!("creating symbolization queue directory {}", dir.display())
+            })?;
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().is_none_or(|ext| ext != "addrs") {
+                    continue;
+                }
+                let data = std::fs::read(&path)
+                    .with_context(|| format!("reading symbolization queue {}", path.display()))?;
+                let (build_id, since, addresses): (String, u64, Vec<PendingAddress>) =
+                    bincode::deserialize(&data).with_context(|| {
+                        format!("decoding symbolization queue {}", path.display())
+                    })?;
+                pending.len += addresses.len();
+                pending.addresses.insert(
+                    build_id,
+                    Queued {
+                        since,
+                        addresses: addresses.into_iter().collect(),
+                    },
+                );
+            }
+        }
+        PENDING_ADDRESSES.set(pending.len as i64);
+        Ok(Self {
+            dir,
+            pending: Mutex::new(pending),
+            ..Default::default()
+        })
+    }
+
+    /// Bounds the queue to `max_addresses` addresses, and drops the addresses
+    /// of build IDs still without debuginfo after `max_age`. Zero disables
+    /// either limit.
+    pub fn with_limits(mut self, max_addresses: usize, max_age: Duration) -> Self {
+        self.max_addresses = max_addresses;
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn with_leader_election(mut self, leader: Arc<LeaderElection>) -> Self {
+        self.leader = Some(leader);
+        self
+    }
+
+    /// Publishes the symbolized locations with `kafka`.
+    pub fn with_kafka_forwarder(mut self, kafka: Arc<KafkaForwarder>) -> Self {
+        self.kafka = Some(kafka);
+        self
+    }
+
+    fn path(&self, build_id: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| {
+            // Build IDs come from profiles, so they're hex encoded to be safe
+            // to use as file names.
+            let mut name = build_id.bytes().fold(String::new(), |mut name, b| {
+                let _ = write!(name, "{:02x}", b);
+                name
+            });
+            name.push_str(".addrs");
+            dir.join(name)
+        })
+    }
+
+    /// Queues the addresses of the locations of the request that have a build
+    /// ID but no lines.
+    pub fn push_request(&self, request: &NormalizedWriteRawRequest) -> anyhow::Result<()> {
+        for (build_id, addresses) in pending_addresses(request)? {
+            self.push(&build_id, addresses);
+        }
+        Ok(())
+    }
+
+    /// Queues addresses of the build ID. Addresses already queued are ignored,
+    /// and addresses that don't fit in the queue are dropped. Nothing is
+    /// queued on instances that aren't the leader.
+    pub fn push(&self, build_id: &str, addresses: impl IntoIterator<Item = PendingAddress>) {
+        if !leader::should_run(&self.leader) {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        let Pending {
+            addresses: queues,
+            len,
+            dirty,
+        } = &mut *pending;
+        let queued = queues
+            .entry(build_id.to_string())
+            .or_insert_with(|| Queued {
+                since: now(),
+                addresses: BTreeSet::new(),
+            });
+        let (mut added, mut dropped) = (0, 0);
+        for address in addresses {
+            if self.max_addresses > 0 && *len >= self.max_addresses {
+                if !queued.addresses.contains(&address) {
+                    dropped += 1;
+                }
+            } else if queued.addresses.insert(address) {
+                *len += 1;
+                added += 1;
+            }
+        }
+        DROPPED_ADDRESSES.inc_by(dropped);
+        if added > 0 {
+            dirty.insert(build_id.to_string());
+            PENDING_ADDRESSES.add(added);
+        } else if queued.addresses.is_empty() {
+            queues.remove(build_id);
+        }
+    }
+
+    /// Returns the build IDs with queued addresses.
+    pub fn build_ids(&self) -> Vec<String> {
+        let pending = self.pending.lock().unwrap();
+        pending.addresses.keys().cloned().collect()
+    }
+
+    /// Returns the queued addresses of the build ID, in order.
+    pub fn addresses(&self, build_id: &str) -> Vec<PendingAddress> {
+        let pending = self.pending.lock().unwrap();
+        pending
+            .addresses
+            .get(build_id)
+            .map(|q| q.addresses.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Removes addresses of the build ID once they've been symbolized.
+    pub fn remove(&self, build_id: &str, addresses: &[PendingAddress]) {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(queued) = pending.addresses.get_mut(build_id) else {
+            return;
+        };
+        let before = queued.addresses.len();
+        for address in addresses {
+            queued.addresses.remove(address);
+        }
+        let removed = before - queued.addresses.len();
+        if queued.addresses.is_empty() {
+            pending.addresses.remove(build_id);
+        }
+        if removed > 0 {
+            pending.len -= removed;
+            pending.dirty.insert(build_id.to_string());
+            PENDING_ADDRESSES.sub(removed as i64);
+        }
+    }
+
+    /// Drops the addresses of build IDs queued before `now - max_age`, in
+    /// seconds since the epoch.
+    fn expire(&self, now: u64) {
+        if self.max_age.is_zero() {
+            return;
+        }
+        let deadline = now.saturating_sub(self.max_age.as_secs());
+        let mut pending = self.pending.lock().unwrap();
+        let expired: Vec<String> = pending
+            .addresses
+            .iter()
+            .filter(|(_, q)| q.since < deadline)
+            .map(|(build_id, _)| build_id.clone())
+            .collect();
+        for build_id in expired {
+            let queued = pending.addresses.remove(&build_id).unwrap_or_default();
+            log::debug!(
+                "Dropping {} queued addresses of build_id {} without debuginfo",
+                queued.addresses.len(),
+                build_id
+            );
+            pending.len -= queued.addresses.len();
+            PENDING_ADDRESSES.sub(queued.addresses.len() as i64);
+            DROPPED_ADDRESSES.inc_by(queued.addresses.len() as u64);
+            pending.dirty.insert(build_id);
+        }
+    }
+
+    /// Writes the addresses of the build IDs that changed to the queue
+    /// directory. Build IDs without addresses left have their file removed.
+    /// This blocks on the file system.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        if self.dir.is_none() {
+            return Ok(());
+        }
+        let changed: Vec<(String, u64, Vec<PendingAddress>)> = {
+            let mut pending = self.pending.lock().unwrap();
+            let dirty = std::mem::take(&mut pending.dirty);
+            dirty
+                .into_iter()
+                .map(|build_id| match pending.addresses.get(&build_id) {
+                    Some(q) => (build_id, q.since, q.addresses.iter().copied().collect()),
+                    None => (build_id, 0, vec![]),
+                })
+                .collect()
+        };
+        for (build_id, since, addresses) in changed {
+            let Some(path) = self.path(&build_id) else {
+                continue;
+            };
+            if addresses.is_empty() {
+                match std::fs::remove_file(&path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(e)
+                            .context(format!("removing symbolization queue {}", path.display()))
+                    }
+                    _ => continue,
+                }
+            }
+            // Written to a temporary file first, so that a crash doesn't
+            // leave a truncated queue behind.
+            let tmp = path.with_extension("addrs.tmp");
+            std::fs::write(&tmp, bincode::serialize(&(&build_id, since, &addresses))?)
+                .with_context(|| format!("writing symbolization queue {}", tmp.display()))?;
+            std::fs::rename(&tmp, &path)
+                .with_context(|| format!("writing symbolization queue {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Symbolizes the queued addresses of the build IDs whose debuginfo is
+    /// available, which fills the symbolizer's cache. Addresses of build IDs
+    /// without debuginfo stay queued until it's uploaded.
+    pub async fn symbolize(&self, symbolizer: &Arc<Symbolizer>) {
+        for build_id in self.build_ids() {
+            if symbolizer
+                .metadata
+                .fetch(&build_id, &DebuginfoType::DebuginfoUnspecified)
+                .is_none()
+            {
+                continue;
+            }
+            let addresses = self.addresses(&build_id);
+            let mut request = SymbolizationRequest::from_pending(&build_id, &addresses);
+            // Failures are accounted to the build ID's symbolization budget,
+            // which stops retrying it, so the addresses are done either way.
+            let started = std::time::Instant::now();
+            let res = symbolizer.resolve(&mut request).await;
+            pipeline::observe(Stage::Symbolize, started.elapsed(), None);
+            if let Err(e) = res {
+                log::warn!(
+                    "Failed to symbolize queued addresses of build_id {}: {}",
+                    build_id,
+                    e
+                );
+            } else {
+                SYMBOLIZED_ADDRESSES.inc_by(addresses.len() as u64);
+                if let Some(kafka) = &self.kafka {
+                    let locations = &request.mappings[0].locations;
+                    if let Err(e) = kafka.publish_symbolized(&build_id, locations).await {
+                        log::warn!(
+                            "Failed to publish symbolized addresses of build_id {}: {}",
+                            build_id,
+                            e
+                        );
+                    }
+                }
+            }
+            self.remove(&build_id, &addresses);
+        }
+    }
+
+    /// Symbolizes the queued addresses every `interval` and writes changes
+    /// to the queue directory every few seconds.
+    pub async fn run(self: Arc<Self>, symbolizer: Arc<Symbolizer>, interval: Duration) {
+        let mut symbolize = tokio::time::interval(interval);
+        let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = symbolize.tick() => {
+                    self.expire(now());
+                    if leader::should_run(&self.leader) {
+                        self.symbolize(&symbolizer).await
+                    }
+                }
+                _ = flush.tick() => {}
+            }
+            let queue = Arc::clone(&self);
+            match tokio::task::spawn_blocking(move || queue.flush()).await {
+                Ok(Err(e)) => log::error!("Failed to write the symbolization queue: {}", e),
+                Err(e) => log::error!("Failed to write the symbolization queue: {}", e),
+                Ok(Ok(())) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(address: u64) -> PendingAddress {
+        PendingAddress {
+            address,
+            start: 0x1000,
+            limit: 0x2000,
+            offset: 0,
+        }
+    }
+
+    #[test]
+    fn test_symbolization_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = SymbolizationQueue::new(Some(dir.path().to_path_buf())).unwrap();
+        queue.push("abcd", [address(3), address(1)]);
+        queue.push("abcd", [address(1), address(2)]);
+        queue.push("../ef", [address(4)]);
+        assert_eq!(
+            queue.addresses("abcd"),
+            vec![address(1), address(2), address(3)]
+        );
+        queue.flush().unwrap();
+        assert!(dir.path().join("61626364.addrs").exists());
+
+        let restarted = SymbolizationQueue::new(Some(dir.path().to_path_buf())).unwrap();
+        assert_eq!(restarted.build_ids(), vec!["../ef", "abcd"]);
+        assert_eq!(restarted.addresses("abcd").len(), 3);
+
+        restarted.remove("../ef", &[address(4)]);
+        restarted.remove("abcd", &[address(1)]);
+        restarted.flush().unwrap();
+        let restarted = SymbolizationQueue::new(Some(dir.path().to_path_buf())).unwrap();
+        assert_eq!(restarted.build_ids(), vec!["abcd"]);
+        assert_eq!(restarted.addresses("abcd"), vec![address(2), address(3)]);
+    }
+
+    #[test]
+    fn test_symbolization_queue_limits() {
+        let queue = SymbolizationQueue::new(None)
+            .unwrap()
+            .with_limits(3, Duration::from_secs(60));
+        queue.push("abcd", [address(1), address(2)]);
+        queue.push("ef", [address(1), address(2)]);
+        assert_eq!(queue.addresses("abcd").len(), 2);
+        assert_eq!(queue.addresses("ef"), vec![address(1)]);
+        queue.push("gh", [address(1)]);
+        assert_eq!(queue.build_ids(), vec!["abcd", "ef"]);
+
+        // Removed addresses make room again.
+        queue.remove("abcd", &[address(1)]);
+        queue.push("gh", [address(1)]);
+        assert_eq!(queue.build_ids(), vec!["abcd", "ef", "gh"]);
+
+        queue
+            .pending
+            .lock()
+            .unwrap()
+            .addresses
+            .get_mut("ef")
+            .unwrap()
+            .since = 0;
+        queue.expire(now());
+        assert_eq!(queue.build_ids(), vec!["abcd", "gh"]);
+        assert_eq!(queue.pending.lock().unwrap().len, 2);
+    }
+}
@@ -1,5 +1,5 @@
-use crate::symbolizer::normalize::NormalizedAddress;
-use crate::{metapb, profile, symbolizer::ElfDebugInfo, symbols::Demangler};
+use crate::symbols::normalize::NormalizedAddress;
+use crate::{metapb, profile, symbols::Demangler, symbols::ElfDebugInfo};
 use addr2line::LookupResult;
 use object::{Object, ObjectSection};
 use std::borrow;
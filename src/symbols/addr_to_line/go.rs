@@ -1,4 +1,4 @@
-use crate::symbolizer::{normalize::NormalizedAddress, ElfDebugInfo};
+use crate::symbols::{normalize::NormalizedAddress, ElfDebugInfo};
 use object::{Object, ObjectSection, ObjectSegment, ObjectSymbol};
 use tonic::Status;
 
@@ -113,7 +113,6 @@ impl<'data> GoLiner<'data> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::symbolizer::normalize::NormalizedAddress;
     use std::path::PathBuf;
 
     #[test]
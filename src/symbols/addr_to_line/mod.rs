@@ -2,8 +2,7 @@ pub mod dwarf;
 mod symbol;
 // pub mod go;
 
-use super::Demangler;
-use crate::symbolizer::ElfDebugInfo;
+use super::{Demangler, ElfDebugInfo};
 pub(crate) use dwarf::DwarfLiner;
 pub(crate) use symbol::SymbolLiner;
 
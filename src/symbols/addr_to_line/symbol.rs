@@ -1,8 +1,7 @@
 use crate::{
     metapb::Function,
     profile,
-    symbolizer::{normalize::NormalizedAddress, ElfDebugInfo},
-    symbols::Demangler,
+    symbols::{normalize::NormalizedAddress, Demangler, ElfDebugInfo},
 };
 use anyhow::bail;
 use object::{Object, ObjectSection, ObjectSymbol, RelocationTarget};
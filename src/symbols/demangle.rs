@@ -1,7 +1,34 @@
 use crate::metapb;
 
 use self::metapb::Function;
-/// Demangler demangles GCC/LLVM C++ and Rust symbol names.
+
+/// A language whose symbol names the Demangler can demangle.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Language {
+    Rust,
+    Cpp,
+    /// Requires the `swift` feature, Swift names are passed through otherwise.
+    Swift,
+}
+
+/// DemangleConfig selects the languages that are demangled. With `raw`, no
+/// demangling is done at all and functions are named by their system name.
+#[derive(Debug, Clone)]
+pub struct DemangleConfig {
+    pub languages: Vec<Language>,
+    pub raw: bool,
+}
+
+impl Default for DemangleConfig {
+    fn default() -> Self {
+        Self {
+            languages: vec![Language::Rust, Language::Cpp, Language::Swift],
+            raw: false,
+        }
+    }
+}
+
+/// Demangler demangles GCC/LLVM C++, Rust and Swift symbol names.
 ///
 /// Demangling is the inverse process of mangling (encoding of each unique
 /// function and parameter list combination into a unique name for the linker).
@@ -11,6 +38,7 @@ use self::metapb::Function;
 #[derive(Debug)]
 pub struct Demangler {
     force: bool,
+    config: DemangleConfig,
 }
 
 impl Demangler {
@@ -18,12 +46,22 @@ impl Demangler {
     ///
     /// If force is set, overwrite any names that appear already demangled.
     pub fn new(force: bool) -> Self {
-        Self { force }
+        Self::with_config(force, DemangleConfig::default())
+    }
+
+    /// Creates a new Demangler that only demangles the configured languages.
+    pub fn with_config(force: bool, config: DemangleConfig) -> Self {
+        Self { force, config }
     }
 
     pub fn demangle(&self, function: &Function) -> Function {
         let mut new_function = function.clone();
 
+        if self.config.raw {
+            new_function.name = function.system_name.clone();
+            return new_function;
+        }
+
         if self.force && !function.name.is_empty() && !function.system_name.is_empty() {
             new_function.name = function.system_name.clone();
         }
@@ -32,7 +70,7 @@ impl Demangler {
             return new_function; // Already Demangled
         }
 
-        let demangled = Self::filter(&function.system_name);
+        let demangled = self.filter(&function.system_name);
 
         if demangled.ne(&function.system_name) {
             new_function.name = demangled;
@@ -43,22 +81,47 @@ impl Demangler {
         new_function
     }
 
-    // Filter demangles a C++ or Rust symbol name,
-    // returning the human-readable C++ or Rust name.
+    fn enabled(&self, language: Language) -> bool {
+        self.config.languages.contains(&language)
+    }
+
+    // Filter demangles a C++, Rust or Swift symbol name of an enabled language,
+    // returning the human-readable name.
     // If any error occurs during demangling, the input string is returned.
-    fn filter(sys_name: &str) -> String {
+    fn filter(&self, sys_name: &str) -> String {
         //Try Demangling Rust
-        if let Ok(demangled) = rustc_demangle::try_demangle(sys_name) {
-            return format!("{:#}", demangled);
+        if self.enabled(Language::Rust) {
+            if let Ok(demangled) = rustc_demangle::try_demangle(sys_name) {
+                return format!("{:#}", demangled);
+            }
+        }
+
+        //Try Demangling Swift
+        #[cfg(feature = "swift")]
+        if self.enabled(Language::Swift) {
+            if let Some(demangled) = Self::demangle_swift(sys_name) {
+                return demangled;
+            }
         }
 
         //Try Demangling C/C++
-        if let Ok(symbol) = cpp_demangle::Symbol::new(sys_name) {
-            return symbol.to_string();
+        if self.enabled(Language::Cpp) {
+            if let Ok(symbol) = cpp_demangle::Symbol::new(sys_name) {
+                return symbol.to_string();
+            }
         }
 
         sys_name.to_string()
     }
+
+    #[cfg(feature = "swift")]
+    fn demangle_swift(sys_name: &str) -> Option<String> {
+        use symbolic_common::{Language, Name, NameMangling};
+        use symbolic_demangle::{Demangle, DemangleOptions};
+
+        Name::new(sys_name, NameMangling::Mangled, Language::Swift)
+            .demangle(DemangleOptions::complete())
+    }
 }
 
 #[cfg(test)]
@@ -117,4 +180,30 @@ mod tests {
         let demangled = demangler.demangle(&function);
         assert_eq!("collections::slice::<impl [T]>::as_mut_ptr", demangled.name);
     }
+
+    #[test]
+    fn test_config() {
+        let function = Function {
+            system_name: "_ZNSaIcEC1ERKS_".to_string(),
+            ..Default::default()
+        };
+
+        let demangler = Demangler::with_config(
+            false,
+            DemangleConfig {
+                languages: vec![Language::Rust],
+                raw: false,
+            },
+        );
+        assert_eq!("_ZNSaIcEC1ERKS_", demangler.demangle(&function).name);
+
+        let demangler = Demangler::with_config(
+            false,
+            DemangleConfig {
+                raw: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!("_ZNSaIcEC1ERKS_", demangler.demangle(&function).name);
+    }
 }
@@ -1,5 +1,19 @@
 pub mod addr_to_line;
 mod demangle;
 pub mod elfutils;
+pub mod normalize;
+mod symtab;
 
-pub use demangle::Demangler;
+pub use demangle::{DemangleConfig, Demangler, Language};
+pub use symtab::SymbolTable;
+
+use crate::debuginfopb::DebuginfoQuality;
+use std::path::PathBuf;
+
+/// An object file being symbolized.
+#[derive(Debug)]
+pub struct ElfDebugInfo<'data> {
+    pub(crate) target_path: PathBuf,
+    pub(crate) e: object::File<'data>,
+    pub(crate) quality: Option<DebuginfoQuality>,
+}
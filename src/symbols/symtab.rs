@@ -0,0 +1,174 @@
+use crate::backfill;
+use crate::profile::executableinfo::{ExecutableInfo, ProgHeader};
+use anyhow::{bail, Context};
+use object::ObjectKind;
+use std::fmt::Write;
+
+/// A function of a symbol table, covering `size` bytes from `address`. Zero
+/// sized symbols cover the addresses up to the next symbol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub address: u64,
+    pub size: u64,
+    pub name: String,
+}
+
+/// SymbolTable maps the addresses of a binary to function names. Agents
+/// extract it from large binaries and upload it with the
+/// `DEBUGINFO_TYPE_SYMBOLS` type instead of their debuginfo, along with the
+/// loadable segments addresses are normalized with. Tables are uploaded in a
+/// line-based text format, optionally gzipped, with hexadecimal numbers:
+///
+/// ```text
+/// kind executable
+/// segment <offset> <vaddr> <memsz>
+/// <address> <size> <name>
+/// ```
+///
+/// `kind` is `executable`, `dynamic` or `relocatable`, and symbols may come
+/// in any order. Tables are stored sorted and gzipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolTable {
+    pub kind: ObjectKind,
+    /// Loadable segments as `(offset, vaddr, memsz)`.
+    pub segments: Vec<(u64, u64, u64)>,
+    /// Symbols sorted by address.
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new(kind: ObjectKind, segments: Vec<(u64, u64, u64)>, mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by(|a, b| a.address.cmp(&b.address).then_with(|| a.name.cmp(&b.name)));
+        Self {
+            kind,
+            segments,
+            symbols,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let data = backfill::decompress(data)?;
+        let text = std::str::from_utf8(&data).context("symbol table is not UTF-8")?;
+        let hex = |s: &str| {
+            u64::from_str_radix(s.trim_start_matches("0x"), 16)
+                .with_context(|| format!("invalid number {:?}", s))
+        };
+        let mut kind = None;
+        let mut segments = vec![];
+        let mut symbols = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let res = (|| {
+                let mut fields = line.splitn(3, ' ');
+                match (fields.next(), fields.next(), fields.next()) {
+                    (Some(""), None, None) => {}
+                    (Some("kind"), Some(k), None) => {
+                        kind = Some(match k {
+                            "executable" => ObjectKind::Executable,
+                            "dynamic" => ObjectKind::Dynamic,
+                            "relocatable" => ObjectKind::Relocatable,
+                            _ => bail!("unknown kind {:?}", k),
+                        })
+                    }
+                    (Some("segment"), Some(offset), Some(rest)) => {
+                        let Some((vaddr, memsz)) = rest.split_once(' ') else {
+                            bail!("segment needs an offset, vaddr and memsz");
+                        };
+                        segments.push((hex(offset)?, hex(vaddr)?, hex(memsz)?));
+                    }
+                    (Some(address), Some(size), Some(name)) if !name.is_empty() => {
+                        symbols.push(Symbol {
+                            address: hex(address)?,
+                            size: hex(size)?,
+                            name: name.to_string(),
+                        })
+                    }
+                    _ => bail!("expected a symbol, segment or kind"),
+                }
+                anyhow::Ok(())
+            })();
+            res.with_context(|| format!("line {} of symbol table", i + 1))?;
+        }
+        let Some(kind) = kind else {
+            bail!("symbol table has no kind");
+        };
+        Ok(Self::new(kind, segments, symbols))
+    }
+
+    /// Encodes the table in its text format, gzipped.
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut text = String::new();
+        let kind = match self.kind {
+            ObjectKind::Executable => "executable",
+            ObjectKind::Dynamic => "dynamic",
+            ObjectKind::Relocatable => "relocatable",
+            kind => bail!("unsupported kind {:?}", kind),
+        };
+        let _ = writeln!(text, "kind {}", kind);
+        for (offset, vaddr, memsz) in self.segments.iter() {
+            let _ = writeln!(text, "segment {:x} {:x} {:x}", offset, vaddr, memsz);
+        }
+        for symbol in self.symbols.iter() {
+            let _ = writeln!(
+                text,
+                "{:x} {:x} {}",
+                symbol.address, symbol.size, symbol.name
+            );
+        }
+        backfill::compress(text.as_bytes())
+    }
+
+    /// Returns the symbol of the normalized address, if any.
+    pub fn lookup(&self, address: u64) -> Option<&Symbol> {
+        let i = self.symbols.partition_point(|s| s.address <= address);
+        let symbol = self.symbols[..i].last()?;
+        (symbol.size == 0 || address < symbol.address + symbol.size).then_some(symbol)
+    }
+
+    /// Returns the executable info addresses of the binary are normalized
+    /// with.
+    pub fn executable_info(&self) -> ExecutableInfo {
+        ExecutableInfo::new(
+            self.kind,
+            self.segments
+                .iter()
+                .map(|&(offset, vaddr, memsz)| ProgHeader {
+                    offset,
+                    vaddr,
+                    memsz,
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = "kind executable\n\
+                    segment 0 400000 1000\n\
+                    \n\
+                    401200 10 main.work\n\
+                    0x401000 100 main.main\n\
+                    401300 0 runtime.goexit\n";
+        let table = SymbolTable::parse(text.as_bytes()).unwrap();
+        assert_eq!(table.kind, ObjectKind::Executable);
+        assert_eq!(table.segments, [(0, 0x400000, 0x1000)]);
+        let name = |address| table.lookup(address).map(|s| s.name.as_str());
+        assert_eq!(name(0x400fff), None);
+        assert_eq!(name(0x401000), Some("main.main"));
+        assert_eq!(name(0x40120f), Some("main.work"));
+        assert_eq!(name(0x401210), None);
+        assert_eq!(name(0x409999), Some("runtime.goexit"));
+
+        let encoded = table.encode().unwrap();
+        assert!(backfill::is_gzip(&encoded));
+        assert_eq!(SymbolTable::parse(&encoded).unwrap(), table);
+
+        assert!(SymbolTable::parse(b"401000 10 main.main\n").is_err());
+        let err = SymbolTable::parse(b"kind executable\n401000 main.main\n").unwrap_err();
+        assert!(format!("{:#}", err).contains("line 2"), "{:#}", err);
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::{ensure, Context};
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+/// First file descriptor passed by socket activation, after stdio.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Listeners is the set of sockets passed by systemd socket activation.
+/// Sockets are looked up by the `FileDescriptorName=` of their socket unit,
+/// `grpc` or `http`, so that systemd keeps accepting connections while the
+/// server restarts.
+#[derive(Debug, Default)]
+pub struct Listeners {
+    listeners: HashMap<String, TcpListener>,
+}
+
+impl Listeners {
+    /// Takes the sockets passed in `LISTEN_FDS`, if they were passed to this
+    /// process.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::parse(
+            std::env::var("LISTEN_PID").ok().as_deref(),
+            std::env::var("LISTEN_FDS").ok().as_deref(),
+            std::env::var("LISTEN_FDNAMES").ok().as_deref(),
+        )
+    }
+
+    fn parse(pid: Option<&str>, fds: Option<&str>, names: Option<&str>) -> anyhow::Result<Self> {
+        let (Some(pid), Some(fds)) = (pid, fds) else {
+            return Ok(Self::default());
+        };
+        // The variables are inherited by children of the process they were
+        // meant for.
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return Ok(Self::default());
+        }
+        let fds: RawFd = fds.parse().context("invalid LISTEN_FDS")?;
+        let names: Vec<&str> = names.map(|n| n.split(':').collect()).unwrap_or_default();
+        ensure!(
+            names.is_empty() || names.len() == fds as usize,
+            "LISTEN_FDNAMES has {} names for {} sockets",
+            names.len(),
+            fds
+        );
+
+        let mut listeners = HashMap::new();
+        for i in 0..fds {
+            // SAFETY: systemd passes the sockets as the descriptors following
+            // stdio, which nothing else in the process owns.
+            let listener = unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START + i) };
+            let name = names.get(i as usize).copied().unwrap_or("unknown");
+            ensure!(
+                listeners.insert(name.to_string(), listener).is_none(),
+                "several sockets named {:?} were passed",
+                name
+            );
+        }
+        Ok(Self { listeners })
+    }
+
+    /// Returns the socket named `name`, ready to be used by tokio.
+    pub fn take(&mut self, name: &str) -> anyhow::Result<Option<tokio::net::TcpListener>> {
+        let Some(listener) = self.listeners.remove(name) else {
+            return Ok(None);
+        };
+        listener.set_nonblocking(true)?;
+        Ok(Some(tokio::net::TcpListener::from_std(listener)?))
+    }
+
+    /// Returns the names of the sockets that weren't taken.
+    pub fn unused(&self) -> Vec<&str> {
+        self.listeners.keys().map(String::as_str).collect()
+    }
+}
+
+/// Binds `addr`, unless systemd passed a socket named `name`.
+pub async fn listen(
+    listeners: &mut Listeners,
+    name: &str,
+    addr: std::net::SocketAddr,
+) -> anyhow::Result<tokio::net::TcpListener> {
+    if let Some(listener) = listeners.take(name)? {
+        log::info!(
+            "Using {} socket {} passed by systemd",
+            name,
+            listener.local_addr()?
+        );
+        return Ok(listener);
+    }
+    tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("binding {} address {}", name, addr))
+}
+
+/// Sends `state`, like `READY=1`, to the service manager. Returns false when
+/// the process isn't supervised by systemd.
+pub fn notify(state: &str) -> anyhow::Result<bool> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy();
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)
+        }
+        _ => socket.send_to(state.as_bytes(), path.as_ref()),
+    }
+    .with_context(|| format!("notifying {}", path))?;
+    Ok(true)
+}
+
+/// Returns how often the watchdog must be notified, which is half the
+/// `WatchdogSec=` of the service, if it is enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Notifies the watchdog every `interval` for as long as the runtime is
+/// responsive.
+pub async fn watchdog(interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        if let Err(e) = notify("WATCHDOG=1") {
+            log::warn!("Notifying the systemd watchdog: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let pid = std::process::id().to_string();
+        assert!(Listeners::parse(None, None, None)
+            .unwrap()
+            .unused()
+            .is_empty());
+        // Sockets meant for another process are left alone.
+        assert!(Listeners::parse(Some("1"), Some("2"), Some("grpc:http"))
+            .unwrap()
+            .unused()
+            .is_empty());
+        assert!(Listeners::parse(Some(&pid), Some("2"), Some("grpc")).is_err());
+        assert!(Listeners::parse(Some(&pid), Some("x"), None).is_err());
+    }
+
+    #[test]
+    fn test_notify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        assert!(notify("READY=1").unwrap());
+        std::env::remove_var("NOTIFY_SOCKET");
+        assert!(!notify("READY=1").unwrap());
+
+        let mut buf = [0; 16];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+}
@@ -0,0 +1,437 @@
+//! Support for end-to-end tests: TestServer runs the gRPC services on an
+//! ephemeral port with in-memory storage, and FakeAgent drives them the way
+//! an agent does.
+
+use crate::adminpb::admin_service_client::AdminServiceClient;
+use crate::agent_store::{AgentStore, AGENT_ID_METADATA_KEY};
+use crate::debuginfo_store::{
+    BucketRoutes, DebugInfod, DebuginfoFetcher, MetadataStore, ObjectLayout,
+};
+use crate::flags::Flags;
+use crate::ingester::Ingester;
+use crate::normalizer::Metastore;
+use crate::pprofpb::{Function, Line, Location, Mapping, Profile, Sample, ValueType};
+use crate::principal::Authenticator;
+use crate::profilestorepb::agents_service_client::AgentsServiceClient;
+use crate::profilestorepb::profile_store_service_client::ProfileStoreServiceClient;
+use crate::profilestorepb::{
+    Label, LabelSet, RawProfileSeries, RawSample, WriteRawRequest, WriteRawResponse,
+};
+use crate::server::{self, ServicesBuilder, Stores};
+use crate::symbolizer::{FrameLimits, Symbolizer};
+use crate::symbols::Demangler;
+use crate::{backfill, query, storage};
+use clap::Parser;
+use evprofiler::client::{DebuginfoClient, Object, UploadOutcome};
+use object_store::ObjectStore;
+use prost::Message;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::{Channel, Server};
+use tonic::{Request, Status};
+
+/// Bearer token of the admin API of the TestServer.
+const ADMIN_TOKEN: &str = "test-admin-token";
+
+/// TestServer serves the gRPC services the way the server does, built by
+/// the same ServicesBuilder from the default flags, with in-memory buckets.
+/// The server stops when it's dropped.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub debuginfo_bucket: Arc<dyn ObjectStore>,
+    pub profile_bucket: Arc<dyn ObjectStore>,
+    pub metadata: MetadataStore,
+    pub series: Arc<query::SeriesIndex>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        let token = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(token.path(), ADMIN_TOKEN).unwrap();
+        let flags = Flags::parse_from([
+            "evprofiler".as_ref(),
+            "--admin-token-file".as_ref(),
+            token.path().as_os_str(),
+        ]);
+        let debuginfo_bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let profile_bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let metadata = MetadataStore::new();
+        let buckets = Arc::new(BucketRoutes::new(Arc::clone(&debuginfo_bucket)));
+
+        let metastore = Arc::new(Metastore::default());
+        let symbolizer = Arc::new(Symbolizer::new(
+            metadata.clone(),
+            DebuginfoFetcher::new(
+                Arc::clone(&debuginfo_bucket),
+                ObjectLayout::default(),
+                DebugInfod::disabled(),
+            )
+            .with_bucket_routes(Arc::clone(&buckets)),
+            FrameLimits::default(),
+            Demangler::new(false),
+        ));
+        let services = ServicesBuilder::new(
+            &flags,
+            Stores {
+                metadata: metadata.clone(),
+                debuginfod: DebugInfod::disabled(),
+                debuginfo_buckets: buckets,
+                layout: ObjectLayout::default(),
+                bucket_breaker: Arc::default(),
+                metastore: Arc::clone(&metastore),
+                ingester: Arc::new(
+                    Ingester::new(1, Arc::clone(&profile_bucket))
+                        .with_metastore(Arc::clone(&metastore)),
+                ),
+                symbolizer,
+                agents: Arc::new(AgentStore::default()),
+                sample_reader: query::SampleReader::new(Arc::clone(&profile_bucket), metastore),
+                sources: Arc::new(query::Sources::new(vec![])),
+                webhooks: Arc::default(),
+                clock: Arc::new(crate::clock::SystemClock),
+            },
+        );
+        let series = Arc::clone(&services.shared().series_index);
+        let services = services.build().unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown, stopped) = oneshot::channel();
+        let server = Server::builder()
+            .layer(server::layer(Authenticator::default()))
+            .add_routes(services.routes())
+            .serve_with_incoming_shutdown(
+                tokio_stream::wrappers::TcpListenerStream::new(listener),
+                async {
+                    let _ = stopped.await;
+                },
+            );
+        tokio::spawn(server);
+
+        Self {
+            addr,
+            debuginfo_bucket,
+            profile_bucket,
+            metadata,
+            series,
+            shutdown: Some(shutdown),
+        }
+    }
+
+    /// Returns the URL clients connect to.
+    pub fn endpoint(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub async fn channel(&self) -> Channel {
+        Channel::from_shared(self.endpoint())
+            .unwrap()
+            .connect()
+            .await
+            .unwrap()
+    }
+
+    /// Returns an admin API client carrying the admin token.
+    pub async fn admin(
+        &self,
+    ) -> AdminServiceClient<
+        InterceptedService<Channel, fn(Request<()>) -> Result<Request<()>, Status>>,
+    > {
+        AdminServiceClient::with_interceptor(self.channel().await, |mut request: Request<()>| {
+            request.metadata_mut().insert(
+                "authorization",
+                format!("Bearer {ADMIN_TOKEN}").parse().unwrap(),
+            );
+            Ok(request)
+        })
+    }
+
+    pub async fn agents_client(&self) -> AgentsServiceClient<Channel> {
+        AgentsServiceClient::new(self.channel().await)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+/// FakeAgent writes profiles and uploads debuginfo to a TestServer,
+/// identifying itself as `id`.
+pub struct FakeAgent {
+    pub id: String,
+    profiles: ProfileStoreServiceClient<Channel>,
+    debuginfo: DebuginfoClient,
+}
+
+impl FakeAgent {
+    pub async fn connect(server: &TestServer, id: &str) -> Self {
+        let channel = server.channel().await;
+        Self {
+            id: id.to_string(),
+            profiles: ProfileStoreServiceClient::new(channel.clone()),
+            debuginfo: DebuginfoClient::new(channel)
+                .with_metadata(AGENT_ID_METADATA_KEY, id)
+                .unwrap(),
+        }
+    }
+
+    /// Writes the profile as the only sample of the series with `labels`,
+    /// with an empty executable info per mapping.
+    pub async fn write_raw(
+        &mut self,
+        profile: &Profile,
+        labels: &[(&str, &str)],
+    ) -> Result<WriteRawResponse, Status> {
+        let mut request = Request::new(WriteRawRequest {
+            series: vec![RawProfileSeries {
+                labels: Some(LabelSet {
+                    labels: labels
+                        .iter()
+                        .map(|(name, value)| Label {
+                            name: name.to_string(),
+                            value: value.to_string(),
+                        })
+                        .collect(),
+                }),
+                samples: vec![RawSample {
+                    raw_profile: backfill::compress(&profile.encode_to_vec()).unwrap(),
+                    executable_info: vec![Default::default(); profile.mapping.len()],
+                }],
+            }],
+            ..Default::default()
+        });
+        request
+            .metadata_mut()
+            .insert(AGENT_ID_METADATA_KEY, self.id.parse().unwrap());
+        Ok(self.profiles.write_raw(request).await?.into_inner())
+    }
+
+    /// Uploads debuginfo of the build ID, going through the whole upload
+    /// protocol.
+    pub async fn upload_debuginfo(
+        &mut self,
+        build_id: &str,
+        data: &[u8],
+    ) -> anyhow::Result<UploadOutcome> {
+        self.debuginfo
+            .upload(
+                &Object {
+                    build_id: build_id.to_string(),
+                    build_id_type: evprofiler::debuginfopb::BuildIdType::Gnu,
+                    r#type: evprofiler::debuginfopb::DebuginfoType::DebuginfoUnspecified,
+                    data: data.to_vec(),
+                },
+                false,
+            )
+            .await
+    }
+}
+
+/// Returns a CPU profile with one sample per value, each with a stack of
+/// `main` calling a function of the executable with `build_id`.
+pub fn cpu_profile(build_id: &str, values: &[i64]) -> Profile {
+    let strings = [
+        "",
+        "samples",
+        "count",
+        "cpu",
+        "nanoseconds",
+        "main",
+        build_id,
+    ];
+    Profile {
+        sample_type: vec![ValueType { r#type: 1, unit: 2 }],
+        period_type: Some(ValueType { r#type: 3, unit: 4 }),
+        period: 10_000_000,
+        time_nanos: chrono::Utc::now().timestamp_nanos_opt().unwrap(),
+        mapping: vec![Mapping {
+            id: 1,
+            memory_start: 0x400000,
+            memory_limit: 0x500000,
+            build_id: 6,
+            ..Default::default()
+        }],
+        function: vec![Function {
+            id: 1,
+            name: 5,
+            system_name: 5,
+            ..Default::default()
+        }],
+        location: vec![
+            Location {
+                id: 1,
+                mapping_id: 1,
+                address: 0x401234,
+                ..Default::default()
+            },
+            Location {
+                id: 2,
+                mapping_id: 1,
+                address: 0x400100,
+                line: vec![Line {
+                    function_id: 1,
+                    line: 1,
+                }],
+                ..Default::default()
+            },
+        ],
+        sample: values
+            .iter()
+            .map(|&value| Sample {
+                location_id: vec![1, 2],
+                value: vec![value],
+                ..Default::default()
+            })
+            .collect(),
+        string_table: strings.iter().map(|s| s.to_string()).collect(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adminpb::SymbolizeRequest;
+    use crate::debuginfopb::{debuginfo_upload, DebuginfoType};
+    use crate::memory::MemoryUsage;
+    use crate::profilestorepb::AgentsRequest;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_end_to_end() {
+        let server = TestServer::start().await;
+        let mut agent = FakeAgent::connect(&server, "agent-1").await;
+
+        let response = agent
+            .write_raw(
+                &cpu_profile("abcd", &[3, 0, 2]),
+                &[("__name__", "parca_agent_cpu"), ("job", "e2e")],
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.series[0].accepted, 2);
+        assert_eq!(server.series.profile_types().len(), 1);
+        // Segments are written in the background, after the functions they
+        // reference.
+        let segments = || async {
+            server
+                .profile_bucket
+                .list(None)
+                .filter(|o| o.as_ref().unwrap().location.extension() == Some("parquet"))
+                .collect::<Vec<_>>()
+                .await
+                .len()
+        };
+        for _ in 0..100 {
+            if segments().await > 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(segments().await > 0);
+        let functions = Metastore::load(server.profile_bucket.as_ref(), false)
+            .await
+            .unwrap();
+        assert!(functions.memory_usage() > 0);
+
+        let outcome = agent.upload_debuginfo("abcd", b"not an elf").await.unwrap();
+        assert!(matches!(outcome, UploadOutcome::Uploaded { size: 10, .. }));
+        let debuginfo = server
+            .metadata
+            .fetch("abcd", &DebuginfoType::DebuginfoUnspecified)
+            .unwrap();
+        assert_eq!(
+            debuginfo.upload.unwrap().state(),
+            debuginfo_upload::State::Uploaded
+        );
+        assert_eq!(
+            server
+                .debuginfo_bucket
+                .list(None)
+                .collect::<Vec<_>>()
+                .await
+                .len(),
+            1
+        );
+
+        let agents = server
+            .agents_client()
+            .await
+            .agents(AgentsRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(agents.agents.len(), 1);
+        assert_eq!(agents.agents[0].id, "agent-1");
+
+        let symbolized = server
+            .admin()
+            .await
+            .symbolize(SymbolizeRequest {
+                build_id: "abcd".into(),
+                addresses: vec![0x1234],
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(
+            symbolized.error.contains("Failed to parse object file"),
+            "{}",
+            symbolized.error
+        );
+
+        let unauthenticated = AdminServiceClient::new(server.channel().await)
+            .symbolize(SymbolizeRequest::default())
+            .await
+            .unwrap_err();
+        assert_eq!(unauthenticated.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn test_upload_larger_than_initiated() {
+        use crate::debuginfopb::debuginfo_service_client::DebuginfoServiceClient;
+        use crate::debuginfopb::{
+            upload_request, InitiateUploadRequest, UploadInfo, UploadRequest,
+        };
+
+        let server = TestServer::start().await;
+        let mut client = DebuginfoServiceClient::new(server.channel().await);
+        let instructions = client
+            .initiate_upload(InitiateUploadRequest {
+                build_id: "abcd".into(),
+                size: 3,
+                hash: "hash".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .upload_instructions
+            .unwrap();
+
+        let requests = [
+            upload_request::Data::Info(UploadInfo {
+                build_id: "abcd".into(),
+                upload_id: instructions.upload_id,
+                r#type: DebuginfoType::DebuginfoUnspecified.into(),
+            }),
+            upload_request::Data::ChunkData(b"el".to_vec()),
+            upload_request::Data::ChunkData(b"ff".to_vec()),
+        ]
+        .map(|data| UploadRequest { data: Some(data) });
+        let err = client
+            .upload(tokio_stream::iter(requests))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert!(server.debuginfo_bucket.list(None).next().await.is_none());
+    }
+}
@@ -0,0 +1,366 @@
+use crate::scrape::duration;
+use anyhow::{ensure, Context};
+use chrono::{SecondsFormat, Utc};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use url::Url;
+
+static DELIVERIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "evprofiler_webhook_deliveries_total",
+        "Total number of webhook notifications, by event and result.",
+        &["event", "result"]
+    )
+    .unwrap()
+});
+
+/// Number of times a notification is sent before it's given up.
+const MAX_ATTEMPTS: u32 = 3;
+/// Delay before the first retry, doubled for every further retry.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+/// Number of notifications queued per endpoint, beyond which they are
+/// dropped.
+const QUEUE_SIZE: usize = 1024;
+
+/// EventKind is a debuginfo lifecycle event webhooks are notified of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// An upload of debuginfo was marked as finished and stored.
+    UploadFinished,
+    /// Debuginfo was rejected, because the uploaded object doesn't match the
+    /// upload or isn't a valid ELF file.
+    ValidationFailed,
+    /// Symbolizing with the debuginfo failed repeatedly, so its build ID
+    /// isn't symbolized anymore.
+    SymbolizationFailed,
+}
+
+impl EventKind {
+    const ALL: [EventKind; 3] = [
+        EventKind::UploadFinished,
+        EventKind::ValidationFailed,
+        EventKind::SymbolizationFailed,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::UploadFinished => "upload_finished",
+            EventKind::ValidationFailed => "validation_failed",
+            EventKind::SymbolizationFailed => "symbolization_failed",
+        }
+    }
+}
+
+/// Event is the JSON payload of a notification.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    pub event: EventKind,
+    pub build_id: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub upload_id: String,
+    /// Why validation or symbolization failed.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub reason: String,
+    /// Time of the event in RFC 3339.
+    pub timestamp: String,
+}
+
+impl Event {
+    pub fn new(event: EventKind, build_id: &str) -> Self {
+        Self {
+            event,
+            build_id: build_id.to_string(),
+            upload_id: String::new(),
+            reason: String::new(),
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        }
+    }
+}
+
+fn default_events() -> Vec<EventKind> {
+    EventKind::ALL.to_vec()
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// WebhookConfig configures an endpoint notified of debuginfo events:
+///
+/// ```yaml
+/// url: https://ci.example.com/hooks/debuginfo
+/// secret_file: /etc/evprofiler/webhook-secret
+/// events: [upload_finished, validation_failed, symbolization_failed]
+/// timeout: 10s
+/// ```
+///
+/// Events are POSTed as JSON with the event in the `X-Evprofiler-Event`
+/// header. With a secret, the `X-Evprofiler-Signature` header holds
+/// `sha256=<hex encoded HMAC-SHA256 of the body>`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// File holding the key the payloads are signed with. Trailing
+    /// whitespace is ignored.
+    #[serde(default)]
+    pub secret_file: Option<PathBuf>,
+    /// Events the endpoint is notified of, all by default.
+    #[serde(default = "default_events")]
+    pub events: Vec<EventKind>,
+    /// Time a notification may take.
+    #[serde(default = "default_timeout", deserialize_with = "duration")]
+    pub timeout: Duration,
+}
+
+impl WebhookConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let url = Url::parse(&self.url).with_context(|| format!("invalid url {:?}", self.url))?;
+        ensure!(
+            matches!(url.scheme(), "http" | "https"),
+            "url must be http or https"
+        );
+        ensure!(!self.events.is_empty(), "events must not be empty");
+        ensure!(!self.timeout.is_zero(), "timeout must be positive");
+        Ok(())
+    }
+
+    fn secret(&self) -> anyhow::Result<Option<hmac::Key>> {
+        let Some(path) = &self.secret_file else {
+            return Ok(None);
+        };
+        let secret = std::fs::read_to_string(path)
+            .with_context(|| format!("reading webhook secret {}", path.display()))?;
+        let secret = secret.trim_end();
+        ensure!(
+            !secret.is_empty(),
+            "webhook secret {} is empty",
+            path.display()
+        );
+        Ok(Some(hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())))
+    }
+}
+
+#[derive(Debug)]
+struct Webhook {
+    url: String,
+    secret: Option<hmac::Key>,
+    client: ureq::Agent,
+}
+
+impl Webhook {
+    /// Sends the event, retrying failures with backoff. Only the requests
+    /// block, on a blocking thread, the backoff doesn't.
+    async fn deliver(self: &Arc<Self>, event: &Event) -> anyhow::Result<()> {
+        let body = Arc::new(serde_json::to_vec(event)?);
+        let delivery = ulid::Ulid::new().to_string();
+        let mut backoff = RETRY_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let res = tokio::task::spawn_blocking({
+                let (hook, body, delivery) =
+                    (Arc::clone(self), Arc::clone(&body), delivery.clone());
+                let event = event.event;
+                move || hook.send(event, &delivery, &body)
+            })
+            .await?;
+            match res {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt >= MAX_ATTEMPTS => {
+                    return Err(e).with_context(|| format!("notifying {}", self.url))
+                }
+                Err(_) => {}
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    fn send(&self, event: EventKind, delivery: &str, body: &[u8]) -> anyhow::Result<()> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .set("Content-Type", "application/json")
+            .set("X-Evprofiler-Event", event.as_str())
+            .set("X-Evprofiler-Delivery", delivery);
+        if let Some(secret) = &self.secret {
+            request = request.set("X-Evprofiler-Signature", &signature(secret, body));
+        }
+        request.send_bytes(body)?;
+        Ok(())
+    }
+
+    /// Delivers the queued events one after the other, until the queue is
+    /// closed.
+    async fn run(self: Arc<Self>, mut queue: mpsc::Receiver<Event>) {
+        while let Some(event) = queue.recv().await {
+            let result = match self.deliver(&event).await {
+                Ok(()) => "delivered",
+                Err(e) => {
+                    log::warn!(
+                        "Failed to notify webhook of {} of build_id {}: {:#}",
+                        event.event.as_str(),
+                        event.build_id,
+                        e
+                    );
+                    "failed"
+                }
+            };
+            DELIVERIES
+                .with_label_values(&[event.event.as_str(), result])
+                .inc();
+        }
+    }
+}
+
+/// Returns the `X-Evprofiler-Signature` header of the body.
+fn signature(secret: &hmac::Key, body: &[u8]) -> String {
+    let tag = hmac::sign(secret, body);
+    tag.as_ref()
+        .iter()
+        .fold(String::from("sha256="), |mut signature, byte| {
+            let _ = write!(signature, "{:02x}", byte);
+            signature
+        })
+}
+
+/// Webhooks notifies the configured endpoints of debuginfo lifecycle events,
+/// so that build pipelines can react to them, like re-uploading debuginfo
+/// that failed validation. Notifications are queued and sent in the
+/// background, one at a time per endpoint. They are dropped while the queue
+/// of an endpoint is full.
+#[derive(Debug, Default)]
+pub struct Webhooks {
+    queues: Vec<WebhookQueue>,
+}
+
+/// The queue of the notifications of an endpoint.
+#[derive(Debug)]
+struct WebhookQueue {
+    events: Vec<EventKind>,
+    sender: mpsc::Sender<Event>,
+}
+
+impl Webhooks {
+    /// Creates the webhooks of the configs, reading their secrets, and
+    /// starts delivering their notifications.
+    pub fn new(configs: &[WebhookConfig]) -> anyhow::Result<Self> {
+        let mut queues = vec![];
+        for config in configs {
+            config
+                .validate()
+                .with_context(|| format!("webhook {}", config.url))?;
+            let hook = Arc::new(Webhook {
+                url: config.url.clone(),
+                secret: config.secret()?,
+                client: ureq::AgentBuilder::new().timeout(config.timeout).build(),
+            });
+            let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+            tokio::spawn(hook.run(receiver));
+            queues.push(WebhookQueue {
+                events: config.events.clone(),
+                sender,
+            });
+        }
+        Ok(Self { queues })
+    }
+
+    /// Queues the event for the endpoints subscribed to it.
+    pub fn notify(&self, event: Event) {
+        for queue in self
+            .queues
+            .iter()
+            .filter(|q| q.events.contains(&event.event))
+        {
+            if let Err(mpsc::error::TrySendError::Full(event)) =
+                queue.sender.try_send(event.clone())
+            {
+                log::warn!(
+                    "Dropping webhook notification of {} of build_id {}, the queue is full",
+                    event.event.as_str(),
+                    event.build_id
+                );
+                DELIVERIES
+                    .with_label_values(&[event.event.as_str(), "dropped"])
+                    .inc();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::HeaderMap, routing::post, Router};
+
+    #[test]
+    fn test_validate() {
+        let config: WebhookConfig =
+            serde_yaml::from_str("url: https://ci.example.com/hook\nevents: [upload_finished]\n")
+                .unwrap();
+        assert_eq!(config.events, [EventKind::UploadFinished]);
+        assert_eq!(config.timeout, Duration::from_secs(10));
+        config.validate().unwrap();
+
+        let config: WebhookConfig = serde_yaml::from_str("url: ftp://example.com\n").unwrap();
+        assert!(config.validate().is_err());
+        assert!(serde_yaml::from_str::<WebhookConfig>("url: x\nevents: [deleted]\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_notify() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let router = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: String| {
+                let tx = tx.clone();
+                async move {
+                    let header = |name: &str| headers[name].to_str().unwrap().to_string();
+                    let signature = header("x-evprofiler-signature");
+                    tx.send((header("x-evprofiler-event"), signature, body))
+                        .unwrap();
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(crate::http::serve(listener, router, std::future::pending()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let secret_file = dir.path().join("secret");
+        std::fs::write(&secret_file, "s3cret\n").unwrap();
+        let webhooks = Webhooks::new(&[WebhookConfig {
+            url: format!("http://{}/hook", addr),
+            secret_file: Some(secret_file),
+            events: vec![EventKind::ValidationFailed],
+            timeout: Duration::from_secs(5),
+        }])
+        .unwrap();
+
+        webhooks.notify(Event::new(EventKind::UploadFinished, "abcd"));
+        webhooks.notify(Event {
+            reason: "not a valid ELF file".into(),
+            ..Event::new(EventKind::ValidationFailed, "abcd")
+        });
+        let (event, signature, body) = rx.recv().await.unwrap();
+        assert_eq!(event, "validation_failed");
+        let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(payload["event"], "validation_failed");
+        assert_eq!(payload["build_id"], "abcd");
+        assert_eq!(payload["reason"], "not a valid ELF file");
+        assert!(payload.get("upload_id").is_none());
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"s3cret");
+        assert_eq!(signature, super::signature(&key, body.as_bytes()));
+        // The upload_finished event isn't subscribed to.
+        assert!(rx.try_recv().is_err());
+    }
+}
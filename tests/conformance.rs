@@ -0,0 +1,510 @@
+//! Replays RPC transcripts of the calls parca-agent makes against the
+//! `evprofiler` binary and diffs its responses with the expected ones, to
+//! keep the server a drop-in replacement for upstream Parca. Run with
+//! `cargo test --test conformance`.
+//!
+//! Transcripts live in `testdata/conformance` as JSON files with a list of
+//! steps, each calling an RPC by its full method name. They are written by
+//! hand from the protocol definitions and the calls of parca-agent, not
+//! captured from an upstream server. Messages are written
+//! by protobuf field number, so transcripts don't depend on the generated
+//! code: strings are length-delimited fields, numbers and booleans varints,
+//! objects nested messages and arrays repeated fields. An array as request
+//! calls a client streaming RPC with its messages. Expected responses only
+//! constrain the fields they mention, because fields this server added are
+//! ignored by upstream clients; `null` accepts any value. Values captured
+//! from responses, like upload IDs, are substituted for `$<name>` strings in
+//! the requests of later steps.
+
+use anyhow::{bail, Context};
+use prost::bytes::{Buf, BufMut};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use tonic::codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder};
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::Channel;
+use tonic::{Request, Status};
+
+#[derive(Debug, Deserialize)]
+struct Transcript {
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Step {
+    /// Full method name, like `/parca.debuginfo.v1alpha1.DebuginfoService/Upload`.
+    method: String,
+    request: Value,
+    /// Expected response message of a successful call.
+    #[serde(default)]
+    response: Option<Value>,
+    /// Expected status code of a failed call, like `InvalidArgument`.
+    #[serde(default)]
+    code: Option<String>,
+    /// Names of values to capture by the dot-separated field numbers of the
+    /// response they're read from.
+    #[serde(default)]
+    capture: BTreeMap<String, String>,
+}
+
+/// A decoded field value; messages, strings and bytes are all
+/// length-delimited.
+#[derive(Debug, Clone)]
+enum Field {
+    Varint(u64),
+    Fixed64(u64),
+    Fixed32(u32),
+    Bytes(Vec<u8>),
+}
+
+/// Fields of a decoded message by number, in wire order.
+type Fields = HashMap<u64, Vec<Field>>;
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn get_varint(buf: &mut &[u8]) -> anyhow::Result<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let Some((&byte, rest)) = buf.split_first() else {
+            bail!("truncated varint");
+        };
+        *buf = rest;
+        v |= u64::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            return Ok(v);
+        }
+    }
+    bail!("varint overflows 64 bits")
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> anyhow::Result<&'a [u8]> {
+    if buf.len() < n {
+        bail!("truncated field");
+    }
+    let (head, rest) = buf.split_at(n);
+    *buf = rest;
+    Ok(head)
+}
+
+/// Encodes a message written as a JSON object keyed by field number.
+fn encode(message: &Value, vars: &HashMap<String, String>) -> anyhow::Result<Vec<u8>> {
+    let Value::Object(fields) = message else {
+        bail!("expected a message, got {}", message);
+    };
+    let mut buf = vec![];
+    for (number, value) in fields {
+        let number: u64 = number
+            .parse()
+            .with_context(|| format!("invalid field number {:?}", number))?;
+        let values = match value {
+            Value::Array(values) => values.as_slice(),
+            value => std::slice::from_ref(value),
+        };
+        for value in values {
+            let varint = match value {
+                Value::Bool(b) => Some(u64::from(*b)),
+                Value::Number(n) => Some(match n.as_u64() {
+                    Some(n) => n,
+                    None => n.as_i64().context("only integers are supported")? as u64,
+                }),
+                _ => None,
+            };
+            if let Some(v) = varint {
+                put_varint(&mut buf, number << 3);
+                put_varint(&mut buf, v);
+                continue;
+            }
+            let payload = match value {
+                Value::String(s) => substitute(s, vars)?.into_bytes(),
+                Value::Object(_) => encode(value, vars)?,
+                _ => bail!("unsupported value {} of field {}", value, number),
+            };
+            put_varint(&mut buf, number << 3 | 2);
+            put_varint(&mut buf, payload.len() as u64);
+            buf.extend(payload);
+        }
+    }
+    Ok(buf)
+}
+
+fn substitute(s: &str, vars: &HashMap<String, String>) -> anyhow::Result<String> {
+    match s.strip_prefix('$') {
+        Some(name) => vars
+            .get(name)
+            .cloned()
+            .with_context(|| format!("${} wasn't captured", name)),
+        None => Ok(s.to_string()),
+    }
+}
+
+fn decode(mut buf: &[u8]) -> anyhow::Result<Fields> {
+    let mut fields = Fields::new();
+    while !buf.is_empty() {
+        let key = get_varint(&mut buf)?;
+        let field = match key & 7 {
+            0 => Field::Varint(get_varint(&mut buf)?),
+            1 => Field::Fixed64(u64::from_le_bytes(take(&mut buf, 8)?.try_into()?)),
+            2 => {
+                let len = get_varint(&mut buf)? as usize;
+                Field::Bytes(take(&mut buf, len)?.to_vec())
+            }
+            5 => Field::Fixed32(u32::from_le_bytes(take(&mut buf, 4)?.try_into()?)),
+            wire_type => bail!("unsupported wire type {}", wire_type),
+        };
+        fields.entry(key >> 3).or_default().push(field);
+    }
+    Ok(fields)
+}
+
+/// Appends the differences between the expected message and the encoded
+/// actual one to `diffs`.
+fn diff(
+    expected: &Value,
+    actual: &[u8],
+    path: &str,
+    vars: &HashMap<String, String>,
+    diffs: &mut Vec<String>,
+) {
+    let Value::Object(expected) = expected else {
+        diffs.push(format!("{}: expected a message in the transcript", path));
+        return;
+    };
+    let actual = match decode(actual) {
+        Ok(actual) => actual,
+        Err(e) => {
+            diffs.push(format!("{}: {}", path, e));
+            return;
+        }
+    };
+    for (number, expected) in expected {
+        let path = format!("{}.{}", path, number);
+        let values = number
+            .parse()
+            .ok()
+            .and_then(|n: u64| actual.get(&n))
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        match expected {
+            Value::Array(expected) => {
+                if expected.len() != values.len() {
+                    diffs.push(format!(
+                        "{}: expected {} values, got {}",
+                        path,
+                        expected.len(),
+                        values.len()
+                    ));
+                    continue;
+                }
+                for (i, (expected, actual)) in expected.iter().zip(values).enumerate() {
+                    diff_value(
+                        expected,
+                        Some(actual),
+                        &format!("{}[{}]", path, i),
+                        vars,
+                        diffs,
+                    );
+                }
+            }
+            // Like protobuf, the last value of a repeated scalar wins.
+            expected => diff_value(expected, values.last(), &path, vars, diffs),
+        }
+    }
+}
+
+fn diff_value(
+    expected: &Value,
+    actual: Option<&Field>,
+    path: &str,
+    vars: &HashMap<String, String>,
+    diffs: &mut Vec<String>,
+) {
+    // Absent fields have their default value.
+    let matches = match (expected, actual) {
+        (Value::Null, actual) => actual.is_some(),
+        (Value::Bool(b), None) => !b,
+        (Value::Bool(b), Some(Field::Varint(v))) => u64::from(*b) == *v,
+        (Value::Number(n), None) => n.as_u64() == Some(0),
+        (Value::Number(n), Some(Field::Varint(v))) => {
+            n.as_u64() == Some(*v) || n.as_i64() == Some(*v as i64)
+        }
+        (Value::String(s), actual) => {
+            let actual = match actual {
+                None => Some(&[][..]),
+                Some(Field::Bytes(b)) => Some(b.as_slice()),
+                Some(_) => None,
+            };
+            match substitute(s, vars) {
+                Ok(s) => actual == Some(s.as_bytes()),
+                Err(e) => {
+                    diffs.push(format!("{}: {}", path, e));
+                    return;
+                }
+            }
+        }
+        (Value::Object(_), None) => {
+            diff(expected, &[], path, vars, diffs);
+            return;
+        }
+        (Value::Object(_), Some(Field::Bytes(b))) => {
+            diff(expected, b, path, vars, diffs);
+            return;
+        }
+        _ => false,
+    };
+    if !matches {
+        diffs.push(format!(
+            "{}: expected {}, got {}",
+            path,
+            expected,
+            describe(actual)
+        ));
+    }
+}
+
+fn describe(field: Option<&Field>) -> String {
+    match field {
+        None => "nothing".into(),
+        Some(Field::Bytes(b)) => format!("{:?}", String::from_utf8_lossy(b)),
+        Some(Field::Varint(v) | Field::Fixed64(v)) => v.to_string(),
+        Some(Field::Fixed32(v)) => v.to_string(),
+    }
+}
+
+/// Returns the string at the dot-separated field numbers of the message.
+fn capture(message: &[u8], path: &str) -> anyhow::Result<String> {
+    let mut message = message.to_vec();
+    for number in path.split('.') {
+        let number: u64 = number.parse()?;
+        match decode(&message)?.remove(&number).and_then(|mut v| v.pop()) {
+            Some(Field::Bytes(b)) => message = b,
+            Some(field) => return Ok(describe(Some(&field))),
+            None => bail!("field {} is absent", path),
+        }
+    }
+    Ok(String::from_utf8(message)?)
+}
+
+/// RawCodec passes encoded messages through, so that any RPC can be called
+/// without its generated types.
+#[derive(Debug, Default, Clone)]
+struct RawCodec;
+
+impl Codec for RawCodec {
+    type Encode = Vec<u8>;
+    type Decode = Vec<u8>;
+    type Encoder = RawCodec;
+    type Decoder = RawCodec;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        RawCodec
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        RawCodec
+    }
+}
+
+impl Encoder for RawCodec {
+    type Item = Vec<u8>;
+    type Error = Status;
+
+    fn encode(&mut self, item: Vec<u8>, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        dst.put_slice(&item);
+        Ok(())
+    }
+}
+
+impl Decoder for RawCodec {
+    type Item = Vec<u8>;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<Vec<u8>>, Status> {
+        Ok(Some(src.copy_to_bytes(src.remaining()).to_vec()))
+    }
+}
+
+async fn call(
+    channel: Channel,
+    method: &str,
+    request: &Value,
+    vars: &HashMap<String, String>,
+) -> anyhow::Result<Result<Vec<u8>, Status>> {
+    let path = PathAndQuery::try_from(method)?;
+    let mut grpc = tonic::client::Grpc::new(channel);
+    grpc.ready().await?;
+    let response = match request {
+        Value::Array(messages) => {
+            let messages = messages
+                .iter()
+                .map(|m| encode(m, vars))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            grpc.client_streaming(Request::new(tokio_stream::iter(messages)), path, RawCodec)
+                .await
+        }
+        message => {
+            grpc.unary(Request::new(encode(message, vars)?), path, RawCodec)
+                .await
+        }
+    };
+    Ok(response.map(|r| r.into_inner()))
+}
+
+/// Server runs the binary with in-memory debuginfo and its profiles in a
+/// temporary directory, and kills it when dropped.
+struct Server {
+    child: Child,
+    grpc_address: SocketAddr,
+    _dir: tempfile::TempDir,
+}
+
+impl Server {
+    fn start() -> anyhow::Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let (grpc_address, http_address) = (free_address()?, free_address()?);
+        let child = Command::new(env!("CARGO_BIN_EXE_evprofiler"))
+            .current_dir(dir.path())
+            .arg(format!("--grpc-address={}", grpc_address))
+            .arg(format!("--http-address={}", http_address))
+            .arg("--debuginfod-disabled")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .context("starting evprofiler")?;
+        Ok(Self {
+            child,
+            grpc_address,
+            _dir: dir,
+        })
+    }
+
+    /// Connects to the server once it listens.
+    async fn channel(&mut self) -> anyhow::Result<Channel> {
+        let endpoint = Channel::from_shared(format!("http://{}", self.grpc_address))?;
+        for _ in 0..100 {
+            if let Ok(channel) = endpoint.connect().await {
+                return Ok(channel);
+            }
+            if let Some(status) = self.child.try_wait()? {
+                bail!("evprofiler exited with {}", status);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        bail!("evprofiler didn't listen on {}", self.grpc_address)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Returns a local address no one listens on.
+fn free_address() -> anyhow::Result<SocketAddr> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?)
+}
+
+/// Replays the transcript against a new server, returning the differences.
+async fn replay(transcript: &Transcript) -> anyhow::Result<Vec<String>> {
+    let mut server = Server::start()?;
+    let channel = server.channel().await?;
+    let mut vars = HashMap::new();
+    let mut diffs = vec![];
+    for (i, step) in transcript.steps.iter().enumerate() {
+        let name = format!("step {} ({})", i, step.method);
+        match (
+            call(channel.clone(), &step.method, &step.request, &vars).await?,
+            &step.code,
+        ) {
+            (Ok(response), None) => {
+                if let Some(expected) = &step.response {
+                    diff(expected, &response, &name, &vars, &mut diffs);
+                }
+                for (var, path) in &step.capture {
+                    vars.insert(var.clone(), capture(&response, path)?);
+                }
+            }
+            (Ok(_), Some(code)) => diffs.push(format!("{}: expected {}, got Ok", name, code)),
+            (Err(status), expected) => {
+                let code = format!("{:?}", status.code());
+                if expected.as_ref() != Some(&code) {
+                    diffs.push(format!(
+                        "{}: expected {}, got {} {:?}",
+                        name,
+                        expected.as_deref().unwrap_or("Ok"),
+                        code,
+                        status.message()
+                    ));
+                }
+            }
+        }
+    }
+    Ok(diffs)
+}
+
+#[tokio::test]
+async fn test_conformance() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/conformance");
+    let mut failures = vec![];
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|e| e != "json") {
+            continue;
+        }
+        let transcript: Transcript =
+            serde_json::from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        match replay(&transcript).await {
+            Ok(diffs) => failures.extend(diffs.into_iter().map(|d| format!("{}: {}", name, d))),
+            Err(e) => failures.push(format!("{}: {:#}", name, e)),
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn test_encode_diff() {
+    let vars = HashMap::from([("id".to_string(), "u1".to_string())]);
+    let message = serde_json::json!({"1": {"1": "abcd", "2": "$id", "3": 1}, "2": [1, 2]});
+    let encoded = encode(&message, &vars).unwrap();
+    assert_eq!(capture(&encoded, "1.2").unwrap(), "u1");
+
+    let mut diffs = vec![];
+    diff(&message, &encoded, "m", &vars, &mut diffs);
+    diff(
+        &serde_json::json!({"1": {"2": null, "4": false}, "3": ""}),
+        &encoded,
+        "m",
+        &vars,
+        &mut diffs,
+    );
+    assert!(diffs.is_empty(), "{:?}", diffs);
+
+    diff(
+        &serde_json::json!({"1": {"1": "ef01", "5": null}, "2": [1]}),
+        &encoded,
+        "m",
+        &vars,
+        &mut diffs,
+    );
+    assert_eq!(
+        diffs,
+        [
+            "m.1.1: expected \"ef01\", got \"abcd\"",
+            "m.1.5: expected null, got nothing",
+            "m.2: expected 1 values, got 2",
+        ]
+    );
+}